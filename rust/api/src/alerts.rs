@@ -0,0 +1,60 @@
+// rust/api/src/alerts.rs
+//! Alert throttling for threat verdicts
+//!
+//! Every threat verdict raises a `detection.alert` audit record naming the
+//! rules (reasons) that fired. A rule that keeps firing for the same content
+//! is muted for `alert_cooldown_secs` after each alert it takes part in, so a
+//! noisy rule cannot flood the alert stream. Scoring is unaffected: the
+//! throttle only decides which rules are listed in alerts.
+
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+
+/// (rule, content hash) pairs remembered; older pairs may alert again early
+const TRACKED_PAIRS: usize = 10_000;
+
+pub struct AlertThrottle {
+    last_alert: Mutex<LruCache<(String, String), Instant>>,
+    cooldown: Duration,
+    clock: SharedClock,
+}
+
+impl AlertThrottle {
+    pub fn new(cooldown: Duration, clock: SharedClock) -> Self {
+        Self {
+            last_alert: Mutex::new(LruCache::new(NonZeroUsize::new(TRACKED_PAIRS).unwrap())),
+            cooldown,
+            clock,
+        }
+    }
+
+    /// Rules among `rules` that are not cooling down for this content.
+    ///
+    /// Returned rules start a new cooldown period.
+    pub fn admit<'a>(&self, content_hash: &str, rules: &'a [String]) -> Vec<&'a str> {
+        if self.cooldown.is_zero() {
+            return rules.iter().map(String::as_str).collect();
+        }
+        let mut last_alert = self.last_alert.lock().unwrap();
+        let now = self.clock.now();
+
+        rules
+            .iter()
+            .filter(|rule| {
+                let key = (rule.to_string(), content_hash.to_string());
+                match last_alert.get(&key) {
+                    Some(at) if now.duration_since(*at) < self.cooldown => false,
+                    _ => {
+                        last_alert.put(key, now);
+                        true
+                    }
+                }
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
@@ -0,0 +1,401 @@
+// rust/api/src/anomaly.rs
+//! Alert rules over rolling detection statistics
+//!
+//! A background task samples the statistics every `stat_alert_interval_secs`
+//! and evaluates the `stat_alerts` rules against the samples. A rule
+//! compares a metric over its `window_secs` with either a fixed `threshold`
+//! or `factor` times the same metric over `baseline_secs`. It fires once the
+//! condition has held for `for_secs`, and resolves only after the value has
+//! been back inside the limit by a `hysteresis` margin for as long again, so
+//! a metric hovering at the limit does not flap.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+
+/// Statistic a rule watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Share of computed verdicts that were threats
+    ThreatRate,
+    /// Computed verdicts per second
+    DetectionRate,
+    /// Share of verdicts served from the cache
+    CacheHitRate,
+    /// 99th percentile latency of recent computed verdicts
+    P99LatencyMs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Condition {
+    Above,
+    Below,
+}
+
+/// One `[[stat_alerts]]` entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    /// Request threat type for `threat_rate` and `detection_rate`; all when unset
+    #[serde(default)]
+    pub threat_type: Option<String>,
+    pub condition: Condition,
+    /// Fixed limit
+    #[serde(default)]
+    pub threshold: Option<f64>,
+    /// Limit as a multiple of the baseline
+    #[serde(default)]
+    pub factor: Option<f64>,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Span of the baseline, limited to the history gathered so far
+    #[serde(default = "default_baseline_secs")]
+    pub baseline_secs: u64,
+    /// How long the condition must hold before firing, and clear before resolving
+    #[serde(default)]
+    pub for_secs: u64,
+    /// Fraction of the limit a firing value must move back past to resolve
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+    /// Rate metrics over fewer verdicts than this are not evaluated
+    #[serde(default = "default_min_count")]
+    pub min_count: u64,
+}
+
+fn default_window_secs() -> u64 {
+    300
+}
+
+fn default_baseline_secs() -> u64 {
+    3600
+}
+
+fn default_hysteresis() -> f64 {
+    0.1
+}
+
+fn default_min_count() -> u64 {
+    20
+}
+
+/// Check rule names are unique and each rule has exactly one kind of limit
+pub fn validate(rules: &[AlertRule]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        let name = &rule.name;
+        if !names.insert(name) {
+            return Err(format!("stat_alerts: duplicate rule name {:?}", name));
+        }
+        match (rule.threshold, rule.factor) {
+            (Some(_), None) => {}
+            (None, Some(factor)) if factor > 0.0 => {
+                if rule.baseline_secs <= rule.window_secs {
+                    return Err(format!("stat_alerts.{}: baseline_secs must exceed window_secs", name));
+                }
+            }
+            (None, Some(_)) => return Err(format!("stat_alerts.{}: factor must be positive", name)),
+            _ => return Err(format!("stat_alerts.{}: set exactly one of threshold and factor", name)),
+        }
+        if rule.window_secs == 0 {
+            return Err(format!("stat_alerts.{}: window_secs must be positive", name));
+        }
+        if !(0.0..1.0).contains(&rule.hysteresis) {
+            return Err(format!("stat_alerts.{}: hysteresis must be at least 0 and below 1", name));
+        }
+        if rule.threat_type.is_some() && !matches!(rule.metric, Metric::ThreatRate | Metric::DetectionRate) {
+            return Err(format!("stat_alerts.{}: threat_type only applies to threat_rate and detection_rate", name));
+        }
+    }
+    Ok(())
+}
+
+/// Verdict counts for one request threat type
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TypeCounts {
+    pub detections: u64,
+    pub threats: u64,
+}
+
+/// Cumulative statistics at one point in time
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub at: Instant,
+    pub detections: u64,
+    pub threats: u64,
+    pub cache_hits: u64,
+    pub by_type: HashMap<String, TypeCounts>,
+    /// p99 of the recent latency buffer, if it holds any
+    pub p99_latency_ms: Option<f64>,
+}
+
+/// A firing rule
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveAlert {
+    pub rule: String,
+    pub metric: Metric,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat_type: Option<String>,
+    /// Latest observed value
+    pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<f64>,
+    pub limit: f64,
+    pub since: DateTime<Utc>,
+}
+
+/// State change produced by an evaluation
+pub enum Transition {
+    Fired(ActiveAlert),
+    Resolved(ActiveAlert),
+}
+
+#[derive(Default)]
+struct RuleState {
+    breach_since: Option<Instant>,
+    clear_since: Option<Instant>,
+    firing: Option<ActiveAlert>,
+}
+
+pub struct Monitor {
+    history: Mutex<VecDeque<Sample>>,
+    states: Mutex<HashMap<String, RuleState>>,
+    clock: SharedClock,
+}
+
+impl Monitor {
+    pub fn new(clock: SharedClock) -> Self {
+        Self { history: Mutex::default(), states: Mutex::default(), clock }
+    }
+
+    /// Add a sample and evaluate every rule against the history
+    pub fn evaluate(&self, sample: Sample, rules: &[AlertRule]) -> Vec<Transition> {
+        let now = sample.at;
+        let mut history = self.history.lock().unwrap();
+        history.push_back(sample);
+        let keep = rules.iter().map(|r| r.window_secs.max(r.baseline_secs)).max().unwrap_or(0);
+        while history.len() > 2 && now.duration_since(history[1].at) >= Duration::from_secs(keep) {
+            history.pop_front();
+        }
+
+        let mut states = self.states.lock().unwrap();
+        states.retain(|name, _| rules.iter().any(|r| &r.name == name));
+        let mut transitions = Vec::new();
+        for rule in rules {
+            let Some((value, baseline, limit)) = observe(rule, &history) else {
+                continue;
+            };
+            let state = states.entry(rule.name.clone()).or_default();
+            let hold = Duration::from_secs(rule.for_secs);
+            match &mut state.firing {
+                None => {
+                    if !breaches(rule, value, limit) {
+                        state.breach_since = None;
+                        continue;
+                    }
+                    let since = *state.breach_since.get_or_insert(now);
+                    if now.duration_since(since) >= hold {
+                        let alert = ActiveAlert {
+                            rule: rule.name.clone(),
+                            metric: rule.metric,
+                            threat_type: rule.threat_type.clone(),
+                            value,
+                            baseline,
+                            limit,
+                            since: self.clock.utc(),
+                        };
+                        state.firing = Some(alert.clone());
+                        state.breach_since = None;
+                        state.clear_since = None;
+                        transitions.push(Transition::Fired(alert));
+                    }
+                }
+                Some(alert) => {
+                    alert.value = value;
+                    alert.baseline = baseline;
+                    alert.limit = limit;
+                    if !clears(rule, value, limit) {
+                        state.clear_since = None;
+                        continue;
+                    }
+                    let since = *state.clear_since.get_or_insert(now);
+                    if now.duration_since(since) >= hold {
+                        let alert = state.firing.take().unwrap();
+                        state.clear_since = None;
+                        transitions.push(Transition::Resolved(alert));
+                    }
+                }
+            }
+        }
+        transitions
+    }
+
+    /// Firing alerts, by rule name
+    pub fn active(&self) -> Vec<ActiveAlert> {
+        let mut alerts: Vec<ActiveAlert> =
+            self.states.lock().unwrap().values().filter_map(|s| s.firing.clone()).collect();
+        alerts.sort_by(|a, b| a.rule.cmp(&b.rule));
+        alerts
+    }
+}
+
+fn breaches(rule: &AlertRule, value: f64, limit: f64) -> bool {
+    match rule.condition {
+        Condition::Above => value > limit,
+        Condition::Below => value < limit,
+    }
+}
+
+fn clears(rule: &AlertRule, value: f64, limit: f64) -> bool {
+    match rule.condition {
+        Condition::Above => value < limit * (1.0 - rule.hysteresis),
+        Condition::Below => value > limit * (1.0 + rule.hysteresis),
+    }
+}
+
+/// Current value, baseline and limit of a rule, when there is enough data
+fn observe(rule: &AlertRule, history: &VecDeque<Sample>) -> Option<(f64, Option<f64>, f64)> {
+    let value = measure(rule, history, rule.window_secs)?;
+    match (rule.threshold, rule.factor) {
+        (Some(threshold), _) => Some((value, None, threshold)),
+        (None, Some(factor)) => {
+            let baseline = measure(rule, history, rule.baseline_secs)?;
+            (baseline > 0.0).then_some((value, Some(baseline), factor * baseline))
+        }
+        (None, None) => None,
+    }
+}
+
+/// Metric over the samples of the last `span_secs`
+fn measure(rule: &AlertRule, history: &VecDeque<Sample>, span_secs: u64) -> Option<f64> {
+    let to = history.back()?;
+    let span = Duration::from_secs(span_secs);
+    let window: Vec<&Sample> = history.iter().filter(|s| to.at.duration_since(s.at) <= span).collect();
+
+    if rule.metric == Metric::P99LatencyMs {
+        let values: Vec<f64> = window.iter().filter_map(|s| s.p99_latency_ms).collect();
+        return (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64);
+    }
+
+    let from = *window.first()?;
+    let elapsed = to.at.duration_since(from.at).as_secs_f64();
+    if elapsed <= 0.0 {
+        return None;
+    }
+    let counts = |s: &Sample| match &rule.threat_type {
+        Some(threat_type) => s.by_type.get(threat_type).copied().unwrap_or_default(),
+        None => TypeCounts { detections: s.detections, threats: s.threats },
+    };
+    let (start, end) = (counts(from), counts(to));
+    let detections = end.detections.saturating_sub(start.detections);
+    match rule.metric {
+        Metric::DetectionRate => Some(detections as f64 / elapsed),
+        Metric::ThreatRate => {
+            (detections >= rule.min_count.max(1))
+                .then(|| end.threats.saturating_sub(start.threats) as f64 / detections as f64)
+        }
+        Metric::CacheHitRate => {
+            let hits = to.cache_hits.saturating_sub(from.cache_hits);
+            let served = hits + detections;
+            (served >= rule.min_count.max(1)).then(|| hits as f64 / served as f64)
+        }
+        Metric::P99LatencyMs => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use std::sync::Arc;
+
+    fn rule(threshold: Option<f64>, factor: Option<f64>) -> AlertRule {
+        AlertRule {
+            name: "threats".to_string(),
+            metric: Metric::ThreatRate,
+            threat_type: None,
+            condition: Condition::Above,
+            threshold,
+            factor,
+            window_secs: 60,
+            baseline_secs: 600,
+            for_secs: 30,
+            hysteresis: 0.1,
+            min_count: 1,
+        }
+    }
+
+    /// Cumulative counts at the clock's current time
+    fn sample(clock: &ManualClock, detections: u64, threats: u64) -> Sample {
+        Sample { at: clock.now(), detections, threats, cache_hits: 0, by_type: HashMap::new(), p99_latency_ms: None }
+    }
+
+    fn fired(transitions: &[Transition]) -> bool {
+        matches!(transitions, [Transition::Fired(_)])
+    }
+
+    fn resolved(transitions: &[Transition]) -> bool {
+        matches!(transitions, [Transition::Resolved(_)])
+    }
+
+    #[test]
+    fn threshold_alert_holds_for_its_window_and_clears_past_hysteresis() {
+        let clock = Arc::new(ManualClock::new());
+        let monitor = Monitor::new(clock.clone());
+        let rules = [rule(Some(0.5), None)];
+        let step = |secs: u64, detections: u64, threats: u64| {
+            clock.advance(Duration::from_secs(secs));
+            monitor.evaluate(sample(&clock, detections, threats), &rules)
+        };
+
+        assert!(step(0, 0, 0).is_empty());
+        // 80% threats, but not yet for `for_secs`
+        assert!(step(10, 10, 8).is_empty());
+        assert!(fired(&step(30, 40, 32)));
+        assert_eq!(monitor.active().len(), 1);
+
+        // 47% over the last minute: under the limit, but not by the hysteresis margin
+        assert!(step(30, 110, 55).is_empty());
+        assert_eq!(monitor.active()[0].value, 0.47);
+        // The early threats have left the window
+        assert!(step(30, 200, 55).is_empty());
+        assert!(resolved(&step(30, 300, 55)));
+        assert!(monitor.active().is_empty());
+    }
+
+    #[test]
+    fn factor_alert_fires_on_a_jump_over_the_baseline_and_resolves_after() {
+        let clock = Arc::new(ManualClock::new());
+        let monitor = Monitor::new(clock.clone());
+        let rules = [rule(None, Some(3.0))];
+        let (mut detections, mut threats) = (0, 0);
+        let mut step = |secs: u64, new: u64, new_threats: u64| {
+            clock.advance(Duration::from_secs(secs));
+            (detections, threats) = (detections + new, threats + new_threats);
+            monitor.evaluate(sample(&clock, detections, threats), &rules)
+        };
+
+        // Ten minutes at a steady 10% threat rate
+        assert!(step(0, 0, 0).is_empty());
+        for _ in 0..10 {
+            assert!(step(60, 100, 10).is_empty());
+        }
+        // 80% is well over three times the baseline, once it has held for `for_secs`
+        assert!(step(30, 50, 40).is_empty());
+        let transitions = step(30, 50, 40);
+        let Some(Transition::Fired(alert)) = transitions.first() else { panic!("rate jump did not fire") };
+        assert_eq!(alert.value, 0.8);
+        assert_eq!(alert.limit, 3.0 * alert.baseline.unwrap());
+
+        // Back to the usual rate
+        assert!(step(60, 100, 10).is_empty());
+        assert!(resolved(&step(30, 50, 5)));
+        assert!(monitor.active().is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+// rust/api/src/audit.rs
+//! Audit log for administrative changes and detection alerts
+//!
+//! Records are emitted on the `audit` log target and, when `audit_log_path`
+//! is configured, appended to that file as JSON lines.
+//!
+//! File appends go through a buffer of `audit_buffer_capacity` records,
+//! drained by a writer thread, so a slow disk does not hold up requests
+//! until the buffer is full. What happens then is `audit_overflow`:
+//! `drop_oldest` discards the oldest buffered record and counts it, and
+//! `block` holds the recording request until the writer makes room.
+//! On shutdown the writer is given up to `FLUSH_TIMEOUT` to empty it.
+//!
+//! Records are made from inside handlers, on the async workers, so a full
+//! buffer never blocks the recording thread. Under `block` the record is
+//! buffered past capacity and the [`backpressure`] middleware awaits room
+//! before the request's response goes out, leaving the worker free to
+//! serve others meanwhile. Records made outside a request, by background
+//! tasks, go past capacity without being held.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::clock::SharedClock;
+use crate::AppState;
+
+/// Longest wait for buffered records to be written on shutdown
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    /// Set when the request being served buffered a record past capacity
+    static OVERRAN: Cell<bool>;
+}
+
+/// What a full buffer does with one more record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Discard the oldest buffered record, counted in `dropped`
+    #[default]
+    DropOldest,
+    /// Hold the request until the writer makes room, counted in `blocked`
+    Block,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct AuditStats {
+    pub capacity: usize,
+    pub overflow: Overflow,
+    /// Records waiting for the writer
+    pub buffered: usize,
+    pub written: u64,
+    /// Records discarded from a full buffer
+    pub dropped: u64,
+    /// Requests held for room after overrunning a full buffer
+    pub blocked: u64,
+}
+
+#[derive(Default)]
+struct Buffer {
+    records: VecDeque<String>,
+    /// Records taken by the writer and not yet written
+    writing: usize,
+    written: u64,
+    dropped: u64,
+    blocked: u64,
+}
+
+struct Queue {
+    buffer: Mutex<Buffer>,
+    /// Signalled when a record is buffered
+    filled: Condvar,
+    /// Signalled when the writer takes records out
+    drained: Condvar,
+    /// Wakes held requests when the writer takes records out
+    made_room: Notify,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Queue {
+    fn push(&self, record: String) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.records.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    buffer.records.pop_front();
+                    buffer.dropped += 1;
+                }
+                Overflow::Block => {
+                    // Not outside a request: there is no one to hold
+                    let _ = OVERRAN.try_with(|overran| overran.set(true));
+                }
+            }
+        }
+        buffer.records.push_back(record);
+        self.filled.notify_one();
+    }
+
+    /// Wait, without blocking the thread, for the buffer to be under capacity
+    async fn room(&self) {
+        loop {
+            let mut made_room = std::pin::pin!(self.made_room.notified());
+            // Registered before the check, so a drain in between still wakes it
+            made_room.as_mut().enable();
+            if self.buffer.lock().unwrap().records.len() < self.capacity {
+                return;
+            }
+            made_room.await;
+        }
+    }
+
+    /// Note the writer took records out
+    fn notify_drained(&self) {
+        self.drained.notify_all();
+        self.made_room.notify_waiters();
+    }
+
+    /// Append buffered records to `file` as they arrive; never returns
+    fn drain_into(&self, mut file: File) {
+        loop {
+            let records = {
+                let buffer = self.buffer.lock().unwrap();
+                let mut buffer = self.filled.wait_while(buffer, |b| b.records.is_empty()).unwrap();
+                let records = std::mem::take(&mut buffer.records);
+                buffer.writing = records.len();
+                self.notify_drained();
+                records
+            };
+            for record in records {
+                if let Err(e) = writeln!(file, "{}", record) {
+                    warn!("Failed to write audit record: {}", e);
+                }
+            }
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.written += std::mem::take(&mut buffer.writing) as u64;
+            self.drained.notify_all();
+        }
+    }
+}
+
+pub struct AuditLog {
+    queue: Option<Arc<Queue>>,
+    clock: SharedClock,
+    /// Hash of the engine state records are written under
+    engine_state_hash: Mutex<Option<String>>,
+}
+
+impl AuditLog {
+    /// Open `path` for appending and start its writer thread
+    pub fn open(path: Option<&Path>, capacity: usize, overflow: Overflow, clock: SharedClock) -> std::io::Result<Self> {
+        let queue = match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let queue = Arc::new(Queue {
+                    buffer: Mutex::default(),
+                    filled: Condvar::new(),
+                    drained: Condvar::new(),
+                    made_room: Notify::new(),
+                    capacity: capacity.max(1),
+                    overflow,
+                });
+                let writer = queue.clone();
+                std::thread::Builder::new().name("audit".to_string()).spawn(move || writer.drain_into(file))?;
+                Some(queue)
+            }
+            None => None,
+        };
+        Ok(Self { queue, clock, engine_state_hash: Mutex::new(None) })
+    }
+
+    /// Stamp later records with a new engine state
+    pub fn set_engine_state(&self, hash: &str) {
+        *self.engine_state_hash.lock().unwrap() = Some(hash.to_string());
+    }
+
+    /// Record an event performed by `actor`
+    pub fn record(&self, event: &str, actor: &str, details: Value) {
+        let record = json!({
+            "timestamp": self.clock.utc().with_timezone(&chrono::Local).to_rfc3339(),
+            "event": event,
+            "actor": actor,
+            "details": details,
+            "engine_state_hash": *self.engine_state_hash.lock().unwrap(),
+        });
+        info!(target: "audit", "{}", record);
+
+        if let Some(queue) = &self.queue {
+            queue.push(record.to_string());
+        }
+    }
+
+    /// Serve `request`, then, if it buffered a record past capacity under
+    /// `block`, wait for the writer to make room before returning
+    pub async fn hold<F: Future>(&self, request: F) -> F::Output {
+        let Some(queue) = self.queue.as_ref().filter(|q| q.overflow == Overflow::Block) else {
+            return request.await;
+        };
+        let (output, overran) = OVERRAN.scope(Cell::new(false), async { (request.await, OVERRAN.with(Cell::get)) }).await;
+        if overran {
+            queue.buffer.lock().unwrap().blocked += 1;
+            queue.room().await;
+        }
+        output
+    }
+
+    /// Wait for buffered records to reach the file, up to `FLUSH_TIMEOUT`
+    pub fn flush(&self) {
+        let Some(queue) = &self.queue else { return };
+        let buffer = queue.buffer.lock().unwrap();
+        let (buffer, timeout) = queue
+            .drained
+            .wait_timeout_while(buffer, FLUSH_TIMEOUT, |b| !b.records.is_empty() || b.writing > 0)
+            .unwrap();
+        if timeout.timed_out() {
+            warn!("{} audit record(s) not written before shutdown", buffer.records.len() + buffer.writing);
+        }
+    }
+
+    /// Buffer counters, present when records go to a file
+    pub fn stats(&self) -> Option<AuditStats> {
+        let queue = self.queue.as_ref()?;
+        let buffer = queue.buffer.lock().unwrap();
+        Some(AuditStats {
+            capacity: queue.capacity,
+            overflow: queue.overflow,
+            buffered: buffer.records.len(),
+            written: buffer.written,
+            dropped: buffer.dropped,
+            blocked: buffer.blocked,
+        })
+    }
+}
+
+/// Middleware holding a request's response while a record it made sits
+/// past the capacity of a full `block` buffer
+pub async fn backpressure(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    match req.app_data::<web::Data<AppState>>().cloned() {
+        Some(state) => state.audit.hold(next.call(req)).await,
+        None => next.call(req).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+
+    /// A queue with no writer behind it, drained by hand
+    fn queue(capacity: usize, overflow: Overflow) -> Arc<Queue> {
+        Arc::new(Queue {
+            buffer: Mutex::default(),
+            filled: Condvar::new(),
+            drained: Condvar::new(),
+            made_room: Notify::new(),
+            capacity,
+            overflow,
+        })
+    }
+
+    fn buffered(queue: &Queue) -> Vec<String> {
+        queue.buffer.lock().unwrap().records.iter().cloned().collect()
+    }
+
+    #[test]
+    fn a_full_buffer_drops_its_oldest_records_and_counts_them() {
+        let queue = queue(3, Overflow::DropOldest);
+        for n in 0..5 {
+            queue.push(n.to_string());
+        }
+        assert_eq!(buffered(&queue), ["2", "3", "4"]);
+        let buffer = queue.buffer.lock().unwrap();
+        assert_eq!((buffer.dropped, buffer.blocked), (2, 0));
+    }
+
+    #[actix_web::test]
+    async fn a_full_buffer_holds_the_overrunning_request_until_the_writer_makes_room() {
+        let queue = queue(2, Overflow::Block);
+        let audit = AuditLog { queue: Some(queue.clone()), clock: Arc::new(crate::clock::SystemClock), engine_state_hash: Mutex::new(None) };
+        let record = |n: u64| audit.record("test.event", "tester", json!({ "n": n }));
+        audit.hold(async { record(0) }).await;
+        audit.hold(async { record(1) }).await;
+
+        // The record goes in past capacity and its request waits, without blocking the thread
+        let mut held = std::pin::pin!(audit.hold(async { record(2) }));
+        assert!(poll!(held.as_mut()).is_pending());
+        assert_eq!(buffered(&queue).len(), 3);
+        assert_eq!(queue.buffer.lock().unwrap().blocked, 1);
+        // Nor is a record made outside a request held
+        record(3);
+        assert_eq!(buffered(&queue).len(), 4);
+
+        // What the writer does when it takes a batch
+        let taken = std::mem::take(&mut queue.buffer.lock().unwrap().records);
+        queue.notify_drained();
+        held.await;
+        assert_eq!(taken.len(), 4);
+        let buffer = queue.buffer.lock().unwrap();
+        assert_eq!((buffer.dropped, buffer.blocked), (0, 1));
+    }
+
+    #[test]
+    fn records_reach_the_file_and_are_counted_as_written() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = AuditLog::open(Some(&path), 4, Overflow::Block, Arc::new(crate::clock::SystemClock)).unwrap();
+        for n in 0..10 {
+            audit.record("test.event", "tester", json!({ "n": n }));
+        }
+        audit.flush();
+        let lines: Vec<Value> =
+            std::fs::read_to_string(&path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(lines.iter().map(|l| l["details"]["n"].as_u64().unwrap()).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        let stats = audit.stats().unwrap();
+        assert_eq!((stats.buffered, stats.written, stats.dropped), (0, 10, 0));
+    }
+}
@@ -0,0 +1,369 @@
+// rust/api/src/auth.rs
+//! API key authentication
+//!
+//! Keys come from `api_keys` (`API_KEYS`, comma-separated, in the
+//! environment) and from `api_keys_file`, one per line with `#` comments.
+//! With no keys configured every request is let through, for local
+//! development. Otherwise every route but those in `UNAUTHENTICATED` needs
+//! one of the keys, as `Authorization: Bearer <key>` or `X-Api-Key: <key>`.
+//!
+//! Keys are held as SHA-256 digests, and a presented key is compared with
+//! each of them without revealing how much of a digest matched.
+//!
+//! A browser cannot put a key on the dashboard's own requests, and
+//! `EventSource` cannot set headers at all. So the dashboard page is served
+//! without a key, and `POST /dashboard/session` with a valid key opens a
+//! session, whose random id (never the key) is set as the `SESSION_COOKIE`,
+//! HttpOnly and SameSite=Strict. Sessions last `SESSION_TTL` and live only
+//! in memory, at most `MAX_SESSIONS` of them. The cookie is only accepted on
+//! `GET` of the routes in `COOKIE_ROUTES`, which the dashboard reads, so it
+//! can change nothing.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use lru::LruCache;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::config::Settings;
+
+/// Routes load balancers and orchestrators probe without credentials, and
+/// the dashboard page, which holds no data of its own
+pub const UNAUTHENTICATED: &[&str] = &["/api/health", "/api/ready", "/dashboard"];
+
+/// Cookie carrying a dashboard session's id
+pub const SESSION_COOKIE: &str = "session";
+
+/// How long a dashboard session is accepted after it opens
+pub const SESSION_TTL: Duration = Duration::from_secs(12 * 3600);
+
+/// Sessions kept at once; past it the one opened longest ago is dropped
+pub const MAX_SESSIONS: usize = 1024;
+
+/// Read-only routes the dashboard draws from, where `SESSION_COOKIE` counts
+pub const COOKIE_ROUTES: &[&str] = &["/api/stats", "/api/stats/stream", "/api/admin/cache"];
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Neither header carried a key
+    Missing,
+    /// A key was given but is not configured, or a session cookie that is
+    /// not open
+    Invalid,
+}
+
+impl Rejection {
+    pub fn message(self) -> &'static str {
+        match self {
+            Rejection::Missing => "Missing API key",
+            Rejection::Invalid => "Invalid API key",
+        }
+    }
+}
+
+pub struct ApiKeys {
+    digests: Vec<[u8; 32]>,
+    /// Open dashboard sessions, by id, with when they opened
+    sessions: Mutex<LruCache<String, Instant>>,
+    #[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+    rng: SystemRandom,
+    clock: SharedClock,
+}
+
+impl ApiKeys {
+    /// Keys from the settings and `api_keys_file`
+    pub fn load(settings: &Settings, clock: SharedClock) -> io::Result<Self> {
+        let mut keys = settings.api_keys.clone();
+        if let Some(path) = &settings.api_keys_file {
+            keys.extend(load_file(path)?);
+        }
+        Ok(Self {
+            digests: digests(&keys),
+            sessions: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_SESSIONS).unwrap())),
+            rng: SystemRandom::new(),
+            clock,
+        })
+    }
+
+    /// Whether requests need a key
+    pub fn enabled(&self) -> bool {
+        !self.digests.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// Whether a `method` request for `path` with `headers` may proceed
+    pub fn check(&self, method: &Method, path: &str, headers: &HeaderMap) -> Result<(), Rejection> {
+        if !self.enabled() || UNAUTHENTICATED.contains(&path) {
+            return Ok(());
+        }
+        if let Some(key) = presented(headers) {
+            return self.accepts(key).then_some(()).ok_or(Rejection::Invalid);
+        }
+        match (*method == Method::GET && COOKIE_ROUTES.contains(&path)).then(|| session_cookie(headers)).flatten() {
+            Some(id) if self.in_session(&id) => Ok(()),
+            Some(_) => Err(Rejection::Invalid),
+            None => Err(Rejection::Missing),
+        }
+    }
+
+    /// Whether `key` is one of the configured keys
+    pub fn accepts(&self, key: &str) -> bool {
+        contains(&self.digests, key)
+    }
+
+    /// Open a dashboard session, returning its id
+    #[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+    pub fn open_session(&self) -> String {
+        let mut id = [0u8; 32];
+        let _ = self.rng.fill(&mut id);
+        let id = hex::encode(id);
+        self.sessions.lock().unwrap().put(id.clone(), self.clock.now());
+        id
+    }
+
+    /// Whether `id` names a session that has not expired
+    fn in_session(&self, id: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.peek(id) {
+            Some(opened) if self.clock.now().saturating_duration_since(*opened) < SESSION_TTL => true,
+            Some(_) => {
+                sessions.pop(id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// A short, non-reversible name for the configured key a request carries
+    pub fn key_id(&self, headers: &HeaderMap) -> Option<String> {
+        let key = presented(headers).filter(|key| self.accepts(key))?;
+        Some(hex::encode(&digest(key)[..8]))
+    }
+}
+
+/// Sorted digests of the non-blank `keys`
+fn digests(keys: &[String]) -> Vec<[u8; 32]> {
+    let mut digests: Vec<[u8; 32]> = keys
+        .iter()
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .map(digest)
+        .collect();
+    digests.sort_unstable();
+    digests.dedup();
+    digests
+}
+
+/// Whether `key`'s digest is among `digests`
+fn contains(digests: &[[u8; 32]], key: &str) -> bool {
+    let given = digest(key);
+    // Every digest is compared, so timing does not tell which one matched
+    digests.iter().fold(false, |found, expected| found | digests_match(&given, expected))
+}
+
+/// Keys in `path`, one per line; blank lines and `#` comments are skipped
+pub fn load_file(path: &Path) -> io::Result<Vec<String>> {
+    let text = fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Middleware turning away requests without a valid key, with 401; the
+/// keys are the app's `web::Data<ApiKeys>`, and without them all pass.
+/// Routes are checked on the percent-decoded path the router matches.
+pub async fn require_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let rejection = req
+        .app_data::<web::Data<ApiKeys>>()
+        .and_then(|keys| keys.check(req.method(), req.match_info().as_str(), req.headers()).err());
+    match rejection {
+        Some(rejection) => Ok(req.into_response(unauthorized(rejection)).map_into_right_body()),
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+pub fn unauthorized(rejection: Rejection) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((WWW_AUTHENTICATE, "Bearer"))
+        .json(serde_json::json!({ "error": rejection.message() }))
+}
+
+/// The key from a bearer `Authorization` header, or else `X-Api-Key`
+pub fn presented(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| {
+        let (scheme, token) = v.trim().split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    });
+    bearer
+        .or_else(|| headers.get("X-Api-Key").and_then(|v| v.to_str().ok()).map(str::trim))
+        .filter(|key| !key.is_empty())
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(actix_web::http::header::COOKIE)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn digests_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use std::sync::Arc;
+
+    const KEY: &str = "k-0123456789";
+
+    fn keys_on(keys: &[&str], clock: SharedClock) -> ApiKeys {
+        let settings = Settings { api_keys: keys.iter().map(|k| k.to_string()).collect(), ..Settings::default() };
+        ApiKeys::load(&settings, clock).unwrap()
+    }
+
+    /// Response to `req` from an app guarded by `keys`
+    async fn respond_with(keys: ApiKeys, req: TestRequest) -> ServiceResponse {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(keys))
+                .wrap(actix_web::middleware::from_fn(require_key))
+                .route("/api/health", web::get().to(HttpResponse::Ok))
+                .route("/api/stats", web::get().to(HttpResponse::Ok))
+                .route("/api/detect", web::post().to(HttpResponse::Ok))
+                .route("/api/admin/chaos", web::put().to(HttpResponse::Ok)),
+        )
+        .await;
+        call_service(&app, req.to_request()).await.map_into_boxed_body()
+    }
+
+    /// Response to `req` from an app guarded by `keys`
+    async fn respond(keys: &[&str], req: TestRequest) -> ServiceResponse {
+        respond_with(keys_on(keys, Arc::new(ManualClock::new())), req).await
+    }
+
+    async fn status(keys: &[&str], req: TestRequest) -> StatusCode {
+        respond(keys, req).await.status()
+    }
+
+    #[actix_web::test]
+    async fn missing_key_is_rejected() {
+        let req = TestRequest::post().uri("/api/detect");
+        assert_eq!(status(&[KEY], req).await, StatusCode::UNAUTHORIZED);
+        let req = TestRequest::put().uri("/api/admin/chaos");
+        assert_eq!(status(&[KEY], req).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn wrong_key_is_rejected() {
+        let req = TestRequest::post().uri("/api/detect").insert_header(("X-Api-Key", "k-wrong"));
+        assert_eq!(status(&[KEY], req).await, StatusCode::UNAUTHORIZED);
+        let req = TestRequest::post().uri("/api/detect").insert_header((AUTHORIZATION, "Basic k-0123456789"));
+        assert_eq!(status(&[KEY], req).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn valid_key_is_accepted_in_either_header() {
+        let req = TestRequest::post().uri("/api/detect").insert_header(("X-Api-Key", KEY));
+        assert_eq!(status(&[KEY], req).await, StatusCode::OK);
+        let req = TestRequest::post().uri("/api/detect").insert_header((AUTHORIZATION, format!("Bearer {}", KEY)));
+        assert_eq!(status(&[KEY], req).await, StatusCode::OK);
+        let req = TestRequest::put().uri("/api/admin/chaos").insert_header(("X-Api-Key", KEY));
+        assert_eq!(status(&[KEY], req).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn rejection_asks_for_a_bearer_token() {
+        let response = respond(&[KEY], TestRequest::post().uri("/api/detect")).await;
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+    }
+
+    #[actix_web::test]
+    async fn probes_need_no_key() {
+        assert_eq!(status(&[KEY], TestRequest::get().uri("/api/health")).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn no_configured_keys_lets_everything_through() {
+        assert_eq!(status(&[], TestRequest::post().uri("/api/detect")).await, StatusCode::OK);
+        assert_eq!(status(&[], TestRequest::get().uri("/api/stats")).await, StatusCode::OK);
+        assert_eq!(status(&[], TestRequest::put().uri("/api/admin/chaos")).await, StatusCode::OK);
+        assert_eq!(status(&[], TestRequest::put().uri("/api/%61dmin/chaos")).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn session_cookie_only_reads_dashboard_routes() {
+        let clock = Arc::new(ManualClock::new());
+        let keys = web::Data::new(keys_on(&[KEY], clock.clone()));
+        let cookie = format!("{}={}", SESSION_COOKIE, keys.open_session());
+        let app = init_service(
+            App::new()
+                .app_data(keys.clone())
+                .wrap(actix_web::middleware::from_fn(require_key))
+                .route("/api/stats", web::get().to(HttpResponse::Ok))
+                .route("/api/detect", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let stats = |cookie: &str| TestRequest::get().uri("/api/stats").insert_header(("Cookie", cookie.to_string())).to_request();
+        assert_eq!(call_service(&app, stats(&cookie)).await.status(), StatusCode::OK);
+        let req = TestRequest::post().uri("/api/detect").insert_header(("Cookie", cookie.clone())).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        // The key itself is not a session
+        let req = stats(&format!("{}={}", SESSION_COOKIE, KEY));
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        clock.advance(SESSION_TTL);
+        assert_eq!(call_service(&app, stats(&cookie)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn sessions_are_random_and_never_the_key() {
+        let keys = keys_on(&[KEY], Arc::new(ManualClock::new()));
+        let (a, b) = (keys.open_session(), keys.open_session());
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 64);
+        assert!(!a.contains(KEY));
+    }
+
+    #[test]
+    fn key_id_names_only_configured_keys() {
+        let keys = keys_on(&[KEY], Arc::new(ManualClock::new()));
+        let headers = |key: &str| TestRequest::default().insert_header(("X-Api-Key", key)).to_http_request().headers().clone();
+        assert!(keys.key_id(&headers(KEY)).is_some());
+        assert_eq!(keys.key_id(&headers("k-made-up")), None);
+    }
+}
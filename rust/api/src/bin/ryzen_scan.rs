@@ -0,0 +1,88 @@
+// rust/api/src/bin/ryzen_scan.rs
+//! Offline tooling for the security API
+//!
+//! - `ryzen-scan keygen <key.pk8>` writes a new Ed25519 signing key and
+//!   prints its public half.
+//! - `ryzen-scan verify <response.json> <public-key>` checks the signature
+//!   of a stored detection response against a base64 public key, as served
+//!   by `GET /api/signing-key`.
+//! - `ryzen-scan preflight --config <path>` checks a configuration and the
+//!   files it names without starting the server. It runs the server binary
+//!   installed alongside with `--preflight`, prints its JSON report and
+//!   exits with its status: 78 for config errors, 74 for environment errors.
+
+use std::fs;
+use std::process::{Command, ExitCode};
+
+// Shared with the API server, which uses the signing half
+#[allow(dead_code)]
+#[path = "../canonical.rs"]
+mod canonical;
+#[allow(dead_code)]
+#[path = "../signing.rs"]
+mod signing;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let ["preflight", "--config", config] = args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        return preflight(config);
+    }
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["keygen", path] => keygen(path),
+        ["verify", response, public_key] => verify(response, public_key),
+        _ => Err("usage: ryzen-scan keygen <key.pk8> | ryzen-scan verify <response.json> <public-key> \
+                  | ryzen-scan preflight --config <path>"
+            .to_string()),
+    };
+
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn keygen(path: &str) -> Result<String, String> {
+    let pkcs8 = signing::generate_pkcs8().map_err(|e| e.to_string())?;
+    fs::write(path, &pkcs8).map_err(|e| format!("{}: {}", path, e))?;
+    let signer = signing::Signer::load(path.as_ref(), String::new()).map_err(|e| e.to_string())?;
+    Ok(format!("Wrote {}; public key {}", path, signer.public_key()))
+}
+
+fn verify(path: &str, public_key: &str) -> Result<String, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let response: serde_json::Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let signature: signing::VerdictSignature = match response.get("signature") {
+        Some(signature) => serde_json::from_value(signature.clone()).map_err(|e| format!("invalid signature: {}", e))?,
+        None => return Err(format!("{}: response is not signed", path)),
+    };
+    let verdict: signing::Verdict =
+        serde_json::from_value(response).map_err(|e| format!("{}: not a detection response: {}", path, e))?;
+
+    signing::verify(&verdict, &signature, public_key)?;
+    Ok(format!(
+        "Valid signature (key id {}, detection id {}, signed at {})",
+        signature.key_id, signature.detection_id, signature.signed_at
+    ))
+}
+
+/// Run the server's preflight against `config`, passing its exit status through
+fn preflight(config: &str) -> ExitCode {
+    let server = std::env::current_exe().ok().and_then(|exe| Some(exe.parent()?.join("amd-security-api")));
+    let Some(server) = server.filter(|path| path.exists()) else {
+        eprintln!("amd-security-api was not found next to ryzen-scan");
+        return ExitCode::FAILURE;
+    };
+    match Command::new(&server).arg("--preflight").env("API_CONFIG", config).status() {
+        Ok(status) => ExitCode::from(status.code().and_then(|code| u8::try_from(code).ok()).unwrap_or(1)),
+        Err(e) => {
+            eprintln!("{}: {}", server.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// rust/api/src/brand_assets.rs
+//! Brand asset table for impersonation checks on HTML pages
+//!
+//! `brand_assets.json` in the data directory lists protected brands with
+//! their own domains, page-title keywords, and SHA-256 hashes of their
+//! normalized titles and favicon bytes. A page that reuses a brand's assets
+//! while being served from some other registrable domain is impersonating
+//! it. The page's address and favicon come from the request context
+//! (`https://... favicon=<base64>`). The table is loaded at startup and can be reloaded through the admin
+//! API; with no file present nothing is checked. The loaded table keeps the
+//! size and SHA-256 of its file for the engine state manifest.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::domain;
+
+pub const ASSETS_FILE_NAME: &str = "brand_assets.json";
+
+/// Assets identifying one protected brand
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BrandAssets {
+    pub name: String,
+    /// Registrable domains the brand legitimately serves from
+    pub domains: Vec<String>,
+    /// Keywords that identify the brand in a page title
+    pub title_keywords: Vec<String>,
+    /// SHA-256 hex of the normalized title of the brand's own pages
+    pub title_hashes: Vec<String>,
+    /// SHA-256 hex of the brand's favicon bytes
+    pub favicon_hashes: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AssetTable {
+    brands: Vec<BrandAssets>,
+}
+
+static TABLE: LazyLock<RwLock<Arc<Vec<BrandAssets>>>> = LazyLock::new(Default::default);
+
+/// Size and SHA-256 of the file the table was loaded from
+static DIGEST: RwLock<Option<(usize, String)>> = RwLock::new(None);
+
+/// Size and SHA-256 of the loaded table's file, if one was loaded
+pub fn digest() -> Option<(usize, String)> {
+    DIGEST.read().unwrap().clone()
+}
+
+/// A brand whose assets a page reuses, and which asset matched
+pub struct Impersonation {
+    pub brand: String,
+    pub asset: &'static str,
+}
+
+/// Check a page's title and favicon against the table.
+///
+/// Pages served from one of the brand's own registrable domains never match.
+pub fn check(page_host: &str, title: Option<&str>, favicon: Option<&[u8]>) -> Option<Impersonation> {
+    let table = TABLE.read().unwrap().clone();
+    check_in(&table, page_host, title, favicon)
+}
+
+fn check_in(table: &[BrandAssets], page_host: &str, title: Option<&str>, favicon: Option<&[u8]>) -> Option<Impersonation> {
+    if table.is_empty() {
+        return None;
+    }
+    let page_domain = domain::registrable_domain(page_host).unwrap_or_else(|| page_host.to_lowercase());
+    let title = title.map(normalize_title);
+    let title_hash = title.as_deref().map(sha256_hex);
+    let favicon_hash = favicon.map(sha256_hex);
+
+    table
+        .iter()
+        .filter(|brand| !brand.domains.iter().any(|d| d.eq_ignore_ascii_case(&page_domain)))
+        .find_map(|brand| {
+            let asset = if favicon_hash.as_ref().is_some_and(|h| contains_hash(&brand.favicon_hashes, h)) {
+                "favicon"
+            } else if title_hash.as_ref().is_some_and(|h| contains_hash(&brand.title_hashes, h)) {
+                "title"
+            } else if title.as_ref().is_some_and(|t| {
+                brand.title_keywords.iter().any(|k| t.contains(&k.to_lowercase()))
+            }) {
+                "title keyword"
+            } else {
+                return None;
+            };
+            Some(Impersonation { brand: brand.name.clone(), asset })
+        })
+}
+
+/// Text of the first `<title>` element in an HTML page
+pub fn page_title(html: &str) -> Option<&str> {
+    // ASCII lowercasing keeps byte offsets valid for slicing `html`
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    Some(html[start..end].trim())
+}
+
+/// Host of the first `http(s)://` URL in the request context, taken as the page's address
+pub fn page_host(context: &str) -> Option<String> {
+    context
+        .split_whitespace()
+        .filter(|token| token.starts_with("http://") || token.starts_with("https://"))
+        .find_map(|token| url::Url::parse(token).ok()?.host_str().map(str::to_string))
+}
+
+/// Favicon bytes passed in the context as `favicon=<base64>`
+pub fn favicon(context: &str) -> Option<Vec<u8>> {
+    let encoded = context.split_whitespace().find_map(|token| token.strip_prefix("favicon="))?;
+    BASE64.decode(encoded).ok()
+}
+
+/// Lowercase a title and collapse its whitespace, as hashed in the table
+pub fn normalize_title(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn sha256_hex(bytes: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn contains_hash(hashes: &[String], hash: &str) -> bool {
+    hashes.iter().any(|h| h.eq_ignore_ascii_case(hash))
+}
+
+/// Replace the active table with `brand_assets.json` from `data_dir`.
+///
+/// Returns `Ok(None)` when the directory has no table, leaving the current
+/// one in place, and otherwise the number of brands loaded.
+pub fn load_from_dir(data_dir: &Path) -> io::Result<Option<usize>> {
+    let path = data_dir.join(ASSETS_FILE_NAME);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let table: AssetTable = serde_json::from_str(&text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+
+    let count = table.brands.len();
+    let mut digest = DIGEST.write().unwrap();
+    *TABLE.write().unwrap() = Arc::new(table.brands);
+    *digest = Some((text.len(), sha256_hex(&text)));
+    Ok(Some(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAVICON: &[u8] = b"\x00\x00\x01\x00examplebank-icon";
+
+    fn table() -> Vec<BrandAssets> {
+        vec![BrandAssets {
+            name: "ExampleBank".to_string(),
+            domains: vec!["examplebank.com".to_string()],
+            title_keywords: vec!["ExampleBank".to_string()],
+            title_hashes: vec![sha256_hex(normalize_title("Sign in  to your account"))],
+            favicon_hashes: vec![sha256_hex(FAVICON)],
+        }]
+    }
+
+    fn asset(host: &str, title: Option<&str>, favicon: Option<&[u8]>) -> Option<&'static str> {
+        check_in(&table(), host, title, favicon).map(|found| found.asset)
+    }
+
+    #[test]
+    fn lookalikes_reusing_brand_assets_match_and_the_brand_does_not() {
+        assert_eq!(asset("login.examplebank.com", Some("ExampleBank"), Some(FAVICON)), None);
+        assert_eq!(asset("examplebank-secure.net", None, Some(FAVICON)), Some("favicon"));
+        assert_eq!(asset("examplebank-secure.net", Some("SIGN IN to  your account"), None), Some("title"));
+        assert_eq!(asset("examplebank-secure.net", Some("Welcome to ExampleBank"), None), Some("title keyword"));
+        assert_eq!(asset("examplebank-secure.net", Some("Cat pictures"), Some(b"other icon")), None);
+        assert!(check_in(&[], "examplebank-secure.net", None, Some(FAVICON)).is_none());
+    }
+
+    #[test]
+    fn page_title_host_and_favicon_come_from_the_page_and_context() {
+        assert_eq!(page_title("<HTML><Title lang=en> Sign in </TITLE>"), Some("Sign in"));
+        assert_eq!(page_title("<p>no title</p>"), None);
+        let context = format!("page https://login.examplebank-secure.net/a favicon={}", BASE64.encode(FAVICON));
+        assert_eq!(page_host(&context).as_deref(), Some("login.examplebank-secure.net"));
+        assert_eq!(favicon(&context).as_deref(), Some(FAVICON));
+    }
+}
@@ -0,0 +1,178 @@
+// rust/api/src/bundle.rs
+//! Export and import of operator-managed configuration
+//!
+//! A bundle is one versioned JSON document holding everything operators
+//! change at runtime: thresholds, pipelines, `stat_alerts` rules and tenant
+//! lists, plus the honeytokens. Honeytokens carry the secrets they are
+//! matched by, so they are only exported when a passphrase is supplied and
+//! are then sealed with ChaCha20-Poly1305 under a PBKDF2-derived key.
+//!
+//! Importing checks the whole bundle first and reports every problem with
+//! the JSON pointer of the offending field; only a bundle without problems
+//! is applied.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+
+use crate::anomaly::{self, AlertRule};
+use crate::config::THREAT_TYPES;
+use crate::honeytoken::Honeytoken;
+use crate::lists::DetectionLists;
+use crate::pipeline::{self, Pipelines};
+use crate::thresholds::Thresholds;
+use crate::validation::Violation;
+
+/// Bumped on any incompatible change to the bundle layout
+pub const BUNDLE_VERSION: u32 = 1;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigBundle {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub thresholds: HashMap<String, Thresholds>,
+    pub pipelines: Pipelines,
+    pub stat_alerts: Vec<AlertRule>,
+    /// Lists of each configured tenant
+    pub tenant_lists: BTreeMap<String, DetectionLists>,
+    /// Sealed honeytokens; absent when exported without a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub honeytokens: Option<Sealed>,
+}
+
+/// Passphrase-encrypted payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Key derivation; always `pbkdf2-sha256`
+    pub kdf: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Seal a value's JSON under `passphrase`
+pub fn seal<T: Serialize>(value: &T, passphrase: &str) -> Result<Sealed, String> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).and(rng.fill(&mut nonce)).map_err(|_| "system random source unavailable".to_string())?;
+
+    let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+    let mut buffer = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut buffer)
+        .map_err(|_| "encryption failed".to_string())?;
+    Ok(Sealed {
+        kdf: "pbkdf2-sha256".to_string(),
+        iterations: PBKDF2_ITERATIONS,
+        salt: BASE64.encode(salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(buffer),
+    })
+}
+
+/// Open a sealed value; a wrong passphrase and tampering look the same
+pub fn open<T: serde::de::DeserializeOwned>(sealed: &Sealed, passphrase: &str) -> Result<T, String> {
+    if sealed.kdf != "pbkdf2-sha256" {
+        return Err(format!("unsupported kdf {:?}", sealed.kdf));
+    }
+    let decode = |field: &str, value: &str| BASE64.decode(value).map_err(|e| format!("{}: {}", field, e));
+    let salt = decode("salt", &sealed.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&decode("nonce", &sealed.nonce)?)
+        .map_err(|_| "nonce has the wrong length".to_string())?;
+    let mut buffer = decode("ciphertext", &sealed.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, sealed.iterations)?;
+    let plain = key
+        .open_in_place(nonce, Aad::empty(), &mut buffer)
+        .map_err(|_| "wrong passphrase or corrupted data".to_string())?;
+    serde_json::from_slice(plain).map_err(|e| e.to_string())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, String> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| "iterations must be positive".to_string())?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| "key derivation failed".to_string())?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// A checked bundle, ready to apply
+pub struct ImportPlan {
+    pub bundle: ConfigBundle,
+    /// Opened honeytokens, when the bundle has any
+    pub honeytokens: Option<Vec<Honeytoken>>,
+}
+
+/// Parse and check a bundle against the running instance's tenants
+pub fn check(body: Value, tenants: &[String], passphrase: Option<&str>) -> Result<ImportPlan, Vec<Violation>> {
+    let violation = |pointer: &str, message: String| Violation { pointer: pointer.to_string(), message };
+    let version = body.get("version").and_then(Value::as_u64);
+    if version != Some(BUNDLE_VERSION as u64) {
+        return Err(vec![violation("/version", format!("unsupported bundle version; expected {}", BUNDLE_VERSION))]);
+    }
+    let mut bundle: ConfigBundle = serde_json::from_value(body).map_err(|e| vec![violation("", e.to_string())])?;
+    let mut violations = Vec::new();
+
+    for threat_type in THREAT_TYPES {
+        match bundle.thresholds.get(*threat_type) {
+            Some(thresholds) => {
+                if let Err(e) = thresholds.validate() {
+                    violations.push(violation(&format!("/thresholds/{}", threat_type), e));
+                }
+            }
+            None => violations.push(violation(&format!("/thresholds/{}", threat_type), "is required".to_string())),
+        }
+    }
+    for threat_type in bundle.thresholds.keys().filter(|t| !THREAT_TYPES.contains(&t.as_str())) {
+        violations.push(violation(&format!("/thresholds/{}", threat_type), "unknown threat type".to_string()));
+    }
+    if let Err(e) = pipeline::validate(&bundle.pipelines) {
+        violations.push(violation("/pipelines", e));
+    }
+    if let Err(e) = anomaly::validate(&bundle.stat_alerts) {
+        violations.push(violation("/stat_alerts", e));
+    }
+    for (tenant, lists) in &mut bundle.tenant_lists {
+        if !tenants.contains(tenant) {
+            violations.push(violation(&format!("/tenant_lists/{}", tenant), "tenant is not configured here".to_string()));
+        }
+        for name in crate::tenant::LIST_NAMES {
+            if let Some(list) = lists.list_mut(name) {
+                list.iter_mut().for_each(|e| *e = e.trim().to_string());
+                list.retain(|e| !e.is_empty());
+            }
+        }
+    }
+
+    let honeytokens = match (&bundle.honeytokens, passphrase) {
+        (None, _) => None,
+        (Some(_), None) => {
+            violations.push(violation("/honeytokens", "sealed; supply the passphrase in X-Bundle-Passphrase".to_string()));
+            None
+        }
+        (Some(sealed), Some(passphrase)) => match open(sealed, passphrase) {
+            Ok(tokens) => Some(tokens),
+            Err(e) => {
+                violations.push(violation("/honeytokens", e));
+                None
+            }
+        },
+    };
+
+    if violations.is_empty() {
+        Ok(ImportPlan { bundle, honeytokens })
+    } else {
+        Err(violations)
+    }
+}
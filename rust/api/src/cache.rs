@@ -0,0 +1,617 @@
+// rust/api/src/cache.rs
+//! Detection result cache entries
+//!
+//! Entries whose serialized form exceeds the configured threshold are stored
+//! zstd-compressed and decompressed on read, trading a little CPU on large
+//! hits for a much smaller resident cache. Other entries are shared, so a
+//! hit only takes a reference while the cache is locked, and the copy the
+//! caller fills in per-request fields on is made after it is released.
+//!
+//! Each entry also carries a verdict summary and hit bookkeeping so the
+//! admin listing can describe entries without decoding them or exposing the
+//! content they were computed from.
+//!
+//! Each entry lives for the TTL of the request threat type it was computed
+//! for: its `cache_ttl_secs_by_type` entry, or `cache_ttl_secs`. Entries
+//! older than that are misses when looked up and are removed then. Entries
+//! computed under another ruleset version or an earlier rules generation
+//! can never be looked up again, as both are part of the key. Both kinds
+//! are also removed by a periodic sweep rather than waiting for LRU
+//! pressure.
+//!
+//! Entries also record the recurrence indicator of their request, so an
+//! indicator escalating or its escalation expiring evicts only the verdicts
+//! computed for it.
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::config::Settings;
+use crate::recurrence::Indicator;
+use crate::ThreatDetectionResponse;
+
+/// zstd level used for cache entries; favours speed over ratio
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// Cached detection result
+#[derive(Clone, Debug)]
+pub struct CachedResult {
+    stored: Stored,
+    summary: VerdictSummary,
+    /// Request threat type, which sets the entry's TTL
+    request_type: String,
+    ruleset_version: String,
+    rules_generation: u64,
+    /// Recurrence indicator of the request, when recurrence is on
+    indicator: Option<Indicator>,
+    created: Instant,
+    created_at: DateTime<Utc>,
+    last_hit_at: Option<DateTime<Utc>>,
+    hit_count: u64,
+}
+
+/// Verdict fields safe to show without the content
+#[derive(Clone, Debug, Serialize)]
+pub struct VerdictSummary {
+    pub threat_type: String,
+    pub is_threat: bool,
+    pub severity: String,
+    pub confidence: f32,
+}
+
+#[derive(Clone, Debug)]
+enum Stored {
+    Plain(ThreatDetectionResponse),
+    Compressed(Vec<u8>),
+}
+
+impl CachedResult {
+    /// Build a cache entry, compressing it when enabled and large enough
+    pub fn new(
+        response: ThreatDetectionResponse,
+        request_type: &str,
+        settings: &Settings,
+        ruleset_version: &str,
+        rules_generation: u64,
+        clock: &dyn Clock,
+    ) -> Self {
+        let (stored, summary) = store(response, settings);
+        Self {
+            stored,
+            summary,
+            request_type: request_type.to_string(),
+            ruleset_version: ruleset_version.to_string(),
+            rules_generation,
+            indicator: None,
+            created: clock.now(),
+            created_at: clock.utc(),
+            last_hit_at: None,
+            hit_count: 0,
+        }
+    }
+
+    /// Build an entry replicated from another instance, aged as it was there
+    pub fn replicated(
+        response: ThreatDetectionResponse,
+        request_type: &str,
+        settings: &Settings,
+        ruleset_version: &str,
+        rules_generation: u64,
+        created_at: DateTime<Utc>,
+        clock: &dyn Clock,
+    ) -> Self {
+        let mut entry = Self::new(response, request_type, settings, ruleset_version, rules_generation, clock);
+        let age = (clock.utc() - created_at).to_std().unwrap_or_default();
+        entry.created = entry.created.checked_sub(age).unwrap_or(entry.created);
+        entry.created_at = created_at;
+        entry
+    }
+
+    /// Tag the entry with the recurrence indicator of its request
+    pub fn with_indicator(mut self, indicator: Option<Indicator>) -> Self {
+        self.indicator = indicator;
+        self
+    }
+
+    /// Swap in a later verdict for the same request, keeping the entry's age
+    pub fn replace(&mut self, response: ThreatDetectionResponse, settings: &Settings) {
+        (self.stored, self.summary) = store(response, settings);
+    }
+
+    /// Note that the entry was served from the cache
+    pub fn record_hit(&mut self, clock: &dyn Clock) {
+        self.hit_count += 1;
+        self.last_hit_at = Some(clock.utc());
+    }
+
+    pub fn request_type(&self) -> &str {
+        &self.request_type
+    }
+
+    pub fn ruleset_version(&self) -> &str {
+        &self.ruleset_version
+    }
+
+    pub fn rules_generation(&self) -> u64 {
+        self.rules_generation
+    }
+
+    pub fn indicator(&self) -> Option<&Indicator> {
+        self.indicator.as_ref()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn is_threat(&self) -> bool {
+        self.summary.is_threat
+    }
+
+    /// Rough bytes held by the entry
+    pub fn estimated_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.summary.threat_type.len()
+            + self.summary.severity.len()
+            + self.ruleset_version.len()
+            + match &self.stored {
+                Stored::Plain(response) => response.estimated_bytes(),
+                Stored::Compressed(bytes) => bytes.len(),
+            }
+    }
+
+    fn age_secs(&self, now: Instant) -> u64 {
+        now.duration_since(self.created).as_secs()
+    }
+
+    /// Whether the entry has outlived its threat type's TTL
+    pub fn is_expired(&self, settings: &Settings, now: Instant) -> bool {
+        ttl(settings, &self.request_type).is_some_and(|ttl| now.duration_since(self.created) >= ttl)
+    }
+
+    /// The cached response, or `None` if a compressed entry failed to decode
+    pub fn response(&self) -> Option<ThreatDetectionResponse> {
+        match &self.stored {
+            Stored::Plain(response) => Some(response.clone()),
+            Stored::Compressed(bytes) => match decompress(bytes) {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    warn!("Discarding undecodable cache entry: {}", e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// A response as held in an entry, with its summary
+fn store(response: ThreatDetectionResponse, settings: &Settings) -> (Stored, VerdictSummary) {
+    let summary = VerdictSummary {
+        threat_type: response.threat_type.clone(),
+        is_threat: response.is_threat,
+        severity: response.severity.clone(),
+        confidence: response.confidence,
+    };
+    let stored = match settings.cache_compression {
+        true => match compress(&response, settings.cache_compression_threshold) {
+            Some(compressed) => Stored::Compressed(compressed),
+            None => Stored::Plain(response.verdict_only()),
+        },
+        false => Stored::Plain(response.verdict_only()),
+    };
+    (stored, summary)
+}
+
+/// Lifetime of entries for a request threat type, if they expire
+pub fn ttl(settings: &Settings, request_type: &str) -> Option<Duration> {
+    let secs = settings.cache_ttl_secs_by_type.get(request_type).copied().unwrap_or(settings.cache_ttl_secs);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Entries removed by one sweep
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sweep {
+    /// Past the TTL
+    pub expired: usize,
+    /// Computed under another ruleset version or an earlier rules generation
+    pub stale: usize,
+}
+
+/// Remove entries that can no longer be served
+pub fn sweep(
+    cache: &mut LruCache<String, CachedResult>,
+    settings: &Settings,
+    ruleset_version: &str,
+    rules_generation: u64,
+    now: Instant,
+) -> Sweep {
+    let mut swept = Sweep::default();
+    let dead: Vec<String> = cache
+        .iter()
+        .filter_map(|(key, entry)| {
+            if entry.ruleset_version != ruleset_version || entry.rules_generation < rules_generation {
+                swept.stale += 1;
+            } else if entry.is_expired(settings, now) {
+                swept.expired += 1;
+            } else {
+                return None;
+            }
+            Some(key.clone())
+        })
+        .collect();
+    for key in &dead {
+        cache.pop(key);
+    }
+    swept
+}
+
+/// Rough bytes held by a whole cache
+pub fn estimated_bytes(cache: &LruCache<String, CachedResult>) -> usize {
+    cache.iter().map(|(key, entry)| key.len() + entry.estimated_bytes()).sum()
+}
+
+fn compress(response: &ThreatDetectionResponse, threshold: usize) -> Option<Vec<u8>> {
+    let serialized = serde_json::to_vec(response).ok()?;
+    if serialized.len() <= threshold {
+        return None;
+    }
+    zstd::encode_all(serialized.as_slice(), COMPRESSION_LEVEL).ok()
+}
+
+fn decompress(bytes: &[u8]) -> anyhow::Result<ThreatDetectionResponse> {
+    let serialized = zstd::decode_all(bytes)?;
+    Ok(serde_json::from_slice(&serialized)?)
+}
+
+/// Filters, ordering and paging for the admin cache listing
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheQuery {
+    /// Response threat type (`phishing`, `malware`, `behavioral`)
+    pub threat_type: Option<String>,
+    pub is_threat: Option<bool>,
+    pub min_age_secs: Option<u64>,
+    pub ruleset_version: Option<String>,
+    pub rules_generation: Option<u64>,
+    /// `age` (oldest first, the default) or `hits` (most hit first)
+    pub sort: Option<String>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// One cache entry as shown by the admin listing
+#[derive(Debug, Serialize)]
+pub struct CacheEntryInfo {
+    pub key_hash: String,
+    #[serde(flatten)]
+    pub verdict: VerdictSummary,
+    pub created_at: DateTime<Utc>,
+    pub last_hit_at: Option<DateTime<Utc>>,
+    pub hit_count: u64,
+    pub age_secs: u64,
+    /// `null` when entries of its threat type do not expire
+    pub ttl_remaining_secs: Option<u64>,
+    pub ruleset_version: String,
+    pub rules_generation: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachePage {
+    pub entries: Vec<CacheEntryInfo>,
+    pub total_matching: usize,
+    pub next_cursor: Option<String>,
+}
+
+/// List cache entries matching the query.
+///
+/// Pages use a keyset cursor of the last entry's sort value and key hash, so
+/// entries inserted or evicted between requests do not shift later pages.
+pub fn list(
+    cache: &LruCache<String, CachedResult>,
+    query: &CacheQuery,
+    settings: &Settings,
+    clock: &dyn Clock,
+) -> Result<CachePage, String> {
+    let now = clock.now();
+    let by_hits = match query.sort.as_deref() {
+        None | Some("age") => false,
+        Some("hits") => true,
+        Some(other) => return Err(format!("Unknown sort {:?}; expected age or hits", other)),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    // Sort value ascending: creation time for age, negated hit count for hits
+    let sort_value = |entry: &CachedResult| -> i64 {
+        if by_hits {
+            -(entry.hit_count as i64)
+        } else {
+            entry.created_at.timestamp_micros()
+        }
+    };
+    let after = query.cursor.as_deref().map(parse_cursor).transpose()?;
+
+    let mut matching: Vec<(i64, &String, &CachedResult)> = cache
+        .iter()
+        .filter(|(_, e)| query.threat_type.as_ref().is_none_or(|t| *t == e.summary.threat_type))
+        .filter(|(_, e)| query.is_threat.is_none_or(|t| t == e.summary.is_threat))
+        .filter(|(_, e)| query.min_age_secs.is_none_or(|age| e.age_secs(now) >= age))
+        .filter(|(_, e)| query.ruleset_version.as_ref().is_none_or(|v| *v == e.ruleset_version))
+        .filter(|(_, e)| query.rules_generation.is_none_or(|g| g == e.rules_generation))
+        .map(|(key, e)| (sort_value(e), key, e))
+        .collect();
+    let total_matching = matching.len();
+    matching.sort_by_key(|&(value, key, _)| (value, key));
+
+    let mut page: Vec<&(i64, &String, &CachedResult)> = matching
+        .iter()
+        .filter(|(value, key, _)| after.as_ref().is_none_or(|(v, k)| (*value, key.as_str()) > (*v, k.as_str())))
+        .take(limit + 1)
+        .collect();
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|(value, key, _)| format!("{}.{}", value, key))
+    } else {
+        None
+    };
+
+    let entries = page
+        .into_iter()
+        .map(|&(_, key, e)| CacheEntryInfo {
+            key_hash: key.clone(),
+            verdict: e.summary.clone(),
+            created_at: e.created_at,
+            last_hit_at: e.last_hit_at,
+            hit_count: e.hit_count,
+            age_secs: e.age_secs(now),
+            ttl_remaining_secs: ttl(settings, &e.request_type)
+                .map(|ttl| ttl.saturating_sub(now.duration_since(e.created)).as_secs()),
+            ruleset_version: e.ruleset_version.clone(),
+            rules_generation: e.rules_generation,
+        })
+        .collect();
+
+    Ok(CachePage { entries, total_matching, next_cursor })
+}
+
+/// Remove the entries computed for an indicator; returns how many
+pub fn evict_indicator(cache: &mut LruCache<String, CachedResult>, indicator: &Indicator) -> usize {
+    let dead: Vec<String> = cache
+        .iter()
+        .filter(|(_, entry)| entry.indicator.as_ref() == Some(indicator))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in &dead {
+        cache.pop(key);
+    }
+    dead.len()
+}
+
+fn parse_cursor(cursor: &str) -> Result<(i64, String), String> {
+    cursor
+        .split_once('.')
+        .and_then(|(value, key)| Some((value.parse().ok()?, key.to_string())))
+        .ok_or_else(|| format!("Invalid cursor {:?}", cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::collections::HashMap;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    fn response(reasons: Vec<String>) -> ThreatDetectionResponse {
+        ThreatDetectionResponse::new("phishing", true, 0.93, "high".to_string(), reasons)
+    }
+
+    #[test]
+    fn large_entries_round_trip_compressed_and_small_ones_stay_plain() {
+        let settings = Settings { cache_compression: true, cache_compression_threshold: 256, ..Settings::default() };
+        let reasons: Vec<String> = (0..40).map(|i| format!("Suspicious pattern {} matched in the submitted content", i)).collect();
+        let large = CachedResult::new(response(reasons.clone()), "url", &settings, "v1", 1, &ManualClock::new());
+        let Stored::Compressed(bytes) = &large.stored else { panic!("large entry stored plain") };
+        assert!(bytes.len() < serde_json::to_vec(&response(reasons.clone())).unwrap().len());
+        let plain = CachedResult::new(response(reasons.clone()), "url", &Settings { cache_compression: false, ..settings.clone() }, "v1", 1, &ManualClock::new());
+        assert!(matches!(plain.stored, Stored::Plain(_)));
+        let round_trip = large.response().unwrap();
+        assert_eq!(round_trip.reasons, reasons);
+        assert_eq!((round_trip.is_threat, round_trip.confidence, round_trip.severity.as_str()), (true, 0.93, "high"));
+
+        let small = CachedResult::new(response(vec!["Short".to_string()]), "url", &settings, "v1", 1, &ManualClock::new());
+        assert!(matches!(small.stored, Stored::Plain(_)));
+        assert_eq!(small.response().unwrap().reasons, vec!["Short".to_string()]);
+    }
+
+    #[test]
+    fn listing_filters_sorts_and_pages_with_a_stable_cursor() {
+        let settings = Settings { cache_ttl_secs: 0, ..Settings::default() };
+        let clock = ManualClock::new();
+        let mut cache = LruCache::new(NonZeroUsize::new(16).unwrap());
+        for i in 0..5 {
+            let is_threat = i % 2 == 0;
+            let response = ThreatDetectionResponse::new("phishing", is_threat, 0.5, "medium".to_string(), vec!["secret content".to_string()]);
+            cache.put(format!("key{}", i), CachedResult::new(response, "url", &settings, "v1", 1, &clock));
+            clock.advance(Duration::from_secs(10));
+        }
+        for _ in 0..3 {
+            cache.peek_mut("key3").unwrap().record_hit(&clock);
+        }
+        let keys = |page: &CachePage| page.entries.iter().map(|e| e.key_hash.clone()).collect::<Vec<_>>();
+        let query = |cursor: Option<String>| CacheQuery { limit: Some(2), cursor, ..CacheQuery::default() };
+
+        let first = list(&cache, &query(None), &settings, &clock).unwrap();
+        assert_eq!((keys(&first), first.total_matching), (vec!["key0".to_string(), "key1".to_string()], 5));
+        assert_eq!((first.entries[0].age_secs, first.entries[0].ttl_remaining_secs), (50, None));
+        // An entry added between pages lands after them rather than shifting them
+        let response = ThreatDetectionResponse::new("phishing", false, 0.1, "low".to_string(), vec![]);
+        cache.put("key5".to_string(), CachedResult::new(response, "url", &settings, "v1", 1, &clock));
+        let second = list(&cache, &query(first.next_cursor), &settings, &clock).unwrap();
+        assert_eq!(keys(&second), ["key2", "key3"]);
+        let third = list(&cache, &query(second.next_cursor), &settings, &clock).unwrap();
+        assert_eq!((keys(&third), third.next_cursor), (vec!["key4".to_string(), "key5".to_string()], None));
+
+        let threats = CacheQuery { is_threat: Some(true), min_age_secs: Some(20), ..CacheQuery::default() };
+        assert_eq!(keys(&list(&cache, &threats, &settings, &clock).unwrap()), ["key0", "key2"]);
+        let by_hits = CacheQuery { sort: Some("hits".to_string()), limit: Some(1), ..CacheQuery::default() };
+        let top = list(&cache, &by_hits, &settings, &clock).unwrap();
+        assert_eq!((keys(&top), top.entries[0].hit_count), (vec!["key3".to_string()], 3));
+        assert!(top.entries[0].last_hit_at.is_some());
+        assert!(!serde_json::to_string(&top).unwrap().contains("secret content"));
+        assert!(list(&cache, &CacheQuery { sort: Some("size".to_string()), ..CacheQuery::default() }, &settings, &clock).is_err());
+    }
+
+    #[test]
+    fn entries_expire_at_their_ttl_and_stale_ones_are_swept() {
+        let settings = Settings { cache_ttl_secs: 60, ..Settings::default() };
+        let clock = ManualClock::new();
+        let mut cache = LruCache::new(NonZeroUsize::new(4).unwrap());
+        cache.put("old".to_string(), CachedResult::new(response(vec![]), "url", &settings, "v1", 1, &clock));
+        clock.advance(Duration::from_secs(30));
+        cache.put("new".to_string(), CachedResult::new(response(vec![]), "url", &settings, "v1", 1, &clock));
+
+        clock.advance(Duration::from_secs(29));
+        assert!(!cache.peek("old").unwrap().is_expired(&settings, clock.now()));
+        assert_eq!(sweep(&mut cache, &settings, "v1", 1, clock.now()).expired, 0);
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.peek("old").unwrap().is_expired(&settings, clock.now()));
+        assert_eq!(sweep(&mut cache, &settings, "v1", 1, clock.now()).expired, 1);
+        assert!(cache.contains("new"));
+
+        let swept = sweep(&mut cache, &settings, "v1", 2, clock.now());
+        assert_eq!((swept.expired, swept.stale), (0, 1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn ttl_follows_the_request_type_and_falls_back_to_the_global_one() {
+        let settings = Settings {
+            cache_ttl_secs: 60,
+            cache_ttl_secs_by_type: HashMap::from([("url".to_string(), 10), ("action".to_string(), 0)]),
+            ..Settings::default()
+        };
+        assert_eq!(ttl(&settings, "url"), Some(Duration::from_secs(10)));
+        assert_eq!(ttl(&settings, "code"), Some(Duration::from_secs(60)));
+        // 0 keeps that type's entries until they are evicted
+        assert_eq!(ttl(&settings, "action"), None);
+
+        let clock = ManualClock::new();
+        let mut cache = LruCache::new(NonZeroUsize::new(4).unwrap());
+        cache.put("url".to_string(), CachedResult::new(response(vec![]), "url", &settings, "v1", 1, &clock));
+        cache.put("code".to_string(), CachedResult::new(response(vec![]), "code", &settings, "v1", 1, &clock));
+        clock.advance(Duration::from_secs(4));
+        let page = list(&cache, &CacheQuery::default(), &settings, &clock).unwrap();
+        let remaining: HashMap<_, _> = page.entries.iter().map(|e| (e.key_hash.as_str(), e.ttl_remaining_secs)).collect();
+        assert_eq!((remaining["url"], remaining["code"]), (Some(6), Some(56)));
+    }
+
+    #[test]
+    fn entries_expire_at_their_type_ttl_and_stale_ones_are_swept() {
+        let settings = Settings {
+            cache_ttl_secs: 60,
+            cache_ttl_secs_by_type: HashMap::from([("url".to_string(), 10)]),
+            ..Settings::default()
+        };
+        let clock = ManualClock::new();
+        let mut cache = LruCache::new(NonZeroUsize::new(4).unwrap());
+        cache.put("code".to_string(), CachedResult::new(response(vec![]), "code", &settings, "v1", 1, &clock));
+        cache.put("url".to_string(), CachedResult::new(response(vec![]), "url", &settings, "v1", 1, &clock));
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(sweep(&mut cache, &settings, "v1", 1, clock.now()).expired, 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sweep(&mut cache, &settings, "v1", 1, clock.now()).expired, 1);
+        assert!(cache.contains("code"));
+
+        clock.advance(Duration::from_secs(49));
+        assert!(!cache.peek("code").unwrap().is_expired(&settings, clock.now()));
+        let swept = sweep(&mut cache, &settings, "v1", 2, clock.now());
+        assert_eq!((swept.expired, swept.stale), (0, 1));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn changing_a_hit_leaves_the_entry_alone() {
+        let settings = Settings { cache_compression: false, ..Settings::default() };
+        let reasons: Vec<String> = (0..40).map(|i| format!("Suspicious pattern {} matched in the submitted content", i)).collect();
+        let entry = CachedResult::new(response(reasons), "url", &settings, "v1", 1, &ManualClock::new());
+        let mut hit = entry.response().unwrap();
+        hit.reasons.clear();
+        assert_eq!(entry.response().unwrap().reasons.len(), 40);
+    }
+}
+
+/// Allocation counts of cache hits. The counting allocator replaces the
+/// global one for the whole binary, so it is only built with the
+/// `alloc-count` feature: `cargo test --features alloc-count cache::allocations`
+#[cfg(all(test, feature = "alloc-count"))]
+mod allocations {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the calling thread's allocations, so tests running alongside
+    /// do not add to them
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|a| a.set((a.get().0 + 1, a.get().1 + layout.size())));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    /// Allocations and bytes `f` makes on this thread
+    fn allocations(f: impl FnOnce()) -> (usize, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        let after = ALLOCATIONS.with(Cell::get);
+        (after.0 - before.0, after.1 - before.1)
+    }
+
+    /// An entry for a verdict with as many reasons as a verbose detection
+    fn entry(settings: &Settings) -> CachedResult {
+        let reasons = (0..40).map(|i| format!("Suspicious pattern {} matched in the submitted content", i)).collect();
+        let response = ThreatDetectionResponse::new("phishing", true, 0.93, "high".to_string(), reasons);
+        CachedResult::new(response, "url", settings, "v1", 1, &SystemClock)
+    }
+
+    /// What a hit does in `detect_single`
+    fn hit(entry: &CachedResult) -> ThreatDetectionResponse {
+        let mut response = entry.response().unwrap();
+        response.cached = true;
+        response.fuzzy_cached = false;
+        response.latency_ms = 1;
+        response
+    }
+
+    #[test]
+    fn hit_shares_the_cached_verdict() {
+        let settings = Settings { cache_compression: false, ..Settings::default() };
+        let entry = entry(&settings);
+        let mut response = None;
+        let (count, _) = allocations(|| response = Some(hit(&entry)));
+        assert_eq!(count, 0);
+        assert_eq!(response.unwrap().reasons.len(), 40);
+    }
+}
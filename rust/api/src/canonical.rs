@@ -0,0 +1,153 @@
+// rust/api/src/canonical.rs
+//! Canonical JSON
+//!
+//! The bytes anything hashes or signs have to be the same every time for
+//! the same logical value, whatever order serde happens to emit fields in.
+//! Canonical JSON is compact, with every object's keys sorted by their
+//! UTF-16 code units as in RFC 8785, and a single spelling for each number:
+//!
+//! - integers, and floats with an integral value within ±2^53, are written
+//!   as plain integers (`1.0` becomes `1`);
+//! - a float that is exactly an `f32` is written in the shortest form that
+//!   reads back as that `f32`, so a confidence of `0.7f32` is `0.7` whether
+//!   it went through `f32` or `f64` on the way;
+//! - any other float in the shortest form that reads back as that `f64`.
+//!
+//! This module is shared with the `ryzen-scan` binary.
+
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+/// Largest integer every `f64` below it represents exactly
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Canonical JSON of a serializable value
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<String> {
+    let value = serde_json::to_value(value)?;
+    let mut out = String::new();
+    write(&value, &mut out);
+    Ok(out)
+}
+
+/// Canonical JSON bytes of a serializable value
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<Vec<u8>> {
+    to_string(value).map(String::into_bytes)
+}
+
+/// Append the canonical JSON of `value` to `out`
+pub fn write(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write(item, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write(item, out);
+            }
+            out.push(']');
+        }
+        Value::Number(number) => out.push_str(&number_text(number)),
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+fn number_text(number: &Number) -> String {
+    if number.is_i64() || number.is_u64() {
+        return number.to_string();
+    }
+    // Finite: JSON values hold no NaN or infinity
+    let x = number.as_f64().unwrap_or_default();
+    if x.fract() == 0.0 && x.abs() < MAX_EXACT_INTEGER {
+        return format!("{}", x as i64);
+    }
+    let single = x as f32;
+    if single as f64 == x {
+        serde_json::to_string(&single).unwrap_or_else(|_| number.to_string())
+    } else {
+        number.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Verdict {
+        is_threat: bool,
+        confidence: f32,
+        reasons: Vec<&'static str>,
+        scores: HashMap<String, f64>,
+    }
+
+    /// The same fields, declared in another order
+    #[derive(Serialize)]
+    struct Reordered {
+        scores: HashMap<String, f64>,
+        reasons: Vec<&'static str>,
+        confidence: f64,
+        is_threat: bool,
+    }
+
+    fn scores() -> HashMap<String, f64> {
+        // Each map hashes with its own random keys, so iteration order varies
+        (0..32).map(|i| (format!("stage_{}", i), i as f64 / 4.0)).collect()
+    }
+
+    #[test]
+    fn the_same_logical_value_serializes_to_the_same_bytes() {
+        let verdict = || Verdict { is_threat: true, confidence: 0.7, reasons: vec!["b", "a"], scores: scores() };
+        let first = to_vec(&verdict()).unwrap();
+        for _ in 0..8 {
+            assert_eq!(to_vec(&verdict()).unwrap(), first);
+        }
+        let reordered = Reordered { scores: scores(), reasons: vec!["b", "a"], confidence: 0.7f32 as f64, is_threat: true };
+        assert_eq!(to_vec(&reordered).unwrap(), first);
+        // Array order is part of the value
+        let swapped = Verdict { reasons: vec!["a", "b"], ..verdict() };
+        assert_ne!(to_vec(&swapped).unwrap(), first);
+
+        let text = String::from_utf8(first).unwrap();
+        assert!(text.starts_with(r#"{"confidence":0.7,"is_threat":true,"reasons":["b","a"],"scores":{"stage_0":0,"stage_1":0.25,"stage_10":2.5,"#), "{}", text);
+    }
+
+    #[test]
+    fn numbers_have_one_spelling() {
+        let cases = [
+            (json!(1.0), "1"),
+            (json!(-3.0), "-3"),
+            (json!(0.7f32), "0.7"),
+            (json!(0.7f64), "0.7"),
+            (json!(0.1f64 + 0.2f64), "0.30000000000000004"),
+            (json!(1e300), "1e+300"),
+            (json!(u64::MAX), "18446744073709551615"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(to_string(&value).unwrap(), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn keys_sort_by_utf16_code_units_and_strings_stay_escaped() {
+        // U+1F600 is a surrogate pair, D83D DE00, so it sorts before U+E000
+        let value = json!({ "\u{e000}": 1, "\u{1f600}": 2, "a\"b": "line\nbreak", "A": null, "": [] });
+        assert_eq!(to_string(&value).unwrap(), "{\"\":[],\"A\":null,\"a\\\"b\":\"line\\nbreak\",\"\u{1f600}\":2,\"\u{e000}\":1}");
+    }
+}
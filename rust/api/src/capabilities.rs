@@ -0,0 +1,161 @@
+// rust/api/src/capabilities.rs
+//! Machine-readable description of what this instance supports
+//!
+//! `GET /api/capabilities` lets clients of different ages find out what
+//! they can send instead of assuming it. The description is built on each
+//! request from what is actually running: the registered detectors and
+//! their active pipelines, the request flags and headers the handlers
+//! read, configured limits, and the integrations that are up. Its `ETag`
+//! is a hash of the body, so it changes exactly when the description does.
+
+use serde::Serialize;
+
+/// One way of submitting content for a threat type
+#[derive(Debug, Clone, Serialize)]
+pub struct Input {
+    /// `json`, `batch`, `raw`, `partial` or `qr_image`
+    pub form: &'static str,
+    pub endpoint: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreatType {
+    /// Request `threat_type`
+    pub threat_type: String,
+    /// `threat_type` reported in responses
+    pub response_type: &'static str,
+    /// What `content` is read as: `url`, `source` or `text`; content is
+    /// always a JSON string or a raw body
+    pub content_format: &'static str,
+    pub inputs: Vec<Input>,
+    /// Active pipeline stages, in order
+    pub stages: Vec<String>,
+    /// Types whose detectors also run when this one finds nothing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+}
+
+impl ThreatType {
+    pub fn new(threat_type: &str, response_type: &'static str, stages: Vec<String>, fallback: Vec<String>) -> Self {
+        let mut inputs = vec![
+            Input { form: "json", endpoint: "/api/detect" },
+            Input { form: "batch", endpoint: "/api/detect/batch" },
+            Input { form: "raw", endpoint: "/api/detect/raw" },
+            Input { form: "partial", endpoint: "/api/detect/partial" },
+        ];
+        if threat_type == "url" {
+            inputs.push(Input { form: "qr_image", endpoint: "/api/detect/qr" });
+        }
+        Self {
+            threat_type: threat_type.to_string(),
+            response_type,
+            content_format: match threat_type {
+                "url" => "url",
+                "code" => "source",
+                _ => "text",
+            },
+            inputs,
+            stages,
+            fallback,
+        }
+    }
+}
+
+/// An optional field of a detection request
+#[derive(Debug, Clone, Serialize)]
+pub struct Flag {
+    pub name: &'static str,
+    /// `boolean` or `enum`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<&'static [&'static str]>,
+    /// Accepted on batch items as well as single detections
+    pub batch: bool,
+}
+
+/// A request header the API reads
+#[derive(Debug, Clone, Serialize)]
+pub struct Header {
+    pub name: &'static str,
+    pub purpose: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Limits {
+    /// Bytes of `content`; `null` when unbounded
+    pub max_content_bytes: Option<usize>,
+    pub max_context_bytes: usize,
+    /// Items in one batch; `null` when unbounded
+    pub max_batch_items: Option<usize>,
+    /// Batch requests run at once before others queue; `null` when unbounded
+    pub max_batch_jobs: Option<usize>,
+    /// Cap on `X-Timeout-Ms`; `null` when the header is ignored
+    pub max_request_timeout_ms: Option<u64>,
+    /// Detections per client per day, unless its tenant sets its own;
+    /// `null` when unlimited
+    pub daily_quota: Option<u64>,
+    /// Detections per client per second, and how many may start at once;
+    /// `null` when unlimited
+    pub rate_limit: Option<RateLimit>,
+    pub qr_max_image_bytes: usize,
+    pub qr_max_pixels: u64,
+    pub raw_content_types: &'static [&'static str],
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Auth {
+    /// How callers present an API key, `bearer` and `x-api-key`, or `none`
+    /// when no keys are configured and the API relies on the network in
+    /// front of it
+    pub modes: Vec<&'static str>,
+    /// Header selecting a tenant overlay
+    pub tenant_header: &'static str,
+    pub tenants: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Enrichment {
+    pub enabled: bool,
+    /// Accepted `enrich` values
+    pub modes: &'static [&'static str],
+    /// Lookups run for enriched detections
+    pub lookups: Vec<&'static str>,
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Signing {
+    pub key_id: String,
+    pub algorithm: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub api_version: &'static str,
+    /// `RESPONSE_SCHEMA_VERSION` of detection responses
+    pub response_schema_version: u32,
+    pub ruleset_version: String,
+    /// Enabled threat types, by request `threat_type`
+    pub threat_types: Vec<ThreatType>,
+    pub request_flags: Vec<Flag>,
+    /// Fields and query parameters outside those described here are
+    /// rejected with 422 rather than ignored
+    pub strict_request_fields: bool,
+    pub headers: Vec<Header>,
+    pub limits: Limits,
+    pub auth: Auth,
+    pub enrichment: Enrichment,
+    /// Present when responses are signed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing: Option<Signing>,
+    /// Optional components that are active: `url_blocklist`, `triage`,
+    /// `watch`, `fingerprint_history`, `session_risk`
+    pub features: Vec<&'static str>,
+}
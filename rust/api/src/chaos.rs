@@ -0,0 +1,234 @@
+// rust/api/src/chaos.rs
+//! Fault injection for exercising client retries and degradation handling
+//!
+//! Only builds with the `chaos` feature can activate it; in any other build
+//! `Chaos::activate` refuses and no request is ever affected. An active
+//! configuration applies to the listed route patterns, optionally only to
+//! requests carrying a marker header, and lapses on its own once its
+//! duration is up.
+
+use actix_web::http::header::HeaderMap;
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::clock::SharedClock;
+
+/// Whether this build can inject faults at all
+pub const AVAILABLE: bool = cfg!(feature = "chaos");
+
+/// Route prefix that faults never apply to, so chaos can always be switched off
+const CONTROL_ROUTE: &str = "/api/admin/chaos";
+
+/// Faults to inject and which traffic they apply to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChaosConfig {
+    /// Route patterns as registered, e.g. `/api/detect` or `/api/admin/honeytokens/{id}`
+    pub routes: Vec<String>,
+    /// Only requests carrying this header are affected
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Chance of delaying a response by `delay_ms`
+    #[serde(default)]
+    pub delay_probability: f32,
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Chance of answering 500 without running the handler
+    #[serde(default)]
+    pub error_probability: f32,
+    /// Chance of treating external lookups (the URL blocklist) as timed out
+    #[serde(default)]
+    pub lookup_timeout_probability: f32,
+    /// Chance of bypassing the verdict cache
+    #[serde(default)]
+    pub cache_miss_probability: f32,
+    /// Lifetime in seconds; defaults to the `chaos_duration_secs` setting
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+/// The configuration in force and when it lapses
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveChaos {
+    #[serde(flatten)]
+    pub config: ChaosConfig,
+    pub activated_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Faults chosen for one request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    pub delay: Option<Duration>,
+    pub error: bool,
+    pub lookup_timeout: bool,
+    pub cache_miss: bool,
+}
+
+pub struct Chaos {
+    /// Lifetime of configurations that do not set `duration_secs`
+    default_duration: Duration,
+    active: RwLock<Option<ActiveChaos>>,
+    rng: SystemRandom,
+    clock: SharedClock,
+}
+
+impl Chaos {
+    pub fn new(default_duration: Duration, clock: SharedClock) -> Self {
+        Self { default_duration, active: RwLock::new(None), rng: SystemRandom::new(), clock }
+    }
+
+    /// Replace the active configuration
+    pub fn activate(&self, mut config: ChaosConfig) -> Result<ActiveChaos, String> {
+        if !AVAILABLE {
+            return Err("Fault injection requires a build with the chaos feature".to_string());
+        }
+        if config.routes.is_empty() {
+            return Err("routes must name at least one route".to_string());
+        }
+        if let Some(route) = config.routes.iter().find(|r| r.starts_with(CONTROL_ROUTE)) {
+            return Err(format!("{} cannot be targeted", route));
+        }
+        for (name, value) in [
+            ("delay_probability", config.delay_probability),
+            ("error_probability", config.error_probability),
+            ("lookup_timeout_probability", config.lookup_timeout_probability),
+            ("cache_miss_probability", config.cache_miss_probability),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        let duration = config.duration_secs.map_or(self.default_duration, Duration::from_secs);
+        if duration.is_zero() {
+            return Err("duration_secs must be positive".to_string());
+        }
+        config.duration_secs = Some(duration.as_secs());
+        config.header = config.header.map(|h| h.to_ascii_lowercase());
+
+        let activated_at = self.clock.utc();
+        let active = ActiveChaos {
+            config,
+            activated_at,
+            expires_at: activated_at + chrono::Duration::from_std(duration).map_err(|e| e.to_string())?,
+        };
+        *self.active.write().unwrap() = Some(active.clone());
+        Ok(active)
+    }
+
+    /// Clear the active configuration; false if none was active
+    pub fn deactivate(&self) -> bool {
+        self.active.write().unwrap().take().is_some()
+    }
+
+    /// The configuration in force, dropping it once expired
+    pub fn active(&self) -> Option<ActiveChaos> {
+        if !AVAILABLE {
+            return None;
+        }
+        let current = self.active.read().unwrap().clone();
+        match current {
+            Some(active) if active.expires_at <= self.clock.utc() => {
+                self.active.write().unwrap().take();
+                None
+            }
+            current => current,
+        }
+    }
+
+    /// Decide which faults apply to a request on `route`
+    pub fn roll(&self, route: &str, headers: &HeaderMap) -> Faults {
+        let Some(active) = self.active() else {
+            return Faults::default();
+        };
+        let config = &active.config;
+        if !config.routes.iter().any(|r| r == route)
+            || config.header.as_ref().is_some_and(|h| !headers.contains_key(h.as_str()))
+        {
+            return Faults::default();
+        }
+        Faults {
+            delay: self.chance(config.delay_probability).then(|| Duration::from_millis(config.delay_ms)),
+            error: self.chance(config.error_probability),
+            lookup_timeout: self.chance(config.lookup_timeout_probability),
+            cache_miss: self.chance(config.cache_miss_probability),
+        }
+    }
+
+    fn chance(&self, probability: f32) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        let mut bytes = [0u8; 4];
+        if self.rng.fill(&mut bytes).is_err() {
+            return false;
+        }
+        (u32::from_le_bytes(bytes) as f64 / u32::MAX as f64) < probability as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use std::sync::Arc;
+
+    fn config() -> ChaosConfig {
+        ChaosConfig {
+            routes: vec!["/api/detect".to_string()],
+            header: Some("X-Chaos".to_string()),
+            delay_probability: 1.0,
+            delay_ms: 250,
+            error_probability: 1.0,
+            lookup_timeout_probability: 0.0,
+            cache_miss_probability: 1.0,
+            duration_secs: Some(60),
+        }
+    }
+
+    fn marked() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("x-chaos"), HeaderValue::from_static("1"));
+        headers
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn faults_apply_to_marked_traffic_on_listed_routes_until_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let chaos = Chaos::new(Duration::from_secs(300), clock.clone());
+        chaos.activate(config()).unwrap();
+
+        let faults = chaos.roll("/api/detect", &marked());
+        assert_eq!(faults.delay, Some(Duration::from_millis(250)));
+        assert!(faults.error && faults.cache_miss && !faults.lookup_timeout);
+        assert!(!chaos.roll("/api/detect", &HeaderMap::new()).error);
+        assert!(!chaos.roll("/api/detect/batch", &marked()).error);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(chaos.active().is_none());
+        assert!(!chaos.roll("/api/detect", &marked()).error);
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn control_route_and_bad_probabilities_are_refused() {
+        let chaos = Chaos::new(Duration::from_secs(300), Arc::new(ManualClock::new()));
+        let control = ChaosConfig { routes: vec!["/api/admin/chaos".to_string()], ..config() };
+        assert_eq!(chaos.activate(control).unwrap_err(), "/api/admin/chaos cannot be targeted");
+        let invalid = ChaosConfig { error_probability: 1.5, ..config() };
+        assert_eq!(chaos.activate(invalid).unwrap_err(), "error_probability must be between 0 and 1");
+        assert!(chaos.active().is_none());
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    #[test]
+    fn builds_without_the_feature_never_inject() {
+        let chaos = Chaos::new(Duration::from_secs(300), Arc::new(ManualClock::new()));
+        assert!(chaos.activate(config()).is_err());
+        assert!(!chaos.roll("/api/detect", &marked()).error);
+    }
+}
@@ -0,0 +1,80 @@
+// rust/api/src/clock.rs
+//! Time source for expiry, cooldowns and timestamps
+//!
+//! Everything whose behaviour depends on elapsed time (idempotency TTLs,
+//! alert cooldowns, emergency rule and chaos expiry, cache entry ages,
+//! request deadlines and stage budgets, batch slot waits, the syslog
+//! reconnect backoff) and every recorded timestamp reads the time through a
+//! `Clock` instead of `Instant::now()`, so a controllable clock can stand in
+//! for the system one. Latency measurements are not logical time and keep
+//! using `Instant`.
+//!
+//! Builds with the `manual-clock` feature run on a `ManualClock` that only
+//! moves when advanced through the admin API, which makes TTLs and windows
+//! reproducible without sleeping. Tests build one directly.
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Instant;
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for durations
+    fn now(&self) -> Instant;
+    /// Wall-clock time, for timestamps
+    fn utc(&self) -> DateTime<Utc>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock frozen at its creation time until advanced
+#[cfg(any(test, feature = "manual-clock"))]
+pub struct ManualClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    offset: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(any(test, feature = "manual-clock"))]
+impl ManualClock {
+    /// Starts at the system time
+    pub fn new() -> Self {
+        Self { start: SystemClock.now(), start_utc: SystemClock.utc(), offset: Default::default() }
+    }
+
+    /// Move time forward; returns the new wall-clock time
+    pub fn advance(&self, by: std::time::Duration) -> DateTime<Utc> {
+        *self.offset.lock().unwrap() += by;
+        self.utc()
+    }
+}
+
+#[cfg(any(test, feature = "manual-clock"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "manual-clock"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let offset = *self.offset.lock().unwrap();
+        self.start_utc + chrono::Duration::from_std(offset).unwrap_or(chrono::Duration::MAX)
+    }
+}
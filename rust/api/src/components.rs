@@ -0,0 +1,94 @@
+// rust/api/src/components.rs
+//! Availability of optional detection dependencies
+//!
+//! A component that fails to load (or reload with nothing to fall back on)
+//! is marked down. Each component has a failure policy: `open` keeps serving
+//! verdicts without it, flagged `degraded`, while `closed` rejects requests
+//! for the threat types that depend on it and fails the readiness probe.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+/// Components and the request threat types that depend on them
+pub const COMPONENTS: &[(&str, &[&str])] = &[("url_blocklist", &["url"])];
+
+/// What to do while a component is unavailable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Serve heuristic-only verdicts marked `degraded`
+    #[default]
+    Open,
+    /// Reject dependent threat types with 503
+    Closed,
+}
+
+/// Components that are down for one threat type, split by policy
+#[derive(Debug, Default)]
+pub struct Degradation {
+    pub open: Vec<&'static str>,
+    pub closed: Vec<&'static str>,
+}
+
+pub struct ComponentHealth {
+    policies: HashMap<String, FailurePolicy>,
+    /// Down components and why
+    down: RwLock<BTreeMap<&'static str, String>>,
+}
+
+impl ComponentHealth {
+    pub fn new(policies: HashMap<String, FailurePolicy>) -> Self {
+        Self { policies, down: RwLock::new(BTreeMap::new()) }
+    }
+
+    pub fn policy(&self, component: &str) -> FailurePolicy {
+        self.policies.get(component).copied().unwrap_or_default()
+    }
+
+    pub fn mark_down(&self, component: &'static str, reason: String) {
+        self.down.write().unwrap().insert(component, reason);
+    }
+
+    pub fn mark_up(&self, component: &str) {
+        self.down.write().unwrap().remove(component);
+    }
+
+    /// Down components that a request threat type depends on
+    pub fn degradation(&self, threat_type: &str) -> Degradation {
+        let down = self.down.read().unwrap();
+        if down.is_empty() {
+            return Degradation::default();
+        }
+        self.degradation_where(threat_type, |component| down.contains_key(component))
+    }
+
+    /// Degradation as if every component `threat_type` depends on had timed out
+    pub fn unavailable(&self, threat_type: &str) -> Degradation {
+        self.degradation_where(threat_type, |_| true)
+    }
+
+    fn degradation_where(&self, threat_type: &str, is_down: impl Fn(&str) -> bool) -> Degradation {
+        let mut degradation = Degradation::default();
+        for (component, types) in COMPONENTS {
+            if types.contains(&threat_type) && is_down(component) {
+                match self.policy(component) {
+                    FailurePolicy::Open => degradation.open.push(component),
+                    FailurePolicy::Closed => degradation.closed.push(component),
+                }
+            }
+        }
+        degradation
+    }
+
+    /// Down components with a fail-closed policy, and why they are down
+    pub fn failing_closed(&self) -> BTreeMap<&'static str, String> {
+        self.down
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(component, _)| self.policy(component) == FailurePolicy::Closed)
+            .map(|(component, reason)| (*component, reason.clone()))
+            .collect()
+    }
+}
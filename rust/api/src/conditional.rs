@@ -0,0 +1,50 @@
+// rust/api/src/conditional.rs
+//! Conditional GET support
+//!
+//! Read-only endpoints attach an `ETag` and `Cache-Control` header and answer
+//! `304 Not Modified` when the client's `If-None-Match` already holds the
+//! current tag, so polling dashboards don't re-download identical payloads.
+
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, Header, IfNoneMatch};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::canonical;
+
+/// Short freshness window for frequently changing statistics
+pub const STATS_MAX_AGE_SECS: u32 = 1;
+/// Longer freshness window for rules and configuration
+pub const RULES_MAX_AGE_SECS: u32 = 10;
+
+/// JSON response tagged with `tag`, or 304 if the client already has it
+pub fn json<T: Serialize>(req: &HttpRequest, tag: String, max_age: u32, body: &T) -> HttpResponse {
+    let etag = EntityTag::new_strong(tag);
+    let cache_control = CacheControl(vec![CacheDirective::MaxAge(max_age)]);
+
+    let fresh = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&etag)),
+        Err(_) => false,
+    };
+
+    if fresh {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .json(body)
+}
+
+/// JSON response tagged with a hash of its canonical JSON, so the tag does
+/// not depend on field or map order
+pub fn json_hashed<T: Serialize>(req: &HttpRequest, max_age: u32, body: &T) -> HttpResponse {
+    let serialized = canonical::to_vec(body).unwrap_or_default();
+    let digest = Sha256::digest(&serialized);
+    json(req, hex::encode(&digest[..8]), max_age, body)
+}
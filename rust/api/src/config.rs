@@ -0,0 +1,952 @@
+// rust/api/src/config.rs
+//! Runtime configuration for the API server
+//!
+//! Settings are read from an optional `config/api.{toml,yaml,json}` file
+//! (path overridable with `API_CONFIG`, which must then exist) and then from
+//! environment variables, so `MIN_WORKERS=4` overrides `min_workers = 2`
+//! from the file.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::anomaly::{self, AlertRule};
+use crate::audit;
+use crate::components::{FailurePolicy, COMPONENTS};
+use crate::lists::DetectionLists;
+use crate::pipeline::{self, Pipelines};
+use crate::policy::ResponsePolicy;
+use crate::recorder::Redaction;
+use crate::signing::Payload;
+use crate::syslog_export;
+use crate::template::ResponseTemplate;
+use crate::tls;
+use crate::thresholds::{self, Thresholds, SEVERITIES};
+use crate::triage::TriageBand;
+
+/// Request threat types with a detector
+pub const THREAT_TYPES: &[&str] = &["url", "code", "action"];
+
+/// API server settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Address the HTTP server listens on
+    pub bind_addr: String,
+    /// Proxies whose `Forwarded` or `X-Forwarded-For` header names the
+    /// client; any other peer is the client itself, whatever it sends
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Keys accepted on every route but health and readiness checks; with
+    /// none here or in `api_keys_file`, requests need no key
+    #[serde(skip_serializing)]
+    pub api_keys: Vec<String>,
+    /// File of further API keys, one per line
+    pub api_keys_file: Option<PathBuf>,
+    /// PEM certificate chain; with `tls_key_file`, serve HTTPS instead of HTTP
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM private key for `tls_cert_file`
+    pub tls_key_file: Option<PathBuf>,
+    /// Oldest TLS protocol version accepted: `1.2` or `1.3`
+    pub tls_min_version: String,
+    /// Minimum number of HTTP workers, even when fewer cores are reported;
+    /// the default of 1 leaves the reported core count as it is
+    pub min_workers: usize,
+    /// Passes over the warmup canaries before `/api/ready` reports ready (0 disables)
+    pub warmup_rounds: u32,
+    /// Verdict cache entries when memory is not under pressure
+    pub cache_capacity: usize,
+    /// Seconds a cached verdict may be served (0 keeps it until evicted);
+    /// also read as `cache_ttl_seconds`, so `CACHE_TTL_SECONDS` sets it
+    #[serde(alias = "cache_ttl_seconds")]
+    pub cache_ttl_secs: u64,
+    /// `cache_ttl_secs` for particular request threat types
+    pub cache_ttl_secs_by_type: HashMap<String, u64>,
+    /// Seconds between sweeps removing expired and stale cache entries (0 disables)
+    pub cache_sweep_interval_secs: u64,
+    /// Partial-detection streams held at once; the least recently updated is dropped for room
+    pub stream_capacity: usize,
+    /// Bytes one partial-detection stream may accumulate
+    pub stream_max_bytes: usize,
+    /// Out-of-order fragments one stream may hold while waiting for a gap to fill
+    pub stream_max_pending_fragments: usize,
+    /// Seconds a stream may go without a fragment before it is abandoned
+    pub stream_ttl_secs: u64,
+    /// Compress cache entries larger than `cache_compression_threshold`
+    pub cache_compression: bool,
+    /// Serialized size in bytes above which cache entries are compressed
+    pub cache_compression_threshold: usize,
+    /// Requests with longer content are rejected with 400 (0 disables)
+    pub max_content_bytes: usize,
+    /// Requests with a longer context are rejected with 400
+    pub max_context_bytes: usize,
+    /// Let near-duplicate content reuse cached verdicts (see `fuzzy`)
+    pub fuzzy_cache: bool,
+    /// Request threat types eligible for fuzzy cache hits
+    pub fuzzy_cache_types: Vec<String>,
+    /// Largest simhash distance, in bits, that counts as a near-duplicate
+    pub fuzzy_cache_max_distance: u32,
+    /// Also let near-duplicates reuse non-threat verdicts
+    pub fuzzy_cache_reuse_safe: bool,
+    /// Regular expression for content that is never looked up in or stored
+    /// to the verdict cache, e.g. content carrying a nonce or timestamp
+    pub cache_bypass_pattern: Option<String>,
+    /// Content beyond this many bytes is cut off before detection (0 disables)
+    pub max_detect_bytes: usize,
+    /// Confidence added by enrichment when a URL's host does not resolve (0 disables)
+    pub unresolvable_host_weight: f32,
+    /// Hosts of URL shorteners whose links enrichment expands by following
+    /// their redirects; subdomains included
+    pub url_shorteners: Vec<String>,
+    /// Most redirects followed when expanding a shortened URL
+    pub redirect_max_hops: usize,
+    /// Redirects from a shortened URL that make a long chain (0 disables)
+    pub long_redirect_chain_hops: usize,
+    /// Confidence added by enrichment for a long redirect chain (0 disables)
+    pub long_redirect_chain_weight: f32,
+    /// Confidence added by enrichment when a shortened URL ends in a 4xx or
+    /// 5xx, or on a parked domain (0 disables)
+    pub dead_end_redirect_weight: f32,
+    /// Longest enrichment lookups may take before the heuristic verdict stands
+    pub enrichment_timeout_ms: u64,
+    /// Async detections kept for `GET /api/detections/{id}`
+    pub enrichment_results_capacity: usize,
+    /// Async enrichments running at once; requests beyond it are answered
+    /// without enrichment (0 disables async enrichment)
+    pub enrichment_max_pending: usize,
+    /// Polyglot content of at least this many bytes runs its detectors concurrently (0 disables)
+    pub parallel_detect_min_bytes: usize,
+    /// Count batch detections in `/api/stats`
+    pub batch_stats: bool,
+    /// Batch items processed at once across all batches, shared fairly between clients (0 disables)
+    pub batch_concurrency: usize,
+    /// Batch requests run at once; more wait in the queue below (0 disables)
+    pub max_batch_jobs: usize,
+    /// Batch requests that may wait for a turn; more are rejected with 429
+    pub batch_job_queue: usize,
+    /// Count how often `/api/detect/compare` verdicts agree in `/api/stats`
+    pub compare_stats: bool,
+    /// Detections held for review per tenant in `/api/triage` (0 disables)
+    pub triage_capacity: usize,
+    /// Confidence band per threat type whose verdicts are queued for review;
+    /// unset types queue `needs_review` verdicts
+    pub triage_bands: HashMap<String, TriageBand>,
+    /// Indicators each client may watch through `/api/watch` (0 disables)
+    pub watch_max_indicators: usize,
+    /// Clients that may have a watch list at once
+    pub watch_max_keys: usize,
+    /// Least time between re-evaluations of watched indicators after rule changes
+    pub watch_reevaluate_interval_secs: u64,
+    /// Longest a `/api/watch/poll` request may wait for a change
+    pub watch_poll_max_secs: u64,
+    /// Threat content hashes tracked for `/api/stats/top` (0 disables)
+    pub top_threats_capacity: usize,
+    /// Upper bound on client deadlines set by `X-Timeout-Ms` (0 ignores the header)
+    pub max_request_timeout_ms: u64,
+    /// Reject detection requests with fields or query parameters no request
+    /// has, such as a misspelled `treat_type`, with 422
+    pub strict_request_fields: bool,
+    /// How a batch's `X-Timeout-Ms` deadline is shared among its items:
+    /// `remaining` (each gets the time left over the items left), `equal`
+    /// (each gets the deadline over the item count) or `none` (the first
+    /// items may use it all)
+    pub batch_deadline_split: String,
+    /// Detections a client may run per day before it is rejected with 429
+    /// (0 disables); tenants may set their own `daily_quota`
+    pub daily_quota: u64,
+    /// Hour (UTC) at which daily quotas start again
+    pub quota_reset_hour_utc: u32,
+    /// Clients whose daily usage is counted; further clients are refused until the next reset
+    pub quota_clients: usize,
+    /// Detections a client may start per second, refilling its bucket for
+    /// `/api/detect` and `/api/detect/batch` (0 disables)
+    pub rate_limit_per_sec: f64,
+    /// Detections a client may start at once before its rate applies
+    pub rate_limit_burst: u32,
+    /// Clients whose buckets are kept; past it, a new client takes the place
+    /// of the fullest among the least recently seen
+    pub rate_limit_clients: usize,
+    /// Maximum number of stored responses for `Idempotency-Key` replays
+    pub idempotency_capacity: usize,
+    /// How long a stored idempotent response can be replayed, in seconds
+    pub idempotency_ttl_secs: u64,
+    /// Content fingerprints tracked for cross-type history (0 disables)
+    pub fingerprint_index_capacity: usize,
+    /// Time lock acquisitions and expose them in `/metrics` (also `/api/metrics`) and `/api/stats`
+    pub fine_grained_metrics: bool,
+    /// Largest image accepted by `/api/detect/qr`, in bytes
+    pub qr_max_image_bytes: usize,
+    /// Largest image accepted by `/api/detect/qr`, in pixels (width × height)
+    pub qr_max_pixels: u64,
+    /// Time allowed for decoding one image before it is abandoned
+    pub qr_decode_timeout_ms: u64,
+    /// Interval between `/api/stats/stream` events, in milliseconds
+    pub stats_stream_interval_ms: u64,
+    /// StatsD collector (`host:port`) receiving detection statistics over UDP
+    pub statsd_addr: Option<String>,
+    /// Prefix of exported StatsD metric names
+    pub statsd_prefix: String,
+    /// Interval between StatsD flushes, in milliseconds
+    pub statsd_flush_interval_ms: u64,
+    /// Syslog collector (`host:port`) receiving every detection decision
+    pub syslog_addr: Option<String>,
+    /// `udp` or `tcp`
+    pub syslog_protocol: String,
+    /// Facility decisions are logged under, e.g. `local0` or `auth`
+    pub syslog_facility: String,
+    /// `primary` to stream state to a standby, `standby` to receive it, or
+    /// `none`
+    pub replication_role: String,
+    /// Standby a primary streams to (`host:port`)
+    pub replication_peer: Option<String>,
+    /// Address a standby accepts the primary's stream on
+    pub replication_listen: Option<String>,
+    /// Secret both instances of a pair share; never serialized
+    #[serde(skip_serializing)]
+    pub replication_token: Option<String>,
+    /// Changes a primary holds while its standby is unreachable; beyond
+    /// that it resends a snapshot
+    pub replication_queue_capacity: usize,
+    /// Alert rules over rolling detection statistics, reloadable at runtime
+    pub stat_alerts: Vec<AlertRule>,
+    /// Interval between statistics samples for `stat_alerts`, in seconds
+    pub stat_alert_interval_secs: u64,
+    /// Estimated store bytes above which load is shed (0 disables the watchdog)
+    pub memory_soft_limit_bytes: usize,
+    /// Estimated store bytes above which batches are rejected too (0: no hard level)
+    pub memory_hard_limit_bytes: usize,
+    pub memory_check_interval_secs: u64,
+    /// Distinct sub-threshold sightings of one indicator within the window
+    /// that escalate it (0, the default, disables)
+    pub recurrence_threshold: usize,
+    pub recurrence_window_secs: u64,
+    /// Lowest confidence of a non-threat verdict that counts as a sighting
+    pub recurrence_min_confidence: f32,
+    /// Confidence added to verdicts for an escalated indicator (0 disables)
+    pub recurrence_weight: f32,
+    /// How long an indicator stays escalated
+    pub recurrence_escalation_secs: u64,
+    /// Treat verdicts for escalated indicators as threats outright
+    pub recurrence_auto_block: bool,
+    /// Indicators tracked for sightings
+    pub recurrence_capacity: usize,
+    /// Sessions tracked for accumulated risk (0 disables)
+    pub session_capacity: usize,
+    /// Idle time after which a session starts over, in seconds
+    pub session_idle_secs: u64,
+    /// Lowest confidence of a verdict that adds to its session's risk
+    pub session_min_confidence: f32,
+    /// Signals a session keeps, latest first
+    pub session_max_signals: usize,
+    /// URL schemes accepted without suspicion
+    pub allowed_url_schemes: Vec<String>,
+    /// Confidence added for DNS-over-HTTPS queries to `doh_resolvers` whose
+    /// name carries encoded data (0 disables)
+    pub doh_tunnel_weight: f32,
+    /// DNS-over-HTTPS resolver hosts, matching their subdomains too
+    pub doh_resolvers: Vec<String>,
+    /// Shortest label of a queried name that can count as encoded data
+    pub doh_min_label_len: usize,
+    /// Confidence added for URLs whose scheme is not in `allowed_url_schemes` (0 disables)
+    pub unknown_scheme_weight: f32,
+    /// Confidence added when content contradicts its declared MIME type (0 disables)
+    pub mime_mismatch_weight: f32,
+    /// Confidence added for meta-refresh or script redirects in code (0 disables)
+    pub redirect_weight: f32,
+    /// Detectors tried in order when a type's own verdict is inconclusive
+    pub fallback_chains: HashMap<String, Vec<String>>,
+    /// Threat types whose detectors are not registered; requests for them
+    /// get 501. From the environment as a comma-separated list, e.g.
+    /// `DISABLED_THREAT_TYPES=action`
+    pub disabled_threat_types: Vec<String>,
+    /// Confidence below which a non-threat verdict runs the fallback chain
+    pub fallback_below: f32,
+    /// Confidence added when a page reuses a protected brand's assets (0 disables)
+    pub brand_impersonation_weight: f32,
+    /// Confidence added for keylogging or clipboard theft patterns in code (0 disables)
+    pub data_theft_weight: f32,
+    /// Confidence added for code reading environment variables (0 disables)
+    pub env_access_weight: f32,
+    /// Further confidence when code reading secrets or the whole environment also sends data over the network (0 disables)
+    pub env_exfiltration_weight: f32,
+    /// Confidence added for code using browser-extension cookie or webRequest APIs (0 disables)
+    pub extension_api_weight: f32,
+    /// Further confidence when such code reads cookies or request data and sends data over the network (0 disables)
+    pub extension_exfiltration_weight: f32,
+    /// Confidence added for signs of in-browser cryptocurrency mining in code (0 disables)
+    pub cryptominer_weight: f32,
+    /// Miner scripts and pool protocols flagged by name, matched case-insensitively
+    pub cryptominer_names: Vec<String>,
+    /// Confidence added for executables embedded in code: WebAssembly, ELF or PE (0 disables)
+    pub embedded_binary_weight: f32,
+    /// Confidence added for bracket nesting deeper than `max_nesting_depth` (0 disables)
+    pub deep_nesting_weight: f32,
+    /// Deepest bracket nesting content may have before it is flagged
+    pub max_nesting_depth: usize,
+    /// Confidence added for actions that contact a destination on a
+    /// frequent, regular schedule (0 disables)
+    pub beacon_weight: f32,
+    /// Fewest timed contacts with one destination that can make a beacon
+    pub beacon_min_events: usize,
+    /// Longest interval between contacts, in seconds, that counts as frequent
+    pub beacon_max_interval_secs: u64,
+    /// Largest spread of the intervals (standard deviation over mean) that
+    /// counts as regular
+    pub beacon_max_jitter: f32,
+    /// Confidence added for timed actions spaced too evenly for a person (0 disables)
+    pub bot_timing_weight: f32,
+    /// Fewest timed actions whose spacing is judged
+    pub bot_timing_min_events: usize,
+    /// Largest spread of the intervals (standard deviation over mean) that
+    /// counts as machine-regular
+    pub bot_timing_max_jitter: f32,
+    /// Confidence added for actions launching a shell with an encoded
+    /// command, e.g. `powershell -enc` (0 disables)
+    pub encoded_spawn_weight: f32,
+    /// Shells whose encoded commands are decoded and rescanned, by program
+    /// name without `.exe`
+    pub spawn_shells: Vec<String>,
+    /// Confidence added for iframe, object or embed tags loading another site (0 disables)
+    pub offsite_frame_weight: f32,
+    /// Further confidence when such a frame is also zero-size or hidden (0 disables)
+    pub hidden_frame_weight: f32,
+    /// Confidence added for installs of packages named like popular ones (0 disables)
+    pub package_typosquat_weight: f32,
+    /// Largest edit distance from a popular package name that counts as a typosquat
+    pub package_typosquat_distance: usize,
+    /// Popular package names per ecosystem (`npm`, `pypi`); unset ecosystems use built-in lists
+    pub popular_packages: HashMap<String, Vec<String>>,
+    /// Ordered detection stages per threat type; unset types use every stage
+    pub pipelines: Pipelines,
+    /// Candidate pipelines `/api/detect/compare` runs against the active ones;
+    /// unset types use the active pipeline
+    pub candidate_pipelines: Pipelines,
+    /// Label of the deployed ruleset. It is part of every cache key, so
+    /// changing it and reloading pipelines retires every cached verdict.
+    pub ruleset_version: String,
+    /// Severity thresholds per threat type; unset types use built-in values
+    pub thresholds: HashMap<String, Thresholds>,
+    /// Severity reported for non-threat verdicts, per threat type
+    pub non_threat_severity: HashMap<String, String>,
+    /// Forced verdicts keyed by SHA-256 hex digest of the content
+    pub overrides: HashMap<String, VerdictOverride>,
+    /// Raise a threat's reported confidence to the lower bound of its severity band
+    pub clamp_confidence_to_severity: bool,
+    /// Audit the per-stage confidence contributions behind every computed threat verdict
+    pub audit_contributions: bool,
+    /// Seconds a rule stays out of alerts for the same content after alerting (0 disables)
+    pub alert_cooldown_secs: u64,
+    /// JSON-lines file receiving audit records, in addition to the log
+    pub audit_log_path: Option<PathBuf>,
+    /// Audit records buffered for `audit_log_path` before `audit_overflow` applies
+    pub audit_buffer_capacity: usize,
+    /// What a full audit buffer does: `drop_oldest` (counted) or `block`, holding the request's response
+    pub audit_overflow: audit::Overflow,
+    /// JSON-lines file receiving sampled detection requests and responses
+    pub recorder_path: Option<PathBuf>,
+    /// Share of served detections recorded, from 0 to 1 (0 disables)
+    pub recorder_sample_rate: f32,
+    /// What recorded lines keep of the content: `none`, `hash` or `omit`
+    pub recorder_redaction: Redaction,
+    /// Size at which the recorder file stops growing (0 disables)
+    pub recorder_max_bytes: u64,
+    /// Directory holding refreshable data files such as the Public Suffix List
+    pub data_dir: PathBuf,
+    /// Prefix of generated honeytoken URLs
+    pub honeytoken_base_url: String,
+    /// PKCS#8 Ed25519 key used to sign detection responses
+    pub signing_key_path: Option<PathBuf>,
+    /// Identifier published with the signing key, changed on rotation
+    pub signing_key_id: String,
+    /// Signed payload: `canonical` JSON, or `fields` in struct order for
+    /// verifiers that predate it
+    pub signing_payload: Payload,
+    /// Longest lifetime accepted for an emergency rule
+    pub emergency_rule_max_ttl_secs: u64,
+    /// Lifetime of a fault injection configuration that sets none (`chaos` builds)
+    pub chaos_duration_secs: u64,
+    /// Sorted host-per-line file checked through a bloom filter
+    pub url_blocklist_path: Option<PathBuf>,
+    /// Target false-positive rate for the URL blocklist bloom filter
+    pub url_blocklist_fp_rate: f64,
+    /// Behaviour while an optional component is down, keyed by component name
+    pub component_policies: HashMap<String, FailurePolicy>,
+    /// Global keyword and domain lists
+    pub lists: DetectionLists,
+    /// Whether a tenant allowlist entry overrides a global blocklist entry
+    pub tenant_allowlist_wins: bool,
+    /// Tenant overlays keyed by tenant id (ids are lowercased by the loader)
+    pub tenants: HashMap<String, TenantSettings>,
+    /// Named limits on the detection detail shown to callers
+    pub response_policies: HashMap<String, ResponsePolicy>,
+    /// Policy for callers whose tenant names none; full detail when unset
+    pub default_response_policy: Option<String>,
+    /// Field renames and omissions of detection responses, by request threat type
+    pub response_templates: HashMap<String, ResponseTemplate>,
+}
+
+/// Verdict forced for a specific piece of content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerdictOverride {
+    pub is_threat: bool,
+    pub severity: String,
+    /// Reported confidence; defaults to 1.0 for threats and 0.0 otherwise
+    pub confidence: Option<f32>,
+}
+
+/// Per-tenant list files layered over the global lists, and the tenant's
+/// response policy
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantSettings {
+    pub brands_file: Option<PathBuf>,
+    pub allowlist_file: Option<PathBuf>,
+    pub blocklist_file: Option<PathBuf>,
+    pub context_keywords_file: Option<PathBuf>,
+    /// Name of an entry in `response_policies`; `default_response_policy` when unset
+    pub response_policy: Option<String>,
+    /// Share of batch capacity relative to other clients, which have weight 1
+    pub batch_weight: Option<u32>,
+    /// Detections per day in place of the global `daily_quota` (0 disables)
+    pub daily_quota: Option<u64>,
+    /// API keys whose callers belong to the tenant, by key id (the first 16
+    /// hex digits of the key's SHA-256); only they are held to its
+    /// `daily_quota`, whatever tenant header anyone else sends
+    pub key_ids: Vec<String>,
+}
+
+impl TenantSettings {
+    /// Backing file for a list by its API name
+    pub fn file_for(&self, list: &str) -> Option<&Path> {
+        match list {
+            "brands" => self.brands_file.as_deref(),
+            "allowlist" => self.allowlist_file.as_deref(),
+            "blocklist" => self.blocklist_file.as_deref(),
+            "context_keywords" => self.context_keywords_file.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:8080".to_string(),
+            trusted_proxies: Vec::new(),
+            api_keys: Vec::new(),
+            api_keys_file: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_min_version: "1.2".to_string(),
+            min_workers: 1,
+            warmup_rounds: 0,
+            cache_capacity: 10_000,
+            cache_ttl_secs: 3600,
+            cache_ttl_secs_by_type: HashMap::new(),
+            cache_sweep_interval_secs: 60,
+            stream_capacity: 1000,
+            stream_max_bytes: 64 * 1024,
+            stream_max_pending_fragments: 32,
+            stream_ttl_secs: 300,
+            cache_compression: false,
+            max_content_bytes: 2 * 1024 * 1024,
+            max_context_bytes: 64 * 1024,
+            fuzzy_cache: false,
+            // A one-character change can turn a safe URL into a lookalike
+            fuzzy_cache_types: vec!["code".to_string()],
+            fuzzy_cache_max_distance: 8,
+            fuzzy_cache_reuse_safe: false,
+            cache_bypass_pattern: None,
+            cache_compression_threshold: 4096,
+            max_detect_bytes: 1024 * 1024,
+            parallel_detect_min_bytes: 16 * 1024,
+            unresolvable_host_weight: 0.3,
+            url_shorteners: builtin_url_shorteners(),
+            redirect_max_hops: 10,
+            long_redirect_chain_hops: 3,
+            long_redirect_chain_weight: 0.3,
+            dead_end_redirect_weight: 0.3,
+            enrichment_timeout_ms: 500,
+            enrichment_results_capacity: 10_000,
+            enrichment_max_pending: 256,
+            batch_stats: true,
+            compare_stats: true,
+            triage_capacity: 1000,
+            triage_bands: HashMap::new(),
+            watch_max_indicators: 50,
+            watch_max_keys: 10_000,
+            watch_reevaluate_interval_secs: 60,
+            watch_poll_max_secs: 60,
+            batch_concurrency: 8,
+            max_batch_jobs: 16,
+            batch_job_queue: 64,
+            top_threats_capacity: 1000,
+            max_request_timeout_ms: 30_000,
+            strict_request_fields: false,
+            batch_deadline_split: "remaining".to_string(),
+            daily_quota: 0,
+            quota_reset_hour_utc: 0,
+            quota_clients: 100_000,
+            rate_limit_per_sec: 0.0,
+            rate_limit_burst: 20,
+            rate_limit_clients: 100_000,
+            idempotency_capacity: 10_000,
+            fingerprint_index_capacity: 10_000,
+            idempotency_ttl_secs: 3600,
+            fine_grained_metrics: false,
+            qr_max_image_bytes: 5 * 1024 * 1024,
+            qr_max_pixels: 16_000_000,
+            qr_decode_timeout_ms: 2000,
+            stats_stream_interval_ms: 1000,
+            statsd_addr: None,
+            statsd_prefix: "amd_security".to_string(),
+            statsd_flush_interval_ms: 10_000,
+            syslog_addr: None,
+            syslog_protocol: "udp".to_string(),
+            syslog_facility: "local0".to_string(),
+            replication_role: "none".to_string(),
+            replication_peer: None,
+            replication_listen: None,
+            replication_token: None,
+            replication_queue_capacity: 10_000,
+            stat_alerts: Vec::new(),
+            stat_alert_interval_secs: 10,
+            memory_soft_limit_bytes: 0,
+            memory_hard_limit_bytes: 0,
+            memory_check_interval_secs: 5,
+            recurrence_threshold: 0,
+            recurrence_window_secs: 3600,
+            recurrence_min_confidence: 0.3,
+            recurrence_weight: 0.3,
+            recurrence_escalation_secs: 3600,
+            recurrence_auto_block: false,
+            recurrence_capacity: 10_000,
+            session_capacity: 10_000,
+            session_idle_secs: 1800,
+            session_min_confidence: 0.3,
+            session_max_signals: 50,
+            allowed_url_schemes: vec!["http".to_string(), "https".to_string()],
+            unknown_scheme_weight: 0.5,
+            mime_mismatch_weight: 0.3,
+            redirect_weight: 0.3,
+            brand_impersonation_weight: 0.8,
+            data_theft_weight: 0.5,
+            env_access_weight: 0.2,
+            env_exfiltration_weight: 0.6,
+            embedded_binary_weight: 0.75,
+            deep_nesting_weight: 0.5,
+            extension_api_weight: 0.3,
+            extension_exfiltration_weight: 0.5,
+            cryptominer_weight: 0.6,
+            cryptominer_names: builtin_cryptominer_names(),
+            doh_tunnel_weight: 0.5,
+            doh_resolvers: builtin_doh_resolvers(),
+            doh_min_label_len: 24,
+            max_nesting_depth: 64,
+            beacon_weight: 0.7,
+            beacon_min_events: 4,
+            beacon_max_interval_secs: 3600,
+            beacon_max_jitter: 0.2,
+            bot_timing_weight: 0.7,
+            bot_timing_min_events: 5,
+            bot_timing_max_jitter: 0.05,
+            encoded_spawn_weight: 0.8,
+            spawn_shells: vec!["powershell".to_string(), "pwsh".to_string()],
+            offsite_frame_weight: 0.3,
+            hidden_frame_weight: 0.5,
+            package_typosquat_weight: 0.8,
+            package_typosquat_distance: 2,
+            popular_packages: builtin_popular_packages(),
+            fallback_chains: HashMap::new(),
+            disabled_threat_types: Vec::new(),
+            fallback_below: 0.5,
+            pipelines: pipeline::builtin(),
+            candidate_pipelines: Pipelines::new(),
+            ruleset_version: String::new(),
+            thresholds: thresholds::builtin(),
+            non_threat_severity: HashMap::from([
+                ("url".to_string(), "low".to_string()),
+                ("code".to_string(), "low".to_string()),
+                ("action".to_string(), "low".to_string()),
+            ]),
+            overrides: HashMap::new(),
+            clamp_confidence_to_severity: false,
+            audit_contributions: false,
+            alert_cooldown_secs: 60,
+            audit_log_path: None,
+            audit_buffer_capacity: 10_000,
+            audit_overflow: audit::Overflow::default(),
+            recorder_path: None,
+            recorder_sample_rate: 0.01,
+            recorder_redaction: Redaction::None,
+            recorder_max_bytes: 100 * 1024 * 1024,
+            data_dir: PathBuf::from("data"),
+            honeytoken_base_url: "https://docs.example.com/share".to_string(),
+            signing_key_path: None,
+            signing_key_id: "default".to_string(),
+            signing_payload: Payload::Canonical,
+            chaos_duration_secs: 300,
+            emergency_rule_max_ttl_secs: 86_400,
+            url_blocklist_path: None,
+            url_blocklist_fp_rate: 0.01,
+            component_policies: HashMap::new(),
+            lists: DetectionLists {
+                brands: vec!["paypa".into(), "amaz0n".into(), "go0gle".into()],
+                context_keywords: vec!["verify".into(), "confirm".into()],
+                ..Default::default()
+            },
+            tenant_allowlist_wins: false,
+            tenants: HashMap::new(),
+            response_policies: HashMap::new(),
+            default_response_policy: None,
+            response_templates: HashMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Load settings from the config file and environment
+    pub fn load() -> Result<Self, config::ConfigError> {
+        // A file named explicitly is required, so a typo is not mistaken for defaults
+        let (path, required) = match std::env::var("API_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => ("config/api".to_string(), false),
+        };
+
+        config::Config::builder()
+            .add_source(config::File::with_name(&path).required(required))
+            .add_source(
+                config::Environment::default()
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("disabled_threat_types")
+                    .with_list_parse_key("trusted_proxies")
+                    .with_list_parse_key("api_keys"),
+            )
+            .build()?
+            .try_deserialize()
+            .map(Self::with_builtin_thresholds)
+            .map(Self::with_builtin_pipelines)
+            .map(Self::with_builtin_popular_packages)
+    }
+
+    /// Whether a config file exists at `path`, as given or with one of the
+    /// extensions `load` tries
+    pub fn config_file_exists(path: &Path) -> bool {
+        const EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+        path.is_file() || EXTENSIONS.iter().any(|ext| {
+            let mut with_ext = path.as_os_str().to_owned();
+            with_ext.push(".");
+            with_ext.push(ext);
+            Path::new(&with_ext).is_file()
+        })
+    }
+
+    /// Check values that deserialization alone cannot validate
+    pub fn validate(&self) -> Result<(), String> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Every value that deserialization alone cannot validate, not just the first
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (threat_type, severity) in &self.non_threat_severity {
+            if !["none", "low", "medium"].contains(&severity.as_str()) {
+                problems.push(format!("non_threat_severity.{}: unsupported severity {:?}", threat_type, severity));
+            }
+        }
+        for (threat_type, thresholds) in &self.thresholds {
+            if let Err(e) = thresholds.validate() {
+                problems.push(format!("thresholds.{}: {}", threat_type, e));
+            }
+        }
+        if let Some(Err(e)) = self.cache_bypass_pattern.as_deref().map(regex::Regex::new) {
+            problems.push(format!("cache_bypass_pattern: {}", e));
+        }
+        if let Some(unknown) = self.disabled_threat_types.iter().find(|t| !THREAT_TYPES.contains(&t.as_str())) {
+            problems.push(format!("disabled_threat_types: unknown threat type {:?}", unknown));
+        }
+        for (threat_type, chain) in &self.fallback_chains {
+            if let Some(unknown) = chain.iter().find(|t| !THREAT_TYPES.contains(&t.as_str())) {
+                problems.push(format!("fallback_chains.{}: unknown threat type {:?}", threat_type, unknown));
+            } else if let Some(disabled) = chain.iter().find(|t| self.disabled_threat_types.contains(t)) {
+                problems.push(format!("fallback_chains.{}: threat type {:?} is disabled", threat_type, disabled));
+            }
+        }
+        for component in self.component_policies.keys() {
+            if !COMPONENTS.iter().any(|(name, _)| name == component) {
+                problems.push(format!("component_policies.{}: unknown component", component));
+            }
+        }
+        let policy_names = self
+            .tenants
+            .iter()
+            .filter_map(|(id, tenant)| Some((format!("tenants.{}.response_policy", id), tenant.response_policy.as_ref()?)))
+            .chain(self.default_response_policy.iter().map(|name| ("default_response_policy".to_string(), name)));
+        for (field, name) in policy_names {
+            if !self.response_policies.contains_key(name) {
+                problems.push(format!("{}: unknown response policy {:?}", field, name));
+            }
+        }
+        if let Some(unknown) = self.cache_ttl_secs_by_type.keys().find(|t| !THREAT_TYPES.contains(&t.as_str())) {
+            problems.push(format!("cache_ttl_secs_by_type.{}: unknown threat type", unknown));
+        }
+        for (threat_type, template) in &self.response_templates {
+            if !THREAT_TYPES.contains(&threat_type.as_str()) {
+                problems.push(format!("response_templates.{}: unknown threat type", threat_type));
+            }
+            problems.extend(template.problems().into_iter().map(|p| format!("response_templates.{}.{}", threat_type, p)));
+        }
+        for (threat_type, band) in &self.triage_bands {
+            if !THREAT_TYPES.contains(&threat_type.as_str()) {
+                problems.push(format!("triage_bands.{}: unknown threat type", threat_type));
+            } else if !(0.0 <= band.min && band.min < band.max && band.max <= 1.0) {
+                problems.push(format!("triage_bands.{}: must satisfy 0 <= min < max <= 1", threat_type));
+            }
+        }
+        let mut key_tenants: HashMap<&str, &str> = HashMap::new();
+        for (id, tenant) in &self.tenants {
+            if tenant.batch_weight == Some(0) {
+                problems.push(format!("tenants.{}.batch_weight: must be at least 1", id));
+            }
+            for key_id in &tenant.key_ids {
+                if let Some(other) = key_tenants.insert(key_id, id) {
+                    problems.push(format!("tenants.{}.key_ids: {:?} already belongs to tenant {:?}", id, key_id, other));
+                }
+            }
+        }
+        if !["remaining", "equal", "none"].contains(&self.batch_deadline_split.as_str()) {
+            problems.push(format!("batch_deadline_split: unsupported split {:?}", self.batch_deadline_split));
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            problems.push("tls_cert_file: HTTPS needs both tls_cert_file and tls_key_file".to_string());
+        }
+        if !tls::MIN_VERSIONS.contains(&self.tls_min_version.as_str()) {
+            problems.push(format!(
+                "tls_min_version: unsupported version {:?}; expected one of {}",
+                self.tls_min_version,
+                tls::MIN_VERSIONS.join(", ")
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.session_min_confidence) {
+            problems.push(format!("session_min_confidence: {} is not between 0 and 1", self.session_min_confidence));
+        }
+        if self.doh_min_label_len == 0 || self.doh_min_label_len > 63 {
+            problems.push(format!("doh_min_label_len: {} is not a DNS label length (1 to 63)", self.doh_min_label_len));
+        }
+        if self.session_max_signals == 0 {
+            problems.push("session_max_signals: must be at least 1".to_string());
+        }
+        if self.beacon_min_events < 3 {
+            problems.push("beacon_min_events: at least 3 contacts are needed to judge regularity".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.beacon_max_jitter) {
+            problems.push(format!("beacon_max_jitter: {} is not between 0 and 1", self.beacon_max_jitter));
+        }
+        if self.bot_timing_min_events < 3 {
+            problems.push("bot_timing_min_events: at least 3 actions are needed to judge regularity".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.bot_timing_max_jitter) {
+            problems.push(format!("bot_timing_max_jitter: {} is not between 0 and 1", self.bot_timing_max_jitter));
+        }
+        if self.quota_reset_hour_utc > 23 {
+            problems.push(format!("quota_reset_hour_utc: {} is not an hour of the day", self.quota_reset_hour_utc));
+        }
+        if !self.rate_limit_per_sec.is_finite() || self.rate_limit_per_sec < 0.0 {
+            problems.push(format!("rate_limit_per_sec: {} is not a rate", self.rate_limit_per_sec));
+        }
+        if self.rate_limit_burst == 0 {
+            problems.push("rate_limit_burst: must allow at least one detection".to_string());
+        }
+        if self.syslog_protocol.parse::<syslog_export::Protocol>().is_err() {
+            problems.push(format!("syslog_protocol: unsupported protocol {:?}", self.syslog_protocol));
+        }
+        if self.syslog_facility.parse::<syslog::Facility>().is_err() {
+            problems.push(format!("syslog_facility: unknown facility {:?}", self.syslog_facility));
+        }
+        match self.replication_role.as_str() {
+            "none" => {}
+            role @ ("primary" | "standby") => {
+                if self.replication_token.as_deref().is_none_or(str::is_empty) {
+                    problems.push(format!("replication_token: required for a {}", role));
+                }
+                if role == "primary" && self.replication_peer.is_none() {
+                    problems.push("replication_peer: required for a primary".to_string());
+                }
+                if role == "standby" && self.replication_listen.is_none() {
+                    problems.push("replication_listen: required for a standby".to_string());
+                }
+            }
+            other => problems.push(format!("replication_role: unsupported role {:?}", other)),
+        }
+        if self.audit_log_path.is_some() && self.audit_buffer_capacity == 0 {
+            problems.push("audit_buffer_capacity: must hold at least one record".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.recorder_sample_rate) {
+            problems.push("recorder_sample_rate must be between 0 and 1".to_string());
+        }
+        if self.memory_hard_limit_bytes > 0 && self.memory_hard_limit_bytes < self.memory_soft_limit_bytes {
+            problems.push("memory_hard_limit_bytes must not be below memory_soft_limit_bytes".to_string());
+        }
+        problems.extend(pipeline::validate(&self.pipelines).err());
+        problems.extend(pipeline::validate(&self.candidate_pipelines).err().map(|e| format!("candidate_{}", e)));
+        problems.extend(anomaly::validate(&self.stat_alerts).err());
+        for (hash, forced) in &self.overrides {
+            if !SEVERITIES.contains(&forced.severity.as_str()) {
+                problems.push(format!("overrides.{}: unsupported severity {:?}", hash, forced.severity));
+            }
+        }
+        problems
+    }
+
+    /// Threat types requests may use
+    pub fn enabled_threat_types(&self) -> Vec<&'static str> {
+        THREAT_TYPES.iter().copied().filter(|t| !self.disabled_threat_types.iter().any(|d| d == t)).collect()
+    }
+
+    /// Fill in built-in thresholds for types the config file left out
+    fn with_builtin_thresholds(mut self) -> Self {
+        for (threat_type, builtin) in thresholds::builtin() {
+            self.thresholds.entry(threat_type).or_insert(builtin);
+        }
+        self
+    }
+
+    /// Fill in built-in package lists for ecosystems the config file left out
+    fn with_builtin_popular_packages(mut self) -> Self {
+        for (ecosystem, builtin) in builtin_popular_packages() {
+            self.popular_packages.entry(ecosystem).or_insert(builtin);
+        }
+        self
+    }
+
+    /// Fill in built-in pipelines for types the config file left out
+    fn with_builtin_pipelines(mut self) -> Self {
+        for (threat_type, builtin) in pipeline::builtin() {
+            self.pipelines.entry(threat_type).or_insert(builtin);
+        }
+        self
+    }
+
+    /// Active pipelines with the candidate ones swapped in
+    pub fn candidate_ruleset(&self) -> Pipelines {
+        let mut pipelines = self.pipelines.clone();
+        pipelines.extend(self.candidate_pipelines.clone());
+        pipelines
+    }
+
+    /// Number of workers to start given the number of available cores
+    pub fn worker_count(&self, available_cores: usize) -> usize {
+        available_cores.max(self.min_workers).max(1)
+    }
+}
+
+fn builtin_cryptominer_names() -> Vec<String> {
+    [
+        "coinhive", "coin-hive", "cryptonight", "cryptoloot", "crypto-loot", "coinimp", "jsecoin", "webminepool",
+        "deepminer", "minero.cc", "xmrig", "stratum+tcp://", "stratum+ssl://",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn builtin_url_shorteners() -> Vec<String> {
+    [
+        "bit.ly", "tinyurl.com", "t.co", "goo.gl", "ow.ly", "is.gd", "buff.ly", "rebrand.ly", "cutt.ly", "shorturl.at",
+        "tiny.cc", "rb.gy", "t.ly", "s.id", "v.gd",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn builtin_doh_resolvers() -> Vec<String> {
+    [
+        "dns.google", "cloudflare-dns.com", "one.one.one.one", "dns.quad9.net", "doh.opendns.com", "dns.nextdns.io",
+        "doh.cleanbrowsing.org", "dns.adguard-dns.com", "doh.mullvad.net", "dns.alidns.com", "doh.pub",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+/// Frequently installed packages, the usual typosquatting targets
+fn builtin_popular_packages() -> HashMap<String, Vec<String>> {
+    let npm = [
+        "express", "react", "react-dom", "lodash", "axios", "chalk", "commander", "moment", "webpack",
+        "typescript", "jquery", "debug", "dotenv", "mongoose", "eslint", "request", "cross-env", "electron",
+    ];
+    let pypi = [
+        "requests", "numpy", "pandas", "django", "flask", "urllib3", "boto3", "setuptools", "matplotlib",
+        "scipy", "pyyaml", "cryptography", "colorama", "beautifulsoup4", "tensorflow", "selenium",
+    ];
+    HashMap::from([
+        ("npm".to_string(), npm.iter().map(|s| s.to_string()).collect()),
+        ("pypi".to_string(), pypi.iter().map(|s| s.to_string()).collect()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_floor(min_workers: usize) -> Settings {
+        Settings { min_workers, ..Settings::default() }
+    }
+
+    #[test]
+    fn worker_floor_applies_on_small_machines() {
+        assert_eq!(with_floor(2).worker_count(1), 2);
+    }
+
+    #[test]
+    fn cores_above_the_floor_are_all_used() {
+        assert_eq!(with_floor(2).worker_count(8), 8);
+    }
+
+    #[test]
+    fn default_floor_follows_the_core_count() {
+        assert_eq!(Settings::default().worker_count(1), 1);
+        assert_eq!(Settings::default().worker_count(4), 4);
+    }
+
+    #[test]
+    fn cache_ttl_is_read_under_either_name() {
+        let ttl = |key: &str| {
+            let source = config::Config::builder().set_override(key, 60).unwrap().build().unwrap();
+            source.try_deserialize::<Settings>().unwrap().cache_ttl_secs
+        };
+        assert_eq!(ttl("cache_ttl_secs"), 60);
+        assert_eq!(ttl("cache_ttl_seconds"), 60);
+    }
+
+    #[test]
+    fn cache_ttl_by_type_accepts_only_request_threat_types() {
+        let with_ttl = |threat_type: &str| Settings {
+            cache_ttl_secs_by_type: HashMap::from([(threat_type.to_string(), 10)]),
+            ..Settings::default()
+        };
+        assert!(with_ttl("url").validate().is_ok());
+        assert_eq!(with_ttl("phishing").validate().unwrap_err(), "cache_ttl_secs_by_type.phishing: unknown threat type");
+    }
+
+    #[test]
+    fn a_key_id_belongs_to_one_tenant_at_most() {
+        let tenant = || TenantSettings { key_ids: vec!["0123456789abcdef".to_string()], ..TenantSettings::default() };
+        let mut settings = Settings::default();
+        settings.tenants.insert("a".to_string(), tenant());
+        assert!(settings.validate().is_ok());
+        settings.tenants.insert("b".to_string(), tenant());
+        assert!(settings.validate().unwrap_err().contains(".key_ids: \"0123456789abcdef\" already belongs to tenant"));
+    }
+
+    #[test]
+    fn zero_floor_follows_the_core_count() {
+        assert_eq!(with_floor(0).worker_count(4), 4);
+        assert_eq!(with_floor(0).worker_count(0), 1);
+    }
+}
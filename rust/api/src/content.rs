@@ -0,0 +1,1131 @@
+// rust/api/src/content.rs
+//! Content type sniffing
+//!
+//! Identifies which type signatures a piece of content matches, so content
+//! that is valid as several types at once (polyglots) can be routed to every
+//! relevant detector instead of only the one the client asked for, and so a
+//! declared MIME type can be checked against what the content really is.
+//!
+//! The signal finders below also report where in the content they matched.
+
+use crate::domain;
+use crate::spans::{Compacted, Layer, Span};
+
+/// Content type signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Image,
+    Document,
+    Script,
+    Url,
+}
+
+impl ContentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentKind::Image => "image",
+            ContentKind::Document => "document",
+            ContentKind::Script => "script",
+            ContentKind::Url => "url",
+        }
+    }
+
+    /// Request threat type whose detector handles this kind, if any
+    pub fn threat_type(&self) -> Option<&'static str> {
+        match self {
+            ContentKind::Script => Some("code"),
+            ContentKind::Url => Some("url"),
+            // Images and documents are carriers only
+            ContentKind::Image | ContentKind::Document => None,
+        }
+    }
+}
+
+const IMAGE_MAGIC: &[&str] = &["GIF87a", "GIF89a", "\u{89}PNG", "\u{ff}\u{d8}\u{ff}"];
+const DOCUMENT_MAGIC: &[&str] = &["%PDF-", "PK\u{3}\u{4}"];
+const SCRIPT_MARKERS: &[&str] = &[
+    "<script", "<html", "<svg", "eval(", "function", "=>", "document.", "window.", "atob(",
+];
+
+/// Return every type signature the content matches
+pub fn sniff(content: &str) -> Vec<ContentKind> {
+    let mut kinds = Vec::new();
+    let trimmed = content.trim_start();
+    let lower = content.to_lowercase();
+
+    if IMAGE_MAGIC.iter().any(|m| content.starts_with(m)) {
+        kinds.push(ContentKind::Image);
+    }
+    if DOCUMENT_MAGIC.iter().any(|m| content.starts_with(m)) {
+        kinds.push(ContentKind::Document);
+    }
+    if SCRIPT_MARKERS.iter().any(|m| lower.contains(m)) {
+        kinds.push(ContentKind::Script);
+    }
+    if trimmed.starts_with("http://") || trimmed.starts_with("https://") {
+        kinds.push(ContentKind::Url);
+    }
+
+    kinds
+}
+
+/// Longest prefix of `content` within `max_bytes` that ends on a character boundary
+pub fn truncate(content: &str, max_bytes: usize) -> &str {
+    if content.len() <= max_bytes {
+        return content;
+    }
+    let mut end = max_bytes;
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    &content[..end]
+}
+
+/// Whether the content matches more than one type signature
+pub fn is_polyglot(kinds: &[ContentKind]) -> bool {
+    kinds.len() > 1
+}
+
+const MIME_TOP_LEVEL: &[&str] = &["text", "image", "application", "audio", "video", "font", "multipart"];
+
+/// First MIME type declared in free-form context, e.g. `Content-Type: text/plain`
+pub fn declared_mime(context: &str) -> Option<String> {
+    context
+        .split(|c: char| c.is_whitespace() || c == ';' || c == ',')
+        .map(|token| token.trim_matches(|c: char| c == '"' || c == '\'').to_lowercase())
+        .find(|token| {
+            token.split_once('/').is_some_and(|(top, sub)| {
+                MIME_TOP_LEVEL.contains(&top) && !sub.is_empty()
+            })
+        })
+}
+
+/// Describe how content disagrees with its declared MIME type, if it does
+pub fn mime_mismatch(declared: &str, kinds: &[ContentKind]) -> Option<String> {
+    let expected = if declared == "image/svg+xml"
+        || declared == "text/html"
+        || declared.contains("javascript")
+        || declared.contains("ecmascript")
+    {
+        Some(ContentKind::Script)
+    } else if declared.starts_with("image/") {
+        Some(ContentKind::Image)
+    } else if declared == "application/pdf" || declared == "application/zip" {
+        Some(ContentKind::Document)
+    } else {
+        None
+    };
+
+    let mismatched = match expected {
+        // Markup need not contain script, but must not be a binary format
+        Some(ContentKind::Script) => kinds.iter().any(|k| matches!(k, ContentKind::Image | ContentKind::Document)),
+        Some(kind) => !kinds.contains(&kind),
+        // Plain data types should carry no script or binary signature
+        None => kinds.iter().any(|k| *k != ContentKind::Url),
+    };
+
+    if !mismatched {
+        return None;
+    }
+    let actual: Vec<&str> = kinds.iter().map(|k| k.as_str()).collect();
+    Some(if actual.is_empty() { "text".to_string() } else { actual.join(", ") })
+}
+
+/// Automatic redirect found in HTML or script content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// `meta refresh` or the script construct used
+    pub method: &'static str,
+    /// Redirect target as written, when it could be extracted
+    pub target: Option<String>,
+    /// The tag or script construct
+    pub span: Span,
+}
+
+const SCRIPT_REDIRECTS: &[(&str, &str)] = &[
+    ("location.href=", "location.href"),
+    ("location.replace(", "location.replace"),
+    ("location.assign(", "location.assign"),
+    ("window.location=", "window.location"),
+    ("document.location=", "document.location"),
+];
+
+/// Find `<meta http-equiv="refresh">` tags and script-driven location changes
+pub fn find_redirects(content: &str) -> Vec<Redirect> {
+    let mut redirects = Vec::new();
+    // ASCII lowercasing keeps byte offsets valid for slicing `content`
+    let lower = content.to_ascii_lowercase();
+
+    let mut rest = 0;
+    while let Some(pos) = lower[rest..].find("<meta") {
+        let start = rest + pos;
+        let end = lower[start..].find('>').map_or(lower.len(), |p| start + p);
+        let tag = &lower[start..end];
+        if tag.replace(['"', '\'', ' '], "").contains("http-equiv=refresh") {
+            let target = tag.find("url=").map(|p| {
+                let from = start + p + 4;
+                content[from..end]
+                    .trim_start_matches(['"', '\''])
+                    .split(['"', '\'', ' ', '>'])
+                    .next()
+                    .unwrap_or("")
+                    .to_string()
+            });
+            redirects.push(Redirect {
+                method: "meta refresh",
+                target: target.filter(|t| !t.is_empty()),
+                span: Span::in_text(content, start..(end + 1).min(content.len())),
+            });
+        }
+        rest = end;
+    }
+
+    // Collapse whitespace so `location.href = "..."` matches too
+    let compact = Compacted::new(content, char::is_whitespace);
+    let original: String = content.chars().filter(|c| !c.is_whitespace()).collect();
+    for (pattern, method) in SCRIPT_REDIRECTS {
+        let mut rest = 0;
+        while let Some(pos) = compact.as_str()[rest..].find(pattern) {
+            let start = rest + pos;
+            let from = start + pattern.len();
+            let target = original[from..]
+                .strip_prefix(['"', '\'', '`'])
+                .and_then(|t| t.split(['"', '\'', '`']).next())
+                .map(str::to_string);
+            let span = Span::in_text(content, compact.original_range(start..from));
+            redirects.push(Redirect { method, target, span });
+            rest = from;
+        }
+    }
+
+    redirects
+}
+
+/// Host of an absolute `http(s)` redirect target, i.e. one leaving the page's site
+pub fn off_site_host(target: &str) -> Option<String> {
+    let url = url::Url::parse(target).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    url.host_str().map(str::to_string)
+}
+
+/// `<iframe>`, `<object>` or `<embed>` tag loading a page or plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedFrame {
+    pub tag: &'static str,
+    /// Host of an absolute or protocol-relative source
+    pub host: Option<String>,
+    /// Zero or one pixel in size, or hidden by its style or attributes
+    pub hidden: bool,
+    /// The tag up to its closing `>`
+    pub span: Span,
+}
+
+/// Tags that load another document, and the attribute naming it
+const FRAME_TAGS: &[(&str, &str)] = &[("iframe", "src"), ("object", "data"), ("embed", "src")];
+
+/// Find iframe, object and embed tags with their source host and visibility
+pub fn find_embedded_frames(content: &str) -> Vec<EmbeddedFrame> {
+    // ASCII lowercasing keeps byte offsets valid for slicing `content`
+    let lower = content.to_ascii_lowercase();
+    let mut frames = Vec::new();
+    for (tag, source) in FRAME_TAGS {
+        let open = format!("<{}", tag);
+        let mut rest = 0;
+        while let Some(pos) = lower[rest..].find(&open) {
+            let start = rest + pos;
+            let name_end = start + open.len();
+            rest = name_end;
+            // `<iframes>` or `<embedded>` are other tags
+            if !lower[name_end..].starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/') {
+                continue;
+            }
+            let end = lower[start..].find('>').map_or(lower.len(), |p| start + p);
+            let attributes = &lower[name_end..end];
+            let host = tag_attribute(attributes, source)
+                .map(|(from, to)| &content[name_end + from..name_end + to])
+                .and_then(|src| off_site_host(&src.strip_prefix("//").map_or(src.to_string(), |s| format!("https://{}", s))));
+            frames.push(EmbeddedFrame {
+                tag,
+                host,
+                hidden: is_hidden(attributes),
+                span: Span::in_text(content, start..(end + 1).min(content.len())),
+            });
+            rest = end;
+        }
+    }
+    frames.sort_by_key(|f| f.span.offset);
+    frames
+}
+
+/// Byte range of an attribute's value within a lowercased tag body
+fn tag_attribute(attributes: &str, name: &str) -> Option<(usize, usize)> {
+    let mut rest = 0;
+    while let Some(pos) = attributes[rest..].find(name) {
+        let at = rest + pos;
+        rest = at + name.len();
+        // Whole attribute names only: `data-src` is not `src`
+        let preceded = attributes[..at].ends_with(|c: char| c.is_ascii_whitespace() || matches!(c, '"' | '\'' | '/'));
+        let after = attributes[rest..].trim_start();
+        let Some(value) = after.strip_prefix('=').filter(|_| preceded) else { continue };
+        let value_start = attributes.len() - value.trim_start().len();
+        let value = value.trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let len = value[1..].find(quote).unwrap_or(value.len() - 1);
+                (value_start + 1, value_start + 1 + len)
+            }
+            _ => (value_start, value_start + value.find(|c: char| c.is_ascii_whitespace()).unwrap_or(value.len())),
+        });
+    }
+    None
+}
+
+/// Zero- or one-pixel size, or a style or attribute hiding the element
+fn is_hidden(attributes: &str) -> bool {
+    let tiny = |name| {
+        tag_attribute(attributes, name)
+            .and_then(|(from, to)| attributes[from..to].trim_end_matches("px").parse::<f32>().ok())
+            .is_some_and(|size| size <= 1.0)
+    };
+    let style: String = tag_attribute(attributes, "style")
+        .map(|(from, to)| attributes[from..to].chars().filter(|c| !c.is_whitespace()).collect())
+        .unwrap_or_default();
+    let hidden_attribute = attributes
+        .split(|c: char| c.is_ascii_whitespace() || c == '/')
+        .any(|word| word == "hidden" || word.starts_with("hidden="));
+    tiny("width")
+        || tiny("height")
+        || hidden_attribute
+        || ["display:none", "visibility:hidden", "width:0", "height:0", "opacity:0;"].iter().any(|p| style.contains(p))
+        || style.ends_with("opacity:0")
+}
+
+// Patterns are matched against lowercased content with whitespace and quotes removed
+const KEY_CAPTURE: &[&str] = &[
+    "addeventlistener(keydown",
+    "addeventlistener(keypress",
+    "addeventlistener(keyup",
+    "onkeydown=",
+    "onkeypress=",
+    "onkeyup=",
+];
+const CLIPBOARD_ACCESS: &[&str] = &[
+    "navigator.clipboard.readtext(",
+    "navigator.clipboard.read(",
+    "clipboarddata.getdata(",
+    "execcommand(paste",
+    "execcommand(copy",
+];
+const EXFILTRATION: &[&str] = &["fetch(", "xmlhttprequest", "sendbeacon(", "newimage(", "newwebsocket("];
+
+/// Content lowercased with whitespace and quotes removed, for the pattern lists below
+fn compact_code(content: &str) -> Compacted<'_> {
+    Compacted::new(content, |c| c.is_whitespace() || matches!(c, '"' | '\'' | '`'))
+}
+
+/// Where the first of `patterns` matches, if any does
+fn first_match(compact: &Compacted, patterns: &[&str]) -> Option<Span> {
+    let original = compact.original();
+    patterns.iter().find_map(|p| compact.find(p)).map(|range| Span::in_text(original, range))
+}
+
+/// Keystroke capture or clipboard access combined with a way to send data off
+/// the page, or with each other. Either API alone is common in benign pages.
+pub fn data_theft_signals(content: &str) -> (Vec<&'static str>, Vec<Span>) {
+    let compact = compact_code(content);
+    let (keys, clipboard, exfiltration) = (
+        first_match(&compact, KEY_CAPTURE),
+        first_match(&compact, CLIPBOARD_ACCESS),
+        first_match(&compact, EXFILTRATION),
+    );
+
+    let mut signals = Vec::new();
+    if keys.is_some() && (exfiltration.is_some() || clipboard.is_some()) {
+        signals.push("keystroke capture");
+    }
+    if clipboard.is_some() && (exfiltration.is_some() || keys.is_some()) {
+        signals.push("clipboard access");
+    }
+    let spans = if signals.is_empty() { Vec::new() } else { [keys, clipboard, exfiltration].into_iter().flatten().collect() };
+    (signals, spans)
+}
+
+const ENV_READ: &[&str] = &["process.env", "os.environ", "os.getenv(", "system.getenv(", "deno.env", "env::var"];
+const ENV_DUMP: &[&str] = &[
+    "json.stringify(process.env",
+    "object.keys(process.env",
+    "object.entries(process.env",
+    "...process.env",
+    "dict(os.environ",
+    "os.environ.items(",
+    "os.environ.copy(",
+    "system.getenv()",
+    "deno.env.toobject(",
+    "env::vars(",
+];
+const SECRET_NAMES: &[&str] = &["secret", "token", "password", "passwd", "api_key", "apikey", "private_key", "credential", "aws_"];
+const NETWORK_SEND: &[&str] = &[
+    "requests.post(",
+    "requests.get(",
+    "urllib.request",
+    "http.request(",
+    "https.request(",
+    "axios",
+    "socket.connect(",
+    "socket.send",
+    "net.connect(",
+    "httpclient",
+    "urlconnection",
+    "curl",
+];
+
+/// How code touches environment variables, and where
+#[derive(Debug, Clone)]
+pub struct EnvAccess {
+    /// The first environment read
+    pub read: Span,
+    /// Reads the whole environment rather than named variables
+    pub dump: Option<Span>,
+    /// Reads a secret-looking variable (`*_TOKEN`, `AWS_*`, ...)
+    pub secrets: Option<Span>,
+    /// Also has a way to send data over the network
+    pub network_send: Option<Span>,
+}
+
+/// Environment variable access in code, if any. Reading one variable is
+/// routine; dumping the environment, or reading secrets, next to a network
+/// send is how they get harvested.
+pub fn env_access(content: &str) -> Option<EnvAccess> {
+    let compact = compact_code(content);
+    let read = first_match(&compact, ENV_READ)?;
+    Some(EnvAccess {
+        read,
+        dump: first_match(&compact, ENV_DUMP),
+        secrets: secret_read(&compact),
+        network_send: first_match(&compact, EXFILTRATION).or_else(|| first_match(&compact, NETWORK_SEND)),
+    })
+}
+
+/// The first secret-looking variable named through an environment
+/// accessor: `process.env.AWS_SECRET`, `os.environ["API_KEY"]`,
+/// `os.environ.get("TOKEN")`, `getenv("PASSWORD")`. The same words
+/// elsewhere in the code are not environment reads.
+fn secret_read(compact: &Compacted) -> Option<Span> {
+    let text = compact.as_str();
+    ENV_READ.iter().find_map(|accessor| {
+        text.match_indices(accessor).find_map(|(at, _)| {
+            let rest = &text[at + accessor.len()..];
+            let name = rest.trim_start_matches(['.', '[', '(']);
+            // `process.envelope` is not `process.env`
+            if name.len() == rest.len() && !accessor.ends_with('(') {
+                return None;
+            }
+            let name = name.strip_prefix("get(").unwrap_or(name);
+            let name = &name[..name.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(name.len())];
+            if name.is_empty() || !SECRET_NAMES.iter().any(|secret| name.contains(secret)) {
+                return None;
+            }
+            // The name is a slice of `text`, so its address gives its offset
+            let start = name.as_ptr() as usize - text.as_ptr() as usize;
+            Some(Span::in_text(compact.original(), compact.original_range(start..start + name.len())))
+        })
+    })
+}
+
+const EXTENSION_COOKIES: &[&str] = &["chrome.cookies.", "browser.cookies."];
+const EXTENSION_WEB_REQUEST: &[&str] = &["chrome.webrequest.", "browser.webrequest."];
+const EXTENSION_TABS: &[&str] = &["chrome.tabs.", "browser.tabs."];
+/// Cookie reads, and `webRequest` options exposing headers or bodies
+const EXTENSION_HARVEST: &[&str] = &["cookies.getall(", "cookies.get(", "requestheaders", "requestbody"];
+
+/// Browser-extension APIs that reach beyond the extension's own pages
+#[derive(Debug, Clone)]
+pub struct ExtensionApis {
+    /// `cookies`, `webRequest` and `tabs`, most invasive first, as used
+    pub apis: Vec<&'static str>,
+    pub spans: Vec<Span>,
+    /// Reads cookie values or request headers or bodies
+    pub harvest: Option<Span>,
+    /// Also has a way to send data over the network
+    pub network_send: Option<Span>,
+}
+
+impl ExtensionApis {
+    /// Cookie or traffic access, rather than only tab bookkeeping, which
+    /// nearly every extension does
+    pub fn invasive(&self) -> bool {
+        self.apis.iter().any(|api| *api != "tabs")
+    }
+}
+
+/// Extension APIs the code touches, if any. Reading cookies or traffic and
+/// sending data over the network together is how malicious extensions
+/// harvest sessions.
+pub fn extension_apis(content: &str) -> Option<ExtensionApis> {
+    let compact = compact_code(content);
+    let mut apis = Vec::new();
+    let mut spans = Vec::new();
+    for (api, patterns) in [("cookies", EXTENSION_COOKIES), ("webRequest", EXTENSION_WEB_REQUEST), ("tabs", EXTENSION_TABS)] {
+        if let Some(span) = first_match(&compact, patterns) {
+            apis.push(api);
+            spans.push(span);
+        }
+    }
+    if apis.is_empty() {
+        return None;
+    }
+    Some(ExtensionApis {
+        apis,
+        spans,
+        harvest: first_match(&compact, EXTENSION_HARVEST),
+        network_send: first_match(&compact, EXFILTRATION).or_else(|| first_match(&compact, NETWORK_SEND)),
+    })
+}
+
+const WORKER_SPAWN: &[&str] = &["newworker(", "newsharedworker(", "navigator.hardwareconcurrency"];
+const MINING_JOB: &[&str] = &[
+    "hashrate",
+    "hashespersecond",
+    "hashes_per_second",
+    "getjob",
+    "job_id",
+    "submitshare",
+    "submit_share",
+    "nonce",
+];
+const SUBTLE_DIGEST: &[&str] = &["crypto.subtle.digest(", "subtle.digest("];
+const UNBOUNDED_LOOP: &[&str] = &["while(true)", "while(1)", "while(!0)", "for(;;)"];
+
+/// Signs of in-browser cryptocurrency mining: a miner script or pool
+/// protocol named in `miners`, workers fed mining jobs, or hashing in a loop
+/// that never ends. Hashing on its own, or in a bounded loop over chunks,
+/// is routine.
+pub fn cryptominer_signals(content: &str, miners: &[String]) -> (Vec<String>, Vec<Span>) {
+    let compact = compact_code(content);
+    let mut signals = Vec::new();
+    let mut spans = Vec::new();
+
+    let named = miners.iter().find_map(|miner| {
+        let pattern: String = miner.to_lowercase().chars().filter(|c| !c.is_whitespace() && !matches!(c, '"' | '\'' | '`')).collect();
+        let range = compact.find(&pattern).filter(|_| !pattern.is_empty())?;
+        Some((pattern, Span::in_text(compact.original(), range)))
+    });
+    if let Some((miner, span)) = named {
+        signals.push(format!("miner {}", miner));
+        spans.push(span);
+    }
+    if let (Some(worker), Some(job)) = (first_match(&compact, WORKER_SPAWN), first_match(&compact, MINING_JOB)) {
+        signals.push("worker mining loop".to_string());
+        spans.extend([worker, job]);
+    }
+    if let (Some(digest), Some(lp)) = (first_match(&compact, SUBTLE_DIGEST), first_match(&compact, UNBOUNDED_LOOP)) {
+        signals.push("hashing in an unbounded loop".to_string());
+        spans.extend([lp, digest]);
+    }
+    (signals, spans)
+}
+
+/// Package named in an install command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageInstall {
+    /// `npm` or `pypi`
+    pub ecosystem: &'static str,
+    /// Name without version specifier, lowercased
+    pub name: String,
+    /// The argument naming the package
+    pub span: Span,
+}
+
+const INSTALL_COMMANDS: &[(&[&str], &str)] = &[
+    (&["npm", "install"], "npm"),
+    (&["npm", "i"], "npm"),
+    (&["npm", "add"], "npm"),
+    (&["yarn", "add"], "npm"),
+    (&["pnpm", "add"], "npm"),
+    (&["pip", "install"], "pypi"),
+    (&["pip3", "install"], "pypi"),
+    (&["-m", "pip", "install"], "pypi"),
+];
+
+/// pip options whose next word is their argument rather than a package
+const PIP_OPTIONS_WITH_VALUE: &[&str] = &["-r", "--requirement", "-c", "--constraint", "-e", "--editable", "-i", "--index-url", "--extra-index-url", "-t", "--target"];
+
+/// Packages named by `npm install`/`pip install` style commands, found
+/// as the iterator is advanced, so a caller out of time can stop early
+pub fn find_package_installs(content: &str) -> impl Iterator<Item = PackageInstall> + '_ {
+    // Shell separators end a command, wherever they appear on a line
+    content.split(['\n', ';', '|', '&']).flat_map(move |command| command_installs(content, command))
+}
+
+/// Packages installed by one command of `content`
+fn command_installs(content: &str, command: &str) -> Vec<PackageInstall> {
+    let mut installs = Vec::new();
+    let words: Vec<&str> = command.split_whitespace().collect();
+    for (position, _) in words.iter().enumerate() {
+        let Some((prefix, ecosystem)) = INSTALL_COMMANDS
+            .iter()
+            .find(|(prefix, _)| words[position..].starts_with(prefix))
+        else {
+            continue;
+        };
+        let mut args = words[position + prefix.len()..].iter();
+        while let Some(arg) = args.next() {
+            if PIP_OPTIONS_WITH_VALUE.contains(arg) {
+                args.next();
+            } else if !arg.starts_with('-') {
+                if let Some(name) = package_name(arg, ecosystem) {
+                    // Words are slices of `content`, so their address gives their offset
+                    let offset = arg.as_ptr() as usize - content.as_ptr() as usize;
+                    let span = Span::in_text(content, offset..offset + arg.len());
+                    installs.push(PackageInstall { ecosystem, name, span });
+                }
+            }
+        }
+        break;
+    }
+    installs
+}
+
+/// Strip version specifiers and extras; `None` for paths, URLs and archives
+fn package_name(arg: &str, ecosystem: &str) -> Option<String> {
+    let arg = arg.trim_matches(['"', '\'', '`']);
+    let name = match ecosystem {
+        // `@scope/name@1.2.3` keeps its scope
+        "npm" => match arg.strip_prefix('@') {
+            Some(scoped) => format!("@{}", scoped.split('@').next().unwrap_or("")),
+            None => arg.split('@').next().unwrap_or("").to_string(),
+        },
+        _ => arg.split(['=', '<', '>', '!', '~', '[', ';']).next().unwrap_or("").to_string(),
+    };
+    let local = name.contains(':') || name.starts_with('.') || (name.contains('/') && !name.starts_with('@'));
+    (!name.is_empty() && !local).then(|| name.to_lowercase())
+}
+
+/// Levenshtein distance between two names
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Executable formats recognised by their magic bytes
+const BINARY_MAGIC: &[(&[u8], &str)] = &[(b"\0asm", "WebAssembly"), (b"\x7fELF", "ELF"), (b"MZ", "PE")];
+/// The same magic written as string escapes or byte arrays, compacted and lowercased
+const ESCAPED_MAGIC: &[(&str, &str)] = &[
+    ("\\0asm", "WebAssembly"),
+    ("\\x00asm", "WebAssembly"),
+    ("\\x00\\x61\\x73\\x6d", "WebAssembly"),
+    ("\\u0000asm", "WebAssembly"),
+    ("[0,97,115,109,", "WebAssembly"),
+    ("[0x00,0x61,0x73,0x6d,", "WebAssembly"),
+    ("\\x7felf", "ELF"),
+    ("\\x7f\\x45\\x4c\\x46", "ELF"),
+    ("[127,69,76,70,", "ELF"),
+    ("[0x7f,0x45,0x4c,0x46,", "ELF"),
+];
+/// Shortest base64 run considered a blob rather than an identifier or hash
+const MIN_BLOB_CHARS: usize = 64;
+
+/// An executable found in code
+#[derive(Debug, Clone)]
+pub struct EmbeddedBinary {
+    /// `WebAssembly`, `ELF` or `PE`
+    pub format: &'static str,
+    /// The magic bytes, within the decoded blob for base64
+    pub span: Span,
+}
+
+/// Executables embedded in code as base64 blobs, string escapes or byte
+/// arrays. `MZ` alone is too short to tell apart from text, so PE is only
+/// reported for base64 blobs.
+pub fn embedded_binaries(content: &str) -> Vec<EmbeddedBinary> {
+    use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+    use base64::Engine;
+
+    let mut found = Vec::new();
+    let is_b64 = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '-' | '_');
+    let mut offset = 0;
+    for run in content.split(|c: char| !is_b64(c)) {
+        let start = offset;
+        // Separators are single ASCII bytes or multibyte characters; step over whichever ended the run
+        offset += run.len() + content[offset + run.len()..].chars().next().map_or(0, char::len_utf8);
+        if run.len() < MIN_BLOB_CHARS {
+            continue;
+        }
+        // The magic sits in the first bytes; 12 characters decode to 9
+        let head = &run[..12];
+        let engine = if head.contains(['-', '_']) { &URL_SAFE_NO_PAD } else { &STANDARD_NO_PAD };
+        let Ok(bytes) = engine.decode(head) else { continue };
+        if let Some((magic, format)) = BINARY_MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+            let layer = Layer::Base64 { offset: start, length: run.len() };
+            found.push(EmbeddedBinary { format, span: Span::in_decoded(&bytes, 0..magic.len(), layer) });
+        }
+    }
+
+    let compact = Compacted::new(content, char::is_whitespace);
+    for (pattern, format) in ESCAPED_MAGIC {
+        if found.iter().any(|f| f.format == *format) {
+            continue;
+        }
+        if let Some(range) = compact.find(pattern) {
+            found.push(EmbeddedBinary { format, span: Span::in_text(content, range) });
+        }
+    }
+    found.sort_by_key(|f| f.format);
+    found
+}
+
+/// Bracket nesting deeper than a limit
+#[derive(Debug, Clone)]
+pub struct DeepNesting {
+    /// Deepest stack of open brackets
+    pub depth: usize,
+    /// The bracket that first went past the limit
+    pub span: Span,
+}
+
+/// Bracket nesting of `()`, `[]` and `{}` deeper than `limit`, counted over
+/// the raw text: quoting is not parsed, and a close that does not match the
+/// innermost open bracket is ignored
+pub fn deep_nesting(content: &str, limit: usize) -> Option<DeepNesting> {
+    let mut stack = Vec::new();
+    let mut depth = 0;
+    let mut first_over = None;
+    for (offset, byte) in content.bytes().enumerate() {
+        match byte {
+            b'(' | b'[' | b'{' => {
+                stack.push(byte);
+                depth = depth.max(stack.len());
+                if stack.len() > limit && first_over.is_none() {
+                    first_over = Some(offset);
+                }
+            }
+            b')' | b']' | b'}' => {
+                let open = match byte {
+                    b')' => b'(',
+                    b']' => b'[',
+                    _ => b'{',
+                };
+                if stack.last() == Some(&open) {
+                    stack.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+    let offset = first_over?;
+    Some(DeepNesting { depth, span: Span::in_text(content, offset..offset + 1) })
+}
+
+/// Signs of periodic outbound beaconing (a C2 heartbeat)
+#[derive(Debug, Clone)]
+pub struct Beacon {
+    pub signal: String,
+    pub spans: Vec<Span>,
+}
+
+/// How regular and frequent a timed sequence must be to count as a beacon
+#[derive(Debug, Clone, Copy)]
+pub struct BeaconLimits {
+    /// Fewest events to one destination
+    pub min_events: usize,
+    /// Longest mean interval between them, in seconds
+    pub max_interval_secs: f64,
+    /// Largest standard deviation of the intervals relative to their mean
+    pub max_jitter: f64,
+}
+
+const PERIODIC: &[&str] = &["periodically", "at regular intervals", "heartbeat", "setinterval(", "beacon"];
+const OUTBOUND: &[&str] = &[
+    "send", "post", "ping", "connect to", "check in", "check-in", "phone home", "report to", "upload",
+    "callback", "call back", "http://", "https://", "curl", "wget",
+];
+
+/// Periodic outbound traffic in an action: either a timed sequence of
+/// events (one per line, each starting with a timestamp) with enough
+/// regular, frequent contacts to one destination, or a description of
+/// sending on a schedule ("ping the server every 30 seconds"). Schedules
+/// slower than `max_interval_secs` are routine and not flagged.
+pub fn beacon(content: &str, limits: &BeaconLimits) -> Option<Beacon> {
+    beacon_sequence(content, limits).or_else(|| beacon_description(content, limits))
+}
+
+fn beacon_sequence(content: &str, limits: &BeaconLimits) -> Option<Beacon> {
+    // Destination to (time, span) of each contact
+    let mut contacts: Vec<(&str, Vec<(f64, Span)>)> = Vec::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let mut words = line.split_whitespace();
+        let Some(time) = words.next().and_then(parse_event_time) else { continue };
+        let Some(destination) = words.find(|word| is_destination(word)) else { continue };
+        // `destination` borrows from `line`, which starts at `start` in `content`
+        let at = start + (destination.as_ptr() as usize - line.as_ptr() as usize);
+        let span = Span::in_text(content, at..at + destination.len());
+        match contacts.iter_mut().find(|(d, _)| *d == destination) {
+            Some((_, events)) => events.push((time, span)),
+            None => contacts.push((destination, vec![(time, span)])),
+        }
+    }
+    contacts.into_iter().find_map(|(destination, mut events)| {
+        if events.len() < limits.min_events {
+            return None;
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let intervals: Vec<f64> = events.windows(2).map(|w| w[1].0 - w[0].0).collect();
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        if mean <= 0.0 || mean > limits.max_interval_secs {
+            return None;
+        }
+        let variance = intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+        let jitter = variance.sqrt() / mean;
+        if jitter > limits.max_jitter {
+            return None;
+        }
+        let signal = format!(
+            "{} contacts with {} every {:.0}s (jitter {:.0}%)",
+            events.len(),
+            destination,
+            mean,
+            jitter * 100.0
+        );
+        let last = events.len() - 1;
+        Some(Beacon { signal, spans: vec![events[0].1.clone(), events[last].1.clone()] })
+    })
+}
+
+fn beacon_description(content: &str, limits: &BeaconLimits) -> Option<Beacon> {
+    let lowered = content.to_lowercase();
+    let outbound = OUTBOUND.iter().find_map(|p| lowered.find(p).map(|at| at..at + p.len()))?;
+    let schedule = match every_interval(&lowered) {
+        Some((secs, _)) if secs > limits.max_interval_secs => return None,
+        Some((secs, range)) => Some((format!("every {:.0}s", secs), range)),
+        None => None,
+    }
+    .or_else(|| {
+        PERIODIC
+            .iter()
+            .find_map(|p| lowered.find(p).map(|at| (p.trim_end_matches('(').to_string(), at..at + p.len())))
+    })?;
+    // Lowercasing can shift offsets in non-ASCII text; spans need the original's
+    let span = |range: std::ops::Range<usize>| {
+        (content.len() == lowered.len() && content.is_char_boundary(range.start) && content.is_char_boundary(range.end))
+            .then(|| Span::in_text(content, range))
+    };
+    Some(Beacon {
+        signal: format!("outbound contact on a schedule ({})", schedule.0),
+        spans: [span(schedule.1), span(outbound)].into_iter().flatten().collect(),
+    })
+}
+
+/// Seconds between repetitions in "every 30 seconds", "every 5 min",
+/// "every minute" and the like, and where the phrase is
+fn every_interval(lowered: &str) -> Option<(f64, std::ops::Range<usize>)> {
+    lowered.match_indices("every ").find_map(|(at, _)| {
+        let rest = &lowered[at + 6..];
+        let mut words = rest.split_whitespace();
+        let first = words.next()?;
+        let (count, unit) = match first.parse::<f64>() {
+            Ok(count) => (count, words.next()?),
+            Err(_) => (1.0, first),
+        };
+        let unit = unit.trim_end_matches(|c: char| !c.is_alphabetic());
+        let secs = match unit {
+            "ms" | "millisecond" | "milliseconds" => count / 1000.0,
+            "s" | "sec" | "secs" | "second" | "seconds" => count,
+            "m" | "min" | "mins" | "minute" | "minutes" => count * 60.0,
+            "h" | "hr" | "hrs" | "hour" | "hours" => count * 3600.0,
+            _ => return None,
+        };
+        let end = at + 6 + rest.find(unit)? + unit.len();
+        (secs > 0.0).then_some((secs, at..end))
+    })
+}
+
+/// Machine-regular timing across a sequence of timed actions
+#[derive(Debug, Clone)]
+pub struct BotTiming {
+    pub events: usize,
+    /// Mean interval between events, in seconds
+    pub mean_secs: f64,
+    /// Standard deviation of the intervals over their mean
+    pub jitter: f64,
+    pub spans: Vec<Span>,
+}
+
+/// Timed actions (one per line, each starting with a timestamp) spaced so
+/// evenly that a script rather than a person is likely behind them: at
+/// least `min_events` of them, whatever they do, with the intervals'
+/// standard deviation within `max_jitter` of their mean. People pause,
+/// read and hesitate; their intervals spread far wider than that.
+pub fn bot_timing(content: &str, min_events: usize, max_jitter: f64) -> Option<BotTiming> {
+    let mut events: Vec<(f64, Span)> = Vec::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let Some(stamp) = line.split_whitespace().next() else { continue };
+        let Some(time) = parse_event_time(stamp) else { continue };
+        let at = start + (stamp.as_ptr() as usize - line.as_ptr() as usize);
+        events.push((time, Span::in_text(content, at..at + stamp.len())));
+    }
+    if events.len() < min_events.max(3) {
+        return None;
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let intervals: Vec<f64> = events.windows(2).map(|w| w[1].0 - w[0].0).collect();
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    if mean <= 0.0 {
+        return None;
+    }
+    let variance = intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+    let jitter = variance.sqrt() / mean;
+    if jitter > max_jitter {
+        return None;
+    }
+    let last = events.len() - 1;
+    Some(BotTiming { events: events.len(), mean_secs: mean, jitter, spans: vec![events[0].1.clone(), events[last].1.clone()] })
+}
+
+/// Seconds of an event timestamp: a plain number of seconds, `HH:MM:SS`
+/// (fractions allowed) or RFC 3339
+fn parse_event_time(stamp: &str) -> Option<f64> {
+    let stamp = stamp.trim_matches(|c| matches!(c, '[' | ']' | ','));
+    if let Ok(secs) = stamp.parse::<f64>() {
+        return secs.is_finite().then_some(secs);
+    }
+    if let Ok(at) = chrono::DateTime::parse_from_rfc3339(stamp) {
+        return Some(at.timestamp_millis() as f64 / 1000.0);
+    }
+    let mut parts = stamp.split(':');
+    let (h, m, s) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let (h, m, s) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?, s.parse::<f64>().ok()?);
+    (h < 24 && m < 60 && (0.0..61.0).contains(&s)).then_some(h as f64 * 3600.0 + m as f64 * 60.0 + s)
+}
+
+/// A URL, or a `host` / `host:port` / `ip:port` an event was sent to
+fn is_destination(word: &str) -> bool {
+    if word.contains("://") {
+        return url::Url::parse(word).is_ok_and(|u| u.host_str().is_some());
+    }
+    let host = word.rsplit_once(':').map_or(word, |(host, port)| if port.parse::<u16>().is_ok() { host } else { word });
+    host.contains('.')
+        && !host.starts_with('.')
+        && !host.ends_with('.')
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-'))
+        && host.chars().any(|c| c.is_ascii_alphabetic() || c.is_ascii_digit())
+        && host.parse::<f64>().is_err()
+}
+
+/// A DNS-over-HTTPS query whose name carries encoded data
+#[derive(Debug, Clone)]
+pub struct DohTunnel {
+    /// Resolver host the URL targets
+    pub resolver: String,
+    /// The encoded label, as queried
+    pub label: String,
+    /// The query parameter holding the name
+    pub span: Span,
+}
+
+/// Shannon entropy above which a long label reads as encoded data rather
+/// than words, in bits per character
+const ENCODED_LABEL_ENTROPY: f64 = 3.5;
+
+/// A URL querying one of `resolvers` over DNS-over-HTTPS (`?dns=`, the
+/// RFC 8484 wire format, or `?name=`, the JSON API) for a name with a label
+/// of at least `min_label_len` characters that looks encoded: hex, base32
+/// or base64 alphabet, with high entropy. Tunnels split data into such
+/// labels; real hostnames rarely have them.
+pub fn doh_tunnel(url: &str, resolvers: &[String], min_label_len: usize) -> Option<DohTunnel> {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    let parsed = url::Url::parse(url.trim()).ok()?;
+    let domains = domain::parent_domains(parsed.host_str()?, true);
+    let resolver = resolvers.iter().find(|r| domains.contains(&r.trim_end_matches('.').to_ascii_lowercase()))?;
+    let (param, name) = parsed.query_pairs().find_map(|(key, value)| match key.as_ref() {
+        "name" => Some(("name", value.into_owned())),
+        "dns" => URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).ok().and_then(|wire| dns_question_name(&wire)).map(|n| ("dns", n)),
+        _ => None,
+    })?;
+    let label = name.split('.').find(|label| {
+        label.len() >= min_label_len
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '/' | '='))
+            && entropy(label) >= ENCODED_LABEL_ENTROPY
+    })?;
+    let at = ['?', '&'].iter().find_map(|sep| url.find(&format!("{}{}=", sep, param))).map_or(0, |at| at + 1);
+    let end = url[at..].find(['&', '#']).map_or(url.trim_end().len(), |e| at + e);
+    Some(DohTunnel { resolver: resolver.clone(), label: label.to_string(), span: Span::in_text(url, at..end) })
+}
+
+/// Name of the first question of a DNS wire-format message
+fn dns_question_name(wire: &[u8]) -> Option<String> {
+    let mut at = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *wire.get(at)? as usize;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers cannot appear in a lone question
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(wire.get(at + 1..at + 1 + len)?).into_owned());
+        at += 1 + len;
+    }
+    (!labels.is_empty()).then(|| labels.join("."))
+}
+
+/// Shannon entropy of a string's characters, in bits per character
+fn entropy(text: &str) -> f64 {
+    let mut counts = std::collections::HashMap::new();
+    for c in text.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let total = text.chars().count() as f64;
+    counts.values().map(|&n| n as f64 / total).map(|p| -p * p.log2()).sum()
+}
+
+/// A shell launched with a base64-encoded command, and what it decodes to
+#[derive(Debug, Clone)]
+pub struct EncodedSpawn {
+    /// Shell launched, as named in the action
+    pub shell: String,
+    /// The decoded command; empty when the argument did not decode
+    pub decoded: String,
+    /// Attack indicators found in the decoded command, nested encoded
+    /// commands included
+    pub indicators: Vec<&'static str>,
+    /// The encoded argument
+    pub span: Span,
+}
+
+/// Indicators looked for in a decoded command, matched as whole words
+/// against its lowercased text
+const DECODED_INDICATORS: &[(&str, &str)] = &[
+    ("iex", "invoke-expression"),
+    ("invoke-expression", "invoke-expression"),
+    ("downloadstring", "download cradle"),
+    ("downloaddata", "download cradle"),
+    ("downloadfile", "file download"),
+    ("net.webclient", "web client"),
+    ("invoke-webrequest", "web request"),
+    ("iwr", "web request"),
+    ("start-bitstransfer", "file download"),
+    ("frombase64string", "further decoding"),
+    ("hidden", "hidden window"),
+    ("bypass", "execution policy bypass"),
+    ("add-mppreference", "defender tampering"),
+    ("set-mppreference", "defender tampering"),
+    ("amsiutils", "amsi bypass"),
+];
+
+/// Encoded commands nested deeper than this are not decoded further
+const MAX_ENCODED_DEPTH: usize = 3;
+
+/// The first of `shells` (e.g. `powershell`, matched on the program name
+/// with any directory and `.exe` dropped) launched with `-EncodedCommand`
+/// or any prefix of it PowerShell accepts (`-enc`, `-e`, `-ec`). The
+/// argument is decoded as base64 UTF-16LE, as PowerShell does, and the
+/// result scanned for download-and-run and evasion indicators and for
+/// further encoded commands. A shell launched any other way is routine.
+pub fn encoded_spawn(content: &str, shells: &[String]) -> Option<EncodedSpawn> {
+    let words = word_spans(content);
+    words.iter().enumerate().find_map(|(i, &(word, _))| {
+        let program = program_name(word);
+        let shell = shells.iter().find(|s| s.eq_ignore_ascii_case(&program))?;
+        let flag = words[i + 1..].iter().take_while(|(w, _)| !is_command_separator(w)).position(|(w, _)| is_encoded_flag(w))?;
+        let &(argument, at) = words.get(i + 2 + flag)?;
+        let argument = argument.trim_matches(|c| matches!(c, '"' | '\''));
+        let decoded = decode_powershell(argument).unwrap_or_default();
+        let mut indicators = Vec::new();
+        scan_decoded(&decoded, shells, 1, &mut indicators);
+        Some(EncodedSpawn {
+            shell: shell.clone(),
+            decoded,
+            indicators,
+            span: Span::in_text(content, at..at + words[i + 2 + flag].0.len()),
+        })
+    })
+}
+
+/// Indicators in a decoded command, decoding nested encoded commands too
+fn scan_decoded(decoded: &str, shells: &[String], depth: usize, indicators: &mut Vec<&'static str>) {
+    let lowered = decoded.to_lowercase();
+    for &(word, indicator) in DECODED_INDICATORS {
+        if contains_word(&lowered, word) && !indicators.contains(&indicator) {
+            indicators.push(indicator);
+        }
+    }
+    if depth < MAX_ENCODED_DEPTH {
+        if let Some(nested) = encoded_spawn(decoded, shells) {
+            if !indicators.contains(&"nested encoded command") {
+                indicators.push("nested encoded command");
+            }
+            scan_decoded(&nested.decoded, shells, depth + 1, indicators);
+        }
+    }
+}
+
+/// Whitespace-separated words and their byte offsets
+fn word_spans(content: &str) -> Vec<(&str, usize)> {
+    content.split_whitespace().map(|word| (word, word.as_ptr() as usize - content.as_ptr() as usize)).collect()
+}
+
+/// Program a command word runs: the file name without quotes, directory or
+/// `.exe`, lowercased
+fn program_name(word: &str) -> String {
+    let word = word.trim_matches(|c| matches!(c, '"' | '\'' | '(' | ')' | '`'));
+    let name = word.rsplit(['\\', '/']).next().unwrap_or(word).to_ascii_lowercase();
+    name.strip_suffix(".exe").map(str::to_string).unwrap_or(name)
+}
+
+/// Whether a word ends the command it follows
+fn is_command_separator(word: &str) -> bool {
+    matches!(word, "&&" | "||" | "|" | ";" | "&")
+}
+
+/// `-EncodedCommand` and its abbreviations, with `-` or `/`
+fn is_encoded_flag(word: &str) -> bool {
+    let Some(name) = word.strip_prefix(['-', '/']) else { return false };
+    let name = name.to_ascii_lowercase();
+    name == "ec" || (!name.is_empty() && "encodedcommand".starts_with(&name))
+}
+
+/// A base64 UTF-16LE command, as `-EncodedCommand` takes it
+fn decode_powershell(argument: &str) -> Option<String> {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    let bytes = STANDARD.decode(argument).ok()?;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).ok()
+}
+
+/// Whether `word` appears in `text` not flanked by letters, digits, `-` or `_`
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_');
+    text.match_indices(word).any(|(at, _)| {
+        !text[..at].chars().next_back().is_some_and(is_word_char)
+            && !text[at + word.len()..].chars().next().is_some_and(is_word_char)
+    })
+}
@@ -0,0 +1,34 @@
+// rust/api/src/dashboard.rs
+//! Built-in dashboard, compiled in with the `dashboard` feature
+//!
+//! A single static page embedded in the binary. It draws everything from
+//! `/api/stats`, `/api/stats/stream` and `/api/admin/cache` in the browser;
+//! the server only hands out the file. With API keys configured, the page
+//! asks for one and trades it for a session cookie (see `auth`).
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::auth::{self, ApiKeys};
+
+const PAGE: &str = include_str!("../assets/dashboard.html");
+
+pub async fn page() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(PAGE)
+}
+
+/// Open a session for the key this request was let in with, and set its id
+/// as the session cookie
+pub async fn session(req: HttpRequest, keys: web::Data<ApiKeys>) -> HttpResponse {
+    if !keys.enabled() {
+        // Every route is open, so there is nothing to sign in to
+        return HttpResponse::NoContent().finish();
+    }
+    let cookie = Cookie::build(auth::SESSION_COOKIE, keys.open_session())
+        .path("/api")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(req.connection_info().scheme() == "https")
+        .finish();
+    HttpResponse::NoContent().cookie(cookie).finish()
+}
@@ -0,0 +1,116 @@
+// rust/api/src/detector.rs
+//! Detection backends
+//!
+//! Each request threat type is served by a `Detector` registered under that
+//! type in the `DetectorRegistry`; dispatch, fallback chains and polyglot
+//! widening all go through the registry, so adding a backend means
+//! implementing the trait and registering it. The built-in `url` and `code`
+//! detectors run their configured stage pipelines.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use crate::pipeline::{self, StageInput};
+use crate::{DetectionContext, ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// A detection backend for one request threat type
+pub trait Detector: Send + Sync {
+    /// `threat_type` reported in responses, e.g. `phishing` for `url`
+    fn response_type(&self) -> &'static str;
+
+    fn detect(&self, req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse;
+}
+
+/// Detectors keyed by request threat type
+pub struct DetectorRegistry {
+    detectors: HashMap<String, Box<dyn Detector>>,
+}
+
+impl DetectorRegistry {
+    /// Registry holding the built-in `url`, `code` and `action` detectors
+    pub fn builtin() -> Self {
+        let mut registry = Self { detectors: HashMap::new() };
+        registry.register("url", PhishingDetector);
+        registry.register("code", MalwareDetector);
+        registry.register("action", BehaviorDetector);
+        registry
+    }
+
+    /// Add or replace the detector for a request threat type
+    pub fn register(&mut self, threat_type: &str, detector: impl Detector + 'static) {
+        self.detectors.insert(threat_type.to_string(), Box::new(detector));
+    }
+
+    /// Drop the detector for a request threat type, disabling it
+    pub fn unregister(&mut self, threat_type: &str) {
+        self.detectors.remove(threat_type);
+    }
+
+    pub fn get(&self, threat_type: &str) -> Option<&dyn Detector> {
+        self.detectors.get(threat_type).map(Box::as_ref)
+    }
+
+    /// Registered request threat types, sorted
+    pub fn threat_types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = self.detectors.keys().map(String::as_str).collect();
+        types.sort_unstable();
+        types
+    }
+}
+
+/// URL phishing heuristics (`url`)
+pub struct PhishingDetector;
+
+impl Detector for PhishingDetector {
+    fn response_type(&self) -> &'static str {
+        "phishing"
+    }
+
+    fn detect(&self, req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+        let input = StageInput {
+            content: &req.content,
+            context: req.context.as_deref(),
+            host: url::Url::parse(&req.content).ok().and_then(|u| u.host_str().map(str::to_string)),
+            deadline: Cell::default(),
+        };
+        pipeline::run("url", "phishing", "URL appears legitimate", &input, ctx, req.explain)
+    }
+}
+
+/// Script and markup malware heuristics (`code`)
+pub struct MalwareDetector;
+
+impl Detector for MalwareDetector {
+    fn response_type(&self) -> &'static str {
+        "malware"
+    }
+
+    fn detect(&self, req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+        let input = StageInput { content: &req.content, context: req.context.as_deref(), host: None, deadline: Cell::default() };
+        pipeline::run("code", "malware", "Code appears safe", &input, ctx, req.explain)
+    }
+}
+
+/// Behavioural analysis (`action`)
+pub struct BehaviorDetector;
+
+impl Detector for BehaviorDetector {
+    fn response_type(&self) -> &'static str {
+        "behavioral"
+    }
+
+    fn detect(&self, req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+        let input = StageInput { content: &req.content, context: req.context.as_deref(), host: None, deadline: Cell::default() };
+        pipeline::run("action", "behavioral", "Behavior analysis pending", &input, ctx, req.explain)
+    }
+}
+
+pub fn detect_phishing(url: &str, context: Option<&str>, ctx: &DetectionContext) -> ThreatDetectionResponse {
+    let input = StageInput {
+        content: url,
+        context,
+        host: url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)),
+        deadline: Cell::default(),
+    };
+    pipeline::run("url", "phishing", "URL appears legitimate", &input, ctx, false)
+}
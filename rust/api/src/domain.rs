@@ -0,0 +1,138 @@
+// rust/api/src/domain.rs
+//! Registrable-domain (eTLD+1) extraction backed by the Public Suffix List
+//!
+//! A copy of the list is bundled into the binary; if the data directory
+//! holds a newer `public_suffix_list.dat` it is loaded instead, and it can be
+//! refreshed at runtime through the admin API. Every domain comparison in
+//! the API goes through `registrable_domain`, or `parent_domains` for
+//! matching hosts against domain lists, rather than "last two labels" or
+//! plain suffix logic, which break on `.co.uk`, `.com.au` and private
+//! suffixes such as `github.io`.
+//!
+//! The active list keeps the size and SHA-256 of the text it was parsed
+//! from, for the engine state manifest.
+
+use publicsuffix::{List, Psl};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock};
+
+pub const PSL_FILE_NAME: &str = "public_suffix_list.dat";
+
+const BUNDLED_PSL: &str = include_str!("../data/public_suffix_list.dat");
+
+/// The list in use and the digest of its source text
+struct Active {
+    list: Arc<List>,
+    bytes: usize,
+    sha256: String,
+}
+
+impl Active {
+    fn parse(text: &str) -> Result<Self, publicsuffix::Error> {
+        Ok(Self { list: Arc::new(text.parse()?), bytes: text.len(), sha256: format!("{:x}", Sha256::digest(text)) })
+    }
+}
+
+static LIST: LazyLock<RwLock<Active>> =
+    LazyLock::new(|| RwLock::new(Active::parse(BUNDLED_PSL).expect("bundled public suffix list is valid")));
+
+fn list() -> Arc<List> {
+    LIST.read().unwrap().list.clone()
+}
+
+/// Size and SHA-256 of the source of the list in use, bundled or loaded
+pub fn digest() -> (usize, String) {
+    let active = LIST.read().unwrap();
+    (active.bytes, active.sha256.clone())
+}
+
+/// Registrable domain of a host, e.g. `login.example.co.uk` → `example.co.uk`.
+///
+/// Unicode hosts are converted to punycode first. Returns `None` for IP
+/// addresses, invalid hosts, and hosts that are themselves a public suffix.
+pub fn registrable_domain(host: &str) -> Option<String> {
+    let host = normalize_host(host)?;
+    let domain = list().domain(host.as_bytes())?;
+    String::from_utf8(domain.as_bytes().to_vec()).ok()
+}
+
+/// A host and its parent domains, nearest first, as matched against domain
+/// lists: `a.example.co.uk`, `example.co.uk`. The walk stops at the
+/// registrable domain unless `match_public_suffixes` is set, when it carries
+/// on through the suffix (`co.uk`, `uk`). An IP address, or a host that is
+/// itself a public suffix when the walk stops early, is only itself.
+pub fn parent_domains(host: &str, match_public_suffixes: bool) -> Vec<String> {
+    let Some(host) = normalize_host(host) else {
+        return vec![host.trim_end_matches('.').to_lowercase()];
+    };
+    let registrable = registrable_domain(&host);
+    if registrable.is_none() && !match_public_suffixes {
+        return vec![host];
+    }
+    let mut domains = Vec::new();
+    let mut candidate = host.as_str();
+    loop {
+        domains.push(candidate.to_string());
+        if !match_public_suffixes && registrable.as_deref() == Some(candidate) {
+            return domains;
+        }
+        match candidate.split_once('.') {
+            Some((_, parent)) => candidate = parent,
+            None => return domains,
+        }
+    }
+}
+
+/// Lowercase ASCII form of a domain name, or `None` for IPs and invalid hosts
+fn normalize_host(host: &str) -> Option<String> {
+    match url::Host::parse(host.trim_end_matches('.')).ok()? {
+        url::Host::Domain(domain) => Some(domain.to_lowercase()),
+        url::Host::Ipv4(_) | url::Host::Ipv6(_) => None,
+    }
+}
+
+/// Replace the active list with `public_suffix_list.dat` from `data_dir`.
+///
+/// Returns `Ok(false)` when the directory has no list file, leaving the
+/// current list in place.
+pub fn load_from_dir(data_dir: &Path) -> io::Result<bool> {
+    let path = data_dir.join(PSL_FILE_NAME);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let active = Active::parse(&text)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", path.display(), e)))?;
+
+    *LIST.write().unwrap() = active;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrable_domain_follows_the_public_suffix_list() {
+        assert_eq!(registrable_domain("a.b.co.uk").as_deref(), Some("b.co.uk"));
+        assert_eq!(registrable_domain("shop.example.com.au").as_deref(), Some("example.com.au"));
+        assert_eq!(registrable_domain("WWW.Example.COM.").as_deref(), Some("example.com"));
+        assert_eq!(registrable_domain("alice.github.io").as_deref(), Some("alice.github.io"));
+        assert_eq!(registrable_domain("mail.bücher.de").as_deref(), Some("xn--bcher-kva.de"));
+        assert_eq!(registrable_domain("co.uk"), None);
+        assert_eq!(registrable_domain("github.io"), None);
+        assert_eq!(registrable_domain("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn parent_domains_stop_at_the_registrable_domain_unless_asked() {
+        assert_eq!(parent_domains("a.b.co.uk", false), ["a.b.co.uk", "b.co.uk"]);
+        assert_eq!(parent_domains("a.b.co.uk", true), ["a.b.co.uk", "b.co.uk", "co.uk", "uk"]);
+        assert_eq!(parent_domains("co.uk", false), ["co.uk"]);
+        assert_eq!(parent_domains("192.0.2.1", true), ["192.0.2.1"]);
+    }
+}
@@ -0,0 +1,176 @@
+// rust/api/src/emergency.rs
+//! Temporary high-priority rules pushed during an active campaign
+//!
+//! An emergency rule matches content containing its pattern (case
+//! insensitive) and adds its weight to the verdict ahead of every pipeline
+//! stage, until it expires. Rules created from a tenant's triage queue only
+//! apply to that tenant's requests. Rules live only in memory; pushing one swaps
+//! the whole set under a write lock, so a detection sees it entirely or not
+//! at all.
+
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::clock::SharedClock;
+
+/// Body of `POST /api/rules/emergency`
+#[derive(Debug, Deserialize)]
+pub struct EmergencyRuleRequest {
+    /// Request threat type the rule applies to; every type when unset
+    #[serde(default)]
+    pub threat_type: Option<String>,
+    pub pattern: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Lifetime in seconds
+    pub ttl_secs: u64,
+    /// Tenant the rule is confined to; set by triage, never by the body
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmergencyRule {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threat_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub pattern: String,
+    pub weight: f32,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl EmergencyRule {
+    fn matches(&self, threat_type: &str, tenant: Option<&str>, content: &str) -> bool {
+        self.threat_type.as_deref().is_none_or(|t| t == threat_type)
+            && self.tenant.as_deref().is_none_or(|t| Some(t) == tenant)
+            && content.to_lowercase().contains(&self.pattern)
+    }
+}
+
+pub struct EmergencyRules {
+    /// Newest first
+    rules: RwLock<Arc<Vec<EmergencyRule>>>,
+    clock: SharedClock,
+}
+
+impl EmergencyRules {
+    pub fn new(clock: SharedClock) -> Self {
+        Self { rules: RwLock::default(), clock }
+    }
+
+    /// Validate a request and put the rule in front of the active set
+    pub fn push(&self, request: EmergencyRuleRequest, max_ttl: Duration) -> Result<EmergencyRule, String> {
+        let pattern = request.pattern.trim().to_lowercase();
+        if pattern.is_empty() {
+            return Err("pattern must not be empty".to_string());
+        }
+        if !(request.weight > 0.0 && request.weight <= 1.0) {
+            return Err("weight must be above 0 and at most 1".to_string());
+        }
+        let ttl = Duration::from_secs(request.ttl_secs);
+        if ttl.is_zero() || ttl > max_ttl {
+            return Err(format!("ttl_secs must be between 1 and {}", max_ttl.as_secs()));
+        }
+
+        let mut id = [0u8; 8];
+        SystemRandom::new().fill(&mut id).map_err(|_| "system random source unavailable".to_string())?;
+        let created_at = self.clock.utc();
+        let rule = EmergencyRule {
+            id: hex::encode(id),
+            threat_type: request.threat_type,
+            tenant: request.tenant,
+            reason: request.reason.unwrap_or_else(|| format!("Matches emergency rule for {:?}", pattern)),
+            pattern,
+            weight: request.weight,
+            created_at,
+            expires_at: created_at + chrono::Duration::from_std(ttl).map_err(|e| e.to_string())?,
+        };
+
+        let mut rules = self.rules.write().unwrap();
+        let mut next = Vec::with_capacity(rules.len() + 1);
+        next.push(rule.clone());
+        next.extend(rules.iter().filter(|r| r.expires_at > created_at).cloned());
+        *rules = Arc::new(next);
+        Ok(rule)
+    }
+
+    /// Drop expired rules; true if any were dropped
+    pub fn prune(&self) -> bool {
+        let now = self.clock.utc();
+        if self.rules.read().unwrap().iter().all(|r| r.expires_at > now) {
+            return false;
+        }
+        let mut rules = self.rules.write().unwrap();
+        let live: Vec<EmergencyRule> = rules.iter().filter(|r| r.expires_at > now).cloned().collect();
+        let pruned = live.len() < rules.len();
+        *rules = Arc::new(live);
+        pruned
+    }
+
+    pub fn list(&self) -> Arc<Vec<EmergencyRule>> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Unexpired rules matching a request from `tenant`
+    pub fn matching(&self, threat_type: &str, tenant: Option<&str>, content: &str) -> Vec<EmergencyRule> {
+        let now = self.clock.utc();
+        let rules = self.rules.read().unwrap();
+        if rules.is_empty() {
+            return Vec::new();
+        }
+        rules.iter().filter(|r| r.expires_at > now && r.matches(threat_type, tenant, content)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn request(pattern: &str, ttl_secs: u64) -> EmergencyRuleRequest {
+        EmergencyRuleRequest { threat_type: Some("url".to_string()), pattern: pattern.to_string(), weight: 0.9, reason: None, ttl_secs, tenant: None }
+    }
+
+    #[test]
+    fn rule_matches_from_its_push_until_its_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let rules = EmergencyRules::new(clock.clone());
+        let rule = rules.push(request("Evil.Example", 30), Duration::from_secs(60)).unwrap();
+        assert_eq!(rule.pattern, "evil.example");
+        assert_eq!(rules.matching("url", None, "https://EVIL.example/login").len(), 1);
+        assert!(rules.matching("code", None, "https://evil.example/login").is_empty());
+        assert!(rules.matching("url", None, "https://good.example/").is_empty());
+
+        clock.advance(Duration::from_secs(29));
+        assert!(!rules.prune());
+        assert_eq!(rules.matching("url", None, "https://evil.example/").len(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert!(rules.matching("url", None, "https://evil.example/").is_empty());
+        assert!(rules.prune());
+        assert!(rules.list().is_empty());
+    }
+
+    #[test]
+    fn push_rejects_bad_rules() {
+        let rules = EmergencyRules::new(Arc::new(ManualClock::new()));
+        let max_ttl = Duration::from_secs(60);
+        assert!(rules.push(request(" ", 30), max_ttl).is_err());
+        assert!(rules.push(request("evil", 0), max_ttl).is_err());
+        assert!(rules.push(request("evil", 61), max_ttl).is_err());
+        assert!(rules.push(EmergencyRuleRequest { weight: 1.5, ..request("evil", 30) }, max_ttl).is_err());
+        assert!(rules.list().is_empty());
+    }
+}
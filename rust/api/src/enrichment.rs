@@ -0,0 +1,649 @@
+// rust/api/src/enrichment.rs
+//! Slow lookups merged into a verdict after the heuristics
+//!
+//! The heuristics answer in microseconds; lookups that leave the process
+//! take far longer. A request's `enrich` field picks how they are combined:
+//! `none` skips them, `wait` runs them before answering (bounded by
+//! `enrichment_timeout_ms`), and `async` answers with the heuristic verdict
+//! at once, marked `enrichment_pending`, and finishes in the background.
+//!
+//! An async detection is kept under its detection id, pending and then
+//! complete, for `GET /api/detections/{id}`, and its final verdict is
+//! published on `/api/detections/stream`; both show a caller only its own
+//! tenant's detections. At most `enrichment_max_pending` async lookups run
+//! at once; a request beyond that gets its heuristic verdict without
+//! enrichment rather than queueing more work. Two lookups run on a URL, side by
+//! side and within the one timeout:
+//!
+//! - its host is resolved in DNS; a host that does not resolve adds
+//!   `unresolvable_host_weight`;
+//! - a URL on one of `url_shorteners` is expanded by following its
+//!   redirects, at most `redirect_max_hops` of them. The hop count and the
+//!   final status are reported as `redirect_chain`. A chain of at least
+//!   `long_redirect_chain_hops` hops adds `long_redirect_chain_weight`. A
+//!   chain that ends in a 4xx or 5xx, or on a parked domain's page, adds
+//!   `dead_end_redirect_weight`. A hop to an internal address (loopback,
+//!   private, link-local, shared, benchmarking, reserved and the like, in
+//!   IPv4, IPv6 or IPv4 embedded in IPv6) is not followed unless its host is
+//!   itself a listed shortener, so a short link cannot point the expansion
+//!   at internal services. Nor is a hop whose host does not resolve. The
+//!   client resolves hosts through `PublicResolver`, which drops internal
+//!   addresses from the answer it connects to, so a host that resolves
+//!   differently by the time of the request (DNS rebinding) is still kept
+//!   off them. No proxy is used.
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+use crate::config::Settings;
+use crate::domain;
+use crate::{ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Final verdicts buffered for slow stream subscribers
+const EVENT_BUFFER: usize = 256;
+
+/// How a request wants its verdict enriched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Heuristics only
+    #[default]
+    None,
+    /// Answer once the lookups finish or time out
+    Wait,
+    /// Answer at once; the final verdict follows
+    Async,
+}
+
+/// Accepted values of `enrich`, for validation messages
+pub const MODES: &[&str] = &["none", "wait", "async"];
+
+/// What a lookup found
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub reason: String,
+    pub weight: f32,
+}
+
+/// Where following a shortened URL's redirects led
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedirectChain {
+    /// Redirects followed
+    pub hops: usize,
+    pub final_url: String,
+    /// Status of the last response; unset when no response came back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub final_status: Option<u16>,
+    /// The last response was itself a redirect, not followed because the
+    /// hop limit was reached or it pointed at a private address
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// The final page reads as a parked domain
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub parked: bool,
+}
+
+/// What the lookups for one request found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Findings to be merged into the verdict
+    pub findings: Vec<Finding>,
+    /// Present when the URL was a shortener's
+    pub redirect_chain: Option<RedirectChain>,
+}
+
+/// Result of running the lookups for one request
+pub enum Outcome {
+    /// Every lookup finished
+    Done(Report),
+    /// The lookups overran `enrichment_timeout_ms`; the heuristic verdict stands
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Complete,
+}
+
+/// An async detection as `GET /api/detections/{id}` reports it
+#[derive(Debug, Clone, Serialize)]
+pub struct Detection {
+    pub detection_id: String,
+    /// Tenant that submitted it (empty without one)
+    #[serde(skip)]
+    pub tenant: String,
+    pub status: Status,
+    /// Whether enrichment changed the verdict; unset while pending
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+    /// The heuristic verdict while pending, the merged one once complete
+    pub response: ThreatDetectionResponse,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct EnrichmentStats {
+    /// Verdicts whose lookups finished, in either mode
+    pub enriched: u64,
+    /// Enriched verdicts that ended up different from the heuristic one
+    pub changed: u64,
+    pub timed_out: u64,
+    /// Async lookups still running
+    pub pending: u64,
+    pub max_pending: usize,
+    /// Async requests answered without enrichment because `max_pending`
+    /// lookups were already running
+    pub dropped: u64,
+}
+
+/// Phrases of parking pages, matched on the lowercased start of the page
+const PARKING_MARKERS: &[&str] = &[
+    "this domain is for sale",
+    "this domain may be for sale",
+    "buy this domain",
+    "domain is parked",
+    "parked free",
+    "sedoparking",
+    "parkingcrew",
+    "bodis.com",
+    "domain has expired",
+];
+
+/// Bytes of a final page read for parking markers
+const PARKING_PAGE_BYTES: usize = 64 * 1024;
+
+pub struct Enrichments {
+    detections: Mutex<LruCache<String, Detection>>,
+    events: broadcast::Sender<Detection>,
+    timeout: Duration,
+    unresolvable_host_weight: f32,
+    client: reqwest::Client,
+    url_shorteners: Vec<String>,
+    redirect_max_hops: usize,
+    long_redirect_chain_hops: usize,
+    long_redirect_chain_weight: f32,
+    dead_end_redirect_weight: f32,
+    max_pending: usize,
+    permits: Arc<Semaphore>,
+    enriched: AtomicU64,
+    changed: AtomicU64,
+    timed_out: AtomicU64,
+    pending: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Enrichments {
+    pub fn new(settings: &Settings) -> reqwest::Result<Self> {
+        let capacity = NonZeroUsize::new(settings.enrichment_results_capacity.max(1)).unwrap();
+        let url_shorteners: Vec<String> = settings.url_shorteners.iter().map(|h| h.to_ascii_lowercase()).collect();
+        // Redirects are followed one at a time, so each hop can be checked
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver { shorteners: url_shorteners.clone() }))
+            .user_agent(concat!("amd-security-api/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self {
+            detections: Mutex::new(LruCache::new(capacity)),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            timeout: Duration::from_millis(settings.enrichment_timeout_ms),
+            unresolvable_host_weight: settings.unresolvable_host_weight,
+            client,
+            url_shorteners,
+            redirect_max_hops: settings.redirect_max_hops,
+            long_redirect_chain_hops: settings.long_redirect_chain_hops,
+            long_redirect_chain_weight: settings.long_redirect_chain_weight,
+            dead_end_redirect_weight: settings.dead_end_redirect_weight,
+            max_pending: settings.enrichment_max_pending,
+            permits: Arc::new(Semaphore::new(settings.enrichment_max_pending)),
+            enriched: AtomicU64::new(0),
+            changed: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether any lookup is configured
+    pub fn enabled(&self) -> bool {
+        self.unresolvable_host_weight > 0.0 || self.expands()
+    }
+
+    /// Whether shortened URLs are expanded
+    fn expands(&self) -> bool {
+        !self.url_shorteners.is_empty() && (self.long_redirect_chain_weight > 0.0 || self.dead_end_redirect_weight > 0.0)
+    }
+
+    /// Names of the configured lookups, for `/api/capabilities`
+    pub fn lookups(&self) -> Vec<&'static str> {
+        let mut lookups = Vec::new();
+        if self.unresolvable_host_weight > 0.0 {
+            lookups.push("dns");
+        }
+        if self.expands() {
+            lookups.push("redirects");
+        }
+        lookups
+    }
+
+    /// URL a request's lookups would be about, if any
+    pub fn target(req: &ThreatDetectionRequest) -> Option<Url> {
+        if req.threat_type != "url" {
+            return None;
+        }
+        Url::parse(req.content.trim()).ok().filter(|url| url.host().is_some())
+    }
+
+    /// Run every lookup for `url`, within the timeout
+    pub async fn lookup(&self, url: &Url) -> Outcome {
+        let lookups = async { tokio::join!(self.resolve(url), self.expand(url)) };
+        let Ok((unresolved, redirect_chain)) = tokio::time::timeout(self.timeout, lookups).await else {
+            self.timed_out.fetch_add(1, Ordering::Relaxed);
+            return Outcome::TimedOut;
+        };
+        let mut findings: Vec<Finding> = unresolved.into_iter().collect();
+        if let Some(chain) = &redirect_chain {
+            findings.extend(self.chain_findings(chain));
+        }
+        Outcome::Done(Report { findings, redirect_chain })
+    }
+
+    /// A finding when the URL's domain does not resolve
+    async fn resolve(&self, url: &Url) -> Option<Finding> {
+        // Addresses have nothing to resolve
+        let Some(url::Host::Domain(host)) = url.host() else { return None };
+        if self.unresolvable_host_weight <= 0.0 {
+            return None;
+        }
+        match tokio::net::lookup_host((host, 443)).await.map(|mut addresses| addresses.next()) {
+            Ok(Some(_)) => None,
+            Ok(None) | Err(_) => Some(Finding {
+                reason: format!("Host {} does not resolve", host),
+                weight: self.unresolvable_host_weight,
+            }),
+        }
+    }
+
+    fn chain_findings(&self, chain: &RedirectChain) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        if self.long_redirect_chain_weight > 0.0 && self.long_redirect_chain_hops > 0 && chain.hops >= self.long_redirect_chain_hops {
+            findings.push(Finding {
+                reason: format!(
+                    "Shortened URL redirects through {}{} hops",
+                    if chain.truncated { "more than " } else { "" },
+                    chain.hops
+                ),
+                weight: self.long_redirect_chain_weight,
+            });
+        }
+        if self.dead_end_redirect_weight > 0.0 {
+            let reason = match chain.final_status {
+                _ if chain.parked => Some(format!("Shortened URL ends on a parked domain ({})", chain.final_url)),
+                Some(status @ 400..=599) => Some(format!("Shortened URL ends in HTTP {} ({})", status, chain.final_url)),
+                _ => None,
+            };
+            findings.extend(reason.map(|reason| Finding { reason, weight: self.dead_end_redirect_weight }));
+        }
+        findings
+    }
+
+    /// Follow a shortened URL's redirects, when it is one
+    async fn expand(&self, start: &Url) -> Option<RedirectChain> {
+        if !self.expands() || !self.is_shortener(start) {
+            return None;
+        }
+        let mut url = start.clone();
+        let mut hops = 0;
+        loop {
+            let chain = |url: &Url, status: Option<u16>, truncated: bool, parked: bool| RedirectChain {
+                hops,
+                final_url: url.to_string(),
+                final_status: status,
+                truncated,
+                parked,
+            };
+            let Ok(response) = self.client.get(url.clone()).send().await else {
+                return Some(chain(&url, None, false, false));
+            };
+            let status = response.status();
+            let next = status
+                .is_redirection()
+                .then(|| response.headers().get(reqwest::header::LOCATION)?.to_str().ok())
+                .flatten()
+                .and_then(|location| url.join(location).ok())
+                .filter(|next| matches!(next.scheme(), "http" | "https"));
+            let Some(next) = next else {
+                let parked = status.is_success() && is_parking_page(response).await;
+                return Some(chain(&url, Some(status.as_u16()), false, parked));
+            };
+            if hops >= self.redirect_max_hops || !self.may_follow(&next).await {
+                return Some(chain(&url, Some(status.as_u16()), true, false));
+            }
+            hops += 1;
+            url = next;
+        }
+    }
+
+    fn is_shortener(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|host| is_listed(&self.url_shorteners, host))
+    }
+
+    /// Whether a redirect target stays off internal addresses. This only
+    /// decides whether the chain goes on; the client's resolver enforces
+    /// it again on the addresses it actually connects to.
+    async fn may_follow(&self, url: &Url) -> bool {
+        if self.is_shortener(url) {
+            return true;
+        }
+        match url.host() {
+            Some(url::Host::Ipv4(ip)) => !is_internal(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => !is_internal(IpAddr::V6(ip)),
+            Some(url::Host::Domain(host)) => match tokio::net::lookup_host((host, 0)).await {
+                Ok(addresses) => {
+                    let addresses: Vec<SocketAddr> = addresses.collect();
+                    !addresses.is_empty() && addresses.iter().all(|address| !is_internal(address.ip()))
+                }
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Count a finished enrichment
+    pub fn record(&self, changed: bool) {
+        self.enriched.fetch_add(1, Ordering::Relaxed);
+        if changed {
+            self.changed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A turn to run async lookups, held until they finish; `None`, counted
+    /// as dropped, when `max_pending` are already running
+    pub fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.permits.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    /// Keep a tenant's async detection while its lookups run
+    pub fn start(&self, tenant: &str, response: &ThreatDetectionResponse) -> Option<String> {
+        let detection_id = response.detection_id.clone()?;
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        self.detections.lock().unwrap().put(detection_id.clone(), Detection {
+            detection_id: detection_id.clone(),
+            tenant: tenant.to_string(),
+            status: Status::Pending,
+            changed: None,
+            response: response.clone(),
+        });
+        Some(detection_id)
+    }
+
+    /// Store and publish the final verdict of a tenant's async detection
+    pub fn finish(&self, tenant: &str, detection_id: String, changed: bool, response: ThreatDetectionResponse) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        let detection = Detection {
+            detection_id: detection_id.clone(),
+            tenant: tenant.to_string(),
+            status: Status::Complete,
+            changed: Some(changed),
+            response,
+        };
+        self.detections.lock().unwrap().put(detection_id, detection.clone());
+        // No subscribers is not an error
+        let _ = self.events.send(detection);
+    }
+
+    /// A tenant's async detection
+    pub fn get(&self, tenant: &str, detection_id: &str) -> Option<Detection> {
+        self.detections.lock().unwrap().get(detection_id).filter(|d| d.tenant == tenant).cloned()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Detection> {
+        self.events.subscribe()
+    }
+
+    pub fn stats(&self) -> EnrichmentStats {
+        EnrichmentStats {
+            enriched: self.enriched.load(Ordering::Relaxed),
+            changed: self.changed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+            max_pending: self.max_pending,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Rough bytes held, for the memory watchdog
+    pub fn estimated_bytes(&self) -> usize {
+        let detections = self.detections.lock().unwrap();
+        detections.iter().map(|(id, d)| id.len() * 2 + d.tenant.len() + d.response.estimated_bytes()).sum()
+    }
+}
+
+/// Whether `host` is one of `hosts` or a subdomain of one
+fn is_listed(hosts: &[String], host: &str) -> bool {
+    let domains = domain::parent_domains(host, true);
+    hosts.iter().any(|listed| domains.contains(listed))
+}
+
+/// The enrichment client's resolver: a host's addresses less internal
+/// ones, unless the host is a listed shortener, and an error if none are
+/// left. The connection is made to what this returns, so the addresses
+/// checked are the ones used.
+struct PublicResolver {
+    shorteners: Vec<String>,
+}
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let trusted = is_listed(&self.shorteners, name.as_str());
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| trusted || !is_internal(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Addresses that are not on the public internet: loopback, private,
+/// shared (CGNAT), link-local, benchmarking, documentation, multicast,
+/// reserved and unspecified ones, and IPv4 ones embedded in IPv6
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => is_internal_v6(ip),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let embedded_v4 = |high: u16, low: u16| Ipv4Addr::from(((high as u32) << 16) | low as u32);
+    // ::a.b.c.d, ::ffff:a.b.c.d and 64:ff9b::a.b.c.d carry an IPv4 address at the end
+    let compatible = segments[..6] == [0; 6];
+    let mapped = segments[..6] == [0, 0, 0, 0, 0, 0xffff];
+    let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    if compatible || mapped || nat64 {
+        return is_internal_v4(embedded_v4(segments[6], segments[7]));
+    }
+    // 2002:a.b.c.d::/48 is 6to4, with the IPv4 address after the prefix
+    if segments[0] == 0x2002 {
+        return is_internal_v4(embedded_v4(segments[1], segments[2]));
+    }
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x2001 && segments[1] < 0x0200)
+}
+
+/// Whether the start of a page reads as a parked domain's
+async fn is_parking_page(mut response: reqwest::Response) -> bool {
+    let mut page = Vec::new();
+    while page.len() < PARKING_PAGE_BYTES {
+        match response.chunk().await {
+            Ok(Some(chunk)) => page.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+    let page = String::from_utf8_lossy(&page).to_lowercase();
+    PARKING_MARKERS.iter().any(|marker| page.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A local server playing a shortener: `/r/{n}` redirects to
+    /// `/r/{n - 1}` and `/r/0` is gone; `/to/{path}` redirects once to
+    /// `/{path}`; `/to-internal` redirects to the same server by address
+    async fn shortener() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        actix_rt::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                actix_rt::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let (status, location, body) = match path.as_str() {
+                        "/r/0" => ("404 Not Found", None, "gone"),
+                        "/ok" => ("200 OK", None, "<h1>Welcome</h1>"),
+                        "/parked" => ("200 OK", None, "<p>This domain is for sale! Make an offer.</p>"),
+                        "/to-internal" => ("302 Found", Some(format!("http://127.0.0.1:{}/ok", port)), ""),
+                        _ => match (path.strip_prefix("/r/").and_then(|n| n.parse::<u32>().ok()), path.strip_prefix("/to/")) {
+                            (Some(n), _) => ("302 Found", Some(format!("/r/{}", n - 1)), ""),
+                            (_, Some(target)) => ("301 Moved Permanently", Some(format!("/{}", target)), ""),
+                            _ => ("404 Not Found", None, ""),
+                        },
+                    };
+                    let location = location.map(|l| format!("Location: {}\r\n", l)).unwrap_or_default();
+                    let response = format!(
+                        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        location,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        port
+    }
+
+    fn enrichments() -> Enrichments {
+        Enrichments::new(&Settings {
+            url_shorteners: vec!["localhost".to_string()],
+            unresolvable_host_weight: 0.0,
+            enrichment_timeout_ms: 10_000,
+            ..Settings::default()
+        })
+        .unwrap()
+    }
+
+    async fn report(enrichments: &Enrichments, url: &str) -> Report {
+        match enrichments.lookup(&Url::parse(url).unwrap()).await {
+            Outcome::Done(report) => report,
+            Outcome::TimedOut => panic!("lookup of {} timed out", url),
+        }
+    }
+
+    fn reasons(report: &Report) -> Vec<&str> {
+        report.findings.iter().map(|f| f.reason.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn a_long_chain_to_a_dead_end_adds_both_findings() {
+        let port = shortener().await;
+        let enrichments = enrichments();
+        let report = report(&enrichments, &format!("http://localhost:{}/r/5", port)).await;
+        let chain = report.redirect_chain.as_ref().unwrap();
+        assert_eq!((chain.hops, chain.final_status, chain.truncated), (5, Some(404), false));
+        assert_eq!(chain.final_url, format!("http://localhost:{}/r/0", port));
+        assert_eq!(
+            reasons(&report),
+            [
+                "Shortened URL redirects through 5 hops".to_string(),
+                format!("Shortened URL ends in HTTP 404 (http://localhost:{}/r/0)", port)
+            ]
+        );
+        assert!(report.findings.iter().all(|f| f.weight == 0.3));
+
+        // Cut off at the hop limit
+        let report = self::report(&enrichments, &format!("http://localhost:{}/r/20", port)).await;
+        let chain = report.redirect_chain.as_ref().unwrap();
+        assert_eq!((chain.hops, chain.final_status, chain.truncated), (10, Some(302), true));
+        assert_eq!(reasons(&report), ["Shortened URL redirects through more than 10 hops"]);
+    }
+
+    #[actix_web::test]
+    async fn parked_pages_are_dead_ends_and_a_single_hop_to_a_page_is_not() {
+        let port = shortener().await;
+        let enrichments = enrichments();
+        let parked = report(&enrichments, &format!("http://localhost:{}/to/parked", port)).await;
+        assert!(parked.redirect_chain.as_ref().unwrap().parked);
+        assert_eq!(reasons(&parked), [format!("Shortened URL ends on a parked domain (http://localhost:{}/parked)", port)]);
+
+        let ordinary = report(&enrichments, &format!("http://localhost:{}/to/ok", port)).await;
+        let chain = ordinary.redirect_chain.as_ref().unwrap();
+        assert_eq!((chain.hops, chain.final_status, chain.parked), (1, Some(200), false));
+        assert!(ordinary.findings.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn internal_hops_are_not_followed_and_other_hosts_are_not_expanded() {
+        let port = shortener().await;
+        let enrichments = enrichments();
+        let report = report(&enrichments, &format!("http://localhost:{}/to-internal", port)).await;
+        let chain = report.redirect_chain.as_ref().unwrap();
+        assert_eq!((chain.hops, chain.final_status, chain.truncated), (0, Some(302), true));
+
+        let unlisted = self::report(&enrichments, &format!("http://127.0.0.1:{}/r/5", port)).await;
+        assert!(unlisted.redirect_chain.is_none());
+        assert!(unlisted.findings.is_empty());
+    }
+}
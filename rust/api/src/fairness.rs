@@ -0,0 +1,298 @@
+// rust/api/src/fairness.rs
+//! Fair sharing of batch capacity between clients
+//!
+//! At most `batch_concurrency` batch items are processed at once across all
+//! batches. Each item takes a slot before it runs. When slots run short,
+//! freed ones go to the waiting key (tenant, or client address without
+//! one) that has received the least service for its weight, so items from
+//! different keys interleave instead of queueing behind whichever batch
+//! arrived first. A key that goes idle and comes back starts level with the
+//! keys already waiting, rather than with credit saved up while away.
+
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+use crate::clock::SharedClock;
+
+/// Keys whose counters are kept for `/api/stats`; the least recent go first
+const TRACKED_KEYS: usize = 1000;
+
+/// Queue state of a key with items waiting or running
+struct Active {
+    weight: u32,
+    /// Service received so far, in items divided by weight
+    virtual_time: f64,
+    waiting: VecDeque<(oneshot::Sender<Slot>, Instant)>,
+    running: usize,
+}
+
+/// Cumulative counters of one key
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct KeyStats {
+    pub weight: u32,
+    /// Items waiting for a slot now
+    pub queued: usize,
+    /// Items holding a slot now
+    pub running: usize,
+    /// Items granted a slot since startup
+    pub granted: u64,
+    /// Mean and longest wait for a slot
+    pub avg_wait_ms: f64,
+    pub max_wait_ms: u64,
+    #[serde(skip)]
+    total_wait_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FairnessStats {
+    pub capacity: usize,
+    pub running: usize,
+    pub queued: usize,
+    pub keys: BTreeMap<String, KeyStats>,
+}
+
+struct State {
+    free: usize,
+    active: HashMap<String, Active>,
+    stats: LruCache<String, KeyStats>,
+}
+
+pub struct FairScheduler {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Time source for slot waits
+    clock: SharedClock,
+}
+
+/// A batch item's slot, returned when dropped. It is handed over through
+/// the waiter's channel, so a waiter that goes away before receiving it
+/// still gives it back.
+pub struct Slot {
+    /// `None` once a slot that was never handed over has been disarmed
+    scheduler: Option<Arc<FairScheduler>>,
+    key: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release(&self.key);
+        }
+    }
+}
+
+impl FairScheduler {
+    /// Scheduler for `capacity` concurrent items; 0 disables it
+    pub fn new(capacity: usize, clock: SharedClock) -> Self {
+        Self {
+            capacity,
+            clock,
+            state: Mutex::new(State {
+                free: capacity,
+                active: HashMap::new(),
+                stats: LruCache::new(NonZeroUsize::new(TRACKED_KEYS).unwrap()),
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Wait for a slot for one item of `key`
+    pub async fn acquire(self: &Arc<Self>, key: &str, weight: u32) -> Option<Slot> {
+        if !self.enabled() {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            if !state.active.contains_key(key) {
+                // Start level with the keys already in line, not with old credit
+                let floor = state.active.values().map(|a| a.virtual_time).fold(f64::INFINITY, f64::min);
+                let virtual_time = if floor.is_finite() { floor } else { 0.0 };
+                state.active.insert(key.to_string(), Active { weight: 0, virtual_time, waiting: VecDeque::new(), running: 0 });
+            }
+            let active = state.active.get_mut(key).unwrap();
+            active.weight = weight.max(1);
+            active.waiting.push_back((sender, self.clock.now()));
+            dispatch(self, state);
+        }
+        // The sender is only dropped unsent if the scheduler is gone
+        receiver.await.ok()
+    }
+
+    fn release(self: &Arc<Self>, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.free += 1;
+        if let Some(active) = state.active.get_mut(key) {
+            active.running -= 1;
+            if active.running == 0 && active.waiting.is_empty() {
+                state.active.remove(key);
+            }
+        }
+        dispatch(self, state);
+    }
+
+    pub fn stats(&self) -> FairnessStats {
+        let state = self.state.lock().unwrap();
+        let mut keys: BTreeMap<String, KeyStats> = state.stats.iter().map(|(k, s)| (k.clone(), *s)).collect();
+        for (key, active) in &state.active {
+            let stats = keys.entry(key.clone()).or_default();
+            stats.weight = active.weight;
+            stats.queued = active.waiting.len();
+            stats.running = active.running;
+        }
+        FairnessStats {
+            capacity: self.capacity,
+            running: self.capacity - state.free,
+            queued: state.active.values().map(|a| a.waiting.len()).sum(),
+            keys,
+        }
+    }
+}
+
+/// Hand free slots to waiting items, least-served key first
+fn dispatch(scheduler: &Arc<FairScheduler>, state: &mut State) {
+    while state.free > 0 {
+        let next = state
+            .active
+            .iter()
+            .filter(|(_, a)| !a.waiting.is_empty())
+            .min_by(|(ka, a), (kb, b)| a.virtual_time.total_cmp(&b.virtual_time).then_with(|| ka.cmp(kb)))
+            .map(|(key, _)| key.clone());
+        let Some(key) = next else { return };
+        let active = state.active.get_mut(&key).unwrap();
+        let (sender, queued_at) = active.waiting.pop_front().unwrap();
+        // The waiting request went away; its turn passes without using a
+        // slot. The slot comes back unsent and is disarmed rather than
+        // released, since that would take the state lock held here.
+        let slot = Slot { scheduler: Some(scheduler.clone()), key: key.clone() };
+        if let Err(mut slot) = sender.send(slot) {
+            slot.scheduler = None;
+            if active.running == 0 && active.waiting.is_empty() {
+                state.active.remove(&key);
+            }
+            continue;
+        }
+        // A receiver dropped from here on releases the slot, but only once
+        // this lock is free and the slot has been counted
+        active.running += 1;
+        active.virtual_time += 1.0 / active.weight as f64;
+        state.free -= 1;
+
+        let wait_ms = scheduler.clock.now().saturating_duration_since(queued_at).as_millis() as u64;
+        let weight = active.weight;
+        let stats = state.stats.get_or_insert_mut(key, KeyStats::default);
+        stats.weight = weight;
+        stats.granted += 1;
+        stats.total_wait_ms += wait_ms;
+        stats.max_wait_ms = stats.max_wait_ms.max(wait_ms);
+        stats.avg_wait_ms = stats.total_wait_ms as f64 / stats.granted as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio::sync::Semaphore;
+
+    use crate::clock::SystemClock;
+
+    /// Items run by a test: each notes its grant, then holds its slot until
+    /// the test releases one
+    struct Items {
+        scheduler: Arc<FairScheduler>,
+        granted: Rc<RefCell<Vec<&'static str>>>,
+        release: Arc<Semaphore>,
+        tasks: Vec<actix_rt::task::JoinHandle<()>>,
+    }
+
+    impl Items {
+        fn new(capacity: usize) -> Self {
+            Self {
+                scheduler: Arc::new(FairScheduler::new(capacity, Arc::new(SystemClock))),
+                granted: Rc::default(),
+                release: Arc::new(Semaphore::new(0)),
+                tasks: Vec::new(),
+            }
+        }
+
+        fn submit(&mut self, key: &'static str, weight: u32, count: usize) {
+            for _ in 0..count {
+                let (scheduler, granted, release) = (self.scheduler.clone(), self.granted.clone(), self.release.clone());
+                self.tasks.push(actix_rt::spawn(async move {
+                    let _slot = scheduler.acquire(key, weight).await;
+                    granted.borrow_mut().push(key);
+                    release.acquire().await.unwrap().forget();
+                }));
+            }
+        }
+
+        /// Finish `count` running items and wait for the slots to be handed on
+        async fn finish(&self, count: usize) {
+            let expected = (self.granted.borrow().len() + count).min(self.tasks.len());
+            self.release.add_permits(count);
+            self.until(|items| items.granted.borrow().len() == expected).await;
+        }
+
+        async fn until(&self, done: impl Fn(&Self) -> bool) {
+            for _ in 0..100_000 {
+                if done(self) {
+                    return;
+                }
+                tokio::task::yield_now().await;
+            }
+            panic!("items did not settle");
+        }
+    }
+
+    #[actix_web::test]
+    async fn small_batch_is_served_while_a_huge_one_is_in_flight() {
+        let mut items = Items::new(2);
+        items.submit("huge", 1, 5000);
+        items.until(|items| items.scheduler.stats().queued == 4998).await;
+        items.submit("small", 1, 3);
+        items.until(|items| items.scheduler.stats().queued == 5001).await;
+
+        // Freed slots alternate between the keys instead of draining the huge batch first
+        items.finish(6).await;
+        let granted = items.granted.borrow().clone();
+        assert_eq!(granted.iter().filter(|k| **k == "small").count(), 3, "{:?}", granted);
+        let stats = items.scheduler.stats();
+        assert_eq!((stats.keys["huge"].queued, stats.keys["small"].queued), (4995, 0));
+
+        items.release.add_permits(5003);
+        for task in items.tasks.drain(..) {
+            task.await.unwrap();
+        }
+        let stats = items.scheduler.stats();
+        assert_eq!((stats.running, stats.queued), (0, 0));
+        assert_eq!((stats.keys["huge"].granted, stats.keys["small"].granted), (5000, 3));
+    }
+
+    #[actix_web::test]
+    async fn slots_are_shared_in_proportion_to_weight() {
+        let mut items = Items::new(1);
+        // Hold the only slot so both keys are queued before any is served
+        let held = items.scheduler.acquire("heavy", 3).await;
+        items.submit("heavy", 3, 40);
+        items.submit("light", 1, 40);
+        items.until(|items| items.scheduler.stats().queued == 80).await;
+        drop(held);
+        items.until(|items| items.granted.borrow().len() == 1).await;
+        items.finish(39).await;
+
+        let heavy = items.granted.borrow().iter().filter(|k| **k == "heavy").count();
+        assert!((29..=31).contains(&heavy), "heavy got {} of the first 40 slots", heavy);
+    }
+}
@@ -0,0 +1,109 @@
+// rust/api/src/fingerprints.rs
+//! Cross-type history of identical content
+//!
+//! The same payload often arrives as `code` and again as a `url` or inside
+//! an action, and each threat type is scored and cached on its own. This
+//! index links them: content is reduced to a fingerprint (SHA-256 of the
+//! lowercased content with whitespace collapsed), and every served verdict
+//! is recorded under it. Responses list what was seen before for their
+//! fingerprint. History is kept per tenant, so one tenant cannot learn what
+//! another has submitted. The index is LRU-bounded by tenant and
+//! fingerprint, and each keeps only its most recent sightings.
+
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use crate::thresholds::Verdict;
+
+/// Sightings kept per fingerprint, newest last
+const SIGHTINGS_PER_FINGERPRINT: usize = 50;
+
+/// Fingerprint of content, insensitive to case and whitespace layout
+pub fn fingerprint(content: &str) -> String {
+    let normalized = content.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+/// Fresh id for a detection that carries no signature id
+pub fn detection_id() -> String {
+    let mut id = [0u8; 16];
+    // A failing system RNG leaves the id zeroed, which only weakens linkage
+    let _ = SystemRandom::new().fill(&mut id);
+    hex::encode(id)
+}
+
+/// One served verdict for a fingerprint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sighting {
+    pub detection_id: String,
+    /// Request threat type
+    pub threat_type: String,
+    pub verdict: Verdict,
+    pub confidence: f32,
+    pub seen_at: DateTime<Utc>,
+}
+
+/// Earlier verdicts for the same content, as included in responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviouslySeen {
+    pub fingerprint: String,
+    /// Newest first
+    pub detections: Vec<Sighting>,
+}
+
+/// Tenant (empty without one) and fingerprint
+type Key = (String, String);
+
+pub struct FingerprintIndex {
+    entries: Mutex<LruCache<Key, VecDeque<Sighting>>>,
+}
+
+impl FingerprintIndex {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self { entries: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    /// Record a tenant's sighting, returning the ones before it, if any
+    pub fn record(&self, tenant: &str, fingerprint: &str, sighting: Sighting) -> Option<PreviouslySeen> {
+        let mut entries = self.entries.lock().unwrap();
+        let sightings = entries.get_or_insert_mut((tenant.to_string(), fingerprint.to_string()), VecDeque::new);
+        let previous = (!sightings.is_empty()).then(|| PreviouslySeen {
+            fingerprint: fingerprint.to_string(),
+            detections: sightings.iter().rev().cloned().collect(),
+        });
+        sightings.push_back(sighting);
+        if sightings.len() > SIGHTINGS_PER_FINGERPRINT {
+            sightings.pop_front();
+        }
+        previous
+    }
+
+    /// Rough bytes held by the index
+    pub fn estimated_bytes(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|((tenant, fingerprint), sightings)| {
+                tenant.len()
+                    + fingerprint.len()
+                    + sightings
+                        .iter()
+                        .map(|s| std::mem::size_of::<Sighting>() + s.detection_id.len() + s.threat_type.len())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Every sighting a tenant has for a fingerprint, newest first
+    pub fn history(&self, tenant: &str, fingerprint: &str) -> Option<Vec<Sighting>> {
+        let entries = self.entries.lock().unwrap();
+        let key = (tenant.to_string(), fingerprint.to_string());
+        entries.peek(&key).map(|sightings| sightings.iter().rev().cloned().collect())
+    }
+}
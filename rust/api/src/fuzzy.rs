@@ -0,0 +1,97 @@
+// rust/api/src/fuzzy.rs
+//! Near-duplicate lookup for the verdict cache
+//!
+//! Content is reduced to a 64-bit simhash over its word tokens and token
+//! pairs, so small edits (a changed comment, a renamed variable) flip only a
+//! few bits. The index remembers the simhash of recently computed verdicts
+//! together with their exact cache key; a request that misses the exact
+//! cache can reuse the verdict of content within `max_distance` bits,
+//! provided everything else that shapes a verdict (tenant, rules, threat
+//! type, context) is identical.
+//!
+//! Only threat verdicts are indexed unless `fuzzy_cache_reuse_safe` is set:
+//! appending a payload to content known to be clean is itself a small edit.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Below this many tokens a simhash says too little to trust a match
+const MIN_TOKENS: usize = 16;
+
+/// 64-bit simhash of `content`, or `None` for content too short to compare
+pub fn simhash(content: &str) -> Option<u64> {
+    let tokens: Vec<String> = content
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if tokens.len() < MIN_TOKENS {
+        return None;
+    }
+
+    let mut weights = [0i32; 64];
+    let pairs = tokens.windows(2).map(|w| (w[0].as_str(), w[1].as_str()));
+    let features = tokens.iter().map(|t| (t.as_str(), "")).chain(pairs);
+    for feature in features {
+        let mut hasher = DefaultHasher::new();
+        feature.hash(&mut hasher);
+        let hash = hasher.finish();
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    Some(weights.iter().enumerate().fold(0u64, |acc, (bit, &w)| if w > 0 { acc | 1 << bit } else { acc }))
+}
+
+struct Entry {
+    scope: String,
+    simhash: u64,
+    key_hash: String,
+}
+
+/// Simhashes of recently cached verdicts, oldest first
+pub struct FuzzyIndex {
+    entries: Mutex<VecDeque<Entry>>,
+    capacity: usize,
+}
+
+impl FuzzyIndex {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Mutex::new(VecDeque::new()), capacity }
+    }
+
+    /// Remember the cache key of a verdict for `simhash` within `scope`
+    pub fn insert(&self, scope: String, simhash: u64, key_hash: String) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(Entry { scope, simhash, key_hash });
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Rough bytes held by the index
+    pub fn estimated_bytes(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().map(|e| std::mem::size_of::<Entry>() + e.scope.len() + e.key_hash.len()).sum()
+    }
+
+    /// Cache keys within `max_distance` bits in the same scope, closest first
+    pub fn nearest(&self, scope: &str, simhash: u64, max_distance: u32) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let mut found: Vec<(u32, &str)> = entries
+            .iter()
+            .rev()
+            .filter(|e| e.scope == scope)
+            .map(|e| ((e.simhash ^ simhash).count_ones(), e.key_hash.as_str()))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        found.sort_by_key(|(distance, _)| *distance);
+        found.into_iter().map(|(_, key)| key.to_string()).collect()
+    }
+}
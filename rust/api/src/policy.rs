@@ -0,0 +1,87 @@
+// rust/api/src/policy.rs
+//! Response policies: how much detection logic a caller is shown
+//!
+//! Reasons and the explain trace tell an attacker what to change, so a
+//! deployment can hide them from some callers. Named policies are defined
+//! in `response_policies`; a tenant's `response_policy` picks one, and
+//! `default_response_policy` covers callers without one. With neither set,
+//! responses pass through untouched.
+//!
+//! Policies apply only when a verdict is serialized for the caller. Cached
+//! verdicts, fingerprint history, recordings and audit records keep the
+//! full detail.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::thresholds::Verdict;
+use crate::ThreatDetectionResponse;
+
+/// Name reported for the implicit pass-through policy
+pub const PASS_THROUGH: &str = "full";
+
+/// Generic categories by response threat type; `safe` covers safe verdicts
+const DEFAULT_CATEGORIES: &[(&str, &str)] = &[
+    ("phishing", "Suspicious link"),
+    ("malware", "Suspicious code"),
+    ("behavioral", "Suspicious action"),
+    ("safe", "No threat detected"),
+];
+
+/// What callers see of reasons and the explain trace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Detail {
+    /// Passed through untouched
+    #[default]
+    Full,
+    /// Reasons replaced by one category; no trace
+    Generic,
+    /// No reasons and no trace
+    Suppress,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponsePolicy {
+    pub detail: Detail,
+    /// Overrides of the generic categories, keyed like the built-in ones
+    pub categories: HashMap<String, String>,
+}
+
+impl ResponsePolicy {
+    /// Whether the policy changes anything
+    pub fn rewrites(&self) -> bool {
+        self.detail != Detail::Full
+    }
+
+    /// Reasons as this policy shows them for a verdict
+    pub fn reasons(&self, threat_type: &str, verdict: Verdict, reasons: Vec<String>) -> Vec<String> {
+        match self.detail {
+            Detail::Full => reasons,
+            Detail::Generic => vec![self.category(threat_type, verdict)],
+            Detail::Suppress => Vec::new(),
+        }
+    }
+
+    /// Rewrite a response in place; returns whether anything changed
+    pub fn apply(&self, response: &mut ThreatDetectionResponse) -> bool {
+        if !self.rewrites() {
+            return false;
+        }
+        let reasons = std::mem::take(&mut response.reasons);
+        response.reasons = self.reasons(&response.threat_type, response.verdict, reasons);
+        response.trace.clear();
+        true
+    }
+
+    fn category(&self, threat_type: &str, verdict: Verdict) -> String {
+        let key = if verdict == Verdict::Safe { "safe" } else { threat_type };
+        self.categories
+            .get(key)
+            .map(String::as_str)
+            .or_else(|| DEFAULT_CATEGORIES.iter().find(|(k, _)| *k == key).map(|(_, c)| *c))
+            .unwrap_or("Suspicious content")
+            .to_string()
+    }
+}
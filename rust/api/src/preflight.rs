@@ -0,0 +1,406 @@
+// rust/api/src/preflight.rs
+//! Startup checks of the configuration and every file it names
+//!
+//! Each artifact is loaded the way the server loads it and every failure is
+//! collected, so one run reports them all. A problem is either a config
+//! error (a bad setting or malformed file contents) or an environment error
+//! (a file missing, unreadable or unwritable), and the exit code says
+//! which, for deployment tooling.
+//!
+//! With `--warn-only` the server starts anyway when every problem is with
+//! an optional artifact, running without it; `/api/ready` lists what was
+//! skipped. Settings errors and the honeytoken store are never skipped.
+
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::{Settings, TenantSettings};
+use crate::honeytoken::{self, HoneytokenStore};
+use crate::lists::DetectionLists;
+use crate::signing::Signer;
+use crate::{auth, blocklist, brand_assets, clock, domain, tenant, tls};
+
+/// Exit status when the configuration or a file's contents are wrong (`EX_CONFIG`)
+pub const EXIT_CONFIG: u8 = 78;
+/// Exit status when only files are missing or inaccessible (`EX_IOERR`)
+pub const EXIT_ENVIRONMENT: u8 = 74;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Class {
+    Config,
+    Environment,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    /// What was being loaded: a setting, list or data file
+    pub artifact: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Setting key, line or entry the problem is at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub problem: String,
+    pub suggestion: String,
+    pub class: Class,
+    /// The server can run without the artifact under `--warn-only`
+    pub skippable: bool,
+    /// Only an entry is affected; the artifact itself still loads
+    #[serde(skip)]
+    pub entry_only: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Config errors take precedence over environment errors
+    pub fn exit_code(&self) -> u8 {
+        if self.problems.iter().any(|p| p.class == Class::Config) {
+            EXIT_CONFIG
+        } else if self.is_clean() {
+            0
+        } else {
+            EXIT_ENVIRONMENT
+        }
+    }
+
+    /// Whether `--warn-only` may start without the failed artifacts
+    pub fn can_start_degraded(&self) -> bool {
+        self.problems.iter().all(|p| p.skippable)
+    }
+
+    /// Whether loading `path` failed
+    pub fn failed(&self, path: &Path) -> bool {
+        self.problems.iter().any(|p| !p.entry_only && p.file.as_deref() == Some(path))
+    }
+
+    /// One line per problem, for the log
+    pub fn lines(&self) -> Vec<String> {
+        self.problems
+            .iter()
+            .map(|p| {
+                let mut at = p.file.as_ref().map(|f| f.display().to_string()).unwrap_or_default();
+                if let Some(location) = &p.location {
+                    at = if at.is_empty() { location.clone() } else { format!("{} ({})", at, location) };
+                }
+                let class = match p.class {
+                    Class::Config => "config",
+                    Class::Environment => "environment",
+                };
+                format!("[{}] {}: {}: {} -- {}", class, p.artifact, at, p.problem, p.suggestion)
+            })
+            .collect()
+    }
+
+    fn push(&mut self, problem: Problem) {
+        self.problems.push(problem);
+    }
+}
+
+/// Report for a config file that could not be read or parsed at all; an
+/// `API_CONFIG` file that does not exist is an environment problem
+pub fn unloadable(error: &config::ConfigError) -> Report {
+    let file = std::env::var("API_CONFIG").ok().map(PathBuf::from);
+    let missing = file.as_deref().is_some_and(|path| !Settings::config_file_exists(path));
+    let (problem, suggestion, class) = match missing {
+        true => (
+            "config file named by API_CONFIG does not exist".to_string(),
+            "Create the file or correct the API_CONFIG path",
+            Class::Environment,
+        ),
+        false => (error.to_string(), "Fix the config file syntax or the API_CONFIG path", Class::Config),
+    };
+    Report {
+        problems: vec![Problem {
+            artifact: "settings".to_string(),
+            file,
+            location: None,
+            problem,
+            suggestion: suggestion.to_string(),
+            class,
+            skippable: false,
+            entry_only: false,
+        }],
+    }
+}
+
+/// Check the settings and load every artifact they name
+pub fn check(settings: &Settings) -> Report {
+    let mut report = Report::default();
+
+    for problem in settings.problems() {
+        let (location, problem) = match problem.split_once(": ") {
+            Some((key, rest)) if !key.contains(' ') => (Some(key.to_string()), rest.to_string()),
+            _ => (None, problem),
+        };
+        report.push(Problem {
+            artifact: "settings".to_string(),
+            file: None,
+            location,
+            problem,
+            suggestion: "Correct the setting in the config file or environment".to_string(),
+            class: Class::Config,
+            skippable: false,
+            entry_only: false,
+        });
+    }
+    check_lists(&settings.lists, &mut report);
+    for (id, tenant) in &settings.tenants {
+        check_tenant_lists(id, tenant, &mut report);
+    }
+
+    let data_dir = &settings.data_dir;
+    if data_dir.exists() && !data_dir.is_dir() {
+        report.push(environment("data_dir", data_dir, "Not a directory", "Point data_dir at a directory", true));
+    }
+    if let Err(e) = domain::load_from_dir(data_dir) {
+        let file = data_dir.join(domain::PSL_FILE_NAME);
+        let suggestion = "Download a fresh copy from https://publicsuffix.org/list/public_suffix_list.dat";
+        report.push(from_io("public suffix list", &file, &e, suggestion, true));
+    }
+    if let Err(e) = brand_assets::load_from_dir(data_dir) {
+        let file = data_dir.join(brand_assets::ASSETS_FILE_NAME);
+        report.push(from_io("brand assets", &file, &e, "Fix the JSON at the reported position", true));
+    }
+    if let Err(e) = HoneytokenStore::load(data_dir, Arc::new(clock::SystemClock)) {
+        let file = data_dir.join(honeytoken::TOKENS_FILE_NAME);
+        let suggestion = "Fix the JSON at the reported position; the store is rewritten on every change";
+        report.push(from_io("honeytokens", &file, &e, suggestion, false));
+    }
+
+    if let Some(path) = &settings.signing_key_path {
+        if let Err(e) = Signer::load(path, settings.signing_key_id.clone()) {
+            report.push(from_io("signing key", path, &e, "Generate a key with `ryzen-scan keygen <key.pk8>`", true));
+        }
+    }
+    if let (Some(cert_file), Some(key_file)) = (&settings.tls_cert_file, &settings.tls_key_file) {
+        let suggestion = "Point tls_cert_file and tls_key_file at a matching PEM chain and key";
+        let certs = tls::load_certs(cert_file).map_err(|e| report.push(from_io("tls certificate", cert_file, &e, suggestion, false)));
+        let key = tls::load_key(key_file).map_err(|e| report.push(from_io("tls key", key_file, &e, suggestion, false)));
+        if let (Ok(certs), Ok(key), Some(_)) = (certs, key, tls::versions(&settings.tls_min_version)) {
+            if let Err(e) = tls::server_config(certs, key, key_file, &settings.tls_min_version) {
+                report.push(from_io("tls key", key_file, &e, suggestion, false));
+            }
+        }
+    }
+    if let Some(path) = &settings.url_blocklist_path {
+        if let Err(e) = blocklist::verify(path) {
+            report.push(from_io("url blocklist", path, &e, "Sort the file with `LC_ALL=C sort -u`", true));
+        }
+    }
+    if let Some(path) = &settings.api_keys_file {
+        if let Err(e) = auth::load_file(path) {
+            report.push(from_io("api keys", path, &e, "Make the file readable by the server user", false));
+        }
+    }
+    if let Some(path) = &settings.audit_log_path {
+        if let Err(e) = OpenOptions::new().create(true).append(true).open(path) {
+            report.push(from_io("audit log", path, &e, "Make the file writable by the server user", true));
+        }
+    }
+    if let Some(path) = settings.recorder_path.as_ref().filter(|_| settings.recorder_sample_rate > 0.0) {
+        if let Err(e) = OpenOptions::new().create(true).append(true).open(path) {
+            report.push(from_io("recorder", path, &e, "Make the file writable by the server user", true));
+        }
+    }
+    report
+}
+
+/// A tenant's list files: unreadable files and entries that can never match
+fn check_tenant_lists(id: &str, tenant: &TenantSettings, report: &mut Report) {
+    for name in tenant::LIST_NAMES {
+        let Some(path) = tenant.file_for(name) else { continue };
+        let artifact = format!("tenants.{}.{}_file", id, name);
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            // Created on the first edit through the admin API
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                report.push(from_io(&artifact, path, &e, "Make the file readable by the server user", true));
+                continue;
+            }
+        };
+        let entries = text.lines().enumerate().map(|(number, line)| (format!("line {}", number + 1), line.trim()));
+        for (location, problem) in entry_problems(name, entries.filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))) {
+            report.push(Problem {
+                artifact: artifact.clone(),
+                file: Some(path.to_path_buf()),
+                location: Some(location),
+                problem,
+                suggestion: suggestion_for(name).to_string(),
+                class: Class::Config,
+                skippable: true,
+                entry_only: true,
+            });
+        }
+    }
+}
+
+/// Host lists inline in the config file
+fn check_lists(lists: &DetectionLists, report: &mut Report) {
+    for (name, list) in [("allowlist", &lists.allowlist), ("blocklist", &lists.blocklist)] {
+        let entries = list.iter().enumerate().map(|(index, entry)| (format!("lists.{}[{}]", name, index), entry.as_str()));
+        for (location, problem) in entry_problems(name, entries) {
+            report.push(Problem {
+                artifact: "settings".to_string(),
+                file: None,
+                location: Some(location),
+                problem,
+                suggestion: suggestion_for(name).to_string(),
+                class: Class::Config,
+                skippable: true,
+                entry_only: true,
+            });
+        }
+    }
+}
+
+/// Entries of a host list that a host can never equal
+fn entry_problems<'a>(list: &str, entries: impl Iterator<Item = (String, &'a str)>) -> Vec<(String, String)> {
+    if !matches!(list, "allowlist" | "blocklist") {
+        return Vec::new();
+    }
+    entries
+        .filter_map(|(location, entry)| {
+            let problem = if entry.contains("://") {
+                "is a URL, not a host"
+            } else if entry.contains(|c: char| c.is_whitespace() || c == '/') {
+                "is not a bare host name"
+            } else {
+                return None;
+            };
+            Some((location, format!("{:?} {} and never matches", entry, problem)))
+        })
+        .collect()
+}
+
+fn suggestion_for(list: &str) -> &'static str {
+    match list {
+        "allowlist" | "blocklist" => "List bare host names, one per entry, such as `example.com`",
+        _ => "Correct the entry",
+    }
+}
+
+fn environment(artifact: &str, file: &Path, problem: &str, suggestion: &str, skippable: bool) -> Problem {
+    Problem {
+        artifact: artifact.to_string(),
+        file: Some(file.to_path_buf()),
+        location: None,
+        problem: problem.to_string(),
+        suggestion: suggestion.to_string(),
+        class: Class::Environment,
+        skippable,
+        entry_only: false,
+    }
+}
+
+/// Classify a load failure: bad contents are config errors, anything else
+/// (missing, unreadable, unwritable) is the environment's
+fn from_io(artifact: &str, file: &Path, error: &io::Error, suggestion: &str, skippable: bool) -> Problem {
+    match error.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+            // Loaders prefix their messages with the path, which has its own field
+            let message = error.to_string();
+            let message = message.strip_prefix(&format!("{}: ", file.display())).unwrap_or(&message).to_string();
+            let (problem, location) = match message.rsplit_once(" at line ") {
+                Some((problem, position)) => (problem.to_string(), Some(format!("line {}", position))),
+                // Line-oriented loaders lead with the line instead
+                None => match message.strip_prefix("line ").and_then(|rest| rest.split_once(' ')) {
+                    Some((number, problem)) if number.parse::<usize>().is_ok() => {
+                        (problem.to_string(), Some(format!("line {}", number)))
+                    }
+                    _ => (message, None),
+                },
+            };
+            Problem {
+                artifact: artifact.to_string(),
+                file: Some(file.to_path_buf()),
+                location,
+                problem,
+                suggestion: suggestion.to_string(),
+                class: Class::Config,
+                skippable,
+                entry_only: false,
+            }
+        }
+        io::ErrorKind::NotFound => environment(artifact, file, "File not found", "Create the file or unset the setting naming it", skippable),
+        io::ErrorKind::PermissionDenied => {
+            environment(artifact, file, "Permission denied", "Give the server user access to the file", skippable)
+        }
+        _ => environment(artifact, file, &error.to_string(), suggestion, skippable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("amd-security-preflight-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn malformed_data_file_is_a_config_error_with_its_position() {
+        let dir = data_dir("malformed");
+        fs::write(dir.join(brand_assets::ASSETS_FILE_NAME), "{\n  \"brands\": [\n    {\"name\": \"Acme\",\n").unwrap();
+        let report = check(&Settings { data_dir: dir.clone(), ..Settings::default() });
+        assert_eq!(report.problems.len(), 1);
+        let problem = &report.problems[0];
+        assert_eq!((problem.artifact.as_str(), problem.class), ("brand assets", Class::Config));
+        assert_eq!(problem.file.as_deref(), Some(dir.join(brand_assets::ASSETS_FILE_NAME).as_path()));
+        assert!(problem.location.as_deref().is_some_and(|l| l.starts_with("line 4")));
+        assert_eq!(problem.suggestion, "Fix the JSON at the reported position");
+        assert_eq!(report.exit_code(), EXIT_CONFIG);
+        assert!(report.can_start_degraded());
+    }
+
+    #[test]
+    fn missing_files_are_environment_errors_unless_a_config_error_is_also_reported() {
+        let dir = data_dir("missing");
+        let settings = Settings {
+            data_dir: dir.clone(),
+            signing_key_path: Some(dir.join("absent.pk8")),
+            ..Settings::default()
+        };
+        let report = check(&settings);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!((report.problems[0].problem.as_str(), report.problems[0].class), ("File not found", Class::Environment));
+        assert_eq!(report.exit_code(), EXIT_ENVIRONMENT);
+
+        let blocklist = dir.join("blocklist.txt");
+        fs::write(&blocklist, "b.example\na.example\n").unwrap();
+        let mut settings = Settings { url_blocklist_path: Some(blocklist), ..settings };
+        settings.lists.allowlist.push("https://example.org/".to_string());
+        let report = check(&settings);
+        assert_eq!(report.problems.len(), 3, "{:#?}", report);
+        assert_eq!(report.exit_code(), EXIT_CONFIG);
+        let lines = report.lines();
+        assert!(lines.iter().any(|l| l.starts_with("[environment] signing key: ")));
+        let unsorted = report.problems.iter().find(|p| p.artifact == "url blocklist").unwrap();
+        assert_eq!((unsorted.location.as_deref(), unsorted.class), (Some("line 2"), Class::Config));
+        assert!(unsorted.problem.starts_with("is out of order"));
+        assert!(lines.iter().any(|l| l.starts_with("[config] settings: lists.allowlist[0]: ")));
+    }
+
+    #[test]
+    fn honeytoken_store_problems_block_a_degraded_start() {
+        let dir = data_dir("honeytokens");
+        fs::write(dir.join(honeytoken::TOKENS_FILE_NAME), "not json").unwrap();
+        let report = check(&Settings { data_dir: dir, ..Settings::default() });
+        assert!(!report.problems.is_empty());
+        assert!(!report.can_start_degraded());
+    }
+}
@@ -0,0 +1,124 @@
+// rust/api/src/pressure.rs
+//! Memory pressure levels for the in-memory stores
+//!
+//! Every `memory_check_interval_secs` the bounded stores report an estimate
+//! of the bytes they hold, and the total is compared with the soft and hard
+//! watermarks. Crossing a watermark raises the level at once; the level
+//! only steps back down once the total is 10% below the watermark that
+//! raised it, so a total hovering at a watermark does not flap. What each
+//! level sheds is decided by the caller; this module only tracks the level
+//! and what was done about it.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Normal,
+    Soft,
+    Hard,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Level::Normal,
+            1 => Level::Soft,
+            _ => Level::Hard,
+        }
+    }
+}
+
+/// Latest evaluation, as shown in health and stats
+#[derive(Debug, Clone, Serialize)]
+pub struct PressureStatus {
+    pub level: Level,
+    pub estimated_bytes: usize,
+    /// Estimate per store
+    pub stores: BTreeMap<&'static str, usize>,
+    pub soft_limit_bytes: usize,
+    pub hard_limit_bytes: usize,
+    /// Measures in force at this level
+    pub actions: Vec<String>,
+    /// When the level last changed
+    pub since: DateTime<Utc>,
+}
+
+pub struct Watchdog {
+    soft: usize,
+    hard: usize,
+    level: AtomicU8,
+    status: RwLock<PressureStatus>,
+}
+
+impl Watchdog {
+    /// Watermarks in bytes; a soft watermark of 0 disables the watchdog
+    pub fn new(soft: usize, hard: usize, now: DateTime<Utc>) -> Self {
+        Self {
+            soft,
+            hard,
+            level: AtomicU8::new(Level::Normal as u8),
+            status: RwLock::new(PressureStatus {
+                level: Level::Normal,
+                estimated_bytes: 0,
+                stores: BTreeMap::new(),
+                soft_limit_bytes: soft,
+                hard_limit_bytes: hard,
+                actions: Vec::new(),
+                since: now,
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.soft > 0
+    }
+
+    /// Current level; cheap enough for every request
+    pub fn level(&self) -> Level {
+        Level::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn status(&self) -> PressureStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Level for the given store estimates, starting from the current one
+    pub fn evaluate(&self, stores: &BTreeMap<&'static str, usize>) -> Level {
+        let total: usize = stores.values().sum();
+        let current = self.level();
+        let raw = if total >= self.hard {
+            Level::Hard
+        } else if total >= self.soft {
+            Level::Soft
+        } else {
+            Level::Normal
+        };
+        if raw >= current {
+            return raw;
+        }
+        let above = |watermark: usize| total >= watermark - watermark / 10;
+        match current {
+            Level::Hard if above(self.hard) => Level::Hard,
+            Level::Hard | Level::Soft if above(self.soft) => Level::Soft,
+            _ => raw,
+        }
+    }
+
+    /// Record an evaluation and the measures applied for it
+    pub fn update(&self, level: Level, stores: BTreeMap<&'static str, usize>, actions: Vec<String>, now: DateTime<Utc>) {
+        let mut status = self.status.write().unwrap();
+        if status.level != level {
+            status.since = now;
+        }
+        status.level = level;
+        status.estimated_bytes = stores.values().sum();
+        status.stores = stores;
+        status.actions = actions;
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,106 @@
+// rust/api/src/qr.rs
+//! QR code extraction from uploaded images
+//!
+//! Images are checked for size, format and dimensions before any pixels are
+//! decoded, so a small file claiming enormous dimensions is rejected up
+//! front. Decoding itself is CPU-heavy and is run by the handler on the
+//! blocking pool under a timeout.
+
+use image::{ImageFormat, ImageReader};
+use serde::Serialize;
+use std::io::Cursor;
+
+/// Why an uploaded image was rejected
+#[derive(Debug)]
+pub enum QrError {
+    TooLarge { bytes: usize, max: usize },
+    UnsupportedFormat,
+    TooManyPixels { width: u32, height: u32, max: u64 },
+    Malformed(String),
+}
+
+impl QrError {
+    /// Stable identifier for the `code` field of error responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            QrError::TooLarge { .. } => "image_too_large",
+            QrError::UnsupportedFormat => "unsupported_format",
+            QrError::TooManyPixels { .. } => "image_dimensions_too_large",
+            QrError::Malformed(_) => "malformed_image",
+        }
+    }
+}
+
+impl std::fmt::Display for QrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QrError::TooLarge { bytes, max } => write!(f, "Image is {} bytes; the limit is {}", bytes, max),
+            QrError::UnsupportedFormat => write!(f, "Only PNG and JPEG images are accepted"),
+            QrError::TooManyPixels { width, height, max } => {
+                write!(f, "Image is {}x{} pixels; the limit is {} pixels", width, height, max)
+            }
+            QrError::Malformed(e) => write!(f, "Image could not be decoded: {}", e),
+        }
+    }
+}
+
+/// Basic facts about a decoded image
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Outcome of decoding one QR grid
+#[derive(Debug, Clone)]
+pub enum DecodedCode {
+    Payload(String),
+    /// A grid was located but its data could not be read
+    Unreadable(String),
+}
+
+/// Upload limits, taken from settings
+pub struct QrLimits {
+    pub max_bytes: usize,
+    pub max_pixels: u64,
+}
+
+/// Decode every QR code in a PNG or JPEG image
+pub fn decode(bytes: &[u8], limits: &QrLimits) -> Result<(ImageInfo, Vec<DecodedCode>), QrError> {
+    if bytes.len() > limits.max_bytes {
+        return Err(QrError::TooLarge { bytes: bytes.len(), max: limits.max_bytes });
+    }
+    let format = match image::guess_format(bytes) {
+        Ok(ImageFormat::Png) => ImageFormat::Png,
+        Ok(ImageFormat::Jpeg) => ImageFormat::Jpeg,
+        _ => return Err(QrError::UnsupportedFormat),
+    };
+
+    let reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let (width, height) = reader.into_dimensions().map_err(|e| QrError::Malformed(e.to_string()))?;
+    if width as u64 * height as u64 > limits.max_pixels {
+        return Err(QrError::TooManyPixels { width, height, max: limits.max_pixels });
+    }
+
+    let image = ImageReader::with_format(Cursor::new(bytes), format)
+        .decode()
+        .map_err(|e| QrError::Malformed(e.to_string()))?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let codes = prepared
+        .detect_grids()
+        .iter()
+        .map(|grid| match grid.decode() {
+            Ok((_, payload)) => DecodedCode::Payload(payload),
+            Err(e) => DecodedCode::Unreadable(e.to_string()),
+        })
+        .collect();
+
+    let info = ImageInfo {
+        format: if format == ImageFormat::Png { "png" } else { "jpeg" },
+        width,
+        height,
+    };
+    Ok((info, codes))
+}
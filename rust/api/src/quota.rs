@@ -0,0 +1,174 @@
+// rust/api/src/quota.rs
+//! Daily detection quotas
+//!
+//! Detections are counted per client key (the API key presented, or the
+//! client address without one) over a day that starts at
+//! `quota_reset_hour_utc`. A key may run up to the `daily_quota` of the
+//! tenant whose `key_ids` hold the API key, or else the global one;
+//! `X-Tenant-Id` plays no part, since any client can send it. Once a
+//! request would take a key past its quota, the request is rejected with
+//! 429 until the next boundary. A batch is charged one detection per item and
+//! is rejected whole if they do not all fit.
+//!
+//! Detections are charged before they run, so concurrent requests cannot
+//! overshoot the quota between them, and refunded if the request then
+//! fails. Counts are held in memory and start again from zero on restart.
+//! At most `quota_clients` keys are counted in a day. A key counted is kept
+//! until the boundary, so a stream of new clients cannot push one out and
+//! hand it a fresh quota; once the limit is reached, keys not yet counted
+//! are refused until the next boundary.
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::clock::SharedClock;
+
+/// Why a charge was refused
+#[derive(Debug, Clone, Copy)]
+pub enum Refusal {
+    /// The key's detections would go past its limit
+    Exhausted(Usage),
+    /// The key is not counted yet and no more keys can be until `resets_at`
+    Full { resets_at: DateTime<Utc> },
+}
+
+impl Refusal {
+    pub fn resets_at(&self) -> DateTime<Utc> {
+        match self {
+            Refusal::Exhausted(usage) => usage.resets_at,
+            Refusal::Full { resets_at } => *resets_at,
+        }
+    }
+}
+
+/// A key's standing after a charge
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub limit: u64,
+    pub used: u64,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl Usage {
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+}
+
+struct Period {
+    start: DateTime<Utc>,
+    used: HashMap<String, u64>,
+}
+
+pub struct QuotaTracker {
+    reset_hour: u32,
+    capacity: usize,
+    period: Mutex<Period>,
+    clock: SharedClock,
+}
+
+impl QuotaTracker {
+    pub fn new(reset_hour: u32, capacity: usize, clock: SharedClock) -> Self {
+        let start = period_start(clock.utc(), reset_hour);
+        let capacity = capacity.max(1);
+        Self { reset_hour, capacity, period: Mutex::new(Period { start, used: HashMap::new() }), clock }
+    }
+
+    /// Count `n` detections against `key`, or leave its usage unchanged
+    /// if they would take it past `limit` or it cannot be counted
+    pub fn charge(&self, key: &str, limit: u64, n: u64) -> Result<Usage, Refusal> {
+        let mut period = self.current();
+        let resets_at = period.start + Duration::days(1);
+        if !period.used.contains_key(key) && period.used.len() >= self.capacity {
+            return Err(Refusal::Full { resets_at });
+        }
+        let used = period.used.entry(key.to_string()).or_insert(0);
+        if *used + n > limit {
+            return Err(Refusal::Exhausted(Usage { limit, used: *used, resets_at }));
+        }
+        *used += n;
+        Ok(Usage { limit, used: *used, resets_at })
+    }
+
+    /// Give back detections charged for a request that did not complete
+    pub fn refund(&self, key: &str, n: u64) {
+        let mut period = self.current();
+        if let Some(used) = period.used.get_mut(key) {
+            *used = used.saturating_sub(n);
+        }
+    }
+
+    /// The period now, after starting a new one if the boundary has passed
+    fn current(&self) -> std::sync::MutexGuard<'_, Period> {
+        let mut period = self.period.lock().unwrap();
+        let start = period_start(self.clock.utc(), self.reset_hour);
+        if start != period.start {
+            period.start = start;
+            period.used.clear();
+        }
+        period
+    }
+
+    /// Rough bytes held, for the memory watchdog
+    pub fn estimated_bytes(&self) -> usize {
+        let period = self.period.lock().unwrap();
+        period.used.keys().map(|key| key.len() + std::mem::size_of::<(String, u64)>()).sum()
+    }
+}
+
+/// Latest boundary at `reset_hour` UTC not after `now`
+fn period_start(now: DateTime<Utc>, reset_hour: u32) -> DateTime<Utc> {
+    let offset = Duration::hours(reset_hour as i64);
+    let start = (now - offset).duration_trunc(Duration::days(1)).unwrap_or(now - offset);
+    start + offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use std::sync::Arc;
+
+    #[test]
+    fn count_starts_again_at_the_reset_hour() {
+        let clock = Arc::new(ManualClock::new());
+        let quota = QuotaTracker::new(6, 10, clock.clone());
+        let usage = quota.charge("client", 2, 2).unwrap();
+        assert_eq!(usage.remaining(), 0);
+        let Err(Refusal::Exhausted(rejected)) = quota.charge("client", 2, 1) else { panic!("quota not exhausted") };
+        assert_eq!(rejected.resets_at, usage.resets_at);
+        assert_eq!(rejected.resets_at.format("%H:%M:%S").to_string(), "06:00:00");
+
+        let until_reset = (usage.resets_at - clock.utc()).to_std().unwrap();
+        clock.advance(until_reset - std::time::Duration::from_secs(1));
+        assert!(quota.charge("client", 2, 1).is_err());
+        clock.advance(std::time::Duration::from_secs(1));
+        let usage = quota.charge("client", 2, 1).unwrap();
+        assert_eq!(usage.used, 1);
+        assert_eq!(usage.resets_at, rejected.resets_at + Duration::days(1));
+    }
+
+    #[test]
+    fn refund_returns_charged_detections() {
+        let quota = QuotaTracker::new(0, 10, Arc::new(ManualClock::new()));
+        quota.charge("client", 3, 3).unwrap();
+        quota.refund("client", 2);
+        assert_eq!(quota.charge("client", 3, 2).unwrap().remaining(), 0);
+    }
+
+    #[test]
+    fn counted_keys_are_kept_and_new_ones_refused_when_full() {
+        let clock = Arc::new(ManualClock::new());
+        let quota = QuotaTracker::new(0, 2, clock.clone());
+        quota.charge("a", 2, 2).unwrap();
+        quota.charge("b", 2, 1).unwrap();
+        let Err(Refusal::Full { resets_at }) = quota.charge("c", 2, 1) else { panic!("new key counted past capacity") };
+        // Refused keys take nothing from counted ones
+        assert!(matches!(quota.charge("a", 2, 1), Err(Refusal::Exhausted(_))));
+        assert_eq!(quota.charge("b", 2, 1).unwrap().remaining(), 0);
+
+        clock.advance((resets_at - clock.utc()).to_std().unwrap());
+        quota.charge("c", 2, 1).unwrap();
+    }
+}
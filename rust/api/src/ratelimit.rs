@@ -0,0 +1,202 @@
+// rust/api/src/ratelimit.rs
+//! Per-client rate limiting
+//!
+//! Each client key (the API key presented, or else the peer address, which
+//! only a trusted proxy's forwarding header can stand in for) has a token
+//! bucket holding up to `rate_limit_burst` detections, refilled at
+//! `rate_limit_per_sec`. `throttle` wraps every `/api/detect` route and
+//! takes one token for each request before the body is read. A request
+//! holding several items, a batch or a QR image with several URLs, takes
+//! one more per item past the first once they are known, so batching does
+//! not get around the limit; one whose items do not all fit is given its
+//! first token back. A request the bucket cannot cover is
+//! rejected with 429 and a `Retry-After` of the seconds until it could be.
+//!
+//! A charge larger than the burst can never be covered, so it is let
+//! through when the bucket is full and leaves it in debt, which the client
+//! pays off before its next request.
+//!
+//! At most `rate_limit_clients` buckets are kept. A client new past that
+//! takes the place of the fullest of the `EVICTION_SAMPLE` buckets used
+//! least recently, since forgetting a bucket costs its client only the
+//! tokens it is missing. New clients are always let in, so peers keeping
+//! their own buckets drained cannot lock others out, and the buckets they
+//! drained are the last to be forgotten.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::AppState;
+
+/// Least recently used buckets looked at for the fullest to forget
+const EVICTION_SAMPLE: usize = 32;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    buckets: Mutex<LruCache<String, Bucket>>,
+    clock: SharedClock,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: f64, burst: u32, capacity: usize, clock: SharedClock) -> Self {
+        Self {
+            per_sec,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN))),
+            clock,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.per_sec > 0.0
+    }
+
+    /// Take `n` tokens from `key`'s bucket, or return how long until it
+    /// could cover them, leaving it unchanged
+    pub fn charge(&self, key: &str, n: u32) -> Result<(), Duration> {
+        if !self.enabled() || n == 0 {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() == buckets.cap().get() && !buckets.contains(key) {
+            let fullest = buckets
+                .iter()
+                .rev()
+                .take(EVICTION_SAMPLE)
+                .max_by(|(_, a), (_, b)| self.level(a, now).total_cmp(&self.level(b, now)))
+                .map(|(key, _)| key.clone());
+            if let Some(fullest) = fullest {
+                buckets.pop(&fullest);
+            }
+        }
+        let bucket = buckets.get_or_insert_mut(key.to_string(), || Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = self.level(bucket, now);
+        bucket.updated = now;
+        let needed = (n as f64).min(self.burst);
+        if bucket.tokens < needed {
+            return Err(Duration::from_secs_f64((needed - bucket.tokens) / self.per_sec));
+        }
+        bucket.tokens -= n as f64;
+        Ok(())
+    }
+
+    /// Tokens `bucket` holds at `now`
+    fn level(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_sec).min(self.burst)
+    }
+
+    /// Give back `n` tokens taken for a request that was then turned away
+    pub fn refund(&self, key: &str, n: u32) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(key) {
+            bucket.tokens = (bucket.tokens + n as f64).min(self.burst);
+        }
+    }
+
+    /// Rough bytes held, for the memory watchdog
+    pub fn estimated_bytes(&self) -> usize {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.iter().map(|(key, _)| key.len() + std::mem::size_of::<(String, Bucket)>()).sum()
+    }
+}
+
+/// Middleware charging each request one detection to its client's bucket,
+/// answering 429 when the bucket is empty
+pub async fn throttle(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let rejection = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.rate_limiter.charge(&state.caller_key(req.request()), 1).err());
+    match rejection {
+        Some(wait) => Ok(req.into_response(rate_limited(wait)).map_into_right_body()),
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+/// 429 for a client over its rate, to retry after `wait`
+pub fn rate_limited(wait: Duration) -> HttpResponse {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    HttpResponse::TooManyRequests()
+        .insert_header((actix_web::http::header::RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": "Rate limit exceeded",
+            "retry_after_secs": retry_after,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn bucket_refills_at_the_configured_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::new(2.0, 3, 10, clock.clone());
+        limiter.charge("client", 3).unwrap();
+        assert_eq!(limiter.charge("client", 1).unwrap_err(), Duration::from_millis(500));
+        // Other clients have buckets of their own
+        limiter.charge("other", 1).unwrap();
+
+        clock.advance(Duration::from_millis(500));
+        limiter.charge("client", 1).unwrap();
+        clock.advance(Duration::from_secs(60));
+        limiter.charge("client", 3).unwrap();
+        assert!(limiter.charge("client", 1).is_err());
+    }
+
+    #[test]
+    fn charge_over_the_burst_waits_for_a_full_bucket_and_leaves_debt() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::new(1.0, 4, 10, clock.clone());
+        limiter.charge("client", 1).unwrap();
+        assert_eq!(limiter.charge("client", 10).unwrap_err(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        limiter.charge("client", 10).unwrap();
+        // Six items over the burst are paid off before the next request
+        assert_eq!(limiter.charge("client", 1).unwrap_err(), Duration::from_secs(7));
+    }
+
+    #[test]
+    fn new_clients_past_capacity_replace_the_fullest_bucket() {
+        let limiter = RateLimiter::new(1.0, 4, 3, Arc::new(ManualClock::new()));
+        limiter.charge("drained", 4).unwrap();
+        limiter.charge("spent", 3).unwrap();
+        limiter.charge("rested", 1).unwrap();
+        limiter.charge("new", 1).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 3);
+        assert!(!buckets.contains("rested"));
+        drop(buckets);
+        // The drained bucket, though used least recently, is kept
+        assert!(limiter.charge("drained", 1).is_err());
+    }
+
+    #[test]
+    fn drained_clients_cannot_lock_out_new_ones() {
+        let limiter = RateLimiter::new(1.0, 1, 4, Arc::new(ManualClock::new()));
+        for key in ["a", "b", "c", "d"] {
+            limiter.charge(key, 1).unwrap();
+        }
+        for key in ["e", "f", "g"] {
+            limiter.charge(key, 1).unwrap();
+        }
+    }
+}
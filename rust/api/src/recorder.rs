@@ -0,0 +1,253 @@
+// rust/api/src/recorder.rs
+//! Sampled recording of detection requests and verdicts for replay
+//!
+//! When `recorder_path` is set, a `recorder_sample_rate` share of served
+//! detections is appended to that file as JSON lines, one request and its
+//! response per line. The `request` of each line is a valid `/api/detect`
+//! body, so a recording can be replayed as a regression corpus.
+//!
+//! `recorder_redaction` keeps content out of the file: `hash` replaces the
+//! content, context and each verdict reason with their SHA-256 digests,
+//! `omit` drops them. Reasons are covered because they quote matched
+//! strings. Either mode also drops the explain trace, whose excerpts quote
+//! the content, and the redirect chain of an enriched URL.
+//!
+//! Lines are written by a writer thread through a queue of `QUEUE_LINES`,
+//! so a slow disk does not hold up detections; a sample that finds the
+//! queue full is dropped. Recording stops once the file reaches
+//! `recorder_max_bytes`. On shutdown the writer is given up to
+//! `FLUSH_TIMEOUT` to empty the queue.
+
+use log::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::SharedClock;
+use crate::config::Settings;
+use crate::{ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Lines waiting for the writer before samples are dropped
+const QUEUE_LINES: usize = 1024;
+
+/// Longest wait for queued lines to be written on shutdown
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What recorded lines keep of the content and context
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// Recorded as received
+    #[default]
+    None,
+    /// Replaced by `sha256:<hex digest>`
+    Hash,
+    /// Left out
+    Omit,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RecorderStats {
+    pub recorded: u64,
+    /// Sampled but not written because the file is full or the writer is
+    /// behind
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    recorded: AtomicU64,
+    dropped: AtomicU64,
+}
+
+enum Message {
+    Line(String),
+    /// Answered once every line queued before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+/// The writer thread's end: the file and the lines for it
+struct Output {
+    file: File,
+    /// Bytes in the file, including what was there on startup
+    written: u64,
+    max_bytes: u64,
+    counters: Arc<Counters>,
+}
+
+impl Output {
+    /// Write queued lines until the recorder is dropped
+    fn drain(mut self, lines: Receiver<Message>) {
+        for message in lines {
+            match message {
+                Message::Line(line) => self.write(&line),
+                Message::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.max_bytes > 0 && self.written + line.len() as u64 > self.max_bytes {
+            if self.counters.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("Recorder file reached its {} byte limit; recording stopped", self.max_bytes);
+            }
+            return;
+        }
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => {
+                self.written += line.len() as u64;
+                self.counters.recorded.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => warn!("Failed to write recorded detection: {}", e),
+        }
+    }
+}
+
+pub struct Recorder {
+    /// Unset when recording is disabled
+    queue: Option<SyncSender<Message>>,
+    sample_rate: f32,
+    redaction: Redaction,
+    rng: SystemRandom,
+    clock: SharedClock,
+    counters: Arc<Counters>,
+}
+
+impl Recorder {
+    /// Open the recording file for appending and start its writer thread
+    pub fn open(settings: &Settings, clock: SharedClock) -> std::io::Result<Self> {
+        let counters = Arc::new(Counters::default());
+        let queue = match &settings.recorder_path {
+            Some(path) if settings.recorder_sample_rate > 0.0 => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let written = file.metadata()?.len();
+                let output = Output { file, written, max_bytes: settings.recorder_max_bytes, counters: counters.clone() };
+                let (queue, lines) = mpsc::sync_channel(QUEUE_LINES);
+                std::thread::Builder::new().name("recorder".to_string()).spawn(move || output.drain(lines))?;
+                Some(queue)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            queue,
+            sample_rate: settings.recorder_sample_rate,
+            redaction: settings.recorder_redaction,
+            rng: SystemRandom::new(),
+            clock,
+            counters,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Record a served detection if it falls in the sample
+    pub fn record(&self, route: &str, req: &ThreatDetectionRequest, response: &ThreatDetectionResponse) {
+        let Some(queue) = &self.queue else { return };
+        if !self.sampled() {
+            return;
+        }
+        let mut line = json!({
+            "recorded_at": self.clock.utc().to_rfc3339(),
+            "route": route,
+            "request": self.request(req),
+            "content_bytes": req.content.len(),
+            "response": response,
+        });
+        if let Some(response) = line["response"].as_object_mut() {
+            self.redact(response);
+        }
+        let mut line = line.to_string();
+        line.push('\n');
+
+        match queue.try_send(Message::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("Recorder writer has stopped; detection not recorded"),
+        }
+    }
+
+    /// Wait for queued lines to reach the file, up to `FLUSH_TIMEOUT`
+    pub fn flush(&self) {
+        let Some(queue) = &self.queue else { return };
+        let (done, written) = mpsc::channel();
+        if queue.send(Message::Flush(done)).is_err() || written.recv_timeout(FLUSH_TIMEOUT).is_err() {
+            warn!("Recorded detections may not all have been written before shutdown");
+        }
+    }
+
+    pub fn stats(&self) -> RecorderStats {
+        RecorderStats {
+            recorded: self.counters.recorded.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Remove or hash what a recorded response quotes of the content
+    fn redact(&self, response: &mut serde_json::Map<String, Value>) {
+        if self.redaction == Redaction::None {
+            return;
+        }
+        response.remove("trace");
+        response.remove("redirect_chain");
+        match self.redaction {
+            Redaction::Hash => {
+                if let Some(Value::Array(reasons)) = response.get_mut("reasons") {
+                    for reason in reasons {
+                        if let Value::String(text) = reason {
+                            *reason = json!(digest(text));
+                        }
+                    }
+                }
+            }
+            Redaction::Omit => {
+                response.remove("reasons");
+            }
+            Redaction::None => {}
+        }
+    }
+
+    /// The request as an `/api/detect` body, redacted
+    fn request(&self, req: &ThreatDetectionRequest) -> Value {
+        let mut body = json!({ "threat_type": req.threat_type, "explain": req.explain });
+        match self.redaction {
+            Redaction::None => {
+                body["content"] = json!(req.content);
+                body["context"] = json!(req.context);
+            }
+            Redaction::Hash => {
+                body["content"] = json!(digest(&req.content));
+                body["context"] = json!(req.context.as_deref().map(digest));
+            }
+            Redaction::Omit => {}
+        }
+        body
+    }
+
+    fn sampled(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        let mut bytes = [0u8; 4];
+        if self.rng.fill(&mut bytes).is_err() {
+            return false;
+        }
+        (u32::from_le_bytes(bytes) as f64 / u32::MAX as f64) < self.sample_rate as f64
+    }
+}
+
+fn digest(text: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(text.as_bytes()))
+}
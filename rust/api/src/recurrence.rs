@@ -0,0 +1,225 @@
+// rust/api/src/recurrence.rs
+//! Escalation of indicators that keep producing weak signals
+//!
+//! One sub-threshold verdict is noise; the same registrable domain behind
+//! dozens of them within an hour is a campaign. Freshly computed verdicts
+//! that are not threats but score at least `recurrence_min_confidence` are
+//! counted against their indicator: the registrable domain or IP of a URL,
+//! or the content fingerprint otherwise. Once an indicator collects more than
+//! `recurrence_threshold` distinct sightings within `recurrence_window_secs`
+//! it is escalated for `recurrence_escalation_secs`: the `recurrence` stage
+//! adds its weight to later verdicts for it, and with `recurrence_auto_block`
+//! set those verdicts are threats outright.
+
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::config::Settings;
+use crate::{domain, fingerprints};
+
+/// What a sighting is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum Indicator {
+    Domain(String),
+    Ip(String),
+    Hash(String),
+}
+
+impl Indicator {
+    /// Indicator of a request's content; `host` is set for URLs
+    pub fn of(content: &str, host: Option<&str>) -> Self {
+        match host {
+            Some(host) => match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                Ok(ip) => Indicator::Ip(ip.to_string()),
+                Err(_) => Indicator::Domain(domain::registrable_domain(host).unwrap_or_else(|| host.to_lowercase())),
+            },
+            None => Indicator::Hash(fingerprints::fingerprint(content)),
+        }
+    }
+}
+
+/// An indicator whose sightings crossed the threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Escalation {
+    #[serde(flatten)]
+    pub indicator: Indicator,
+    /// Sightings in the window when it escalated
+    pub sightings: usize,
+    pub window_secs: u64,
+    pub blocked: bool,
+    pub escalated_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Escalation {
+    pub fn reason(&self) -> String {
+        format!("Recurring suspicious indicator: {} sightings in {}", self.sightings, human_duration(self.window_secs))
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 && s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+pub struct RecurrenceTracker {
+    /// Recent sightings per indicator: when, and a hash of the content
+    sightings: Mutex<LruCache<Indicator, VecDeque<(Instant, String)>>>,
+    escalated: Mutex<HashMap<Indicator, Escalation>>,
+    clock: SharedClock,
+}
+
+impl RecurrenceTracker {
+    pub fn new(capacity: NonZeroUsize, clock: SharedClock) -> Self {
+        Self { sightings: Mutex::new(LruCache::new(capacity)), escalated: Mutex::default(), clock }
+    }
+
+    /// Count a sub-threshold sighting; returns the escalation it triggers, if any
+    pub fn observe(&self, indicator: Indicator, content_hash: String, settings: &Settings) -> Option<Escalation> {
+        if settings.recurrence_threshold == 0 || self.escalation(&indicator).is_some() {
+            return None;
+        }
+        let now = self.clock.now();
+        let window = Duration::from_secs(settings.recurrence_window_secs);
+        let distinct = {
+            let mut sightings = self.sightings.lock().unwrap();
+            let recent = sightings.get_or_insert_mut(indicator.clone(), VecDeque::new);
+            while recent.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+                recent.pop_front();
+            }
+            if !recent.iter().any(|(_, hash)| *hash == content_hash) {
+                recent.push_back((now, content_hash));
+            }
+            recent.len()
+        };
+        if distinct <= settings.recurrence_threshold {
+            return None;
+        }
+
+        self.sightings.lock().unwrap().pop(&indicator);
+        let escalated_at = self.clock.utc();
+        let escalation = Escalation {
+            indicator: indicator.clone(),
+            sightings: distinct,
+            window_secs: settings.recurrence_window_secs,
+            blocked: settings.recurrence_auto_block,
+            escalated_at,
+            expires_at: escalated_at + chrono::Duration::seconds(settings.recurrence_escalation_secs as i64),
+        };
+        self.escalated.lock().unwrap().insert(indicator, escalation.clone());
+        Some(escalation)
+    }
+
+    /// Take an escalation made elsewhere, unless a later one of the same
+    /// indicator is held; whether it was taken
+    pub fn adopt(&self, escalation: Escalation) -> bool {
+        let mut escalated = self.escalated.lock().unwrap();
+        if escalated.get(&escalation.indicator).is_some_and(|held| held.escalated_at > escalation.escalated_at) {
+            return false;
+        }
+        self.sightings.lock().unwrap().pop(&escalation.indicator);
+        escalated.insert(escalation.indicator.clone(), escalation);
+        true
+    }
+
+    /// Rough bytes held by the sighting windows
+    pub fn estimated_bytes(&self) -> usize {
+        let sightings = self.sightings.lock().unwrap();
+        sightings
+            .iter()
+            .map(|(indicator, recent)| {
+                let value = match indicator {
+                    Indicator::Domain(v) | Indicator::Ip(v) | Indicator::Hash(v) => v.len(),
+                };
+                std::mem::size_of::<Indicator>()
+                    + value
+                    + recent.iter().map(|(_, hash)| std::mem::size_of::<(Instant, String)>() + hash.len()).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Current escalation of an indicator
+    pub fn escalation(&self, indicator: &Indicator) -> Option<Escalation> {
+        let escalated = self.escalated.lock().unwrap();
+        escalated.get(indicator).filter(|e| e.expires_at > self.clock.utc()).cloned()
+    }
+
+    /// Whether any indicator is escalated, expired or not
+    pub fn any(&self) -> bool {
+        !self.escalated.lock().unwrap().is_empty()
+    }
+
+    /// Drop expired escalations, returning their indicators
+    pub fn prune(&self) -> Vec<Indicator> {
+        let now = self.clock.utc();
+        let mut escalated = self.escalated.lock().unwrap();
+        let expired: Vec<Indicator> = escalated.iter().filter(|(_, e)| e.expires_at <= now).map(|(i, _)| i.clone()).collect();
+        for indicator in &expired {
+            escalated.remove(indicator);
+        }
+        expired
+    }
+
+    /// Unexpired escalations, newest first
+    pub fn escalated(&self) -> Vec<Escalation> {
+        let now = self.clock.utc();
+        let escalated = self.escalated.lock().unwrap();
+        let mut list: Vec<Escalation> = escalated.values().filter(|e| e.expires_at > now).cloned().collect();
+        list.sort_by_key(|e| std::cmp::Reverse(e.escalated_at));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::sync::Arc;
+
+    fn tracker() -> RecurrenceTracker {
+        RecurrenceTracker::new(NonZeroUsize::new(100).unwrap(), Arc::new(SystemClock))
+    }
+
+    fn domain() -> Indicator {
+        Indicator::of("http://a.example.tk/x", Some("a.example.tk"))
+    }
+
+    #[test]
+    fn off_by_default() {
+        let tracker = tracker();
+        let settings = Settings::default();
+        for i in 0..100 {
+            assert!(tracker.observe(domain(), format!("h{}", i), &settings).is_none());
+        }
+        assert!(tracker.escalation(&domain()).is_none());
+    }
+
+    #[test]
+    fn distinct_sightings_past_the_threshold_escalate() {
+        let tracker = tracker();
+        let settings = Settings { recurrence_threshold: 3, ..Settings::default() };
+        for i in 0..3 {
+            assert!(tracker.observe(domain(), format!("h{}", i), &settings).is_none());
+        }
+        // The same content again is not another sighting
+        assert!(tracker.observe(domain(), "h0".to_string(), &settings).is_none());
+
+        let escalation = tracker.observe(domain(), "h3".to_string(), &settings).unwrap();
+        assert_eq!(escalation.sightings, 4);
+        assert!(!escalation.blocked);
+        assert_eq!(tracker.escalation(&domain()).unwrap().indicator, domain());
+        // Subdomains of the same registrable domain share the escalation
+        assert!(tracker.escalation(&Indicator::of("", Some("b.example.tk"))).is_some());
+    }
+}
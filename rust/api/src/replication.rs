@@ -0,0 +1,484 @@
+// rust/api/src/replication.rs
+//! Warm-standby replication between a primary and a standby instance
+//!
+//! With `replication_role = "primary"` an instance connects to
+//! `replication_peer` and streams its state changes: cache entries stored
+//! and evicted, sessions updated, and indicators escalated. With
+//! `replication_role = "standby"` an instance listens on
+//! `replication_listen` and applies them to its own stores, so that when it
+//! is promoted through `POST /api/admin/replication/promote` it serves with
+//! the primary's warm cache, sessions and escalations instead of empty ones.
+//!
+//! The stream is a sequence of frames, each a 4-byte big-endian length and
+//! a JSON body. A connection opens with a challenge in which each side
+//! proves it holds the shared `replication_token` without sending it:
+//! - the primary sends a `Hello` with `PROTOCOL_VERSION` and a fresh nonce
+//! - the standby answers with its own nonce and an HMAC of both nonces
+//!   under the token
+//! - the primary checks it and answers with a `Proof`, the same HMAC with
+//!   the roles swapped
+//!
+//! Either side closes the connection on a wrong version or proof, so state
+//! only goes to, and is only taken from, a peer holding the token. Every
+//! later frame carries an HMAC tag under a key derived from the token and
+//! both nonces, over the frame's position and body, so frames cannot be
+//! injected, reordered or replayed. The link is not encrypted: cache
+//! entries are readable on the wire, so keep it on a private network.
+//!
+//! The primary then sends a snapshot of its cache, sessions and escalations, followed
+//! by changes as they happen and a heartbeat every `HEARTBEAT` when there
+//! are none. Frames are numbered, and a standby counts any gap it sees.
+//!
+//! Changes wait in a queue of `replication_queue_capacity` while the
+//! standby is unreachable. When the queue overflows, the primary
+//! reconnects and starts again from a fresh snapshot.
+//!
+//! Conflicts are last-writer-wins: a later frame for a cache key replaces
+//! the entry, and the later of two states of a session, or of two
+//! escalations of an indicator, is kept.
+//! Every frame carries the primary's rules generation, which the standby
+//! adopts when it is ahead of its own. Entries computed under an earlier
+//! generation or another ruleset version could never be looked up, so they
+//! are dropped and counted as stale.
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::clock::SharedClock;
+use crate::recurrence::{Escalation, Indicator};
+use crate::session::SessionState;
+use crate::ThreatDetectionResponse;
+
+/// Bumped on any incompatible change to `Hello`, `Frame` or `Message`
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Interval between frames when there are no changes
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Silence after which a standby drops the connection
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest frame either side accepts
+const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    Standby,
+}
+
+/// Bytes of each side's challenge nonce
+const NONCE_BYTES: usize = 32;
+
+/// First frame each side sends on a connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Hex challenge for the other side to answer
+    pub nonce: String,
+    /// The standby's answer to the primary's nonce, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+}
+
+/// The primary's answer to the standby's nonce
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Proof {
+    pub proof: String,
+}
+
+/// An authenticated connection: the key tagging its frames, and how many
+/// have been tagged
+pub struct Session {
+    key: hmac::Key,
+    frames: u64,
+}
+
+impl Session {
+    fn tag(&self, body: &[u8]) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.key);
+        context.update(&self.frames.to_be_bytes());
+        context.update(body);
+        context.sign()
+    }
+}
+
+/// A cache entry as replicated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub response: ThreatDetectionResponse,
+    /// Request threat type, which sets the entry's TTL; primaries that
+    /// predate it send none, and the global TTL applies
+    #[serde(default)]
+    pub request_type: String,
+    pub ruleset_version: String,
+    pub rules_generation: u64,
+    /// Recurrence indicator of the request, so an escalation evicts the
+    /// entry on the standby too
+    #[serde(default)]
+    pub indicator: Option<Indicator>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    CachePut(Box<CacheEntry>),
+    CacheEvict { key: String },
+    SessionUpdate(Box<SessionState>),
+    Escalated(Box<Escalation>),
+    Heartbeat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame {
+    pub seq: u64,
+    pub sent_at: DateTime<Utc>,
+    /// The primary's rules generation when the frame was sent
+    pub rules_generation: u64,
+    pub message: Message,
+}
+
+/// Replication state as shown by `/api/health`
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    pub role: Role,
+    /// A primary is streaming to, or a standby receiving from, its peer
+    pub connected: bool,
+    /// Last frame sent (primary) or applied (standby)
+    pub seq: u64,
+    /// Time since the last applied frame was sent; includes clock skew
+    /// between the two hosts, and is `null` before the first frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_frame_at: Option<DateTime<Utc>>,
+    /// Changes lost to a full queue, each forcing a fresh snapshot
+    pub dropped: u64,
+    /// Frames missing between ones received
+    pub gaps: u64,
+    /// Cache entries not applied for predating the standby's rules
+    pub stale: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub promoted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct Received {
+    seq: u64,
+    sent_at: Option<DateTime<Utc>>,
+    gaps: u64,
+    stale: u64,
+}
+
+#[derive(Debug)]
+pub enum PromoteError {
+    NotStandby,
+}
+
+pub struct Replicator {
+    role: RwLock<Option<Role>>,
+    token: String,
+    queue_capacity: usize,
+    outbound: Mutex<Option<mpsc::Sender<Message>>>,
+    connected: AtomicBool,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    /// Set when a change was dropped, so the next snapshot covers it
+    resync: AtomicBool,
+    received: Mutex<Received>,
+    promoted_at: Mutex<Option<DateTime<Utc>>>,
+    clock: SharedClock,
+}
+
+impl Replicator {
+    /// Replication in `role`, or none; `token` authenticates the pair
+    pub fn new(role: Option<Role>, token: String, queue_capacity: usize, clock: SharedClock) -> Self {
+        Self {
+            role: RwLock::new(role),
+            token,
+            queue_capacity: queue_capacity.max(1),
+            outbound: Mutex::new(None),
+            connected: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            resync: AtomicBool::new(false),
+            received: Mutex::default(),
+            promoted_at: Mutex::new(None),
+            clock,
+        }
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        *self.role.read().unwrap()
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.role() == Some(Role::Standby)
+    }
+
+    /// Queue for changes a primary streams; taken by the streaming task
+    pub fn outbound(&self) -> mpsc::Receiver<Message> {
+        let (tx, rx) = mpsc::channel(self.queue_capacity);
+        *self.outbound.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Whether this instance is a primary with a queue for changes
+    pub fn publishing(&self) -> bool {
+        self.outbound.lock().unwrap().is_some()
+    }
+
+    /// Queue a change for the standby; `message` is only built on a
+    /// primary that is streaming
+    pub fn publish(&self, message: impl FnOnce() -> Message) {
+        let outbound = self.outbound.lock().unwrap();
+        let Some(tx) = outbound.as_ref() else { return };
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(message()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.resync.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Stream to a connected standby until the connection fails or the
+    /// queue overflows. Changes queued before the snapshot are discarded,
+    /// as the snapshot covers them.
+    pub async fn stream(
+        &self,
+        mut stream: TcpStream,
+        rx: &mut mpsc::Receiver<Message>,
+        snapshot: impl FnOnce() -> Vec<Message>,
+        rules_generation: impl Fn() -> u64,
+    ) -> io::Result<()> {
+        let mut session = self.connect(&mut stream).await?;
+        while rx.try_recv().is_ok() {}
+        self.resync.store(false, Ordering::Relaxed);
+        let snapshot = snapshot();
+        info!("Replicating to standby: snapshot of {} changes", snapshot.len());
+        self.connected.store(true, Ordering::Relaxed);
+        let result = async {
+            for message in snapshot {
+                self.send(&mut stream, &mut session, message, rules_generation()).await?;
+            }
+            let mut heartbeat = tokio::time::interval(HEARTBEAT);
+            loop {
+                if self.resync.load(Ordering::Relaxed) {
+                    return Err(io::Error::other("change queue overflowed; resending snapshot"));
+                }
+                let message = tokio::select! {
+                    message = rx.recv() => match message {
+                        Some(message) => message,
+                        None => return Ok(()),
+                    },
+                    _ = heartbeat.tick() => Message::Heartbeat,
+                };
+                self.send(&mut stream, &mut session, message, rules_generation()).await?;
+            }
+        }
+        .await;
+        self.connected.store(false, Ordering::Relaxed);
+        result
+    }
+
+    async fn send(&self, stream: &mut TcpStream, session: &mut Session, message: Message, rules_generation: u64) -> io::Result<()> {
+        let frame = Frame {
+            seq: self.sent.fetch_add(1, Ordering::Relaxed) + 1,
+            sent_at: self.clock.utc(),
+            rules_generation,
+            message,
+        };
+        let body = serde_json::to_vec(&frame)?;
+        let tag = session.tag(&body);
+        session.frames += 1;
+        write_frame(stream, &body).await?;
+        stream.write_all(tag.as_ref()).await
+    }
+
+    /// The primary's side of the challenge
+    async fn connect(&self, stream: &mut TcpStream) -> io::Result<Session> {
+        let nonce = new_nonce()?;
+        write_json(stream, &Hello { version: PROTOCOL_VERSION, nonce: nonce.clone(), proof: None }).await?;
+        let hello: Hello = read_json(stream).await?;
+        check_version(&hello)?;
+        let proof = hello.proof.as_deref().unwrap_or_default();
+        self.verify_proof(Role::Standby, &nonce, &hello.nonce, proof)?;
+        let proof = self.proof(Role::Primary, &hello.nonce, &nonce);
+        write_json(stream, &Proof { proof: hex::encode(proof.as_ref()) }).await?;
+        Ok(self.session(&nonce, &hello.nonce))
+    }
+
+    /// Answer a connecting primary's challenge and check its answer to ours
+    pub async fn accept(&self, stream: &mut TcpStream) -> io::Result<Session> {
+        let hello: Hello = read_json(stream).await?;
+        check_version(&hello)?;
+        let nonce = new_nonce()?;
+        let proof = self.proof(Role::Standby, &hello.nonce, &nonce);
+        let answer = Hello { version: PROTOCOL_VERSION, nonce: nonce.clone(), proof: Some(hex::encode(proof.as_ref())) };
+        write_json(stream, &answer).await?;
+        let Proof { proof } = read_json(stream).await?;
+        self.verify_proof(Role::Primary, &nonce, &hello.nonce, &proof)?;
+        self.connected.store(true, Ordering::Relaxed);
+        Ok(self.session(&hello.nonce, &nonce))
+    }
+
+    /// HMAC by the side in `role` of the other side's `challenge`, bound to
+    /// its own nonce so it cannot be replayed on another connection
+    fn proof(&self, role: Role, challenge: &str, own: &str) -> hmac::Tag {
+        hmac::sign(&self.token_key(), &proof_message(role, challenge, own))
+    }
+
+    fn verify_proof(&self, role: Role, challenge: &str, own: &str, proof: &str) -> io::Result<()> {
+        let proof = hex::decode(proof).unwrap_or_default();
+        hmac::verify(&self.token_key(), &proof_message(role, challenge, own), &proof)
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "wrong replication token"))
+    }
+
+    fn token_key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, self.token.as_bytes())
+    }
+
+    /// Frame key for a connection, from the primary's then the standby's nonce
+    fn session(&self, primary_nonce: &str, standby_nonce: &str) -> Session {
+        let derived = hmac::sign(&self.token_key(), format!("session|{}|{}", primary_nonce, standby_nonce).as_bytes());
+        Session { key: hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref()), frames: 0 }
+    }
+
+    /// Next frame from the primary, or `None` once this instance is no
+    /// longer a standby
+    pub async fn receive(&self, stream: &mut TcpStream, session: &mut Session) -> io::Result<Option<Frame>> {
+        let read = async {
+            let body = read_frame(stream).await?;
+            let mut tag = [0u8; 32];
+            stream.read_exact(&mut tag).await?;
+            if session.tag(&body).as_ref() != tag.as_slice() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "frame failed authentication"));
+            }
+            session.frames += 1;
+            Ok::<_, io::Error>(serde_json::from_slice::<Frame>(&body)?)
+        };
+        let frame = match tokio::time::timeout(READ_TIMEOUT, read).await {
+            Ok(frame) => frame?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no frame from primary")),
+        };
+        if !self.is_standby() {
+            return Ok(None);
+        }
+        let mut received = self.received.lock().unwrap();
+        if received.seq > 0 && frame.seq > received.seq + 1 {
+            warn!("Replication gap: frames {} to {} missing", received.seq + 1, frame.seq - 1);
+            received.gaps += frame.seq - received.seq - 1;
+        }
+        received.seq = frame.seq;
+        received.sent_at = Some(frame.sent_at);
+        Ok(Some(frame))
+    }
+
+    pub fn disconnected(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Count a cache entry the standby could not use
+    pub fn stale(&self) {
+        self.received.lock().unwrap().stale += 1;
+    }
+
+    /// Stop applying the primary's stream and take over as primary
+    pub fn promote(&self) -> Result<DateTime<Utc>, PromoteError> {
+        let mut role = self.role.write().unwrap();
+        if *role != Some(Role::Standby) {
+            return Err(PromoteError::NotStandby);
+        }
+        *role = Some(Role::Primary);
+        self.connected.store(false, Ordering::Relaxed);
+        let now = self.clock.utc();
+        *self.promoted_at.lock().unwrap() = Some(now);
+        Ok(now)
+    }
+
+    pub fn status(&self) -> Option<ReplicationStatus> {
+        let role = self.role()?;
+        let received = self.received.lock().unwrap();
+        let (seq, lag_ms) = match role {
+            Role::Standby => {
+                let lag = received.sent_at.map(|at| (self.clock.utc() - at).num_milliseconds().max(0) as u64);
+                (received.seq, lag)
+            }
+            Role::Primary => (self.sent.load(Ordering::Relaxed), None),
+        };
+        Some(ReplicationStatus {
+            role,
+            connected: self.connected.load(Ordering::Relaxed),
+            seq,
+            lag_ms,
+            last_frame_at: received.sent_at,
+            dropped: self.dropped.load(Ordering::Relaxed),
+            gaps: received.gaps,
+            stale: received.stale,
+            promoted_at: *self.promoted_at.lock().unwrap(),
+        })
+    }
+}
+
+/// What the side in `role` signs: the role keeps a proof from being
+/// reflected back to the side that made it
+fn proof_message(role: Role, challenge: &str, own: &str) -> Vec<u8> {
+    let label = match role {
+        Role::Primary => "primary",
+        Role::Standby => "standby",
+    };
+    format!("{}|{}|{}", label, challenge, own).into_bytes()
+}
+
+fn check_version(hello: &Hello) -> io::Result<()> {
+    if hello.version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("protocol version {} (expected {})", hello.version, PROTOCOL_VERSION),
+        ));
+    }
+    Ok(())
+}
+
+fn new_nonce() -> io::Result<String> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| io::Error::other("no randomness for a replication nonce"))?;
+    Ok(hex::encode(nonce))
+}
+
+async fn write_json<T: Serialize>(stream: &mut TcpStream, value: &T) -> io::Result<()> {
+    write_frame(stream, &serde_json::to_vec(value)?).await
+}
+
+async fn write_frame(stream: &mut TcpStream, body: &[u8]) -> io::Result<()> {
+    if body.len() > MAX_FRAME_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", body.len())));
+    }
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(body).await
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(stream: &mut TcpStream) -> io::Result<T> {
+    Ok(serde_json::from_slice(&read_frame(stream).await?)?)
+}
+
+async fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
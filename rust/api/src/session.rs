@@ -0,0 +1,250 @@
+// rust/api/src/session.rs
+//! Risk accumulated across the requests of a session
+//!
+//! One medium-risk action is routine; several in the same session add up
+//! to a high-risk one. A request joins a session by naming it in its
+//! context, as `session_id=<id>` or `"session_id": "<id>"`. Every verdict
+//! in the session scoring at least `session_min_confidence` is a signal,
+//! counted once per distinct content, and the session's risk is the chance
+//! that at least one of them is real: 1 − ∏(1 − confidence). Each detection
+//! in a session reports the risk after its own signal as `session_risk`.
+//!
+//! Sessions are scoped to the tenant, idle ones start over after
+//! `session_idle_secs`, and at most `session_capacity` are tracked, the
+//! least recently used dropped first. A session keeps its latest
+//! `session_max_signals` signals.
+//!
+//! A primary replicates every session it changes to its standby as a
+//! `SessionState`; the standby keeps whichever state of a session was seen
+//! last.
+
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+
+/// Longest session id read from a context
+pub const MAX_ID_LEN: usize = 128;
+
+/// A session's standing after a detection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRisk {
+    pub session_id: String,
+    /// Combined risk of the session's signals, 0 to 1
+    pub risk: f32,
+    /// Severity of `risk` under the detection's threat type thresholds
+    pub severity: String,
+    /// Distinct signals the risk is built from
+    pub signals: usize,
+    pub started_at: DateTime<Utc>,
+}
+
+struct Session {
+    started_at: DateTime<Utc>,
+    last_seen: Instant,
+    /// `last_seen` as wall-clock time, which replication can carry
+    last_seen_at: DateTime<Utc>,
+    /// Content hash and confidence of each signal, oldest first
+    signals: VecDeque<(String, f32)>,
+}
+
+impl Session {
+    fn risk(&self) -> f32 {
+        1.0 - self.signals.iter().map(|(_, confidence)| 1.0 - confidence.clamp(0.0, 1.0)).product::<f32>()
+    }
+}
+
+/// A session as replicated to a standby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    pub tenant: String,
+    pub session_id: String,
+    pub started_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// Content hash and confidence of each signal, oldest first
+    pub signals: Vec<(String, f32)>,
+}
+
+/// Limits a session store runs under
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+    pub idle: Duration,
+    pub min_confidence: f32,
+    pub max_signals: usize,
+}
+
+pub struct SessionStore {
+    sessions: Mutex<LruCache<(String, String), Session>>,
+    limits: SessionLimits,
+    clock: SharedClock,
+}
+
+impl SessionStore {
+    pub fn new(capacity: NonZeroUsize, limits: SessionLimits, clock: SharedClock) -> Self {
+        Self { sessions: Mutex::new(LruCache::new(capacity)), limits, clock }
+    }
+
+    /// Add a verdict to a tenant's session; the session's risk after it,
+    /// without a severity
+    pub fn observe(&self, tenant: &str, session_id: &str, content_hash: String, confidence: f32) -> SessionRisk {
+        let now = self.clock.now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = (tenant.to_string(), session_id.to_string());
+        if sessions.peek(&key).is_some_and(|s| now.duration_since(s.last_seen) > self.limits.idle) {
+            sessions.pop(&key);
+        }
+        let session = sessions.get_or_insert_mut(key, || Session {
+            started_at: self.clock.utc(),
+            last_seen: now,
+            last_seen_at: self.clock.utc(),
+            signals: VecDeque::new(),
+        });
+        session.last_seen = now;
+        session.last_seen_at = self.clock.utc();
+        if confidence >= self.limits.min_confidence && !session.signals.iter().any(|(hash, _)| *hash == content_hash) {
+            session.signals.push_back((content_hash, confidence));
+            while session.signals.len() > self.limits.max_signals {
+                session.signals.pop_front();
+            }
+        }
+        SessionRisk {
+            session_id: session_id.to_string(),
+            risk: session.risk(),
+            severity: String::new(),
+            signals: session.signals.len(),
+            started_at: session.started_at,
+        }
+    }
+
+    /// A tenant's session as it is now, for replication
+    pub fn state(&self, tenant: &str, session_id: &str) -> Option<SessionState> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.peek(&(tenant.to_string(), session_id.to_string()))?;
+        Some(export(tenant, session_id, session))
+    }
+
+    /// Every session, least recently used first, for a standby's snapshot
+    pub fn states(&self) -> Vec<SessionState> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter().rev().map(|((tenant, id), session)| export(tenant, id, session)).collect()
+    }
+
+    /// Take a session replicated from the primary, unless a later state of
+    /// it is held; whether it was taken
+    pub fn adopt(&self, state: SessionState) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = (state.tenant, state.session_id);
+        if sessions.peek(&key).is_some_and(|held| held.last_seen_at > state.last_seen_at) {
+            return false;
+        }
+        // Idle time carries over, as far as the two clocks agree
+        let idle = (self.clock.utc() - state.last_seen_at).to_std().unwrap_or_default();
+        let now = self.clock.now();
+        let mut signals: VecDeque<_> = state.signals.into();
+        while signals.len() > self.limits.max_signals {
+            signals.pop_front();
+        }
+        sessions.put(key, Session {
+            started_at: state.started_at,
+            last_seen: now.checked_sub(idle).unwrap_or(now),
+            last_seen_at: state.last_seen_at,
+            signals,
+        });
+        true
+    }
+
+    /// Rough bytes held, for the memory watchdog
+    pub fn estimated_bytes(&self) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|((tenant, id), session)| {
+                std::mem::size_of::<((String, String), Session)>()
+                    + tenant.len()
+                    + id.len()
+                    + session.signals.iter().map(|(hash, _)| std::mem::size_of::<(String, f32)>() + hash.len()).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+fn export(tenant: &str, session_id: &str, session: &Session) -> SessionState {
+    SessionState {
+        tenant: tenant.to_string(),
+        session_id: session_id.to_string(),
+        started_at: session.started_at,
+        last_seen_at: session.last_seen_at,
+        signals: session.signals.iter().cloned().collect(),
+    }
+}
+
+/// Session a request's free-form context names, e.g. `session_id=abc123`
+/// or `{"session_id": "abc123"}`: letters, digits, `-`, `_` and `.`
+pub fn session_id(context: &str) -> Option<&str> {
+    // ASCII lowercasing keeps byte offsets
+    let at = context.to_ascii_lowercase().find("session_id")? + "session_id".len();
+    let rest = context[at..].trim_start_matches(['"', '\'']).trim_start();
+    let rest = rest.strip_prefix(['=', ':'])?.trim_start().trim_start_matches(['"', '\'']);
+    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))).unwrap_or(rest.len());
+    let id = &rest[..end];
+    (!id.is_empty() && id.len() <= MAX_ID_LEN).then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    fn store(clock: &Arc<ManualClock>) -> SessionStore {
+        let limits = SessionLimits { idle: Duration::from_secs(60), min_confidence: 0.3, max_signals: 3 };
+        SessionStore::new(NonZeroUsize::new(2).unwrap(), limits, clock.clone())
+    }
+
+    #[test]
+    fn medium_signals_add_up_and_repeats_or_weak_ones_do_not() {
+        let clock = Arc::new(ManualClock::new());
+        let sessions = store(&clock);
+        let risks: Vec<f32> = ["a", "b", "c"].iter().map(|hash| sessions.observe("", "s", hash.to_string(), 0.5).risk).collect();
+        assert_eq!(risks, [0.5, 0.75, 0.875]);
+
+        assert_eq!(sessions.observe("", "s", "b".to_string(), 0.5).risk, 0.875);
+        let weak = sessions.observe("", "s", "d".to_string(), 0.2);
+        assert_eq!((weak.risk, weak.signals), (0.875, 3));
+        // Only the latest signals are kept
+        let strong = sessions.observe("", "s", "e".to_string(), 0.9);
+        assert_eq!(strong.signals, 3);
+        assert!((strong.risk - (1.0 - 0.5 * 0.5 * 0.1)).abs() < 1e-6, "{}", strong.risk);
+    }
+
+    #[test]
+    fn sessions_are_per_tenant_start_over_when_idle_and_are_bounded() {
+        let clock = Arc::new(ManualClock::new());
+        let sessions = store(&clock);
+        sessions.observe("acme", "s", "a".to_string(), 0.5);
+        assert_eq!(sessions.observe("other", "s", "b".to_string(), 0.5).signals, 1);
+        assert_eq!(sessions.observe("acme", "s", "b".to_string(), 0.5).signals, 2);
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(sessions.observe("acme", "s", "c".to_string(), 0.5).signals, 1);
+
+        // Capacity 2: the least recently used session goes
+        sessions.observe("acme", "t", "a".to_string(), 0.5);
+        assert!(sessions.state("other", "s").is_none());
+        assert!(sessions.state("acme", "s").is_some());
+    }
+
+    #[test]
+    fn session_ids_are_read_from_either_context_form() {
+        assert_eq!(session_id("user=alice session_id=abc-123.x other=1"), Some("abc-123.x"));
+        assert_eq!(session_id(r#"{"Session_Id": "s_42", "page": "/"}"#), Some("s_42"));
+        assert_eq!(session_id("session_id="), None);
+        assert_eq!(session_id("no session here"), None);
+        assert_eq!(session_id(&format!("session_id={}", "x".repeat(MAX_ID_LEN + 1))), None);
+    }
+}
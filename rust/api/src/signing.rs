@@ -0,0 +1,172 @@
+// rust/api/src/signing.rs
+//! Ed25519 signatures over detection verdicts
+//!
+//! The signed payload is the canonical JSON of `SignedFields` (see
+//! `canonical`), so a verifier rebuilds it byte for byte from a stored
+//! response, however that response was reserialized, and checks it against
+//! the public key named by `key_id`. Flags that describe how a verdict was
+//! served (`cached`, `latency_ms`, …) are not signed.
+//!
+//! `signing_payload = "fields"` signs the earlier payload instead: compact
+//! JSON in the struct's field order, for verifiers not yet reading the
+//! canonical form. Verification accepts either. This module is shared with
+//! the `ryzen-scan` binary.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::canonical;
+
+pub const ALGORITHM: &str = "ed25519";
+
+/// Serialization of the signed payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    /// Canonical JSON: sorted keys, one spelling per number
+    #[default]
+    Canonical,
+    /// Compact JSON in `SignedFields` order, as signed before canonical JSON
+    Fields,
+}
+
+/// Signature attached to a response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerdictSignature {
+    pub key_id: String,
+    pub algorithm: String,
+    pub detection_id: String,
+    pub signed_at: String,
+    /// Base64 signature over the canonical payload
+    pub value: String,
+}
+
+/// Verdict fields covered by the signature
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    detection_id: &'a str,
+    signed_at: &'a str,
+    key_id: &'a str,
+    is_threat: bool,
+    verdict: &'a Value,
+    threat_type: &'a str,
+    confidence: f32,
+    severity: &'a str,
+    reasons: &'a [String],
+}
+
+impl SignedFields<'_> {
+    fn to_bytes(&self, payload: Payload) -> Vec<u8> {
+        match payload {
+            Payload::Canonical => canonical::to_vec(self),
+            Payload::Fields => serde_json::to_vec(self),
+        }
+        .expect("signed fields serialize")
+    }
+}
+
+/// Verdict fields as read from a serialized response
+#[derive(Deserialize)]
+pub struct Verdict {
+    pub is_threat: bool,
+    pub verdict: Value,
+    pub threat_type: String,
+    pub confidence: f32,
+    pub severity: String,
+    pub reasons: Vec<String>,
+}
+
+pub struct Signer {
+    key_id: String,
+    key_pair: Ed25519KeyPair,
+    payload: Payload,
+    rng: SystemRandom,
+}
+
+impl Signer {
+    /// Load a PKCS#8 Ed25519 key, as written by `ryzen-scan keygen`
+    pub fn load(path: &Path, key_id: String) -> io::Result<Self> {
+        let der = fs::read(path)?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(&der)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        Ok(Self { key_id, key_pair, payload: Payload::default(), rng: SystemRandom::new() })
+    }
+
+    /// Sign `payload` serializations instead of canonical JSON
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Base64 raw public key
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Sign a verdict under a fresh detection id, stamped `signed_at`
+    pub fn sign(&self, verdict: &Verdict, signed_at: chrono::DateTime<chrono::Utc>) -> VerdictSignature {
+        let mut id = [0u8; 16];
+        // A failing system RNG leaves the id zeroed; the signature stays valid
+        let _ = self.rng.fill(&mut id);
+        let detection_id = hex::encode(id);
+        let signed_at = signed_at.to_rfc3339();
+
+        let payload = signed_fields(verdict, &detection_id, &signed_at, &self.key_id).to_bytes(self.payload);
+        VerdictSignature {
+            key_id: self.key_id.clone(),
+            algorithm: ALGORITHM.to_string(),
+            detection_id,
+            signed_at,
+            value: BASE64.encode(self.key_pair.sign(&payload).as_ref()),
+        }
+    }
+}
+
+fn signed_fields<'a>(verdict: &'a Verdict, detection_id: &'a str, signed_at: &'a str, key_id: &'a str) -> SignedFields<'a> {
+    SignedFields {
+        detection_id,
+        signed_at,
+        key_id,
+        is_threat: verdict.is_threat,
+        verdict: &verdict.verdict,
+        threat_type: &verdict.threat_type,
+        confidence: verdict.confidence,
+        severity: &verdict.severity,
+        reasons: &verdict.reasons,
+    }
+}
+
+/// Check a signature against a base64 raw Ed25519 public key
+pub fn verify(verdict: &Verdict, signature: &VerdictSignature, public_key: &str) -> Result<(), String> {
+    if signature.algorithm != ALGORITHM {
+        return Err(format!("unsupported algorithm {:?}", signature.algorithm));
+    }
+    let key = BASE64.decode(public_key.trim()).map_err(|e| format!("invalid public key: {}", e))?;
+    let value = BASE64.decode(&signature.value).map_err(|e| format!("invalid signature encoding: {}", e))?;
+    let fields = signed_fields(verdict, &signature.detection_id, &signature.signed_at, &signature.key_id);
+    let key = UnparsedPublicKey::new(&ED25519, key);
+
+    [Payload::Canonical, Payload::Fields]
+        .into_iter()
+        .find(|payload| key.verify(&fields.to_bytes(*payload), &value).is_ok())
+        .map(|_| ())
+        .ok_or_else(|| "signature does not match".to_string())
+}
+
+/// New PKCS#8 Ed25519 private key
+pub fn generate_pkcs8() -> io::Result<Vec<u8>> {
+    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map(|doc| doc.as_ref().to_vec())
+        .map_err(|_| io::Error::other("system random source unavailable"))
+}
@@ -0,0 +1,164 @@
+// rust/api/src/snapshot.rs
+//! Deterministic hash of the engine state verdicts are computed under
+//!
+//! Every loaded artifact (settings, thresholds, pipelines, lists, rules,
+//! data files) is reduced to normalized bytes and hashed on its own; the
+//! engine state hash covers the artifact names and hashes, sorted by name.
+//! Normalization makes the hash independent of anything that cannot change
+//! a verdict: JSON artifacts are taken as canonical JSON, list entries are
+//! sorted, and files are taken in name order however the directory lists
+//! them.
+//!
+//! Manifests of recent states are kept so an auditor holding a hash from a
+//! response can see which artifacts it covered and compare them with an
+//! archived configuration bundle.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::canonical;
+use crate::lists::DetectionLists;
+
+/// Distinct engine states whose manifests are kept
+const HISTORY: usize = 32;
+
+/// One artifact of the engine state
+#[derive(Debug, Clone, Serialize)]
+pub struct Artifact {
+    /// `settings`, `lists/global`, `data/public_suffix_list.dat`, ...
+    pub name: String,
+    /// Size of the normalized contents
+    pub bytes: usize,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub engine_state_hash: String,
+    pub engine_version: String,
+    /// When this state was first computed
+    pub computed_at: DateTime<Utc>,
+    /// Sorted by name
+    pub artifacts: Vec<Artifact>,
+}
+
+/// Collects artifacts for one manifest
+#[derive(Default)]
+pub struct Builder {
+    artifacts: Vec<Artifact>,
+}
+
+impl Builder {
+    /// Add an artifact from its normalized bytes
+    pub fn bytes(&mut self, name: impl Into<String>, contents: &[u8]) {
+        self.digest(name, contents.len(), format!("{:x}", Sha256::digest(contents)));
+    }
+
+    /// Add an artifact whose digest was computed when it was loaded
+    pub fn digest(&mut self, name: impl Into<String>, bytes: usize, sha256: String) {
+        self.artifacts.push(Artifact { name: name.into(), bytes, sha256 });
+    }
+
+    /// Add an artifact from its canonical JSON form
+    pub fn json(&mut self, name: impl Into<String>, value: &impl Serialize) {
+        let canonical = canonical::to_vec(value).unwrap_or_else(|_| b"null".to_vec());
+        self.bytes(name, &canonical);
+    }
+
+    pub fn finish(mut self, engine_version: &str, computed_at: DateTime<Utc>) -> Manifest {
+        self.artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut hasher = Sha256::new();
+        hasher.update(engine_version.as_bytes());
+        hasher.update(b"\n");
+        for artifact in &self.artifacts {
+            hasher.update(format!("{} {} {}\n", artifact.name, artifact.bytes, artifact.sha256).as_bytes());
+        }
+        Manifest {
+            engine_state_hash: format!("{:x}", hasher.finalize()),
+            engine_version: engine_version.to_string(),
+            computed_at,
+            artifacts: self.artifacts,
+        }
+    }
+}
+
+/// Lists with their entries sorted; matching does not depend on order
+pub fn sorted_lists(lists: &DetectionLists) -> DetectionLists {
+    let mut lists = lists.clone();
+    for list in [&mut lists.brands, &mut lists.allowlist, &mut lists.blocklist, &mut lists.context_keywords] {
+        list.sort();
+    }
+    lists
+}
+
+/// Manifests of the most recent distinct engine states
+#[derive(Default)]
+pub struct History {
+    manifests: Mutex<VecDeque<Arc<Manifest>>>,
+}
+
+impl History {
+    /// Keep a newly computed manifest, returning the stored one when the
+    /// state was seen before so its first computation time is kept
+    pub fn insert(&self, manifest: Manifest) -> Arc<Manifest> {
+        let mut manifests = self.manifests.lock().unwrap();
+        if let Some(position) = manifests.iter().position(|m| m.engine_state_hash == manifest.engine_state_hash) {
+            let seen = manifests.remove(position).unwrap();
+            manifests.push_back(seen.clone());
+            return seen;
+        }
+        let manifest = Arc::new(manifest);
+        manifests.push_back(manifest.clone());
+        if manifests.len() > HISTORY {
+            manifests.pop_front();
+        }
+        manifest
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Arc<Manifest>> {
+        self.manifests.lock().unwrap().iter().find(|m| m.engine_state_hash == hash).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(artifacts: &[(&str, &[u8])]) -> Manifest {
+        let mut builder = Builder::default();
+        for (name, contents) in artifacts {
+            builder.bytes(*name, contents);
+        }
+        builder.finish("1.0.0", Utc::now())
+    }
+
+    #[test]
+    fn hash_does_not_depend_on_the_order_artifacts_are_added() {
+        let files: [(&str, &[u8]); 3] = [("data/a.dat", b"alpha"), ("data/b.dat", b"beta"), ("settings", b"{}")];
+        let forward = manifest(&files);
+        let mut reversed = files;
+        reversed.reverse();
+        assert_eq!(forward.engine_state_hash, manifest(&reversed).engine_state_hash);
+        assert_eq!(forward.artifacts.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(), ["data/a.dat", "data/b.dat", "settings"]);
+
+        let edited = manifest(&[("data/a.dat", b"alpha"), ("data/b.dat", b"beta!"), ("settings", b"{}")]);
+        assert_ne!(forward.engine_state_hash, edited.engine_state_hash);
+        let renamed = manifest(&[("data/a.dat", b"alpha"), ("data/c.dat", b"beta"), ("settings", b"{}")]);
+        assert_ne!(forward.engine_state_hash, renamed.engine_state_hash);
+    }
+
+    #[test]
+    fn history_keeps_the_first_computation_of_a_state() {
+        let history = History::default();
+        let first = history.insert(manifest(&[("settings", b"{}")]));
+        let again = history.insert(manifest(&[("settings", b"{}")]));
+        assert!(Arc::ptr_eq(&first, &again));
+        for n in 0..HISTORY {
+            history.insert(manifest(&[("settings", n.to_string().as_bytes())]));
+        }
+        assert!(history.get(&first.engine_state_hash).is_none());
+    }
+}
@@ -0,0 +1,304 @@
+// rust/api/src/spans.rs
+//! Where in the content a finding matched
+//!
+//! Stages report the byte range of each match alongside their reason. A
+//! range is always widened to whole characters, so it never splits a code
+//! point. Matches inside the request context or inside a decoded layer
+//! (a base64 blob) name that layer, and their offset is within it.
+//!
+//! Excerpts are rendered confusable-safe: printable ASCII is shown as is,
+//! and every other character is escaped (`\u{430}`, `\n`), so a Cyrillic
+//! `а` cannot pass for a Latin `a` and bidi controls cannot reorder the
+//! line. A marker line of carets sits under the match. Stages only record
+//! ranges; the excerpt is rendered, from the characters around the match
+//! alone, when the span goes into an explain trace.
+
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Characters of surrounding text shown on each side of a match
+const EXCERPT_CONTEXT_CHARS: usize = 16;
+/// Longest match shown in full; longer ones are cut in the middle
+const EXCERPT_MAX_MATCH_CHARS: usize = 48;
+
+/// Layer of the request a span points into, when not the content itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Layer {
+    /// The request's `context` field
+    Context,
+    /// Bytes decoded from the base64 run at this range of the content
+    Base64 { offset: usize, length: usize },
+}
+
+/// One matched region
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// Byte offset of the match, within `layer` when set
+    pub offset: usize,
+    /// Length of the match in bytes
+    pub length: usize,
+    /// The match and the text around it, with non-ASCII characters escaped;
+    /// empty until rendered
+    pub excerpt: String,
+    /// Carets under the match in `excerpt`
+    pub marker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<Layer>,
+}
+
+impl Span {
+    /// Span of a range of the content
+    pub fn in_text(text: &str, range: Range<usize>) -> Self {
+        Self::text_span(text, range, None)
+    }
+
+    /// Span of a range of the request context
+    pub fn in_context(context: &str, range: Range<usize>) -> Self {
+        Self::text_span(context, range, Some(Layer::Context))
+    }
+
+    /// Span of a range of bytes decoded from a layer of the content; the
+    /// decoded bytes are not kept, so this one is rendered straight away
+    pub fn in_decoded(bytes: &[u8], range: Range<usize>, layer: Layer) -> Self {
+        let start = range.start.min(bytes.len());
+        let end = range.end.clamp(start, bytes.len());
+        let from = start.saturating_sub(EXCERPT_CONTEXT_CHARS);
+        let to = (end + EXCERPT_CONTEXT_CHARS).min(bytes.len());
+        let (excerpt, marker) = render(
+            bytes[from..start].iter().map(|b| escape_byte(*b)).collect(),
+            elided(bytes[start..end].iter(), end - start, |b| escape_byte(*b)),
+            bytes[end..to].iter().map(|b| escape_byte(*b)).collect(),
+            from > 0,
+            to < bytes.len(),
+        );
+        Self { offset: start, length: end - start, excerpt, marker, layer: Some(layer) }
+    }
+
+    fn text_span(text: &str, range: Range<usize>, layer: Option<Layer>) -> Self {
+        let start = floor_char_boundary(text, range.start);
+        let end = ceil_char_boundary(text, range.end.max(start));
+        Self { offset: start, length: end - start, excerpt: String::new(), marker: String::new(), layer }
+    }
+
+    /// Fill in the excerpt from the text the span points into
+    pub fn render(&mut self, content: &str, context: Option<&str>) {
+        let text = match self.layer {
+            None => content,
+            Some(Layer::Context) => context.unwrap_or_default(),
+            // Rendered when made
+            Some(Layer::Base64 { .. }) => return,
+        };
+        let start = floor_char_boundary(text, self.offset);
+        let end = ceil_char_boundary(text, (self.offset + self.length).max(start));
+        let mut before: Vec<char> = text[..start].chars().rev().take(EXCERPT_CONTEXT_CHARS).collect();
+        before.reverse();
+        let from = start - before.iter().map(|c| c.len_utf8()).sum::<usize>();
+        let after: Vec<char> = text[end..].chars().take(EXCERPT_CONTEXT_CHARS).collect();
+        let to = end + after.iter().map(|c| c.len_utf8()).sum::<usize>();
+        let matched = &text[start..end];
+        (self.excerpt, self.marker) = render(
+            before.into_iter().map(escape_char).collect(),
+            elided(matched.chars(), matched.chars().count(), escape_char),
+            after.into_iter().map(escape_char).collect(),
+            from > 0,
+            to < text.len(),
+        );
+    }
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+fn escape_char(c: char) -> String {
+    match c {
+        ' '..='~' => c.to_string(),
+        '\n' => "\\n".to_string(),
+        '\r' => "\\r".to_string(),
+        '\t' => "\\t".to_string(),
+        c => format!("\\u{{{:x}}}", c as u32),
+    }
+}
+
+fn escape_byte(b: u8) -> String {
+    match b {
+        b' '..=b'~' => (b as char).to_string(),
+        b => format!("\\x{:02x}", b),
+    }
+}
+
+/// Rendered units of a match of `count` units, cut in the middle when long
+fn elided<I: DoubleEndedIterator + Clone>(units: I, count: usize, escape: impl Fn(I::Item) -> String) -> Vec<String> {
+    if count <= EXCERPT_MAX_MATCH_CHARS {
+        return units.map(escape).collect();
+    }
+    let half = EXCERPT_MAX_MATCH_CHARS / 2;
+    let mut shown: Vec<String> = units.clone().take(half).map(&escape).collect();
+    shown.push("...".to_string());
+    let mut tail: Vec<String> = units.rev().take(half).map(&escape).collect();
+    tail.reverse();
+    shown.extend(tail);
+    shown
+}
+
+/// Excerpt of rendered units, with the matched ones marked, and its caret line
+fn render(before: Vec<String>, matched: Vec<String>, after: Vec<String>, more_before: bool, more_after: bool) -> (String, String) {
+    let mut excerpt = String::new();
+    let mut marker = String::new();
+    let mut push = |text: &str, caret: bool| {
+        excerpt.push_str(text);
+        let fill = if caret { '^' } else { ' ' };
+        marker.extend(std::iter::repeat_n(fill, text.len()));
+    };
+
+    if more_before {
+        push("...", false);
+    }
+    for unit in &before {
+        push(unit, false);
+    }
+    for unit in &matched {
+        push(unit, true);
+    }
+    for unit in &after {
+        push(unit, false);
+    }
+    if more_after {
+        push("...", false);
+    }
+    (excerpt, marker.trim_end().to_string())
+}
+
+/// First occurrence of `needle`
+pub fn find(haystack: &str, needle: &str) -> Option<Range<usize>> {
+    haystack.find(needle).map(|start| start..start + needle.len())
+}
+
+/// First occurrence of `needle`, ignoring ASCII case
+pub fn find_ignore_ascii_case(haystack: &str, needle: &str) -> Option<Range<usize>> {
+    // ASCII lowercasing keeps byte offsets unchanged
+    find(&haystack.to_ascii_lowercase(), &needle.to_ascii_lowercase())
+}
+
+/// Lowercased text with some characters dropped, remembering where each
+/// run of dropped characters was so matches map back to the original
+pub struct Compacted<'a> {
+    original: &'a str,
+    text: String,
+    /// From each of these compacted offsets on, the original is this many
+    /// bytes further along; one entry per run of dropped characters
+    shifts: Vec<(usize, usize)>,
+}
+
+impl<'a> Compacted<'a> {
+    pub fn new(original: &'a str, drop: impl Fn(char) -> bool) -> Self {
+        let mut text = String::with_capacity(original.len());
+        let mut shifts = Vec::new();
+        let mut dropped = 0;
+        for c in original.chars() {
+            if drop(c) {
+                dropped += c.len_utf8();
+                continue;
+            }
+            if shifts.last().map_or(0, |&(_, shift)| shift) != dropped {
+                shifts.push((text.len(), dropped));
+            }
+            // ASCII lowercasing keeps every character's length
+            text.push(c.to_ascii_lowercase());
+        }
+        Self { original, text, shifts }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn original(&self) -> &'a str {
+        self.original
+    }
+
+    /// Original range of the first match of `pattern`
+    pub fn find(&self, pattern: &str) -> Option<Range<usize>> {
+        self.find_from(0, pattern)
+    }
+
+    /// Original range of the first match of `pattern` at or after compacted offset `from`
+    pub fn find_from(&self, from: usize, pattern: &str) -> Option<Range<usize>> {
+        if pattern.is_empty() {
+            return None;
+        }
+        let start = from + self.text.get(from..)?.find(pattern)?;
+        Some(self.original_range(start..start + pattern.len()))
+    }
+
+    /// Map a compacted range back to whole characters of the original
+    pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        let start = floor_char_boundary(self.original, self.origin(range.start));
+        let last = floor_char_boundary(self.original, self.origin(range.end - 1));
+        let end = last + self.original[last..].chars().next().map_or(0, char::len_utf8);
+        start..end
+    }
+
+    /// Original offset of a compacted byte
+    fn origin(&self, offset: usize) -> usize {
+        let runs = self.shifts.partition_point(|&(at, _)| at <= offset);
+        offset + runs.checked_sub(1).map_or(0, |run| self.shifts[run].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_widened_to_whole_characters() {
+        let inside = Span::in_text("naïve", 3..4);
+        assert_eq!((inside.offset, inside.length), (2, 2));
+        let straddling = Span::in_text("naïve", 2..3);
+        assert_eq!((straddling.offset, straddling.length), (2, 2));
+    }
+
+    #[test]
+    fn confusables_are_escaped_and_the_marker_follows_the_escapes() {
+        let text = "go to pаypal.com";
+        let mut span = Span::in_text(text, find(text, "pаypal").unwrap());
+        assert_eq!((span.offset, span.length), (6, 7));
+        span.render(text, None);
+        assert_eq!(span.excerpt, "go to p\\u{430}ypal.com");
+        assert_eq!(span.marker, format!("{}{}", " ".repeat(6), "^".repeat(12)));
+    }
+
+    #[test]
+    fn context_and_decoded_spans_name_their_layer() {
+        let mut context = Span::in_context("Please verify", 7..13);
+        context.render("unrelated content", Some("Please verify"));
+        assert_eq!((context.excerpt.as_str(), context.marker.as_str()), ("Please verify", "       ^^^^^^"));
+        assert_eq!(context.layer, Some(Layer::Context));
+
+        let layer = Layer::Base64 { offset: 16, length: 64 };
+        let decoded = Span::in_decoded(b"\0asm\x01\0\0\0", 0..4, layer.clone());
+        assert_eq!((decoded.offset, decoded.length, decoded.layer), (0, 4, Some(layer)));
+        assert_eq!(decoded.excerpt, "\\x00asm\\x01\\x00\\x00\\x00");
+        assert_eq!(decoded.marker, "^".repeat(7));
+    }
+
+    #[test]
+    fn compacted_matches_map_back_over_dropped_multibyte_characters() {
+        let compact = Compacted::new("x = E\u{3000}val(code)", char::is_whitespace);
+        assert_eq!(compact.as_str(), "x=eval(code)");
+        assert_eq!(compact.find("eval"), Some(4..11));
+    }
+}
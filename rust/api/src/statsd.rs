@@ -0,0 +1,102 @@
+// rust/api/src/statsd.rs
+//! StatsD export of detection statistics
+//!
+//! Every flush sends one UDP datagram holding the counters' increase since
+//! the previous flush (`|c`) and the current gauges (`|g`). UDP is
+//! fire-and-forget: an unreachable collector costs a failed send and
+//! nothing else, so errors are only logged at debug level.
+
+use log::debug;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{lookup_host, UdpSocket};
+
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    target: SocketAddr,
+    /// Metric name prefix including its trailing dot, or empty
+    prefix: String,
+    /// Counter values at the previous flush
+    last: HashMap<&'static str, u64>,
+}
+
+impl StatsdExporter {
+    /// Resolve the collector address once; `addr` is `host:port`
+    pub async fn connect(addr: &str, prefix: &str) -> io::Result<Self> {
+        let target = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no address", addr)))?;
+        let bind: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        Ok(Self {
+            socket: UdpSocket::bind(bind).await?,
+            target,
+            prefix: match prefix.trim_end_matches('.') {
+                "" => String::new(),
+                prefix => format!("{}.", prefix),
+            },
+            last: HashMap::new(),
+        })
+    }
+
+    /// Send counter increases and gauge values; nothing is sent if both are empty
+    pub async fn flush(&mut self, counters: &[(&'static str, u64)], gauges: &[(&str, f64)]) {
+        let mut lines = Vec::with_capacity(counters.len() + gauges.len());
+        for &(name, value) in counters {
+            let previous = self.last.insert(name, value).unwrap_or(0);
+            // Counters only restart with the process, but never send a negative count
+            let delta = value.saturating_sub(previous);
+            if delta > 0 {
+                lines.push(format!("{}{}:{}|c", self.prefix, name, delta));
+            }
+        }
+        for &(name, value) in gauges {
+            lines.push(format!("{}{}:{}|g", self.prefix, name, value));
+        }
+        if lines.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.socket.send_to(lines.join("\n").as_bytes(), self.target).await {
+            debug!("StatsD flush to {} failed: {}", self.target, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn received(collector: &UdpSocket) -> Option<String> {
+        let mut buf = [0u8; 1024];
+        let (n, _) = tokio::time::timeout(Duration::from_millis(200), collector.recv_from(&mut buf)).await.ok()?.ok()?;
+        Some(String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+
+    #[actix_web::test]
+    async fn flushes_send_counter_increases_and_gauges() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = collector.local_addr().unwrap().to_string();
+        let mut exporter = StatsdExporter::connect(&addr, "ryzen.").await.unwrap();
+
+        exporter.flush(&[("detections", 5), ("threats", 0)], &[("latency_ms.avg", 1.5)]).await;
+        assert_eq!(received(&collector).await.unwrap(), "ryzen.detections:5|c\nryzen.latency_ms.avg:1.5|g");
+        exporter.flush(&[("detections", 8), ("threats", 2)], &[]).await;
+        assert_eq!(received(&collector).await.unwrap(), "ryzen.detections:3|c\nryzen.threats:2|c");
+        // Nothing changed and no gauges: no datagram at all
+        exporter.flush(&[("detections", 8), ("threats", 2)], &[]).await;
+        assert_eq!(received(&collector).await, None);
+    }
+
+    #[actix_web::test]
+    async fn an_absent_collector_is_not_an_error() {
+        let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = collector.local_addr().unwrap().to_string();
+        drop(collector);
+        let mut exporter = StatsdExporter::connect(&addr, "").await.unwrap();
+        exporter.flush(&[("detections", 1)], &[]).await;
+        exporter.flush(&[("detections", 2)], &[]).await;
+    }
+}
@@ -0,0 +1,308 @@
+// rust/api/src/streams.rs
+//! Accumulated content of partially received messages
+//!
+//! `/api/detect/partial` receives a message a fragment at a time, keyed by
+//! (client identity, stream id). Fragments carrying a `seq` are applied in
+//! order: later ones are held until the gap before them fills, and repeats
+//! of an applied sequence number are ignored. Only text up to the last
+//! whitespace is handed out for scanning, so a URL or word still being
+//! typed is not judged before it is complete.
+//!
+//! The store is bounded three ways: the number of streams (least recently
+//! updated evicted first), bytes per stream, and fragments held out of
+//! order per stream. Streams idle for longer than the TTL are abandoned and
+//! dropped.
+
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::thresholds::Verdict;
+
+/// What the cheap checks made of one URL
+#[derive(Debug, Clone, Serialize)]
+pub struct UrlHint {
+    pub url: String,
+    pub is_threat: bool,
+    pub verdict: Verdict,
+    pub confidence: f32,
+    pub severity: String,
+    pub reasons: Vec<String>,
+}
+
+struct Stream {
+    text: String,
+    /// Next sequence number to apply
+    next_seq: u64,
+    /// Fragments received ahead of `next_seq`
+    pending: BTreeMap<u64, String>,
+    /// Bytes of `text` already handed out for scanning
+    scanned: usize,
+    hints: Vec<UrlHint>,
+    keywords: BTreeSet<String>,
+    updated: Instant,
+}
+
+impl Stream {
+    fn new(now: Instant) -> Self {
+        Self {
+            text: String::new(),
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            scanned: 0,
+            hints: Vec::new(),
+            keywords: BTreeSet::new(),
+            updated: now,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.text.len() + self.pending.values().map(String::len).sum::<usize>()
+    }
+}
+
+/// Why a fragment could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamError {
+    TooLarge { max_bytes: usize },
+    TooManyPending { max: usize },
+    /// A final fragment arrived while earlier ones are still missing; it
+    /// is kept, and resending it once the gaps are filled closes the stream
+    Incomplete { missing: Vec<u64> },
+}
+
+impl StreamError {
+    pub fn message(&self) -> String {
+        match self {
+            StreamError::TooLarge { max_bytes } => format!("Stream would exceed {} bytes", max_bytes),
+            StreamError::TooManyPending { max } => format!("More than {} fragments are waiting for a gap to fill", max),
+            StreamError::Incomplete { missing } => format!("Fragments {:?} have not been received", missing),
+        }
+    }
+}
+
+/// State of a stream after a fragment was applied
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub received_bytes: usize,
+    pub next_seq: u64,
+    /// Sequence numbers held until the gap before them fills
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pending_seqs: Vec<u64>,
+    /// The fragment repeated an applied sequence number and was ignored
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub duplicate: bool,
+}
+
+/// A closed stream
+pub struct Finished {
+    pub progress: Progress,
+    pub text: String,
+    /// Text after the last scanned whitespace, never handed out for scanning
+    pub unscanned: String,
+    pub hints: Vec<UrlHint>,
+    pub keywords: Vec<String>,
+}
+
+/// A fragment to apply
+pub struct Fragment<'a> {
+    pub seq: Option<u64>,
+    pub text: &'a str,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct StreamStats {
+    pub active: usize,
+    /// Dropped after going idle or evicted for room, without a final fragment
+    pub abandoned: u64,
+    pub finished: u64,
+}
+
+pub struct StreamStore {
+    streams: Mutex<LruCache<(String, String), Stream>>,
+    max_bytes: usize,
+    max_pending: usize,
+    ttl: Duration,
+    clock: SharedClock,
+    abandoned: AtomicU64,
+    finished: AtomicU64,
+}
+
+impl StreamStore {
+    pub fn new(capacity: usize, max_bytes: usize, max_pending: usize, ttl: Duration, clock: SharedClock) -> Self {
+        Self {
+            streams: Mutex::new(LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN))),
+            max_bytes,
+            max_pending,
+            ttl,
+            clock,
+            abandoned: AtomicU64::new(0),
+            finished: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply a fragment and return the newly completed text to scan
+    pub fn append(&self, client: &str, id: &str, fragment: Fragment) -> Result<(Progress, String), StreamError> {
+        let mut streams = self.lock();
+        let stream = self.stream(&mut streams, client, id);
+        let duplicate = apply(stream, fragment, self.max_bytes, self.max_pending)?;
+
+        // Stop at the last whitespace: the token after it may still be growing
+        let complete = stream
+            .text
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let unscanned = stream.text.get(stream.scanned..complete).unwrap_or_default().to_string();
+        stream.scanned = stream.scanned.max(complete);
+        Ok((progress(stream, duplicate), unscanned))
+    }
+
+    /// Record what scanning found; returns every hint and keyword so far
+    pub fn add_findings(
+        &self,
+        client: &str,
+        id: &str,
+        hints: Vec<UrlHint>,
+        keywords: Vec<String>,
+    ) -> (Vec<UrlHint>, Vec<String>) {
+        let mut streams = self.lock();
+        let Some(stream) = streams.peek_mut(&(client.to_string(), id.to_string())) else {
+            return (hints, keywords);
+        };
+        for hint in hints {
+            if !stream.hints.iter().any(|h| h.url == hint.url) {
+                stream.hints.push(hint);
+            }
+        }
+        stream.keywords.extend(keywords);
+        (stream.hints.clone(), stream.keywords.iter().cloned().collect())
+    }
+
+    /// Apply the last fragment and hand back the whole text, closing the stream
+    pub fn finish(&self, client: &str, id: &str, fragment: Fragment) -> Result<Finished, StreamError> {
+        let mut streams = self.lock();
+        let stream = self.stream(&mut streams, client, id);
+        let duplicate = apply(stream, fragment, self.max_bytes, self.max_pending)?;
+        if !stream.pending.is_empty() {
+            let last_held = *stream.pending.keys().next_back().unwrap();
+            let missing = (stream.next_seq..last_held).filter(|seq| !stream.pending.contains_key(seq)).collect();
+            return Err(StreamError::Incomplete { missing });
+        }
+        let progress = progress(stream, duplicate);
+        let stream = streams.pop(&(client.to_string(), id.to_string())).unwrap();
+        self.finished.fetch_add(1, Ordering::Relaxed);
+        Ok(Finished {
+            progress,
+            unscanned: stream.text.get(stream.scanned..).unwrap_or_default().to_string(),
+            text: stream.text,
+            hints: stream.hints,
+            keywords: stream.keywords.into_iter().collect(),
+        })
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        let streams = self.lock();
+        StreamStats {
+            active: streams.len(),
+            abandoned: self.abandoned.load(Ordering::Relaxed),
+            finished: self.finished.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Rough bytes held by all streams
+    pub fn estimated_bytes(&self) -> usize {
+        let streams = self.lock();
+        streams
+            .iter()
+            .map(|((client, id), s)| {
+                client.len()
+                    + id.len()
+                    + std::mem::size_of::<Stream>()
+                    + s.bytes()
+                    + s.hints.iter().map(|h| h.url.len() + h.reasons.iter().map(String::len).sum::<usize>()).sum::<usize>()
+                    + s.keywords.iter().map(String::len).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Lock the streams, dropping the ones left idle past the TTL
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<(String, String), Stream>> {
+        let mut streams = self.streams.lock().unwrap();
+        let now = self.clock.now();
+        // Least recently updated last, so idle streams are found from the tail
+        while streams.peek_lru().is_some_and(|(_, s)| now.duration_since(s.updated) > self.ttl) {
+            streams.pop_lru();
+            self.abandoned.fetch_add(1, Ordering::Relaxed);
+        }
+        streams
+    }
+
+    fn stream<'a>(
+        &self,
+        streams: &'a mut LruCache<(String, String), Stream>,
+        client: &str,
+        id: &str,
+    ) -> &'a mut Stream {
+        let key = (client.to_string(), id.to_string());
+        if !streams.contains(&key) && streams.len() == streams.cap().get() {
+            streams.pop_lru();
+            self.abandoned.fetch_add(1, Ordering::Relaxed);
+        }
+        let now = self.clock.now();
+        let stream = streams.get_or_insert_mut(key, || Stream::new(now));
+        stream.updated = now;
+        stream
+    }
+}
+
+/// Apply a fragment in order; true if it repeated an applied sequence number
+fn apply(stream: &mut Stream, fragment: Fragment, max_bytes: usize, max_pending: usize) -> Result<bool, StreamError> {
+    let seq = fragment.seq.unwrap_or(stream.next_seq);
+    if seq < stream.next_seq || stream.pending.contains_key(&seq) {
+        return Ok(true);
+    }
+    if stream.bytes() + fragment.text.len() > max_bytes {
+        return Err(StreamError::TooLarge { max_bytes });
+    }
+    if seq > stream.next_seq {
+        if stream.pending.len() >= max_pending {
+            return Err(StreamError::TooManyPending { max: max_pending });
+        }
+        stream.pending.insert(seq, fragment.text.to_string());
+        return Ok(false);
+    }
+    stream.text.push_str(fragment.text);
+    stream.next_seq += 1;
+    while let Some(text) = stream.pending.remove(&stream.next_seq) {
+        stream.text.push_str(&text);
+        stream.next_seq += 1;
+    }
+    Ok(false)
+}
+
+fn progress(stream: &Stream, duplicate: bool) -> Progress {
+    Progress {
+        received_bytes: stream.bytes(),
+        next_seq: stream.next_seq,
+        pending_seqs: stream.pending.keys().copied().collect(),
+        duplicate,
+    }
+}
+
+/// URLs in scanned text, without trailing punctuation
+pub fn extract_urls(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .filter_map(|token| {
+            let start = token.find("http://").or_else(|| token.find("https://"))?;
+            let url = token[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']);
+            (url.len() > "https://".len()).then_some(url)
+        })
+        .collect()
+}
@@ -0,0 +1,312 @@
+// rust/api/src/syslog_export.rs
+//! Syslog export of detection decisions
+//!
+//! With `syslog_addr` set, each verdict served is sent to that collector
+//! as an RFC 5424 message over `syslog_protocol` (`udp` or `tcp`), under
+//! `syslog_facility`. Threats are sent at warning severity and everything
+//! else at info. The message text summarizes the verdict, and its
+//! structured data element `decision@32473` carries the fields a
+//! collector would filter on. Content is never sent, only its hash.
+//!
+//! Messages are handed to a sender thread through a queue of `QUEUE`, so
+//! a slow or unreachable collector never holds up a request. When the
+//! queue is full, messages are dropped and counted. A TCP connection that
+//! fails is reopened for the next message, at most once per `RECONNECT`.
+//! Over TCP each message ends in a newline, the framing most collectors
+//! expect.
+
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use syslog::{Facility, Formatter5424, Logger, LoggerBackend};
+
+use crate::clock::SharedClock;
+use crate::{ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Decisions waiting for the sender thread
+const QUEUE: usize = 10_000;
+
+/// Least time between attempts to reopen a failed connection
+const RECONNECT: Duration = Duration::from_secs(5);
+
+/// Structured data element id; 32473 is the enterprise number RFC 5424
+/// reserves for documentation, as no number is registered for this service
+const SD_ID: &str = "decision@32473";
+
+/// RFC 5424 MSGID of decision messages
+const MSG_ID: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where and how decisions are sent
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub addr: String,
+    pub protocol: Protocol,
+    pub facility: Facility,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SyslogStats {
+    pub sent: u64,
+    /// Dropped for a full queue or a collector that could not be reached
+    pub dropped: u64,
+}
+
+/// RFC 5424 structured data: element id to parameters
+type StructuredData = BTreeMap<String, BTreeMap<String, String>>;
+
+struct Decision {
+    threat: bool,
+    data: StructuredData,
+    text: String,
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+pub struct SyslogExporter {
+    tx: SyncSender<Decision>,
+    counters: Arc<Counters>,
+}
+
+impl SyslogExporter {
+    /// Start the sender thread; the connection is opened on first use
+    pub fn start(target: Target, clock: SharedClock) -> std::io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        let counters = Arc::new(Counters::default());
+        let sender = Sender { target, logger: None, last_attempt: None, counters: counters.clone(), clock };
+        std::thread::Builder::new().name("syslog".to_string()).spawn(move || sender.run(rx))?;
+        Ok(Self { tx, counters })
+    }
+
+    /// Queue a served verdict for the collector
+    pub fn record(&self, tenant: Option<&str>, req: &ThreatDetectionRequest, response: &ThreatDetectionResponse, content_hash: &str) {
+        let mut params = BTreeMap::new();
+        let mut param = |name: &str, value: String| {
+            params.insert(name.to_string(), value);
+        };
+        param("threat_type", req.threat_type.clone());
+        param("verdict", serde_json::to_value(response.verdict).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default());
+        param("is_threat", response.is_threat.to_string());
+        param("confidence", format!("{:.3}", response.confidence));
+        param("severity", response.severity.clone());
+        param("cached", response.cached.to_string());
+        param("content_hash", content_hash.to_string());
+        if let Some(tenant) = tenant {
+            param("tenant", tenant.to_string());
+        }
+        if let Some(id) = &response.detection_id {
+            param("detection_id", id.clone());
+        }
+        if let Some(hash) = &response.engine_state_hash {
+            param("engine_state", hash.to_string());
+        }
+        let text = format!(
+            "{} {} ({:.2}, {}): {}",
+            response.threat_type,
+            if response.is_threat { "threat" } else { "no threat" },
+            response.confidence,
+            response.severity,
+            response.reasons.join("; ")
+        )
+        .replace(['\r', '\n'], " ");
+        let decision = Decision { threat: response.is_threat, data: BTreeMap::from([(SD_ID.to_string(), params)]), text };
+        if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) = self.tx.try_send(decision) {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> SyslogStats {
+        SyslogStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Sender {
+    target: Target,
+    logger: Option<Logger<LoggerBackend, Formatter5424>>,
+    last_attempt: Option<Instant>,
+    counters: Arc<Counters>,
+    /// Time source for the reconnect backoff
+    clock: SharedClock,
+}
+
+impl Sender {
+    fn run(mut self, rx: Receiver<Decision>) {
+        for decision in rx {
+            match self.send(decision) {
+                true => self.counters.sent.fetch_add(1, Ordering::Relaxed),
+                false => self.counters.dropped.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+
+    fn send(&mut self, decision: Decision) -> bool {
+        // TCP collectors split messages on LF (RFC 6587 non-transparent framing)
+        let text = match self.target.protocol {
+            Protocol::Udp => decision.text,
+            Protocol::Tcp => decision.text + "\n",
+        };
+        let Some(logger) = self.logger() else { return false };
+        let message = (MSG_ID, decision.data, text);
+        let result = match decision.threat {
+            true => logger.warning(message),
+            false => logger.info(message),
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Syslog send to {} failed: {}", self.target.addr, e);
+                // UDP sockets stay usable; a broken TCP stream is reopened
+                if self.target.protocol == Protocol::Tcp {
+                    self.logger = None;
+                }
+                false
+            }
+        }
+    }
+
+    fn logger(&mut self) -> Option<&mut Logger<LoggerBackend, Formatter5424>> {
+        let now = self.clock.now();
+        if self.logger.is_none() && self.last_attempt.is_none_or(|at| now.saturating_duration_since(at) >= RECONNECT) {
+            self.last_attempt = Some(now);
+            let formatter = Formatter5424 {
+                facility: self.target.facility,
+                hostname: std::env::var("HOSTNAME").ok(),
+                process: env!("CARGO_PKG_NAME").to_string(),
+                pid: std::process::id(),
+            };
+            let bind = match self.target.addr.starts_with('[') {
+                true => "[::]:0",
+                false => "0.0.0.0:0",
+            };
+            let logger = match self.target.protocol {
+                Protocol::Udp => syslog::udp(formatter, bind, self.target.addr.as_str()),
+                Protocol::Tcp => syslog::tcp(formatter, self.target.addr.as_str()),
+            };
+            match logger {
+                Ok(logger) => self.logger = Some(logger),
+                Err(e) => warn!("Syslog collector {} unavailable: {}", self.target.addr, e),
+            }
+        }
+        self.logger.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::enrichment;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, UdpSocket};
+
+    fn request(content: &str) -> ThreatDetectionRequest {
+        ThreatDetectionRequest {
+            threat_type: "url".to_string(),
+            content: content.to_string(),
+            context: None,
+            explain: false,
+            enrich: enrichment::Mode::None,
+        }
+    }
+
+    fn start(addr: String, protocol: Protocol) -> SyslogExporter {
+        let target = Target { addr, protocol, facility: Facility::LOG_LOCAL0 };
+        SyslogExporter::start(target, Arc::new(SystemClock)).unwrap()
+    }
+
+    /// Wait for the sender thread to account for `count` messages
+    fn settled(exporter: &SyslogExporter, count: u64) -> SyslogStats {
+        let started = Instant::now();
+        loop {
+            let stats = exporter.stats();
+            if stats.sent + stats.dropped >= count || started.elapsed() > Duration::from_secs(5) {
+                return stats;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn decisions_reach_a_udp_collector_as_rfc5424_without_their_content() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let exporter = start(collector.local_addr().unwrap().to_string(), Protocol::Udp);
+        let content = "https://secure-login.example.net/verify";
+        let response = ThreatDetectionResponse::new("phishing", true, 0.93, "high".to_string(), vec!["Suspicious keywords".to_string()]);
+        exporter.record(Some("acme"), &request(content), &response, "c0ffee");
+
+        let mut buf = [0u8; 2048];
+        let n = collector.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        // local0 (16) at warning (4): 16 * 8 + 4
+        assert!(message.starts_with("<132>1 "), "{}", message);
+        assert!(message.contains(" 1 [decision@32473 "), "{}", message);
+        for param in [r#"threat_type="url""#, r#"verdict="threat""#, r#"confidence="0.930""#, r#"tenant="acme""#, r#"content_hash="c0ffee""#] {
+            assert!(message.contains(param), "{} in {}", param, message);
+        }
+        assert!(message.ends_with("phishing threat (0.93, high): Suspicious keywords"), "{}", message);
+        assert!(!message.contains(content));
+        assert_eq!(settled(&exporter, 1).sent, 1);
+
+        // Anything but a threat goes at info: 16 * 8 + 6
+        let safe = ThreatDetectionResponse::new("phishing", false, 0.1, "low".to_string(), vec![]);
+        exporter.record(None, &request(content), &safe, "c0ffee");
+        let n = collector.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        assert!(message.starts_with("<134>1 "), "{}", message);
+        assert!(!message.contains("tenant="), "{}", message);
+    }
+
+    #[test]
+    fn tcp_messages_end_in_a_newline_and_an_absent_collector_drops_them() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let exporter = start(collector.local_addr().unwrap().to_string(), Protocol::Tcp);
+        let response = ThreatDetectionResponse::new("phishing", false, 0.1, "low".to_string(), vec!["Line one\nline two".to_string()]);
+        exporter.record(None, &request("https://example.org/"), &response, "ab");
+        exporter.record(None, &request("https://example.org/"), &response, "cd");
+
+        let (stream, _) = collector.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let lines: Vec<String> = BufReader::new(stream).lines().take(2).map(Result::unwrap).collect();
+        assert!(lines[0].contains(r#"content_hash="ab""#) && lines[0].ends_with("Line one line two"), "{:?}", lines);
+        assert!(lines[1].contains(r#"content_hash="cd""#), "{:?}", lines);
+        assert_eq!(settled(&exporter, 2).sent, 2);
+
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = unused.local_addr().unwrap().to_string();
+        drop(unused);
+        let absent = start(addr, Protocol::Tcp);
+        absent.record(None, &request("https://example.org/"), &response, "ef");
+        let stats = settled(&absent, 1);
+        assert_eq!((stats.sent, stats.dropped), (0, 1));
+    }
+}
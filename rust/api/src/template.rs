@@ -0,0 +1,115 @@
+// rust/api/src/template.rs
+//! Response templates: detection responses in a downstream schema
+//!
+//! `response_templates` maps a request threat type to a template that
+//! renames top-level fields of its detection responses (`is_threat` to
+//! `blocked`, say) and leaves others out, so integrators can consume
+//! verdicts without reshaping them. A renamed field replaces any field
+//! already under its new name.
+//!
+//! Templates apply last, as `/api/detect`, `/api/detect/raw` and batch
+//! results are serialized for the caller, after the response policy.
+//! Streamed and QR verdicts keep the standard shape. The signature covers
+//! the standard field names, so a templated response must be mapped back
+//! before `ryzen-scan verify` can check it.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseTemplate {
+    /// New name by response field name
+    pub rename: HashMap<String, String>,
+    /// Response fields left out
+    pub omit: Vec<String>,
+}
+
+impl ResponseTemplate {
+    /// Renames that collide or contradict `omit`
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut targets: Vec<(&String, &String)> = self.rename.iter().map(|(from, to)| (to, from)).collect();
+        targets.sort();
+        for pair in targets.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                problems.push(format!("rename: {} and {} both renamed to {:?}", pair[0].1, pair[1].1, pair[0].0));
+            }
+        }
+        for (from, to) in &self.rename {
+            if to.is_empty() {
+                problems.push(format!("rename.{}: empty field name", from));
+            }
+            if self.omit.contains(from) {
+                problems.push(format!("rename.{}: field is also omitted", from));
+            }
+        }
+        problems
+    }
+
+    /// A response's JSON shaped by this template
+    pub fn apply(&self, response: &impl Serialize) -> Value {
+        // Through text rather than `to_value`, so f32 fields keep their short form
+        let mut value = serde_json::to_string(response)
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .unwrap_or(Value::Null);
+        let Value::Object(fields) = &mut value else { return value };
+        for name in &self.omit {
+            fields.remove(name);
+        }
+        let renamed: Vec<(&String, Value)> =
+            self.rename.iter().filter_map(|(from, to)| Some((to, fields.remove(from)?))).collect();
+        for (to, field) in renamed {
+            fields.insert(to.clone(), field);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn template(rename: &[(&str, &str)], omit: &[&str]) -> ResponseTemplate {
+        ResponseTemplate {
+            rename: rename.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect(),
+            omit: omit.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Response {
+        is_threat: bool,
+        confidence: f32,
+        severity: &'static str,
+        blocked: &'static str,
+        reasons: Vec<&'static str>,
+    }
+
+    fn response() -> Response {
+        Response { is_threat: true, confidence: 0.7, severity: "high", blocked: "stale", reasons: vec!["Suspicious"] }
+    }
+
+    #[test]
+    fn fields_are_renamed_and_omitted_and_the_rest_kept() {
+        let shaped = template(&[("is_threat", "blocked"), ("confidence", "score")], &["reasons"]).apply(&response());
+        assert_eq!(shaped, json!({ "blocked": true, "score": 0.7, "severity": "high" }));
+        // f32 fields keep their short form rather than 0.699999988079071
+        assert_eq!(shaped["score"].to_string(), "0.7");
+        assert_eq!(ResponseTemplate::default().apply(&response())["reasons"], json!(["Suspicious"]));
+    }
+
+    #[test]
+    fn colliding_or_contradicting_renames_are_problems() {
+        let colliding = template(&[("is_threat", "flag"), ("severity", "flag")], &[]);
+        assert_eq!(colliding.problems(), ["rename: is_threat and severity both renamed to \"flag\""]);
+        let omitted = template(&[("reasons", "why"), ("severity", "")], &["reasons"]);
+        let mut problems = omitted.problems();
+        problems.sort();
+        assert_eq!(problems, ["rename.reasons: field is also omitted", "rename.severity: empty field name"]);
+        assert!(template(&[("is_threat", "blocked")], &["reasons"]).problems().is_empty());
+    }
+}
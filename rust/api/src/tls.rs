@@ -0,0 +1,188 @@
+// rust/api/src/tls.rs
+//! HTTPS serving
+//!
+//! With `tls_cert_file` and `tls_key_file` set, the server listens on
+//! `bind_addr` over TLS only. The certificate file holds the PEM chain,
+//! leaf first; the key file a PEM PKCS#8, PKCS#1 or SEC1 private key.
+//! Handshakes below `tls_min_version` are refused. TLS 1.0 and 1.1 are not
+//! supported at all, so the minimum is `1.2` or `1.3`.
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, SupportedProtocolVersion};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// Accepted `tls_min_version` values
+pub const MIN_VERSIONS: &[&str] = &["1.2", "1.3"];
+
+const FROM_TLS12: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13, &rustls::version::TLS12];
+const FROM_TLS13: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Protocol versions allowed by a `tls_min_version`, newest first
+pub fn versions(min_version: &str) -> Option<&'static [&'static SupportedProtocolVersion]> {
+    match min_version {
+        "1.2" => Some(FROM_TLS12),
+        "1.3" => Some(FROM_TLS13),
+        _ => None,
+    }
+}
+
+/// Server config from a certificate chain and key, allowing `min_version` and up
+pub fn load(cert_file: &Path, key_file: &Path, min_version: &str) -> io::Result<ServerConfig> {
+    server_config(load_certs(cert_file)?, load_key(key_file)?, key_file, min_version)
+}
+
+/// Certificates of a PEM chain file
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<_>>()
+        .map_err(|e| invalid(path, e.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no PEM certificate found".to_string()));
+    }
+    Ok(certs)
+}
+
+/// First private key of a PEM file
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))
+        .map_err(|e| invalid(path, e.to_string()))?
+        .ok_or_else(|| invalid(path, "no PEM private key found".to_string()))
+}
+
+/// Server config for a loaded chain and the key from `key_file`
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    key_file: &Path,
+    min_version: &str,
+) -> io::Result<ServerConfig> {
+    let versions = versions(min_version)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported TLS version {:?}", min_version)))?;
+    ServerConfig::builder_with_provider(ring::default_provider().into())
+        .with_protocol_versions(versions)
+        .map_err(|e| io::Error::other(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(key_file, format!("unusable with the certificate: {}", e)))
+}
+
+fn invalid(path: &Path, message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConnection};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    fn server(min_version: &str) -> ServerConnection {
+        let config = load(&testdata("tls-cert.pem"), &testdata("tls-key.pem"), min_version).unwrap();
+        ServerConnection::new(Arc::new(config)).unwrap()
+    }
+
+    /// A client trusting the test CA that speaks only `versions`
+    fn client(versions: &[&'static SupportedProtocolVersion]) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&testdata("tls-ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(ring::default_provider().into())
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap()
+    }
+
+    /// Move what one side has to send into the other
+    fn deliver(from: &mut Connection, to: &mut Connection) -> Result<bool, rustls::Error> {
+        let mut records = Vec::new();
+        from.write_tls(&mut records).unwrap();
+        let mut unread = records.as_slice();
+        while !unread.is_empty() {
+            to.read_tls(&mut unread).unwrap();
+        }
+        to.process_new_packets()?;
+        Ok(!records.is_empty())
+    }
+
+    /// Pass records back and forth until the handshake completes or a side fails
+    fn handshake(client: ClientConnection, server: ServerConnection) -> Result<rustls::ProtocolVersion, rustls::Error> {
+        let (mut client, mut server) = (Connection::from(client), Connection::from(server));
+        while client.is_handshaking() || server.is_handshaking() {
+            let sent = deliver(&mut client, &mut server)?;
+            if !deliver(&mut server, &mut client)? && !sent {
+                break;
+            }
+        }
+        Ok(server.protocol_version().unwrap())
+    }
+
+    /// A TLS 1.1 ClientHello, as rustls clients cannot be made to send one.
+    /// It carries the extensions rustls requires before it looks at the
+    /// version, so the version alone is what gets it refused.
+    fn tls11_client_hello() -> Vec<u8> {
+        let mut hello = vec![0x03, 0x02];
+        hello.extend([0x5a; 32]);
+        // No session id; two CBC suites; null compression
+        hello.extend([0x00, 0x00, 0x04, 0xc0, 0x13, 0x00, 0x2f, 0x01, 0x00]);
+        let extensions = [
+            // signature_algorithms: ecdsa_secp256r1_sha256
+            &[0x00, 0x0d, 0x00, 0x04, 0x00, 0x02, 0x04, 0x03][..],
+            // supported_groups: x25519
+            &[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d],
+            // ec_point_formats: uncompressed
+            &[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00],
+        ]
+        .concat();
+        hello.extend([0x00, extensions.len() as u8]);
+        hello.extend(extensions);
+        let mut handshake = vec![0x01, 0x00, 0x00, hello.len() as u8];
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01, 0x00, handshake.len() as u8];
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn a_tls_1_1_handshake_is_refused_with_a_protocol_version_alert() {
+        let mut server = server("1.2");
+        let hello = tls11_client_hello();
+        server.read_tls(&mut hello.as_slice()).unwrap();
+        let refused = server.process_new_packets().unwrap_err();
+        assert_eq!(refused, rustls::Error::PeerIncompatible(rustls::PeerIncompatible::Tls12NotOffered));
+        let mut alert = Vec::new();
+        server.write_tls(&mut alert).unwrap();
+        // Alert record, fatal, protocol_version
+        assert_eq!((alert[0], &alert[alert.len() - 2..]), (0x15, &[0x02, 0x46][..]));
+    }
+
+    #[test]
+    fn the_minimum_version_decides_which_clients_complete_a_handshake() {
+        let tls12 = &[&rustls::version::TLS12][..];
+        assert_eq!(handshake(client(tls12), server("1.2")), Ok(rustls::ProtocolVersion::TLSv1_2));
+        assert_eq!(handshake(client(rustls::DEFAULT_VERSIONS), server("1.2")), Ok(rustls::ProtocolVersion::TLSv1_3));
+        assert!(matches!(handshake(client(tls12), server("1.3")), Err(rustls::Error::PeerIncompatible(_))));
+        assert_eq!(handshake(client(rustls::DEFAULT_VERSIONS), server("1.3")), Ok(rustls::ProtocolVersion::TLSv1_3));
+    }
+
+    #[test]
+    fn unsupported_minimum_versions_are_rejected() {
+        for version in ["1.0", "1.1", "TLS1.2", ""] {
+            let e = load(&testdata("tls-cert.pem"), &testdata("tls-key.pem"), version).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", version);
+        }
+        let mismatched = load(&testdata("tls-ca.pem"), &testdata("tls-key.pem"), "1.2").unwrap_err();
+        assert!(mismatched.to_string().contains("unusable with the certificate"), "{}", mismatched);
+    }
+}
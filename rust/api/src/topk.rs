@@ -0,0 +1,114 @@
+// rust/api/src/topk.rs
+//! Most frequently seen threat content
+//!
+//! A Space-Saving counter over content hashes of threat verdicts: at most
+//! `capacity` hashes are tracked, and a new hash arriving when the table is
+//! full replaces the least counted one, inheriting its count as the error
+//! bound. Any hash seen more than total/capacity times is guaranteed to be
+//! tracked, and `count - error` is a lower bound on its true count.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopEntry {
+    pub content_hash: String,
+    /// Request threat type of the most recent sighting
+    pub threat_type: String,
+    /// Upper bound on the number of sightings
+    pub count: u64,
+    /// How much of `count` may belong to evicted hashes
+    pub error: u64,
+}
+
+pub struct TopThreats {
+    entries: Mutex<HashMap<String, TopEntry>>,
+    capacity: usize,
+}
+
+impl TopThreats {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Mutex::new(HashMap::with_capacity(capacity)), capacity }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn record(&self, content_hash: &str, threat_type: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(content_hash) {
+            entry.count += 1;
+            entry.threat_type = threat_type.to_string();
+            return;
+        }
+        let (count, error) = if entries.len() < self.capacity {
+            (1, 0)
+        } else {
+            let evicted = entries
+                .values()
+                .min_by_key(|e| e.count)
+                .map(|e| e.content_hash.clone())
+                .expect("a full table has entries");
+            let evicted = entries.remove(&evicted).unwrap();
+            (evicted.count + 1, evicted.count)
+        };
+        entries.insert(content_hash.to_string(), TopEntry {
+            content_hash: content_hash.to_string(),
+            threat_type: threat_type.to_string(),
+            count,
+            error,
+        });
+    }
+
+    /// Rough bytes held by the table
+    pub fn estimated_bytes(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|(key, e)| key.len() + std::mem::size_of::<TopEntry>() + e.content_hash.len() + e.threat_type.len())
+            .sum()
+    }
+
+    /// The `n` most counted hashes, most counted first
+    pub fn top(&self, n: usize) -> Vec<TopEntry> {
+        let mut top: Vec<TopEntry> = self.entries.lock().unwrap().values().cloned().collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.content_hash.cmp(&b.content_hash)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavy_hitters_survive_a_stream_of_one_off_hashes() {
+        let top = TopThreats::new(4);
+        for i in 0..200 {
+            top.record("heavy", "code");
+            if i % 4 == 0 {
+                top.record("medium", "url");
+            }
+            top.record(&format!("noise-{}", i), "url");
+        }
+
+        let entries = top.top(4);
+        assert_eq!((entries[0].content_hash.as_str(), entries[0].count, entries[0].error), ("heavy", 200, 0));
+        // Evictions inflate the other counts, but never past their error bound
+        let true_count = |hash: &str| if hash == "medium" { 50 } else { 1 };
+        assert!(entries[1..].iter().all(|e| e.count - e.error <= true_count(&e.content_hash)));
+    }
+
+    #[test]
+    fn zero_capacity_tracks_nothing() {
+        let top = TopThreats::new(0);
+        top.record("heavy", "code");
+        assert!(top.top(10).is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+// rust/api/src/triage.rs
+//! Queue of detections the engine could not confidently call
+//!
+//! A detection is queued for human review when its confidence falls in its
+//! threat type's triage band (the needs-review band when none is
+//! configured), or when structured content does not parse: a `url` that is
+//! not a URL. Its response is tagged `triage`, and the item keeps the
+//! request and verdict for the reviewer. Content already pending is not
+//! queued twice.
+//!
+//! Each tenant, and callers without one, has a separate queue of up to
+//! `triage_capacity` items. When a queue is full the oldest resolved item
+//! makes room; if every item is still pending, the new one is dropped and
+//! counted instead, so reviewers never lose work they may have started.
+//!
+//! A resolution claims its item before applying its action and only then
+//! records the verdict, so of two concurrent resolutions of one item only
+//! the first applies anything.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::clock::SharedClock;
+use crate::fingerprints;
+use crate::thresholds::{Verdict, SEVERITIES};
+use crate::{ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Confidence range `[min, max)` whose verdicts are queued for review
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TriageBand {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl TriageBand {
+    pub fn contains(&self, confidence: f32) -> bool {
+        self.min <= confidence && confidence < self.max
+    }
+}
+
+/// Why a detection was queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// Confidence in the triage band
+    GrayBand,
+    /// Structured content that did not parse
+    ParseFailure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Pending,
+    /// Claimed by a resolution whose action is being applied
+    Resolving,
+    Resolved,
+}
+
+/// A reviewer's verdict on a queued detection
+#[derive(Debug, Clone, Serialize)]
+pub struct Resolution {
+    /// `threat` or `safe`
+    pub verdict: Verdict,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub resolved_by: String,
+    pub resolved_at: DateTime<Utc>,
+    /// Rule or list entry created from the resolution, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Item {
+    pub id: String,
+    pub state: State,
+    pub reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Request threat type
+    pub threat_type: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    pub content_hash: String,
+    /// The engine's verdict
+    pub verdict: Verdict,
+    pub confidence: f32,
+    pub severity: String,
+    pub reasons: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+    /// Cache entry holding the engine's verdict, dropped on resolution
+    #[serde(skip)]
+    pub cache_key: Option<String>,
+}
+
+impl Item {
+    fn estimated_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.content.len()
+            + self.context.as_ref().map_or(0, String::len)
+            + self.reasons.iter().map(String::len).sum::<usize>()
+            + self.content_hash.len()
+            + self.cache_key.as_ref().map_or(0, String::len)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct TriageStats {
+    /// Items queued since startup
+    pub queued: u64,
+    /// Items not queued because their queue was full of pending ones
+    pub dropped: u64,
+    /// Items held now, by state; claimed items count as pending
+    pub pending: u64,
+    pub resolved: u64,
+    /// Resolutions by the reviewer's verdict
+    pub resolved_threat: u64,
+    pub resolved_safe: u64,
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound,
+    AlreadyResolved,
+}
+
+pub struct TriageQueue {
+    capacity: usize,
+    /// Keyed by tenant; `None` for callers without one
+    queues: Mutex<HashMap<Option<String>, VecDeque<Item>>>,
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    resolved_threat: AtomicU64,
+    resolved_safe: AtomicU64,
+    clock: SharedClock,
+}
+
+impl TriageQueue {
+    /// Queue of up to `capacity` items per tenant; 0 disables triage
+    pub fn new(capacity: usize, clock: SharedClock) -> Self {
+        Self {
+            capacity,
+            queues: Mutex::new(HashMap::new()),
+            queued: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            resolved_threat: AtomicU64::new(0),
+            resolved_safe: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Queue a detection for review, unless the same content is already
+    /// pending; whether it is now pending
+    pub fn offer(
+        &self,
+        tenant: Option<&str>,
+        req: &ThreatDetectionRequest,
+        response: &ThreatDetectionResponse,
+        reason: Reason,
+        cache_key: Option<&str>,
+    ) -> bool {
+        let content_hash = fingerprints::fingerprint(&req.content);
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(tenant.map(str::to_string)).or_default();
+        let pending = |item: &Item| item.state != State::Resolved && item.threat_type == req.threat_type && item.content_hash == content_hash;
+        if queue.iter().any(pending) {
+            return true;
+        }
+        if queue.len() >= self.capacity {
+            match queue.iter().position(|item| item.state == State::Resolved) {
+                Some(oldest) => {
+                    queue.remove(oldest);
+                }
+                None => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
+        queue.push_back(Item {
+            id: fingerprints::detection_id(),
+            state: State::Pending,
+            reason,
+            tenant: tenant.map(str::to_string),
+            threat_type: req.threat_type.clone(),
+            content: req.content.clone(),
+            context: req.context.clone(),
+            content_hash,
+            verdict: response.verdict,
+            confidence: response.confidence,
+            severity: response.severity.clone(),
+            reasons: response.reasons.clone(),
+            detection_id: response.detection_id.clone(),
+            created_at: self.clock.utc(),
+            resolution: None,
+            cache_key: cache_key.map(str::to_string),
+        });
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// A tenant's items, most severe first, then most confident, then oldest
+    pub fn list(&self, tenant: Option<&str>, state: Option<State>) -> Vec<Item> {
+        let queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get(&tenant.map(str::to_string)) else { return Vec::new() };
+        let mut items: Vec<Item> = queue.iter().filter(|i| state.is_none_or(|s| s == i.state)).cloned().collect();
+        let rank = |severity: &str| SEVERITIES.iter().position(|s| *s == severity).unwrap_or(0);
+        items.sort_by(|a, b| {
+            rank(&b.severity)
+                .cmp(&rank(&a.severity))
+                .then(b.confidence.total_cmp(&a.confidence))
+                .then(a.created_at.cmp(&b.created_at))
+        });
+        items
+    }
+
+    /// Claim a pending item for resolution; a second claim fails as if the
+    /// item were resolved
+    pub fn claim(&self, tenant: Option<&str>, id: &str) -> Result<Item, ResolveError> {
+        let mut queues = self.queues.lock().unwrap();
+        let item = queues
+            .get_mut(&tenant.map(str::to_string))
+            .and_then(|queue| queue.iter_mut().find(|i| i.id == id))
+            .ok_or(ResolveError::NotFound)?;
+        if item.state != State::Pending {
+            return Err(ResolveError::AlreadyResolved);
+        }
+        item.state = State::Resolving;
+        Ok(item.clone())
+    }
+
+    /// Put a claimed item back when its resolution could not be applied
+    pub fn release(&self, tenant: Option<&str>, id: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        let item = queues.get_mut(&tenant.map(str::to_string)).and_then(|queue| queue.iter_mut().find(|i| i.id == id));
+        if let Some(item) = item.filter(|item| item.state == State::Resolving) {
+            item.state = State::Pending;
+        }
+    }
+
+    /// Record a reviewer's verdict on a pending or claimed item
+    pub fn resolve(&self, tenant: Option<&str>, id: &str, resolution: Resolution) -> Result<Item, ResolveError> {
+        let mut queues = self.queues.lock().unwrap();
+        let item = queues
+            .get_mut(&tenant.map(str::to_string))
+            .and_then(|queue| queue.iter_mut().find(|i| i.id == id))
+            .ok_or(ResolveError::NotFound)?;
+        if item.state == State::Resolved {
+            return Err(ResolveError::AlreadyResolved);
+        }
+        match resolution.verdict {
+            Verdict::Threat => self.resolved_threat.fetch_add(1, Ordering::Relaxed),
+            _ => self.resolved_safe.fetch_add(1, Ordering::Relaxed),
+        };
+        item.state = State::Resolved;
+        item.resolution = Some(resolution);
+        Ok(item.clone())
+    }
+
+    pub fn stats(&self) -> TriageStats {
+        let queues = self.queues.lock().unwrap();
+        let items = || queues.values().flatten();
+        TriageStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            pending: items().filter(|i| i.state != State::Resolved).count() as u64,
+            resolved: items().filter(|i| i.state == State::Resolved).count() as u64,
+            resolved_threat: self.resolved_threat.load(Ordering::Relaxed),
+            resolved_safe: self.resolved_safe.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Rough bytes held, for the memory watchdog
+    pub fn estimated_bytes(&self) -> usize {
+        self.queues.lock().unwrap().values().flatten().map(Item::estimated_bytes).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::sync::Arc;
+
+    fn queue(capacity: usize) -> TriageQueue {
+        TriageQueue::new(capacity, Arc::new(SystemClock))
+    }
+
+    fn offer(queue: &TriageQueue, tenant: Option<&str>, content: &str, confidence: f32, severity: &str) -> bool {
+        let req = ThreatDetectionRequest {
+            threat_type: "url".to_string(),
+            content: content.to_string(),
+            context: None,
+            explain: false,
+            enrich: Default::default(),
+        };
+        let response = ThreatDetectionResponse::new("phishing", false, confidence, severity.to_string(), vec![]);
+        queue.offer(tenant, &req, &response, Reason::GrayBand, None)
+    }
+
+    fn resolution(verdict: Verdict) -> Resolution {
+        Resolution { verdict, note: None, resolved_by: "reviewer".to_string(), resolved_at: Utc::now(), action: None }
+    }
+
+    #[test]
+    fn items_list_most_severe_first() {
+        let queue = queue(10);
+        offer(&queue, None, "https://a.example", 0.4, "low");
+        offer(&queue, None, "https://b.example", 0.5, "high");
+        offer(&queue, None, "https://c.example", 0.3, "high");
+        offer(&queue, None, "https://d.example", 0.45, "medium");
+        let order: Vec<String> = queue.list(None, Some(State::Pending)).into_iter().map(|i| i.content).collect();
+        assert_eq!(order, ["https://b.example", "https://c.example", "https://d.example", "https://a.example"]);
+    }
+
+    #[test]
+    fn tenants_see_and_resolve_only_their_own_items() {
+        let queue = queue(10);
+        offer(&queue, Some("a"), "https://a.example", 0.4, "low");
+        offer(&queue, Some("b"), "https://b.example", 0.4, "low");
+        let item = queue.list(Some("a"), None).remove(0);
+        assert_eq!(queue.list(Some("b"), None).len(), 1);
+        assert!(queue.list(None, None).is_empty());
+
+        assert!(matches!(queue.claim(Some("b"), &item.id), Err(ResolveError::NotFound)));
+        assert!(matches!(queue.resolve(None, &item.id, resolution(Verdict::Safe)), Err(ResolveError::NotFound)));
+        assert!(queue.claim(Some("a"), &item.id).is_ok());
+        assert!(matches!(queue.claim(Some("a"), &item.id), Err(ResolveError::AlreadyResolved)));
+        assert_eq!(queue.resolve(Some("a"), &item.id, resolution(Verdict::Threat)).unwrap().state, State::Resolved);
+        assert_eq!(queue.list(Some("b"), Some(State::Pending)).len(), 1);
+    }
+
+    #[test]
+    fn full_queue_drops_new_items_until_one_is_resolved() {
+        let queue = queue(2);
+        assert!(offer(&queue, None, "https://a.example", 0.4, "low"));
+        assert!(offer(&queue, None, "https://b.example", 0.4, "low"));
+        // Already pending, so not queued twice
+        assert!(offer(&queue, None, "https://a.example", 0.4, "low"));
+        assert!(!offer(&queue, None, "https://c.example", 0.4, "low"));
+        assert_eq!(queue.stats().dropped, 1);
+
+        let item = queue.list(None, None).into_iter().find(|i| i.content == "https://a.example").unwrap();
+        queue.resolve(None, &item.id, resolution(Verdict::Safe)).unwrap();
+        assert!(offer(&queue, None, "https://c.example", 0.4, "low"));
+        let held: Vec<String> = queue.list(None, None).into_iter().map(|i| i.content).collect();
+        assert_eq!(held.len(), 2);
+        assert!(!held.contains(&"https://a.example".to_string()));
+        let stats = queue.stats();
+        assert_eq!((stats.queued, stats.pending, stats.resolved, stats.resolved_safe), (3, 2, 0, 1));
+    }
+}
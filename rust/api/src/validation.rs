@@ -0,0 +1,266 @@
+// rust/api/src/validation.rs
+//! Field-level validation of detection request bodies
+//!
+//! Bodies are checked as plain JSON before they are deserialized, so every
+//! problem is reported at once, each with the JSON pointer of the offending
+//! field (`/threats/17/content`), instead of serde's first error.
+//!
+//! Fields no request has are ignored unless `strict_request_fields` is set,
+//! when they are all reported, with the known field they most look like.
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{Settings, THREAT_TYPES};
+use crate::content::edit_distance;
+use crate::enrichment::MODES;
+use crate::{BatchDetectionRequest, ThreatDetectionRequest};
+
+/// One problem with a request body
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    /// JSON pointer to the field; empty for the whole body
+    pub pointer: String,
+    pub message: String,
+}
+
+/// Fields of a single detection request, and of each batch item
+const DETECTION_FIELDS: &[&str] = &["threat_type", "content", "context", "explain", "enrich"];
+const BATCH_FIELDS: &[&str] = &["threats"];
+const PARTIAL_FIELDS: &[&str] = &["stream_id", "fragment", "seq", "final", "threat_type", "context"];
+/// Query parameters of a raw-body detection
+const RAW_PARAMS: &[&str] = &["threat_type", "context", "explain"];
+
+/// Request shapes checked for unexpected fields
+#[derive(Debug, Clone, Copy)]
+pub enum Shape {
+    Detection,
+    Batch,
+    Partial,
+}
+
+fn violation(pointer: &str, message: impl Into<String>) -> Violation {
+    Violation { pointer: pointer.to_string(), message: message.into() }
+}
+
+/// Validate and parse a single detection request
+pub fn detection_request(body: Value, settings: &Settings) -> Result<ThreatDetectionRequest, Vec<Violation>> {
+    let mut violations = Vec::new();
+    check_detection(&body, "", settings, &mut violations);
+    finish(body, violations)
+}
+
+/// Validate and parse a batch request, reporting problems in every item
+pub fn batch_request(body: Value, settings: &Settings) -> Result<BatchDetectionRequest, Vec<Violation>> {
+    let mut violations = Vec::new();
+    match body.get("threats") {
+        _ if !body.is_object() => violations.push(violation("", "must be an object")),
+        None => violations.push(violation("/threats", "is required")),
+        Some(Value::Array(items)) if items.is_empty() => {
+            violations.push(violation("/threats", "must contain at least one request"))
+        }
+        Some(Value::Array(items)) => {
+            for (index, item) in items.iter().enumerate() {
+                let pointer = format!("/threats/{}", index);
+                check_detection(item, &pointer, settings, &mut violations);
+                if item.get("enrich").is_some_and(|e| e != "none") {
+                    violations.push(violation(&format!("{}/enrich", pointer), "is only supported by single detections"));
+                }
+            }
+        }
+        Some(_) => violations.push(violation("/threats", "must be an array")),
+    }
+    finish(body, violations)
+}
+
+/// Fields of a body that its shape does not have, for strict mode; the
+/// body's other problems are left to the full validation
+pub fn unexpected_fields(body: &Value, shape: Shape) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    match shape {
+        Shape::Detection => check_fields(body, "", DETECTION_FIELDS, &mut violations),
+        Shape::Partial => check_fields(body, "", PARTIAL_FIELDS, &mut violations),
+        Shape::Batch => {
+            check_fields(body, "", BATCH_FIELDS, &mut violations);
+            if let Some(Value::Array(items)) = body.get("threats") {
+                for (index, item) in items.iter().enumerate() {
+                    check_fields(item, &format!("/threats/{}", index), DETECTION_FIELDS, &mut violations);
+                }
+            }
+        }
+    }
+    violations
+}
+
+/// Threat types a body asks for that are disabled; such requests are
+/// answered with 501 rather than as invalid
+pub fn disabled_types(body: &Value, shape: Shape, settings: &Settings) -> Vec<Violation> {
+    let disabled = |item: &Value, pointer: &str, default: Option<&str>| {
+        let threat_type = item.get("threat_type").and_then(Value::as_str).or(default)?;
+        settings
+            .disabled_threat_types
+            .iter()
+            .any(|d| d == threat_type)
+            .then(|| violation(&format!("{}/threat_type", pointer), format!("threat type {:?} is disabled", threat_type)))
+    };
+    match shape {
+        Shape::Detection => disabled(body, "", None).into_iter().collect(),
+        Shape::Partial => disabled(body, "", Some("url")).into_iter().collect(),
+        Shape::Batch => match body.get("threats") {
+            Some(Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .filter_map(|(index, item)| disabled(item, &format!("/threats/{}", index), None))
+                .collect(),
+            _ => Vec::new(),
+        },
+    }
+}
+
+/// Query parameters of a raw-body detection that it does not have
+pub fn unexpected_params<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<Violation> {
+    names
+        .into_iter()
+        .filter(|name| !RAW_PARAMS.contains(name))
+        .map(|name| violation(&format!("/{}", escape_pointer(name)), unexpected(name, RAW_PARAMS, "query parameter")))
+        .collect()
+}
+
+fn check_fields(item: &Value, pointer: &str, known: &[&str], violations: &mut Vec<Violation>) {
+    let Some(fields) = item.as_object() else { return };
+    for name in fields.keys().filter(|name| !known.contains(&name.as_str())) {
+        violations.push(violation(&format!("{}/{}", pointer, escape_pointer(name)), unexpected(name, known, "field")));
+    }
+}
+
+fn unexpected(name: &str, known: &[&str], what: &str) -> String {
+    let name = name.to_lowercase();
+    let nearest = known.iter().map(|k| (edit_distance(&name, k), k)).filter(|(d, _)| *d <= 2).min_by_key(|(d, _)| *d);
+    match nearest.map(|(_, k)| k) {
+        Some(similar) => format!("unexpected {}; did you mean {:?}?", what, similar),
+        None => format!("unexpected {}; expected one of {}", what, known.join(", ")),
+    }
+}
+
+/// A field name as a JSON pointer token (RFC 6901)
+fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn finish<T: serde::de::DeserializeOwned>(body: Value, mut violations: Vec<Violation>) -> Result<T, Vec<Violation>> {
+    if !violations.is_empty() {
+        return Err(violations);
+    }
+    // Anything the checks above let through should deserialize; report it if not
+    serde_json::from_value(body).map_err(|e| {
+        violations.push(violation("", e.to_string()));
+        violations
+    })
+}
+
+fn check_detection(item: &Value, pointer: &str, settings: &Settings, violations: &mut Vec<Violation>) {
+    let Some(fields) = item.as_object() else {
+        violations.push(violation(pointer, "must be an object"));
+        return;
+    };
+    let field = |name: &str| format!("{}/{}", pointer, name);
+
+    match fields.get("threat_type") {
+        None | Some(Value::Null) => violations.push(violation(&field("threat_type"), "is required")),
+        Some(Value::String(t)) if !THREAT_TYPES.contains(&t.as_str()) => violations.push(violation(
+            &field("threat_type"),
+            format!("unknown threat type {:?}; expected one of {}", t, settings.enabled_threat_types().join(", ")),
+        )),
+        Some(Value::String(t)) if settings.disabled_threat_types.contains(t) => violations.push(violation(
+            &field("threat_type"),
+            format!("threat type {:?} is disabled; expected one of {}", t, settings.enabled_threat_types().join(", ")),
+        )),
+        Some(Value::String(_)) => {}
+        Some(_) => violations.push(violation(&field("threat_type"), "must be a string")),
+    }
+
+    match fields.get("content") {
+        None | Some(Value::Null) => violations.push(violation(&field("content"), "is required")),
+        Some(Value::String(c)) if c.trim().is_empty() => violations.push(violation(&field("content"), "must not be empty")),
+        Some(Value::String(c)) if settings.max_content_bytes > 0 && c.len() > settings.max_content_bytes => {
+            violations.push(violation(
+                &field("content"),
+                format!("is {} bytes; the limit is {}", c.len(), settings.max_content_bytes),
+            ))
+        }
+        Some(Value::String(_)) => {}
+        Some(_) => violations.push(violation(&field("content"), "must be a string")),
+    }
+
+    match fields.get("context") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(c)) if c.len() > settings.max_context_bytes => violations.push(violation(
+            &field("context"),
+            format!("is {} bytes; the limit is {}", c.len(), settings.max_context_bytes),
+        )),
+        Some(Value::String(_)) => {}
+        Some(_) => violations.push(violation(&field("context"), "must be a string")),
+    }
+
+    if fields.get("explain").is_some_and(|e| !e.is_boolean()) {
+        violations.push(violation(&field("explain"), "must be a boolean"));
+    }
+
+    match fields.get("enrich") {
+        None => {}
+        Some(Value::String(mode)) if MODES.contains(&mode.as_str()) => {}
+        Some(_) => violations.push(violation(&field("enrich"), format!("must be one of {}", MODES.join(", ")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pointers(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.pointer.as_str()).collect()
+    }
+
+    #[test]
+    fn every_problem_in_a_batch_is_reported_at_its_item() {
+        let settings = Settings { max_context_bytes: 8, ..Settings::default() };
+        let body = json!({ "threats": [
+            { "threat_type": "url", "content": "https://example.org" },
+            { "threat_type": "email", "content": "  " },
+            { "content": 7, "context": "far too long", "explain": "yes" },
+            "not an object",
+        ]});
+        let violations = batch_request(body, &settings).unwrap_err();
+        assert_eq!(
+            pointers(&violations),
+            ["/threats/1/threat_type", "/threats/1/content", "/threats/2/threat_type", "/threats/2/content", "/threats/2/context", "/threats/2/explain", "/threats/3"]
+        );
+        assert_eq!(violations[1].message, "must not be empty");
+        assert_eq!(violations[4].message, "is 12 bytes; the limit is 8");
+    }
+
+    #[test]
+    fn batch_shape_is_checked_before_its_items() {
+        let settings = Settings::default();
+        assert_eq!(pointers(&batch_request(json!({ "threats": [] }), &settings).unwrap_err()), ["/threats"]);
+        assert_eq!(pointers(&batch_request(json!({ "threats": {} }), &settings).unwrap_err()), ["/threats"]);
+        assert_eq!(pointers(&batch_request(json!([]), &settings).unwrap_err()), [""]);
+    }
+
+    #[test]
+    fn valid_requests_parse() {
+        let settings = Settings::default();
+        let req = detection_request(json!({ "threat_type": "code", "content": "eval(x)", "explain": true }), &settings).unwrap();
+        assert_eq!((req.threat_type.as_str(), req.explain), ("code", true));
+        let unknown = detection_request(json!({ "threat_type": "email", "content": "hi" }), &settings).unwrap_err();
+        assert_eq!(unknown[0].message, "unknown threat type \"email\"; expected one of url, code, action");
+    }
+
+    #[test]
+    fn unexpected_fields_suggest_the_nearest_known_one() {
+        let violations = unexpected_fields(&json!({ "threats": [{ "content": "x", "contxt": "y" }] }), Shape::Batch);
+        assert_eq!(pointers(&violations), ["/threats/0/contxt"]);
+        assert_eq!(violations[0].message, "unexpected field; did you mean \"context\"?");
+    }
+}
@@ -0,0 +1,113 @@
+// rust/api/src/warmup.rs
+//! Startup warmup
+//!
+//! With `warmup_rounds` set, the server listens right away but reports
+//! not ready on `/api/ready` while `CANARIES`, cases of the test vector
+//! corpus, run through detection that many times, bringing detector code and data into memory before a load
+//! balancer sends traffic. Readiness flips once every canary has returned
+//! its expected verdict. A canary that does not keeps the instance
+//! unready, since its detectors cannot be trusted. Canaries of disabled
+//! threat types are skipped.
+
+use serde::Serialize;
+use std::sync::RwLock;
+use std::time::Instant;
+
+use crate::config::Settings;
+use crate::{testvectors, validation, ThreatDetectionRequest, ThreatDetectionResponse};
+
+/// Test vector corpus case with the verdict any working engine gives it
+pub struct Canary {
+    pub name: &'static str,
+    pub is_threat: bool,
+}
+
+/// One threat and one harmless input per threat type, named by their test vector corpus case
+pub const CANARIES: &[Canary] = &[
+    Canary { name: "url_phishing", is_threat: true },
+    Canary { name: "url_benign", is_threat: false },
+    Canary { name: "code_malicious", is_threat: true },
+    Canary { name: "code_benign", is_threat: false },
+    Canary { name: "action_typosquat", is_threat: true },
+    Canary { name: "action_empty_context", is_threat: false },
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Status {
+    Running { round: u32, rounds: u32 },
+    Passed { rounds: u32, duration_ms: u64 },
+    /// Canaries that came back with the wrong verdict, or could not run
+    Failed { failures: Vec<String> },
+}
+
+pub struct Warmup {
+    rounds: u32,
+    status: RwLock<Status>,
+}
+
+impl Warmup {
+    pub fn new(rounds: u32) -> Self {
+        let status = match rounds {
+            0 => Status::Passed { rounds: 0, duration_ms: 0 },
+            _ => Status::Running { round: 0, rounds },
+        };
+        Self { rounds, status: RwLock::new(status) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.rounds > 0
+    }
+
+    pub fn ready(&self) -> bool {
+        matches!(*self.status.read().unwrap(), Status::Passed { .. })
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Run every round through `detect`, then settle readiness
+    pub fn run(&self, settings: &Settings, detect: impl Fn(&ThreatDetectionRequest) -> ThreatDetectionResponse) -> Status {
+        let start = Instant::now();
+        let canaries: Vec<(&Canary, Result<ThreatDetectionRequest, String>)> = CANARIES
+            .iter()
+            .map(|canary| (canary, testvectors::case_body(canary.name)))
+            .filter(|(_, body)| {
+                !body.as_ref().is_some_and(|body| settings.disabled_threat_types.iter().any(|t| body["threat_type"] == t.as_str()))
+            })
+            .map(|(canary, body)| {
+                let request = body.ok_or_else(|| format!("{}: not in the test vector corpus", canary.name)).and_then(|body| {
+                    validation::detection_request(body, settings)
+                        .map_err(|violations| format!("{}: rejected ({} violation(s))", canary.name, violations.len()))
+                });
+                (canary, request)
+            })
+            .collect();
+
+        let mut failures: Vec<String> = canaries.iter().filter_map(|(_, request)| request.as_ref().err().cloned()).collect();
+        for round in 1..=self.rounds {
+            *self.status.write().unwrap() = Status::Running { round, rounds: self.rounds };
+            for (canary, request) in &canaries {
+                let Ok(request) = request else { continue };
+                let response = detect(request);
+                if round == self.rounds && response.is_threat != canary.is_threat {
+                    failures.push(format!(
+                        "{}: expected {}, got {} ({:.2})",
+                        canary.name,
+                        if canary.is_threat { "threat" } else { "no threat" },
+                        if response.is_threat { "threat" } else { "no threat" },
+                        response.confidence
+                    ));
+                }
+            }
+        }
+
+        let status = match failures.is_empty() {
+            true => Status::Passed { rounds: self.rounds, duration_ms: start.elapsed().as_millis() as u64 },
+            false => Status::Failed { failures },
+        };
+        *self.status.write().unwrap() = status.clone();
+        status
+    }
+}
@@ -0,0 +1,618 @@
+// rust/api/src/watch.rs
+//! Indicators clients watch for verdict changes
+//!
+//! A client (its API key, or its address without one) registers domains,
+//! URLs and content hashes with `POST /api/watch`, up to
+//! `watch_max_indicators`, and long-polls `GET /api/watch/poll` for changes.
+//! A watched indicator's verdict is updated from two sources: detections
+//! served for matching content under the same tenant, and re-evaluation of
+//! watched domains and URLs after the engine state changes (new feeds,
+//! lists or rules), at most once per `watch_reevaluate_interval_secs`.
+//! Hashes are only ever updated by detections, since the content behind
+//! them is not kept.
+//!
+//! Only a change of verdict (or the first one) is reported. Changes are
+//! numbered per store; a poll returns those after its `cursor`, and each key
+//! keeps only its most recent ones. At most `watch_max_keys` keys have a
+//! list at a time.
+//!
+//! Watch lists and their last verdicts are persisted to `watches.json` in
+//! the data directory: at once when a list changes, and for verdict changes
+//! by a background task, at most once per `PERSIST_DELAY`.
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::clock::SharedClock;
+use crate::fingerprints;
+use crate::thresholds::Verdict;
+use crate::{ThreatDetectionRequest, ThreatDetectionResponse};
+
+pub const WATCHES_FILE_NAME: &str = "watches.json";
+
+/// Changes kept per key for polls that fall behind
+const CHANGES_PER_KEY: usize = 100;
+
+/// How long verdict changes gather before they are written out together
+pub const PERSIST_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Domain,
+    Url,
+    /// Content fingerprint, as reported under `previously_seen`
+    Hash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Indicator {
+    pub kind: Kind,
+    pub value: String,
+}
+
+impl Indicator {
+    /// The indicator in the form it is matched in, or why it cannot be watched
+    pub fn normalized(&self) -> Result<Indicator, String> {
+        let value = self.value.trim();
+        let value = match self.kind {
+            Kind::Domain => {
+                let domain = value.trim_end_matches('.').to_lowercase();
+                if domain.is_empty() || domain.contains(['/', ':', ' ']) {
+                    return Err(format!("{:?} is not a domain", self.value));
+                }
+                domain
+            }
+            Kind::Url => match url::Url::parse(value) {
+                Ok(url) if url.host_str().is_some() => url.to_string(),
+                _ => return Err(format!("{:?} is not an absolute URL", self.value)),
+            },
+            Kind::Hash => {
+                let hash = value.to_lowercase();
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(format!("{:?} is not a SHA-256 fingerprint", self.value));
+                }
+                hash
+            }
+        };
+        Ok(Indicator { kind: self.kind, value })
+    }
+
+    /// Content a detection of this indicator runs on; hashes have none
+    pub fn detection_content(&self) -> Option<String> {
+        match self.kind {
+            Kind::Domain => Some(format!("https://{}/", self.value)),
+            Kind::Url => Some(self.value.clone()),
+            Kind::Hash => None,
+        }
+    }
+}
+
+/// What produced a verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Evaluated when the indicator was registered
+    Registration,
+    /// A detection served for matching content
+    Detection,
+    /// Evaluated again after the engine state changed
+    Reevaluation,
+}
+
+/// Last known verdict of a watched indicator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assessment {
+    pub verdict: Verdict,
+    pub severity: String,
+    pub confidence: f32,
+    pub source: Source,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watched {
+    #[serde(flatten)]
+    pub indicator: Indicator,
+    pub added_at: DateTime<Utc>,
+    /// Unset until the indicator has been evaluated or seen
+    pub last: Option<Assessment>,
+}
+
+/// A new or changed verdict, as polls return it
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    /// Pass the highest one seen as the next poll's `cursor`
+    pub seq: u64,
+    #[serde(flatten)]
+    pub indicator: Indicator,
+    /// Unset for an indicator's first verdict
+    pub previous: Option<Verdict>,
+    #[serde(flatten)]
+    pub assessment: Assessment,
+    /// Detection that produced the verdict, for `Source::Detection`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Poll {
+    pub changes: Vec<Change>,
+    pub cursor: u64,
+    /// Changes after the requested cursor were dropped; fetch `GET /api/watch`
+    /// for the current verdicts
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reset: bool,
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    TooMany { max: usize },
+    /// The store holds lists for `max` keys already
+    TooManyKeys { max: usize },
+    Io(io::Error),
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct KeyWatches {
+    /// Tenant whose lists and detections apply, as of the last registration
+    tenant: Option<String>,
+    indicators: Vec<Watched>,
+    #[serde(skip)]
+    changes: VecDeque<Change>,
+    /// Highest change dropped from `changes`
+    #[serde(skip)]
+    dropped_through: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    seq: u64,
+    keys: BTreeMap<String, KeyWatches>,
+    /// Keys watching each indicator, so detections are matched without
+    /// going through every list
+    #[serde(skip)]
+    index: HashMap<Indicator, BTreeSet<String>>,
+    /// Distinct hashes in `index`; content is only fingerprinted when some
+    /// are watched
+    #[serde(skip)]
+    hashes: usize,
+}
+
+impl State {
+    fn index(&mut self, key: &str, indicator: &Indicator) {
+        let keys = self.index.entry(indicator.clone()).or_default();
+        if keys.is_empty() && indicator.kind == Kind::Hash {
+            self.hashes += 1;
+        }
+        keys.insert(key.to_string());
+    }
+
+    fn unindex(&mut self, key: &str, indicator: &Indicator) {
+        let Some(keys) = self.index.get_mut(indicator) else { return };
+        keys.remove(key);
+        if keys.is_empty() {
+            self.index.remove(indicator);
+            if indicator.kind == Kind::Hash {
+                self.hashes -= 1;
+            }
+        }
+    }
+}
+
+pub struct WatchStore {
+    path: PathBuf,
+    max_per_key: usize,
+    max_keys: usize,
+    state: Mutex<State>,
+    /// Held while writing the file, taken before `state` is released so
+    /// snapshots are written in order
+    writing: Mutex<()>,
+    /// Verdicts changed since the file was last written
+    dirty: AtomicBool,
+    /// Holds a permit while unsaved verdict changes are waiting
+    persist: Notify,
+    /// Woken on every change, for pending polls
+    changed: Notify,
+    /// Holds a permit while a re-evaluation is requested
+    reevaluate: Notify,
+    /// Least time from the start of one re-evaluation to the next
+    reevaluate_interval: Duration,
+    last_reevaluation: Mutex<Option<Instant>>,
+    clock: SharedClock,
+}
+
+impl WatchStore {
+    /// Load watch lists from the data directory; a missing file means none yet
+    pub fn load(
+        data_dir: &Path,
+        max_per_key: usize,
+        max_keys: usize,
+        reevaluate_interval: Duration,
+        clock: SharedClock,
+    ) -> io::Result<Self> {
+        let path = data_dir.join(WATCHES_FILE_NAME);
+        let mut state: State = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+        // Changes are not persisted; polls from before the restart must resync
+        let seq = state.seq;
+        let mut indexed = Vec::new();
+        for (key, watches) in state.keys.iter_mut() {
+            watches.dropped_through = seq;
+            indexed.extend(watches.indicators.iter().map(|w| (key.clone(), w.indicator.clone())));
+        }
+        for (key, indicator) in &indexed {
+            state.index(key, indicator);
+        }
+        Ok(Self {
+            path,
+            max_per_key,
+            max_keys,
+            state: Mutex::new(state),
+            writing: Mutex::new(()),
+            dirty: AtomicBool::new(false),
+            persist: Notify::new(),
+            changed: Notify::new(),
+            reevaluate: Notify::new(),
+            reevaluate_interval,
+            last_reevaluation: Mutex::new(None),
+            clock,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_per_key > 0
+    }
+
+    /// Add normalized indicators to a key's list, returning the ones not
+    /// already watched
+    pub fn register(&self, key: &str, tenant: Option<&str>, indicators: Vec<Indicator>) -> Result<Vec<Indicator>, WatchError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.keys.contains_key(key) && state.keys.len() >= self.max_keys {
+            return Err(WatchError::TooManyKeys { max: self.max_keys });
+        }
+        let watches = state.keys.entry(key.to_string()).or_default();
+        let mut added: Vec<Indicator> = Vec::new();
+        for indicator in indicators {
+            if !added.contains(&indicator) && !watches.indicators.iter().any(|w| w.indicator == indicator) {
+                added.push(indicator);
+            }
+        }
+        if watches.indicators.len() + added.len() > self.max_per_key {
+            if watches.indicators.is_empty() {
+                state.keys.remove(key);
+            }
+            return Err(WatchError::TooMany { max: self.max_per_key });
+        }
+        watches.tenant = tenant.map(str::to_string);
+        let now = self.clock.utc();
+        watches.indicators.extend(added.iter().map(|i| Watched { indicator: i.clone(), added_at: now, last: None }));
+        for indicator in &added {
+            state.index(key, indicator);
+        }
+        self.save(state).map_err(WatchError::Io)?;
+        Ok(added)
+    }
+
+    /// Stop watching indicators, returning how many were watched
+    pub fn remove(&self, key: &str, indicators: &[Indicator]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let Some(watches) = state.keys.get_mut(key) else { return Ok(0) };
+        let (removed, kept): (Vec<Watched>, Vec<Watched>) =
+            std::mem::take(&mut watches.indicators).into_iter().partition(|w| indicators.contains(&w.indicator));
+        watches.indicators = kept;
+        if watches.indicators.is_empty() {
+            state.keys.remove(key);
+        }
+        for watched in &removed {
+            state.unindex(key, &watched.indicator);
+        }
+        if !removed.is_empty() {
+            self.save(state)?;
+        }
+        let removed = removed.len();
+        Ok(removed)
+    }
+
+    pub fn list(&self, key: &str) -> Vec<Watched> {
+        self.state.lock().unwrap().keys.get(key).map(|w| w.indicators.clone()).unwrap_or_default()
+    }
+
+    /// Every watched domain and URL with the key and tenant it is watched under
+    pub fn evaluable(&self) -> Vec<(String, Option<String>, Indicator)> {
+        let state = self.state.lock().unwrap();
+        state
+            .keys
+            .iter()
+            .flat_map(|(key, watches)| {
+                watches
+                    .indicators
+                    .iter()
+                    .filter(|w| w.indicator.kind != Kind::Hash)
+                    .map(|w| (key.clone(), watches.tenant.clone(), w.indicator.clone()))
+            })
+            .collect()
+    }
+
+    /// Record an indicator's verdict, reporting it if it is new or changed
+    pub fn update(&self, key: &str, indicator: &Indicator, response: &ThreatDetectionResponse, source: Source) {
+        let mut state = self.state.lock().unwrap();
+        if self.apply(&mut state, key, indicator, response, source) {
+            drop(state);
+            self.note_change();
+        }
+    }
+
+    /// Update indicators matching a served detection under its tenant
+    pub fn observe(&self, tenant: Option<&str>, req: &ThreatDetectionRequest, response: &ThreatDetectionResponse) {
+        let hashes = {
+            let state = self.state.lock().unwrap();
+            if state.index.is_empty() {
+                return;
+            }
+            state.hashes > 0
+        };
+        let mut seen = Vec::new();
+        if hashes {
+            // Fingerprinted already when history is kept
+            let fingerprint = match &response.previously_seen {
+                Some(previously) => previously.fingerprint.clone(),
+                None => fingerprints::fingerprint(&req.content),
+            };
+            seen.push(Indicator { kind: Kind::Hash, value: fingerprint });
+        }
+        if req.threat_type == "url" {
+            if let Ok(url) = url::Url::parse(req.content.trim()) {
+                if let Some(host) = url.host_str() {
+                    seen.push(Indicator { kind: Kind::Domain, value: host.trim_end_matches('.').to_lowercase() });
+                }
+                seen.push(Indicator { kind: Kind::Url, value: url.to_string() });
+            }
+        }
+        if seen.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let matching: Vec<(String, Indicator)> = seen
+            .into_iter()
+            .filter_map(|indicator| state.index.get(&indicator).map(|keys| (keys, indicator)))
+            .flat_map(|(keys, indicator)| keys.iter().map(move |key| (key.clone(), indicator.clone())))
+            .filter(|(key, _)| state.keys.get(key).is_some_and(|w| w.tenant.as_deref() == tenant))
+            .collect();
+        let mut changed = false;
+        for (key, indicator) in matching {
+            changed |= self.apply(&mut state, &key, &indicator, response, Source::Detection);
+        }
+        if changed {
+            drop(state);
+            self.note_change();
+        }
+    }
+
+    /// Changes for a key after `cursor`
+    pub fn changes(&self, key: &str, cursor: u64) -> Poll {
+        let state = self.state.lock().unwrap();
+        let Some(watches) = state.keys.get(key) else {
+            return Poll { changes: Vec::new(), cursor: cursor.min(state.seq), reset: false };
+        };
+        let changes: Vec<Change> = watches.changes.iter().filter(|c| c.seq > cursor).cloned().collect();
+        Poll {
+            cursor: changes.last().map_or(cursor.min(state.seq), |c| c.seq),
+            reset: cursor < watches.dropped_through,
+            changes,
+        }
+    }
+
+    /// Highest change number so far, the cursor of a first poll
+    pub fn cursor(&self) -> u64 {
+        self.state.lock().unwrap().seq
+    }
+
+    /// Resolves on the next change; create it before checking for changes
+    /// so none is missed in between
+    pub fn changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// Ask for watched domains and URLs to be evaluated again; requests
+    /// made while one is pending are merged into it
+    pub fn request_reevaluation(&self) {
+        self.reevaluate.notify_one();
+    }
+
+    /// Wait until a re-evaluation is due: one has been requested and the
+    /// interval since the last one started has passed
+    pub async fn reevaluation_due(&self) {
+        self.reevaluate.notified().await;
+        let wait = self.reevaluation_wait();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        *self.last_reevaluation.lock().unwrap() = Some(self.clock.now());
+    }
+
+    /// Time left before another re-evaluation may start
+    fn reevaluation_wait(&self) -> Duration {
+        let last = *self.last_reevaluation.lock().unwrap();
+        last.map_or(Duration::ZERO, |at| (at + self.reevaluate_interval).saturating_duration_since(self.clock.now()))
+    }
+
+    pub fn estimated_bytes(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .keys
+            .iter()
+            .map(|(key, w)| {
+                key.len()
+                    + w.indicators.iter().map(|i| std::mem::size_of::<Watched>() + i.indicator.value.len()).sum::<usize>()
+                    + w.changes.iter().map(|c| std::mem::size_of::<Change>() + c.indicator.value.len()).sum::<usize>()
+            })
+            .sum()
+    }
+
+    fn apply(&self, state: &mut State, key: &str, indicator: &Indicator, response: &ThreatDetectionResponse, source: Source) -> bool {
+        let Some(watches) = state.keys.get_mut(key) else { return false };
+        let Some(watched) = watches.indicators.iter_mut().find(|w| w.indicator == *indicator) else { return false };
+        let assessment = Assessment {
+            verdict: response.verdict,
+            severity: response.severity.clone(),
+            confidence: response.confidence,
+            source,
+            at: self.clock.utc(),
+        };
+        let previous = watched.last.as_ref().map(|a| a.verdict);
+        watched.last = Some(assessment.clone());
+        if previous == Some(response.verdict) {
+            return false;
+        }
+        state.seq += 1;
+        watches.changes.push_back(Change {
+            seq: state.seq,
+            indicator: indicator.clone(),
+            previous,
+            assessment,
+            detection_id: response.detection_id.clone().filter(|_| source == Source::Detection),
+        });
+        if watches.changes.len() > CHANGES_PER_KEY {
+            watches.dropped_through = watches.changes.pop_front().map_or(0, |c| c.seq);
+        }
+        true
+    }
+
+    /// Wait until verdict changes are waiting to be written
+    pub async fn persist_requested(&self) {
+        self.persist.notified().await
+    }
+
+    /// Write out unsaved verdict changes; they are served even if this fails
+    pub fn flush(&self) {
+        if !self.dirty.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(e) = self.save(self.state.lock().unwrap()) {
+            warn!("Failed to persist watch lists: {}", e);
+        }
+    }
+
+    /// Wake pending polls and schedule the verdict change to be written
+    fn note_change(&self) {
+        self.changed.notify_waiters();
+        self.dirty.store(true, Ordering::Release);
+        self.persist.notify_one();
+    }
+
+    /// Snapshot `state` and write it once the lock is released
+    fn save(&self, state: std::sync::MutexGuard<'_, State>) -> io::Result<()> {
+        self.dirty.store(false, Ordering::Release);
+        let body = serde_json::to_vec_pretty(&*state).map_err(io::Error::other)?;
+        let _writing = self.writing.lock().unwrap();
+        drop(state);
+        let written = self.write(&body);
+        if written.is_err() {
+            // Left for the next flush to try again
+            self.dirty.store(true, Ordering::Release);
+        }
+        written
+    }
+
+    fn write(&self, body: &[u8]) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write aside and rename so a crash never leaves a truncated file
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// A poll timeout such as `30s`, `500ms` or `30` (seconds)
+pub fn parse_timeout(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    if let Some(ms) = text.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
+    }
+    text.strip_suffix('s').unwrap_or(text).parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use futures::poll;
+    use std::sync::Arc;
+
+    fn store(name: &str, max_per_key: usize, max_keys: usize) -> (WatchStore, Arc<ManualClock>) {
+        let dir = std::env::temp_dir().join(format!("watch-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let clock = Arc::new(ManualClock::new());
+        (WatchStore::load(&dir, max_per_key, max_keys, Duration::from_secs(60), clock.clone()).unwrap(), clock)
+    }
+
+    fn domain(name: &str) -> Indicator {
+        Indicator { kind: Kind::Domain, value: name.to_string() }
+    }
+
+    #[actix_web::test]
+    async fn reevaluations_requested_together_run_once_and_no_sooner_than_the_interval() {
+        let (store, clock) = store("reevaluate", 10, 10);
+        store.request_reevaluation();
+        store.request_reevaluation();
+        assert!(poll!(std::pin::pin!(store.reevaluation_due())).is_ready());
+        // Both requests were served by that one pass
+        assert!(poll!(std::pin::pin!(store.reevaluation_due())).is_pending());
+
+        store.request_reevaluation();
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(store.reevaluation_wait(), Duration::from_secs(15));
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(store.reevaluation_wait(), Duration::ZERO);
+        assert!(poll!(std::pin::pin!(store.reevaluation_due())).is_ready());
+        assert_eq!(store.reevaluation_wait(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_key_watches_at_most_max_per_key_indicators() {
+        let (store, _) = store("per-key", 2, 10);
+        assert_eq!(store.register("key:a", None, vec![domain("one.example"), domain("two.example")]).unwrap().len(), 2);
+        // Already watched: nothing new to count
+        assert!(store.register("key:a", None, vec![domain("one.example")]).unwrap().is_empty());
+        assert!(matches!(store.register("key:a", None, vec![domain("three.example")]), Err(WatchError::TooMany { max: 2 })));
+        assert_eq!(store.list("key:a").len(), 2);
+
+        // A first request over the cap leaves no list behind
+        let over = vec![domain("one.example"), domain("two.example"), domain("three.example")];
+        assert!(matches!(store.register("key:b", None, over), Err(WatchError::TooMany { max: 2 })));
+        assert!(store.list("key:b").is_empty());
+        assert_eq!(store.state.lock().unwrap().keys.len(), 1);
+    }
+
+    #[test]
+    fn new_keys_are_refused_once_max_keys_have_lists() {
+        let (store, _) = store("max-keys", 10, 2);
+        store.register("key:a", None, vec![domain("a.example")]).unwrap();
+        store.register("key:b", None, vec![domain("b.example")]).unwrap();
+        assert!(matches!(store.register("key:c", None, vec![domain("c.example")]), Err(WatchError::TooManyKeys { max: 2 })));
+        // Keys with a list may still add to it
+        store.register("key:a", None, vec![domain("more.example")]).unwrap();
+
+        // Emptying a list frees its place
+        assert_eq!(store.remove("key:b", &[domain("b.example")]).unwrap(), 1);
+        store.register("key:c", None, vec![domain("c.example")]).unwrap();
+        assert_eq!(store.list("key:c").len(), 1);
+    }
+}